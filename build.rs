@@ -0,0 +1,47 @@
+//! With the `ffi` feature, writes the C header for `src/ffi.rs` to `include/tx_engine.h`.
+//! With the `grpc` feature, generates the gRPC service from `proto/tx_engine.proto`.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    generate_header();
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+#[cfg(feature = "ffi")]
+fn generate_header() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        include_guard: Some("TX_ENGINE_H".to_string()),
+        autogen_warning: Some(
+            "/* Generated by cbindgen from src/ffi.rs when building with the `ffi` feature. */"
+                .to_string(),
+        ),
+        export: cbindgen::ExportConfig {
+            include: vec!["TxEngine".to_string()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(format!("{}/src/ffi.rs", crate_dir))
+        .generate()
+        .expect("src/ffi.rs parses")
+        .write_to_file(format!("{}/include/tx_engine.h", crate_dir));
+}
+
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto/tx_engine.proto");
+    // A vendored protoc, so building needs no protobuf install.
+    let protoc =
+        protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored for this platform");
+    // SAFETY: the build script is single-threaded.
+    unsafe { std::env::set_var("PROTOC", protoc) };
+    tonic_prost_build::compile_protos("proto/tx_engine.proto")
+        .expect("proto/tx_engine.proto compiles");
+}
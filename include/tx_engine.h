@@ -0,0 +1,89 @@
+#ifndef TX_ENGINE_H
+#define TX_ENGINE_H
+
+/* Generated by cbindgen from src/ffi.rs when building with the `ffi` feature. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The transaction was applied.
+ */
+#define TX_ENGINE_APPLIED 0
+
+/**
+ * The transaction was rejected; [`tx_engine_last_error`] gives the reason code.
+ */
+#define TX_ENGINE_REJECTED 1
+
+/**
+ * The argument was null, not UTF-8 or not a transaction; [`tx_engine_last_error`] says why.
+ */
+#define TX_ENGINE_INVALID -1
+
+/**
+ * An engine and the message about its latest failed call.
+ */
+typedef struct TxEngine TxEngine;
+
+/**
+ * Creates an engine with the default configuration. Release it with [`tx_engine_free`].
+ */
+struct TxEngine *tx_engine_new(void);
+
+/**
+ * Applies the transaction in the NUL-terminated JSON string `transaction`, e.g.
+ * `{"type":"deposit","client":1,"tx":1,"amount":"2.5"}`. Returns `TX_ENGINE_APPLIED`,
+ * `TX_ENGINE_REJECTED` or `TX_ENGINE_INVALID`.
+ *
+ * # Safety
+ *
+ * `engine` must come from [`tx_engine_new`] and not have been freed, and `transaction`
+ * must be null or point to a NUL-terminated string.
+ */
+int32_t tx_engine_process(struct TxEngine *engine, const char *transaction);
+
+/**
+ * The reason the latest [`tx_engine_process`] call on `engine` did not apply its
+ * transaction: a rejection code such as `insufficient_funds`, or a description of the
+ * invalid input. Null if it was applied. The string stays valid until the next call on
+ * `engine`.
+ *
+ * # Safety
+ *
+ * `engine` must come from [`tx_engine_new`] and not have been freed.
+ */
+const char *tx_engine_last_error(const struct TxEngine *engine);
+
+/**
+ * Every account, ordered by client, as a JSON array in the format of the HTTP server's
+ * `GET /accounts`. Release the string with [`tx_engine_string_free`]. Null if `engine`
+ * is null.
+ *
+ * # Safety
+ *
+ * `engine` must come from [`tx_engine_new`] and not have been freed.
+ */
+char *tx_engine_output_json(const struct TxEngine *engine);
+
+/**
+ * Releases a string returned by [`tx_engine_output_json`]. Does nothing if it is null.
+ *
+ * # Safety
+ *
+ * `string` must be null or come from [`tx_engine_output_json`], and not have been freed.
+ */
+void tx_engine_string_free(char *string);
+
+/**
+ * Releases an engine. Does nothing if it is null.
+ *
+ * # Safety
+ *
+ * `engine` must be null or come from [`tx_engine_new`], and not have been freed.
+ */
+void tx_engine_free(struct TxEngine *engine);
+
+#endif  /* TX_ENGINE_H */
@@ -0,0 +1,217 @@
+//! The engine's table of accounts, in one of the layouts of [`AccountStorage`].
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
+use crate::config::AccountStorage;
+use crate::types::{Account, ClientId};
+
+/// Clients below this get a slot in the dense table; larger ones an entry beside it.
+const DENSE_CLIENTS: ClientId = 1 << 16;
+
+/// Accounts by client id.
+#[derive(Debug)]
+pub(crate) enum Accounts {
+    Map(HashMap<ClientId, Account>),
+    /// Slot `client` holds the account of a client below [`DENSE_CLIENTS`]. The vector
+    /// grows to the largest such client seen, so at most 65,536 slots. Larger clients are
+    /// kept in `sparse`.
+    Dense {
+        slots: Vec<Option<Account>>,
+        len: usize,
+        sparse: BTreeMap<ClientId, Account>,
+    },
+}
+
+impl Accounts {
+    pub(crate) fn new(storage: AccountStorage) -> Self {
+        match storage {
+            AccountStorage::Map => Accounts::Map(HashMap::new()),
+            AccountStorage::Dense => Accounts::Dense {
+                slots: Vec::new(),
+                len: 0,
+                sparse: BTreeMap::new(),
+            },
+        }
+    }
+
+    pub(crate) fn get(&self, client: ClientId) -> Option<&Account> {
+        match self {
+            Accounts::Map(map) => map.get(&client),
+            Accounts::Dense { sparse, .. } if client >= DENSE_CLIENTS => sparse.get(&client),
+            Accounts::Dense { slots, .. } => slots.get(client as usize)?.as_ref(),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, client: ClientId) -> Option<&mut Account> {
+        match self {
+            Accounts::Map(map) => map.get_mut(&client),
+            Accounts::Dense { sparse, .. } if client >= DENSE_CLIENTS => sparse.get_mut(&client),
+            Accounts::Dense { slots, .. } => slots.get_mut(client as usize)?.as_mut(),
+        }
+    }
+
+    /// The client's account, created empty if it has none.
+    pub(crate) fn get_or_default(&mut self, client: ClientId) -> &mut Account {
+        match self {
+            Accounts::Map(map) => map.entry(client).or_default(),
+            Accounts::Dense { sparse, .. } if client >= DENSE_CLIENTS => {
+                sparse.entry(client).or_default()
+            }
+            Accounts::Dense { slots, len, .. } => {
+                let slot = Self::slot(slots, client);
+                if slot.is_none() {
+                    *len += 1;
+                }
+                slot.get_or_insert_with(Account::default)
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, client: ClientId, account: Account) -> Option<Account> {
+        match self {
+            Accounts::Map(map) => map.insert(client, account),
+            Accounts::Dense { sparse, .. } if client >= DENSE_CLIENTS => {
+                sparse.insert(client, account)
+            }
+            Accounts::Dense { slots, len, .. } => {
+                let previous = Self::slot(slots, client).replace(account);
+                if previous.is_none() {
+                    *len += 1;
+                }
+                previous
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, client: ClientId) -> Option<Account> {
+        match self {
+            Accounts::Map(map) => map.remove(&client),
+            Accounts::Dense { sparse, .. } if client >= DENSE_CLIENTS => sparse.remove(&client),
+            Accounts::Dense { slots, len, .. } => {
+                let removed = slots.get_mut(client as usize)?.take();
+                if removed.is_some() {
+                    *len -= 1;
+                }
+                removed
+            }
+        }
+    }
+
+    /// Puts back `saved`, the client's account as it was before a change: restores it, or
+    /// removes the account if it didn't exist.
+    pub(crate) fn restore(&mut self, client: ClientId, saved: Option<Account>) {
+        match saved {
+            Some(account) => self.insert(client, account),
+            None => self.remove(client),
+        };
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Accounts::Map(map) => map.is_empty(),
+            Accounts::Dense { len, sparse, .. } => *len == 0 && sparse.is_empty(),
+        }
+    }
+
+    pub(crate) fn clients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.iter().map(|(client, _)| client)
+    }
+
+    /// Every account; by client with dense storage, in no particular order otherwise.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (ClientId, &Account)> + '_ {
+        let (map, dense) = match self {
+            Accounts::Map(map) => (
+                Some(map.iter().map(|(&client, account)| (client, account))),
+                None,
+            ),
+            Accounts::Dense { slots, sparse, .. } => {
+                let sparse = sparse.iter().map(|(&client, account)| (client, account));
+                (
+                    None,
+                    Some(occupied(slots.iter().map(Option::as_ref)).chain(sparse)),
+                )
+            }
+        };
+        map.into_iter().flatten().chain(dense.into_iter().flatten())
+    }
+
+    /// Approximate bytes allocated for the table.
+    pub(crate) fn memory_usage(&self) -> usize {
+        match self {
+            Accounts::Map(map) => map.capacity() * (mem::size_of::<(ClientId, Account)>() + 1),
+            // A B-tree node holds up to 11 entries; count each as a whole one.
+            Accounts::Dense { slots, sparse, .. } => {
+                slots.capacity() * mem::size_of::<Option<Account>>()
+                    + sparse.len() * mem::size_of::<(ClientId, Account)>()
+            }
+        }
+    }
+
+    /// The slot of `client`, below [`DENSE_CLIENTS`], growing the table to reach it.
+    fn slot(slots: &mut Vec<Option<Account>>, client: ClientId) -> &mut Option<Account> {
+        let index = client as usize;
+        if index >= slots.len() {
+            slots.resize(index + 1, None);
+        }
+        &mut slots[index]
+    }
+}
+
+/// The filled slots of a dense table, with their client ids.
+fn occupied<'a>(
+    slots: impl Iterator<Item = Option<&'a Account>>,
+) -> impl Iterator<Item = (ClientId, &'a Account)> {
+    slots
+        .enumerate()
+        .filter_map(|(index, slot)| Some((index as ClientId, slot?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Money;
+
+    #[test]
+    fn test_dense_accounts() {
+        let mut accounts = Accounts::new(AccountStorage::Dense);
+        assert!(accounts.is_empty());
+        accounts.get_or_default(7).available = Money::from_units(5);
+        accounts.insert(2, Account::default());
+        accounts.get_or_default(7).held = Money::from_units(1);
+        assert_eq!(accounts.clients().collect::<Vec<_>>(), [2, 7]);
+        assert_eq!(accounts.get(7).unwrap().total(), Money::from_units(6));
+        assert_eq!(accounts.get(3), None);
+        assert_eq!(accounts.get(ClientId::MAX), None);
+
+        accounts.restore(7, None);
+        assert_eq!(accounts.remove(7), None);
+        assert!(!accounts.is_empty());
+        accounts.remove(2);
+        assert!(accounts.is_empty());
+
+        accounts.get_or_default(65_535).locked = true;
+        assert_eq!(accounts.iter().count(), 1);
+        assert_eq!(
+            accounts.memory_usage(),
+            65_536 * mem::size_of::<Option<Account>>()
+        );
+
+        // Larger clients are kept beside the table, after it in client order.
+        accounts.get_or_default(ClientId::MAX).available = Money::from_units(2);
+        accounts.insert(1 << 64, Account::default());
+        assert_eq!(
+            accounts.clients().collect::<Vec<_>>(),
+            [65_535, 1 << 64, ClientId::MAX]
+        );
+        assert_eq!(
+            accounts.get(ClientId::MAX).unwrap().available,
+            Money::from_units(2)
+        );
+        assert_eq!(accounts.remove(1 << 64), Some(Account::default()));
+        accounts.remove(65_535);
+        assert!(!accounts.is_empty());
+        accounts.remove(ClientId::MAX);
+        assert!(accounts.is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+//! Manual operations on accounts, applied with
+//! [`Engine::apply_admin`](crate::Engine::apply_admin).
+//!
+//! These are for operators, not clients: unlocking an account once a chargeback
+//! investigation is closed, locking a suspicious one, posting balance corrections,
+//! closing accounts and setting overdraft limits.
+//! Every operation carries a reason, which the audit trail records with it.
+
+use std::fmt;
+
+use crate::types::{ClientId, Money};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminOp {
+    /// Locks `client`'s account, creating it if needed, so it rejects deposits, withdrawals
+    /// and transfers until unlocked.
+    Lock { client: ClientId, reason: String },
+    /// Unlocks `client`'s account. A pending automatic unlock is cancelled either way.
+    Unlock { client: ClientId, reason: String },
+    /// Adds `amount` (fixed-point, negative to debit) to `client`'s available balance.
+    /// Locks and balance limits don't apply; arithmetic follows the configured mode.
+    Adjust {
+        client: ClientId,
+        amount: Money,
+        reason: String,
+    },
+    /// Closes `client`'s account, creating it if needed, which must have no balance and no
+    /// open dispute. A closed account rejects deposits, withdrawals, transfers, disputes and
+    /// adjustments for good.
+    Close { client: ClientId, reason: String },
+    /// Lets `client`'s withdrawals take the available balance down to `-limit`, in place of
+    /// the configured overdraft limit. Zero allows no overdraft.
+    Overdraft {
+        client: ClientId,
+        limit: Money,
+        reason: String,
+    },
+}
+
+impl AdminOp {
+    pub fn client(&self) -> ClientId {
+        match self {
+            AdminOp::Lock { client, .. }
+            | AdminOp::Unlock { client, .. }
+            | AdminOp::Adjust { client, .. }
+            | AdminOp::Close { client, .. }
+            | AdminOp::Overdraft { client, .. } => *client,
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            AdminOp::Lock { reason, .. }
+            | AdminOp::Unlock { reason, .. }
+            | AdminOp::Adjust { reason, .. }
+            | AdminOp::Close { reason, .. }
+            | AdminOp::Overdraft { reason, .. } => reason,
+        }
+    }
+}
+
+/// The operation without its client, e.g. `adjust -1.5000 (duplicate payout)`.
+impl fmt::Display for AdminOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminOp::Lock { .. } => f.write_str("lock")?,
+            AdminOp::Unlock { .. } => f.write_str("unlock")?,
+            AdminOp::Adjust { amount, .. } => write!(f, "adjust {}", amount)?,
+            AdminOp::Close { .. } => f.write_str("close")?,
+            AdminOp::Overdraft { limit, .. } => write!(f, "overdraft {}", limit)?,
+        }
+        match self.reason() {
+            "" => Ok(()),
+            reason => write!(f, " ({})", reason),
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! Balance alerting evaluated as transactions are applied.
+//!
+//! An [`AlertMonitor`] is fed each affected account after processing and checks it
+//! against a set of [`AlertRule`]s. Rules are edge-triggered: an alert fires when the
+//! condition starts to hold and is re-armed once it clears, so a client sitting below a
+//! threshold produces one alert rather than one per transaction. Alerts are delivered
+//! through a [`NotificationBus`] that fans out to any number of [`AlertSink`]s.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::types::{Account, ClientId, DEFAULT_SCALE, Money};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertRule {
+    /// Available balance fell below `threshold`.
+    BalanceBelow { threshold: Money },
+    /// Available balance dropped by more than `amount` from its highest point in this run.
+    DropExceeding { amount: Money },
+}
+
+impl AlertRule {
+    /// The rule in words, with amounts of `scale` decimal places.
+    pub fn describe(&self, scale: u32) -> String {
+        match self {
+            AlertRule::BalanceBelow { threshold } => {
+                format!("balance below {}", threshold.format(scale))
+            }
+            AlertRule::DropExceeding { amount } => {
+                format!("drop exceeding {}", amount.format(scale))
+            }
+        }
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe(DEFAULT_SCALE))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    pub client: ClientId,
+    pub rule: AlertRule,
+    /// Available balance when the alert fired.
+    pub available: Money,
+    /// Highest available balance seen for the client in this run.
+    pub peak: Money,
+    /// Decimal places of the amounts.
+    pub scale: u32,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {}: {} (available {}, peak {})",
+            self.client,
+            self.rule.describe(self.scale),
+            self.available.format(self.scale),
+            self.peak.format(self.scale)
+        )
+    }
+}
+
+impl Alert {
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"client":{},"rule":"{}","available":"{}","peak":"{}"}}"#,
+            self.client,
+            self.rule.describe(self.scale),
+            self.available.format(self.scale),
+            self.peak.format(self.scale)
+        )
+    }
+}
+
+/// Destination for alerts.
+pub trait AlertSink {
+    fn notify(&mut self, alert: &Alert) -> io::Result<()>;
+}
+
+/// Writes one line per alert, e.g. to stderr.
+pub struct LogSink<W> {
+    writer: W,
+}
+
+impl<W: Write> LogSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> AlertSink for LogSink<W> {
+    fn notify(&mut self, alert: &Alert) -> io::Result<()> {
+        writeln!(self.writer, "ALERT {}", alert)
+    }
+}
+
+/// Logs each alert as a warning through the `log` facade.
+pub struct LoggerSink;
+
+impl AlertSink for LoggerSink {
+    fn notify(&mut self, alert: &Alert) -> io::Result<()> {
+        log::warn!("ALERT {}", alert);
+        Ok(())
+    }
+}
+
+/// POSTs each alert as JSON to a plain `http://` endpoint.
+pub struct WebhookSink {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid webhook url '{}'", url),
+            )
+        };
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            timeout: Duration::from_secs(5),
+        })
+    }
+}
+
+impl AlertSink for WebhookSink {
+    fn notify(&mut self, alert: &Alert) -> io::Result<()> {
+        let body = alert.to_json();
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status = response.split_whitespace().nth(1).unwrap_or("");
+        if !status.starts_with('2') {
+            return Err(io::Error::other(format!(
+                "webhook responded with status '{}'",
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Fans alerts out to every registered sink.
+#[derive(Default)]
+pub struct NotificationBus {
+    sinks: Vec<Box<dyn AlertSink>>,
+}
+
+impl NotificationBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_sink(&mut self, sink: impl AlertSink + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Delivers to all sinks, returning the errors of those that failed.
+    pub fn publish(&mut self, alert: &Alert) -> Vec<io::Error> {
+        self.sinks
+            .iter_mut()
+            .filter_map(|sink| sink.notify(alert).err())
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientState {
+    peak: Money,
+    /// Per rule, whether the alert has fired and not yet cleared.
+    firing: Vec<bool>,
+}
+
+pub struct AlertMonitor {
+    rules: Vec<AlertRule>,
+    bus: NotificationBus,
+    clients: HashMap<ClientId, ClientState>,
+    scale: u32,
+}
+
+impl AlertMonitor {
+    pub fn new(rules: Vec<AlertRule>, bus: NotificationBus) -> Self {
+        Self {
+            rules,
+            bus,
+            clients: HashMap::new(),
+            scale: DEFAULT_SCALE,
+        }
+    }
+
+    /// Balances and rule amounts have `scale` decimal places, as in an engine configured
+    /// with `EngineConfig::with_scale`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Evaluates the rules against the client's current account state, publishing any
+    /// newly triggered alerts. Returns the alerts raised and any sink delivery errors.
+    pub fn observe(&mut self, client: ClientId, account: &Account) -> (Vec<Alert>, Vec<io::Error>) {
+        let state = self.clients.entry(client).or_insert_with(|| ClientState {
+            peak: account.available,
+            firing: vec![false; self.rules.len()],
+        });
+        state.peak = state.peak.max(account.available);
+
+        let mut alerts = Vec::new();
+        for (rule, firing) in self.rules.iter().zip(state.firing.iter_mut()) {
+            let holds = match *rule {
+                AlertRule::BalanceBelow { threshold } => account.available < threshold,
+                AlertRule::DropExceeding { amount } => {
+                    state.peak.saturating_sub(account.available) > amount
+                }
+            };
+            if holds && !*firing {
+                alerts.push(Alert {
+                    client,
+                    rule: *rule,
+                    available: account.available,
+                    peak: state.peak,
+                    scale: self.scale,
+                });
+            }
+            *firing = holds;
+        }
+
+        let errors = alerts
+            .iter()
+            .flat_map(|alert| self.bus.publish(alert))
+            .collect();
+        (alerts, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SCALE;
+
+    fn account(available: i64) -> Account {
+        Account {
+            available: Money::from_units(available * SCALE),
+            ..Account::default()
+        }
+    }
+
+    #[test]
+    fn test_balance_below_is_edge_triggered() {
+        let rules = vec![AlertRule::BalanceBelow {
+            threshold: Money::from_units(10 * SCALE),
+        }];
+        let mut monitor = AlertMonitor::new(rules, NotificationBus::new());
+
+        assert!(monitor.observe(1, &account(20)).0.is_empty());
+        assert_eq!(monitor.observe(1, &account(5)).0.len(), 1);
+        assert!(monitor.observe(1, &account(4)).0.is_empty());
+        assert!(monitor.observe(1, &account(15)).0.is_empty());
+        assert_eq!(monitor.observe(1, &account(3)).0.len(), 1);
+    }
+
+    #[test]
+    fn test_drop_from_peak() {
+        let rules = vec![AlertRule::DropExceeding {
+            amount: Money::from_units(50 * SCALE),
+        }];
+        let mut monitor = AlertMonitor::new(rules, NotificationBus::new());
+
+        monitor.observe(1, &account(10));
+        monitor.observe(1, &account(100));
+        assert!(monitor.observe(1, &account(60)).0.is_empty());
+        let (alerts, _) = monitor.observe(1, &account(40));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].peak, Money::from_units(100 * SCALE));
+        // Other clients are tracked independently.
+        assert!(monitor.observe(2, &account(1)).0.is_empty());
+    }
+
+    #[test]
+    fn test_webhook_url_parsing() {
+        let sink = WebhookSink::new("http://alerts.internal:8080/hooks/treasury").unwrap();
+        assert_eq!(sink.host, "alerts.internal");
+        assert_eq!(sink.port, 8080);
+        assert_eq!(sink.path, "/hooks/treasury");
+        assert!(WebhookSink::new("https://example.com").is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Storage for dormant accounts moved out of memory.
+//!
+//! [`Engine::archive_dormant`](crate::Engine::archive_dormant) hands each dormant account,
+//! together with the deposits it owns, to an [`AccountArchive`] and forgets it. When the
+//! client shows up again the engine takes it back from the archive before applying the
+//! transaction, so archival is invisible apart from the memory it saves.
+//!
+//! [`FileArchive`] appends records to a side file and keeps only an index of where each
+//! client's latest record starts. Records are plain text, one line per account followed by
+//! one line per deposit, withdrawal or transfer it made, with amounts as raw fixed-point integers so
+//! nothing is rounded:
+//!
+//! ```text
+//! A,<client>,<available>,<held>,<reserved>,<locked>,<deposits>[,closed]
+//! T,<tx>,<amount>,<none|disputed|chargedback>,<timestamp>,<deposit|withdrawal|transfer>[,<to_client>]
+//! ```
+//!
+//! The timestamp is empty for a transaction without one. Only a transfer line names a
+//! recipient, and only a closed account's line ends in `closed`. Funds on hold are not
+//! recorded: the engine keeps holds in memory and adds them back up on restore.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::types::{Account, ClientId, DisputeState, Money, StoredKind, StoredTransaction, TxId};
+
+/// A dormant account and the deposits it owns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedAccount {
+    pub client: ClientId,
+    pub account: Account,
+    pub transactions: Vec<(TxId, StoredTransaction)>,
+}
+
+pub trait AccountArchive: Send {
+    fn store(&mut self, archived: ArchivedAccount) -> io::Result<()>;
+
+    /// Removes and returns the archived state of `client`, if any.
+    fn take(&mut self, client: ClientId) -> io::Result<Option<ArchivedAccount>>;
+}
+
+/// Append-only archive in a file (or any seekable stream). Taking an account only drops it
+/// from the index; its record stays in the file.
+pub struct FileArchive<F = File> {
+    file: F,
+    index: HashMap<ClientId, u64>,
+}
+
+impl FileArchive<File> {
+    /// Creates (or truncates) the archive file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<F: Read + Write + Seek> FileArchive<F> {
+    /// Archive in `file`, which is assumed empty.
+    pub fn new(file: F) -> Self {
+        Self {
+            file,
+            index: HashMap::new(),
+        }
+    }
+
+    /// Number of accounts currently archived.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+}
+
+impl<F: Read + Write + Seek + Send> AccountArchive for FileArchive<F> {
+    fn store(&mut self, archived: ArchivedAccount) -> io::Result<()> {
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&mut self.file);
+        write_record(&mut writer, &archived)?;
+        writer.flush()?;
+        self.index.insert(archived.client, offset);
+        Ok(())
+    }
+
+    fn take(&mut self, client: ClientId) -> io::Result<Option<ArchivedAccount>> {
+        let Some(offset) = self.index.remove(&client) else {
+            return Ok(None);
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut lines = BufReader::new(&mut self.file).lines();
+        let header = lines
+            .next()
+            .unwrap_or_else(|| Err(corrupt("truncated record")))?;
+        read_record(&header, &mut lines).map(Some)
+    }
+}
+
+/// Writes `archived` as an account line followed by one line per transaction.
+pub(crate) fn write_record<W: Write>(writer: &mut W, archived: &ArchivedAccount) -> io::Result<()> {
+    let account = &archived.account;
+    writeln!(
+        writer,
+        "A,{},{},{},{},{},{}{}",
+        archived.client,
+        account.available.units(),
+        account.held.units(),
+        account.reserved.units(),
+        account.locked,
+        archived.transactions.len(),
+        if account.closed { ",closed" } else { "" }
+    )?;
+    for (tx, stored) in &archived.transactions {
+        let state = match stored.dispute_state {
+            DisputeState::None => "none",
+            DisputeState::Disputed => "disputed",
+            DisputeState::ChargedBack => "chargedback",
+        };
+        let kind = match stored.kind {
+            StoredKind::Deposit => "deposit".to_string(),
+            StoredKind::Withdrawal => "withdrawal".to_string(),
+            StoredKind::Transfer { to_client } => format!("transfer,{}", to_client),
+        };
+        let timestamp = stored.timestamp.map(|t| t.to_string()).unwrap_or_default();
+        writeln!(
+            writer,
+            "T,{},{},{},{},{}",
+            tx,
+            stored.amount.units(),
+            state,
+            timestamp,
+            kind
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads the record starting with account line `header`, taking its transaction lines from
+/// `lines`.
+pub(crate) fn read_record(
+    header: &str,
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+) -> io::Result<ArchivedAccount> {
+    let mut next = || -> io::Result<Vec<String>> {
+        let line = lines
+            .next()
+            .unwrap_or_else(|| Err(corrupt("truncated record")))?;
+        Ok(line.split(',').map(str::to_string).collect())
+    };
+
+    let header: Vec<&str> = header.split(',').collect();
+    let (fields, closed) = match header.split_last() {
+        Some((&"closed", fields)) => (fields, true),
+        _ => (header.as_slice(), false),
+    };
+    let ["A", client, available, held, reserved, locked, count] = fields else {
+        return Err(corrupt("bad account line"));
+    };
+    let client: ClientId = parse(client)?;
+    let account = Account {
+        available: parse_money(available)?,
+        held: parse_money(held)?,
+        reserved: parse_money(reserved)?,
+        on_hold: Money::ZERO,
+        locked: parse(locked)?,
+        closed,
+    };
+    let count: usize = parse(count)?;
+    let mut transactions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let fields = next()?;
+        let (tag, tx, amount, state, timestamp, kind) = match fields.as_slice() {
+            [tag, tx, amount, state, timestamp, kind] => match kind.as_str() {
+                "deposit" => (tag, tx, amount, state, timestamp, StoredKind::Deposit),
+                "withdrawal" => (tag, tx, amount, state, timestamp, StoredKind::Withdrawal),
+                _ => return Err(corrupt("bad transaction kind")),
+            },
+            [tag, tx, amount, state, timestamp, kind, to] if kind == "transfer" => {
+                let kind = StoredKind::Transfer {
+                    to_client: parse(to)?,
+                };
+                (tag, tx, amount, state, timestamp, kind)
+            }
+            _ => return Err(corrupt("bad transaction line")),
+        };
+        let dispute_state = match state.as_str() {
+            "none" => DisputeState::None,
+            "disputed" => DisputeState::Disputed,
+            "chargedback" => DisputeState::ChargedBack,
+            _ => return Err(corrupt("bad dispute state")),
+        };
+        if tag != "T" {
+            return Err(corrupt("bad deposit line"));
+        }
+        let stored = StoredTransaction {
+            client,
+            amount: parse_money(amount)?,
+            dispute_state,
+            kind,
+            timestamp: match timestamp.as_str() {
+                "" => None,
+                timestamp => Some(parse(timestamp)?),
+            },
+        };
+        transactions.push((parse(tx)?, stored));
+    }
+    Ok(ArchivedAccount {
+        client,
+        account,
+        transactions,
+    })
+}
+
+fn parse<T: std::str::FromStr>(field: &str) -> io::Result<T> {
+    field.parse().map_err(|_| corrupt("bad number"))
+}
+
+/// Parses an amount stored as a count of minor units.
+fn parse_money(field: &str) -> io::Result<Money> {
+    parse(field).map(Money::from_units)
+}
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt account record: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_store_and_take_round_trip() {
+        let mut archive = FileArchive::new(Cursor::new(Vec::new()));
+        let first = ArchivedAccount {
+            client: 7,
+            account: Account {
+                available: Money::from_units(12_345),
+                held: Money::from_units(-1),
+                reserved: Money::ZERO,
+                on_hold: Money::ZERO,
+                locked: true,
+                closed: false,
+            },
+            transactions: vec![
+                (
+                    3,
+                    StoredTransaction {
+                        client: 7,
+                        amount: Money::from_units(99),
+                        dispute_state: DisputeState::Disputed,
+                        kind: StoredKind::Deposit,
+                        timestamp: Some(86_400),
+                    },
+                ),
+                (
+                    4,
+                    StoredTransaction {
+                        client: 7,
+                        amount: Money::from_units(5),
+                        dispute_state: DisputeState::None,
+                        kind: StoredKind::Transfer { to_client: 9 },
+                        timestamp: None,
+                    },
+                ),
+            ],
+        };
+        let second = ArchivedAccount {
+            client: 8,
+            account: Account {
+                closed: true,
+                ..Account::default()
+            },
+            transactions: Vec::new(),
+        };
+        archive.store(first.clone()).unwrap();
+        archive.store(second.clone()).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        assert_eq!(archive.take(7).unwrap(), Some(first));
+        assert_eq!(archive.take(7).unwrap(), None);
+        assert_eq!(archive.take(8).unwrap(), Some(second));
+        assert!(archive.is_empty());
+
+        let text = String::from_utf8(archive.into_inner().into_inner()).unwrap();
+        assert_eq!(
+            text,
+            "A,7,12345,-1,0,true,2\nT,3,99,disputed,86400,deposit\nT,4,5,none,,transfer,9\nA,8,0,0,0,false,0,closed\n"
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//! Transaction input from Apache Parquet files, and the account report as Arrow record
+//! batches or Parquet (feature `arrow`).
+//!
+//! [`ParquetReader`] reads transactions a record batch at a time, so files larger than
+//! memory stream through. Columns are found by name, as in a CSV header: `type`, `client`,
+//! `tx` and `amount` are required, `timestamp` and `to_client` optional, and any others
+//! ignored. Column types are converted as needed: the type may be any string column, the
+//! ids any integer column, the timestamp an integer in seconds or an Arrow timestamp of any
+//! unit, and the amount a decimal, integer, float or string. Floats are read through their
+//! shortest round-trip text, so `703.2999` stays exactly that.
+//!
+//! [`accounts_batch`] turns `v1` report rows into a record batch, with amounts as
+//! `Decimal128` at the engine's scale, and [`write_parquet`] writes one as a Parquet file.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::vec;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Int64Type, UInt64Type};
+use arrow_array::{
+    Array, ArrayRef, BooleanArray, Decimal128Array, Int64Array, RecordBatch, StringArray,
+    UInt64Array,
+};
+use arrow_cast::cast;
+use arrow_schema::{ArrowError, DataType, Field, Schema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::errors::ParquetError;
+use rust_decimal::Decimal;
+
+use crate::types::{
+    AccountActivity, AccountOutput, ClientId, DEFAULT_SCALE, Transaction, TransactionType,
+};
+
+/// Rows decoded at a time.
+const BATCH_ROWS: usize = 8192;
+
+/// Digits of a `Decimal128` amount column: enough for any balance in minor units.
+const AMOUNT_PRECISION: u8 = 19;
+
+#[derive(Debug)]
+pub enum ArrowIoError {
+    Io(io::Error),
+    Parquet(ParquetError),
+    Arrow(ArrowError),
+    MissingColumn(&'static str),
+    /// A record could not be converted. `row` is 1-based, counted from the start of the file.
+    InvalidRow {
+        row: u64,
+        message: String,
+    },
+}
+
+impl fmt::Display for ArrowIoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArrowIoError::Io(e) => write!(f, "{}", e),
+            ArrowIoError::Parquet(e) => write!(f, "failed to read parquet file: {}", e),
+            ArrowIoError::Arrow(e) => write!(f, "{}", e),
+            ArrowIoError::MissingColumn(name) => write!(f, "missing column '{}'", name),
+            ArrowIoError::InvalidRow { row, message } => write!(f, "row {}: {}", row, message),
+        }
+    }
+}
+
+impl Error for ArrowIoError {}
+
+impl From<io::Error> for ArrowIoError {
+    fn from(e: io::Error) -> Self {
+        ArrowIoError::Io(e)
+    }
+}
+
+impl From<ParquetError> for ArrowIoError {
+    fn from(e: ParquetError) -> Self {
+        ArrowIoError::Parquet(e)
+    }
+}
+
+impl From<ArrowError> for ArrowIoError {
+    fn from(e: ArrowError) -> Self {
+        ArrowIoError::Arrow(e)
+    }
+}
+
+/// Transactions read from a Parquet file, in file order. Stops after the first error.
+pub struct ParquetReader {
+    batches: ParquetRecordBatchReader,
+    /// What is left of the latest batch; an error, if any, comes last.
+    current: vec::IntoIter<Result<Transaction, ArrowIoError>>,
+    /// Rows in the batches before the current one.
+    rows: u64,
+    failed: bool,
+}
+
+impl ParquetReader {
+    /// Opens the Parquet file at `path`, failing if it lacks a required column.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ArrowIoError> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+        for name in ["type", "client", "tx", "amount"] {
+            if builder.schema().index_of(name).is_err() {
+                return Err(ArrowIoError::MissingColumn(name));
+            }
+        }
+        Ok(Self {
+            batches: builder.with_batch_size(BATCH_ROWS).build()?,
+            current: Vec::new().into_iter(),
+            rows: 0,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for ParquetReader {
+    type Item = Result<Transaction, ArrowIoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(result) = self.current.next() {
+                self.failed |= result.is_err();
+                return Some(result);
+            }
+            if self.failed {
+                return None;
+            }
+            let batch = match self.batches.next()? {
+                Ok(batch) => batch,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            self.current = transactions(&batch, self.rows).into_iter();
+            self.rows += batch.num_rows() as u64;
+        }
+    }
+}
+
+/// The transactions of `batch`, whose first row is row `before + 1` of the file, up to and
+/// including the first that can't be converted.
+fn transactions(batch: &RecordBatch, before: u64) -> Vec<Result<Transaction, ArrowIoError>> {
+    let columns = match Columns::of(batch) {
+        Ok(columns) => columns,
+        Err(e) => return vec![Err(e)],
+    };
+    let mut transactions = Vec::with_capacity(batch.num_rows());
+    for index in 0..batch.num_rows() {
+        let result = columns
+            .transaction(index)
+            .map_err(|message| ArrowIoError::InvalidRow {
+                row: before + index as u64 + 1,
+                message,
+            });
+        let failed = result.is_err();
+        transactions.push(result);
+        if failed {
+            break;
+        }
+    }
+    transactions
+}
+
+/// The columns of a batch, converted to the types transactions are read from.
+struct Columns {
+    tx_type: StringArray,
+    client: UInt64Array,
+    tx: UInt64Array,
+    amount: Amounts,
+    /// Raw values, and how many of them make a second.
+    timestamp: Option<(Int64Array, i64)>,
+    /// Converted, and as read, to tell nulls from ids out of range.
+    to_client: Option<(UInt64Array, ArrayRef)>,
+}
+
+enum Amounts {
+    /// Values and their scale.
+    Decimal(Decimal128Array, u32),
+    Text(StringArray),
+}
+
+impl Columns {
+    fn of(batch: &RecordBatch) -> Result<Self, ArrowIoError> {
+        let column = |name: &'static str| {
+            batch
+                .column_by_name(name)
+                .ok_or(ArrowIoError::MissingColumn(name))
+        };
+        let id = |column: &ArrayRef| -> Result<UInt64Array, ArrowError> {
+            Ok(cast(column, &DataType::UInt64)?
+                .as_primitive::<UInt64Type>()
+                .clone())
+        };
+        let amount = column("amount")?;
+        let amount = match amount.data_type() {
+            DataType::Decimal128(_, scale) if (0..=28).contains(scale) => {
+                Amounts::Decimal(amount.as_primitive().clone(), *scale as u32)
+            }
+            _ => Amounts::Text(cast(amount, &DataType::Utf8)?.as_string::<i32>().clone()),
+        };
+        let timestamp = match batch.column_by_name("timestamp") {
+            Some(column) => {
+                let per_second = match column.data_type() {
+                    DataType::Timestamp(TimeUnit::Millisecond, _) => 1_000,
+                    DataType::Timestamp(TimeUnit::Microsecond, _) => 1_000_000,
+                    DataType::Timestamp(TimeUnit::Nanosecond, _) => 1_000_000_000,
+                    _ => 1,
+                };
+                let values = cast(column, &DataType::Int64)?;
+                Some((values.as_primitive::<Int64Type>().clone(), per_second))
+            }
+            None => None,
+        };
+        Ok(Self {
+            tx_type: cast(column("type")?, &DataType::Utf8)?
+                .as_string::<i32>()
+                .clone(),
+            client: id(column("client")?)?,
+            tx: id(column("tx")?)?,
+            amount,
+            timestamp,
+            to_client: match batch.column_by_name("to_client") {
+                Some(column) => Some((id(column)?, column.clone())),
+                None => None,
+            },
+        })
+    }
+
+    /// Row `index` as a transaction. Casting turned out-of-range ids into nulls, so a null
+    /// is reported as an invalid value.
+    fn transaction(&self, index: usize) -> Result<Transaction, String> {
+        let valid = |array: &dyn Array| array.is_valid(index);
+        if !valid(&self.tx_type) {
+            return Err("missing transaction type".to_string());
+        }
+        let tx_type =
+            TransactionType::from_str(self.tx_type.value(index)).map_err(|e| e.to_string())?;
+        if !valid(&self.client) {
+            return Err("invalid client id".to_string());
+        }
+        if !valid(&self.tx) {
+            return Err("invalid transaction id".to_string());
+        }
+        let amount = match &self.amount {
+            Amounts::Decimal(values, _) if !values.is_valid(index) => None,
+            Amounts::Decimal(values, scale) => Some(
+                Decimal::try_from_i128_with_scale(values.value(index), *scale)
+                    .map_err(|e| format!("invalid amount: {}", e))?,
+            ),
+            Amounts::Text(values) if !values.is_valid(index) => None,
+            Amounts::Text(values) => match values.value(index).trim() {
+                "" => None,
+                text => Some(
+                    Decimal::from_str(text)
+                        .map_err(|e| format!("invalid amount '{}': {}", text, e))?,
+                ),
+            },
+        };
+        let timestamp = match &self.timestamp {
+            Some((values, _)) if !values.is_valid(index) => None,
+            Some((values, per_second)) => Some(
+                u64::try_from(values.value(index) / per_second)
+                    .map_err(|_| "invalid timestamp".to_string())?,
+            ),
+            None => None,
+        };
+        let to_client = match &self.to_client {
+            Some((values, _)) if values.is_valid(index) => {
+                Some(ClientId::from(values.value(index)))
+            }
+            Some((_, original)) if original.is_valid(index) => {
+                return Err("invalid recipient id".to_string());
+            }
+            _ => None,
+        };
+        Ok(Transaction {
+            tx_type,
+            client: ClientId::from(self.client.value(index)),
+            tx: self.tx.value(index),
+            amount,
+            timestamp,
+            to_client,
+            metadata: Default::default(),
+        })
+    }
+}
+
+/// `v1` report rows as a record batch: `client` (`UInt64`), `available`, `held` and
+/// `total` (`Decimal128` at the rows' scale) and `locked` (`Boolean`), then `reserved`,
+/// `last_activity` (`UInt64`), `closed` and `overdrawn` when the rows have them, and the
+/// activity columns of extended output: `transactions` and `open_disputes` (`UInt64`),
+/// `deposited` and `withdrawn`, `last_tx` (`UInt64`) and `last_tx_timestamp` (`UInt64`).
+/// Fails for a client id too large for a `UInt64`.
+pub fn accounts_batch(accounts: &[AccountOutput]) -> Result<RecordBatch, ArrowError> {
+    let first = accounts.first();
+    let scale = first.map_or(DEFAULT_SCALE, |account| account.scale);
+    let amount_type = DataType::Decimal128(AMOUNT_PRECISION, scale as i8);
+    let amounts = |values: Vec<Option<i64>>| -> Result<ArrayRef, ArrowError> {
+        let values: Decimal128Array = values.into_iter().map(|v| v.map(i128::from)).collect();
+        Ok(Arc::new(
+            values.with_precision_and_scale(AMOUNT_PRECISION, scale as i8)?,
+        ))
+    };
+
+    let mut fields = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    let clients = accounts
+        .iter()
+        .map(|a| {
+            u64::try_from(a.client).map_err(|_| {
+                ArrowError::InvalidArgumentError(format!(
+                    "client {} doesn't fit a UInt64 column",
+                    a.client
+                ))
+            })
+        })
+        .collect::<Result<UInt64Array, _>>()?;
+    fields.push(Field::new("client", DataType::UInt64, false));
+    columns.push(Arc::new(clients));
+    for (name, value) in [
+        (
+            "available",
+            (|a: &AccountOutput| a.available) as fn(&AccountOutput) -> _,
+        ),
+        ("held", |a| a.held),
+        ("total", |a| a.total),
+    ] {
+        fields.push(Field::new(name, amount_type.clone(), false));
+        columns.push(amounts(
+            accounts.iter().map(|a| Some(value(a).units())).collect(),
+        )?);
+    }
+    fields.push(Field::new("locked", DataType::Boolean, false));
+    columns.push(Arc::new(
+        accounts
+            .iter()
+            .map(|a| Some(a.locked))
+            .collect::<BooleanArray>(),
+    ));
+    if first.is_some_and(|a| a.reserved.is_some()) {
+        fields.push(Field::new("reserved", amount_type.clone(), true));
+        columns.push(amounts(
+            accounts
+                .iter()
+                .map(|a| a.reserved.map(|r| r.units()))
+                .collect(),
+        )?);
+    }
+    if first.is_some_and(|a| a.last_activity.is_some()) {
+        fields.push(Field::new("last_activity", DataType::UInt64, true));
+        columns.push(Arc::new(
+            accounts
+                .iter()
+                .map(|a| a.last_activity)
+                .collect::<UInt64Array>(),
+        ));
+    }
+    if first.is_some_and(|a| a.closed.is_some()) {
+        fields.push(Field::new("closed", DataType::Boolean, true));
+        columns.push(Arc::new(
+            accounts.iter().map(|a| a.closed).collect::<BooleanArray>(),
+        ));
+        fields.push(Field::new("overdrawn", DataType::Boolean, true));
+        columns.push(Arc::new(
+            accounts
+                .iter()
+                .map(|a| a.overdrawn)
+                .collect::<BooleanArray>(),
+        ));
+    }
+    if first.is_some_and(|a| a.activity.is_some()) {
+        let activity = |a: &AccountOutput| a.activity.unwrap_or_default();
+        for (name, value) in [
+            (
+                "transactions",
+                (|a: &AccountActivity| Some(a.transactions)) as fn(&AccountActivity) -> _,
+            ),
+            ("open_disputes", |a| Some(a.open_disputes)),
+        ] {
+            fields.push(Field::new(name, DataType::UInt64, true));
+            columns.push(Arc::new(
+                accounts
+                    .iter()
+                    .map(|a| value(&activity(a)))
+                    .collect::<UInt64Array>(),
+            ));
+        }
+        for (name, value) in [
+            (
+                "deposited",
+                (|a: &AccountActivity| a.deposited) as fn(&AccountActivity) -> _,
+            ),
+            ("withdrawn", |a| a.withdrawn),
+        ] {
+            fields.push(Field::new(name, amount_type.clone(), true));
+            columns.push(amounts(
+                accounts
+                    .iter()
+                    .map(|a| Some(value(&activity(a)).units()))
+                    .collect(),
+            )?);
+        }
+        fields.push(Field::new("last_tx", DataType::UInt64, true));
+        columns.push(Arc::new(
+            accounts
+                .iter()
+                .map(|a| activity(a).last_tx)
+                .collect::<UInt64Array>(),
+        ));
+        fields.push(Field::new("last_tx_timestamp", DataType::UInt64, true));
+        columns.push(Arc::new(
+            accounts
+                .iter()
+                .map(|a| activity(a).last_timestamp)
+                .collect::<UInt64Array>(),
+        ));
+    }
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+/// Writes `batch` to `writer` as a Parquet file.
+pub fn write_parquet<W: Write + Send>(batch: &RecordBatch, writer: W) -> Result<(), ArrowIoError> {
+    let mut writer = ArrowWriter::try_new(writer, batch.schema(), None)?;
+    writer.write(batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ClientId, Money};
+    use rust_decimal_macros::dec;
+
+    fn clients(batch: &RecordBatch) -> Vec<ClientId> {
+        batch
+            .column(0)
+            .as_primitive::<UInt64Type>()
+            .values()
+            .iter()
+            .map(|&client| ClientId::from(client))
+            .collect()
+    }
+
+    #[test]
+    fn test_reads_transactions_and_writes_accounts() {
+        let dir = std::env::temp_dir().join(format!("tx-engine-arrow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("transactions.parquet");
+
+        // Amounts as floats and text, a millisecond timestamp column and wider id types.
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::Int64, false),
+            Field::new("tx", DataType::Int64, false),
+            Field::new("amount", DataType::Float64, true),
+            Field::new(
+                "timestamp",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                true,
+            ),
+            Field::new("to_client", DataType::Int32, true),
+            Field::new("memo", DataType::Utf8, true),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec![
+                "deposit",
+                "transfer",
+                "dispute",
+                "withdrawal",
+            ])),
+            Arc::new(Int64Array::from(vec![1, 1, 1, -1])),
+            Arc::new(Int64Array::from(vec![1, 2, 1, 3])),
+            Arc::new(arrow_array::Float64Array::from(vec![
+                Some(703.2999),
+                Some(1.5),
+                None,
+                Some(1.0),
+            ])),
+            Arc::new(arrow_array::TimestampMillisecondArray::from(vec![
+                Some(1_700_000_000_000),
+                None,
+                Some(1_700_000_100_500),
+                None,
+            ])),
+            Arc::new(arrow_array::Int32Array::from(vec![
+                None,
+                Some(2),
+                None,
+                None,
+            ])),
+            Arc::new(StringArray::from(vec![Some("first"), None, None, None])),
+        ];
+        let batch = RecordBatch::try_new(schema, columns).unwrap();
+        write_parquet(&batch, File::create(&path).unwrap()).unwrap();
+
+        let results: Vec<_> = ParquetReader::open(&path).unwrap().collect();
+        assert_eq!(results.len(), 4);
+        let read: Vec<Transaction> = results[..3]
+            .iter()
+            .map(|r| r.as_ref().unwrap().clone())
+            .collect();
+        assert_eq!(read[0].amount, Some(dec!(703.2999)));
+        assert_eq!(read[0].timestamp, Some(1_700_000_000));
+        assert_eq!(read[1].tx_type, TransactionType::Transfer);
+        assert_eq!(read[1].to_client, Some(2));
+        assert_eq!(read[2].amount, None);
+        assert_eq!(read[2].timestamp, Some(1_700_000_100));
+        // Client -1 isn't a client id.
+        assert!(matches!(
+            results[3],
+            Err(ArrowIoError::InvalidRow { row: 4, .. })
+        ));
+
+        let accounts = vec![
+            AccountOutput {
+                client: 2,
+                available: Money::from_units(15_000),
+                held: Money::ZERO,
+                total: Money::from_units(15_000),
+                locked: false,
+                reserved: None,
+                last_activity: Some(1_700_000_000),
+                closed: None,
+                overdrawn: None,
+                activity: None,
+                scale: 4,
+            },
+            AccountOutput {
+                client: 1,
+                available: Money::from_units(-5),
+                held: Money::from_units(7_032_999),
+                total: Money::from_units(7_032_994),
+                locked: true,
+                reserved: None,
+                last_activity: Some(0),
+                closed: None,
+                overdrawn: None,
+                activity: None,
+                scale: 4,
+            },
+        ];
+        let batch = accounts_batch(&accounts).unwrap();
+        let names: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "client",
+                "available",
+                "held",
+                "total",
+                "locked",
+                "last_activity"
+            ]
+        );
+        assert_eq!(clients(&batch), [2, 1]);
+        let mut wide = accounts.clone();
+        wide[0].client = 1 << 64;
+        assert!(accounts_batch(&wide).is_err());
+        let held = batch
+            .column(2)
+            .as_primitive::<arrow_array::types::Decimal128Type>();
+        assert_eq!(held.value_as_string(1), "703.2999");
+
+        let report = dir.join("accounts.parquet");
+        write_parquet(&batch, File::create(&report).unwrap()).unwrap();
+        let reread = ParquetRecordBatchReaderBuilder::try_new(File::open(&report).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reread, batch);
+
+        assert!(matches!(
+            ParquetReader::open(&report),
+            Err(ArrowIoError::MissingColumn("type"))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
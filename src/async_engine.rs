@@ -0,0 +1,193 @@
+//! Async front end for an [`Engine`] (feature `tokio`).
+//!
+//! [`AsyncEngine`] moves the engine to a thread of its own and sends it commands over a
+//! bounded tokio channel, so an async service, such as a Kafka consumer, can feed it
+//! without blocking the runtime. Commands are applied in the order they are sent, by that
+//! one thread, so transactions from several tasks interleave exactly as they were queued.
+//! When the engine falls behind, sending waits, which pushes back on the producer.
+
+use std::error::Error;
+use std::fmt;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::engine::Engine;
+use crate::io::ProcessSummary;
+use crate::outcome::{ProcessError, ProcessOutcome};
+use crate::types::Transaction;
+
+/// Commands queued before sending waits for the engine to catch up.
+const QUEUED: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncError {
+    /// The engine rejected the transaction.
+    Rejected(ProcessError),
+    /// The engine thread panicked, so the engine is lost.
+    Stopped,
+}
+
+impl fmt::Display for AsyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsyncError::Rejected(error) => write!(f, "transaction rejected: {}", error),
+            AsyncError::Stopped => f.write_str("engine thread has stopped"),
+        }
+    }
+}
+
+impl Error for AsyncError {}
+
+type Job = Box<dyn FnOnce(&mut Engine) + Send>;
+
+enum Command {
+    /// A transaction whose result is added to a running summary.
+    Tally(Transaction, Arc<Mutex<ProcessSummary>>),
+    Run(Job),
+}
+
+/// An [`Engine`] on its own thread, driven from async code.
+pub struct AsyncEngine {
+    commands: mpsc::Sender<Command>,
+    stopped: oneshot::Receiver<Engine>,
+}
+
+impl AsyncEngine {
+    /// Starts a thread running `engine`. Needs no runtime; the thread only waits on the
+    /// channel.
+    pub fn new(mut engine: Engine) -> Self {
+        let (commands, mut receiver) = mpsc::channel(QUEUED);
+        let (done, stopped) = oneshot::channel();
+        thread::spawn(move || {
+            while let Some(command) = receiver.blocking_recv() {
+                match command {
+                    Command::Tally(tx, summary) => {
+                        let result = engine.process(tx);
+                        let mut summary = summary.lock().expect("summary is never poisoned");
+                        match result {
+                            Ok(_) => summary.applied += 1,
+                            Err(error) => summary.ignored.record(error),
+                        }
+                    }
+                    Command::Run(job) => job(&mut engine),
+                }
+            }
+            // Nobody may be waiting for the engine any more.
+            let _ = done.send(engine);
+        });
+        Self { commands, stopped }
+    }
+
+    /// Runs `f` on the engine once every command sent before it is done, returning its
+    /// result, e.g. `engine.call(|engine| engine.account(1).copied())`.
+    pub async fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Engine) -> R + Send + 'static,
+    ) -> Result<R, AsyncError> {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |engine| {
+            // The caller may have given up waiting.
+            let _ = reply.send(f(engine));
+        });
+        self.send(Command::Run(job)).await?;
+        result.await.map_err(|_| AsyncError::Stopped)
+    }
+
+    /// Processes one transaction and waits for its result.
+    pub async fn process(&self, tx: Transaction) -> Result<ProcessOutcome, AsyncError> {
+        self.call(move |engine| engine.process(tx))
+            .await?
+            .map_err(AsyncError::Rejected)
+    }
+
+    /// Processes every transaction from `stream`, without waiting for each result, and
+    /// returns how many were applied and why the others were rejected once the last one is
+    /// done. Rejections don't stop the stream.
+    pub async fn process_stream(
+        &self,
+        stream: impl Stream<Item = Transaction>,
+    ) -> Result<ProcessSummary, AsyncError> {
+        let summary = Arc::new(Mutex::new(ProcessSummary::default()));
+        let mut stream = pin!(stream);
+        while let Some(tx) = stream.next().await {
+            self.send(Command::Tally(tx, Arc::clone(&summary))).await?;
+        }
+        // Commands run in order, so this waits for the last transaction.
+        self.call(|_| ()).await?;
+        let summary = summary.lock().expect("summary is never poisoned");
+        Ok(summary.clone())
+    }
+
+    /// Waits for every queued command and hands the engine back.
+    pub async fn shutdown(self) -> Result<Engine, AsyncError> {
+        drop(self.commands);
+        self.stopped.await.map_err(|_| AsyncError::Stopped)
+    }
+
+    async fn send(&self, command: Command) -> Result<(), AsyncError> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| AsyncError::Stopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Money, TransactionType, TxId};
+    use rust_decimal_macros::dec;
+
+    fn tx(
+        tx_type: TransactionType,
+        tx: TxId,
+        amount: Option<rust_decimal::Decimal>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_stream_and_queries() {
+        let engine = AsyncEngine::new(Engine::new());
+        let stream = tokio_stream::iter([
+            tx(TransactionType::Deposit, 1, Some(dec!(10.0))),
+            tx(TransactionType::Withdrawal, 2, Some(dec!(50.0))),
+            tx(TransactionType::Withdrawal, 3, Some(dec!(4.0))),
+        ]);
+        let summary = engine.process_stream(stream).await.unwrap();
+        assert_eq!(summary.applied, 2);
+        assert_eq!(summary.ignored.get(ProcessError::InsufficientFunds), 1);
+
+        assert_eq!(
+            engine
+                .process(tx(TransactionType::Dispute, 9, None))
+                .await
+                .unwrap_err(),
+            AsyncError::Rejected(ProcessError::UnknownTransaction)
+        );
+        engine
+            .process(tx(TransactionType::Dispute, 1, None))
+            .await
+            .unwrap();
+        let held = engine
+            .call(|engine| engine.account(1).map(|account| account.held))
+            .await
+            .unwrap();
+        assert_eq!(held, Some(Money::from_units(100_000)));
+
+        let engine = engine.shutdown().await.unwrap();
+        assert_eq!(engine.stats().processed, 5);
+    }
+}
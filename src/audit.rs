@@ -0,0 +1,250 @@
+//! Audit trail of account changes, delivered to sinks registered with
+//! [`Engine::on_audit`](crate::Engine::on_audit).
+//!
+//! Every transaction the engine processes yields an [`AuditEvent`] for its client, with the
+//! account before and after, including rejected transactions (which leave it unchanged).
+//! Other accounts it changes, such as a transfer's recipient, get one too. Changes the
+//! engine makes on its own (reserve releases, expired disputes, automatic unlocks, client
+//! merges) are recorded as well, so a client's events, in order, reconstruct the history of
+//! its account. Manual operations from [`Engine::apply_admin`](crate::Engine::apply_admin)
+//! are recorded with their reason. Like engine events, the records of a batch are only delivered once it
+//! commits. A transaction turned away before it reaches the accounts (memory limit,
+//! write-ahead log or archive failure) has no record.
+//!
+//! [`AuditLog`] writes the records as CSV, as `--audit-log` does.
+
+use std::fmt;
+#[cfg(feature = "io")]
+use std::io::{self, Write};
+
+#[cfg(feature = "io")]
+use serde::Serialize;
+
+use crate::admin::AdminOp;
+use crate::outcome::ProcessError;
+#[cfg(feature = "io")]
+use crate::types::DEFAULT_SCALE;
+use crate::types::{Account, ClientId, Metadata, Money, TransactionType, TxId};
+
+/// Callback receiving audit records. Sinks must be `Send` so the engine can move between
+/// threads.
+pub type AuditSink = Box<dyn FnMut(&AuditEvent) + Send>;
+
+/// What changed an account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditAction {
+    Applied(TransactionType),
+    /// The account is unchanged.
+    Rejected(TransactionType, ProcessError),
+    /// Funds withheld by the rolling reserve became available.
+    ReserveReleased,
+    /// A dispute passed its expiry and was resolved automatically.
+    DisputeExpired,
+    /// The cooldown after the last chargeback elapsed.
+    Unlocked,
+    /// Client `from` was merged into client `into`; recorded for both accounts.
+    Merged {
+        from: ClientId,
+        into: ClientId,
+    },
+    /// A manual operation, successful or not.
+    Admin(AdminOp),
+    /// Interest was credited to the available balance.
+    InterestAccrued,
+    /// `Engine::undo_last` undid the transaction, or a committed batch without one.
+    Undone,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditAction::Applied(tx_type) => write!(f, "{} applied", tx_type),
+            AuditAction::Rejected(tx_type, error) => {
+                write!(f, "{} rejected: {}", tx_type, error.code())
+            }
+            AuditAction::ReserveReleased => f.write_str("reserve released"),
+            AuditAction::DisputeExpired => f.write_str("dispute expired"),
+            AuditAction::Unlocked => f.write_str("unlocked"),
+            AuditAction::Merged { from, into } => {
+                write!(f, "client {} merged into client {}", from, into)
+            }
+            AuditAction::Admin(op) => write!(f, "admin {}", op),
+            AuditAction::InterestAccrued => f.write_str("interest accrued"),
+            AuditAction::Undone => f.write_str("undone"),
+        }
+    }
+}
+
+/// One change to one account. A client without an account appears with zero balances.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// The transaction processed or, for a reserve release or expired dispute, the one the
+    /// engine acted on. `None` for unlocks, merges, manual operations and interest.
+    pub tx: Option<TxId>,
+    pub client: ClientId,
+    pub action: AuditAction,
+    pub before: Account,
+    pub after: Account,
+    /// The [metadata](crate::Engine::metadata) of transaction `tx`, if it was stored with
+    /// any; for a dispute, resolve or chargeback, that of the transaction it refers to.
+    pub metadata: Metadata,
+}
+
+impl AuditEvent {
+    /// Whether this change locked the account.
+    pub fn locked(&self) -> bool {
+        !self.before.locked && self.after.locked
+    }
+}
+
+/// A change to a client's available or held balance, as streamed by
+/// [`Engine::changes`](crate::Engine::changes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub client: ClientId,
+    /// Amount added to the available balance; negative if it went down.
+    pub delta_available: Money,
+    /// Amount added to the held balance; negative if it went down.
+    pub delta_held: Money,
+    /// What made the change, as in the audit trail.
+    pub cause: AuditAction,
+    /// The transaction involved, as in [`AuditEvent::tx`].
+    pub tx: Option<TxId>,
+}
+
+impl BalanceChange {
+    /// The change recorded by `event`, or `None` if it left both balances as they were.
+    pub fn from_audit(event: &AuditEvent) -> Option<Self> {
+        let delta_available = event.after.available.saturating_sub(event.before.available);
+        let delta_held = event.after.held.saturating_sub(event.before.held);
+        if delta_available.is_zero() && delta_held.is_zero() {
+            return None;
+        }
+        Some(BalanceChange {
+            client: event.client,
+            delta_available,
+            delta_held,
+            cause: event.action.clone(),
+            tx: event.tx,
+        })
+    }
+}
+
+#[cfg(feature = "io")]
+#[derive(Serialize)]
+struct Row {
+    tx: Option<TxId>,
+    client: ClientId,
+    action: String,
+    available_before: String,
+    held_before: String,
+    reserved_before: String,
+    on_hold_before: String,
+    locked_before: bool,
+    available: String,
+    held: String,
+    reserved: String,
+    on_hold: String,
+    locked: bool,
+}
+
+#[cfg(feature = "io")]
+/// Writes audit records as CSV, one row per record with the balances before and after.
+pub struct AuditLog<W: Write> {
+    writer: csv::Writer<W>,
+    scale: u32,
+}
+
+#[cfg(feature = "io")]
+impl<W: Write> AuditLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+            scale: DEFAULT_SCALE,
+        }
+    }
+
+    /// Balances have `scale` decimal places, as in an engine configured with
+    /// `EngineConfig::with_scale`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn record(&mut self, event: &AuditEvent) -> io::Result<()> {
+        let (before, after) = (&event.before, &event.after);
+        let amount = |value: Money| value.format(self.scale);
+        self.writer.serialize(Row {
+            tx: event.tx,
+            client: event.client,
+            action: event.action.to_string(),
+            available_before: amount(before.available),
+            held_before: amount(before.held),
+            reserved_before: amount(before.reserved),
+            on_hold_before: amount(before.on_hold),
+            locked_before: before.locked,
+            available: amount(after.available),
+            held: amount(after.held),
+            reserved: amount(after.reserved),
+            on_hold: amount(after.on_hold),
+            locked: after.locked,
+        })?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_writes_balances_before_and_after() {
+        let mut log = AuditLog::new(Vec::new());
+        log.record(&AuditEvent {
+            tx: Some(4),
+            client: 1,
+            action: AuditAction::Applied(TransactionType::Chargeback),
+            before: Account {
+                available: Money::from_units(10_000),
+                held: Money::from_units(25_000),
+                ..Account::default()
+            },
+            after: Account {
+                available: Money::from_units(10_000),
+                locked: true,
+                ..Account::default()
+            },
+            metadata: Metadata::new(),
+        })
+        .unwrap();
+        log.record(&AuditEvent {
+            tx: None,
+            client: 1,
+            action: AuditAction::Unlocked,
+            before: Account {
+                available: Money::from_units(10_000),
+                locked: true,
+                ..Account::default()
+            },
+            after: Account {
+                available: Money::from_units(10_000),
+                ..Account::default()
+            },
+            metadata: Metadata::new(),
+        })
+        .unwrap();
+        log.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(log.writer.into_inner().unwrap()).unwrap(),
+            "tx,client,action,available_before,held_before,reserved_before,on_hold_before,\
+             locked_before,available,held,reserved,on_hold,locked\n\
+             4,1,chargeback applied,1.0000,2.5000,0.0000,0.0000,false,1.0000,0.0000,0.0000,\
+             0.0000,true\n\
+             ,1,unlocked,1.0000,0.0000,0.0000,0.0000,true,1.0000,0.0000,0.0000,0.0000,false\n"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Case records of open disputes, kept with
+//! [`EngineConfig::with_dispute_cases`](crate::EngineConfig::with_dispute_cases).
+//!
+//! Each accepted dispute opens a [`DisputeCase`] numbered from 1 in the order disputes were
+//! opened, with the time it was opened, the funds it holds and the reason code the dispute
+//! came with, if any: its `reason` [metadata](crate::Transaction::metadata), which
+//! [`CsvReader`](crate::io::CsvReader) fills from the `reason` column of a dispute row. A
+//! resolve or chargeback closes the case and drops it, and a dispute raised again afterwards
+//! opens a new one. A dispute that expires under `ExpiryAction::Escalate` stays open as
+//! [`CaseStatus::Escalated`].
+//!
+//! [`Engine::open_disputes`](crate::Engine::open_disputes) lists a client's open cases and
+//! [`write_disputes`] writes every open case as a CSV report.
+
+use std::fmt;
+#[cfg(feature = "io")]
+use std::io::Write;
+
+#[cfg(feature = "io")]
+use crate::engine::Engine;
+use crate::types::{ClientId, Money, TxId};
+
+/// Metadata key of a dispute's reason code.
+pub const REASON_KEY: &str = "reason";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseStatus {
+    /// Waiting for a resolve or chargeback.
+    Open,
+    /// Expired under `ExpiryAction::Escalate`; the funds stay held pending review.
+    Escalated,
+}
+
+impl fmt::Display for CaseStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CaseStatus::Open => "open",
+            CaseStatus::Escalated => "escalated",
+        })
+    }
+}
+
+/// An open dispute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisputeCase {
+    pub id: u64,
+    /// The client who raised the dispute.
+    pub client: ClientId,
+    /// The disputed transaction.
+    pub tx: TxId,
+    /// Funds the dispute holds, less than the transaction's amount when only part of them
+    /// was left to hold.
+    pub amount: Money,
+    /// The dispute's timestamp, or the engine's time when it had none.
+    pub opened_at: u64,
+    pub reason: Option<String>,
+    pub status: CaseStatus,
+}
+
+#[cfg(feature = "io")]
+const DISPUTE_COLUMNS: [&str; 7] = [
+    "case",
+    "client",
+    "tx",
+    "amount",
+    "opened_at",
+    "reason",
+    "status",
+];
+
+#[cfg(feature = "io")]
+/// Writes every open dispute case of `engine` as CSV in case order, with amounts of the
+/// engine's scale. Returns the number of rows; the header is written even when there are
+/// none.
+pub fn write_disputes<W: Write>(engine: &Engine, writer: W) -> csv::Result<usize> {
+    let scale = engine.config().precision.scale;
+    let mut cases: Vec<&DisputeCase> = engine.dispute_cases().collect();
+    cases.sort_unstable_by_key(|case| case.id);
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(DISPUTE_COLUMNS)?;
+    for case in &cases {
+        writer.write_record([
+            case.id.to_string(),
+            case.client.to_string(),
+            case.tx.to_string(),
+            case.amount.format(scale),
+            case.opened_at.to_string(),
+            case.reason.clone().unwrap_or_default(),
+            case.status.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(cases.len())
+}
+
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use super::*;
+    use crate::config::{DisputeExpiry, EngineConfig, ExpiryAction};
+    use crate::types::{Transaction, TransactionType};
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    fn tx(tx_type: TransactionType, client: ClientId, tx: TxId, timestamp: u64) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: None,
+            timestamp: Some(timestamp),
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn deposit(client: ClientId, id: TxId) -> Transaction {
+        Transaction {
+            amount: Some(dec!(5.0)),
+            ..tx(TransactionType::Deposit, client, id, 0)
+        }
+    }
+
+    #[test]
+    fn test_cases_open_and_close() {
+        let expiry = DisputeExpiry {
+            after: Duration::from_secs(100),
+            action: ExpiryAction::Escalate,
+        };
+        let config = EngineConfig::default()
+            .with_dispute_cases()
+            .with_dispute_expiry(expiry);
+        let mut engine = Engine::with_config(config);
+        for (client, id) in [(1, 1), (1, 2), (2, 3)] {
+            engine.process(deposit(client, id)).unwrap();
+        }
+        let mut fraud = tx(TransactionType::Dispute, 1, 1, 10);
+        fraud
+            .metadata
+            .insert(REASON_KEY.to_string(), "fraud".to_string());
+        engine.process(fraud).unwrap();
+        engine
+            .process(tx(TransactionType::Dispute, 2, 3, 20))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Dispute, 1, 2, 30))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Resolve, 2, 3, 40))
+            .unwrap();
+
+        // A rolled-back dispute opens no case and uses no case id.
+        engine.begin_batch();
+        engine
+            .process(tx(TransactionType::Dispute, 2, 3, 50))
+            .unwrap();
+        engine.rollback_batch();
+        // The first dispute expires at 110.
+        engine
+            .process(tx(TransactionType::Chargeback, 1, 2, 120))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Dispute, 2, 3, 130))
+            .unwrap();
+
+        let open: Vec<_> = engine
+            .open_disputes(1)
+            .iter()
+            .map(|case| (case.id, case.tx, case.status, case.reason.as_deref()))
+            .collect();
+        assert_eq!(open, [(1, 1, CaseStatus::Escalated, Some("fraud"))]);
+        let reopened = engine.open_disputes(2)[0];
+        assert_eq!((reopened.id, reopened.opened_at), (4, 130));
+
+        let mut out = Vec::new();
+        assert_eq!(write_disputes(&engine, &mut out).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "case,client,tx,amount,opened_at,reason,status\n\
+             1,1,1,5.0000,10,fraud,escalated\n\
+             4,2,3,5.0000,130,,open\n"
+        );
+    }
+}
@@ -0,0 +1,621 @@
+//! Checkpoints, so a long batch job can resume after a crash instead of starting over.
+//!
+//! [`Engine::write_snapshot`] writes the engine's state, together with a position: how many
+//! transactions of the input the state covers. [`Engine::restore_snapshot`] loads it into a
+//! new engine, after which the caller skips that many transactions of the input and carries
+//! on. [`Checkpointer`] writes a snapshot to a directory every so many transactions, keeping
+//! only the latest, and resumes from it.
+//!
+//! A snapshot holds the accounts with their stored transactions, in the
+//! [archive](crate::archive) record format, followed by pending timers and the bookkeeping
+//! of the enabled policies, one line each:
+//!
+//! ```text
+//! S,1,<position>,<seq>,<now>,<saturations>
+//! A,<client>,<available>,<held>,<reserved>,<locked>,<transactions>[,closed]
+//! T,<tx>,<amount>,<none|disputed|chargedback>,<timestamp>,<deposit|withdrawal|transfer>[,<to_client>]
+//! R,<due>,<client>,<tx>,<amount>           pending reserve release
+//! U,<due>,<client>,<locked_at>             pending automatic unlock
+//! X,<due>,<client>,<tx>,<opened_at>        pending dispute expiry
+//! Q,<due>,<client>,<tx>,<type>,<amount>[,<to_client>]   post-dated transaction
+//! C,<client>,<time>                        latest chargeback
+//! O,<tx>,<time>                            open dispute
+//! P,<tx>,<amount>                          partial hold
+//! M,<from>,<into>                          merged client
+//! Y,<client>,<time>                        latest activity
+//! D,<client>,<time>,<seq>                  latest activity, for dormancy
+//! I,<tx>,<client>,<type>                   first owner of an id
+//! H,<tx>,<client>,<amount>,<remaining>     hold
+//! V,<client>,<day>,<withdrawn>,<times>     risk-limit counts, times separated by spaces
+//! L,<time>                                 latest interest accrual
+//! W,<client>,<limit>                       overdraft limit set by an operator
+//! K,<first>,<last>,<none|chargedback>      run of compacted transaction ids
+//! end
+//! ```
+//!
+//! An account's funds on hold are not in its record; they are added up from the `H` lines.
+//! Statistics are not part of a snapshot and start again from zero. Engines keeping
+//! transaction history, or with accounts in the archive, can't be checkpointed.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::archive::{self, ArchivedAccount};
+use crate::compaction::CompactedIds;
+use crate::engine::Engine;
+use crate::limits::ClientVelocity;
+use crate::timers::Timer;
+use crate::types::{ClientId, DisputeState, Hold, Money, TransactionType, TxId};
+
+const VERSION: u32 = 1;
+const PREFIX: &str = "checkpoint-";
+const EXTENSION: &str = "snap";
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    /// The snapshot is damaged, truncated or of an unknown version.
+    Corrupt(String),
+    /// The engine uses a feature snapshots don't capture.
+    Unsupported(&'static str),
+    /// A batch is open; snapshots are only taken between batches.
+    InBatch,
+    /// Snapshots can only be restored into a new engine.
+    NotEmpty,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(error) => write!(f, "{}", error),
+            CheckpointError::Corrupt(message) => write!(f, "corrupt snapshot: {}", message),
+            CheckpointError::Unsupported(feature) => {
+                write!(f, "snapshots can't capture {}", feature)
+            }
+            CheckpointError::InBatch => f.write_str("cannot take a snapshot inside a batch"),
+            CheckpointError::NotEmpty => {
+                f.write_str("snapshots can only be restored into a new engine")
+            }
+        }
+    }
+}
+
+impl Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(error: io::Error) -> Self {
+        CheckpointError::Io(error)
+    }
+}
+
+/// Engine state captured by a snapshot.
+#[derive(Debug, Default)]
+pub(crate) struct Snapshot {
+    pub position: u64,
+    pub seq: u64,
+    pub now: u64,
+    pub saturations: u64,
+    pub accounts: Vec<ArchivedAccount>,
+    pub timers: Vec<(u64, Timer)>,
+    pub last_chargeback: HashMap<ClientId, u64>,
+    pub dispute_opened: HashMap<TxId, u64>,
+    pub partial_holds: HashMap<TxId, Money>,
+    pub merged: HashMap<ClientId, ClientId>,
+    pub last_activity: HashMap<ClientId, u64>,
+    pub last_active: HashMap<ClientId, (u64, u64)>,
+    pub id_owners: HashMap<TxId, (ClientId, TransactionType)>,
+    pub holds: HashMap<TxId, Hold>,
+    pub velocity: HashMap<ClientId, ClientVelocity>,
+    pub last_accrual: Option<u64>,
+    pub overdrafts: HashMap<ClientId, Money>,
+    pub compacted: CompactedIds,
+}
+
+impl Engine {
+    /// Writes the engine's state as a snapshot covering the first `position` transactions
+    /// of the input. Fails inside a batch and for the unsupported features listed in the
+    /// [module docs](crate::checkpoint).
+    pub fn write_snapshot<W: Write>(
+        &self,
+        position: u64,
+        mut writer: W,
+    ) -> Result<(), CheckpointError> {
+        let snapshot = self.snapshot(position)?;
+        write(&snapshot, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads a snapshot into this engine, which must be new and configured as the one that
+    /// wrote it, and returns its position. The installed clock, store, archive and sinks
+    /// are kept.
+    pub fn restore_snapshot<R: BufRead>(&mut self, reader: R) -> Result<u64, CheckpointError> {
+        let snapshot = read(reader)?;
+        let position = snapshot.position;
+        self.restore(snapshot)?;
+        Ok(position)
+    }
+}
+
+pub(crate) fn write<W: Write>(snapshot: &Snapshot, writer: &mut W) -> io::Result<()> {
+    writeln!(
+        writer,
+        "S,{},{},{},{},{}",
+        VERSION, snapshot.position, snapshot.seq, snapshot.now, snapshot.saturations
+    )?;
+    for account in &snapshot.accounts {
+        archive::write_record(writer, account)?;
+    }
+    for (due, timer) in &snapshot.timers {
+        match timer {
+            Timer::ReleaseReserve { client, tx, amount } => {
+                writeln!(writer, "R,{},{},{},{}", due, client, tx, amount.units())?
+            }
+            Timer::Unlock { client, locked_at } => {
+                writeln!(writer, "U,{},{},{}", due, client, locked_at)?
+            }
+            Timer::ExpireDispute {
+                client,
+                tx,
+                opened_at,
+            } => writeln!(writer, "X,{},{},{},{}", due, client, tx, opened_at)?,
+            Timer::Settle {
+                client,
+                tx,
+                tx_type,
+                amount,
+                to_client,
+            } => {
+                write!(
+                    writer,
+                    "Q,{},{},{},{},{}",
+                    due,
+                    client,
+                    tx,
+                    tx_type,
+                    amount.units()
+                )?;
+                match to_client {
+                    Some(to_client) => writeln!(writer, ",{}", to_client)?,
+                    None => writeln!(writer)?,
+                }
+            }
+        }
+    }
+    for (client, time) in sorted(&snapshot.last_chargeback) {
+        writeln!(writer, "C,{},{}", client, time)?;
+    }
+    for (tx, time) in sorted(&snapshot.dispute_opened) {
+        writeln!(writer, "O,{},{}", tx, time)?;
+    }
+    for (tx, amount) in sorted(&snapshot.partial_holds) {
+        writeln!(writer, "P,{},{}", tx, amount.units())?;
+    }
+    for (from, into) in sorted(&snapshot.merged) {
+        writeln!(writer, "M,{},{}", from, into)?;
+    }
+    for (client, time) in sorted(&snapshot.last_activity) {
+        writeln!(writer, "Y,{},{}", client, time)?;
+    }
+    for (client, (time, seq)) in sorted(&snapshot.last_active) {
+        writeln!(writer, "D,{},{},{}", client, time, seq)?;
+    }
+    for (tx, (client, tx_type)) in sorted(&snapshot.id_owners) {
+        writeln!(writer, "I,{},{},{}", tx, client, tx_type)?;
+    }
+    for (tx, hold) in sorted(&snapshot.holds) {
+        writeln!(
+            writer,
+            "H,{},{},{},{}",
+            tx,
+            hold.client,
+            hold.amount.units(),
+            hold.remaining.units()
+        )?;
+    }
+    for (client, counts) in sorted(&snapshot.velocity) {
+        let times: Vec<String> = counts.recent.iter().map(u64::to_string).collect();
+        writeln!(
+            writer,
+            "V,{},{},{},{}",
+            client,
+            counts.day,
+            counts.withdrawn.units(),
+            times.join(" ")
+        )?;
+    }
+    if let Some(time) = snapshot.last_accrual {
+        writeln!(writer, "L,{}", time)?;
+    }
+    for (client, limit) in sorted(&snapshot.overdrafts) {
+        writeln!(writer, "W,{},{}", client, limit.units())?;
+    }
+    for (first, last, state) in snapshot.compacted.runs() {
+        let state = match state {
+            DisputeState::ChargedBack => "chargedback",
+            DisputeState::None | DisputeState::Disputed => "none",
+        };
+        writeln!(writer, "K,{},{},{}", first, last, state)?;
+    }
+    writeln!(writer, "end")
+}
+
+/// The entries of `map` in key order, so the same state always gives the same file.
+pub(crate) fn sorted<K: Ord + Copy, V>(map: &HashMap<K, V>) -> Vec<(K, &V)> {
+    let mut entries: Vec<_> = map.iter().map(|(&key, value)| (key, value)).collect();
+    entries.sort_by_key(|&(key, _)| key);
+    entries
+}
+
+pub(crate) fn read<R: BufRead>(reader: R) -> Result<Snapshot, CheckpointError> {
+    let mut lines = reader.lines();
+    let header = lines
+        .next()
+        .transpose()?
+        .ok_or_else(|| corrupt("empty file"))?;
+    let mut snapshot = Snapshot::default();
+    match header.split(',').collect::<Vec<_>>().as_slice() {
+        ["S", version, position, seq, now, saturations] => {
+            if parse::<u32>(version)? != VERSION {
+                return Err(corrupt(&format!("unknown version {}", version)));
+            }
+            snapshot.position = parse(position)?;
+            snapshot.seq = parse(seq)?;
+            snapshot.now = parse(now)?;
+            snapshot.saturations = parse(saturations)?;
+        }
+        _ => return Err(corrupt("missing header")),
+    }
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').collect();
+        match fields.as_slice() {
+            ["A", ..] => snapshot
+                .accounts
+                .push(archive::read_record(&line, &mut lines)?),
+            ["R", due, client, tx, amount] => snapshot.timers.push((
+                parse(due)?,
+                Timer::ReleaseReserve {
+                    client: parse(client)?,
+                    tx: parse(tx)?,
+                    amount: parse_money(amount)?,
+                },
+            )),
+            ["U", due, client, locked_at] => snapshot.timers.push((
+                parse(due)?,
+                Timer::Unlock {
+                    client: parse(client)?,
+                    locked_at: parse(locked_at)?,
+                },
+            )),
+            ["X", due, client, tx, opened_at] => snapshot.timers.push((
+                parse(due)?,
+                Timer::ExpireDispute {
+                    client: parse(client)?,
+                    tx: parse(tx)?,
+                    opened_at: parse(opened_at)?,
+                },
+            )),
+            ["Q", due, client, tx, tx_type, amount, to_client @ ..] if to_client.len() <= 1 => {
+                snapshot.timers.push((
+                    parse(due)?,
+                    Timer::Settle {
+                        client: parse(client)?,
+                        tx: parse(tx)?,
+                        tx_type: parse(tx_type)?,
+                        amount: parse_money(amount)?,
+                        to_client: to_client.first().map(|to| parse(to)).transpose()?,
+                    },
+                ))
+            }
+            ["C", client, time] => {
+                snapshot
+                    .last_chargeback
+                    .insert(parse(client)?, parse(time)?);
+            }
+            ["O", tx, time] => {
+                snapshot.dispute_opened.insert(parse(tx)?, parse(time)?);
+            }
+            ["P", tx, amount] => {
+                snapshot
+                    .partial_holds
+                    .insert(parse(tx)?, parse_money(amount)?);
+            }
+            ["M", from, into] => {
+                snapshot.merged.insert(parse(from)?, parse(into)?);
+            }
+            ["Y", client, time] => {
+                snapshot.last_activity.insert(parse(client)?, parse(time)?);
+            }
+            ["D", client, time, seq] => {
+                let active = (parse(time)?, parse(seq)?);
+                snapshot.last_active.insert(parse(client)?, active);
+            }
+            ["I", tx, client, tx_type] => {
+                let owner = (parse(client)?, parse(tx_type)?);
+                snapshot.id_owners.insert(parse(tx)?, owner);
+            }
+            ["H", tx, client, amount, remaining] => {
+                let hold = Hold {
+                    client: parse(client)?,
+                    amount: parse_money(amount)?,
+                    remaining: parse_money(remaining)?,
+                };
+                snapshot.holds.insert(parse(tx)?, hold);
+            }
+            ["V", client, day, withdrawn, times] => {
+                let counts = ClientVelocity {
+                    day: parse(day)?,
+                    withdrawn: parse_money(withdrawn)?,
+                    recent: times
+                        .split_whitespace()
+                        .map(parse)
+                        .collect::<Result<_, _>>()?,
+                };
+                snapshot.velocity.insert(parse(client)?, counts);
+            }
+            ["L", time] => snapshot.last_accrual = Some(parse(time)?),
+            ["W", client, limit] => {
+                snapshot
+                    .overdrafts
+                    .insert(parse(client)?, parse_money(limit)?);
+            }
+            ["K", first, last, state] => {
+                let state = match *state {
+                    "none" => DisputeState::None,
+                    "chargedback" => DisputeState::ChargedBack,
+                    _ => return Err(corrupt("bad dispute state")),
+                };
+                snapshot
+                    .compacted
+                    .insert_run(parse(first)?, parse(last)?, state);
+            }
+            ["end"] => return Ok(snapshot),
+            _ => return Err(corrupt(&format!("bad line '{}'", line))),
+        }
+    }
+    Err(corrupt("truncated"))
+}
+
+fn parse<T: FromStr>(field: &str) -> Result<T, CheckpointError> {
+    field
+        .parse()
+        .map_err(|_| corrupt(&format!("bad value '{}'", field)))
+}
+
+fn parse_money(field: &str) -> Result<Money, CheckpointError> {
+    parse(field).map(Money::from_units)
+}
+
+fn corrupt(message: &str) -> CheckpointError {
+    CheckpointError::Corrupt(message.to_string())
+}
+
+/// Takes a snapshot every `every` transactions into a directory of files named
+/// `checkpoint-<position>.snap`, replacing the previous one.
+pub struct Checkpointer {
+    dir: PathBuf,
+    every: u64,
+    /// Position of the latest checkpoint written or resumed from.
+    last: u64,
+}
+
+impl Checkpointer {
+    /// Checkpoints in `dir`, which is created if needed, every `every` transactions.
+    pub fn new(dir: impl AsRef<Path>, every: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            every: every.max(1),
+            last: 0,
+        })
+    }
+
+    /// Restores `engine`, which must be new, from the latest checkpoint in the directory and
+    /// returns its position: the number of input transactions to skip. Returns 0 and leaves
+    /// the engine alone if there is no checkpoint yet.
+    pub fn resume(&mut self, engine: &mut Engine) -> Result<u64, CheckpointError> {
+        let Some((_, path)) = checkpoints(&self.dir)?.pop() else {
+            return Ok(0);
+        };
+        self.last = engine.restore_snapshot(BufReader::new(File::open(path)?))?;
+        Ok(self.last)
+    }
+
+    /// Takes a checkpoint at `position` if `every` transactions went by since the last one,
+    /// returning whether it did. One falling due inside a batch is taken on the first call
+    /// after the batch ends.
+    pub fn maybe_checkpoint(
+        &mut self,
+        engine: &Engine,
+        position: u64,
+    ) -> Result<bool, CheckpointError> {
+        if position < self.last.saturating_add(self.every) {
+            return Ok(false);
+        }
+        match self.checkpoint(engine, position) {
+            Ok(_) => Ok(true),
+            Err(CheckpointError::InBatch) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Takes a checkpoint at `position` now and returns its path. The snapshot is written
+    /// to a temporary file and synced before it replaces the previous checkpoint, so a
+    /// crash while writing leaves the previous one usable.
+    pub fn checkpoint(
+        &mut self,
+        engine: &Engine,
+        position: u64,
+    ) -> Result<PathBuf, CheckpointError> {
+        let path = self
+            .dir
+            .join(format!("{}{:020}.{}", PREFIX, position, EXTENSION));
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        engine.write_snapshot(position, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&temporary, &path)?;
+        for (older, old_path) in checkpoints(&self.dir)? {
+            if older != position {
+                fs::remove_file(old_path)?;
+            }
+        }
+        self.last = position;
+        Ok(path)
+    }
+}
+
+/// The checkpoint files in `dir` with their positions, oldest first.
+fn checkpoints(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let position = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(PREFIX))
+            .and_then(|name| name.strip_suffix(EXTENSION))
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|position| position.parse().ok());
+        if let Some(position) = position {
+            found.push((position, path));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DisputeExpiry, EngineConfig, ExpiryAction};
+    use crate::types::Transaction;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    fn tx(tx_type: TransactionType, client: ClientId, tx: TxId, time: u64) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: matches!(tx_type, TransactionType::Deposit).then_some(dec!(2.5)),
+            timestamp: Some(time),
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn config() -> EngineConfig {
+        EngineConfig::default()
+            .with_dispute_expiry(DisputeExpiry {
+                after: Duration::from_secs(100),
+                action: ExpiryAction::Resolve,
+            })
+            .with_id_collision_detection()
+    }
+
+    fn input() -> Vec<Transaction> {
+        use TransactionType::{Chargeback, Deposit, Dispute};
+        vec![
+            tx(Deposit, 1, 1, 10),
+            tx(Deposit, 2, 2, 20),
+            tx(Dispute, 1, 1, 30),
+            tx(Deposit, 3, 3, 40),
+            tx(Dispute, 2, 2, 50),
+            tx(Chargeback, 2, 2, 60),
+            tx(Deposit, 1, 4, 200),
+        ]
+    }
+
+    #[test]
+    fn test_resumed_run_matches_uninterrupted_run() {
+        let mut whole = Engine::with_config(config());
+        for tx in input() {
+            let _ = whole.process(tx);
+        }
+
+        let mut first = Engine::with_config(config());
+        for tx in input().into_iter().take(4) {
+            let _ = first.process(tx);
+        }
+        let mut snapshot = Vec::new();
+        first.write_snapshot(4, &mut snapshot).unwrap();
+        let text = String::from_utf8(snapshot.clone()).unwrap();
+        assert!(text.starts_with("S,1,4,4,40,0\nA,1,0,25000,0,false,1\n"));
+        assert!(text.contains("\nX,130,1,1,30\n") && text.ends_with("\nend\n"));
+
+        let mut resumed = Engine::with_config(config());
+        let position = resumed.restore_snapshot(snapshot.as_slice()).unwrap();
+        for tx in input().into_iter().skip(position as usize) {
+            let _ = resumed.process(tx);
+        }
+        let accounts = |engine: &Engine| {
+            let mut accounts: Vec<_> = engine.accounts().map(|(c, a)| (c, *a)).collect();
+            accounts.sort_by_key(|&(client, _)| client);
+            accounts
+        };
+        assert_eq!(accounts(&resumed), accounts(&whole));
+        // The dispute expired after the restore, from the restored timer.
+        assert_eq!(
+            resumed.account(1).unwrap().available,
+            Money::from_units(50_000)
+        );
+
+        let mut used = Engine::new();
+        used.process(tx(TransactionType::Deposit, 9, 9, 0)).unwrap();
+        assert!(matches!(
+            used.restore_snapshot(text.as_bytes()),
+            Err(CheckpointError::NotEmpty)
+        ));
+        let truncated = &text[..text.len() - 4];
+        assert!(matches!(
+            Engine::new().restore_snapshot(truncated.as_bytes()),
+            Err(CheckpointError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_checkpointer_keeps_latest() {
+        let dir = std::env::temp_dir().join(format!("tx-checkpoints-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut checkpointer = Checkpointer::new(&dir, 3).unwrap();
+        let mut engine = Engine::with_config(config());
+        for (position, tx) in input().into_iter().enumerate() {
+            let _ = engine.process(tx);
+            checkpointer
+                .maybe_checkpoint(&engine, position as u64 + 1)
+                .unwrap();
+        }
+        let files: Vec<u64> = checkpoints(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(files, [6]);
+
+        let mut resumed = Engine::with_config(config());
+        let position = Checkpointer::new(&dir, 3)
+            .unwrap()
+            .resume(&mut resumed)
+            .unwrap();
+        assert_eq!(position, 6);
+        assert!(resumed.account(2).unwrap().locked);
+
+        engine.begin_batch();
+        assert!(matches!(
+            checkpointer.checkpoint(&engine, 7),
+            Err(CheckpointError::InBatch)
+        ));
+        assert!(!checkpointer.maybe_checkpoint(&engine, 9).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
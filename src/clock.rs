@@ -0,0 +1,74 @@
+//! Time sources for the engine's time-based policies.
+//!
+//! Without a clock the engine only learns the time from transaction timestamps, which keeps
+//! replays of historical files deterministic. With a [`Clock`] installed through
+//! [`Engine::set_clock`](crate::Engine::set_clock), the engine reads it before every
+//! transaction: rows without a timestamp happen at the clock's time, and reserves,
+//! dispute expiries and unlocks fall due as the clock moves. [`SystemClock`] follows the
+//! wall clock for live processing; [`ManualClock`] is moved by hand in tests and replays.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send {
+    /// Current time in seconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// The operating system's wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs())
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a test can keep
+/// one handle and give another to the engine.
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_secs(), Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_handles_share_time() {
+        let clock = ManualClock::new(100);
+        let handle = clock.clone();
+        handle.advance(Duration::from_secs(50));
+        assert_eq!(clock.now(), 150);
+        clock.set(10);
+        assert_eq!(handle.now(), 10);
+        assert!(SystemClock.now() > 1_600_000_000);
+    }
+}
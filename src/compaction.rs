@@ -0,0 +1,170 @@
+//! Compaction of stored transactions that can no longer be disputed, with
+//! [`Engine::compact`](crate::Engine::compact) or automatically under a
+//! [`CompactionPolicy`](crate::config::CompactionPolicy).
+//!
+//! Every accepted deposit, withdrawal and transfer is stored so it can be disputed later,
+//! so a long-running engine keeps growing. Compaction drops the ones that can't be:
+//! undisputed transactions whose dispute window has closed by the engine's time, and, if
+//! the policy says so, charged-back ones. Transactions without a timestamp, and those
+//! currently disputed, are always kept.
+//!
+//! What is left of a compacted transaction is its id and dispute state, kept as runs of
+//! consecutive ids so a steady stream of expiring transactions takes next to no memory.
+//! That is enough to reject what would have been rejected anyway, with the same reason: a
+//! reused id is still a `duplicate_transaction`, a dispute of an expired transaction is
+//! still `dispute_window_closed` and one of a charged-back transaction still
+//! `already_charged_back`. A few things change. The id and state are all that is known, so
+//! a dispute naming another client's transaction gets those reasons rather than
+//! `client_mismatch`. The window is checked against the engine's time when compacting, so
+//! a dispute arriving afterwards with an earlier timestamp, which the ordering policy may
+//! let through, is rejected even if that timestamp was inside the window. And a compacted
+//! chargeback can't be reversed: the reversal is rejected as `transaction_compacted`, and
+//! the chargeback no longer keeps its account locked when another one on it is reversed
+//! with `EngineConfig::unlock_on_reversal`.
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::outcome::ProcessError;
+use crate::types::{DisputeState, TransactionType, TxId};
+
+/// The ids of compacted transactions, with the state they were compacted in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CompactedIds {
+    /// The first id of each run of consecutive ids compacted in the same state, to its last
+    /// id and that state.
+    runs: BTreeMap<TxId, (TxId, DisputeState)>,
+}
+
+impl CompactedIds {
+    /// The state `tx` was compacted in, if it was.
+    pub fn get(&self, tx: TxId) -> Option<DisputeState> {
+        self.runs
+            .range(..=tx)
+            .next_back()
+            .filter(|&(_, &(last, _))| tx <= last)
+            .map(|(_, &(_, state))| state)
+    }
+
+    pub fn insert(&mut self, tx: TxId, state: DisputeState) {
+        self.insert_run(tx, tx, state);
+    }
+
+    /// Adds the ids from `first` to `last`, none of which may be compacted already.
+    pub fn insert_run(&mut self, mut first: TxId, mut last: TxId, state: DisputeState) {
+        let before = first
+            .checked_sub(1)
+            .and_then(|previous| self.runs.range(..=previous).next_back())
+            .filter(|&(_, &(end, run_state))| {
+                end.checked_add(1) == Some(first) && run_state == state
+            })
+            .map(|(&start, _)| start);
+        if let Some(start) = before {
+            self.runs.remove(&start);
+            first = start;
+        }
+        let after = last
+            .checked_add(1)
+            .and_then(|next| self.runs.get(&next).map(|&run| (next, run)))
+            .filter(|&(_, (_, run_state))| run_state == state);
+        if let Some((next, (end, _))) = after {
+            self.runs.remove(&next);
+            last = end;
+        }
+        self.runs.insert(first, (last, state));
+    }
+
+    /// The lowest id from `first` to `last` that is compacted, if any.
+    pub fn first_in(&self, first: TxId, last: TxId) -> Option<TxId> {
+        let containing = self
+            .runs
+            .range(..=first)
+            .next_back()
+            .filter(|&(_, &(end, _))| end >= first)
+            .map(|_| first);
+        containing.or_else(|| {
+            self.runs
+                .range(first..=last)
+                .next()
+                .map(|(&start, _)| start)
+        })
+    }
+
+    /// Every run as its first id, last id and state, in id order.
+    pub fn runs(&self) -> impl Iterator<Item = (TxId, TxId, DisputeState)> + '_ {
+        self.runs
+            .iter()
+            .map(|(&first, &(last, state))| (first, last, state))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Approximate bytes of memory the runs take.
+    pub fn memory_usage(&self) -> usize {
+        // A B-tree node holds up to 11 entries; count it as about one pointer per entry.
+        self.runs.len() * (mem::size_of::<(TxId, (TxId, DisputeState))>() + mem::size_of::<usize>())
+    }
+}
+
+/// Why a `tx_type` referring to a transaction compacted in `state` is rejected: the reason
+/// it would have been rejected for had the transaction been kept.
+pub(crate) fn rejection(tx_type: TransactionType, state: DisputeState) -> ProcessError {
+    match (tx_type, state) {
+        (TransactionType::Dispute, DisputeState::ChargedBack) => ProcessError::AlreadyChargedBack,
+        (TransactionType::Dispute, _) => ProcessError::DisputeWindowClosed,
+        (TransactionType::ChargebackReversal, DisputeState::ChargedBack) => {
+            ProcessError::TransactionCompacted
+        }
+        (TransactionType::ChargebackReversal, _) => ProcessError::NotChargedBack,
+        (TransactionType::Resolve | TransactionType::Chargeback, _) => ProcessError::NotDisputed,
+        (
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Hold,
+            _,
+        ) => ProcessError::DuplicateTransaction,
+        // Releases refer to holds, which are never compacted.
+        (TransactionType::Release, _) => ProcessError::UnknownTransaction,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_join_and_split_by_state() {
+        let mut ids = CompactedIds::default();
+        for tx in [3, 1, 2, 5] {
+            ids.insert(tx, DisputeState::None);
+        }
+        ids.insert(4, DisputeState::ChargedBack);
+        ids.insert(6, DisputeState::None);
+        assert_eq!(
+            ids.runs().collect::<Vec<_>>(),
+            [
+                (1, 3, DisputeState::None),
+                (4, 4, DisputeState::ChargedBack),
+                (5, 6, DisputeState::None),
+            ]
+        );
+        assert_eq!(ids.get(2), Some(DisputeState::None));
+        assert_eq!(ids.get(4), Some(DisputeState::ChargedBack));
+        assert_eq!(ids.get(7), None);
+        assert_eq!(ids.get(0), None);
+
+        assert_eq!(ids.first_in(0, 10), Some(1));
+        assert_eq!(ids.first_in(5, 5), Some(5));
+        assert_eq!(ids.first_in(7, 10), None);
+        ids.insert_run(10, 20, DisputeState::None);
+        assert_eq!(ids.first_in(7, 12), Some(10));
+
+        ids.insert(TxId::MAX, DisputeState::None);
+        ids.insert(0, DisputeState::None);
+        assert_eq!(ids.get(TxId::MAX), Some(DisputeState::None));
+        assert_eq!(ids.runs().next(), Some((0, 3, DisputeState::None)));
+    }
+}
@@ -0,0 +1,161 @@
+//! Transparent decompression of gzip and zstd input.
+//!
+//! Compressed input is recognised by its magic bytes, so it works the same for files and
+//! stdin. Decoding needs the `gzip` or `zstd` feature; without it a compressed input is an
+//! error rather than being parsed as garbage.
+
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How an input is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Recognises the compression from the first bytes of an input.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else {
+            Compression::None
+        }
+    }
+
+    /// The compression implied by a `.gz` or `.zst` extension.
+    pub fn from_path(path: &str) -> Compression {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => Compression::Gzip,
+            Some(ext) if ext.eq_ignore_ascii_case("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// The path without a `.gz` or `.zst` extension, so `accounts.csv.gz` is recognised as CSV.
+pub fn strip_extension(path: &str) -> &str {
+    match Compression::from_path(path) {
+        Compression::None => path,
+        _ => &path[..path.rfind('.').unwrap_or(path.len())],
+    }
+}
+
+/// Wraps `reader` in a decoder if its content is compressed, or returns it as it is.
+pub fn decompress<'a, R: Read + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let header = &header[..filled];
+    let input = Cursor::new(header.to_vec()).chain(reader);
+    match Compression::detect(header) {
+        Compression::None => Ok(Box::new(input)),
+        Compression::Gzip => gzip(input),
+        Compression::Zstd => zstd(input),
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip<'a, R: Read + 'a>(input: R) -> io::Result<Box<dyn Read + 'a>> {
+    // Multi-member, as `cat a.gz b.gz` and parallel compressors produce.
+    Ok(Box::new(flate2::read::MultiGzDecoder::new(input)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip<'a, R: Read + 'a>(_input: R) -> io::Result<Box<dyn Read + 'a>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "gzip input requires building with the `gzip` feature",
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd<'a, R: Read + 'a>(input: R) -> io::Result<Box<dyn Read + 'a>> {
+    let decoder = ruzstd::decoding::StreamingDecoder::new(input)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Box::new(decoder))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd<'a, R: Read + 'a>(_input: R) -> io::Result<Box<dyn Read + 'a>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd input requires building with the `zstd` feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+    fn read_all(input: &[u8]) -> io::Result<String> {
+        let mut text = String::new();
+        decompress(input)?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    #[test]
+    fn test_detection() {
+        assert_eq!(Compression::detect(b"type,client"), Compression::None);
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 8, 0]), Compression::Gzip);
+        assert_eq!(Compression::detect(&ZSTD_MAGIC), Compression::Zstd);
+        assert_eq!(Compression::detect(b""), Compression::None);
+        assert_eq!(Compression::from_path("logs/tx.csv.GZ"), Compression::Gzip);
+        assert_eq!(strip_extension("logs/tx.csv.zst"), "logs/tx.csv");
+        assert_eq!(strip_extension("logs/tx.csv"), "logs/tx.csv");
+    }
+
+    #[test]
+    fn test_uncompressed_passes_through() {
+        assert_eq!(read_all(CSV.as_bytes()).unwrap(), CSV);
+        assert_eq!(read_all(b"ab").unwrap(), "ab");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        use flate2::{Compression as Level, write::GzEncoder};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(CSV.as_bytes()).unwrap();
+        let member = encoder.finish().unwrap();
+        assert_eq!(read_all(&member).unwrap(), CSV);
+
+        let concatenated = [member.as_slice(), member.as_slice()].concat();
+        assert_eq!(read_all(&concatenated).unwrap(), CSV.repeat(2));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() {
+        use ruzstd::encoding::{CompressionLevel, compress_to_vec};
+
+        let compressed = compress_to_vec(CSV.as_bytes(), CompressionLevel::Fastest);
+        assert_eq!(read_all(&compressed).unwrap(), CSV);
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    #[test]
+    fn test_gzip_needs_feature() {
+        let error = read_all(&[0x1f, 0x8b, 8, 0]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
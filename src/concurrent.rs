@@ -0,0 +1,229 @@
+//! A handle that feeds one [`Engine`] from many threads.
+//!
+//! [`ConcurrentEngine`] moves the engine to a thread of its own and sends it commands over a
+//! bounded queue. The handle is cheap to clone and can be given to every producer, such as
+//! the handler of each socket; commands are applied one at a time, in the order they were
+//! queued, so transactions from several producers interleave exactly as they arrived. When
+//! the engine falls behind and the queue is full, [`ConcurrentEngine::submit`] waits, which
+//! pushes back on the producers instead of buffering without bound.
+//!
+//! This is the blocking counterpart of [`AsyncEngine`](crate::async_engine::AsyncEngine),
+//! for threads rather than async tasks.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::engine::Engine;
+use crate::ignored::IgnoredCounts;
+use crate::outcome::{ProcessError, ProcessOutcome};
+use crate::types::Transaction;
+
+/// Commands queued before submitting waits for the engine to catch up.
+const QUEUED: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrentError {
+    /// The engine rejected the transaction.
+    Rejected(ProcessError),
+    /// The engine was shut down, or its thread panicked.
+    Stopped,
+}
+
+impl fmt::Display for ConcurrentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConcurrentError::Rejected(error) => write!(f, "transaction rejected: {}", error),
+            ConcurrentError::Stopped => f.write_str("engine has stopped"),
+        }
+    }
+}
+
+impl Error for ConcurrentError {}
+
+/// What became of the transactions given to [`ConcurrentEngine::submit`], by every handle.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SubmitSummary {
+    pub applied: u64,
+    /// Rejected transactions by reason.
+    pub ignored: IgnoredCounts,
+}
+
+type Job = Box<dyn FnOnce(&mut Engine) + Send>;
+
+enum Command {
+    /// A transaction whose result is added to the shared summary.
+    Submit(Transaction),
+    Run(Job),
+    /// Stop after the commands queued before this one.
+    Shutdown,
+}
+
+/// An [`Engine`] on its own thread, fed through a bounded queue by any number of handles.
+#[derive(Clone)]
+pub struct ConcurrentEngine {
+    commands: SyncSender<Command>,
+    summary: Arc<Mutex<SubmitSummary>>,
+    /// The engine thread, taken by the first handle to shut it down.
+    thread: Arc<Mutex<Option<JoinHandle<Engine>>>>,
+}
+
+impl ConcurrentEngine {
+    /// Starts a thread running `engine`, with room for 1024 queued commands.
+    pub fn new(engine: Engine) -> Self {
+        Self::with_capacity(engine, QUEUED)
+    }
+
+    /// Starts a thread running `engine`, with room for `capacity` queued commands (at least
+    /// one) before submitting waits.
+    pub fn with_capacity(mut engine: Engine, capacity: usize) -> Self {
+        let (commands, receiver) = mpsc::sync_channel(capacity.max(1));
+        let summary = Arc::new(Mutex::new(SubmitSummary::default()));
+        let tally = Arc::clone(&summary);
+        let thread = thread::spawn(move || {
+            for command in receiver {
+                match command {
+                    Command::Submit(tx) => {
+                        let result = engine.process(tx);
+                        let mut summary = tally.lock().expect("summary is never poisoned");
+                        match result {
+                            Ok(_) => summary.applied += 1,
+                            Err(error) => summary.ignored.record(error),
+                        }
+                    }
+                    Command::Run(job) => job(&mut engine),
+                    // Dropping the receiver fails every later and waiting submission.
+                    Command::Shutdown => break,
+                }
+            }
+            engine
+        });
+        Self {
+            commands,
+            summary,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        }
+    }
+
+    /// Queues `tx` without waiting for its result, which is added to the summary
+    /// [`ConcurrentEngine::flush`] returns. Waits while the queue is full.
+    pub fn submit(&self, tx: Transaction) -> Result<(), ConcurrentError> {
+        self.send(Command::Submit(tx))
+    }
+
+    /// Runs `f` on the engine once every command queued before it is done, returning its
+    /// result, e.g. `engine.call(|engine| engine.account(1).copied())`.
+    pub fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Engine) -> R + Send + 'static,
+    ) -> Result<R, ConcurrentError> {
+        let (reply, result) = mpsc::sync_channel(1);
+        let job: Job = Box::new(move |engine| {
+            // The caller may have gone away.
+            let _ = reply.send(f(engine));
+        });
+        self.send(Command::Run(job))?;
+        result.recv().map_err(|_| ConcurrentError::Stopped)
+    }
+
+    /// Processes one transaction and waits for its result. It doesn't count towards the
+    /// summary of submitted transactions.
+    pub fn process(&self, tx: Transaction) -> Result<ProcessOutcome, ConcurrentError> {
+        self.call(move |engine| engine.process(tx))?
+            .map_err(ConcurrentError::Rejected)
+    }
+
+    /// Waits until every command queued so far, by any handle, is done, and returns what
+    /// became of all the transactions submitted since the engine started.
+    pub fn flush(&self) -> Result<SubmitSummary, ConcurrentError> {
+        self.call(|_| ())?;
+        Ok(self.summary())
+    }
+
+    /// What became of the submitted transactions applied so far, without waiting for the
+    /// queue.
+    pub fn summary(&self) -> SubmitSummary {
+        self.summary
+            .lock()
+            .expect("summary is never poisoned")
+            .clone()
+    }
+
+    /// Applies every command queued so far and hands the engine back. Afterwards every
+    /// handle's commands fail with [`ConcurrentError::Stopped`], including those waiting
+    /// for room in the queue; only the first handle to shut down gets the engine.
+    pub fn shutdown(self) -> Result<Engine, ConcurrentError> {
+        self.send(Command::Shutdown)?;
+        let thread = self.thread.lock().expect("handle is never poisoned").take();
+        thread
+            .ok_or(ConcurrentError::Stopped)?
+            .join()
+            .map_err(|_| ConcurrentError::Stopped)
+    }
+
+    fn send(&self, command: Command) -> Result<(), ConcurrentError> {
+        self.commands
+            .send(command)
+            .map_err(|_| ConcurrentError::Stopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ClientId, Money, TransactionType, TxId};
+    use rust_decimal_macros::dec;
+
+    fn deposit(client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client,
+            tx,
+            amount: Some(dec!(1.0)),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_submit_from_many_threads() {
+        let engine = ConcurrentEngine::with_capacity(Engine::new(), 4);
+        thread::scope(|scope| {
+            for producer in 0..4 {
+                let engine = engine.clone();
+                scope.spawn(move || {
+                    let first = producer as TxId * 100;
+                    for tx in first..first + 100 {
+                        engine.submit(deposit(producer, tx)).unwrap();
+                    }
+                    // Every producer reuses its first id once.
+                    engine.submit(deposit(producer, first)).unwrap();
+                });
+            }
+        });
+
+        let summary = engine.flush().unwrap();
+        assert_eq!(summary.applied, 400);
+        assert_eq!(summary.ignored.get(ProcessError::DuplicateTransaction), 4);
+        assert_eq!(
+            engine.process(deposit(1, 1)).unwrap_err(),
+            ConcurrentError::Rejected(ProcessError::DuplicateTransaction)
+        );
+        let available = engine
+            .call(|engine| engine.account(3).map(|account| account.available))
+            .unwrap();
+        assert_eq!(available, Some(Money::from_units(1_000_000)));
+
+        let other = engine.clone();
+        let engine = engine.shutdown().unwrap();
+        assert_eq!(engine.stats().processed, 405);
+        assert_eq!(
+            other.submit(deposit(1, 1000)),
+            Err(ConcurrentError::Stopped)
+        );
+        assert!(matches!(other.shutdown(), Err(ConcurrentError::Stopped)));
+    }
+}
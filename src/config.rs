@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+use crate::fees::{FeePolicy, InterestPolicy};
+use crate::limits::RiskLimits;
+use crate::types::{ClientId, DEFAULT_SCALE, Money, Precision, Rounding};
+
+/// Engine behaviour knobs. `EngineConfig::default()` matches the engine's original behaviour.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    pub reserve: Option<ReservePolicy>,
+    pub arithmetic: ArithmeticMode,
+    /// Limits for clients without a tier.
+    pub balance_limits: BalanceLimits,
+    pub tiers: ClientTiers,
+    /// Unlock an account this long after the chargeback that locked it, unless another
+    /// chargeback happened in the meantime. `None` keeps locks permanent.
+    pub auto_unlock: Option<Duration>,
+    /// Unlock an account when a chargeback reversal leaves none of the transactions it holds
+    /// charged back.
+    pub unlock_on_reversal: bool,
+    pub dispute_expiry: Option<DisputeExpiry>,
+    /// How long after a transaction it may still be disputed. Disputes raised later are
+    /// rejected; transactions without a timestamp can always be disputed.
+    pub dispute_window: Option<Duration>,
+    /// What a dispute does when the disputed funds have already left the account.
+    pub dispute_shortfall: DisputeShortfallPolicy,
+    /// Which transactions a locked account still accepts.
+    pub locked: LockedPolicy,
+    /// Remember the id of every deposit and withdrawal and emit an event when one is reused.
+    pub detect_id_collisions: bool,
+    /// Approximate bytes the engine's tables may occupy. Once exceeded, every further
+    /// transaction is rejected with `ProcessError::MemoryLimit`.
+    pub memory_limit: Option<usize>,
+    pub dormancy: Option<DormancyPolicy>,
+    /// What to do with a transaction timestamped before its client's latest one.
+    pub ordering: OrderingPolicy,
+    /// Fill `AccountOutput::last_activity` in reports.
+    pub report_last_activity: bool,
+    /// Fill `AccountOutput::closed`, `AccountOutput::overdrawn` and `AccountOutput::activity`
+    /// in reports. Activity is counted from the engine's start and isn't kept in snapshots.
+    pub extended_output: bool,
+    /// Keep every client's accepted transactions for `Engine::history`.
+    pub keep_history: bool,
+    /// Keep every account change as a domain event for `Engine::events`.
+    pub keep_event_log: bool,
+    /// Keep what the last this many accepted transactions changed, so `Engine::undo_last`
+    /// can undo them. Automatic compaction doesn't run while it is set.
+    pub undo_depth: usize,
+    /// Keep a case record of every open dispute for `Engine::open_disputes`; see
+    /// [`crate::cases`].
+    pub keep_dispute_cases: bool,
+    /// Amount, daily and velocity limits; see [`crate::limits`].
+    pub limits: RiskLimits,
+    /// Decimal places of balances and how input amounts are rounded to them. Amounts in
+    /// the rest of the configuration are minor units at this scale.
+    pub precision: Precision,
+    /// Deposits, withdrawals and transfers of more than this are rejected with
+    /// `AmountError::TooLarge`, whatever the client's limits.
+    pub max_amount: Option<Decimal>,
+    /// How the accounts are held in memory.
+    pub account_storage: AccountStorage,
+    /// Fees charged on withdrawals; see [`crate::fees`].
+    pub fees: Option<FeePolicy>,
+    /// Interest credited by `Engine::accrue_interest`.
+    pub interest: Option<InterestPolicy>,
+    /// Automatic compaction of transactions that can no longer be disputed; see
+    /// [`crate::compaction`].
+    pub compaction: Option<CompactionPolicy>,
+    /// Check the accounts each accepted transaction touched and reject it if it broke an
+    /// invariant; see [`crate::invariants`]. Always on with the `debug-invariants` feature.
+    pub check_invariants: bool,
+}
+
+impl EngineConfig {
+    pub fn with_arithmetic(mut self, arithmetic: ArithmeticMode) -> Self {
+        self.arithmetic = arithmetic;
+        self
+    }
+
+    pub fn with_reserve(mut self, reserve: ReservePolicy) -> Self {
+        self.reserve = Some(reserve);
+        self
+    }
+
+    pub fn with_fees(mut self, fees: FeePolicy) -> Self {
+        self.fees = Some(fees);
+        self
+    }
+
+    pub fn with_interest(mut self, interest: InterestPolicy) -> Self {
+        self.interest = Some(interest);
+        self
+    }
+
+    pub fn with_balance_limits(mut self, limits: BalanceLimits) -> Self {
+        self.balance_limits = limits;
+        self
+    }
+
+    pub fn with_tiers(mut self, tiers: ClientTiers) -> Self {
+        self.tiers = tiers;
+        self
+    }
+
+    pub fn with_auto_unlock(mut self, cooldown: Duration) -> Self {
+        self.auto_unlock = Some(cooldown);
+        self
+    }
+
+    pub fn with_unlock_on_reversal(mut self) -> Self {
+        self.unlock_on_reversal = true;
+        self
+    }
+
+    pub fn with_dispute_expiry(mut self, expiry: DisputeExpiry) -> Self {
+        self.dispute_expiry = Some(expiry);
+        self
+    }
+
+    pub fn with_dispute_window(mut self, window: Duration) -> Self {
+        self.dispute_window = Some(window);
+        self
+    }
+
+    pub fn with_dispute_shortfall(mut self, policy: DisputeShortfallPolicy) -> Self {
+        self.dispute_shortfall = policy;
+        self
+    }
+
+    pub fn with_locked_policy(mut self, policy: LockedPolicy) -> Self {
+        self.locked = policy;
+        self
+    }
+
+    pub fn with_id_collision_detection(mut self) -> Self {
+        self.detect_id_collisions = true;
+        self
+    }
+
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    pub fn with_dormancy(mut self, dormancy: DormancyPolicy) -> Self {
+        self.dormancy = Some(dormancy);
+        self
+    }
+
+    pub fn with_ordering(mut self, ordering: OrderingPolicy) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    pub fn with_last_activity(mut self) -> Self {
+        self.report_last_activity = true;
+        self
+    }
+
+    pub fn with_extended_output(mut self) -> Self {
+        self.extended_output = true;
+        self
+    }
+
+    pub fn with_history(mut self) -> Self {
+        self.keep_history = true;
+        self
+    }
+
+    pub fn with_event_log(mut self) -> Self {
+        self.keep_event_log = true;
+        self
+    }
+
+    pub fn with_undo_depth(mut self, depth: usize) -> Self {
+        self.undo_depth = depth;
+        self
+    }
+
+    pub fn with_dispute_cases(mut self) -> Self {
+        self.keep_dispute_cases = true;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: RiskLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Keeps balances with `scale` decimal places instead of 4.
+    ///
+    /// # Panics
+    ///
+    /// If `scale` is above [`MAX_SCALE`](crate::types::MAX_SCALE).
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.precision = Precision {
+            rounding: self.precision.rounding,
+            ..Precision::new(scale)
+        };
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.precision.rounding = rounding;
+        self
+    }
+
+    pub fn with_account_storage(mut self, storage: AccountStorage) -> Self {
+        self.account_storage = storage;
+        self
+    }
+
+    pub fn with_max_amount(mut self, max: Decimal) -> Self {
+        self.max_amount = Some(max);
+        self
+    }
+
+    pub fn with_compaction(mut self, compaction: CompactionPolicy) -> Self {
+        self.compaction = Some(compaction);
+        self
+    }
+
+    pub fn with_invariant_checks(mut self) -> Self {
+        self.check_invariants = true;
+        self
+    }
+
+    /// The limits that apply to `client`: its tier's, or the engine-wide ones.
+    pub fn balance_limits_for(&self, client: ClientId) -> BalanceLimits {
+        self.tiers.limits_for(client).unwrap_or(self.balance_limits)
+    }
+}
+
+/// Rolling reserve: a share of every deposit is withheld in `Account::reserved` and moved
+/// to available once `period` has elapsed since the deposit's timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReservePolicy {
+    /// Fraction of each deposit to withhold, between 0 and 1 (e.g. `0.1` for 10%).
+    pub rate: Decimal,
+    pub period: Duration,
+}
+
+/// An account is dormant once it has seen no transaction for `after_time`, or while
+/// `after_transactions` transactions of other clients were processed, whichever comes
+/// first. A threshold left `None` is not checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DormancyPolicy {
+    pub after_time: Option<Duration>,
+    pub after_transactions: Option<u64>,
+}
+
+/// Runs `Engine::compact` after every `every` transactions, outside batches. Charged-back
+/// transactions are only compacted with `charged_back`, since their chargebacks can then
+/// no longer be reversed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionPolicy {
+    pub every: u64,
+    pub charged_back: bool,
+}
+
+/// Disputes still open `after` they were raised are handled according to `action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeExpiry {
+    pub after: Duration,
+    pub action: ExpiryAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpiryAction {
+    /// Resolve the dispute, returning the held funds to available.
+    #[default]
+    Resolve,
+    /// Leave the funds held and emit an escalation event for manual follow-up.
+    Escalate,
+}
+
+impl FromStr for ExpiryAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolve" => Ok(ExpiryAction::Resolve),
+            "escalate" => Ok(ExpiryAction::Escalate),
+            _ => Err(format!("unknown dispute expiry action '{}'", s)),
+        }
+    }
+}
+
+/// Handling of disputes of a deposit or transfer whose amount exceeds the available funds of
+/// the account it would be held on, typically because the funds were withdrawn before the
+/// dispute. Disputes of withdrawals don't touch available funds and are never short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisputeShortfallPolicy {
+    /// Hold the full amount, taking available funds negative.
+    #[default]
+    AllowNegative,
+    /// Reject the dispute with `ProcessError::DisputeShortfall`. A deposit's own reserve,
+    /// not yet released, counts toward the available funds.
+    RejectDispute,
+    /// Hold only the funds still available, or nothing if available is already negative.
+    /// Resolving or charging back the dispute releases or removes that partial amount.
+    HoldPartial,
+}
+
+impl FromStr for DisputeShortfallPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow-negative" => Ok(DisputeShortfallPolicy::AllowNegative),
+            "reject" => Ok(DisputeShortfallPolicy::RejectDispute),
+            "hold-partial" => Ok(DisputeShortfallPolicy::HoldPartial),
+            _ => Err(format!("unknown dispute shortfall policy '{}'", s)),
+        }
+    }
+}
+
+/// Transactions a locked account accepts. Whatever the policy, withdrawals and outgoing
+/// transfers are rejected with `ProcessError::AccountLocked`, and chargeback reversals
+/// always go through, since they are what can unlock the account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockedPolicy {
+    /// Reject everything else too, including resolves and chargebacks of disputes opened
+    /// before the lock.
+    RejectAll,
+    /// Accept disputes, resolves, chargebacks, holds and releases, but no deposits or
+    /// incoming transfers.
+    #[default]
+    AllowDisputesOnly,
+    /// Accept deposits and incoming transfers, but no new disputes or holds. Disputes and
+    /// holds placed before the lock can still be resolved, charged back or released.
+    AllowCreditsOnly,
+}
+
+impl LockedPolicy {
+    /// Whether a locked account can be credited by a deposit or transfer.
+    pub fn accepts_credits(self) -> bool {
+        self == LockedPolicy::AllowCreditsOnly
+    }
+
+    /// Whether a locked account's funds can be disputed or put on hold.
+    pub fn accepts_disputes(self) -> bool {
+        self == LockedPolicy::AllowDisputesOnly
+    }
+
+    /// Whether disputes and holds on a locked account can be resolved, charged back or
+    /// released.
+    pub fn settles_disputes(self) -> bool {
+        self != LockedPolicy::RejectAll
+    }
+}
+
+impl FromStr for LockedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-all" => Ok(LockedPolicy::RejectAll),
+            "allow-disputes" => Ok(LockedPolicy::AllowDisputesOnly),
+            "allow-credits" => Ok(LockedPolicy::AllowCreditsOnly),
+            _ => Err(format!("unknown locked policy '{}'", s)),
+        }
+    }
+}
+
+/// Handling of transactions that arrive out of chronological order for their client, i.e.
+/// timestamped before the client's latest accepted transaction. Rows without a timestamp
+/// are never out of order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrderingPolicy {
+    /// Apply them like any other.
+    #[default]
+    Ignore,
+    /// Apply them, emitting `EngineEvent::OutOfOrder`.
+    Flag,
+    /// Reject them with `ProcessError::OutOfOrder`.
+    Reject,
+}
+
+impl FromStr for OrderingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(OrderingPolicy::Ignore),
+            "flag" => Ok(OrderingPolicy::Flag),
+            "reject" => Ok(OrderingPolicy::Reject),
+            _ => Err(format!("unknown ordering policy '{}'", s)),
+        }
+    }
+}
+
+/// Layout of the engine's account table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccountStorage {
+    /// A hash map with an entry per account. Small whatever the client ids.
+    #[default]
+    Map,
+    /// An array indexed by client id, so lookups involve no hashing. Grows to the largest
+    /// client id below 65,536 seen: up to 65,536 slots of 32 bytes, about 2 MiB. Larger
+    /// client ids are kept in an ordered map beside it.
+    Dense,
+}
+
+impl FromStr for AccountStorage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "map" => Ok(AccountStorage::Map),
+            "dense" => Ok(AccountStorage::Dense),
+            _ => Err(format!("unknown account storage '{}'", s)),
+        }
+    }
+}
+
+/// What happens when a balance update would leave the `i64` range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    /// Clamp at the bound and emit a saturation event.
+    #[default]
+    Saturating,
+    /// Reject the transaction with `ProcessError::Overflow`, leaving balances untouched, so
+    /// every balance is the exact sum of the transactions applied to it.
+    Checked,
+    /// Reject like `Checked`, and also lock every account the transaction would have
+    /// changed, for review. Each one emits `EngineEvent::AccountFrozen`.
+    Freeze,
+}
+
+impl FromStr for ArithmeticMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "saturating" => Ok(ArithmeticMode::Saturating),
+            "checked" => Ok(ArithmeticMode::Checked),
+            "freeze" => Ok(ArithmeticMode::Freeze),
+            _ => Err(format!("unknown arithmetic mode '{}'", s)),
+        }
+    }
+}
+
+/// Bounds on an account's total balance, in fixed-point units. Deposits that would raise the
+/// total above `ceiling` and withdrawals that would lower it below `floor` are rejected.
+/// Disputes, resolves and chargebacks are never blocked by these limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BalanceLimits {
+    pub floor: Option<Money>,
+    pub ceiling: Option<Money>,
+    /// How far a withdrawal may take the available balance below zero. `None` allows no
+    /// overdraft. An operator can override it per client with an `overdraft` admin operation.
+    pub overdraft: Option<Money>,
+}
+
+/// Named limit tiers and the clients assigned to them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientTiers {
+    tiers: HashMap<String, BalanceLimits>,
+    clients: HashMap<ClientId, String>,
+}
+
+impl ClientTiers {
+    pub fn add_tier(&mut self, name: &str, limits: BalanceLimits) {
+        self.tiers.insert(name.to_string(), limits);
+    }
+
+    pub fn assign(&mut self, client: ClientId, tier: &str) {
+        self.clients.insert(client, tier.to_string());
+    }
+
+    /// Limits of `client`'s tier, or `None` if it has no (known) tier.
+    pub fn limits_for(&self, client: ClientId) -> Option<BalanceLimits> {
+        self.clients
+            .get(&client)
+            .and_then(|tier| self.tiers.get(tier))
+            .copied()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::load_with_scale(path, DEFAULT_SCALE)
+    }
+
+    /// Like [`load`](Self::load), with amounts in `scale` decimal places.
+    pub fn load_with_scale<P: AsRef<Path>>(path: P, scale: u32) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::parse_with_scale(&fs::read_to_string(path)?, scale)?)
+    }
+}
+
+#[derive(Debug)]
+pub struct TierError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tier file line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for TierError {}
+
+/// Parses a tier file with one entry per line:
+///
+/// ```text
+/// tier <name> [floor=<amount>] [ceiling=<amount>] [overdraft=<amount>]
+/// client <id> <tier>
+/// ```
+///
+/// Blank lines and `#` comments are ignored. Clients may be assigned before their tier is
+/// defined, but every tier they name must be defined somewhere in the file.
+impl FromStr for ClientTiers {
+    type Err = TierError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_scale(s, DEFAULT_SCALE)
+    }
+}
+
+impl ClientTiers {
+    /// Parses a tier file like `from_str`, converting amounts to `scale` decimal places.
+    pub fn parse_with_scale(s: &str, scale: u32) -> Result<Self, TierError> {
+        let mut tiers = ClientTiers::default();
+        for (index, raw) in s.lines().enumerate() {
+            let err = |message: String| TierError {
+                line: index + 1,
+                message,
+            };
+            let content = raw.split('#').next().unwrap_or("").trim();
+            let mut words = content.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) => {}
+                (Some("tier"), Some(name)) => {
+                    let mut limits = BalanceLimits::default();
+                    for setting in words {
+                        let (key, value) = setting
+                            .split_once('=')
+                            .ok_or_else(|| err(format!("expected key=value, got '{}'", setting)))?;
+                        let amount = Decimal::from_str(value)
+                            .map(|amount| Money::from_decimal_lossy(amount, scale))
+                            .map_err(|_| err(format!("invalid amount '{}'", value)))?;
+                        match key {
+                            "floor" => limits.floor = Some(amount),
+                            "ceiling" => limits.ceiling = Some(amount),
+                            "overdraft" => limits.overdraft = Some(amount),
+                            _ => return Err(err(format!("unknown limit '{}'", key))),
+                        }
+                    }
+                    tiers.add_tier(name, limits);
+                }
+                (Some("client"), Some(id)) => {
+                    let client = id
+                        .parse()
+                        .map_err(|_| err(format!("invalid client id '{}'", id)))?;
+                    let tier = words
+                        .next()
+                        .ok_or_else(|| err("missing tier name".to_string()))?;
+                    tiers.assign(client, tier);
+                }
+                _ => return Err(err(format!("unrecognised entry '{}'", content))),
+            }
+        }
+
+        if let Some(tier) = tiers
+            .clients
+            .values()
+            .find(|t| !tiers.tiers.contains_key(*t))
+        {
+            return Err(TierError {
+                line: 0,
+                message: format!("undefined tier '{}'", tier),
+            });
+        }
+        Ok(tiers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SCALE;
+
+    #[test]
+    fn test_tier_file() {
+        let tiers: ClientTiers = "\
+# basic wallets are capped
+tier basic ceiling=1000
+tier premium floor=-50.5 ceiling=100000 overdraft=25
+client 7 premium
+client 8 basic
+"
+        .parse()
+        .unwrap();
+        let config = EngineConfig::default()
+            .with_balance_limits(BalanceLimits {
+                floor: Some(Money::ZERO),
+                ceiling: None,
+                overdraft: None,
+            })
+            .with_tiers(tiers);
+
+        assert_eq!(
+            config.balance_limits_for(8).ceiling,
+            Some(Money::from_units(1000 * SCALE))
+        );
+        assert_eq!(
+            config.balance_limits_for(7).floor,
+            Some(Money::from_units(-505_000))
+        );
+        assert_eq!(config.balance_limits_for(9).floor, Some(Money::ZERO));
+        assert_eq!(
+            config.balance_limits_for(7).overdraft,
+            Some(Money::from_units(25 * SCALE))
+        );
+
+        let err = "client 1 gold\n".parse::<ClientTiers>().unwrap_err();
+        assert_eq!(err.message, "undefined tier 'gold'");
+        let err = "tier x cap=1\n".parse::<ClientTiers>().unwrap_err();
+        assert_eq!(err.to_string(), "tier file line 1: unknown limit 'cap'");
+    }
+}
@@ -0,0 +1,494 @@
+//! Engine configuration read from a TOML file, for `--config engine.toml`.
+//!
+//! Every setting is optional; a missing one keeps its [`EngineConfig::default`] value, so an
+//! empty file configures the engine exactly like no file at all. Names and values follow the
+//! command-line options:
+//!
+//! ```toml
+//! scale = 4
+//! rounding = "truncate"            # truncate | half-even | reject
+//! arithmetic = "saturating"        # saturating | checked | freeze
+//! ordering = "ignore"              # ignore | flag | reject
+//! account-storage = "map"          # map | dense
+//! max-amount = "1000000"
+//! memory-limit = 536870912         # bytes
+//! detect-id-collisions = false
+//! check-invariants = false
+//! last-activity = false
+//! extended-output = false
+//! tiers = "tiers.txt"
+//! limits = "limits.txt"
+//!
+//! [dispute]
+//! shortfall = "allow-negative"     # allow-negative | reject | hold-partial
+//! window-days = 90
+//! expiry-days = 30
+//! expiry-action = "resolve"        # resolve | escalate
+//!
+//! [locked]
+//! policy = "allow-disputes"        # reject-all | allow-disputes | allow-credits
+//! unlock-after-days = 7
+//! unlock-on-reversal = true
+//!
+//! [balance]
+//! floor = "0"
+//! ceiling = "1000000"
+//! overdraft = "50"
+//!
+//! [reserve]
+//! rate = "0.1"
+//! days = 30
+//!
+//! [fees]
+//! flat = "0.5"
+//! rate = "0.01"
+//! account = 9999
+//!
+//! [interest]
+//! rate = "0.05"
+//! days = 365
+//!
+//! [dormancy]
+//! after-days = 180
+//! after-txs = 1000000
+//!
+//! [compaction]
+//! every = 100000
+//! charged-back = false
+//! ```
+//!
+//! Amounts may be strings or numbers and are read at the file's `scale`. The `tiers` and
+//! `limits` files use the formats of [`ClientTiers`] and [`RiskLimits`], and relative paths
+//! are taken from the working directory. A section only takes effect with its main setting:
+//! `reserve.rate`, `fees.flat` or `fees.rate`, `interest.rate`, `dispute.expiry-days`, one of
+//! the `dormancy` thresholds and `compaction.every`.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+
+use crate::config::{
+    AccountStorage, ArithmeticMode, BalanceLimits, ClientTiers, CompactionPolicy, DisputeExpiry,
+    DisputeShortfallPolicy, DormancyPolicy, EngineConfig, ExpiryAction, LockedPolicy,
+    OrderingPolicy, ReservePolicy,
+};
+use crate::fees::{FeePolicy, InterestPolicy};
+use crate::limits::RiskLimits;
+use crate::types::{ClientId, DEFAULT_SCALE, MAX_SCALE, Money, Rounding};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The settings of a configuration file; see the [module docs](self). Fields left `None`
+/// keep their default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    pub scale: Option<u32>,
+    #[serde(deserialize_with = "parsed")]
+    pub rounding: Option<Rounding>,
+    #[serde(deserialize_with = "parsed")]
+    pub arithmetic: Option<ArithmeticMode>,
+    #[serde(deserialize_with = "parsed")]
+    pub ordering: Option<OrderingPolicy>,
+    #[serde(deserialize_with = "parsed")]
+    pub account_storage: Option<AccountStorage>,
+    pub max_amount: Option<Decimal>,
+    pub memory_limit: Option<usize>,
+    pub detect_id_collisions: bool,
+    pub check_invariants: bool,
+    pub last_activity: bool,
+    pub extended_output: bool,
+    pub tiers: Option<PathBuf>,
+    pub limits: Option<PathBuf>,
+    pub dispute: DisputeSection,
+    pub locked: LockedSection,
+    pub balance: BalanceSection,
+    pub reserve: ReserveSection,
+    pub fees: FeeSection,
+    pub interest: InterestSection,
+    pub dormancy: DormancySection,
+    pub compaction: CompactionSection,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DisputeSection {
+    #[serde(deserialize_with = "parsed")]
+    pub shortfall: Option<DisputeShortfallPolicy>,
+    pub window_days: Option<u64>,
+    pub expiry_days: Option<u64>,
+    #[serde(deserialize_with = "parsed")]
+    pub expiry_action: Option<ExpiryAction>,
+}
+
+/// What happens to accounts locked by a chargeback.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LockedSection {
+    #[serde(deserialize_with = "parsed")]
+    pub policy: Option<LockedPolicy>,
+    pub unlock_after_days: Option<u64>,
+    pub unlock_on_reversal: bool,
+}
+
+/// Engine-wide [`BalanceLimits`], for clients without a tier.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalanceSection {
+    pub floor: Option<Decimal>,
+    pub ceiling: Option<Decimal>,
+    pub overdraft: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReserveSection {
+    pub rate: Option<Decimal>,
+    pub days: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSection {
+    pub flat: Option<Decimal>,
+    pub rate: Option<Decimal>,
+    #[serde(deserialize_with = "client")]
+    pub account: Option<ClientId>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterestSection {
+    pub rate: Option<Decimal>,
+    /// The rate's period; a year by default.
+    pub days: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DormancySection {
+    pub after_days: Option<u64>,
+    pub after_txs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CompactionSection {
+    pub every: Option<u64>,
+    pub charged_back: bool,
+}
+
+/// Deserializes a string setting with the `FromStr` of its type, e.g. `"half-even"`.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// A client id, from a TOML integer. Those are 64-bit, so larger client ids can't be given.
+fn client<'de, D>(deserializer: D) -> Result<Option<ClientId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Some(ClientId::from(u64::deserialize(deserializer)?)))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    /// A setting with a value the engine doesn't accept.
+    Invalid(String),
+    /// The tier or limits file named by the configuration can't be loaded.
+    File {
+        path: PathBuf,
+        error: Box<dyn Error>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "{}", error),
+            ConfigError::Parse(error) => write!(f, "{}", error),
+            ConfigError::Invalid(message) => write!(f, "{}", message),
+            ConfigError::File { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(error) => Some(error),
+            ConfigError::Parse(error) => Some(error),
+            ConfigError::File { error, .. } => Some(error.as_ref()),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::Parse(error)
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+impl ConfigFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// The engine configuration these settings describe. Fails on an out-of-range setting,
+    /// a section missing a setting it needs, or a tier or limits file that can't be loaded.
+    pub fn into_config(self) -> Result<EngineConfig, ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+        let days = |days: u64| Duration::from_secs(days * SECONDS_PER_DAY);
+        let scale = self.scale.unwrap_or(DEFAULT_SCALE);
+        if scale > MAX_SCALE {
+            return invalid(format!("scale must be 0 to {}, got {}", MAX_SCALE, scale));
+        }
+        let fixed = |amount| Money::from_decimal_lossy(amount, scale);
+
+        let mut config = EngineConfig::default()
+            .with_scale(scale)
+            .with_rounding(self.rounding.unwrap_or_default())
+            .with_arithmetic(self.arithmetic.unwrap_or_default())
+            .with_ordering(self.ordering.unwrap_or_default())
+            .with_account_storage(self.account_storage.unwrap_or_default())
+            .with_dispute_shortfall(self.dispute.shortfall.unwrap_or_default())
+            .with_locked_policy(self.locked.policy.unwrap_or_default())
+            .with_balance_limits(BalanceLimits {
+                floor: self.balance.floor.map(fixed),
+                ceiling: self.balance.ceiling.map(fixed),
+                overdraft: self.balance.overdraft.map(fixed),
+            });
+        if self
+            .balance
+            .overdraft
+            .is_some_and(|limit| limit < Decimal::ZERO)
+        {
+            return invalid("balance.overdraft must not be negative".to_string());
+        }
+        if let Some(max) = self.max_amount {
+            config = config.with_max_amount(max);
+        }
+        if let Some(bytes) = self.memory_limit {
+            config = config.with_memory_limit(bytes);
+        }
+        if self.detect_id_collisions {
+            config = config.with_id_collision_detection();
+        }
+        if self.check_invariants {
+            config = config.with_invariant_checks();
+        }
+        if self.last_activity {
+            config = config.with_last_activity();
+        }
+        if self.extended_output {
+            config = config.with_extended_output();
+        }
+        if let Some(path) = self.tiers {
+            let tiers = ClientTiers::load_with_scale(&path, scale)
+                .map_err(|error| ConfigError::File { path, error })?;
+            config = config.with_tiers(tiers);
+        }
+        if let Some(path) = self.limits {
+            let limits = RiskLimits::load_with_scale(&path, scale)
+                .map_err(|error| ConfigError::File { path, error })?;
+            config = config.with_limits(limits);
+        }
+
+        if let Some(window) = self.dispute.window_days {
+            config = config.with_dispute_window(days(window));
+        }
+        if let Some(after) = self.dispute.expiry_days {
+            config = config.with_dispute_expiry(DisputeExpiry {
+                after: days(after),
+                action: self.dispute.expiry_action.unwrap_or_default(),
+            });
+        }
+        if let Some(after) = self.locked.unlock_after_days {
+            config = config.with_auto_unlock(days(after));
+        }
+        if self.locked.unlock_on_reversal {
+            config = config.with_unlock_on_reversal();
+        }
+
+        if let Some(rate) = self.reserve.rate {
+            if rate < Decimal::ZERO || rate > Decimal::ONE {
+                return invalid(format!(
+                    "reserve.rate must be between 0 and 1, got {}",
+                    rate
+                ));
+            }
+            config = config.with_reserve(ReservePolicy {
+                rate,
+                period: days(self.reserve.days.unwrap_or(0)),
+            });
+        }
+        if self.fees.flat.is_some() || self.fees.rate.is_some() {
+            let Some(account) = self.fees.account else {
+                return invalid("withdrawal fees need fees.account".to_string());
+            };
+            let flat = self.fees.flat.unwrap_or_default();
+            let rate = self.fees.rate.unwrap_or_default();
+            if flat < Decimal::ZERO || rate < Decimal::ZERO || rate > Decimal::ONE {
+                return invalid(
+                    "withdrawal fees must not be negative, nor the rate above 1".to_string(),
+                );
+            }
+            config = config.with_fees(FeePolicy {
+                flat: fixed(flat),
+                rate,
+                account,
+            });
+        }
+        if let Some(rate) = self.interest.rate {
+            if rate < Decimal::ZERO {
+                return invalid(format!("interest.rate must not be negative, got {}", rate));
+            }
+            config = config.with_interest(InterestPolicy {
+                rate,
+                period: days(self.interest.days.unwrap_or(365)),
+            });
+        }
+        if self.dormancy.after_txs == Some(0) {
+            return invalid("dormancy.after-txs must be positive".to_string());
+        }
+        if self.dormancy.after_days.is_some() || self.dormancy.after_txs.is_some() {
+            config = config.with_dormancy(DormancyPolicy {
+                after_time: self.dormancy.after_days.map(days),
+                after_transactions: self.dormancy.after_txs,
+            });
+        }
+        match self.compaction.every {
+            Some(0) => return invalid("compaction.every must be positive".to_string()),
+            Some(every) => {
+                config = config.with_compaction(CompactionPolicy {
+                    every,
+                    charged_back: self.compaction.charged_back,
+                });
+            }
+            None if self.compaction.charged_back => {
+                return invalid("compaction.charged-back needs compaction.every".to_string());
+            }
+            None => {}
+        }
+        Ok(config)
+    }
+}
+
+impl EngineConfig {
+    /// Parses a configuration file's contents; see [`crate::config_file`].
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        s.parse::<ConfigFile>()?.into_config()
+    }
+
+    /// Reads a configuration file; see [`crate::config_file`].
+    pub fn load_toml<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        ConfigFile::load(path)?.into_config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_empty_file_is_default() {
+        let config = EngineConfig::from_toml("# nothing set\n").unwrap();
+        assert_eq!(
+            format!("{:?}", config),
+            format!("{:?}", EngineConfig::default())
+        );
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = EngineConfig::from_toml(
+            r#"
+            scale = 2
+            rounding = "half-even"
+            arithmetic = "checked"
+            max-amount = 5000
+
+            [dispute]
+            shortfall = "hold-partial"
+            expiry-days = 30
+            expiry-action = "escalate"
+
+            [locked]
+            policy = "allow-credits"
+            unlock-on-reversal = true
+
+            [balance]
+            overdraft = "12.345"
+
+            [fees]
+            rate = 0.01
+            account = 9
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.precision.scale, 2);
+        assert_eq!(config.precision.rounding, Rounding::HalfEven);
+        assert_eq!(config.arithmetic, ArithmeticMode::Checked);
+        assert_eq!(config.max_amount, Some(dec!(5000)));
+        assert_eq!(
+            config.dispute_shortfall,
+            DisputeShortfallPolicy::HoldPartial
+        );
+        assert_eq!(
+            config.dispute_expiry,
+            Some(DisputeExpiry {
+                after: Duration::from_secs(30 * SECONDS_PER_DAY),
+                action: ExpiryAction::Escalate,
+            })
+        );
+        assert_eq!(config.locked, LockedPolicy::AllowCreditsOnly);
+        assert!(config.unlock_on_reversal);
+        assert_eq!(
+            config.balance_limits.overdraft,
+            Some(Money::from_units(1234))
+        );
+        assert_eq!(config.fees.map(|fees| fees.rate), Some(dec!(0.01)));
+
+        let err = EngineConfig::from_toml("[dispute]\nshortfall = \"hold\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)));
+        assert!(
+            err.to_string()
+                .contains("unknown dispute shortfall policy 'hold'")
+        );
+        let err = EngineConfig::from_toml("overdraft = 5\n").unwrap_err();
+        assert!(err.to_string().contains("unknown field `overdraft`"));
+        let err = EngineConfig::from_toml("[fees]\nflat = 1\n").unwrap_err();
+        assert_eq!(err.to_string(), "withdrawal fees need fees.account");
+    }
+}
@@ -0,0 +1,278 @@
+//! Live terminal dashboard shown while a file is processed.
+//!
+//! The dashboard occupies a fixed number of lines below the cursor rather than the whole
+//! screen, so the command's own output and any errors stay readable once it finishes. It
+//! shows throughput, how much of the input is left, the accounts holding the most disputed
+//! funds, the locked-account count and the latest rejections. Redraws are throttled, so
+//! calling [`Dashboard::refresh`] after every transaction is cheap.
+
+use std::collections::VecDeque;
+use std::io::{self, Stderr};
+use std::time::{Duration, Instant};
+
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal, TerminalOptions, Viewport};
+
+use crate::engine::Engine;
+use crate::outcome::ProcessError;
+use crate::types::{ClientId, Money, TxId};
+
+/// Lines taken by the dashboard.
+const HEIGHT: u16 = 14;
+
+/// Minimum time between two redraws.
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+
+/// Number of accounts and rejections listed.
+const LISTED: usize = 8;
+
+/// A rejected transaction, as listed on the dashboard.
+#[derive(Debug, Clone)]
+struct Rejection {
+    row: u64,
+    client: ClientId,
+    tx: TxId,
+    error: ProcessError,
+}
+
+/// Everything one frame shows.
+#[derive(Debug)]
+struct Snapshot {
+    rows: u64,
+    total_rows: Option<u64>,
+    elapsed: Duration,
+    accounts: usize,
+    locked: usize,
+    /// Clients with held funds, largest first.
+    top_held: Vec<(ClientId, Money)>,
+    /// Decimal places of the engine's amounts.
+    scale: u32,
+    /// Newest first.
+    rejections: Vec<Rejection>,
+}
+
+pub struct Dashboard<B: Backend> {
+    terminal: Terminal<B>,
+    total_rows: Option<u64>,
+    rows: u64,
+    started: Instant,
+    last_draw: Option<Instant>,
+    rejections: VecDeque<Rejection>,
+}
+
+impl Dashboard<CrosstermBackend<Stderr>> {
+    /// Dashboard on stderr, leaving stdout free for the report.
+    pub fn stderr(total_rows: Option<u64>) -> io::Result<Self> {
+        Self::new(CrosstermBackend::new(io::stderr()), total_rows)
+    }
+}
+
+impl<B: Backend> Dashboard<B> {
+    /// `total_rows` is the number of transactions in the input, when known up front.
+    pub fn new(backend: B, total_rows: Option<u64>) -> io::Result<Self> {
+        let options = TerminalOptions {
+            viewport: Viewport::Inline(HEIGHT),
+        };
+        Ok(Self {
+            terminal: Terminal::with_options(backend, options)?,
+            total_rows,
+            rows: 0,
+            started: Instant::now(),
+            last_draw: None,
+            rejections: VecDeque::with_capacity(LISTED),
+        })
+    }
+
+    /// Counts one more transaction read from the input.
+    pub fn row(&mut self) {
+        self.rows += 1;
+    }
+
+    /// Lists a rejected transaction; `row` is its 1-based position in the input.
+    pub fn reject(&mut self, row: u64, client: ClientId, tx: TxId, error: ProcessError) {
+        if self.rejections.len() == LISTED {
+            self.rejections.pop_back();
+        }
+        self.rejections.push_front(Rejection {
+            row,
+            client,
+            tx,
+            error,
+        });
+    }
+
+    /// Redraws if the last frame is older than the redraw interval.
+    pub fn refresh(&mut self, engine: &Engine) -> io::Result<()> {
+        match self.last_draw {
+            Some(last) if last.elapsed() < REDRAW_EVERY => Ok(()),
+            _ => self.draw(engine),
+        }
+    }
+
+    pub fn draw(&mut self, engine: &Engine) -> io::Result<()> {
+        let snapshot = self.snapshot(engine);
+        self.terminal.draw(|frame| render(frame, &snapshot))?;
+        self.last_draw = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Draws the final state and moves the cursor below the dashboard, which stays on
+    /// screen.
+    pub fn finish(&mut self, engine: &Engine) -> io::Result<()> {
+        let snapshot = self.snapshot(engine);
+        let area = self.terminal.draw(|frame| render(frame, &snapshot))?.area;
+        self.terminal
+            .set_cursor_position((0, area.bottom().saturating_sub(1)))?;
+        self.terminal.backend_mut().append_lines(1)?;
+        self.terminal.show_cursor()?;
+        self.terminal.flush()
+    }
+
+    fn snapshot(&self, engine: &Engine) -> Snapshot {
+        let accounts = engine.output();
+        let mut top_held: Vec<(ClientId, Money)> = accounts
+            .iter()
+            .filter(|account| account.held.is_positive())
+            .map(|account| (account.client, account.held))
+            .collect();
+        top_held.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top_held.truncate(LISTED);
+        Snapshot {
+            rows: self.rows,
+            total_rows: self.total_rows,
+            elapsed: self.started.elapsed(),
+            accounts: accounts.len(),
+            locked: accounts.iter().filter(|account| account.locked).count(),
+            top_held,
+            scale: engine.config().precision.scale,
+            rejections: self.rejections.iter().cloned().collect(),
+        }
+    }
+}
+
+fn render(frame: &mut Frame, snapshot: &Snapshot) {
+    let [progress, stats, lists] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(1),
+        Constraint::Min(0),
+    ])
+    .areas(frame.area());
+    render_progress(frame, progress, snapshot);
+
+    let seconds = snapshot.elapsed.as_secs_f64();
+    let throughput = if seconds > 0.0 {
+        snapshot.rows as f64 / seconds
+    } else {
+        0.0
+    };
+    let remaining = match snapshot.total_rows {
+        Some(total) => total.saturating_sub(snapshot.rows).to_string(),
+        None => "unknown".to_string(),
+    };
+    let line = format!(
+        " {:.0} tx/s | {} remaining | {} accounts | {} locked | {:.1}s",
+        throughput, remaining, snapshot.accounts, snapshot.locked, seconds
+    );
+    frame.render_widget(Paragraph::new(line), stats);
+
+    let [held, rejections] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(lists);
+    let rows = snapshot
+        .top_held
+        .iter()
+        .map(|&(client, amount)| Row::new([client.to_string(), amount.format(snapshot.scale)]));
+    let table = Table::new(rows, [Constraint::Length(6), Constraint::Min(10)])
+        .header(Row::new(["client", "held"]).style(Style::new().add_modifier(Modifier::BOLD)))
+        .block(Block::new().borders(Borders::ALL).title(" Top held "));
+    frame.render_widget(table, held);
+
+    let items = snapshot.rejections.iter().map(|rejection| {
+        ListItem::new(Line::from(format!(
+            "row {} client {} tx {}: {}",
+            rejection.row,
+            rejection.client,
+            rejection.tx,
+            rejection.error.code()
+        )))
+    });
+    let list = List::new(items).block(
+        Block::new()
+            .borders(Borders::ALL)
+            .title(" Recent rejections "),
+    );
+    frame.render_widget(list, rejections);
+}
+
+fn render_progress(frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let block = Block::new().borders(Borders::ALL).title(" tx-engine ");
+    let gauge = match snapshot.total_rows {
+        Some(total) if total > 0 => {
+            let ratio = (snapshot.rows as f64 / total as f64).min(1.0);
+            Gauge::default()
+                .ratio(ratio)
+                .label(format!("{} / {} rows", snapshot.rows, total))
+        }
+        _ => Gauge::default()
+            .ratio(0.0)
+            .label(format!("{} rows", snapshot.rows)),
+    };
+    frame.render_widget(gauge.block(block), area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Transaction, TransactionType};
+    use ratatui::backend::TestBackend;
+    use rust_decimal_macros::dec;
+
+    fn tx(tx_type: TransactionType, client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: Some(dec!(2.5)),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_dashboard_shows_progress_held_funds_and_rejections() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1)).unwrap();
+        engine.process(tx(TransactionType::Deposit, 2, 2)).unwrap();
+        engine.process(tx(TransactionType::Dispute, 2, 2)).unwrap();
+
+        let mut dashboard = Dashboard::new(TestBackend::new(80, HEIGHT), Some(4)).unwrap();
+        for _ in 0..3 {
+            dashboard.row();
+        }
+        dashboard.reject(3, 3, 9, ProcessError::UnknownTransaction);
+        dashboard.draw(&engine).unwrap();
+
+        let buffer = dashboard.terminal.backend().buffer();
+        let text: String = buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect();
+        assert!(text.contains("3 / 4 rows"), "{}", text);
+        assert!(
+            text.contains("1 remaining | 2 accounts | 0 locked"),
+            "{}",
+            text
+        );
+        assert!(text.contains("│2      2.5000"), "{}", text);
+        assert!(
+            text.contains("row 3 client 3 tx 9: unknown_transaction"),
+            "{}",
+            text
+        );
+    }
+}
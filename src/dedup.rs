@@ -0,0 +1,255 @@
+//! Parallel duplicate detection for CSV transaction input.
+//!
+//! Deposits, withdrawals and transfers each introduce a transaction id. When the same id
+//! appears on more than one of those rows, every row after the first is flagged: as a
+//! duplicate if it is an exact replay (same type, client and amount), or as a conflict
+//! otherwise. Disputes, resolves and chargebacks legitimately reuse the id they refer to and
+//! are never flagged.
+//!
+//! Parsing, the expensive part, is split across threads by cutting the input at line
+//! boundaries, so this assumes no field contains an embedded newline.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::thread;
+
+use csv::{ByteRecord, ReaderBuilder, Trim};
+use serde::Serialize;
+
+use crate::types::TxId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FindingKind {
+    Duplicate,
+    Conflict,
+}
+
+/// A flagged row. Line numbers are 1-based and count the header line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub line: u64,
+    pub tx: TxId,
+    pub kind: FindingKind,
+    pub first_line: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct DedupReport {
+    pub findings: Vec<Finding>,
+}
+
+impl DedupReport {
+    pub fn duplicates(&self) -> usize {
+        self.count(FindingKind::Duplicate)
+    }
+
+    pub fn conflicts(&self) -> usize {
+        self.count(FindingKind::Conflict)
+    }
+
+    fn count(&self, kind: FindingKind) -> usize {
+        self.findings.iter().filter(|f| f.kind == kind).count()
+    }
+
+    /// Writes the findings as CSV with columns `line`, `tx`, `kind`, `first_line`.
+    pub fn write_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for finding in &self.findings {
+            writer.serialize(finding)?;
+        }
+        writer.flush()
+    }
+
+    /// Copies `data` to `writer` without the flagged rows, keeping the first occurrence of
+    /// every transaction id.
+    pub fn write_cleaned<W: Write>(&self, data: &[u8], mut writer: W) -> io::Result<()> {
+        let dropped: HashSet<u64> = self.findings.iter().map(|f| f.line).collect();
+        for (index, line) in data.split_inclusive(|&b| b == b'\n').enumerate() {
+            if !dropped.contains(&(index as u64 + 1)) {
+                writer.write_all(line)?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// A deposit, withdrawal or transfer row reduced to what identifies a replay.
+#[derive(Debug, PartialEq, Eq)]
+struct Row {
+    line: u64,
+    tx: TxId,
+    fingerprint: Vec<u8>,
+}
+
+/// Scans CSV `data` (with a header line) for repeated transaction ids using up to `threads`
+/// parser threads.
+pub fn scan(data: &[u8], threads: usize) -> DedupReport {
+    let body_start = data
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(data.len(), |i| i + 1);
+    let chunks = split_lines(&data[body_start..], threads.max(1));
+
+    let parsed: Vec<(u64, Vec<Row>)> = thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|chunk| scope.spawn(move || (count_lines(chunk), parse_chunk(chunk))))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_default())
+            .collect()
+    });
+
+    let mut first_seen: HashMap<TxId, (u64, Vec<u8>)> = HashMap::new();
+    let mut report = DedupReport::default();
+    // Line 1 is the header.
+    let mut base = 1;
+    for (lines, rows) in parsed {
+        for row in rows {
+            let line = base + row.line;
+            match first_seen.get(&row.tx) {
+                None => {
+                    first_seen.insert(row.tx, (line, row.fingerprint));
+                }
+                Some((first_line, fingerprint)) => report.findings.push(Finding {
+                    line,
+                    tx: row.tx,
+                    kind: if *fingerprint == row.fingerprint {
+                        FindingKind::Duplicate
+                    } else {
+                        FindingKind::Conflict
+                    },
+                    first_line: *first_line,
+                }),
+            }
+        }
+        base += lines;
+    }
+    report
+}
+
+/// Splits `data` into at most `parts` pieces, each ending on a line boundary.
+fn split_lines(data: &[u8], parts: usize) -> Vec<&[u8]> {
+    let target = data.len().div_ceil(parts).max(1);
+    let mut chunks = Vec::with_capacity(parts);
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = match rest.iter().skip(target).position(|&b| b == b'\n') {
+            Some(offset) => target + offset + 1,
+            None => rest.len(),
+        };
+        let (chunk, tail) = rest.split_at(end.min(rest.len()));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+fn count_lines(chunk: &[u8]) -> u64 {
+    let newlines = chunk.iter().filter(|&&b| b == b'\n').count() as u64;
+    match chunk.last() {
+        Some(b'\n') | None => newlines,
+        Some(_) => newlines + 1,
+    }
+}
+
+fn parse_chunk(chunk: &[u8]) -> Vec<Row> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(chunk);
+    let mut record = ByteRecord::new();
+    let mut rows = Vec::new();
+
+    // Malformed rows are left for the engine's own error handling.
+    while let Ok(true) = reader.read_byte_record(&mut record) {
+        let kind = record.get(0).unwrap_or_default().to_ascii_lowercase();
+        if kind != b"deposit" && kind != b"withdrawal" && kind != b"transfer" {
+            continue;
+        }
+        let Some(tx) = record
+            .get(2)
+            .and_then(|f| std::str::from_utf8(f).ok())
+            .and_then(|f| f.parse().ok())
+        else {
+            continue;
+        };
+        let line = record.position().map_or(0, |p| p.line());
+
+        let mut fingerprint = kind;
+        for field in [record.get(1), record.get(3)] {
+            fingerprint.push(b',');
+            fingerprint.extend_from_slice(field.unwrap_or_default());
+        }
+        rows.push(Row {
+            line,
+            tx,
+            fingerprint,
+        });
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,1,1,
+deposit,1,1,10.0
+withdrawal,3,2,1.0
+deposit,4,3,1.0
+";
+
+    #[test]
+    fn test_flags_duplicates_and_conflicts() {
+        for threads in [1, 2, 3, 8] {
+            let report = scan(INPUT, threads);
+            assert_eq!(
+                report.findings,
+                vec![
+                    Finding {
+                        line: 5,
+                        tx: 1,
+                        kind: FindingKind::Duplicate,
+                        first_line: 2
+                    },
+                    Finding {
+                        line: 6,
+                        tx: 2,
+                        kind: FindingKind::Conflict,
+                        first_line: 3
+                    },
+                ],
+                "threads = {}",
+                threads
+            );
+        }
+    }
+
+    #[test]
+    fn test_cleaned_output_keeps_first_occurrences() {
+        let report = scan(INPUT, 2);
+        let mut cleaned = Vec::new();
+        report.write_cleaned(INPUT, &mut cleaned).unwrap();
+        assert_eq!(
+            String::from_utf8(cleaned).unwrap(),
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndispute,1,1,\ndeposit,4,3,1.0\n"
+        );
+    }
+
+    #[test]
+    fn test_split_lines_covers_input() {
+        let body = &INPUT[22..];
+        for parts in 1..6 {
+            let chunks = split_lines(body, parts);
+            assert_eq!(chunks.concat(), body);
+            assert!(chunks.iter().all(|c| c.ends_with(b"\n")));
+        }
+    }
+}
@@ -1,492 +1,6172 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::iter::Peekable;
+use std::mem;
+#[cfg(feature = "io")]
+use std::path::Path;
+use std::sync::mpsc;
 
 use rust_decimal::Decimal;
 
-use crate::types::{to_fixed, Account, AccountOutput, DisputeState, StoredTransaction, Transaction, TransactionType};
+use crate::accounts::Accounts;
+use crate::admin::AdminOp;
+use crate::archive::{AccountArchive, ArchivedAccount};
+use crate::audit::{AuditAction, AuditEvent, AuditSink, BalanceChange};
+use crate::cases::{self, CaseStatus, DisputeCase};
+use crate::checkpoint::{CheckpointError, Snapshot};
+use crate::clock::Clock;
+use crate::compaction::{self, CompactedIds};
+use crate::config::{
+    ArithmeticMode, DisputeShortfallPolicy, EngineConfig, ExpiryAction, OrderingPolicy,
+};
+use crate::event_log::{self, DomainEvent, Projection};
+use crate::events::{EngineEvent, EventSink};
+use crate::history::HistoryEntry;
+use crate::invariants::{self, TransactionViolation};
+use crate::limits::ClientVelocity;
+use crate::netting::Obligation;
+use crate::observer::{self, EngineObserver};
+use crate::outcome::{
+    AmountError, BatchError, BatchRejected, MergeError, ProcessError, ProcessOutcome,
+};
+use crate::stats::EngineStats;
+use crate::store::{MemoryStore, TxStore};
+use crate::timers::{Timer, TimerQueue};
+use crate::types::{
+    Account, AccountActivity, AccountOutput, ClientId, DisputeState, Hold, Metadata, Money,
+    StoredKind, StoredTransaction, Transaction, TransactionType, TxId,
+};
+#[cfg(feature = "io")]
+use crate::wal::{self, Wal, WalError, WalMark};
 
 pub struct Engine {
-    accounts: HashMap<u16, Account>,
-    transactions: HashMap<u32, StoredTransaction>,
+    accounts: Accounts,
+    transactions: Box<dyn TxStore>,
+    config: EngineConfig,
+    timers: TimerQueue,
+    /// Latest timestamp seen, in seconds since the Unix epoch.
+    now: u64,
+    /// Consulted before every transaction when set; see [`Engine::set_clock`].
+    clock: Option<Box<dyn Clock>>,
+    sinks: Vec<EventSink>,
+    audit_sinks: Vec<AuditSink>,
+    observers: Vec<Box<dyn EngineObserver>>,
+    saturations: u64,
+    stats: EngineStats,
+    /// Time of each client's most recent chargeback, kept only with auto-unlock enabled.
+    last_chargeback: HashMap<ClientId, u64>,
+    /// When each open dispute was raised, kept only with dispute expiry enabled.
+    dispute_opened: HashMap<TxId, u64>,
+    /// Funds held by each open dispute, or taken by each chargeback, that held less than its
+    /// transaction's amount under `DisputeShortfallPolicy::HoldPartial`.
+    partial_holds: HashMap<TxId, Money>,
+    /// Client and type that first used each deposit or withdrawal id, kept only with
+    /// collision detection enabled.
+    id_owners: HashMap<TxId, (ClientId, TransactionType)>,
+    /// Funds set aside by hold transactions, by hold id. Holds released in full are kept so
+    /// their ids stay taken.
+    holds: HashMap<TxId, Hold>,
+    /// Largest `memory_usage` seen after any transaction.
+    peak_memory: usize,
+    /// Undo information of the open batch, if any.
+    batch: Option<BatchUndo>,
+    /// What the latest accepted transactions and committed batches changed, newest last,
+    /// kept only with an undo depth configured.
+    undo_log: VecDeque<UndoRecord>,
+    /// Number of transactions processed so far.
+    seq: u64,
+    /// Time and sequence number of each client's latest transaction, kept only with a
+    /// dormancy policy.
+    last_active: HashMap<ClientId, (u64, u64)>,
+    /// Latest timestamp of each client's accepted transactions, kept only with an ordering
+    /// policy or last-activity reporting. Stays in memory while the account is archived.
+    last_activity: HashMap<ClientId, u64>,
+    /// Each client's transaction counts and totals, kept only with extended output. Open
+    /// disputes are counted when reporting.
+    account_activity: HashMap<ClientId, AccountActivity>,
+    archive: Option<Box<dyn AccountArchive>>,
+    /// Clients whose accounts currently live in the archive.
+    archived: HashSet<ClientId>,
+    /// Clients merged into another one, mapped to the client that now holds their account.
+    merged: HashMap<ClientId, ClientId>,
+    /// Each client's accepted transactions in order, kept only with history enabled.
+    history: HashMap<ClientId, Vec<HistoryEntry>>,
+    /// Number of entries across `history`, for `memory_usage`.
+    history_entries: usize,
+    /// What the risk limits count per client, kept only with limits configured.
+    velocity: HashMap<ClientId, ClientVelocity>,
+    /// Every account change as a domain event, kept only with the event log enabled.
+    event_log: Vec<DomainEvent>,
+    /// Time of the latest interest accrual.
+    last_accrual: Option<u64>,
+    /// Overdraft limits set by an operator, in place of the configured ones.
+    overdrafts: HashMap<ClientId, Money>,
+    /// Ids of the transactions dropped by `compact`.
+    compacted: CompactedIds,
+    /// Metadata of the stored transactions that came with any.
+    metadata: HashMap<TxId, Metadata>,
+    /// Bytes the keys and values of `metadata` take, for `memory_usage`.
+    metadata_bytes: usize,
+    /// Transactions rejected by the invariant checks.
+    violations: Vec<TransactionViolation>,
+    /// Case record of each open dispute, kept only with dispute cases enabled.
+    cases: HashMap<TxId, DisputeCase>,
+    /// Number of dispute cases opened so far, the id of the latest one.
+    cases_opened: u64,
+    #[cfg(feature = "io")]
+    wal: Option<Wal>,
+}
+
+/// State overwritten since a batch began, kept so the batch can be rolled back. Entries are
+/// saved the first time a client or transaction id is touched inside the batch.
+#[derive(Default)]
+struct BatchUndo {
+    clients: HashMap<ClientId, ClientSnapshot>,
+    ids: HashMap<TxId, IdSnapshot>,
+    timer_mark: u64,
+    /// End of the write-ahead log when the batch began.
+    #[cfg(feature = "io")]
+    wal_mark: Option<WalMark>,
+    /// Timers that fired inside the batch, rescheduled on rollback.
+    fired: Vec<(u64, Timer)>,
+    /// Events held back until commit.
+    events: Vec<EngineEvent>,
+    /// Audit records held back until commit, each with whether a chargeback took its
+    /// client's funds.
+    audit: Vec<(AuditEvent, bool)>,
+    /// Domain events held back until commit.
+    domain_events: Vec<DomainEvent>,
+    last_accrual: Option<u64>,
+    cases_opened: u64,
+    saturations: u64,
+    stats: EngineStats,
+}
+
+/// An accepted transaction processed outside a batch, or a committed batch, as
+/// `Engine::undo_last` undoes it.
+struct UndoRecord {
+    /// The transaction, or `None` for a batch.
+    tx: Option<TxId>,
+    undo: BatchUndo,
+}
+
+/// What the invariant checks after a transaction compare against; see [`crate::invariants`].
+struct InvariantCheck {
+    tx: Transaction,
+    /// The accounts of the transaction's parties before it.
+    before: Vec<(ClientId, Account)>,
+    /// The open batch, set aside while the transaction runs in one of its own.
+    outer: Option<BatchUndo>,
+}
+
+/// A client's state when a batch first touched it. `None` means the entry didn't exist.
+struct ClientSnapshot {
+    account: Option<Account>,
+    last_chargeback: Option<u64>,
+    last_activity: Option<u64>,
+    /// Length of the client's history, which the batch can only append to.
+    history_len: usize,
+    /// Risk-limit counts.
+    velocity: Option<ClientVelocity>,
+    overdraft: Option<Money>,
+    /// Activity counts.
+    activity: Option<AccountActivity>,
+}
+
+/// A transaction id's state when a batch first touched it. `None` means the entry didn't
+/// exist.
+struct IdSnapshot {
+    stored: Option<StoredTransaction>,
+    dispute_opened: Option<u64>,
+    partial_hold: Option<Money>,
+    /// The client and type that first used the id.
+    owner: Option<(ClientId, TransactionType)>,
+    hold: Option<Hold>,
+    case: Option<DisputeCase>,
+}
+
+/// Puts back a saved map entry, removing the key if it didn't exist.
+fn restore<K: std::hash::Hash + Eq, V>(map: &mut HashMap<K, V>, key: K, saved: Option<V>) {
+    match saved {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+fn store_unavailable(_: io::Error) -> ProcessError {
+    ProcessError::StoreUnavailable
+}
+
+/// `target` and `source` as one account: balances added exactly, locked if either is and
+/// closed only if both are.
+fn combine(target: Account, source: Account) -> Result<Account, MergeError> {
+    let add = |a: Money, b: Money| a.checked_add(b).ok_or(MergeError::Overflow);
+    let merged = Account {
+        available: add(target.available, source.available)?,
+        held: add(target.held, source.held)?,
+        reserved: add(target.reserved, source.reserved)?,
+        on_hold: add(target.on_hold, source.on_hold)?,
+        locked: target.locked || source.locked,
+        closed: target.closed && source.closed,
+    };
+    // The total must fit as well.
+    add(
+        add(add(merged.available, merged.held)?, merged.reserved)?,
+        merged.on_hold,
+    )?;
+    Ok(merged)
+}
+
+/// Bytes allocated by a hash table: one `(K, V)` slot plus one control byte per bucket.
+fn table_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (mem::size_of::<(K, V)>() + 1)
+}
+
+/// Approximate bytes one transaction's metadata takes beyond its entry in `metadata`.
+fn metadata_bytes(metadata: &Metadata) -> usize {
+    table_bytes(metadata)
+        + metadata
+            .iter()
+            .map(|(key, value)| key.capacity() + value.capacity())
+            .sum::<usize>()
 }
 
 impl Engine {
     pub fn new() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
         Self {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
+            accounts: Accounts::new(config.account_storage),
+            transactions: Box::new(MemoryStore::default()),
+            config,
+            timers: TimerQueue::default(),
+            now: 0,
+            clock: None,
+            sinks: Vec::new(),
+            audit_sinks: Vec::new(),
+            observers: Vec::new(),
+            saturations: 0,
+            stats: EngineStats::default(),
+            last_chargeback: HashMap::new(),
+            dispute_opened: HashMap::new(),
+            partial_holds: HashMap::new(),
+            id_owners: HashMap::new(),
+            holds: HashMap::new(),
+            peak_memory: 0,
+            batch: None,
+            undo_log: VecDeque::new(),
+            seq: 0,
+            last_active: HashMap::new(),
+            last_activity: HashMap::new(),
+            account_activity: HashMap::new(),
+            archive: None,
+            archived: HashSet::new(),
+            merged: HashMap::new(),
+            history: HashMap::new(),
+            history_entries: 0,
+            velocity: HashMap::new(),
+            event_log: Vec::new(),
+            last_accrual: None,
+            overdrafts: HashMap::new(),
+            compacted: CompactedIds::default(),
+            metadata: HashMap::new(),
+            metadata_bytes: 0,
+            violations: Vec::new(),
+            cases: HashMap::new(),
+            cases_opened: 0,
+            #[cfg(feature = "io")]
+            wal: None,
         }
     }
 
-    pub fn process(&mut self, tx: Transaction) {
-        match tx.tx_type {
-            TransactionType::Deposit => self.deposit(tx),
-            TransactionType::Withdrawal => self.withdrawal(tx),
-            TransactionType::Dispute => self.dispute(tx),
-            TransactionType::Resolve => self.resolve(tx),
-            TransactionType::Chargeback => self.chargeback(tx),
-        }
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
     }
 
-    fn deposit(&mut self, tx: Transaction) {
-        let Some(decimal_amount) = tx.amount else { return };
-        if decimal_amount <= Decimal::ZERO {
-            return;
-        }
+    /// Registers a callback invoked for every [`EngineEvent`], in registration order.
+    pub fn on_event(&mut self, sink: impl FnMut(&EngineEvent) + Send + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
 
-        let amount = to_fixed(decimal_amount);
+    /// Registers a callback invoked with an [`AuditEvent`] for every processed transaction and
+    /// every other account change, in registration order; see [`crate::audit`].
+    pub fn on_audit(&mut self, sink: impl FnMut(&AuditEvent) + Send + 'static) {
+        self.audit_sinks.push(Box::new(sink));
+    }
 
-        let account = self.accounts.entry(tx.client).or_default();
-        if account.locked {
-            return;
-        }
+    /// Opens a channel receiving a [`BalanceChange`] for every change to a client's available
+    /// or held balance, in the order they happen: applied transactions and the engine's own
+    /// changes alike, taken from the audit trail, so the changes of a batch arrive once it
+    /// commits. The channel is unbounded; once the receiver is dropped nothing more is sent.
+    pub fn changes(&mut self) -> mpsc::Receiver<BalanceChange> {
+        let (sender, receiver) = mpsc::channel();
+        let mut sender = Some(sender);
+        self.on_audit(move |event| {
+            let (Some(to), Some(change)) = (&sender, BalanceChange::from_audit(event)) else {
+                return;
+            };
+            if to.send(change).is_err() {
+                sender = None;
+            }
+        });
+        receiver
+    }
 
-        account.available = account.available.saturating_add(amount);
+    /// Registers `observer` to be called back when an account is locked or unlocked, charged
+    /// back or goes negative, in registration order; see [`crate::observer`].
+    pub fn subscribe(&mut self, observer: impl EngineObserver + 'static) {
+        self.observers.push(Box::new(observer));
+    }
 
-        self.transactions.insert(
-            tx.tx,
-            StoredTransaction {
-                client: tx.client,
-                amount,
-                dispute_state: DisputeState::None,
-            },
-        );
+    /// Number of balance updates clamped at the `i64` bounds so far.
+    pub fn saturation_count(&self) -> u64 {
+        self.saturations
     }
 
-    fn withdrawal(&mut self, tx: Transaction) {
-        let Some(decimal_amount) = tx.amount else { return };
-        if decimal_amount <= Decimal::ZERO {
+    /// Totals of transactions processed, by type and result, and of accounts locked and
+    /// unlocked; see [`EngineStats`].
+    pub fn stats(&self) -> &EngineStats {
+        &self.stats
+    }
+
+    /// Approximate bytes currently allocated for accounts, stored transactions and the
+    /// bookkeeping of enabled policies. Counts table capacity, not just live entries, and
+    /// ignores the allocator's own overhead.
+    pub fn memory_usage(&self) -> usize {
+        self.accounts.memory_usage()
+            + self.transactions.memory_usage()
+            + table_bytes(&self.last_chargeback)
+            + table_bytes(&self.dispute_opened)
+            + table_bytes(&self.partial_holds)
+            + table_bytes(&self.id_owners)
+            + table_bytes(&self.holds)
+            + table_bytes(&self.last_active)
+            + table_bytes(&self.last_activity)
+            + table_bytes(&self.account_activity)
+            + table_bytes(&self.merged)
+            + table_bytes(&self.history)
+            + self.history_entries * mem::size_of::<HistoryEntry>()
+            + table_bytes(&self.velocity)
+            + table_bytes(&self.overdrafts)
+            + self.compacted.memory_usage()
+            + table_bytes(&self.metadata)
+            + self.metadata_bytes
+            + table_bytes(&self.cases)
+            + self
+                .cases
+                .values()
+                .map(|case| case.reason.as_ref().map_or(0, String::capacity))
+                .sum::<usize>()
+            + self.event_log.capacity() * mem::size_of::<DomainEvent>()
+            + self.velocity.len()
+                * self
+                    .config
+                    .limits
+                    .velocity
+                    .map_or(0, |velocity| velocity.count as usize)
+                * mem::size_of::<u64>()
+            + self.archived.capacity() * (mem::size_of::<ClientId>() + 1)
+            + self.timers.memory_usage()
+    }
+
+    /// Highest [`Engine::memory_usage`] reached so far.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    fn emit(&mut self, event: EngineEvent) {
+        if let Some(batch) = &mut self.batch {
+            batch.events.push(event);
             return;
         }
+        #[cfg(feature = "tracing")]
+        tracing::info!(event = ?event, "{}", event);
+        for sink in &mut self.sinks {
+            sink(&event);
+        }
+    }
 
-        let amount = to_fixed(decimal_amount);
-
-        let account = self.accounts.entry(tx.client).or_default();
-        if account.locked {
+    /// Delivers `event` to the audit sinks and observers; `charged_back` says whether a
+    /// chargeback took its client's funds.
+    fn audit(&mut self, event: AuditEvent, charged_back: bool) {
+        if let Some(batch) = &mut self.batch {
+            batch.audit.push((event, charged_back));
             return;
         }
+        for sink in &mut self.audit_sinks {
+            sink(&event);
+        }
+        for observer in &mut self.observers {
+            observer::notify(observer.as_mut(), &event, charged_back);
+        }
+    }
 
-        if account.available >= amount {
-            account.available = account.available.saturating_sub(amount);
+    fn record_events(&mut self, events: Vec<DomainEvent>) {
+        match &mut self.batch {
+            Some(batch) => batch.domain_events.extend(events),
+            None => self.event_log.extend(events),
         }
     }
 
-    /// Only deposits are stored, so disputes implicitly only apply to deposits.
-    /// Disputes can still happen if the account is locked.
-    /// A transaction can only be disputed if it's not currently disputed and hasn't been chargedback.
-    fn dispute(&mut self, tx: Transaction) {
-        let Some(stored) = self.transactions.get_mut(&tx.tx) else {
-            return;
-        };
+    /// The accounts of `clients` as they are before a change, brought back from the archive
+    /// first. Empty when nothing is auditing or logging events, so the change isn't recorded.
+    fn audit_snapshot(
+        &mut self,
+        clients: impl IntoIterator<Item = ClientId>,
+    ) -> Vec<(ClientId, Option<Account>)> {
+        let mut snapshot: Vec<(ClientId, Option<Account>)> = Vec::new();
+        if self.audit_sinks.is_empty() && self.observers.is_empty() && !self.config.keep_event_log {
+            return snapshot;
+        }
+        for client in clients {
+            if snapshot.iter().any(|&(seen, _)| seen == client) || self.touch(client).is_err() {
+                continue;
+            }
+            snapshot.push((client, self.accounts.get(client).copied()));
+        }
+        snapshot
+    }
 
-        if stored.client != tx.client || stored.dispute_state != DisputeState::None {
-            return;
+    /// Records `action` for each account in `snapshot` that changed since, and for
+    /// `primary` even if it didn't, as audit records and domain events.
+    fn audit_changes(
+        &mut self,
+        tx: Option<TxId>,
+        action: AuditAction,
+        primary: Option<ClientId>,
+        snapshot: Vec<(ClientId, Option<Account>)>,
+    ) {
+        let delivered = !self.audit_sinks.is_empty() || !self.observers.is_empty();
+        let charged = match (&action, tx) {
+            (AuditAction::Applied(TransactionType::Chargeback), Some(tx)) => {
+                self.transaction(tx).map(|stored| stored.credited())
+            }
+            _ => None,
+        };
+        for (client, before) in snapshot {
+            let after = self.accounts.get(client).copied();
+            if self.config.keep_event_log {
+                self.record_events(event_log::account_events(
+                    &action,
+                    tx,
+                    client,
+                    primary == Some(client),
+                    before,
+                    after,
+                ));
+            }
+            let (before, after) = (before.unwrap_or_default(), after.unwrap_or_default());
+            if delivered && (after != before || primary == Some(client)) {
+                let metadata = tx
+                    .and_then(|tx| self.metadata.get(&tx))
+                    .cloned()
+                    .unwrap_or_default();
+                self.audit(
+                    AuditEvent {
+                        tx,
+                        client,
+                        action: action.clone(),
+                        before,
+                        after,
+                        metadata,
+                    },
+                    charged == Some(client),
+                );
+            }
         }
+    }
 
-        let account = self.accounts.entry(tx.client).or_default();
+    fn saturated(&mut self, client: ClientId, tx: TxId, attempted: Money) {
+        self.saturations += 1;
+        self.emit(EngineEvent::Saturated {
+            client,
+            tx,
+            attempted,
+        });
+    }
 
-        stored.dispute_state = DisputeState::Disputed;
-        account.available = account.available.saturating_sub(stored.amount);
-        account.held = account.held.saturating_add(stored.amount);
+    /// Makes `clock` a time source alongside transaction timestamps. Before each transaction
+    /// the engine advances to the clock's time, firing any timers that fell due, and rows
+    /// without a timestamp happen at that time.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Some(Box::new(clock));
     }
 
-    /// Resolve returns held funds to available. Only works on currently disputed transactions.
-    /// After resolve, the transaction returns to None state and can be disputed again.
-    fn resolve(&mut self, tx: Transaction) {
-        let Some(stored) = self.transactions.get_mut(&tx.tx) else {
-            return;
-        };
+    /// Advances to the installed clock's current time, firing due timers. Useful while no
+    /// transactions arrive; does nothing without a clock.
+    pub fn tick(&mut self) {
+        if let Some(now) = self.clock.as_ref().map(|clock| clock.now()) {
+            self.advance_time(now);
+        }
+    }
 
-        if stored.client != tx.client || stored.dispute_state != DisputeState::Disputed {
-            return;
+    /// Moves the engine clock forward to `now` and fires any timers that have become due,
+    /// such as reserve releases. Moving the clock backwards has no effect.
+    pub fn advance_time(&mut self, now: u64) {
+        self.now = self.now.max(now);
+        while let Some((due, timer)) = self.timers.pop_due(self.now) {
+            let client = timer.client();
+            if self.unarchive(client).is_err() {
+                // Can't apply it without the account; leave the timer for a later attempt.
+                self.timers.schedule(due, timer);
+                break;
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(due, timer = ?timer, "timer fired");
+            if self.batch.is_some() {
+                match timer {
+                    Timer::ReleaseReserve { client, .. } | Timer::Unlock { client, .. } => {
+                        self.save_client(client)
+                    }
+                    Timer::ExpireDispute { client, tx, .. } => {
+                        self.save_client(client);
+                        self.save_id(tx);
+                    }
+                    // Applying the transaction saves what it changes.
+                    Timer::Settle { .. } => {}
+                }
+                if let Some(batch) = &mut self.batch {
+                    batch.fired.push((due, timer.clone()));
+                }
+            } else {
+                self.undo_log.clear();
+            }
+            // A settled transaction is audited as it is applied.
+            let (tx, action, credited) = match timer {
+                Timer::ReleaseReserve { tx, .. } => {
+                    (Some(tx), Some(AuditAction::ReserveReleased), None)
+                }
+                Timer::Unlock { .. } => (None, Some(AuditAction::Unlocked), None),
+                Timer::ExpireDispute { tx, .. } => (
+                    Some(tx),
+                    Some(AuditAction::DisputeExpired),
+                    self.transaction(tx).map(|stored| stored.credited()),
+                ),
+                Timer::Settle { tx, .. } => (Some(tx), None, None),
+            };
+            let snapshot = action
+                .is_some()
+                .then(|| self.audit_snapshot([Some(client), credited].into_iter().flatten()));
+            match timer {
+                Timer::ReleaseReserve { client, tx, amount } => {
+                    self.release_reserve(client, tx, amount)
+                }
+                Timer::Unlock { client, locked_at } => self.auto_unlock(client, locked_at),
+                Timer::ExpireDispute {
+                    client,
+                    tx,
+                    opened_at,
+                } => self.expire_dispute(client, tx, opened_at),
+                Timer::Settle {
+                    client,
+                    tx,
+                    tx_type,
+                    amount,
+                    to_client,
+                } => self.settle(due, client, tx, tx_type, amount, to_client),
+            }
+            if let (Some(action), Some(snapshot)) = (action, snapshot) {
+                self.audit_changes(tx, action, None, snapshot);
+            }
         }
+    }
 
-        let account = self.accounts.entry(tx.client).or_default();
+    /// Queues `tx` until the engine clock reaches `value_date`, then processes it as if it
+    /// arrived at that time, whether the clock gets there through a later transaction's
+    /// timestamp, the installed clock or [`Engine::advance_time`]. A value date already
+    /// reached applies it right away. Only deposits, withdrawals and transfers can be
+    /// post-dated, and they must have an amount the engine can represent; a transaction
+    /// rejected when it settles is reported as [`EngineEvent::ScheduledRejected`].
+    ///
+    /// The queue is part of batches, checkpoints and snapshots, but not of the write-ahead
+    /// log, and a queued transaction's metadata is not kept.
+    pub fn schedule(&mut self, tx: Transaction, value_date: u64) -> Result<(), ProcessError> {
+        if !matches!(
+            tx.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        ) {
+            return Err(ProcessError::NotSchedulable);
+        }
+        let amount = tx.amount.ok_or(ProcessError::InvalidAmount)?;
+        let amount = self
+            .config
+            .precision
+            .to_fixed(amount)
+            .map_err(ProcessError::Amount)?;
+        if !amount.is_positive() {
+            return Err(ProcessError::InvalidAmount);
+        }
+        self.timers.schedule(
+            value_date,
+            Timer::Settle {
+                client: tx.client,
+                tx: tx.tx,
+                tx_type: tx.tx_type,
+                amount,
+                to_client: tx.to_client,
+            },
+        );
+        self.stats.scheduled += 1;
+        self.tick();
+        self.advance_time(self.now);
+        Ok(())
+    }
 
-        stored.dispute_state = DisputeState::None;
-        account.held = account.held.saturating_sub(stored.amount);
-        account.available = account.available.saturating_add(stored.amount);
+    fn settle(
+        &mut self,
+        due: u64,
+        client: ClientId,
+        tx: TxId,
+        tx_type: TransactionType,
+        amount: Money,
+        to_client: Option<ClientId>,
+    ) {
+        let amount = Some(amount.to_decimal(self.config.precision.scale));
+        let settled = Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp: Some(due),
+            to_client,
+            metadata: Default::default(),
+        };
+        let result = self.apply(settled);
+        self.stats.record(tx_type, &result);
+        if let Err(error) = result {
+            self.emit(EngineEvent::ScheduledRejected { client, tx, error });
+        }
     }
 
-    /// Chargeback is a terminal state - the transaction can never be disputed again.
-    fn chargeback(&mut self, tx: Transaction) {
-        let Some(stored) = self.transactions.get_mut(&tx.tx) else {
+    /// In checked mode a release that would overflow is skipped and the funds stay reserved.
+    fn release_reserve(&mut self, client: ClientId, tx: TxId, amount: Money) {
+        let account = self.accounts.get_or_default(client);
+        let mut arith = Arith::new(self.config.arithmetic);
+        let (Ok(reserved), Ok(available)) = (
+            arith.sub(account.reserved, amount),
+            arith.add(account.available, amount),
+        ) else {
             return;
         };
+        account.reserved = reserved;
+        account.available = available;
+        if arith.clamped {
+            self.saturated(client, tx, amount);
+        }
+    }
 
-        if stored.client != tx.client || stored.dispute_state != DisputeState::Disputed {
+    fn auto_unlock(&mut self, client: ClientId, locked_at: u64) {
+        if self.last_chargeback.get(&client) != Some(&locked_at) {
             return;
         }
-
-        let account = self.accounts.entry(tx.client).or_default();
-
-        stored.dispute_state = DisputeState::ChargedBack;
-        account.held = account.held.saturating_sub(stored.amount);
-        account.locked = true;
+        self.last_chargeback.remove(&client);
+        if let Some(account) = self.accounts.get_mut(client) {
+            account.locked = false;
+            self.stats.accounts_unlocked += 1;
+            self.emit(EngineEvent::AccountUnlocked { client });
+        }
     }
 
-    pub fn output(&self) -> Vec<AccountOutput> {
-        self.accounts
-            .iter()
-            .map(|(&client, account)| AccountOutput {
-                client,
-                available: account.available,
-                held: account.held,
-                total: account.total(),
-                locked: account.locked,
-            })
-            .collect()
+    fn expire_dispute(&mut self, client: ClientId, tx: TxId, opened_at: u64) {
+        if self.dispute_opened.get(&tx) != Some(&opened_at) {
+            return;
+        }
+        let action = self
+            .config
+            .dispute_expiry
+            .map(|expiry| expiry.action)
+            .unwrap_or_default();
+        match action {
+            ExpiryAction::Resolve => {
+                let resolve = Transaction {
+                    tx_type: TransactionType::Resolve,
+                    client,
+                    tx,
+                    amount: None,
+                    timestamp: None,
+                    to_client: None,
+                    metadata: Default::default(),
+                };
+                if self.resolve(resolve).is_ok() {
+                    self.stats.disputes_expired += 1;
+                    self.emit(EngineEvent::DisputeExpired { client, tx });
+                }
+            }
+            ExpiryAction::Escalate => {
+                self.dispute_opened.remove(&tx);
+                if let Some(case) = self.cases.get_mut(&tx) {
+                    case.status = CaseStatus::Escalated;
+                }
+                self.emit(EngineEvent::DisputeEscalated { client, tx });
+            }
+        }
     }
-}
 
-impl Default for Engine {
-    fn default() -> Self {
-        Self::new()
+    /// Starts an atomic batch: the transactions processed until [`Engine::commit_batch`] or
+    /// [`Engine::rollback_batch`] take effect together or not at all. Each is still
+    /// processed, and accepted or rejected, individually; events are held back until
+    /// commit. Batches don't nest; beginning one while another is open does nothing.
+    pub fn begin_batch(&mut self) {
+        if self.batch.is_none() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!("batch begun");
+            self.batch = Some(BatchUndo {
+                timer_mark: self.timers.mark(),
+                #[cfg(feature = "io")]
+                wal_mark: self.wal.as_ref().map(Wal::mark),
+                last_accrual: self.last_accrual,
+                cases_opened: self.cases_opened,
+                saturations: self.saturations,
+                stats: self.stats.clone(),
+                ..BatchUndo::default()
+            });
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::types::SCALE;
-    use rust_decimal_macros::dec;
+    /// Keeps everything the open batch did and delivers its events and audit records.
+    pub fn commit_batch(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!("batch committed");
+            if self.config.undo_depth > 0 {
+                self.remember(None, batch);
+            } else {
+                self.deliver(batch);
+            }
+        }
+    }
 
-    fn deposit(client: u16, tx: u32, amount: Decimal) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Deposit,
-            client,
-            tx,
-            amount: Some(amount),
+    /// Delivers the events and audit records `undo` held back and keeps the rest for
+    /// [`Engine::undo_last`], forgetting the oldest record beyond the configured depth.
+    fn remember(&mut self, tx: Option<TxId>, mut undo: BatchUndo) {
+        let held = BatchUndo {
+            events: mem::take(&mut undo.events),
+            audit: mem::take(&mut undo.audit),
+            domain_events: mem::take(&mut undo.domain_events),
+            ..BatchUndo::default()
+        };
+        self.deliver(held);
+        self.undo_log.push_back(UndoRecord { tx, undo });
+        if self.undo_log.len() > self.config.undo_depth {
+            self.undo_log.pop_front();
         }
     }
 
-    fn withdrawal(client: u16, tx: u32, amount: Decimal) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client,
-            tx,
-            amount: Some(amount),
+    /// Undoes the last `n` accepted transactions, newest first, and returns how many were
+    /// undone: fewer if fewer are kept. Everything they did is put back as a batch rollback
+    /// would, dispute states, locks, timers and stats included, and the write-ahead log is
+    /// cut back before them. Each account they changed is audited as `AuditAction::Undone`
+    /// and gets domain events for the reverse change. The clock is not rewound.
+    ///
+    /// Only the last `EngineConfig::undo_depth` transactions can be undone, and a committed
+    /// batch counts as one. Anything else that changes the engine, such as an admin
+    /// operation, an interest accrual, a timer firing between transactions, a merge,
+    /// compaction or archiving, forgets the transactions before it. Does nothing while a
+    /// batch is open.
+    pub fn undo_last(&mut self, n: usize) -> usize {
+        if self.batch.is_some() {
+            return 0;
         }
+        // Auditing touches the accounts, which would otherwise forget the remaining records.
+        let mut log = mem::take(&mut self.undo_log);
+        let mut undone = 0;
+        #[cfg(feature = "io")]
+        let mut wal_mark = None;
+        while undone < n {
+            let Some(record) = log.pop_back() else {
+                break;
+            };
+            let mut clients: Vec<ClientId> = record.undo.clients.keys().copied().collect();
+            clients.sort_unstable();
+            let snapshot = self.audit_snapshot(clients);
+            #[cfg(feature = "io")]
+            {
+                wal_mark = record.undo.wal_mark;
+            }
+            self.undo(record.undo);
+            self.audit_changes(record.tx, AuditAction::Undone, None, snapshot);
+            undone += 1;
+        }
+        self.undo_log = log;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(undone, "transactions undone");
+        #[cfg(feature = "io")]
+        self.truncate_wal(wal_mark, "undone transactions");
+        undone
     }
 
-    fn dispute(client: u16, tx: u32) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Dispute,
-            client,
-            tx,
-            amount: None,
+    /// Sends the events and audit records `batch` held back.
+    fn deliver(&mut self, batch: BatchUndo) {
+        for event in batch.events {
+            self.emit(event);
         }
+        for (event, charged_back) in batch.audit {
+            self.audit(event, charged_back);
+        }
+        self.event_log.extend(batch.domain_events);
     }
 
-    fn resolve(client: u16, tx: u32) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Resolve,
-            client,
-            tx,
-            amount: None,
+    /// Hands what `batch` saved to the open batch, so rolling that back undoes it too, or
+    /// delivers its events and audit records if no batch is open.
+    fn keep(&mut self, batch: BatchUndo) {
+        let Some(outer) = &mut self.batch else {
+            self.deliver(batch);
+            return;
+        };
+        for (client, saved) in batch.clients {
+            outer.clients.entry(client).or_insert(saved);
         }
+        for (id, saved) in batch.ids {
+            outer.ids.entry(id).or_insert(saved);
+        }
+        outer.fired.extend(batch.fired);
+        outer.events.extend(batch.events);
+        outer.audit.extend(batch.audit);
+        outer.domain_events.extend(batch.domain_events);
     }
 
-    fn chargeback(client: u16, tx: u32) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Chargeback,
-            client,
-            tx,
-            amount: None,
+    /// Undo information starting now, for a transaction applied and then undone or kept.
+    fn undo_point(&self) -> BatchUndo {
+        BatchUndo {
+            timer_mark: self.timers.mark(),
+            last_accrual: self.last_accrual,
+            cases_opened: self.cases_opened,
+            saturations: self.saturations,
+            stats: self.stats.clone(),
+            ..BatchUndo::default()
         }
     }
 
-    /// Helper to create fixed-point value from integer and decimal parts
-    fn fixed(whole: i64, frac: i64) -> i64 {
-        whole * SCALE + frac
+    /// Restores balances, stored transactions, policy bookkeeping and timers to how they
+    /// were when the open batch began, and drops its events and audit records. The clock is
+    /// not rewound.
+    pub fn rollback_batch(&mut self) {
+        let Some(batch) = self.batch.take() else {
+            return;
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!("batch rolled back");
+        #[cfg(feature = "io")]
+        let wal_mark = batch.wal_mark;
+        self.undo(batch);
+        self.stats.batches_rolled_back += 1;
+        // Harmless if it fails: replaying the batch rejects the same member again.
+        #[cfg(feature = "io")]
+        self.truncate_wal(wal_mark, "rolled-back batch");
     }
 
-    #[test]
-    fn test_deposit() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
+    /// Puts back everything `batch` saved.
+    fn undo(&mut self, batch: BatchUndo) {
+        for (client, saved) in batch.clients {
+            self.accounts.restore(client, saved.account);
+            restore(&mut self.velocity, client, saved.velocity);
+            restore(&mut self.overdrafts, client, saved.overdraft);
+            restore(&mut self.last_chargeback, client, saved.last_chargeback);
+            restore(&mut self.last_activity, client, saved.last_activity);
+            restore(&mut self.account_activity, client, saved.activity);
+            if let Some(entries) = self.history.get_mut(&client) {
+                self.history_entries -= entries.len() - saved.history_len;
+                entries.truncate(saved.history_len);
+            }
+        }
+        for (id, saved) in batch.ids {
+            let restored = match saved.stored {
+                Some(stored) => self.transactions.insert(id, stored),
+                None => {
+                    self.remove_metadata(id);
+                    self.transactions.remove(id).map(|_| ())
+                }
+            };
+            if let Err(error) = restored {
+                log::warn!(
+                    "could not restore transaction {} in the store: {}",
+                    id,
+                    error
+                );
+            }
+            restore(&mut self.dispute_opened, id, saved.dispute_opened);
+            restore(&mut self.partial_holds, id, saved.partial_hold);
+            restore(&mut self.id_owners, id, saved.owner);
+            restore(&mut self.holds, id, saved.hold);
+            restore(&mut self.cases, id, saved.case);
+        }
+        self.timers.discard_from(batch.timer_mark);
+        for (due, timer) in batch.fired {
+            self.timers.schedule(due, timer);
+        }
+        self.last_accrual = batch.last_accrual;
+        self.cases_opened = batch.cases_opened;
+        self.saturations = batch.saturations;
+        self.stats = batch.stats;
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
-        assert_eq!(account.held, 0);
-        assert_eq!(account.total, fixed(10, 0));
-        assert!(!account.locked);
+    /// Whether `tx` would be accepted if processed now, and if not why, leaving the engine
+    /// as it was: it is applied and then undone like a rolled-back batch, so nothing is
+    /// stored, logged, counted or sent to sinks and observers, and the clock stays put. Works
+    /// inside an open batch too, without affecting it.
+    pub fn validate(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        if self
+            .config
+            .memory_limit
+            .is_some_and(|limit| self.memory_usage() > limit)
+        {
+            return Err(ProcessError::MemoryLimit);
+        }
+        let client = self.merged.get(&tx.client).copied().unwrap_or(tx.client);
+        let (now, seq, active) = (self.now, self.seq, self.last_active.get(&client).copied());
+        let outer = self.batch.replace(self.undo_point());
+        let result = self.apply(tx);
+        if let Some(batch) = mem::replace(&mut self.batch, outer) {
+            self.undo(batch);
+        }
+        self.now = now;
+        self.seq = seq;
+        if self.config.dormancy.is_some() {
+            restore(&mut self.last_active, client, active);
+        }
+        result.map(|_| ())
     }
 
-    #[test]
-    fn test_multiple_deposits() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(deposit(1, 2, dec!(5.5)));
+    /// Processes `txs` as one batch, committing it if every transaction is accepted and
+    /// rolling it back at the first rejection. Borrowed transactions, such as a
+    /// `&[Transaction]`, are cloned as they are processed. Returns each member's outcome, in
+    /// order, so callers that only care whether the batch went through can drop them.
+    ///
+    /// Fails with [`BatchError::BatchOpen`], processing nothing, while a batch begun with
+    /// [`Engine::begin_batch`] is open: committing or rolling back would otherwise take the
+    /// caller's own changes with it.
+    pub fn process_batch(
+        &mut self,
+        txs: impl IntoIterator<Item = impl Into<Transaction>>,
+    ) -> Result<Vec<ProcessOutcome>, BatchError> {
+        if self.batch.is_some() {
+            return Err(BatchError::BatchOpen);
+        }
+        self.begin_batch();
+        let mut outcomes = Vec::new();
+        for (index, tx) in txs.into_iter().enumerate() {
+            match self.process(tx.into()) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(error) => {
+                    self.rollback_batch();
+                    return Err(BatchRejected { index, error }.into());
+                }
+            }
+        }
+        self.commit_batch();
+        Ok(outcomes)
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(15, 5000));
+    /// Saves `client`'s state for the open batch. Without one, the change about to happen
+    /// can't be undone, and neither can the transactions before it.
+    fn save_client(&mut self, client: ClientId) {
+        let Some(batch) = &mut self.batch else {
+            self.undo_log.clear();
+            return;
+        };
+        batch
+            .clients
+            .entry(client)
+            .or_insert_with(|| ClientSnapshot {
+                account: self.accounts.get(client).copied(),
+                last_chargeback: self.last_chargeback.get(&client).copied(),
+                last_activity: self.last_activity.get(&client).copied(),
+                history_len: self.history.get(&client).map_or(0, Vec::len),
+                velocity: self.velocity.get(&client).cloned(),
+                overdraft: self.overdrafts.get(&client).copied(),
+                activity: self.account_activity.get(&client).copied(),
+            });
     }
 
-    #[test]
-    fn test_withdrawal_sufficient_funds() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(withdrawal(1, 2, dec!(4.0)));
+    fn save_id(&mut self, id: TxId) {
+        let Some(batch) = &self.batch else {
+            self.undo_log.clear();
+            return;
+        };
+        if batch.ids.contains_key(&id) {
+            return;
+        }
+        // If the store can't be read, the transaction can't change it either.
+        let Ok(stored) = self.transactions.get(id) else {
+            return;
+        };
+        let saved = IdSnapshot {
+            stored,
+            dispute_opened: self.dispute_opened.get(&id).copied(),
+            partial_hold: self.partial_holds.get(&id).copied(),
+            owner: self.id_owners.get(&id).copied(),
+            hold: self.holds.get(&id).copied(),
+            case: self.cases.get(&id).cloned(),
+        };
+        if let Some(batch) = &mut self.batch {
+            batch.ids.insert(id, saved);
+        }
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(6, 0));
+    /// Applies one transaction, reporting what it did or why it was rejected. A rejected
+    /// transaction leaves balances unchanged.
+    pub fn process(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        let tx_type = tx.tx_type;
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("process", client = tx.client, tx = tx.tx, r#type = %tx_type, outcome = tracing::field::Empty).entered();
+        let result = self.log_and_apply(tx);
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(_) => {
+                span.record("outcome", "applied");
+            }
+            Err(error) => {
+                span.record("outcome", error.code());
+                tracing::debug!(reason = error.code(), "transaction rejected: {}", error);
+            }
+        }
+        self.stats.record(tx_type, &result);
+        result
     }
 
-    #[test]
-    fn test_withdrawal_insufficient_funds() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(withdrawal(1, 2, dec!(15.0)));
+    /// Writes `tx` to the write-ahead log, if any, and applies it, taking it out of the log
+    /// again if it is rejected.
+    fn log_and_apply(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        if self
+            .config
+            .memory_limit
+            .is_some_and(|limit| self.memory_usage() > limit)
+        {
+            return Err(ProcessError::MemoryLimit);
+        }
+        #[cfg(feature = "io")]
+        let (tx, logged) = self.log_to_wal(tx)?;
+        // Outside a batch, the transaction runs in one of its own so it can be undone later.
+        let id = tx.tx;
+        let recording = self.config.undo_depth > 0 && self.batch.is_none();
+        if recording {
+            self.batch = Some(BatchUndo {
+                #[cfg(feature = "io")]
+                wal_mark: logged,
+                ..self.undo_point()
+            });
+        }
+        let result = self.apply(tx);
+        let undo = if recording { self.batch.take() } else { None };
+        match (undo, &result) {
+            (Some(undo), Ok(_)) => self.remember(Some(id), undo),
+            (Some(undo), Err(_)) => self.deliver(undo),
+            (None, _) => {}
+        }
+        // Harmless if it fails: replaying it rejects it again.
+        #[cfg(feature = "io")]
+        if result.is_err() {
+            self.truncate_wal(logged, "a rejected transaction");
+        }
+        self.peak_memory = self.peak_memory.max(self.memory_usage());
+        result
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
+    /// Appends `tx` to the write-ahead log, if any, returning it with the time the clock
+    /// gave it, so replaying it is exact, and the end of the log before it.
+    #[cfg(feature = "io")]
+    fn log_to_wal(
+        &mut self,
+        mut tx: Transaction,
+    ) -> Result<(Transaction, Option<WalMark>), ProcessError> {
+        let Some(wal) = &mut self.wal else {
+            return Ok((tx, None));
+        };
+        if tx.timestamp.is_none() {
+            tx.timestamp = self.clock.as_ref().map(|clock| clock.now());
+        }
+        let mark = wal.append(&tx).map_err(|_| ProcessError::WalUnavailable)?;
+        Ok((tx, Some(mark)))
     }
 
-    #[test]
-    fn test_withdrawal_exact_balance() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(withdrawal(1, 2, dec!(10.0)));
+    /// Cuts the write-ahead log back to `mark`, dropping `what` from it.
+    #[cfg(feature = "io")]
+    fn truncate_wal(&mut self, mark: Option<WalMark>, what: &str) {
+        let truncated = match (&mut self.wal, mark) {
+            (Some(wal), Some(mark)) => wal.truncate(mark),
+            _ => Ok(()),
+        };
+        if let Err(error) = truncated {
+            log::warn!(
+                "could not remove {} from the write-ahead log: {}",
+                what,
+                error
+            );
+        }
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
+    /// Logs every transaction to `wal` before applying it; see [`crate::wal`]. Call
+    /// [`Engine::recover_from_wal`] first when the log may already hold transactions.
+    #[cfg(feature = "io")]
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
     }
 
-    #[test]
-    fn test_dispute() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
+    /// Replays the accepted transactions logged in `dir`, returning how many were applied.
+    /// The engine should be new and configured as it was when they were logged. The
+    /// installed clock and write-ahead log are not used while replaying.
+    #[cfg(feature = "io")]
+    pub fn recover_from_wal(&mut self, dir: impl AsRef<Path>) -> Result<u64, WalError> {
+        let transactions = wal::read_all(dir.as_ref())?;
+        let clock = self.clock.take();
+        let log = self.wal.take();
+        let mut applied = 0;
+        for tx in transactions {
+            if self.process(tx).is_ok() {
+                applied += 1;
+            }
+        }
+        self.clock = clock;
+        self.wal = log;
+        Ok(applied)
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, fixed(10, 0));
-        assert_eq!(account.total, fixed(10, 0));
+    /// Processes transactions from `txs` until `stop` returns true for one, which is left in
+    /// `txs` unprocessed, or `txs` runs out. Returns how many were processed, accepted or
+    /// not. Replays an input up to a point, such as a time, from which it can be continued.
+    pub fn process_until<I: Iterator<Item = Transaction>>(
+        &mut self,
+        txs: &mut Peekable<I>,
+        mut stop: impl FnMut(&Transaction) -> bool,
+    ) -> u64 {
+        let mut processed = 0;
+        while let Some(tx) = txs.next_if(|tx| !stop(tx)) {
+            let _ = self.process(tx);
+            processed += 1;
+        }
+        processed
     }
 
-    #[test]
-    fn test_dispute_nonexistent_tx() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 999));
+    /// The state a snapshot at `position` captures; see [`crate::checkpoint`].
+    pub(crate) fn snapshot(&self, position: u64) -> Result<Snapshot, CheckpointError> {
+        if self.batch.is_some() {
+            return Err(CheckpointError::InBatch);
+        }
+        if self.config.keep_history {
+            return Err(CheckpointError::Unsupported("transaction history"));
+        }
+        if self.config.keep_event_log {
+            return Err(CheckpointError::Unsupported("the event log"));
+        }
+        if self.config.keep_dispute_cases {
+            return Err(CheckpointError::Unsupported("dispute cases"));
+        }
+        if !self.archived.is_empty() {
+            return Err(CheckpointError::Unsupported("archived accounts"));
+        }
+        if !self.metadata.is_empty() {
+            return Err(CheckpointError::Unsupported("transaction metadata"));
+        }
+        let mut owned: HashMap<ClientId, Vec<(TxId, StoredTransaction)>> = HashMap::new();
+        for entry in self.transactions.iter() {
+            let (tx, stored) = entry?;
+            owned.entry(stored.client).or_default().push((tx, stored));
+        }
+        let mut clients: Vec<ClientId> = self
+            .accounts
+            .clients()
+            .chain(owned.keys().copied())
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        let accounts = clients
+            .into_iter()
+            .map(|client| {
+                let mut transactions = owned.remove(&client).unwrap_or_default();
+                transactions.sort_unstable_by_key(|&(tx, _)| tx);
+                let account = self.accounts.get(client).copied().unwrap_or_default();
+                ArchivedAccount {
+                    client,
+                    account,
+                    transactions,
+                }
+            })
+            .collect();
+        Ok(Snapshot {
+            position,
+            seq: self.seq,
+            now: self.now,
+            saturations: self.saturations,
+            accounts,
+            timers: self.timers.pending(),
+            last_chargeback: self.last_chargeback.clone(),
+            dispute_opened: self.dispute_opened.clone(),
+            partial_holds: self.partial_holds.clone(),
+            merged: self.merged.clone(),
+            last_activity: self.last_activity.clone(),
+            last_active: self.last_active.clone(),
+            id_owners: self.id_owners.clone(),
+            holds: self.holds.clone(),
+            velocity: self.velocity.clone(),
+            last_accrual: self.last_accrual,
+            overdrafts: self.overdrafts.clone(),
+            compacted: self.compacted.clone(),
+        })
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
-        assert_eq!(account.held, 0);
+    /// Loads `snapshot` into this engine, which must be new.
+    pub(crate) fn restore(&mut self, snapshot: Snapshot) -> Result<(), CheckpointError> {
+        if !self.is_new() {
+            return Err(CheckpointError::NotEmpty);
+        }
+        let mut on_hold: HashMap<ClientId, Money> = HashMap::new();
+        for hold in snapshot.holds.values() {
+            let sum = on_hold.entry(hold.client).or_default();
+            *sum = sum.saturating_add(hold.remaining);
+        }
+        for archived in snapshot.accounts {
+            for (tx, stored) in archived.transactions {
+                self.transactions.insert(tx, stored)?;
+            }
+            let on_hold = on_hold.get(&archived.client).copied().unwrap_or_default();
+            self.accounts.insert(
+                archived.client,
+                Account {
+                    on_hold,
+                    ..archived.account
+                },
+            );
+        }
+        for (due, timer) in snapshot.timers {
+            self.timers.schedule(due, timer);
+        }
+        self.seq = snapshot.seq;
+        self.now = snapshot.now;
+        self.saturations = snapshot.saturations;
+        self.last_chargeback = snapshot.last_chargeback;
+        self.dispute_opened = snapshot.dispute_opened;
+        self.partial_holds = snapshot.partial_holds;
+        self.merged = snapshot.merged;
+        self.last_activity = snapshot.last_activity;
+        self.last_active = snapshot.last_active;
+        self.id_owners = snapshot.id_owners;
+        self.holds = snapshot.holds;
+        self.velocity = snapshot.velocity;
+        self.last_accrual = snapshot.last_accrual;
+        self.overdrafts = snapshot.overdrafts;
+        self.compacted = snapshot.compacted;
+        self.peak_memory = self.peak_memory.max(self.memory_usage());
+        Ok(())
     }
 
-    #[test]
-    fn test_dispute_wrong_client() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(2, 1));
+    /// Whether a batch is open.
+    pub(crate) fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
+    /// Whether nothing has been processed or stored yet, and no batch is open.
+    pub(crate) fn is_new(&self) -> bool {
+        self.seq == 0
+            && !self.in_batch()
+            && self.accounts.is_empty()
+            && self.transactions.iter().next().is_none()
     }
 
-    #[test]
-    fn test_double_dispute_ignored() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(dispute(1, 1));
+    /// The engine clock's time.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn now(&self) -> u64 {
+        self.now
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, fixed(10, 0));
+    /// Former name of [`Engine::process`], from before it returned the result.
+    #[deprecated(note = "use `Engine::process`, which now returns the result")]
+    pub fn try_process(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        self.process(tx)
     }
 
-    #[test]
-    fn test_resolve() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(resolve(1, 1));
+    fn apply(&mut self, mut tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        if let Some(&into) = self.merged.get(&tx.client) {
+            tx.client = into;
+        }
+        if let Some(&into) = tx.to_client.and_then(|to| self.merged.get(&to)) {
+            tx.to_client = Some(into);
+        }
+        self.touch(tx.client)?;
+        self.save_id(tx.tx);
+        self.tick();
+        if tx.timestamp.is_none() {
+            tx.timestamp = self.clock.as_ref().map(|clock| clock.now());
+        }
+        if let Some(timestamp) = tx.timestamp {
+            self.advance_time(timestamp);
+        }
+
+        if self.config.detect_id_collisions {
+            self.track_id(&tx);
+        }
+        self.seq += 1;
+        let compact = self
+            .config
+            .compaction
+            .is_some_and(|policy| self.seq.is_multiple_of(policy.every));
+        if let Some(Err(error)) = compact.then(|| self.compact()) {
+            log::warn!("could not compact stored transactions: {}", error);
+        }
+        if self.config.dormancy.is_some() {
+            self.last_active.insert(tx.client, (self.now, self.seq));
+        }
+
+        let metadata = mem::take(&mut tx.metadata);
+        let (id, client, tx_type, timestamp, activity_amount) =
+            (tx.tx, tx.client, tx.tx_type, tx.timestamp, tx.amount);
+        let parties = self.parties(&tx);
+        let snapshot = self.audit_snapshot(parties.into_iter().flatten());
+        let limited = (!self.config.limits.is_empty()).then(|| {
+            (
+                tx.amount.map_or(Money::ZERO, |amount| {
+                    Money::from_decimal_lossy(amount, self.config.precision.scale)
+                }),
+                timestamp.unwrap_or(self.now),
+            )
+        });
+        let check = self
+            .checks_invariants()
+            .then(|| self.begin_check(&tx, parties));
+        let result = self
+            .check_order(&tx)
+            .and_then(|()| self.check_closed(parties))
+            .and_then(|()| self.check_limits(&tx, limited))
+            .and_then(|()| match tx_type {
+                TransactionType::Deposit => self.deposit(tx),
+                TransactionType::Withdrawal => self.withdrawal(tx),
+                TransactionType::Dispute => {
+                    self.dispute(tx, metadata.get(cases::REASON_KEY).cloned())
+                }
+                TransactionType::Resolve => self.resolve(tx),
+                TransactionType::Chargeback => self.chargeback(tx),
+                TransactionType::ChargebackReversal => self.chargeback_reversal(tx),
+                TransactionType::Transfer => self.transfer(tx),
+                TransactionType::Hold => self.place_hold(tx),
+                TransactionType::Release => self.release_hold(tx),
+            });
+        let result = match check {
+            Some(check) => self.finish_check(check, result),
+            None => result,
+        };
+        if let (Ok(()), Some(timestamp), true) = (result, timestamp, self.tracks_activity()) {
+            let latest = self.last_activity.entry(client).or_default();
+            *latest = (*latest).max(timestamp);
+        }
+        if result.is_ok() && self.config.extended_output {
+            let counted = activity_amount
+                .and_then(|amount| self.config.precision.to_fixed(amount).ok())
+                .unwrap_or_default();
+            let activity = self.account_activity.entry(client).or_default();
+            activity.transactions += 1;
+            match tx_type {
+                TransactionType::Deposit => {
+                    activity.deposited = activity.deposited.saturating_add(counted)
+                }
+                TransactionType::Withdrawal => {
+                    activity.withdrawn = activity.withdrawn.saturating_add(counted)
+                }
+                _ => {}
+            }
+            activity.last_tx = Some(id);
+            activity.last_timestamp = timestamp;
+        }
+        if let (Ok(()), Some((amount, now))) = (result, limited) {
+            let limits = self.config.limits;
+            limits.record(
+                tx_type,
+                amount,
+                now,
+                self.velocity.entry(client).or_default(),
+            );
+        }
+        if result.is_ok()
+            && !metadata.is_empty()
+            && matches!(
+                tx_type,
+                TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+            )
+        {
+            self.metadata_bytes += metadata_bytes(&metadata);
+            self.metadata.insert(id, metadata);
+        }
+        if result.is_ok() && self.config.keep_history {
+            self.record_history(id, tx_type, timestamp, parties);
+        }
+        if result == Err(ProcessError::Overflow) && self.config.arithmetic == ArithmeticMode::Freeze
+        {
+            for party in parties.into_iter().flatten() {
+                self.freeze(party, id);
+            }
+        }
+        let action = match result {
+            Ok(()) => AuditAction::Applied(tx_type),
+            Err(error) => AuditAction::Rejected(tx_type, error),
+        };
+        self.audit_changes(Some(id), action, Some(client), snapshot);
+        result.map(|()| ProcessOutcome::for_type(&tx_type))
+    }
+
+    fn checks_invariants(&self) -> bool {
+        self.config.check_invariants || cfg!(feature = "debug-invariants")
+    }
+
+    /// Runs the transaction about to be applied in a batch of its own, so it can be undone if
+    /// it breaks an invariant, and notes its parties' accounts.
+    fn begin_check(&mut self, tx: &Transaction, parties: [Option<ClientId>; 2]) -> InvariantCheck {
+        let outer = self.batch.replace(self.undo_point());
+        self.save_id(tx.tx);
+        let mut before: Vec<(ClientId, Account)> = Vec::new();
+        for client in parties.into_iter().flatten() {
+            if before.iter().any(|&(seen, _)| seen == client) || self.touch(client).is_err() {
+                continue;
+            }
+            before.push((
+                client,
+                self.accounts.get(client).copied().unwrap_or_default(),
+            ));
+        }
+        InvariantCheck {
+            tx: tx.clone(),
+            before,
+            outer,
+        }
+    }
+
+    /// Ends the check begun before a transaction that gave `result`: undoes and rejects it if
+    /// it broke an invariant, and otherwise keeps what it did as part of the open batch, if
+    /// any.
+    fn finish_check(
+        &mut self,
+        check: InvariantCheck,
+        result: Result<(), ProcessError>,
+    ) -> Result<(), ProcessError> {
+        let batch = mem::replace(&mut self.batch, check.outer)
+            .expect("checked transaction runs in its own batch");
+        let clamped = self.saturations > 0;
+        let violation = result.ok().and_then(|()| {
+            check.before.iter().find_map(|&(client, before)| {
+                let after = self.accounts.get(client).copied().unwrap_or_default();
+                let violation = invariants::check_change(
+                    &check.tx,
+                    client,
+                    &before,
+                    &after,
+                    clamped,
+                    self.config.locked,
+                )
+                .err()?;
+                Some(TransactionViolation {
+                    tx: check.tx.clone(),
+                    violation,
+                    before,
+                    after,
+                })
+            })
+        });
+        let Some(violation) = violation else {
+            self.keep(batch);
+            return result;
+        };
+        self.undo(batch);
+        log::error!("{}", violation);
+        self.violations.push(violation);
+        Err(ProcessError::InvariantViolation)
+    }
+
+    /// The transactions rejected for breaking an invariant, oldest first; see
+    /// [`crate::invariants`].
+    pub fn invariant_violations(&self) -> &[TransactionViolation] {
+        &self.violations
+    }
+
+    /// Adds accepted transaction `tx` to the history of each client whose account it may
+    /// have changed.
+    fn record_history(
+        &mut self,
+        tx: TxId,
+        tx_type: TransactionType,
+        timestamp: Option<u64>,
+        parties: [Option<ClientId>; 2],
+    ) {
+        let amount = match self.holds.get(&tx) {
+            Some(hold) => hold.amount,
+            None => self
+                .transaction(tx)
+                .map_or(Money::ZERO, |stored| stored.amount),
+        };
+        let [Some(client), other] = parties else {
+            return;
+        };
+        let other = other.filter(|&other| other != client);
+        for (party, counterparty) in [(Some(client), other), (other, Some(client))] {
+            let Some(party) = party else {
+                continue;
+            };
+            let balance = self.accounts.get(party).copied().unwrap_or_default();
+            let entry = HistoryEntry {
+                seq: self.seq,
+                tx,
+                tx_type,
+                counterparty,
+                amount,
+                timestamp,
+                balance,
+            };
+            self.history.entry(party).or_default().push(entry);
+            self.history_entries += 1;
+        }
+    }
+
+    /// `client`'s accepted transactions in the order they were processed, each with the
+    /// account as it was right after. Empty unless history is enabled
+    /// ([`EngineConfig::with_history`]). Rolled-back batches leave no entries; time-triggered
+    /// changes such as reserve releases are not transactions and don't appear.
+    pub fn history(&self, client: ClientId) -> &[HistoryEntry] {
+        self.history.get(&client).map_or(&[], Vec::as_slice)
+    }
+
+    /// `client`'s account as it was at `timestamp`: right after the last of its transactions
+    /// processed before the first one timestamped later. Transactions without a timestamp
+    /// count from when they were processed. `None` if the client had no accepted transaction
+    /// by then, or history is disabled. Built from [`Engine::history`], so time-triggered
+    /// changes such as reserve releases are not reflected.
+    pub fn balance_at(&self, client: ClientId, timestamp: u64) -> Option<Account> {
+        let history = self.history(client);
+        let end = history
+            .iter()
+            .position(|entry| entry.timestamp.is_some_and(|time| time > timestamp))
+            .unwrap_or(history.len());
+        history[..end].last().map(|entry| entry.balance)
+    }
+
+    /// `client`'s open disputes in the order they were opened; see [`crate::cases`]. Empty
+    /// unless dispute cases are enabled ([`EngineConfig::with_dispute_cases`]).
+    pub fn open_disputes(&self, client: ClientId) -> Vec<&DisputeCase> {
+        let mut cases: Vec<_> = self
+            .cases
+            .values()
+            .filter(|case| case.client == client)
+            .collect();
+        cases.sort_unstable_by_key(|case| case.id);
+        cases
+    }
+
+    /// Every open dispute's case, in no particular order.
+    pub fn dispute_cases(&self) -> impl Iterator<Item = &DisputeCase> {
+        self.cases.values()
+    }
+
+    /// The [metadata](Transaction::metadata) stored deposit, withdrawal or transfer `tx`
+    /// came with, if any. Disputes, resolves and chargebacks refer to it by the same id, so
+    /// this is also the metadata of their history entries. Dropped when the transaction is
+    /// compacted.
+    pub fn metadata(&self, tx: TxId) -> Option<&Metadata> {
+        self.metadata.get(&tx)
+    }
+
+    fn remove_metadata(&mut self, tx: TxId) {
+        if let Some(metadata) = self.metadata.remove(&tx) {
+            self.metadata_bytes -= metadata_bytes(&metadata);
+        }
+    }
+
+    /// The domain events from number `offset` on; see [`crate::event_log`]. Empty unless the
+    /// event log is enabled ([`EngineConfig::with_event_log`]).
+    pub fn events(&self, offset: u64) -> &[DomainEvent] {
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+        self.event_log.get(offset..).unwrap_or_default()
+    }
+
+    /// Feeds the domain events from number `offset` on to `projection`, returning the offset
+    /// to continue from next time.
+    pub fn replay(&self, offset: u64, projection: &mut impl Projection) -> u64 {
+        let mut next = offset;
+        for event in self.events(offset) {
+            projection.apply(next, event);
+            next += 1;
+        }
+        next
+    }
+
+    /// Applies the risk limits to `tx`, given its amount and time when limits are configured.
+    fn check_limits(
+        &self,
+        tx: &Transaction,
+        limited: Option<(Money, u64)>,
+    ) -> Result<(), ProcessError> {
+        match limited {
+            Some((amount, now)) => {
+                self.config
+                    .limits
+                    .check(tx.tx_type, amount, now, self.velocity.get(&tx.client))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn tracks_activity(&self) -> bool {
+        self.config.ordering != OrderingPolicy::Ignore || self.config.report_last_activity
+    }
+
+    /// Applies the ordering policy to `tx` if it is timestamped before its client's latest
+    /// accepted transaction.
+    fn check_order(&mut self, tx: &Transaction) -> Result<(), ProcessError> {
+        let (Some(timestamp), Some(&latest)) = (tx.timestamp, self.last_activity.get(&tx.client))
+        else {
+            return Ok(());
+        };
+        if timestamp >= latest {
+            return Ok(());
+        }
+        match self.config.ordering {
+            OrderingPolicy::Ignore => {}
+            OrderingPolicy::Flag => self.emit(EngineEvent::OutOfOrder {
+                client: tx.client,
+                tx: tx.tx,
+                timestamp,
+                latest,
+            }),
+            OrderingPolicy::Reject => return Err(ProcessError::OutOfOrder),
+        }
+        Ok(())
+    }
+
+    /// Rejects a transaction that would move money on a closed account, bringing the
+    /// accounts involved back from the archive to find out.
+    fn check_closed(&mut self, parties: [Option<ClientId>; 2]) -> Result<(), ProcessError> {
+        for client in parties.into_iter().flatten() {
+            self.touch(client)?;
+            if self
+                .accounts
+                .get(client)
+                .is_some_and(|account| account.closed)
+            {
+                return Err(ProcessError::AccountClosed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks `client`'s account after transaction `tx` overflowed one of its balances.
+    fn freeze(&mut self, client: ClientId, tx: TxId) {
+        self.save_client(client);
+        let account = self.accounts.get_or_default(client);
+        if !account.locked {
+            account.locked = true;
+            self.stats.accounts_frozen += 1;
+            self.emit(EngineEvent::AccountFrozen { client, tx });
+        }
+    }
+
+    /// Applies a manual operation; see [`crate::admin`]. It is audited, and undone by a batch
+    /// rollback, like a transaction, but it isn't counted in the stats or written to the
+    /// write-ahead log. Fails with `Overflow` for an adjustment that would leave the
+    /// representable range, whatever the arithmetic mode, `AccountClosed` for an adjustment
+    /// of a closed account, `AccountNotEmpty` or `OpenDispute` for a closure that isn't
+    /// allowed yet, `InvalidAmount` for a negative overdraft limit, or `ArchiveUnavailable`.
+    pub fn apply_admin(&mut self, op: AdminOp) -> Result<(), ProcessError> {
+        let client = self
+            .merged
+            .get(&op.client())
+            .copied()
+            .unwrap_or(op.client());
+        self.touch(client)?;
+        let snapshot = self.audit_snapshot([client]);
+        let result = self.admin(client, &op);
+        self.audit_changes(None, AuditAction::Admin(op), Some(client), snapshot);
+        result
+    }
+
+    fn admin(&mut self, client: ClientId, op: &AdminOp) -> Result<(), ProcessError> {
+        match *op {
+            AdminOp::Lock { .. } => {
+                self.last_chargeback.remove(&client);
+                self.accounts.get_or_default(client).locked = true;
+            }
+            AdminOp::Unlock { .. } => {
+                self.last_chargeback.remove(&client);
+                if let Some(account) = self.accounts.get_mut(client) {
+                    account.locked = false;
+                }
+            }
+            AdminOp::Adjust { amount, .. } => {
+                let account = self.accounts.get_or_default(client);
+                if account.closed {
+                    return Err(ProcessError::AccountClosed);
+                }
+                let available = account
+                    .available
+                    .checked_add(amount)
+                    .ok_or(ProcessError::Overflow)?;
+                Arith::new(ArithmeticMode::Checked).check_total(&Account {
+                    available,
+                    ..*account
+                })?;
+                account.available = available;
+            }
+            AdminOp::Close { .. } => {
+                let account = self.accounts.get(client).copied().unwrap_or_default();
+                if !(account.available.is_zero()
+                    && account.held.is_zero()
+                    && account.reserved.is_zero()
+                    && account.on_hold.is_zero())
+                {
+                    return Err(ProcessError::AccountNotEmpty);
+                }
+                for entry in self.transactions.iter() {
+                    let (_, stored) = entry.map_err(store_unavailable)?;
+                    if stored.dispute_state == DisputeState::Disputed
+                        && (stored.client == client || stored.credited() == client)
+                    {
+                        return Err(ProcessError::OpenDispute);
+                    }
+                }
+                self.accounts.get_or_default(client).closed = true;
+            }
+            AdminOp::Overdraft { limit, .. } => {
+                if limit.is_negative() {
+                    return Err(ProcessError::InvalidAmount);
+                }
+                self.overdrafts.insert(client, limit);
+            }
+        }
+        Ok(())
+    }
+
+    /// How far `client`'s withdrawals may take its available balance below zero: the limit
+    /// set by an operator, or else the configured one.
+    pub fn overdraft_limit(&self, client: ClientId) -> Money {
+        let client = self.merged.get(&client).copied().unwrap_or(client);
+        self.overdrafts
+            .get(&client)
+            .copied()
+            .or(self.config.balance_limits_for(client).overdraft)
+            .unwrap_or(Money::ZERO)
+            .max(Money::ZERO)
+    }
+
+    /// Credits interest at the configured [`InterestPolicy`](crate::fees::InterestPolicy) to
+    /// every open account with a positive available balance, for the time since the previous
+    /// accrual, after advancing the clock to `timestamp`; see [`crate::fees`]. The first
+    /// accrual only starts the count, and one at or before the previous does nothing, as
+    /// does any accrual without a policy. Archived accounts earn nothing, and in checked
+    /// mode an account whose balance would overflow is skipped. It is audited, and undone by
+    /// a batch rollback, but isn't written to the write-ahead log. Returns the total credited.
+    pub fn accrue_interest(&mut self, timestamp: u64) -> Money {
+        let Some(policy) = self.config.interest else {
+            return Money::ZERO;
+        };
+        self.advance_time(timestamp);
+        let elapsed = match self.last_accrual {
+            Some(previous) if timestamp <= previous => return Money::ZERO,
+            Some(previous) => timestamp - previous,
+            None => 0,
+        };
+        self.last_accrual = Some(timestamp);
+        let scale = self.config.precision.scale;
+        let mut credits: Vec<(ClientId, Money)> = self
+            .accounts
+            .iter()
+            .filter(|(_, account)| !account.closed)
+            .map(|(client, account)| (client, policy.interest(account.available, elapsed, scale)))
+            .filter(|(_, interest)| interest.is_positive())
+            .collect();
+        credits.sort_unstable_by_key(|&(client, _)| client);
+        let mut total = Money::ZERO;
+        for (client, interest) in credits {
+            self.save_client(client);
+            let snapshot = self.audit_snapshot([client]);
+            let account = self.accounts.get_or_default(client);
+            let mut arith = Arith::new(self.config.arithmetic);
+            let credited = arith
+                .add(account.available, interest)
+                .and_then(|available| {
+                    arith
+                        .check_total(&Account {
+                            available,
+                            ..*account
+                        })
+                        .map(|()| available)
+                });
+            if let Ok(available) = credited {
+                account.available = available;
+                total = total.saturating_add(interest);
+            }
+            self.audit_changes(None, AuditAction::InterestAccrued, None, snapshot);
+        }
+        total
+    }
+
+    /// Clients whose accounts `tx` may change: its own, and a transfer's recipient, the fee
+    /// account for a withdrawal, or the account holding the funds of a disputed or
+    /// charged-back transfer.
+    fn parties(&self, tx: &Transaction) -> [Option<ClientId>; 2] {
+        let other = match tx.tx_type {
+            TransactionType::Transfer => tx.to_client,
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::ChargebackReversal => self
+                .transaction(tx.tx)
+                .filter(|stored| stored.client == tx.client)
+                .map(|stored| stored.credited()),
+            TransactionType::Withdrawal => self
+                .config
+                .fees
+                .map(|fees| fees.account)
+                .filter(|&account| account != tx.client),
+            TransactionType::Deposit | TransactionType::Hold | TransactionType::Release => None,
+        };
+        [Some(tx.client), other]
+    }
+
+    /// Brings `client`'s account back from the archive and saves it for the open batch,
+    /// before a transaction changes it.
+    fn touch(&mut self, client: ClientId) -> Result<(), ProcessError> {
+        self.unarchive(client)
+            .map_err(|_| ProcessError::ArchiveUnavailable)?;
+        self.save_client(client);
+        Ok(())
+    }
+
+    /// Whether `client` has been inactive for longer than the dormancy policy allows. Always
+    /// false without a policy, and for archived clients, which are no longer in memory.
+    pub fn is_dormant(&self, client: ClientId) -> bool {
+        let (Some(policy), Some(&(time, seq))) =
+            (self.config.dormancy, self.last_active.get(&client))
+        else {
+            return false;
+        };
+        let idle_time = self.now.saturating_sub(time);
+        let idle_transactions = self.seq - seq;
+        policy
+            .after_time
+            .is_some_and(|after| idle_time >= after.as_secs())
+            || policy
+                .after_transactions
+                .is_some_and(|after| idle_transactions >= after)
+    }
+
+    /// Where [`Engine::archive_dormant`] moves dormant accounts. Archived accounts are
+    /// brought back automatically when their client has a new transaction.
+    pub fn set_archive(&mut self, archive: impl AccountArchive + 'static) {
+        self.archive = Some(Box::new(archive));
+    }
+
+    /// Number of clients currently archived.
+    pub fn archived_count(&self) -> usize {
+        self.archived.len()
+    }
+
+    /// Moves every dormant account, with the deposits it owns, to the archive and drops it
+    /// from memory, returning how many were moved. Does nothing without an archive or
+    /// inside a batch. Scans all stored deposits, so call it periodically, not per
+    /// transaction.
+    pub fn archive_dormant(&mut self) -> io::Result<usize> {
+        if self.archive.is_none() || self.batch.is_some() {
+            return Ok(0);
+        }
+        self.undo_log.clear();
+        let dormant: HashSet<ClientId> = self
+            .accounts
+            .clients()
+            .filter(|&client| self.is_dormant(client))
+            .collect();
+        if dormant.is_empty() {
+            return Ok(0);
+        }
+
+        let mut owned: HashMap<ClientId, Vec<(TxId, StoredTransaction)>> = HashMap::new();
+        for entry in self.transactions.iter() {
+            let (tx, stored) = entry?;
+            if dormant.contains(&stored.client) {
+                owned.entry(stored.client).or_default().push((tx, stored));
+            }
+        }
+        let mut pending: Vec<ArchivedAccount> = dormant
+            .iter()
+            .map(|&client| {
+                self.last_active.remove(&client);
+                ArchivedAccount {
+                    client,
+                    account: self.accounts.remove(client).unwrap_or_default(),
+                    transactions: owned.remove(&client).unwrap_or_default(),
+                }
+            })
+            .collect();
+        let count = pending.len();
+        while let Some(archived) = pending.pop() {
+            let client = archived.client;
+            let stored = match self.archive.as_mut() {
+                Some(archive) => archive.store(archived.clone()),
+                None => Err(io::Error::other("no archive configured")),
+            };
+            let stored = stored.and_then(|()| {
+                self.archived.insert(client);
+                // Once archived, leftovers in the store are harmless: they come back unchanged.
+                archived
+                    .transactions
+                    .iter()
+                    .try_for_each(|&(tx, _)| self.transactions.remove(tx).map(|_| ()))
+            });
+            if let Err(error) = stored {
+                // Put back everything not yet archived, so nothing is lost.
+                if !self.archived.contains(&client) {
+                    pending.push(archived);
+                }
+                for archived in pending {
+                    // The first error is the one reported; accounts come back regardless.
+                    let _ = self.restore_archived(archived);
+                }
+                return Err(error);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Brings `client` back from the archive if it is there.
+    fn unarchive(&mut self, client: ClientId) -> io::Result<()> {
+        if !self.archived.contains(&client) {
+            return Ok(());
+        }
+        let Some(archive) = self.archive.as_mut() else {
+            return Ok(());
+        };
+        if let Some(archived) = archive.take(client)? {
+            self.restore_archived(archived)?;
+        }
+        self.archived.remove(&client);
+        Ok(())
+    }
+
+    fn restore_archived(&mut self, archived: ArchivedAccount) -> io::Result<()> {
+        // Holds stay in memory while the account is archived.
+        let on_hold = self
+            .holds
+            .values()
+            .filter(|hold| hold.client == archived.client)
+            .fold(Money::ZERO, |sum, hold| sum.saturating_add(hold.remaining));
+        self.accounts.insert(
+            archived.client,
+            Account {
+                on_hold,
+                ..archived.account
+            },
+        );
+        if self.config.dormancy.is_some() {
+            self.last_active
+                .insert(archived.client, (self.now, self.seq));
+        }
+        for (tx, stored) in archived.transactions {
+            self.transactions.insert(tx, stored)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the stored transactions that can no longer be disputed, keeping only their ids,
+    /// and returns how many were dropped; see [`crate::compaction`]. Charged-back
+    /// transactions are only dropped if the compaction policy allows it. Does nothing inside
+    /// a batch. Scans all stored transactions, so call it periodically, not per transaction.
+    pub fn compact(&mut self) -> io::Result<usize> {
+        if self.batch.is_some() {
+            return Ok(0);
+        }
+        self.undo_log.clear();
+        let charged_back = self
+            .config
+            .compaction
+            .is_some_and(|policy| policy.charged_back);
+        let mut done = Vec::new();
+        for entry in self.transactions.iter() {
+            let (tx, stored) = entry?;
+            let deadline = self
+                .config
+                .dispute_window
+                .zip(stored.timestamp)
+                .map(|(window, made_at)| made_at.saturating_add(window.as_secs()));
+            let compactable = match stored.dispute_state {
+                DisputeState::None => deadline.is_some_and(|deadline| self.now > deadline),
+                DisputeState::Disputed => false,
+                DisputeState::ChargedBack => charged_back,
+            };
+            if compactable {
+                done.push((tx, stored.dispute_state));
+            }
+        }
+        for &(tx, state) in &done {
+            self.transactions.remove(tx)?;
+            self.compacted.insert(tx, state);
+            self.partial_holds.remove(&tx);
+            self.remove_metadata(tx);
+        }
+        Ok(done.len())
+    }
+
+    /// Merges client `from` into client `into`, for customers who ended up with two ids.
+    /// Balances are added together, the account is locked if either was, and `from`'s
+    /// deposits, holds and pending timers move to `into` so later disputes, resolves,
+    /// chargebacks and releases apply there. Transactions still arriving for `from` are
+    /// redirected to `into`. Their histories are combined in processing order. Emits
+    /// [`EngineEvent::ClientsMerged`] and returns the number of deposits moved.
+    ///
+    /// Balances are added exactly whatever the arithmetic mode; if a sum doesn't fit, or the
+    /// transaction store fails, nothing is changed. Not allowed inside a batch.
+    pub fn merge_clients(&mut self, from: ClientId, into: ClientId) -> Result<usize, MergeError> {
+        if self.batch.is_some() {
+            return Err(MergeError::InBatch);
+        }
+        self.undo_log.clear();
+        let into = self.merged.get(&into).copied().unwrap_or(into);
+        if from == into {
+            return Err(MergeError::SameClient);
+        }
+        for client in [from, into] {
+            self.unarchive(client)
+                .map_err(|_| MergeError::ArchiveUnavailable)?;
+        }
+        let source = *self
+            .accounts
+            .get(from)
+            .ok_or(MergeError::UnknownClient(from))?;
+        let target = self.accounts.get(into).copied().unwrap_or_default();
+        let merged = combine(target, source)?;
+
+        // Every moved transaction with how it was stored, to put back if the store fails.
+        let mut moved = Vec::new();
+        let mut deposits = 0;
+        for entry in self.transactions.iter() {
+            let (tx, original) = entry.map_err(|_| MergeError::StoreUnavailable)?;
+            let mut stored = original;
+            let owned = stored.client == from;
+            let received = stored.kind == (StoredKind::Transfer { to_client: from });
+            if owned {
+                stored.client = into;
+                deposits += 1;
+            }
+            if received {
+                stored.kind = StoredKind::Transfer { to_client: into };
+            }
+            if owned || received {
+                moved.push((tx, original, stored));
+            }
+        }
+        for (done, &(tx, _, stored)) in moved.iter().enumerate() {
+            if self.transactions.insert(tx, stored).is_err() {
+                for &(tx, original, _) in &moved[..done] {
+                    if let Err(error) = self.transactions.insert(tx, original) {
+                        log::warn!(
+                            "could not restore transaction {} in the store: {}",
+                            tx,
+                            error
+                        );
+                    }
+                }
+                return Err(MergeError::StoreUnavailable);
+            }
+        }
+
+        let snapshot = self.audit_snapshot([from, into]);
+        self.accounts.remove(from);
+        self.accounts.insert(into, merged);
+        self.timers.rehome(from, into);
+        for hold in self.holds.values_mut().filter(|hold| hold.client == from) {
+            hold.client = into;
+        }
+        for case in self.cases.values_mut().filter(|case| case.client == from) {
+            case.client = into;
+        }
+        for (client, _) in self.id_owners.values_mut() {
+            if *client == from {
+                *client = into;
+            }
+        }
+        if let Some(locked_at) = self.last_chargeback.remove(&from) {
+            let latest = self.last_chargeback.entry(into).or_default();
+            *latest = (*latest).max(locked_at);
+        }
+        if let Some(active) = self.last_active.remove(&from) {
+            let latest = self.last_active.entry(into).or_default();
+            *latest = (*latest).max(active);
+        }
+        if let Some(activity) = self.last_activity.remove(&from) {
+            let latest = self.last_activity.entry(into).or_default();
+            *latest = (*latest).max(activity);
+        }
+        if let Some(activity) = self.account_activity.remove(&from) {
+            self.account_activity
+                .entry(into)
+                .or_default()
+                .absorb(activity);
+        }
+        if let Some(counts) = self.velocity.remove(&from) {
+            self.velocity.entry(into).or_default().absorb(counts);
+        }
+        if let Some(limit) = self.overdrafts.remove(&from) {
+            let limit = limit.max(self.overdraft_limit(into));
+            self.overdrafts.insert(into, limit);
+        }
+        if let Some(mut entries) = self.history.remove(&from) {
+            let history = self.history.entry(into).or_default();
+            history.append(&mut entries);
+            history.sort_by_key(|entry| entry.seq);
+        }
+        for target in self.merged.values_mut().filter(|target| **target == from) {
+            *target = into;
+        }
+        self.merged.insert(from, into);
+        self.audit_changes(None, AuditAction::Merged { from, into }, None, snapshot);
+        self.emit(EngineEvent::ClientsMerged {
+            from,
+            into,
+            deposits,
+        });
+        Ok(deposits)
+    }
+
+    /// Merges engine `other` into this one, for input partitioned across processes or
+    /// threads and processed separately. Accounts of clients known to both are added
+    /// together, as by [`Engine::merge_clients`], and locked if either was; stored
+    /// transactions, pending timers, histories and statistics are combined. Transaction ids
+    /// must not overlap: if any is stored by both engines, nothing is merged. Where the two
+    /// disagree otherwise, such as the first owner of a reused id or a client's later merge
+    /// target, this engine's record wins. `other`'s configuration, clock, event sinks,
+    /// observers and write-ahead log are dropped, and its history and domain events come
+    /// after this engine's.
+    ///
+    /// A transaction in one partition can't see the other's: a dispute of a deposit made in
+    /// the other partition was rejected as unknown, and a withdrawal was checked against the
+    /// funds of its own partition only. Partition by client to avoid both.
+    pub fn merge(&mut self, mut other: Engine) -> Result<(), MergeError> {
+        if self.batch.is_some() || other.batch.is_some() {
+            return Err(MergeError::InBatch);
+        }
+        self.undo_log.clear();
+        for client in other.archived.clone() {
+            other
+                .unarchive(client)
+                .map_err(|_| MergeError::ArchiveUnavailable)?;
+        }
+        for client in other.accounts.clients() {
+            self.unarchive(client)
+                .map_err(|_| MergeError::ArchiveUnavailable)?;
+        }
+        let transactions: Vec<_> = other
+            .transactions
+            .iter()
+            .collect::<io::Result<_>>()
+            .map_err(|_| MergeError::StoreUnavailable)?;
+        for &(tx, _) in &transactions {
+            if self
+                .transactions
+                .get(tx)
+                .map_err(|_| MergeError::StoreUnavailable)?
+                .is_some()
+                || self.compacted.get(tx).is_some()
+            {
+                return Err(MergeError::DuplicateTransaction(tx));
+            }
+        }
+        if let Some(tx) = other
+            .compacted
+            .runs()
+            .find_map(|(first, last, _)| self.compacted.first_in(first, last))
+        {
+            return Err(MergeError::DuplicateTransaction(tx));
+        }
+        for &tx in other.holds.keys() {
+            if self.holds.contains_key(&tx)
+                || self
+                    .transactions
+                    .get(tx)
+                    .map_err(|_| MergeError::StoreUnavailable)?
+                    .is_some()
+                || self.compacted.get(tx).is_some()
+            {
+                return Err(MergeError::DuplicateTransaction(tx));
+            }
+        }
+        for &tx in self.holds.keys() {
+            if other
+                .transactions
+                .get(tx)
+                .map_err(|_| MergeError::StoreUnavailable)?
+                .is_some()
+                || other.compacted.get(tx).is_some()
+            {
+                return Err(MergeError::DuplicateTransaction(tx));
+            }
+        }
+        if !other.compacted.is_empty() {
+            for entry in self.transactions.iter() {
+                let (tx, _) = entry.map_err(|_| MergeError::StoreUnavailable)?;
+                if other.compacted.get(tx).is_some() {
+                    return Err(MergeError::DuplicateTransaction(tx));
+                }
+            }
+        }
+        let mut accounts: Vec<(ClientId, Account)> = other
+            .accounts
+            .iter()
+            .map(|(client, &account)| (client, account))
+            .collect();
+        for (client, account) in accounts.iter_mut() {
+            if let Some(&existing) = self.accounts.get(*client) {
+                *account = combine(existing, *account)?;
+            }
+        }
+
+        for (tx, stored) in transactions {
+            self.transactions
+                .insert(tx, stored)
+                .map_err(|_| MergeError::StoreUnavailable)?;
+        }
+        for (first, last, state) in other.compacted.runs() {
+            self.compacted.insert_run(first, last, state);
+        }
+        for (client, account) in accounts {
+            self.accounts.insert(client, account);
+        }
+        self.timers.append(other.timers);
+        for (client, locked_at) in other.last_chargeback {
+            let latest = self.last_chargeback.entry(client).or_default();
+            *latest = (*latest).max(locked_at);
+        }
+        self.dispute_opened.extend(other.dispute_opened);
+        self.partial_holds.extend(other.partial_holds);
+        self.holds.extend(other.holds);
+        self.metadata.extend(other.metadata);
+        self.metadata_bytes += other.metadata_bytes;
+        self.violations.append(&mut other.violations);
+        // Case ids of `other` continue after this engine's.
+        for (tx, mut case) in other.cases {
+            case.id += self.cases_opened;
+            self.cases.insert(tx, case);
+        }
+        self.cases_opened += other.cases_opened;
+        for (tx, owner) in other.id_owners {
+            self.id_owners.entry(tx).or_insert(owner);
+        }
+        // Sequence numbers of `other` continue after this engine's.
+        for (client, (time, seq)) in other.last_active {
+            let latest = self.last_active.entry(client).or_default();
+            *latest = (*latest).max((time, seq + self.seq));
+        }
+        for (client, activity) in other.last_activity {
+            let latest = self.last_activity.entry(client).or_default();
+            *latest = (*latest).max(activity);
+        }
+        for (client, activity) in other.account_activity {
+            self.account_activity
+                .entry(client)
+                .or_default()
+                .absorb(activity);
+        }
+        for (client, into) in other.merged {
+            self.merged.entry(client).or_insert(into);
+        }
+        for (client, mut entries) in other.history {
+            for entry in &mut entries {
+                entry.seq += self.seq;
+            }
+            self.history.entry(client).or_default().append(&mut entries);
+        }
+        self.history_entries += other.history_entries;
+        self.event_log.append(&mut other.event_log);
+        self.last_accrual = self.last_accrual.max(other.last_accrual);
+        for (client, counts) in other.velocity {
+            self.velocity.entry(client).or_default().absorb(counts);
+        }
+        for (client, limit) in other.overdrafts {
+            self.overdrafts.entry(client).or_insert(limit);
+        }
+        self.seq += other.seq;
+        self.now = self.now.max(other.now);
+        self.saturations += other.saturations;
+        self.stats.merge(&other.stats);
+        self.peak_memory = self.peak_memory.max(self.memory_usage());
+        Ok(())
+    }
+
+    /// The client `client` was merged into, if it was.
+    pub fn merged_into(&self, client: ClientId) -> Option<ClientId> {
+        self.merged.get(&client).copied()
+    }
+
+    /// Records the id introduced by a deposit, withdrawal, transfer or hold, reporting it if
+    /// already taken. Rejected transactions count too: a reused id is suspicious whatever
+    /// became of it.
+    fn track_id(&mut self, tx: &Transaction) {
+        if !matches!(
+            tx.tx_type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Hold
+        ) {
+            return;
+        }
+        match self.id_owners.entry(tx.tx) {
+            Entry::Vacant(entry) => {
+                entry.insert((tx.client, tx.tx_type));
+            }
+            Entry::Occupied(entry) => {
+                let (first_client, first_type) = *entry.get();
+                self.emit(EngineEvent::IdCollision {
+                    tx: tx.tx,
+                    client: tx.client,
+                    tx_type: tx.tx_type,
+                    first_client,
+                    first_type,
+                });
+            }
+        }
+    }
+
+    /// Rejects a deposit, withdrawal, transfer or hold whose id is already stored, compacted or
+    /// held, so a replayed transaction is not applied twice.
+    fn check_new_id(&self, tx: &Transaction) -> Result<(), ProcessError> {
+        if self
+            .transactions
+            .get(tx.tx)
+            .map_err(store_unavailable)?
+            .is_some()
+            || self.compacted.get(tx.tx).is_some()
+            || self.holds.contains_key(&tx.tx)
+        {
+            return Err(ProcessError::DuplicateTransaction);
+        }
+        Ok(())
+    }
+
+    /// A deposit, withdrawal or transfer amount in minor units, unless it can't be
+    /// represented at the engine's scale or exceeds the configured maximum.
+    fn fixed_amount(&self, amount: Decimal) -> Result<Money, ProcessError> {
+        if self.config.max_amount.is_some_and(|max| amount > max) {
+            return Err(AmountError::TooLarge.into());
+        }
+        Ok(self.config.precision.to_fixed(amount)?)
+    }
+
+    fn deposit(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        self.check_new_id(&tx)?;
+        let decimal_amount = tx.amount.ok_or(ProcessError::InvalidAmount)?;
+        if decimal_amount <= Decimal::ZERO {
+            return Err(ProcessError::InvalidAmount);
+        }
+
+        let amount = self.fixed_amount(decimal_amount)?;
+
+        let account = self.accounts.get_or_default(tx.client);
+        if account.locked && !self.config.locked.accepts_credits() {
+            return Err(ProcessError::AccountLocked);
+        }
+
+        let reserve = match self.config.reserve {
+            Some(policy) => decimal_amount
+                .checked_mul(policy.rate)
+                .map_or(Money::ZERO, |reserve| {
+                    Money::from_decimal_lossy(reserve, self.config.precision.scale)
+                })
+                .clamp(Money::ZERO, amount),
+            None => Money::ZERO,
+        };
+        let mut arith = Arith::new(self.config.arithmetic);
+        let available = arith.add(account.available, amount - reserve)?;
+        let reserved = arith.add(account.reserved, reserve)?;
+        let credited = Account {
+            available,
+            reserved,
+            ..*account
+        };
+        arith.check_total(&credited)?;
+        let ceiling = self.config.balance_limits_for(tx.client).ceiling;
+        if ceiling.is_some_and(|ceiling| total(&credited) > ceiling) {
+            return Err(ProcessError::AboveCeiling);
+        }
+        let stored = StoredTransaction {
+            client: tx.client,
+            amount,
+            dispute_state: DisputeState::None,
+            kind: StoredKind::Deposit,
+            timestamp: tx.timestamp,
+        };
+        self.transactions
+            .insert(tx.tx, stored)
+            .map_err(store_unavailable)?;
+        account.available = available;
+        account.reserved = reserved;
+        if reserve.is_positive() {
+            let period = self
+                .config
+                .reserve
+                .map_or(0, |policy| policy.period.as_secs());
+            let due = tx.timestamp.unwrap_or(self.now).saturating_add(period);
+            self.timers.schedule(
+                due,
+                Timer::ReleaseReserve {
+                    client: tx.client,
+                    tx: tx.tx,
+                    amount: reserve,
+                },
+            );
+        }
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    fn withdrawal(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        self.check_new_id(&tx)?;
+        let decimal_amount = tx.amount.ok_or(ProcessError::InvalidAmount)?;
+        if decimal_amount <= Decimal::ZERO {
+            return Err(ProcessError::InvalidAmount);
+        }
+
+        let amount = self.fixed_amount(decimal_amount)?;
+        let mut arith = Arith::new(self.config.arithmetic);
+        let (fee, collector) = self.withdrawal_fee(tx.client, amount, &mut arith)?;
+        let overdraft = self.overdraft_limit(tx.client);
+
+        let account = self.accounts.get_or_default(tx.client);
+        if account.locked {
+            return Err(ProcessError::AccountLocked);
+        }
+
+        let debit = amount
+            .checked_add(fee)
+            .ok_or(ProcessError::InsufficientFunds)?;
+        if account.available.saturating_add(overdraft) < debit {
+            return Err(ProcessError::InsufficientFunds);
+        }
+        let available = arith.sub(account.available, debit)?;
+        let floor = self.config.balance_limits_for(tx.client).floor;
+        if floor.is_some_and(|floor| {
+            total(&Account {
+                available,
+                ..*account
+            }) < floor
+        }) {
+            return Err(ProcessError::BelowFloor);
+        }
+        let stored = StoredTransaction {
+            client: tx.client,
+            amount,
+            dispute_state: DisputeState::None,
+            kind: StoredKind::Withdrawal,
+            timestamp: tx.timestamp,
+        };
+        self.transactions
+            .insert(tx.tx, stored)
+            .map_err(store_unavailable)?;
+        account.available = available;
+        if let Some((client, collector)) = collector {
+            self.accounts.insert(client, collector);
+        }
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// The fee `client` pays on a withdrawal of `amount`, and the fee account as it will be
+    /// once credited with it. No fee without a policy or for the fee account itself.
+    fn withdrawal_fee(
+        &mut self,
+        client: ClientId,
+        amount: Money,
+        arith: &mut Arith,
+    ) -> Result<(Money, Option<(ClientId, Account)>), ProcessError> {
+        let Some(fees) = self.config.fees.filter(|fees| fees.account != client) else {
+            return Ok((Money::ZERO, None));
+        };
+        let fee = fees.fee(amount, self.config.precision.scale);
+        if !fee.is_positive() {
+            return Ok((Money::ZERO, None));
+        }
+        self.touch(fees.account)?;
+        let collector = self.accounts.get(fees.account).copied().unwrap_or_default();
+        let available = arith.add(collector.available, fee)?;
+        arith.check_total(&Account {
+            available,
+            ..collector
+        })?;
+        Ok((
+            fee,
+            Some((
+                fees.account,
+                Account {
+                    available,
+                    ..collector
+                },
+            )),
+        ))
+    }
+
+    /// Moves funds from `tx.client`'s available balance to `tx.to_client`'s, or changes
+    /// nothing. The transfer is stored so the sender can dispute it later.
+    fn transfer(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        self.check_new_id(&tx)?;
+        let decimal_amount = tx.amount.ok_or(ProcessError::InvalidAmount)?;
+        if decimal_amount <= Decimal::ZERO {
+            return Err(ProcessError::InvalidAmount);
+        }
+        let to = tx
+            .to_client
+            .filter(|&to| to != tx.client)
+            .ok_or(ProcessError::InvalidRecipient)?;
+        self.touch(to)?;
+
+        let amount = self.fixed_amount(decimal_amount)?;
+        let source = *self.accounts.get_or_default(tx.client);
+        let target = self.accounts.get(to).copied().unwrap_or_default();
+        if source.locked || (target.locked && !self.config.locked.accepts_credits()) {
+            return Err(ProcessError::AccountLocked);
+        }
+        if source.available < amount {
+            return Err(ProcessError::InsufficientFunds);
+        }
+
+        let mut arith = Arith::new(self.config.arithmetic);
+        let debited = arith.sub(source.available, amount)?;
+        let credited = arith.add(target.available, amount)?;
+        arith.check_total(&Account {
+            available: credited,
+            ..target
+        })?;
+        let floor = self.config.balance_limits_for(tx.client).floor;
+        if floor.is_some_and(|floor| {
+            total(&Account {
+                available: debited,
+                ..source
+            }) < floor
+        }) {
+            return Err(ProcessError::BelowFloor);
+        }
+        let ceiling = self.config.balance_limits_for(to).ceiling;
+        if ceiling.is_some_and(|ceiling| {
+            total(&Account {
+                available: credited,
+                ..target
+            }) > ceiling
+        }) {
+            return Err(ProcessError::AboveCeiling);
+        }
+        let stored = StoredTransaction {
+            client: tx.client,
+            amount,
+            dispute_state: DisputeState::None,
+            kind: StoredKind::Transfer { to_client: to },
+            timestamp: tx.timestamp,
+        };
+        self.transactions
+            .insert(tx.tx, stored)
+            .map_err(store_unavailable)?;
+        self.accounts.insert(
+            tx.client,
+            Account {
+                available: debited,
+                ..source
+            },
+        );
+        self.accounts.insert(
+            to,
+            Account {
+                available: credited,
+                ..target
+            },
+        );
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Looks up the transaction a dispute, resolve or chargeback from `tx.client` refers to.
+    fn referenced(&self, tx: &Transaction) -> Result<StoredTransaction, ProcessError> {
+        let Some(stored) = self.transactions.get(tx.tx).map_err(store_unavailable)? else {
+            return Err(self
+                .compacted
+                .get(tx.tx)
+                .map_or(ProcessError::UnknownTransaction, |state| {
+                    compaction::rejection(tx.tx_type, state)
+                }));
+        };
+        if stored.client != tx.client {
+            return Err(ProcessError::ClientMismatch);
+        }
+        Ok(stored)
+    }
+
+    /// Deposits, withdrawals and transfers can be disputed by the client who made them.
+    /// Disputing a deposit or transfer holds the amount on the account that received it: the
+    /// depositor's, or the recipient's for a transfer. Disputing a withdrawal holds the amount
+    /// as a potential credit, leaving available funds alone.
+    /// If the funds to hold are no longer available, the shortfall policy decides whether the
+    /// dispute takes available negative, is rejected or holds only what is left.
+    /// Whether disputes can happen on a locked account depends on `EngineConfig::locked`.
+    /// A transaction can only be disputed if it's not currently disputed and hasn't been chargedback.
+    /// With dispute cases enabled, an accepted dispute opens a case with `reason`.
+    fn dispute(&mut self, tx: Transaction, reason: Option<String>) -> Result<(), ProcessError> {
+        let stored = self.referenced(&tx)?;
+        match stored.dispute_state {
+            DisputeState::None => {}
+            DisputeState::Disputed => return Err(ProcessError::AlreadyDisputed),
+            DisputeState::ChargedBack => return Err(ProcessError::AlreadyChargedBack),
+        }
+        let deadline = self
+            .config
+            .dispute_window
+            .zip(stored.timestamp)
+            .map(|(window, made_at)| made_at.saturating_add(window.as_secs()));
+        if deadline.is_some_and(|deadline| tx.timestamp.unwrap_or(self.now) > deadline) {
+            return Err(ProcessError::DisputeWindowClosed);
+        }
+        let amount = stored.amount;
+        let kind = stored.kind;
+        let credited = stored.credited();
+        self.touch(credited)?;
+
+        let account = self.accounts.get_or_default(credited);
+        if account.locked && !self.config.locked.accepts_disputes() {
+            return Err(ProcessError::AccountLocked);
+        }
+        let hold = match (kind, self.config.dispute_shortfall) {
+            (StoredKind::Withdrawal, _) | (_, DisputeShortfallPolicy::AllowNegative) => amount,
+            _ if account.available >= amount => amount,
+            // The deposit's own reserve is released into available later, so it covers the
+            // hold too.
+            (_, DisputeShortfallPolicy::RejectDispute) => {
+                let reserve = self.timers.reserve_of(tx.tx);
+                if account.available.saturating_add(reserve) < amount {
+                    return Err(ProcessError::DisputeShortfall);
+                }
+                amount
+            }
+            (_, DisputeShortfallPolicy::HoldPartial) => account.available.max(Money::ZERO),
+        };
+        let mut arith = Arith::new(self.config.arithmetic);
+        let available = match kind {
+            StoredKind::Withdrawal => account.available,
+            StoredKind::Deposit | StoredKind::Transfer { .. } => {
+                arith.sub(account.available, hold)?
+            }
+        };
+        let held = arith.add(account.held, hold)?;
+        self.transactions
+            .insert(
+                tx.tx,
+                StoredTransaction {
+                    dispute_state: DisputeState::Disputed,
+                    ..stored
+                },
+            )
+            .map_err(store_unavailable)?;
+        account.available = available;
+        account.held = held;
+        if hold != amount {
+            self.partial_holds.insert(tx.tx, hold);
+        }
+        if self.config.keep_dispute_cases {
+            self.cases_opened += 1;
+            let case = DisputeCase {
+                id: self.cases_opened,
+                client: tx.client,
+                tx: tx.tx,
+                amount: hold,
+                opened_at: tx.timestamp.unwrap_or(self.now),
+                reason,
+                status: CaseStatus::Open,
+            };
+            self.cases.insert(tx.tx, case);
+        }
+        if let Some(expiry) = self.config.dispute_expiry {
+            let opened_at = tx.timestamp.unwrap_or(self.now);
+            self.dispute_opened.insert(tx.tx, opened_at);
+            let due = opened_at.saturating_add(expiry.after.as_secs());
+            self.timers.schedule(
+                due,
+                Timer::ExpireDispute {
+                    client: tx.client,
+                    tx: tx.tx,
+                    opened_at,
+                },
+            );
+        }
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Resolve returns held funds to available, or for a withdrawal drops the potential
+    /// credit. Only works on currently disputed transactions.
+    /// After resolve, the transaction returns to None state and can be disputed again.
+    fn resolve(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        let stored = self.referenced(&tx)?;
+        if stored.dispute_state != DisputeState::Disputed {
+            return Err(ProcessError::NotDisputed);
+        }
+        let amount = self.held_by(tx.tx, &stored);
+        let kind = stored.kind;
+        let credited = stored.credited();
+        self.touch(credited)?;
+
+        let account = self.accounts.get_or_default(credited);
+        if account.locked && !self.config.locked.settles_disputes() {
+            return Err(ProcessError::AccountLocked);
+        }
+        let mut arith = Arith::new(self.config.arithmetic);
+        let held = arith.sub(account.held, amount)?;
+        let available = match kind {
+            StoredKind::Withdrawal => account.available,
+            StoredKind::Deposit | StoredKind::Transfer { .. } => {
+                arith.add(account.available, amount)?
+            }
+        };
+        self.transactions
+            .insert(
+                tx.tx,
+                StoredTransaction {
+                    dispute_state: DisputeState::None,
+                    ..stored
+                },
+            )
+            .map_err(store_unavailable)?;
+        account.held = held;
+        account.available = available;
+        self.dispute_opened.remove(&tx.tx);
+        self.partial_holds.remove(&tx.tx);
+        self.cases.remove(&tx.tx);
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Chargeback is a terminal state - the transaction can never be disputed again.
+    /// The held funds leave the account holding them, which is locked. For a transfer they go
+    /// back to the sender's available balance, and for a withdrawal they return to the
+    /// client's.
+    fn chargeback(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        let stored = self.referenced(&tx)?;
+        if stored.dispute_state != DisputeState::Disputed {
+            return Err(ProcessError::NotDisputed);
+        }
+        let amount = self.held_by(tx.tx, &stored);
+        let credited = stored.credited();
+        let refund_to = match stored.kind {
+            StoredKind::Transfer { .. } | StoredKind::Withdrawal => Some(stored.client),
+            StoredKind::Deposit => None,
+        };
+        self.touch(credited)?;
+        if self
+            .accounts
+            .get(credited)
+            .is_some_and(|account| account.locked)
+            && !self.config.locked.settles_disputes()
+        {
+            return Err(ProcessError::AccountLocked);
+        }
+
+        let mut arith = Arith::new(self.config.arithmetic);
+        let held = arith.sub(
+            self.accounts
+                .get(credited)
+                .map_or(Money::ZERO, |account| account.held),
+            amount,
+        )?;
+        let refunded = match refund_to {
+            Some(client) => Some(
+                arith.add(
+                    self.accounts
+                        .get(client)
+                        .map_or(Money::ZERO, |account| account.available),
+                    amount,
+                )?,
+            ),
+            None => None,
+        };
+        self.transactions
+            .insert(
+                tx.tx,
+                StoredTransaction {
+                    dispute_state: DisputeState::ChargedBack,
+                    ..stored
+                },
+            )
+            .map_err(store_unavailable)?;
+        let account = self.accounts.get_or_default(credited);
+        account.held = held;
+        if !account.locked {
+            self.stats.accounts_locked += 1;
+        }
+        account.locked = true;
+        if let (Some(client), Some(available)) = (refund_to, refunded) {
+            self.accounts.get_or_default(client).available = available;
+        }
+        self.dispute_opened.remove(&tx.tx);
+        self.cases.remove(&tx.tx);
+        if let Some(cooldown) = self.config.auto_unlock {
+            let locked_at = tx.timestamp.unwrap_or(self.now);
+            self.last_chargeback.insert(credited, locked_at);
+            let due = locked_at.saturating_add(cooldown.as_secs());
+            self.timers.schedule(
+                due,
+                Timer::Unlock {
+                    client: credited,
+                    locked_at,
+                },
+            );
+        }
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Chargeback reversal undoes a chargeback, as when the bank decides for the merchant
+    /// after all: the funds the chargeback took return to the available balance of the
+    /// account that held them, and the refund a transfer's sender or a withdrawing client got
+    /// is taken back, even if that leaves them negative. The transaction can then be disputed
+    /// again. With `EngineConfig::unlock_on_reversal` the account is unlocked too, unless
+    /// another of the transactions it holds is still charged back.
+    fn chargeback_reversal(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        let stored = self.referenced(&tx)?;
+        if stored.dispute_state != DisputeState::ChargedBack {
+            return Err(ProcessError::NotChargedBack);
+        }
+        let amount = self.held_by(tx.tx, &stored);
+        let credited = stored.credited();
+        let (recredit, debit) = match stored.kind {
+            StoredKind::Deposit => (Some(credited), None),
+            StoredKind::Transfer { .. } => (Some(credited), Some(stored.client)),
+            StoredKind::Withdrawal => (None, Some(stored.client)),
+        };
+        self.touch(credited)?;
+
+        let mut arith = Arith::new(self.config.arithmetic);
+        let available = |client: ClientId| {
+            self.accounts
+                .get(client)
+                .map_or(Money::ZERO, |account| account.available)
+        };
+        let recredited = match recredit {
+            Some(client) => Some((client, arith.add(available(client), amount)?)),
+            None => None,
+        };
+        let debited = match debit {
+            Some(client) => Some((client, arith.sub(available(client), amount)?)),
+            None => None,
+        };
+        self.transactions
+            .insert(
+                tx.tx,
+                StoredTransaction {
+                    dispute_state: DisputeState::None,
+                    ..stored
+                },
+            )
+            .map_err(store_unavailable)?;
+        for (client, available) in recredited.into_iter().chain(debited) {
+            self.accounts.get_or_default(client).available = available;
+        }
+        self.partial_holds.remove(&tx.tx);
+        if self.config.unlock_on_reversal
+            && !self.transactions().any(|(_, other)| {
+                other.dispute_state == DisputeState::ChargedBack && other.credited() == credited
+            })
+        {
+            self.last_chargeback.remove(&credited);
+            if let Some(account) = self
+                .accounts
+                .get_mut(credited)
+                .filter(|account| account.locked)
+            {
+                account.locked = false;
+                self.stats.accounts_unlocked += 1;
+                self.emit(EngineEvent::AccountUnlocked { client: credited });
+            }
+        }
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Hold sets `tx.amount` of the client's available funds aside under the hold's own id,
+    /// apart from the funds held by disputes, until a release returns them. Only available
+    /// funds can be put on hold. Like a dispute, it works on a locked account if
+    /// `EngineConfig::locked` accepts disputes.
+    fn place_hold(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        self.check_new_id(&tx)?;
+        let decimal_amount = tx.amount.ok_or(ProcessError::InvalidAmount)?;
+        if decimal_amount <= Decimal::ZERO {
+            return Err(ProcessError::InvalidAmount);
+        }
+
+        let amount = self.fixed_amount(decimal_amount)?;
+        let account = self.accounts.get_or_default(tx.client);
+        if account.locked && !self.config.locked.accepts_disputes() {
+            return Err(ProcessError::AccountLocked);
+        }
+        if account.available < amount {
+            return Err(ProcessError::InsufficientFunds);
+        }
+        let mut arith = Arith::new(self.config.arithmetic);
+        let available = arith.sub(account.available, amount)?;
+        let on_hold = arith.add(account.on_hold, amount)?;
+        account.available = available;
+        account.on_hold = on_hold;
+        self.holds.insert(
+            tx.tx,
+            Hold {
+                client: tx.client,
+                amount,
+                remaining: amount,
+            },
+        );
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Release returns funds of the hold `tx.tx` to available: `tx.amount` of them, or all
+    /// that is left on the hold without an amount. A hold can be released in several parts,
+    /// and once nothing is left on it, it is done.
+    fn release_hold(&mut self, tx: Transaction) -> Result<(), ProcessError> {
+        let hold = *self
+            .holds
+            .get(&tx.tx)
+            .ok_or(ProcessError::UnknownTransaction)?;
+        if hold.client != tx.client {
+            return Err(ProcessError::ClientMismatch);
+        }
+        if hold.remaining.is_zero() {
+            return Err(ProcessError::HoldReleased);
+        }
+        let amount = match tx.amount {
+            Some(amount) if amount <= Decimal::ZERO => return Err(ProcessError::InvalidAmount),
+            Some(amount) => self.fixed_amount(amount)?,
+            None => hold.remaining,
+        };
+        if amount > hold.remaining {
+            return Err(ProcessError::ReleaseExceedsHold);
+        }
+
+        let account = self.accounts.get_or_default(tx.client);
+        if account.locked && !self.config.locked.settles_disputes() {
+            return Err(ProcessError::AccountLocked);
+        }
+        let mut arith = Arith::new(self.config.arithmetic);
+        let on_hold = arith.sub(account.on_hold, amount)?;
+        let available = arith.add(account.available, amount)?;
+        account.on_hold = on_hold;
+        account.available = available;
+        self.holds.insert(
+            tx.tx,
+            Hold {
+                remaining: hold.remaining - amount,
+                ..hold
+            },
+        );
+        if arith.clamped {
+            self.saturated(tx.client, tx.tx, amount);
+        }
+        Ok(())
+    }
+
+    /// Funds the open dispute of `stored`, with id `tx`, holds: its whole amount unless the
+    /// dispute was short of funds. The same goes for the funds its chargeback took.
+    pub(crate) fn held_by(&self, tx: TxId, stored: &StoredTransaction) -> Money {
+        self.partial_holds
+            .get(&tx)
+            .copied()
+            .unwrap_or(stored.amount)
+    }
+
+    /// The account of `client`, if it is in memory.
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(client)
+    }
+
+    /// Every account in memory, in no particular order. Archived accounts are not included.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &Account)> + '_ {
+        self.accounts.iter()
+    }
+
+    /// A 128-bit FNV-1a hash of the accounts in memory, taken in client order, and of the
+    /// scale their balances are kept at. Two engines with the same digest hold the same
+    /// accounts, so runs can be compared bit for bit without comparing reports. Clients
+    /// below 2^16 are hashed as 2 bytes, the width ids had when digests were introduced, so
+    /// recorded digests stay valid.
+    pub fn state_digest(&self) -> u128 {
+        const OFFSET: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+        const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+        let mut accounts: Vec<_> = self.accounts().collect();
+        accounts.sort_unstable_by_key(|&(client, _)| client);
+        let mut bytes = self.config.precision.scale.to_le_bytes().to_vec();
+        for (client, account) in accounts {
+            match u16::try_from(client) {
+                Ok(client) => bytes.extend_from_slice(&client.to_le_bytes()),
+                Err(_) => bytes.extend_from_slice(&client.to_le_bytes()),
+            }
+            for balance in [
+                account.available,
+                account.held,
+                account.reserved,
+                account.on_hold,
+            ] {
+                bytes.extend_from_slice(&balance.units().to_le_bytes());
+            }
+            bytes.extend_from_slice(&[u8::from(account.locked), u8::from(account.closed)]);
+        }
+        bytes.iter().fold(OFFSET, |hash, &byte| {
+            (hash ^ u128::from(byte)).wrapping_mul(PRIME)
+        })
+    }
+
+    /// Every stored transaction, in no particular order. Stops early, with a warning, if the
+    /// store can't be read.
+    pub fn transactions(&self) -> impl Iterator<Item = (TxId, StoredTransaction)> + '_ {
+        self.transactions.iter().map_while(|entry| {
+            entry
+                .map_err(|error| log::warn!("could not read the transaction store: {}", error))
+                .ok()
+        })
+    }
+
+    /// Every stored transfer that wasn't charged back, as an obligation of its sender to its
+    /// receiver, for [`netting`](crate::netting).
+    pub fn obligations(&self) -> impl Iterator<Item = Obligation> + '_ {
+        self.transactions()
+            .filter_map(|(_, stored)| match stored.kind {
+                StoredKind::Transfer { to_client }
+                    if stored.dispute_state != DisputeState::ChargedBack =>
+                {
+                    Some(Obligation {
+                        from: stored.client,
+                        to: to_client,
+                        amount: stored.amount,
+                    })
+                }
+                _ => None,
+            })
+    }
+
+    /// Every hold, including those released in full, in no particular order.
+    pub fn holds(&self) -> impl Iterator<Item = (TxId, Hold)> + '_ {
+        self.holds.iter().map(|(&tx, &hold)| (tx, hold))
+    }
+
+    /// The hold with id `tx`, including one released in full. Releases refer to these.
+    pub fn hold(&self, tx: TxId) -> Option<Hold> {
+        self.holds.get(&tx).copied()
+    }
+
+    /// The stored deposit, withdrawal or transfer with id `tx`, if any. Disputes, resolves
+    /// and chargebacks refer to these. A store that can't be read is logged and counts as
+    /// not having it.
+    pub fn transaction(&self, tx: TxId) -> Option<StoredTransaction> {
+        self.transactions.get(tx).unwrap_or_else(|error| {
+            log::warn!(
+                "could not read transaction {} from the store: {}",
+                tx,
+                error
+            );
+            None
+        })
+    }
+
+    /// Keeps stored transactions in `store` from now on, moving those already stored into
+    /// it; see [`crate::store`]. Fails, leaving the current store in place, if they can't be
+    /// moved.
+    pub fn set_store(&mut self, mut store: impl TxStore + 'static) -> io::Result<()> {
+        for entry in self.transactions.iter() {
+            let (tx, stored) = entry?;
+            store.insert(tx, stored)?;
+        }
+        self.transactions = Box::new(store);
+        Ok(())
+    }
+
+    /// The account report, one row per account in memory, in no particular order.
+    pub fn output(&self) -> Vec<AccountOutput> {
+        self.iter_output().collect()
+    }
+
+    /// The rows of [`Engine::output`], built one at a time as the iterator is advanced.
+    pub fn iter_output(&self) -> impl Iterator<Item = AccountOutput> + '_ {
+        let disputes = self.open_dispute_counts();
+        self.accounts()
+            .map(move |(client, account)| self.report_row(client, account, &disputes))
+    }
+
+    /// [`Engine::output`] ordered by client id, so the same input always gives the same report.
+    pub fn output_sorted(&self) -> Vec<AccountOutput> {
+        let mut output = self.output();
+        output.sort_unstable_by_key(|account| account.client);
+        output
+    }
+
+    /// `client`'s row of [`Engine::output`], if it has an account in memory.
+    pub fn account_output(&self, client: ClientId) -> Option<AccountOutput> {
+        self.account(client)
+            .map(|account| self.report_row(client, account, &self.open_dispute_counts()))
+    }
+
+    /// Open disputes per client holding the disputed funds, counted only for extended output.
+    fn open_dispute_counts(&self) -> HashMap<ClientId, u64> {
+        let mut counts = HashMap::new();
+        if self.config.extended_output {
+            for (_, stored) in self
+                .transactions()
+                .filter(|(_, stored)| stored.dispute_state == DisputeState::Disputed)
+            {
+                *counts.entry(stored.credited()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    fn report_row(
+        &self,
+        client: ClientId,
+        account: &Account,
+        disputes: &HashMap<ClientId, u64>,
+    ) -> AccountOutput {
+        AccountOutput {
+            client,
+            available: account.available,
+            // Funds on hold are reported with those held by disputes.
+            held: account.held.saturating_add(account.on_hold),
+            total: account.total(),
+            locked: account.locked,
+            reserved: self.config.reserve.map(|_| account.reserved),
+            last_activity: self
+                .config
+                .report_last_activity
+                .then(|| self.last_activity.get(&client).copied().unwrap_or_default()),
+            closed: self.config.extended_output.then_some(account.closed),
+            overdrawn: self
+                .config
+                .extended_output
+                .then_some(account.available.is_negative()),
+            activity: self.config.extended_output.then(|| AccountActivity {
+                open_disputes: disputes.get(&client).copied().unwrap_or_default(),
+                ..self
+                    .account_activity
+                    .get(&client)
+                    .copied()
+                    .unwrap_or_default()
+            }),
+            scale: self.config.precision.scale,
+        }
+    }
+}
+
+/// Account total for limit checks, saturating so it never panics.
+fn total(account: &Account) -> Money {
+    account
+        .available
+        .saturating_add(account.held)
+        .saturating_add(account.reserved)
+        .saturating_add(account.on_hold)
+}
+
+/// Balance arithmetic under the configured [`ArithmeticMode`]. In saturating mode results
+/// are clamped at the `i64` bounds and `clamped` records that it happened; in checked mode
+/// an out-of-range result is an error, so the caller can reject before mutating anything.
+struct Arith {
+    mode: ArithmeticMode,
+    clamped: bool,
+}
+
+impl Arith {
+    fn new(mode: ArithmeticMode) -> Self {
+        Self {
+            mode,
+            clamped: false,
+        }
+    }
+
+    fn add(&mut self, a: Money, b: Money) -> Result<Money, ProcessError> {
+        self.exact_or(a.checked_add(b), || a.saturating_add(b))
+    }
+
+    fn sub(&mut self, a: Money, b: Money) -> Result<Money, ProcessError> {
+        self.exact_or(a.checked_sub(b), || a.saturating_sub(b))
+    }
+
+    /// In checked mode, also requires the total of `account`, as it is about to become, to
+    /// stay representable.
+    fn check_total(&self, account: &Account) -> Result<(), ProcessError> {
+        let total = account
+            .available
+            .checked_add(account.held)
+            .and_then(|sum| sum.checked_add(account.reserved))
+            .and_then(|sum| sum.checked_add(account.on_hold));
+        match (self.mode, total) {
+            (ArithmeticMode::Checked | ArithmeticMode::Freeze, None) => Err(ProcessError::Overflow),
+            _ => Ok(()),
+        }
+    }
+
+    fn exact_or(
+        &mut self,
+        exact: Option<Money>,
+        saturated: impl FnOnce() -> Money,
+    ) -> Result<Money, ProcessError> {
+        match (exact, self.mode) {
+            (Some(value), _) => Ok(value),
+            (None, ArithmeticMode::Checked | ArithmeticMode::Freeze) => Err(ProcessError::Overflow),
+            (None, ArithmeticMode::Saturating) => {
+                self.clamped = true;
+                Ok(saturated())
+            }
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LockedPolicy;
+    use crate::types::{SCALE, to_fixed};
+    use rust_decimal_macros::dec;
+
+    fn deposit(client: ClientId, tx: TxId, amount: Decimal) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client,
+            tx,
+            amount: Some(amount),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn withdrawal(client: ClientId, tx: TxId, amount: Decimal) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client,
+            tx,
+            amount: Some(amount),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn dispute(client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Dispute,
+            client,
+            tx,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn resolve(client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Resolve,
+            client,
+            tx,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    fn chargeback(client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Chargeback,
+            client,
+            tx,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    /// Helper to create fixed-point value from integer and decimal parts
+    fn fixed(whole: i64, frac: i64) -> Money {
+        Money::from_units(whole * SCALE + frac)
+    }
+
+    #[test]
+    fn test_deposit() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+        assert_eq!(account.held, Money::ZERO);
+        assert_eq!(account.total, fixed(10, 0));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn test_output_sorted() {
+        let mut engine = Engine::new();
+        for (tx, client) in [500, 3, 70, 1, 65535, 2].into_iter().enumerate() {
+            engine
+                .process(deposit(client, tx as TxId + 1, dec!(1.0)))
+                .unwrap();
+        }
+
+        let clients: Vec<ClientId> = engine.output_sorted().iter().map(|a| a.client).collect();
+        assert_eq!(clients, vec![1, 2, 3, 70, 500, 65535]);
+        assert_eq!(engine.output().len(), clients.len());
+    }
+
+    #[test]
+    fn test_multiple_deposits() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(5.5))).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(15, 5000));
+    }
+
+    #[test]
+    fn test_withdrawal_sufficient_funds() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(4.0))).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(6, 0));
+    }
+
+    #[test]
+    fn test_withdrawal_insufficient_funds() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 2, dec!(15.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_withdrawal_exact_balance() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(10.0))).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+    }
+
+    #[test]
+    fn test_dispute() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert_eq!(account.held, fixed(10, 0));
+        assert_eq!(account.total, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_dispute_nonexistent_tx() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(dispute(1, 999)),
+            Err(ProcessError::UnknownTransaction)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+        assert_eq!(account.held, Money::ZERO);
+    }
+
+    #[test]
+    fn test_dispute_wrong_client() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(dispute(2, 1)),
+            Err(ProcessError::ClientMismatch)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_double_dispute_ignored() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::AlreadyDisputed)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert_eq!(account.held, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(resolve(1, 1)).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+        assert_eq!(account.held, Money::ZERO);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn test_resolve_not_disputed() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(resolve(1, 1)),
+            Err(ProcessError::NotDisputed)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_chargeback() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert_eq!(account.held, Money::ZERO);
+        assert_eq!(account.total, Money::ZERO);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_chargeback_not_disputed() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(chargeback(1, 1)),
+            Err(ProcessError::NotDisputed)
+        );
 
         let output = engine.output();
         let account = output.iter().find(|a| a.client == 1).unwrap();
         assert_eq!(account.available, fixed(10, 0));
-        assert_eq!(account.held, 0);
         assert!(!account.locked);
     }
 
     #[test]
-    fn test_resolve_not_disputed() {
+    fn test_locked_account_rejects_deposit() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(50.0))),
+            Err(ProcessError::AccountLocked)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_locked_account_rejects_withdrawal() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 3, dec!(5.0))),
+            Err(ProcessError::AccountLocked)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_locked_account_allows_dispute() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(20.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        // Account is now locked with 20 available
+        engine.process(dispute(1, 2)).unwrap(); // Should still work
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert_eq!(account.held, fixed(20, 0));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_locked_account_allows_resolve() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(20.0))).unwrap();
+        engine.process(dispute(1, 2)).unwrap(); // Dispute tx 2 first
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap(); // Lock via tx 1
+        // Account is now locked with 0 available, 20 held
+        engine.process(resolve(1, 2)).unwrap(); // Should still work
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(20, 0));
+        assert_eq!(account.held, Money::ZERO);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_locked_policy_allow_credits() {
+        let mut engine = Engine::with_config(
+            EngineConfig::default().with_locked_policy(LockedPolicy::AllowCreditsOnly),
+        );
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(20.0))).unwrap();
+        engine.process(deposit(2, 3, dec!(5.0))).unwrap();
+        engine.process(dispute(1, 2)).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+
+        // Credits are accepted, disputes and debits are not.
+        engine.process(deposit(1, 4, dec!(7.0))).unwrap();
+        engine.process(transfer(2, 5, 1, dec!(3.0))).unwrap();
+        assert_eq!(
+            engine.process(dispute(1, 4)),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(
+            engine.process(withdrawal(1, 6, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(
+            engine.process(transfer(1, 7, 2, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+        // A dispute opened before the lock still settles.
+        engine.process(resolve(1, 2)).unwrap();
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.locked),
+            (fixed(30, 0), Money::ZERO, true)
+        );
+    }
+
+    #[test]
+    fn test_locked_policy_reject_all() {
+        let mut engine = Engine::with_config(
+            EngineConfig::default().with_locked_policy(LockedPolicy::RejectAll),
+        );
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(20.0))).unwrap();
+        engine.process(dispute(1, 2)).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+
+        assert_eq!(
+            engine.process(deposit(1, 3, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(
+            engine.process(resolve(1, 2)),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(
+            engine.process(chargeback(1, 2)),
+            Err(ProcessError::AccountLocked)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Money::ZERO, fixed(20, 0))
+        );
+    }
+
+    #[test]
+    fn test_dispute_withdrawal() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(5.0))).unwrap();
+        engine.process(dispute(1, 2)).unwrap();
+
+        // The disputed amount is held as a potential credit.
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (fixed(5, 0), fixed(5, 0))
+        );
+
+        // Resolving cancels it; the withdrawal stands.
+        engine.process(resolve(1, 2)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (fixed(5, 0), Money::ZERO)
+        );
+
+        // A chargeback returns the funds and locks the account.
+        engine.process(dispute(1, 2)).unwrap();
+        engine.process(chargeback(1, 2)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.locked),
+            (fixed(10, 0), Money::ZERO, true)
+        );
+        assert_eq!(
+            engine.process(dispute(1, 2)),
+            Err(ProcessError::AlreadyChargedBack)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_ids_rejected() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(3.0))).unwrap();
+        assert_eq!(
+            engine.process(deposit(1, 1, dec!(10.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.process(withdrawal(1, 2, dec!(3.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.process(deposit(2, 2, dec!(5.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+
+        // The originals are untouched and can still be disputed.
+        assert_eq!(engine.account(1).unwrap().available, fixed(7, 0));
+        assert_eq!(engine.account(2), None);
+        engine.process(dispute(1, 1)).unwrap();
+        assert_eq!(engine.transaction(1).unwrap().amount, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_chargeback_prevents_redispute() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        // Try to dispute again - should be ignored
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::AlreadyChargedBack)
+        );
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert_eq!(account.held, Money::ZERO); // Should still be 0, not 10
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_resolve_allows_redispute() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(resolve(1, 1)).unwrap();
+        // Dispute again after resolve - should work
+        engine.process(dispute(1, 1)).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, Money::ZERO);
+        assert_eq!(account.held, fixed(10, 0));
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(1.2345))).unwrap();
+        engine.process(deposit(1, 2, dec!(0.0001))).unwrap();
+
+        let output = engine.output();
+        let account = output.iter().find(|a| a.client == 1).unwrap();
+        assert_eq!(account.available, fixed(1, 2346));
+    }
+
+    #[test]
+    fn test_multiple_clients() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(2, 2, dec!(20.0))).unwrap();
+        engine.process(withdrawal(1, 3, dec!(5.0))).unwrap();
+
+        let output = engine.output();
+        let client1 = output.iter().find(|a| a.client == 1).unwrap();
+        let client2 = output.iter().find(|a| a.client == 2).unwrap();
+        assert_eq!(client1.available, fixed(5, 0));
+        assert_eq!(client2.available, fixed(20, 0));
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_dense_account_storage_matches_map() {
+        use crate::config::AccountStorage;
+
+        let input = std::fs::read("examples/3k_records.csv").unwrap();
+        let mut reports = Vec::new();
+        for storage in [AccountStorage::Map, AccountStorage::Dense] {
+            let mut engine =
+                Engine::with_config(EngineConfig::default().with_account_storage(storage));
+            let summary = engine.process_csv(input.as_slice()).unwrap();
+            let mut out = Vec::new();
+            engine.write_accounts_csv(&mut out).unwrap();
+            reports.push((summary, out));
+        }
+        assert_eq!(reports[0], reports[1]);
+    }
+
+    #[test]
+    fn test_query_accounts_and_transactions() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(2, 2, dec!(20.0))).unwrap();
+        engine.process(dispute(2, 2)).unwrap();
+
+        let mut accounts: Vec<_> = engine
+            .accounts()
+            .map(|(client, account)| (client, account.available))
+            .collect();
+        accounts.sort();
+        assert_eq!(accounts, [(1, fixed(10, 0)), (2, Money::ZERO)]);
+        assert_eq!(engine.account(2).unwrap().held, fixed(20, 0));
+        assert_eq!(
+            engine.transaction(2).map(|stored| stored.dispute_state),
+            Some(DisputeState::Disputed)
+        );
+        assert!(engine.transaction(3).is_none());
+    }
+
+    #[test]
+    fn test_rolling_reserve_withheld_and_released() {
+        use crate::config::ReservePolicy;
+        use std::time::Duration;
+
+        let config = EngineConfig::default().with_reserve(ReservePolicy {
+            rate: dec!(0.1),
+            period: Duration::from_secs(100),
+        });
+        let mut engine = Engine::with_config(config);
+        engine
+            .process(Transaction {
+                timestamp: Some(1_000),
+                to_client: None,
+                ..deposit(1, 1, dec!(50.0))
+            })
+            .unwrap();
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, fixed(45, 0));
+        assert_eq!(account.reserved, fixed(5, 0));
+        assert_eq!(account.total(), fixed(50, 0));
+
+        engine
+            .process(Transaction {
+                timestamp: Some(1_099),
+                to_client: None,
+                ..deposit(2, 2, dec!(1.0))
+            })
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().reserved, fixed(5, 0));
+
+        engine.advance_time(1_100);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, fixed(50, 0));
+        assert_eq!(account.reserved, Money::ZERO);
+        let output = engine.output();
+        assert_eq!(
+            output.iter().find(|a| a.client == 1).unwrap().reserved,
+            Some(Money::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_rejected_dispute_counts_own_reserve() {
+        use crate::config::ReservePolicy;
+        use std::time::Duration;
+
+        let config = EngineConfig::default()
+            .with_reserve(ReservePolicy {
+                rate: dec!(0.5),
+                period: Duration::from_secs(100),
+            })
+            .with_dispute_shortfall(DisputeShortfallPolicy::RejectDispute);
+        let mut engine = Engine::with_config(config);
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.reserved),
+            (-fixed(5, 0), fixed(10, 0), fixed(5, 0))
+        );
+        engine.advance_time(100);
+        assert_eq!(engine.account(1).unwrap().available, Money::ZERO);
+
+        // Once released, the reserve is spendable and no longer covers the hold.
+        engine.process(resolve(1, 1)).unwrap();
+        engine.process(withdrawal(1, 2, dec!(1.0))).unwrap();
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::DisputeShortfall)
+        );
+    }
+
+    #[test]
+    fn test_process_reports_rejection_reasons() {
+        let mut engine = Engine::new();
+        assert_eq!(
+            engine.process(deposit(1, 1, dec!(10.0))),
+            Ok(ProcessOutcome::Deposited)
+        );
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(0))),
+            Err(ProcessError::InvalidAmount)
+        );
+        assert_eq!(
+            engine.process(withdrawal(1, 3, dec!(11.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.process(dispute(1, 9)),
+            Err(ProcessError::UnknownTransaction)
+        );
+        assert_eq!(
+            engine.process(dispute(2, 1)),
+            Err(ProcessError::ClientMismatch)
+        );
+        assert_eq!(
+            engine.process(resolve(1, 1)),
+            Err(ProcessError::NotDisputed)
+        );
+        assert_eq!(engine.process(dispute(1, 1)), Ok(ProcessOutcome::Disputed));
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::AlreadyDisputed)
+        );
+        assert_eq!(
+            engine.process(chargeback(1, 1)),
+            Ok(ProcessOutcome::ChargedBack)
+        );
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::AlreadyChargedBack)
+        );
+        assert_eq!(
+            engine.process(deposit(1, 4, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn test_saturation_emits_event() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+
+        let max = Decimal::from(i64::MAX / SCALE);
+        engine.process(deposit(1, 1, max)).unwrap();
+        engine.process(deposit(1, 2, max)).unwrap();
+        engine.process(deposit(1, 3, dec!(1.0))).unwrap();
+
+        assert_eq!(engine.saturation_count(), 2);
+        assert_eq!(engine.account(1).unwrap().available, Money::MAX);
+        assert_eq!(
+            events.lock().unwrap()[0],
+            EngineEvent::Saturated {
+                client: 1,
+                tx: 2,
+                attempted: Money::from_units(to_fixed(max))
+            }
+        );
+    }
+
+    #[test]
+    fn test_checked_arithmetic_rejects_overflow() {
+        let config = EngineConfig::default().with_arithmetic(ArithmeticMode::Checked);
+        let mut engine = Engine::with_config(config);
+        let max = Decimal::from(i64::MAX / SCALE);
+        engine.process(deposit(1, 1, max)).unwrap();
+        let before = *engine.account(1).unwrap();
+
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(1.0))),
+            Err(ProcessError::Overflow)
+        );
+        assert_eq!(
+            engine.process(dispute(1, 2)),
+            Err(ProcessError::UnknownTransaction)
+        );
+        assert_eq!(*engine.account(1).unwrap(), before);
+        assert_eq!(engine.saturation_count(), 0);
+
+        // Held plus available would overflow the total.
+        engine.process(withdrawal(1, 3, max)).unwrap();
+        engine.process(deposit(1, 4, max)).unwrap();
+        engine.process(dispute(1, 4)).unwrap();
+        assert_eq!(
+            engine.process(deposit(1, 5, max)),
+            Err(ProcessError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_freeze_locks_accounts_on_overflow() {
+        use std::sync::{Arc, Mutex};
+
+        let config = EngineConfig::default().with_arithmetic(ArithmeticMode::Freeze);
+        let mut engine = Engine::with_config(config);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+        let max = Decimal::from(i64::MAX / SCALE);
+        engine.process(deposit(1, 1, max)).unwrap();
+        engine.process(deposit(2, 2, dec!(5.0))).unwrap();
+
+        // The recipient would overflow, so both sides of the transfer are frozen.
+        assert_eq!(
+            engine.process(transfer(2, 3, 1, dec!(1.0))),
+            Err(ProcessError::Overflow)
+        );
+        assert_eq!(
+            (
+                engine.account(1).unwrap().available,
+                engine.account(1).unwrap().locked
+            ),
+            (Money::from_units(to_fixed(max)), true)
+        );
+        assert_eq!(
+            (
+                engine.account(2).unwrap().available,
+                engine.account(2).unwrap().locked
+            ),
+            (fixed(5, 0), true)
+        );
+        assert_eq!(
+            engine.process(withdrawal(2, 4, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                EngineEvent::AccountFrozen { client: 2, tx: 3 },
+                EngineEvent::AccountFrozen { client: 1, tx: 3 }
+            ]
+        );
+        assert_eq!(engine.stats().accounts_frozen, 2);
+    }
+
+    #[test]
+    fn test_balance_floor_and_ceiling() {
+        use crate::config::{BalanceLimits, ClientTiers};
+
+        let mut tiers = ClientTiers::default();
+        tiers.add_tier(
+            "capped",
+            BalanceLimits {
+                floor: None,
+                ceiling: Some(fixed(20, 0)),
+                overdraft: None,
+            },
+        );
+        tiers.assign(2, "capped");
+        let config = EngineConfig::default()
+            .with_balance_limits(BalanceLimits {
+                floor: Some(fixed(1, 0)),
+                ceiling: None,
+                overdraft: None,
+            })
+            .with_tiers(tiers);
+        let mut engine = Engine::with_config(config);
+
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 2, dec!(9.5))),
+            Err(ProcessError::BelowFloor)
+        );
+        assert_eq!(
+            engine.process(withdrawal(1, 3, dec!(9.0))),
+            Ok(ProcessOutcome::Withdrawn)
+        );
+
+        engine.process(deposit(2, 4, dec!(15.0))).unwrap();
+        assert_eq!(
+            engine.process(deposit(2, 5, dec!(6.0))),
+            Err(ProcessError::AboveCeiling)
+        );
+        // The tier has no floor, so client 2 can withdraw everything.
+        assert_eq!(
+            engine.process(withdrawal(2, 6, dec!(15.0))),
+            Ok(ProcessOutcome::Withdrawn)
+        );
+        assert_eq!(engine.account(2).unwrap().available, Money::ZERO);
+    }
+
+    #[test]
+    fn test_auto_unlock_after_last_chargeback() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let mut engine =
+            Engine::with_config(EngineConfig::default().with_auto_unlock(Duration::from_secs(100)));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+
+        for (time, id) in [(0, 1), (0, 2)] {
+            engine.process(at(time, deposit(1, id, dec!(5.0)))).unwrap();
+            engine.process(at(time, dispute(1, id))).unwrap();
+        }
+        engine.process(at(10, chargeback(1, 1))).unwrap();
+        engine.process(at(60, chargeback(1, 2))).unwrap();
+
+        // The first chargeback's cooldown has passed, but the second one restarted it.
+        engine.advance_time(110);
+        assert!(engine.account(1).unwrap().locked);
+        engine.advance_time(160);
+        assert!(!engine.account(1).unwrap().locked);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [EngineEvent::AccountUnlocked { client: 1 }]
+        );
+
+        engine.process(at(170, deposit(1, 3, dec!(1.0)))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, fixed(1, 0));
+    }
+
+    #[test]
+    fn test_dispute_expiry_resolves_or_escalates() {
+        use crate::config::DisputeExpiry;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        for action in [ExpiryAction::Resolve, ExpiryAction::Escalate] {
+            let expiry = DisputeExpiry {
+                after: Duration::from_secs(50),
+                action,
+            };
+            let mut engine =
+                Engine::with_config(EngineConfig::default().with_dispute_expiry(expiry));
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&events);
+            engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+
+            engine.process(at(0, deposit(1, 1, dec!(5.0)))).unwrap();
+            engine.process(at(0, deposit(1, 2, dec!(3.0)))).unwrap();
+            engine.process(at(10, dispute(1, 1))).unwrap();
+            // Resolved and re-disputed: only the second dispute's expiry counts.
+            engine.process(at(20, dispute(1, 2))).unwrap();
+            engine.process(at(30, resolve(1, 2))).unwrap();
+            engine.process(at(40, dispute(1, 2))).unwrap();
+
+            engine.advance_time(70);
+            let account = engine.account(1).unwrap();
+            match action {
+                ExpiryAction::Resolve => {
+                    assert_eq!(account.held, fixed(3, 0));
+                    assert_eq!(
+                        *events.lock().unwrap(),
+                        [EngineEvent::DisputeExpired { client: 1, tx: 1 }]
+                    );
+                }
+                ExpiryAction::Escalate => {
+                    assert_eq!(account.held, fixed(8, 0));
+                    assert_eq!(
+                        *events.lock().unwrap(),
+                        [EngineEvent::DisputeEscalated { client: 1, tx: 1 }]
+                    );
+                }
+            }
+            engine.advance_time(90);
+            assert_eq!(events.lock().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let mut engine = Engine::with_config(
+            EngineConfig::default().with_dispute_window(Duration::from_secs(100)),
+        );
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&records);
+        engine.on_audit(move |event| sink.lock().unwrap().push(event.clone()));
+        engine.process(at(1_000, deposit(1, 1, dec!(5.0)))).unwrap();
+        engine.process(at(1_000, deposit(1, 2, dec!(5.0)))).unwrap();
+        engine.process(deposit(1, 3, dec!(5.0))).unwrap();
+
+        engine.process(at(1_100, dispute(1, 1))).unwrap();
+        assert_eq!(
+            engine.process(at(1_101, dispute(1, 2))),
+            Err(ProcessError::DisputeWindowClosed)
+        );
+        // Without a timestamp the dispute happens at the engine's time, also past the window.
+        assert_eq!(
+            engine.process(dispute(1, 2)),
+            Err(ProcessError::DisputeWindowClosed)
+        );
+        // A deposit without a timestamp can always be disputed.
+        engine.process(at(9_000, dispute(1, 3))).unwrap();
+
+        assert_eq!(engine.account(1).unwrap().held, fixed(10, 0));
+        assert_eq!(
+            engine
+                .stats()
+                .rejected
+                .get(ProcessError::DisputeWindowClosed),
+            2
+        );
+        let rejected =
+            AuditAction::Rejected(TransactionType::Dispute, ProcessError::DisputeWindowClosed);
+        assert_eq!(
+            records
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|event| event.action == rejected)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_compaction() {
+        use crate::config::CompactionPolicy;
+        use std::time::Duration;
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let reversal = |client: ClientId, tx: TxId| Transaction {
+            tx_type: TransactionType::ChargebackReversal,
+            ..dispute(client, tx)
+        };
+        let config = EngineConfig::default().with_dispute_window(Duration::from_secs(100));
+        let mut engine = Engine::with_config(config.clone().with_compaction(CompactionPolicy {
+            every: 1_000,
+            charged_back: true,
+        }));
+        engine.process(at(1_000, deposit(1, 1, dec!(5.0)))).unwrap();
+        engine.process(at(1_000, deposit(2, 2, dec!(5.0)))).unwrap();
+        engine.process(at(1_000, dispute(2, 2))).unwrap();
+        engine.process(at(1_000, chargeback(2, 2))).unwrap();
+        engine.process(deposit(1, 3, dec!(5.0))).unwrap();
+        engine.process(at(1_000, deposit(1, 4, dec!(5.0)))).unwrap();
+        engine.process(at(1_050, dispute(1, 4))).unwrap();
+        engine.process(at(1_150, deposit(1, 5, dec!(5.0)))).unwrap();
+        engine.process(at(1_200, deposit(3, 6, dec!(5.0)))).unwrap();
+
+        // Expired, and charged back; the one without a timestamp, the open dispute and the
+        // recent deposits stay.
+        assert_eq!(engine.compact().unwrap(), 2);
+        let mut kept: Vec<TxId> = engine.transactions().map(|(tx, _)| tx).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, [3, 4, 5, 6]);
+        assert_eq!(engine.compact().unwrap(), 0);
+
+        // Compacted transactions are rejected for the reasons they would have been.
+        assert_eq!(
+            engine.process(deposit(3, 1, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::DisputeWindowClosed)
+        );
+        assert_eq!(
+            engine.process(resolve(1, 1)),
+            Err(ProcessError::NotDisputed)
+        );
+        assert_eq!(
+            engine.process(dispute(2, 2)),
+            Err(ProcessError::AlreadyChargedBack)
+        );
+        assert_eq!(
+            engine.process(reversal(2, 2)),
+            Err(ProcessError::TransactionCompacted)
+        );
+        assert_eq!(
+            engine.process(reversal(1, 1)),
+            Err(ProcessError::NotChargedBack)
+        );
+        engine.process(dispute(1, 5)).unwrap();
+
+        let mut snapshot = Vec::new();
+        engine.write_snapshot(0, &mut snapshot).unwrap();
+        let mut restored = Engine::with_config(config.clone());
+        restored.restore_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            restored.process(withdrawal(1, 2, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            restored.process(dispute(1, 1)),
+            Err(ProcessError::DisputeWindowClosed)
+        );
+
+        let mut other = Engine::with_config(config.clone());
+        other.process(deposit(4, 1, dec!(1.0))).unwrap();
+        assert_eq!(
+            restored.merge(other),
+            Err(MergeError::DuplicateTransaction(1))
+        );
+
+        // Automatically every 3 transactions, keeping charged-back ones by default.
+        let mut engine = Engine::with_config(config.with_compaction(CompactionPolicy {
+            every: 3,
+            charged_back: false,
+        }));
+        engine.process(at(0, deposit(1, 1, dec!(5.0)))).unwrap();
+        engine.process(at(0, deposit(1, 2, dec!(5.0)))).unwrap();
+        assert_eq!(engine.transactions().count(), 2);
+        engine.process(at(500, deposit(1, 3, dec!(5.0)))).unwrap();
+        assert_eq!(
+            engine.transactions().map(|(tx, _)| tx).collect::<Vec<_>>(),
+            [3]
+        );
+    }
+
+    #[test]
+    fn test_dispute_shortfall_policies() {
+        use crate::config::DisputeShortfallPolicy;
+
+        let run = |policy: DisputeShortfallPolicy| {
+            let mut engine =
+                Engine::with_config(EngineConfig::default().with_dispute_shortfall(policy));
+            engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+            engine.process(withdrawal(1, 2, dec!(7.0))).unwrap();
+            let disputed = engine
+                .process(dispute(1, 1))
+                .map(|_| *engine.account(1).unwrap());
+            (engine, disputed)
+        };
+
+        let (_, disputed) = run(DisputeShortfallPolicy::AllowNegative);
+        let account = disputed.unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (-fixed(7, 0), fixed(10, 0))
+        );
+
+        let (engine, disputed) = run(DisputeShortfallPolicy::RejectDispute);
+        assert_eq!(disputed, Err(ProcessError::DisputeShortfall));
+        assert_eq!(engine.account(1).unwrap().available, fixed(3, 0));
+        assert_eq!(
+            engine.transaction(1).unwrap().dispute_state,
+            DisputeState::None
+        );
+
+        let (mut engine, disputed) = run(DisputeShortfallPolicy::HoldPartial);
+        let account = disputed.unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Money::ZERO, fixed(3, 0))
+        );
+        engine.process(resolve(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (fixed(3, 0), Money::ZERO)
+        );
+        // A dispute with nothing left to hold still opens, and its chargeback removes nothing.
+        engine.process(withdrawal(1, 3, dec!(3.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.locked),
+            (Money::ZERO, Money::ZERO, true)
+        );
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_scale_and_rounding() {
+        use crate::types::Rounding;
+
+        let run = |rounding: Rounding| {
+            let mut engine = Engine::with_config(
+                EngineConfig::default()
+                    .with_scale(2)
+                    .with_rounding(rounding),
+            );
+            let results = [(1, dec!(1.005)), (2, dec!(1.015)), (3, dec!(2.5))]
+                .map(|(tx, amount)| engine.process(deposit(1, tx, amount)).err());
+            (engine.account(1).unwrap().available.units(), results)
+        };
+        assert_eq!(run(Rounding::Truncate), (451, [None; 3]));
+        // 1.005 rounds down to 1.00 and 1.015 up to 1.02.
+        assert_eq!(run(Rounding::HalfEven), (452, [None; 3]));
+        assert_eq!(
+            run(Rounding::Reject),
+            (
+                250,
+                [
+                    Some(ProcessError::Amount(AmountError::ExcessPrecision)),
+                    Some(ProcessError::Amount(AmountError::ExcessPrecision)),
+                    None
+                ]
+            )
+        );
+
+        let mut engine = Engine::with_config(EngineConfig::default().with_scale(2));
+        engine.process(deposit(1, 1, dec!(12.3456))).unwrap();
+        let mut out = Vec::new();
+        engine.write_accounts_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,12.34,0.00,12.34,false\n"
+        );
+    }
+
+    #[test]
+    fn test_amount_validation() {
+        let mut engine = Engine::with_config(EngineConfig::default().with_max_amount(dec!(1000)));
+        let rejected = |error| Err(ProcessError::Amount(error));
+        assert_eq!(
+            engine.process(deposit(1, 1, dec!(1e27))),
+            rejected(AmountError::TooLarge)
+        );
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(0.00001))),
+            rejected(AmountError::BelowMinorUnit)
+        );
+        assert_eq!(
+            engine.process(deposit(1, 3, dec!(1000.0001))),
+            rejected(AmountError::TooLarge)
+        );
+        engine.process(deposit(1, 4, dec!(1000))).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 5, dec!(0.00009))),
+            rejected(AmountError::BelowMinorUnit)
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(1000, 0));
+        assert_eq!(
+            ProcessError::Amount(AmountError::BelowMinorUnit).code(),
+            "amount_below_minor_unit"
+        );
+
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(1.0))).unwrap();
+        // Fits a Decimal but not i64 minor units; this used to deposit nothing.
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(1e27))),
+            rejected(AmountError::Overflow)
+        );
+        assert_eq!(
+            engine.process(transfer(1, 3, 2, dec!(1e27))),
+            rejected(AmountError::Overflow)
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(1, 0));
+        assert!(engine.account(2).is_none());
+    }
+
+    #[test]
+    fn test_money() {
+        use crate::types::Precision;
+
+        let amount = Money::from_decimal(dec!(12.345), Precision::new(2)).unwrap();
+        assert_eq!(amount, Money::from_units(1234));
+        assert_eq!(
+            (amount.format(2), amount.to_decimal(2)),
+            ("12.34".to_string(), dec!(12.34))
+        );
+        assert_eq!(Money::from_units(15_000).to_string(), "1.5000");
+        assert_eq!(Money::MAX.checked_add(Money::from_units(1)), None);
+        assert_eq!(Money::MAX.saturating_add(Money::from_units(1)), Money::MAX);
+        assert_eq!(Money::from_decimal_lossy(dec!(-1e27), 4), Money::MIN);
+        assert_eq!(
+            [fixed(1, 0), fixed(2, 5000)].into_iter().sum::<Money>(),
+            fixed(3, 5000)
+        );
+        assert_eq!(mem::size_of::<Money>(), mem::size_of::<i64>());
+    }
+
+    #[test]
+    fn test_id_collisions_reported_across_types_and_clients() {
+        use std::sync::{Arc, Mutex};
+
+        let mut engine = Engine::with_config(EngineConfig::default().with_id_collision_detection());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(1.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.process(withdrawal(2, 1, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                EngineEvent::IdCollision {
+                    tx: 2,
+                    client: 1,
+                    tx_type: TransactionType::Deposit,
+                    first_client: 1,
+                    first_type: TransactionType::Withdrawal,
+                },
+                EngineEvent::IdCollision {
+                    tx: 1,
+                    client: 2,
+                    tx_type: TransactionType::Withdrawal,
+                    first_client: 1,
+                    first_type: TransactionType::Deposit,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_tracking_and_limit() {
+        let mut engine = Engine::new();
+        assert_eq!(engine.memory_usage(), 0);
+        for id in 1..=100 {
+            engine.process(deposit(1, id, dec!(1.0))).unwrap();
+        }
+        let peak = engine.peak_memory();
+        assert!(peak >= 100 * mem::size_of::<(TxId, StoredTransaction)>());
+        assert_eq!(peak, engine.memory_usage());
+
+        let limit = EngineConfig::default().with_memory_limit(1024);
+        let mut engine = Engine::with_config(limit);
+        let results: Vec<_> = (1..=1000)
+            .map(|id| engine.process(deposit(1, id, dec!(1.0))))
+            .collect();
+        let first_rejected = results.iter().position(|r| r.is_err()).unwrap();
+        assert!(first_rejected > 0);
+        assert!(
+            results[first_rejected..]
+                .iter()
+                .all(|r| *r == Err(ProcessError::MemoryLimit))
+        );
+        assert_eq!(
+            engine.account(1).unwrap().available,
+            Money::from_units(first_rejected as i64 * SCALE)
+        );
+    }
+
+    #[test]
+    fn test_file_store_keeps_transactions_out_of_memory() {
+        use crate::store::FileStore;
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("tx-engine-engine-store-{}", process::id()));
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.set_store(FileStore::create(&path).unwrap()).unwrap();
+        assert_eq!(engine.memory_usage(), engine.accounts.memory_usage());
+        for id in 2..=1000 {
+            engine.process(deposit(1, id, dec!(1.0))).unwrap();
+        }
+        assert_eq!(engine.memory_usage(), engine.accounts.memory_usage());
+
+        // Transactions stored before and after the switch can both be disputed.
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(dispute(1, 1000)).unwrap();
+        assert_eq!(
+            engine.process(deposit(1, 500, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (fixed(998, 0), fixed(11, 0))
+        );
+        assert_eq!(engine.transactions().count(), 1000);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_manual_clock_drives_policies_without_timestamps() {
+        use crate::clock::ManualClock;
+        use std::time::Duration;
+
+        let config = EngineConfig::default().with_auto_unlock(Duration::from_secs(100));
+        let mut engine = Engine::with_config(config);
+        let clock = ManualClock::new(1_000);
+        engine.set_clock(clock.clone());
+
+        engine.process(deposit(1, 1, dec!(5.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        assert!(engine.account(1).unwrap().locked);
+
+        clock.advance(Duration::from_secs(99));
+        engine.tick();
+        assert!(engine.account(1).unwrap().locked);
+        clock.advance(Duration::from_secs(1));
+        engine.tick();
+        assert!(!engine.account(1).unwrap().locked);
+
+        // A row's own timestamp still wins over an earlier clock.
+        engine
+            .process(Transaction {
+                timestamp: Some(5_000),
+                ..deposit(1, 2, dec!(1.0))
+            })
+            .unwrap();
+        clock.set(2_000);
+        engine.tick();
+        assert_eq!(engine.now, 5_000);
+    }
+
+    #[test]
+    fn test_batch_commits_or_rolls_back_as_a_whole() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+
+        let rejected = engine.process_batch([
+            withdrawal(1, 2, dec!(4.0)),
+            deposit(2, 3, dec!(4.0)),
+            withdrawal(1, 4, dec!(100.0)),
+        ]);
+        assert_eq!(
+            rejected,
+            Err(BatchError::Rejected(BatchRejected {
+                index: 2,
+                error: ProcessError::InsufficientFunds
+            }))
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(10, 0));
+        assert_eq!(engine.account(2), None);
+        assert_eq!(engine.transaction(3).map(|stored| stored.amount), None);
+
+        let legs = [withdrawal(1, 5, dec!(4.0)), deposit(2, 6, dec!(4.0))];
+        let applied = engine.process_batch(&legs[..]);
+        assert_eq!(
+            applied,
+            Ok(vec![ProcessOutcome::Withdrawn, ProcessOutcome::Deposited])
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(6, 0));
+        assert_eq!(engine.account(2).unwrap().available, fixed(4, 0));
+    }
+
+    #[test]
+    fn test_batch_inside_an_open_batch() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.begin_batch();
+        engine.process(withdrawal(1, 2, dec!(4.0))).unwrap();
+
+        // Neither a rejected nor an accepted batch touches the caller's open one.
+        let rejected = engine.process_batch([withdrawal(1, 3, dec!(100.0))]);
+        assert_eq!(rejected, Err(BatchError::BatchOpen));
+        let refused = engine.process_batch([deposit(2, 4, dec!(1.0))]);
+        assert_eq!(refused, Err(BatchError::BatchOpen));
+        assert_eq!(engine.account(2), None);
+        assert_eq!(engine.account(1).unwrap().available, fixed(6, 0));
+
+        engine.rollback_batch();
+        assert_eq!(engine.account(1).unwrap().available, fixed(10, 0));
+        assert_eq!(engine.transaction(2).map(|stored| stored.amount), None);
+    }
+
+    #[test]
+    fn test_undo_last() {
+        use crate::event_log::AccountProjection;
+
+        let mut engine =
+            Engine::with_config(EngineConfig::default().with_undo_depth(3).with_event_log());
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(5.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 3, dec!(50.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        engine.process(chargeback(1, 1)).unwrap();
+        assert!(engine.account(1).unwrap().locked);
+
+        // Rejected transactions aren't undone; the chargeback and dispute are.
+        assert_eq!(engine.undo_last(2), 2);
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.locked),
+            (fixed(15, 0), Money::ZERO, false)
+        );
+        assert_eq!(
+            engine.transaction(1).map(|stored| stored.dispute_state),
+            Some(DisputeState::None)
+        );
+        assert_eq!(engine.stats().chargebacks.accepted, 0);
+
+        // Only the last three were kept, and two of them are gone.
+        engine.process(deposit(1, 4, dec!(1.0))).unwrap();
+        assert_eq!(engine.undo_last(5), 2);
+        assert_eq!(engine.account(1).unwrap().available, fixed(10, 0));
+        assert_eq!(engine.transaction(2), None);
+        assert_eq!(engine.undo_last(1), 0);
+
+        let mut projection = AccountProjection::default();
+        engine.replay(0, &mut projection);
+        assert_eq!(projection.account(1), engine.account(1));
+
+        // A change outside a transaction forgets the ones before it.
+        engine.process(deposit(1, 5, dec!(1.0))).unwrap();
+        engine
+            .apply_admin(AdminOp::Lock {
+                client: 1,
+                reason: String::new(),
+            })
+            .unwrap();
+        assert_eq!(engine.undo_last(1), 0);
+    }
+
+    #[test]
+    fn test_batch_rollback_restores_timers() {
+        use crate::config::ReservePolicy;
+        use std::time::Duration;
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let config = EngineConfig::default().with_reserve(ReservePolicy {
+            rate: dec!(0.5),
+            period: Duration::from_secs(10),
+        });
+        let mut engine = Engine::with_config(config);
+        engine.process(at(0, deposit(1, 1, dec!(10.0)))).unwrap();
+
+        // The batch fires the first deposit's release and schedules its own, then fails.
+        engine.begin_batch();
+        assert!(engine.process(at(20, deposit(1, 2, dec!(10.0)))).is_ok());
+        assert_eq!(engine.account(1).unwrap().reserved, fixed(5, 0));
+        engine.rollback_batch();
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.reserved),
+            (fixed(5, 0), fixed(5, 0))
+        );
+        // The release consumed by the batch is due again; the batch's own never fires.
+        engine.advance_time(100);
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.reserved),
+            (fixed(10, 0), Money::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_scheduled_transactions() {
+        use std::sync::{Arc, Mutex};
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+
+        engine.schedule(deposit(1, 1, dec!(10.0)), 100).unwrap();
+        engine.schedule(withdrawal(1, 2, dec!(20.0)), 150).unwrap();
+        assert_eq!(
+            engine.schedule(dispute(1, 1), 100),
+            Err(ProcessError::NotSchedulable)
+        );
+        engine.process(at(50, deposit(2, 3, dec!(1.0)))).unwrap();
+        assert!(engine.account(1).is_none());
+
+        // A later timestamp settles the deposit first, then the transaction itself.
+        engine
+            .process(at(120, withdrawal(1, 4, dec!(1.0))))
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available, fixed(9, 0));
+        assert_eq!(engine.transaction(1).unwrap().timestamp, Some(100));
+
+        engine.advance_time(200);
+        assert_eq!(engine.account(1).unwrap().available, fixed(9, 0));
+        assert_eq!(
+            events.lock().unwrap().as_slice(),
+            [EngineEvent::ScheduledRejected {
+                client: 1,
+                tx: 2,
+                error: ProcessError::InsufficientFunds
+            }]
+        );
+
+        // Rolled back with its batch; a value date already passed applies at once.
+        engine.begin_batch();
+        engine.schedule(deposit(1, 5, dec!(5.0)), 300).unwrap();
+        engine.rollback_batch();
+        engine.schedule(deposit(1, 6, dec!(1.0)), 150).unwrap();
+        engine.advance_time(400);
+        assert_eq!(engine.account(1).unwrap().available, fixed(10, 0));
+        assert_eq!(engine.stats().scheduled, 3);
+    }
+
+    #[test]
+    fn test_dormant_accounts_archived_and_reactivated() {
+        use crate::archive::FileArchive;
+        use crate::config::DormancyPolicy;
+        use std::io::Cursor;
+
+        let policy = DormancyPolicy {
+            after_time: None,
+            after_transactions: Some(3),
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().with_dormancy(policy));
+        engine.set_archive(FileArchive::new(Cursor::new(Vec::new())));
+
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(2, 2, dec!(1.0))).unwrap();
+        engine.process(deposit(2, 3, dec!(1.0))).unwrap();
+        assert!(!engine.is_dormant(1));
+        engine.process(deposit(2, 4, dec!(1.0))).unwrap();
+        assert!(engine.is_dormant(1));
+        assert!(!engine.is_dormant(2));
+
+        assert_eq!(engine.archive_dormant().unwrap(), 1);
+        assert_eq!(engine.archived_count(), 1);
+        assert_eq!(engine.account(1), None);
+        assert!(engine.transaction(1).is_none());
+        assert_eq!(engine.output().len(), 1);
+
+        // A dispute on the archived deposit brings the account and the deposit back.
+        assert_eq!(engine.process(dispute(1, 1)), Ok(ProcessOutcome::Disputed));
+        assert_eq!(engine.archived_count(), 0);
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Money::ZERO, fixed(10, 0))
+        );
+        assert!(!engine.is_dormant(1));
+    }
+
+    #[test]
+    fn test_merge_clients() {
+        use std::sync::{Arc, Mutex};
+
+        let mut engine = Engine::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(5.0))).unwrap();
+        engine.process(deposit(2, 3, dec!(1.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+
+        assert_eq!(engine.merge_clients(1, 1), Err(MergeError::SameClient));
+        assert_eq!(
+            engine.merge_clients(3, 2),
+            Err(MergeError::UnknownClient(3))
+        );
+        assert_eq!(engine.merge_clients(1, 2), Ok(2));
+        assert_eq!(engine.account(1), None);
+        let account = engine.account(2).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (fixed(6, 0), fixed(10, 0))
+        );
+        assert_eq!(engine.merged_into(1), Some(2));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![EngineEvent::ClientsMerged {
+                from: 1,
+                into: 2,
+                deposits: 2
+            }]
+        );
+
+        // The moved dispute resolves under the new id, and the old id is redirected.
+        assert_eq!(engine.process(resolve(2, 1)), Ok(ProcessOutcome::Resolved));
+        assert_eq!(
+            engine.process(chargeback(1, 2)),
+            Err(ProcessError::NotDisputed)
+        );
+        engine.process(withdrawal(1, 4, dec!(16.0))).unwrap();
+        assert_eq!(engine.account(2).unwrap().available, Money::ZERO);
+        assert_eq!(engine.account(1), None);
+        assert_eq!(engine.merge_clients(2, 1), Err(MergeError::SameClient));
+    }
+
+    #[test]
+    fn test_merge_clients_is_all_or_nothing() {
+        use std::sync::{Arc, Mutex};
+
+        /// A memory store that fails the insert after the next `fail_in` ones, once.
+        struct FlakyStore {
+            inner: MemoryStore,
+            fail_in: Arc<Mutex<Option<usize>>>,
+        }
+
+        impl TxStore for FlakyStore {
+            fn get(&self, tx: TxId) -> io::Result<Option<StoredTransaction>> {
+                self.inner.get(tx)
+            }
+
+            fn insert(&mut self, tx: TxId, stored: StoredTransaction) -> io::Result<()> {
+                let mut fail_in = self.fail_in.lock().unwrap();
+                match *fail_in {
+                    Some(0) => {
+                        *fail_in = None;
+                        Err(io::Error::other("disk full"))
+                    }
+                    Some(n) => {
+                        *fail_in = Some(n - 1);
+                        self.inner.insert(tx, stored)
+                    }
+                    None => self.inner.insert(tx, stored),
+                }
+            }
+
+            fn remove(&mut self, tx: TxId) -> io::Result<Option<StoredTransaction>> {
+                self.inner.remove(tx)
+            }
+
+            fn iter(&self) -> Box<dyn Iterator<Item = io::Result<(TxId, StoredTransaction)>> + '_> {
+                self.inner.iter()
+            }
+
+            fn memory_usage(&self) -> usize {
+                self.inner.memory_usage()
+            }
+        }
+
+        let config = EngineConfig::default().with_id_collision_detection();
+        let mut engine = Engine::with_config(config);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(5.0))).unwrap();
+        engine.process(deposit(2, 3, dec!(1.0))).unwrap();
+        let fail_in = Arc::new(Mutex::new(None));
+        engine
+            .set_store(FlakyStore {
+                inner: MemoryStore::default(),
+                fail_in: Arc::clone(&fail_in),
+            })
+            .unwrap();
+
+        // The second moved deposit fails to store, so the first is put back.
+        *fail_in.lock().unwrap() = Some(1);
+        assert_eq!(
+            engine.merge_clients(1, 2),
+            Err(MergeError::StoreUnavailable)
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(15, 0));
+        assert_eq!(engine.account(2).unwrap().available, fixed(1, 0));
+        assert_eq!(engine.transaction(1).unwrap().client, 1);
+        assert_eq!(engine.transaction(2).unwrap().client, 1);
+        assert_eq!(engine.merged_into(1), None);
+
+        assert_eq!(engine.merge_clients(1, 2), Ok(2));
+        // A reused id is reported against the client it now belongs to.
+        events.lock().unwrap().clear();
+        assert!(engine.process(deposit(3, 1, dec!(1.0))).is_err());
+        assert!(events.lock().unwrap().contains(&EngineEvent::IdCollision {
+            tx: 1,
+            client: 3,
+            tx_type: TransactionType::Deposit,
+            first_client: 2,
+            first_type: TransactionType::Deposit,
+        }));
+    }
+
+    #[test]
+    fn test_process_until() {
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let mut txs = vec![
+            at(10, deposit(1, 1, dec!(5.0))),
+            at(20, withdrawal(1, 2, dec!(9.0))),
+            at(30, deposit(1, 3, dec!(1.0))),
+        ]
+        .into_iter()
+        .peekable();
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(resolve(1, 1));
+        assert_eq!(
+            engine.process_until(&mut txs, |tx| tx.timestamp > Some(20)),
+            2
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(5, 0));
+        assert_eq!(txs.peek().map(|tx| tx.tx), Some(3));
+        assert_eq!(engine.process_until(&mut txs, |_| false), 1);
+        assert!(txs.next().is_none());
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
+    #[test]
+    fn test_merge_engines() {
+        let mut left = Engine::with_config(EngineConfig::default().with_history());
+        left.process(deposit(1, 1, dec!(10.0))).unwrap();
+        left.process(deposit(2, 2, dec!(3.0))).unwrap();
+        let mut right = Engine::with_config(EngineConfig::default().with_history());
+        right.process(deposit(1, 3, dec!(5.0))).unwrap();
+        right.process(dispute(1, 3)).unwrap();
+        right.process(deposit(3, 4, dec!(1.0))).unwrap();
+        right.process(dispute(3, 4)).unwrap();
+        right.process(chargeback(3, 4)).unwrap();
+
+        let mut clash = Engine::new();
+        clash.process(deposit(4, 2, dec!(1.0))).unwrap();
+        assert_eq!(left.merge(clash), Err(MergeError::DuplicateTransaction(2)));
+        assert_eq!(left.account(4), None);
+
+        left.merge(right).unwrap();
+        let account = left.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (fixed(10, 0), fixed(5, 0))
+        );
+        assert!(left.account(3).unwrap().locked);
+        assert_eq!(left.stats().processed, 7);
+        let seqs: Vec<_> = left.history(1).iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [1, 3, 4]);
+
+        // Transactions of either side can be referred to afterwards.
+        left.process(resolve(1, 3)).unwrap();
+        left.process(dispute(1, 1)).unwrap();
+        assert_eq!(left.account(1).unwrap().available, fixed(5, 0));
+    }
+
+    fn transfer(client: ClientId, tx: TxId, to_client: ClientId, amount: Decimal) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Transfer,
+            client,
+            tx,
+            amount: Some(amount),
+            timestamp: None,
+            to_client: Some(to_client),
+            metadata: Default::default(),
+        }
     }
 
     #[test]
-    fn test_chargeback() {
+    fn test_transfer_moves_funds_or_nothing() {
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(chargeback(1, 1));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(transfer(1, 2, 2, dec!(4.0))),
+            Ok(ProcessOutcome::Transferred)
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(6, 0));
+        assert_eq!(engine.account(2).unwrap().available, fixed(4, 0));
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.total, 0);
-        assert!(account.locked);
+        assert_eq!(
+            engine.process(transfer(1, 3, 2, dec!(7.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.process(transfer(1, 4, 1, dec!(1.0))),
+            Err(ProcessError::InvalidRecipient)
+        );
+        let no_recipient = Transaction {
+            to_client: None,
+            ..transfer(1, 5, 2, dec!(1.0))
+        };
+        assert_eq!(
+            engine.process(no_recipient),
+            Err(ProcessError::InvalidRecipient)
+        );
+        assert_eq!(
+            engine.process(transfer(1, 6, 2, dec!(0.0))),
+            Err(ProcessError::InvalidAmount)
+        );
+
+        // Either side being locked blocks it.
+        engine.process(deposit(3, 7, dec!(1.0))).unwrap();
+        engine.process(dispute(3, 7)).unwrap();
+        engine.process(chargeback(3, 7)).unwrap();
+        assert_eq!(
+            engine.process(transfer(1, 8, 3, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(
+            engine.process(transfer(3, 9, 1, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(engine.account(1).unwrap().available, fixed(6, 0));
+
+        // A rolled-back batch undoes both sides.
+        let rejected = engine.process_batch([
+            transfer(1, 10, 2, dec!(1.0)),
+            withdrawal(2, 11, dec!(100.0)),
+        ]);
+        assert!(rejected.is_err());
+        assert_eq!(engine.account(1).unwrap().available, fixed(6, 0));
+        assert_eq!(engine.account(2).unwrap().available, fixed(4, 0));
     }
 
     #[test]
-    fn test_chargeback_not_disputed() {
+    fn test_transfer_disputed_by_sender() {
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(chargeback(1, 1));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(transfer(1, 2, 2, dec!(4.0))).unwrap();
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
-        assert!(!account.locked);
+        // Only the sender can dispute; the funds are held on the recipient.
+        assert_eq!(
+            engine.process(dispute(2, 2)),
+            Err(ProcessError::ClientMismatch)
+        );
+        engine.process(dispute(1, 2)).unwrap();
+        let recipient = engine.account(2).unwrap();
+        assert_eq!(
+            (recipient.available, recipient.held),
+            (Money::ZERO, fixed(4, 0))
+        );
+        engine.process(resolve(1, 2)).unwrap();
+        assert_eq!(engine.account(2).unwrap().available, fixed(4, 0));
+
+        // A chargeback returns the funds to the sender and locks the recipient.
+        engine.process(dispute(1, 2)).unwrap();
+        engine.process(chargeback(1, 2)).unwrap();
+        let recipient = engine.account(2).unwrap();
+        assert_eq!(
+            (recipient.available, recipient.held, recipient.locked),
+            (Money::ZERO, Money::ZERO, true)
+        );
+        let sender = engine.account(1).unwrap();
+        assert_eq!((sender.available, sender.locked), (fixed(10, 0), false));
     }
 
     #[test]
-    fn test_locked_account_rejects_deposit() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(chargeback(1, 1));
-        engine.process(deposit(1, 2, dec!(50.0)));
+    fn test_chargeback_reversal() {
+        let reversal = |client: ClientId, tx: TxId| Transaction {
+            tx_type: TransactionType::ChargebackReversal,
+            ..dispute(client, tx)
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().with_unlock_on_reversal());
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(1, 2, dec!(5.0))).unwrap();
+        assert_eq!(
+            engine.process(reversal(1, 1)),
+            Err(ProcessError::NotChargedBack)
+        );
+        for tx in [1, 2] {
+            engine.process(dispute(1, tx)).unwrap();
+            engine.process(chargeback(1, tx)).unwrap();
+        }
+        assert_eq!(engine.account(1).unwrap().total(), Money::ZERO);
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert!(account.locked);
+        // The other chargeback keeps the account locked.
+        assert_eq!(
+            engine.process(reversal(1, 1)),
+            Ok(ProcessOutcome::ChargebackReversed)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available, account.locked), (fixed(10, 0), true));
+        assert_eq!(
+            engine.transaction(1).unwrap().dispute_state,
+            DisputeState::None
+        );
+        assert_eq!(
+            engine.process(reversal(1, 1)),
+            Err(ProcessError::NotChargedBack)
+        );
+        engine.process(reversal(1, 2)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available, account.locked), (fixed(15, 0), false));
+        assert_eq!(engine.stats().accounts_unlocked, 1);
+        // The reversed transaction can be disputed again.
+        engine.process(dispute(1, 1)).unwrap();
+
+        // Reversing a transfer's chargeback takes the refund back from the sender.
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(transfer(1, 2, 2, dec!(4.0))).unwrap();
+        engine.process(dispute(1, 2)).unwrap();
+        engine.process(chargeback(1, 2)).unwrap();
+        engine.process(withdrawal(1, 3, dec!(8.0))).unwrap();
+        engine.process(reversal(1, 2)).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, -fixed(2, 0));
+        let recipient = engine.account(2).unwrap();
+        assert_eq!((recipient.available, recipient.locked), (fixed(4, 0), true));
     }
 
     #[test]
-    fn test_locked_account_rejects_withdrawal() {
+    fn test_hold_and_release() {
+        let hold = |client: ClientId, tx: TxId, amount: Decimal| Transaction {
+            tx_type: TransactionType::Hold,
+            ..deposit(client, tx, amount)
+        };
+        let release = |client: ClientId, tx: TxId, amount: Option<Decimal>| Transaction {
+            tx_type: TransactionType::Release,
+            amount,
+            ..dispute(client, tx)
+        };
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(deposit(1, 2, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(chargeback(1, 1));
-        engine.process(withdrawal(1, 3, dec!(5.0)));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(hold(1, 2, dec!(11.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.process(hold(1, 1, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.process(hold(1, 2, dec!(4.0))),
+            Ok(ProcessOutcome::Held)
+        );
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        let account = *engine.account(1).unwrap();
+        assert_eq!(
+            (
+                account.available,
+                account.held,
+                account.on_hold,
+                account.total()
+            ),
+            (fixed(6, 0), Money::ZERO, fixed(4, 0), fixed(10, 0))
+        );
+        let report = engine.output_sorted();
+        assert_eq!(
+            (report[0].held, report[0].total),
+            (fixed(4, 0), fixed(10, 0))
+        );
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(10, 0));
+        // A dispute holds its funds separately, and can't refer to a hold.
+        engine.process(dispute(1, 1)).unwrap();
+        assert_eq!(
+            engine.process(dispute(1, 2)),
+            Err(ProcessError::UnknownTransaction)
+        );
+        let account = *engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.on_hold),
+            (-fixed(4, 0), fixed(10, 0), fixed(4, 0))
+        );
+        engine.process(resolve(1, 1)).unwrap();
+
+        assert_eq!(
+            engine.process(release(2, 2, None)),
+            Err(ProcessError::ClientMismatch)
+        );
+        assert_eq!(
+            engine.process(release(1, 1, None)),
+            Err(ProcessError::UnknownTransaction)
+        );
+        assert_eq!(
+            engine.process(release(1, 2, Some(dec!(5.0)))),
+            Err(ProcessError::ReleaseExceedsHold)
+        );
+        assert_eq!(
+            engine.process(release(1, 2, Some(dec!(1.5)))),
+            Ok(ProcessOutcome::Released)
+        );
+        assert_eq!(engine.hold(2).unwrap().remaining, fixed(2, 5000));
+        engine.process(release(1, 2, None)).unwrap();
+        let account = *engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.on_hold),
+            (fixed(10, 0), Money::ZERO)
+        );
+        assert_eq!(
+            engine.hold(2),
+            Some(Hold {
+                client: 1,
+                amount: fixed(4, 0),
+                remaining: Money::ZERO
+            })
+        );
+        assert_eq!(
+            engine.process(release(1, 2, None)),
+            Err(ProcessError::HoldReleased)
+        );
+        assert_eq!(
+            engine.process(hold(1, 2, dec!(1.0))),
+            Err(ProcessError::DuplicateTransaction)
+        );
+        assert_eq!(
+            (
+                engine.stats().holds.accepted,
+                engine.stats().releases.accepted
+            ),
+            (1, 2)
+        );
+
+        // Holds work on a locked account, and keep it from being closed.
+        engine.process(hold(1, 3, dec!(3.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        let account = *engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.on_hold, account.locked),
+            (-fixed(3, 0), fixed(3, 0), true)
+        );
+        engine.process(release(1, 3, None)).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, Money::ZERO);
+        assert_eq!(engine.check_invariants(), Ok(()));
     }
 
     #[test]
-    fn test_locked_account_allows_dispute() {
+    fn test_holds_survive_rollbacks_merges_and_snapshots() {
+        let hold = |client: ClientId, tx: TxId, amount: Decimal| Transaction {
+            tx_type: TransactionType::Hold,
+            ..deposit(client, tx, amount)
+        };
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(deposit(1, 2, dec!(20.0)));
-        engine.process(dispute(1, 1));
-        engine.process(chargeback(1, 1));
-        // Account is now locked with 20 available
-        engine.process(dispute(1, 2)); // Should still work
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(deposit(2, 2, dec!(10.0))).unwrap();
+        engine.begin_batch();
+        engine.process(hold(1, 3, dec!(2.0))).unwrap();
+        engine.rollback_batch();
+        assert!(engine.hold(3).is_none());
+        assert_eq!(engine.account(1).unwrap().on_hold, Money::ZERO);
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, fixed(20, 0));
-        assert!(account.locked);
+        engine.process(hold(1, 3, dec!(2.0))).unwrap();
+        engine.process(hold(2, 4, dec!(3.0))).unwrap();
+        engine.merge_clients(2, 1).unwrap();
+        assert_eq!(engine.account(1).unwrap().on_hold, fixed(5, 0));
+        assert_eq!(engine.hold(4).unwrap().client, 1);
+
+        let mut snapshot = Vec::new();
+        engine.write_snapshot(0, &mut snapshot).unwrap();
+        let mut restored = Engine::new();
+        restored.restore_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.account(1), engine.account(1));
+        assert_eq!(restored.hold(4), engine.hold(4));
+        let release = Transaction {
+            tx_type: TransactionType::Release,
+            ..dispute(2, 4)
+        };
+        assert_eq!(restored.process(release), Ok(ProcessOutcome::Released));
+        assert_eq!(restored.account(1).unwrap().on_hold, fixed(2, 0));
+        assert_eq!(restored.check_invariants(), Ok(()));
     }
 
     #[test]
-    fn test_locked_account_allows_resolve() {
+    fn test_audit_records_every_change() {
+        use std::sync::{Arc, Mutex};
+
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(deposit(1, 2, dec!(20.0)));
-        engine.process(dispute(1, 2)); // Dispute tx 2 first
-        engine.process(dispute(1, 1));
-        engine.process(chargeback(1, 1)); // Lock via tx 1
-        // Account is now locked with 0 available, 20 held
-        engine.process(resolve(1, 2)); // Should still work
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&records);
+        engine.on_audit(move |event| sink.lock().unwrap().push(event.clone()));
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(20, 0));
-        assert_eq!(account.held, 0);
-        assert!(account.locked);
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(50.0))).unwrap_err();
+        engine.process(transfer(1, 3, 2, dec!(4.0))).unwrap();
+        engine.process(dispute(1, 3)).unwrap();
+        engine.process(chargeback(1, 3)).unwrap();
+        engine
+            .process_batch([deposit(2, 4, dec!(1.0)), withdrawal(2, 5, dec!(9.0))])
+            .unwrap_err();
+
+        let account = |available, held, locked| Account {
+            available: fixed(available, 0),
+            held: fixed(held, 0),
+            reserved: Money::ZERO,
+            on_hold: Money::ZERO,
+            locked,
+            closed: false,
+        };
+        let record = |tx, client, action, before, after| AuditEvent {
+            tx: Some(tx),
+            client,
+            action,
+            before,
+            after,
+            metadata: Metadata::new(),
+        };
+        let records = records.lock().unwrap();
+        assert_eq!(
+            *records,
+            [
+                record(
+                    1,
+                    1,
+                    AuditAction::Applied(TransactionType::Deposit),
+                    account(0, 0, false),
+                    account(10, 0, false)
+                ),
+                record(
+                    2,
+                    1,
+                    AuditAction::Rejected(
+                        TransactionType::Withdrawal,
+                        ProcessError::InsufficientFunds
+                    ),
+                    account(10, 0, false),
+                    account(10, 0, false)
+                ),
+                record(
+                    3,
+                    1,
+                    AuditAction::Applied(TransactionType::Transfer),
+                    account(10, 0, false),
+                    account(6, 0, false)
+                ),
+                record(
+                    3,
+                    2,
+                    AuditAction::Applied(TransactionType::Transfer),
+                    account(0, 0, false),
+                    account(4, 0, false)
+                ),
+                // The sender's account is untouched by the dispute but still recorded.
+                record(
+                    3,
+                    1,
+                    AuditAction::Applied(TransactionType::Dispute),
+                    account(6, 0, false),
+                    account(6, 0, false)
+                ),
+                record(
+                    3,
+                    2,
+                    AuditAction::Applied(TransactionType::Dispute),
+                    account(4, 0, false),
+                    account(0, 4, false)
+                ),
+                record(
+                    3,
+                    1,
+                    AuditAction::Applied(TransactionType::Chargeback),
+                    account(6, 0, false),
+                    account(10, 0, false)
+                ),
+                record(
+                    3,
+                    2,
+                    AuditAction::Applied(TransactionType::Chargeback),
+                    account(0, 4, false),
+                    account(0, 0, true)
+                ),
+            ]
+        );
+        assert!(records[7].locked());
     }
 
     #[test]
-    fn test_dispute_withdrawal_ignored() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(withdrawal(1, 2, dec!(5.0)));
-        engine.process(dispute(1, 2));
+    fn test_state_digest() {
+        let run = |txs: &[Transaction]| {
+            let mut engine = Engine::new();
+            for tx in txs {
+                let _ = engine.process(tx.clone());
+            }
+            engine.state_digest()
+        };
+        let txs = [
+            deposit(2, 1, dec!(5.0)),
+            deposit(1, 2, dec!(3.0)),
+            dispute(2, 1),
+        ];
+        assert_eq!(run(&txs), run(&txs));
+        // Accounts are taken in client order, whatever order they were created in.
+        assert_eq!(
+            run(&[
+                deposit(1, 2, dec!(3.0)),
+                deposit(2, 1, dec!(5.0)),
+                dispute(2, 1)
+            ]),
+            run(&txs)
+        );
+        assert_ne!(run(&txs[..2]), run(&txs));
+        assert_ne!(run(&[deposit(1, 1, dec!(0.0001))]), run(&[]));
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(5, 0));
-        assert_eq!(account.held, 0);
+    #[test]
+    fn test_balance_changes() {
+        let mut engine = Engine::with_config(EngineConfig::default().with_undo_depth(1));
+        let changes = engine.changes();
+
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(50.0))).unwrap_err();
+        engine.process(transfer(1, 3, 2, dec!(4.0))).unwrap();
+        engine.process(dispute(1, 3)).unwrap();
+        assert_eq!(engine.undo_last(1), 1);
+        engine
+            .process_batch([deposit(2, 4, dec!(1.0)), withdrawal(2, 5, dec!(9.0))])
+            .unwrap_err();
+
+        let change = |tx, client, available: i64, held: i64, cause| BalanceChange {
+            client,
+            tx: Some(tx),
+            delta_available: fixed(available, 0),
+            delta_held: fixed(held, 0),
+            cause,
+        };
+        // Neither the rejected withdrawal, nor the dispute's record for the sender, nor the
+        // rolled-back batch moved a balance.
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            [
+                change(1, 1, 10, 0, AuditAction::Applied(TransactionType::Deposit)),
+                change(3, 1, -4, 0, AuditAction::Applied(TransactionType::Transfer)),
+                change(3, 2, 4, 0, AuditAction::Applied(TransactionType::Transfer)),
+                change(3, 2, -4, 4, AuditAction::Applied(TransactionType::Dispute)),
+                change(3, 2, 4, -4, AuditAction::Undone),
+            ]
+        );
+
+        drop(changes);
+        engine.process(deposit(1, 6, dec!(1.0))).unwrap();
     }
 
     #[test]
-    fn test_chargeback_prevents_redispute() {
-        let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(chargeback(1, 1));
-        // Try to dispute again - should be ignored
-        engine.process(dispute(1, 1));
+    fn test_event_log_rebuilds_accounts() {
+        use crate::config::ReservePolicy;
+        use crate::event_log::{AccountProjection, BalanceChange};
+        use std::time::Duration;
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 0); // Should still be 0, not 10
-        assert!(account.locked);
+        let config = EngineConfig::default()
+            .with_event_log()
+            .with_reserve(ReservePolicy {
+                rate: dec!(0.5),
+                period: Duration::from_secs(100),
+            })
+            .with_auto_unlock(Duration::from_secs(1_000));
+        let mut engine = Engine::with_config(config);
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        engine.process(at(0, deposit(1, 1, dec!(10.0)))).unwrap();
+        engine
+            .process(at(0, withdrawal(1, 2, dec!(50.0))))
+            .unwrap_err();
+        engine.process(at(0, transfer(1, 3, 2, dec!(4.0)))).unwrap();
+        engine.process(at(0, dispute(1, 3))).unwrap();
+        engine.process(at(0, chargeback(1, 3))).unwrap();
+        engine
+            .process_batch([deposit(3, 4, dec!(1.0)), withdrawal(3, 5, dec!(9.0))])
+            .unwrap_err();
+        engine.process_batch([deposit(3, 6, dec!(2.0))]).unwrap();
+        engine
+            .apply_admin(AdminOp::Adjust {
+                client: 3,
+                amount: -fixed(1, 0),
+                reason: String::new(),
+            })
+            .unwrap();
+        engine.merge_clients(3, 1).unwrap();
+        engine.advance_time(2_000);
+
+        let deposited = BalanceChange {
+            available: fixed(5, 0),
+            held: Money::ZERO,
+            reserved: fixed(5, 0),
+            on_hold: Money::ZERO,
+        };
+        assert_eq!(
+            engine.events(0)[..2],
+            [
+                DomainEvent::AccountOpened { client: 1 },
+                DomainEvent::FundsDeposited {
+                    client: 1,
+                    tx: 1,
+                    change: deposited
+                }
+            ]
+        );
+        assert!(
+            engine
+                .events(0)
+                .contains(&DomainEvent::AccountLocked { client: 2 })
+        );
+        assert!(
+            engine
+                .events(0)
+                .contains(&DomainEvent::AccountUnlocked { client: 2 })
+        );
+        // The rolled-back batch left no events.
+        assert!(
+            !engine
+                .events(0)
+                .iter()
+                .any(|event| matches!(event, DomainEvent::FundsDeposited { tx: 4, .. }))
+        );
+
+        let mut projection = AccountProjection::default();
+        let offset = engine.replay(0, &mut projection);
+        assert_eq!(offset, engine.events(0).len() as u64);
+        let accounts = |accounts: &mut dyn Iterator<Item = (ClientId, &Account)>| {
+            let mut accounts: Vec<_> = accounts
+                .map(|(client, account)| (client, *account))
+                .collect();
+            accounts.sort_by_key(|&(client, _)| client);
+            accounts
+        };
+        assert_eq!(
+            accounts(&mut projection.accounts()),
+            accounts(&mut engine.accounts())
+        );
+
+        // Replaying from the offset reached picks up only what happened since.
+        engine.process(deposit(2, 7, dec!(3.0))).unwrap();
+        assert_eq!(engine.replay(offset, &mut projection), offset + 1);
+        assert_eq!(projection.account(2), engine.account(2));
+        assert!(engine.events(u64::MAX).is_empty());
+        assert!(Engine::new().events(0).is_empty());
     }
 
     #[test]
-    fn test_resolve_allows_redispute() {
+    fn test_validate_leaves_engine_untouched() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let config = EngineConfig::default()
+            .with_auto_unlock(Duration::from_secs(100))
+            .with_event_log();
+        let mut engine = Engine::with_config(config);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        engine.process(at(0, deposit(1, 1, dec!(10.0)))).unwrap();
+        engine.process(at(0, dispute(1, 1))).unwrap();
+        engine.process(at(0, chargeback(1, 1))).unwrap();
+
+        let state = |engine: &Engine| {
+            (
+                engine.account(1).copied(),
+                engine.account(2).copied(),
+                engine.transaction(2),
+                engine.stats().clone(),
+                engine.events(0).len(),
+            )
+        };
+        let before = state(&engine);
+        assert_eq!(
+            engine.validate(at(50, deposit(1, 2, dec!(1.0)))),
+            Err(ProcessError::AccountLocked)
+        );
+        assert_eq!(engine.validate(deposit(2, 2, dec!(1.0))), Ok(()));
+        assert_eq!(
+            engine.validate(withdrawal(2, 3, dec!(1.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        // The unlock due at 100 fires while validating, and is put back afterwards.
+        assert_eq!(engine.validate(at(150, deposit(1, 4, dec!(1.0)))), Ok(()));
+        assert_eq!(state(&engine), before);
+        assert!(engine.account(1).unwrap().locked);
+        assert!(events.lock().unwrap().is_empty());
+
+        // Inside a batch it sees the batch's changes and leaves the batch alone.
+        engine.begin_batch();
+        engine.process(deposit(2, 5, dec!(3.0))).unwrap();
+        assert_eq!(engine.validate(withdrawal(2, 6, dec!(2.0))), Ok(()));
+        engine.rollback_batch();
+        assert_eq!(engine.account(2), None);
+        engine.advance_time(150);
+        assert!(!engine.account(1).unwrap().locked);
+    }
+
+    #[test]
+    fn test_observer_callbacks() {
+        use crate::observer::EngineObserver;
+        use std::sync::{Arc, Mutex};
+
+        type Call = (&'static str, ClientId, Option<TxId>);
+        struct Recorder(Arc<Mutex<Vec<Call>>>);
+        impl EngineObserver for Recorder {
+            fn on_account_locked(&mut self, event: &AuditEvent) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(("locked", event.client, event.tx));
+            }
+            fn on_account_unlocked(&mut self, event: &AuditEvent) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(("unlocked", event.client, event.tx));
+            }
+            fn on_chargeback(&mut self, event: &AuditEvent) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(("chargeback", event.client, event.tx));
+            }
+            fn on_balance_negative(&mut self, event: &AuditEvent) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(("negative", event.client, event.tx));
+            }
+        }
+
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(dispute(1, 1));
-        engine.process(resolve(1, 1));
-        // Dispute again after resolve - should work
-        engine.process(dispute(1, 1));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        engine.subscribe(Recorder(Arc::clone(&calls)));
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, fixed(10, 0));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(transfer(1, 2, 2, dec!(4.0))).unwrap();
+        engine.process(dispute(1, 2)).unwrap();
+        engine.process(chargeback(1, 2)).unwrap();
+        engine.process(withdrawal(1, 3, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.begin_batch();
+        engine.process(deposit(3, 4, dec!(1.0))).unwrap();
+        engine.process(dispute(3, 4)).unwrap();
+        engine.process(chargeback(3, 4)).unwrap();
+        engine.rollback_batch();
+        engine
+            .apply_admin(AdminOp::Unlock {
+                client: 2,
+                reason: String::new(),
+            })
+            .unwrap();
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                ("chargeback", 2, Some(2)),
+                ("locked", 2, Some(2)),
+                ("negative", 1, Some(1)),
+                ("unlocked", 2, None),
+            ]
+        );
     }
 
+    #[cfg(feature = "tracing")]
     #[test]
-    fn test_precision() {
+    fn test_process_is_traced() {
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Fields as `name=value` pairs.
+        #[derive(Default)]
+        struct Fields(Vec<String>);
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0.push(format!("{}={:?}", field.name(), value));
+            }
+        }
+
+        /// Writes a line for every span, recorded value and event.
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+        impl Recorder {
+            fn push(&self, kind: &str, fields: Fields) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", kind, fields.0.join(" ")));
+            }
+        }
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                self.push(span.metadata().name(), fields);
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, values: &Record<'_>) {
+                let mut fields = Fields::default();
+                values.record(&mut fields);
+                self.push("record", fields);
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.push("event", fields);
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(1.2345)));
-        engine.process(deposit(1, 2, dec!(0.0001)));
+        tracing::subscriber::with_default(Recorder(Arc::clone(&lines)), || {
+            engine.process(deposit(1, 1, dec!(2.0))).unwrap();
+            engine.process(withdrawal(1, 2, dec!(5.0))).unwrap_err();
+        });
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "process client=1 tx=1 type=deposit",
+                "record outcome=\"applied\"",
+                "process client=1 tx=2 type=withdrawal",
+                "record outcome=\"insufficient_funds\"",
+                "event message=transaction rejected: insufficient available funds reason=\"insufficient_funds\"",
+            ]
+        );
+    }
 
-        let output = engine.output();
-        let account = output.iter().find(|a| a.client == 1).unwrap();
-        assert_eq!(account.available, fixed(1, 2346));
+    #[test]
+    fn test_admin_operations() {
+        use std::sync::{Arc, Mutex};
+
+        let mut engine = Engine::new();
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&records);
+        engine.on_audit(move |event| sink.lock().unwrap().push(event.clone()));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        assert!(engine.account(1).unwrap().locked);
+
+        let unlock = AdminOp::Unlock {
+            client: 1,
+            reason: "investigation closed".to_string(),
+        };
+        engine.apply_admin(unlock.clone()).unwrap();
+        assert_eq!(
+            engine.process(deposit(1, 2, dec!(3.0))),
+            Ok(ProcessOutcome::Deposited)
+        );
+        engine
+            .apply_admin(AdminOp::Adjust {
+                client: 1,
+                amount: -fixed(1, 0),
+                reason: String::new(),
+            })
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available, fixed(2, 0));
+        let overflow = AdminOp::Adjust {
+            client: 1,
+            amount: Money::MAX,
+            reason: String::new(),
+        };
+        assert_eq!(engine.apply_admin(overflow), Err(ProcessError::Overflow));
+        assert_eq!(engine.account(1).unwrap().available, fixed(2, 0));
+
+        engine
+            .apply_admin(AdminOp::Lock {
+                client: 2,
+                reason: String::new(),
+            })
+            .unwrap();
+        assert_eq!(
+            engine.process(deposit(2, 3, dec!(1.0))),
+            Err(ProcessError::AccountLocked)
+        );
+
+        let records = records.lock().unwrap();
+        let unlocked = records
+            .iter()
+            .find(|event| event.action == AuditAction::Admin(unlock.clone()))
+            .unwrap();
+        assert_eq!(
+            (unlocked.tx, unlocked.before.locked, unlocked.after.locked),
+            (None, true, false)
+        );
+        assert_eq!(
+            unlocked.action.to_string(),
+            "admin unlock (investigation closed)"
+        );
     }
 
     #[test]
-    fn test_multiple_clients() {
+    fn test_withdrawal_fees_and_interest() {
+        use crate::fees::{FeePolicy, InterestPolicy};
+        use std::time::Duration;
+
+        let fees = FeePolicy {
+            flat: fixed(1, 0),
+            rate: dec!(0.1),
+            account: 99,
+        };
+        let interest = InterestPolicy {
+            rate: dec!(0.1),
+            period: Duration::from_secs(100),
+        };
+        let mut engine = Engine::with_config(
+            EngineConfig::default()
+                .with_fees(fees)
+                .with_interest(interest),
+        );
+        engine.process(deposit(1, 1, dec!(20.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(10.0))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, fixed(8, 0));
+        assert_eq!(engine.account(99).unwrap().available, fixed(2, 0));
+        // 7.5 plus a 1.75 fee is more than is available, so neither is taken.
+        assert_eq!(
+            engine.process(withdrawal(1, 3, dec!(7.5))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        assert_eq!(
+            (
+                engine.account(1).unwrap().available,
+                engine.account(99).unwrap().available
+            ),
+            (fixed(8, 0), fixed(2, 0))
+        );
+        // The fee account pays no fee.
+        engine.process(withdrawal(99, 4, dec!(2.0))).unwrap();
+        assert_eq!(engine.account(99).unwrap().available, Money::ZERO);
+
+        assert_eq!(engine.accrue_interest(1_000), Money::ZERO);
+        assert_eq!(engine.accrue_interest(1_050), fixed(0, 4_000));
+        assert_eq!(engine.accrue_interest(1_050), Money::ZERO);
+        assert_eq!(engine.account(1).unwrap().available, fixed(8, 4_000));
+
+        engine.begin_batch();
+        assert_eq!(engine.accrue_interest(1_150), fixed(0, 8_400));
+        engine.rollback_batch();
+        assert_eq!(engine.account(1).unwrap().available, fixed(8, 4_000));
+        assert_eq!(engine.accrue_interest(1_150), fixed(0, 8_400));
+    }
+
+    #[test]
+    fn test_close_account() {
+        let close = |client| AdminOp::Close {
+            client,
+            reason: String::new(),
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().with_extended_output());
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.apply_admin(close(1)),
+            Err(ProcessError::AccountNotEmpty)
+        );
+        engine.process(withdrawal(1, 2, dec!(10.0))).unwrap();
+
+        // A disputed transfer holds the funds on the recipient, yet the sender can't close either.
+        engine.process(deposit(2, 3, dec!(5.0))).unwrap();
+        engine.process(transfer(2, 4, 3, dec!(5.0))).unwrap();
+        engine.process(dispute(2, 4)).unwrap();
+        assert_eq!(engine.apply_admin(close(2)), Err(ProcessError::OpenDispute));
+        engine.process(resolve(2, 4)).unwrap();
+        engine.apply_admin(close(2)).unwrap();
+        engine.apply_admin(close(1)).unwrap();
+        assert_eq!(engine.account_output(1).unwrap().closed, Some(true));
+        assert_eq!(engine.account_output(3).unwrap().closed, Some(false));
+
+        assert_eq!(
+            engine.process(deposit(1, 5, dec!(1.0))),
+            Err(ProcessError::AccountClosed)
+        );
+        assert_eq!(
+            engine.process(transfer(3, 6, 2, dec!(1.0))),
+            Err(ProcessError::AccountClosed)
+        );
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Err(ProcessError::AccountClosed)
+        );
+        let adjust = AdminOp::Adjust {
+            client: 1,
+            amount: fixed(1, 0),
+            reason: String::new(),
+        };
+        assert_eq!(engine.apply_admin(adjust), Err(ProcessError::AccountClosed));
+        engine
+            .apply_admin(AdminOp::Lock {
+                client: 1,
+                reason: String::new(),
+            })
+            .unwrap();
+        assert_eq!(engine.account(3).unwrap().available, fixed(5, 0));
+
+        // Merging keeps the account open unless both were closed.
+        engine.merge_clients(1, 3).unwrap();
+        assert!(!engine.account(3).unwrap().closed);
+        let mut plain = Engine::new();
+        plain.process(deposit(1, 1, dec!(1.0))).unwrap();
+        assert_eq!(plain.account_output(1).unwrap().closed, None);
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_extended_output_activity() {
+        let mut engine =
+            Engine::with_config(EngineConfig::default().with_scale(2).with_extended_output());
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine
+            .process(Transaction {
+                timestamp: Some(1_700),
+                ..deposit(1, 2, dec!(5.0))
+            })
+            .unwrap();
+        engine.process(withdrawal(1, 3, dec!(2.5))).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 4, dec!(100.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        engine
+            .process(Transaction {
+                timestamp: Some(1_800),
+                ..dispute(1, 1)
+            })
+            .unwrap();
+        engine.process(transfer(1, 5, 2, dec!(1.0))).unwrap();
+        engine.process(dispute(1, 5)).unwrap();
+
+        let activity = engine.account_output(1).unwrap().activity.unwrap();
+        assert_eq!(activity.transactions, 6);
+        assert_eq!(
+            (activity.deposited, activity.withdrawn),
+            (Money::from_units(1500), Money::from_units(250))
+        );
+        assert_eq!((activity.last_tx, activity.last_timestamp), (Some(5), None));
+        // The disputed transfer's funds are held by the recipient.
+        assert_eq!(activity.open_disputes, 1);
+        assert_eq!(
+            engine
+                .account_output(2)
+                .unwrap()
+                .activity
+                .map(|activity| activity.open_disputes),
+            Some(1)
+        );
+
+        let mut out = Vec::new();
+        engine.write_accounts_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked,closed,overdrawn,transactions,open_disputes,deposited,withdrawn,last_tx,last_tx_timestamp\n\
+             1,1.50,10.00,11.50,false,false,false,6,1,15.00,2.50,5,\n\
+             2,0.00,1.00,1.00,false,false,false,0,1,0.00,0.00,,\n"
+        );
+        assert_eq!(
+            Engine::new()
+                .account_output(1)
+                .map(|output| output.activity),
+            None
+        );
+    }
+
+    #[test]
+    fn test_overdraft_limits() {
+        use crate::config::BalanceLimits;
+
+        let overdraft = |client, limit| AdminOp::Overdraft {
+            client,
+            limit: fixed(limit, 0),
+            reason: String::new(),
+        };
+        let limits = BalanceLimits {
+            overdraft: Some(fixed(5, 0)),
+            ..BalanceLimits::default()
+        };
+        let mut engine = Engine::with_config(
+            EngineConfig::default()
+                .with_balance_limits(limits)
+                .with_extended_output(),
+        );
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 2, dec!(15.5))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        engine.process(withdrawal(1, 3, dec!(14.0))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, -fixed(4, 0));
+        assert_eq!(engine.account_output(1).unwrap().overdrawn, Some(true));
+
+        // An operator's limit replaces the configured one, and is rolled back with a batch.
+        engine.begin_batch();
+        engine.apply_admin(overdraft(1, 0)).unwrap();
+        assert_eq!(
+            engine.process(withdrawal(1, 4, dec!(0.5))),
+            Err(ProcessError::InsufficientFunds)
+        );
+        engine.rollback_batch();
+        engine.process(withdrawal(1, 4, dec!(0.5))).unwrap();
+        engine.apply_admin(overdraft(2, 100)).unwrap();
+        assert_eq!(
+            engine.apply_admin(overdraft(2, -1)),
+            Err(ProcessError::InvalidAmount)
+        );
+        engine.process(withdrawal(2, 5, dec!(100.0))).unwrap();
+        // Transfers can't overdraw.
+        assert_eq!(
+            engine.process(transfer(3, 6, 1, dec!(1.0))),
+            Err(ProcessError::InsufficientFunds)
+        );
+
+        let mut snapshot = Vec::new();
+        engine.write_snapshot(0, &mut snapshot).unwrap();
+        let mut restored = Engine::with_config(EngineConfig::default());
+        restored.restore_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.overdraft_limit(2), fixed(100, 0));
+        assert_eq!(restored.overdraft_limit(1), Money::ZERO);
+
+        engine.merge_clients(2, 1).unwrap();
+        assert_eq!(engine.overdraft_limit(1), fixed(100, 0));
+        assert_eq!(engine.overdraft_limit(2), fixed(100, 0));
+        engine.process(deposit(3, 7, dec!(1.0))).unwrap();
+        assert_eq!(engine.account_output(3).unwrap().overdrawn, Some(false));
+    }
+
+    #[test]
+    fn test_ordering_policy_and_last_activity() {
+        use std::sync::{Arc, Mutex};
+
+        let at = |time: u64, tx: Transaction| Transaction {
+            timestamp: Some(time),
+            ..tx
+        };
+        let config = EngineConfig::default()
+            .with_ordering(OrderingPolicy::Reject)
+            .with_last_activity();
+        let mut engine = Engine::with_config(config);
+        engine
+            .process(at(2_000, deposit(1, 1, dec!(10.0))))
+            .unwrap();
+        assert_eq!(
+            engine.process(at(1_000, withdrawal(1, 2, dec!(1.0)))),
+            Err(ProcessError::OutOfOrder)
+        );
+        // Rows without a timestamp, other clients and equal timestamps are in order.
+        engine.process(withdrawal(1, 3, dec!(1.0))).unwrap();
+        engine.process(at(1_000, deposit(2, 4, dec!(1.0)))).unwrap();
+        engine
+            .process(at(2_000, withdrawal(1, 5, dec!(1.0))))
+            .unwrap();
+        assert_eq!(engine.transaction(1).unwrap().timestamp, Some(2_000));
+        assert_eq!(engine.transaction(3).unwrap().timestamp, None);
+
+        // A rolled-back batch doesn't move the client's last activity.
+        assert!(
+            engine
+                .process_batch([
+                    at(3_000, deposit(1, 6, dec!(1.0))),
+                    withdrawal(1, 7, dec!(99.0))
+                ])
+                .is_err()
+        );
+        engine.process(at(2_500, deposit(1, 8, dec!(1.0)))).unwrap();
+        let activity =
+            |engine: &Engine, client| engine.account_output(client).unwrap().last_activity;
+        assert_eq!(
+            (activity(&engine, 1), activity(&engine, 2)),
+            (Some(2_500), Some(1_000))
+        );
+        engine.process(deposit(3, 9, dec!(1.0))).unwrap();
+        assert_eq!(activity(&engine, 3), Some(0));
+
+        let mut engine =
+            Engine::with_config(EngineConfig::default().with_ordering(OrderingPolicy::Flag));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        engine.on_event(move |event| sink.lock().unwrap().push(event.clone()));
+        engine
+            .process(at(2_000, deposit(1, 1, dec!(10.0))))
+            .unwrap();
+        assert_eq!(
+            engine.process(at(1_000, withdrawal(1, 2, dec!(1.0)))),
+            Ok(ProcessOutcome::Withdrawn)
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            [EngineEvent::OutOfOrder {
+                client: 1,
+                tx: 2,
+                timestamp: 1_000,
+                latest: 2_000
+            }]
+        );
+        assert_eq!(engine.account_output(1).unwrap().last_activity, None);
+    }
+
+    #[test]
+    fn test_stats_count_processed_transactions() {
+        use crate::stats::TypeCounts;
+
         let mut engine = Engine::new();
-        engine.process(deposit(1, 1, dec!(10.0)));
-        engine.process(deposit(2, 2, dec!(20.0)));
-        engine.process(withdrawal(1, 3, dec!(5.0)));
+        engine.process(deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(withdrawal(1, 2, dec!(50.0))).unwrap_err();
+        engine.process(deposit(1, 3, dec!(5.0))).unwrap();
+        engine.process(dispute(1, 3)).unwrap();
+        engine.process(resolve(1, 3)).unwrap();
+        engine.process(resolve(1, 3)).unwrap_err();
+        engine.process(dispute(1, 1)).unwrap();
+        engine.process(chargeback(1, 1)).unwrap();
+        // Nothing a rolled-back batch did is counted.
+        engine
+            .process_batch([deposit(2, 4, dec!(1.0)), withdrawal(2, 5, dec!(9.0))])
+            .unwrap_err();
 
-        let output = engine.output();
-        let client1 = output.iter().find(|a| a.client == 1).unwrap();
-        let client2 = output.iter().find(|a| a.client == 2).unwrap();
-        assert_eq!(client1.available, fixed(5, 0));
-        assert_eq!(client2.available, fixed(20, 0));
+        let stats = engine.stats();
+        let counts = |accepted, rejected| TypeCounts { accepted, rejected };
+        assert_eq!(stats.processed, 8);
+        assert_eq!(stats.deposits, counts(2, 0));
+        assert_eq!(stats.withdrawals, counts(0, 1));
+        assert_eq!(stats.disputes, counts(2, 0));
+        assert_eq!(stats.resolves, counts(1, 1));
+        assert_eq!(stats.chargebacks, counts(1, 0));
+        assert_eq!(stats.rejected.get(ProcessError::InsufficientFunds), 1);
+        assert_eq!(stats.rejected.get(ProcessError::NotDisputed), 1);
+        assert_eq!((stats.accounts_locked, stats.batches_rolled_back), (1, 1));
     }
 }
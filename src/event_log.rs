@@ -0,0 +1,350 @@
+//! The engine's account changes as a stream of domain events, kept with
+//! [`EngineConfig::with_event_log`](crate::EngineConfig::with_event_log).
+//!
+//! Every change to an account becomes a [`DomainEvent`]: funds deposited or withdrawn, a
+//! dispute opened, funds put on hold, an account locked, two clients merged and so on, each with the exact
+//! change it made to the balances. Every accepted transaction yields an event for its
+//! client even when its balances didn't move. Events are numbered from 0 in the order they
+//! happened; those of a batch are added when it commits and dropped if it rolls back.
+//!
+//! A [`Projection`] folds events into a view of its own, such as per-client totals or
+//! fraud features. [`Engine::replay`](crate::Engine::replay) feeds it the events from any
+//! offset, so a consumer can start over or pick up where it stopped. [`AccountProjection`]
+//! rebuilds the accounts themselves, and matches [`Engine::accounts`](crate::Engine::accounts)
+//! after replaying the whole log.
+
+use std::collections::HashMap;
+
+use crate::audit::AuditAction;
+use crate::types::{Account, ClientId, Money, TransactionType, TxId};
+
+/// What an event did to an account's balances. Each field is the amount added, negative
+/// for a decrease.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub available: Money,
+    pub held: Money,
+    pub reserved: Money,
+    pub on_hold: Money,
+}
+
+impl BalanceChange {
+    /// The change from `before` to `after`. Differences wrap around the `i64` range, as
+    /// [`apply`](Self::apply) does, so saturated balances still round-trip exactly.
+    fn between(before: &Account, after: &Account) -> Self {
+        let diff = |before: Money, after: Money| {
+            Money::from_units(after.units().wrapping_sub(before.units()))
+        };
+        Self {
+            available: diff(before.available, after.available),
+            held: diff(before.held, after.held),
+            reserved: diff(before.reserved, after.reserved),
+            on_hold: diff(before.on_hold, after.on_hold),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Adds the change to `account`'s balances.
+    pub fn apply(&self, account: &mut Account) {
+        let add = |balance: &mut Money, change: Money| {
+            *balance = Money::from_units(balance.units().wrapping_add(change.units()));
+        };
+        add(&mut account.available, self.available);
+        add(&mut account.held, self.held);
+        add(&mut account.reserved, self.reserved);
+        add(&mut account.on_hold, self.on_hold);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainEvent {
+    /// `client` got an account, with zero balances.
+    AccountOpened {
+        client: ClientId,
+    },
+    FundsDeposited {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    FundsWithdrawn {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// `client` sent transfer `tx`.
+    TransferSent {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// `client` received transfer `tx`.
+    TransferReceived {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    DisputeOpened {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    DisputeResolved {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    ChargedBack {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    ChargebackReversed {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// Hold `tx` set funds aside.
+    FundsHeld {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// Funds of hold `tx` were released, in full or in part.
+    FundsReleased {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// The rolling reserve withheld from deposit `tx` became available.
+    ReserveReleased {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// The dispute on `tx` passed its expiry and was resolved automatically.
+    DisputeExpired {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// The fee account `client` was credited the fee on withdrawal `tx`. The withdrawing
+    /// client's `FundsWithdrawn` includes the fee.
+    FeeCollected {
+        client: ClientId,
+        tx: TxId,
+        change: BalanceChange,
+    },
+    /// Interest was credited to the available balance.
+    InterestAccrued {
+        client: ClientId,
+        change: BalanceChange,
+    },
+    /// Any other change to the balances, such as a manual adjustment.
+    BalanceAdjusted {
+        client: ClientId,
+        tx: Option<TxId>,
+        change: BalanceChange,
+    },
+    AccountLocked {
+        client: ClientId,
+    },
+    AccountUnlocked {
+        client: ClientId,
+    },
+    /// An operator closed the account.
+    AccountClosed {
+        client: ClientId,
+    },
+    /// Client `from`'s account was added into `into`'s, which is locked if either was and
+    /// stays closed only if both were. `from`'s account is gone.
+    ClientsMerged {
+        from: ClientId,
+        into: ClientId,
+    },
+}
+
+impl DomainEvent {
+    /// The client whose account the event changed; for a merge, the one merged into.
+    pub fn client(&self) -> ClientId {
+        match *self {
+            DomainEvent::AccountOpened { client }
+            | DomainEvent::FundsDeposited { client, .. }
+            | DomainEvent::FundsWithdrawn { client, .. }
+            | DomainEvent::TransferSent { client, .. }
+            | DomainEvent::TransferReceived { client, .. }
+            | DomainEvent::DisputeOpened { client, .. }
+            | DomainEvent::DisputeResolved { client, .. }
+            | DomainEvent::ChargedBack { client, .. }
+            | DomainEvent::ChargebackReversed { client, .. }
+            | DomainEvent::FundsHeld { client, .. }
+            | DomainEvent::FundsReleased { client, .. }
+            | DomainEvent::ReserveReleased { client, .. }
+            | DomainEvent::DisputeExpired { client, .. }
+            | DomainEvent::FeeCollected { client, .. }
+            | DomainEvent::InterestAccrued { client, .. }
+            | DomainEvent::BalanceAdjusted { client, .. }
+            | DomainEvent::AccountLocked { client }
+            | DomainEvent::AccountUnlocked { client }
+            | DomainEvent::AccountClosed { client } => client,
+            DomainEvent::ClientsMerged { into, .. } => into,
+        }
+    }
+
+    /// The change to the balances, for the events that carry one.
+    pub fn change(&self) -> Option<BalanceChange> {
+        match *self {
+            DomainEvent::FundsDeposited { change, .. }
+            | DomainEvent::FundsWithdrawn { change, .. }
+            | DomainEvent::TransferSent { change, .. }
+            | DomainEvent::TransferReceived { change, .. }
+            | DomainEvent::DisputeOpened { change, .. }
+            | DomainEvent::DisputeResolved { change, .. }
+            | DomainEvent::ChargedBack { change, .. }
+            | DomainEvent::ChargebackReversed { change, .. }
+            | DomainEvent::FundsHeld { change, .. }
+            | DomainEvent::FundsReleased { change, .. }
+            | DomainEvent::ReserveReleased { change, .. }
+            | DomainEvent::DisputeExpired { change, .. }
+            | DomainEvent::FeeCollected { change, .. }
+            | DomainEvent::InterestAccrued { change, .. }
+            | DomainEvent::BalanceAdjusted { change, .. } => Some(change),
+            DomainEvent::AccountOpened { .. }
+            | DomainEvent::AccountLocked { .. }
+            | DomainEvent::AccountUnlocked { .. }
+            | DomainEvent::AccountClosed { .. }
+            | DomainEvent::ClientsMerged { .. } => None,
+        }
+    }
+}
+
+/// A view built from domain events.
+pub trait Projection {
+    /// Folds in the event numbered `offset`.
+    fn apply(&mut self, offset: u64, event: &DomainEvent);
+}
+
+/// The accounts, rebuilt from their events.
+#[derive(Debug, Default)]
+pub struct AccountProjection {
+    accounts: HashMap<ClientId, Account>,
+}
+
+impl AccountProjection {
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Every account, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &Account)> {
+        self.accounts
+            .iter()
+            .map(|(&client, account)| (client, account))
+    }
+}
+
+impl Projection for AccountProjection {
+    fn apply(&mut self, _offset: u64, event: &DomainEvent) {
+        let client = event.client();
+        match *event {
+            DomainEvent::AccountOpened { .. } => {
+                self.accounts.entry(client).or_default();
+            }
+            DomainEvent::AccountLocked { .. } => {
+                self.accounts.entry(client).or_default().locked = true;
+            }
+            DomainEvent::AccountUnlocked { .. } => {
+                self.accounts.entry(client).or_default().locked = false;
+            }
+            DomainEvent::AccountClosed { .. } => {
+                self.accounts.entry(client).or_default().closed = true;
+            }
+            DomainEvent::ClientsMerged { from, into } => {
+                let source = self.accounts.remove(&from).unwrap_or_default();
+                let target = self.accounts.entry(into).or_default();
+                BalanceChange {
+                    available: source.available,
+                    held: source.held,
+                    reserved: source.reserved,
+                    on_hold: source.on_hold,
+                }
+                .apply(target);
+                target.locked |= source.locked;
+                target.closed &= source.closed;
+            }
+            _ => {
+                if let Some(change) = event.change() {
+                    change.apply(self.accounts.entry(client).or_default());
+                }
+            }
+        }
+    }
+}
+
+/// The events for how `client`'s account went from `before` to `after` through `action`,
+/// where `tx` is the transaction involved and `primary` says whether `client` made it.
+pub(crate) fn account_events(
+    action: &AuditAction,
+    tx: Option<TxId>,
+    client: ClientId,
+    primary: bool,
+    before: Option<Account>,
+    after: Option<Account>,
+) -> Vec<DomainEvent> {
+    let mut events = Vec::new();
+    if let AuditAction::Merged { from, into } = *action {
+        if client == from {
+            events.push(DomainEvent::ClientsMerged { from, into });
+        }
+        return events;
+    }
+    if before.is_none() && after.is_some() {
+        events.push(DomainEvent::AccountOpened { client });
+    }
+    let (before, after) = (before.unwrap_or_default(), after.unwrap_or_default());
+    let change = BalanceChange::between(&before, &after);
+    let applied = matches!(action, AuditAction::Applied(_));
+    if !change.is_zero() || (applied && primary) {
+        events.push(match (action, tx) {
+            (AuditAction::Applied(tx_type), Some(tx)) => match tx_type {
+                TransactionType::Deposit => DomainEvent::FundsDeposited { client, tx, change },
+                TransactionType::Withdrawal if primary => {
+                    DomainEvent::FundsWithdrawn { client, tx, change }
+                }
+                TransactionType::Withdrawal => DomainEvent::FeeCollected { client, tx, change },
+                TransactionType::Transfer if primary => {
+                    DomainEvent::TransferSent { client, tx, change }
+                }
+                TransactionType::Transfer => DomainEvent::TransferReceived { client, tx, change },
+                TransactionType::Dispute => DomainEvent::DisputeOpened { client, tx, change },
+                TransactionType::Resolve => DomainEvent::DisputeResolved { client, tx, change },
+                TransactionType::Chargeback => DomainEvent::ChargedBack { client, tx, change },
+                TransactionType::ChargebackReversal => {
+                    DomainEvent::ChargebackReversed { client, tx, change }
+                }
+                TransactionType::Hold => DomainEvent::FundsHeld { client, tx, change },
+                TransactionType::Release => DomainEvent::FundsReleased { client, tx, change },
+            },
+            (AuditAction::ReserveReleased, Some(tx)) => {
+                DomainEvent::ReserveReleased { client, tx, change }
+            }
+            (AuditAction::DisputeExpired, Some(tx)) => {
+                DomainEvent::DisputeExpired { client, tx, change }
+            }
+            (AuditAction::InterestAccrued, _) => DomainEvent::InterestAccrued { client, change },
+            _ => DomainEvent::BalanceAdjusted { client, tx, change },
+        });
+    }
+    match (before.locked, after.locked) {
+        (false, true) => events.push(DomainEvent::AccountLocked { client }),
+        (true, false) => events.push(DomainEvent::AccountUnlocked { client }),
+        _ => {}
+    }
+    if !before.closed && after.closed {
+        events.push(DomainEvent::AccountClosed { client });
+    }
+    events
+}
@@ -0,0 +1,138 @@
+//! Notable things the engine does besides applying balances, delivered to sinks registered
+//! with [`Engine::on_event`](crate::Engine::on_event).
+
+use std::fmt;
+
+use crate::outcome::ProcessError;
+use crate::types::{ClientId, Money, TransactionType, TxId};
+
+/// Callback receiving engine events. Sinks must be `Send` so the engine can move between
+/// threads.
+pub type EventSink = Box<dyn FnMut(&EngineEvent) + Send>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineEvent {
+    /// A balance update for `client` would have left the `i64` range and was clamped at the
+    /// bound instead, so the account no longer equals the exact sum of its transactions.
+    /// `attempted` is the fixed-point amount that was being applied by transaction `tx`.
+    Saturated {
+        client: ClientId,
+        tx: TxId,
+        attempted: Money,
+    },
+    /// `client`'s account was locked because transaction `tx` would have overflowed a
+    /// balance, with the arithmetic mode set to `Freeze`.
+    AccountFrozen { client: ClientId, tx: TxId },
+    /// `client`'s account was unlocked automatically once the cooldown after its last
+    /// chargeback elapsed.
+    AccountUnlocked { client: ClientId },
+    /// The dispute on deposit `tx` passed its expiry and was resolved automatically.
+    DisputeExpired { client: ClientId, tx: TxId },
+    /// The dispute on deposit `tx` passed its expiry; the funds remain held pending review.
+    DisputeEscalated { client: ClientId, tx: TxId },
+    /// Transaction id `tx` was introduced again by a deposit or withdrawal after
+    /// `first_client` already used it for a `first_type`. Ids are meant to be globally
+    /// unique, so this usually points at corrupt or replayed upstream data.
+    IdCollision {
+        tx: TxId,
+        client: ClientId,
+        tx_type: TransactionType,
+        first_client: ClientId,
+        first_type: TransactionType,
+    },
+    /// Transaction `tx` of `client` is timestamped `timestamp`, before the client's latest
+    /// transaction at `latest`. Emitted with the ordering policy set to `Flag`, whether or
+    /// not the transaction is then accepted.
+    OutOfOrder {
+        client: ClientId,
+        tx: TxId,
+        timestamp: u64,
+        latest: u64,
+    },
+    /// Client `from` was merged into client `into`, which took over its balances and
+    /// `deposits` stored deposits. Later transactions for `from` apply to `into`.
+    ClientsMerged {
+        from: ClientId,
+        into: ClientId,
+        deposits: usize,
+    },
+    /// Post-dated transaction `tx` of `client` was rejected when it reached its value date.
+    ScheduledRejected {
+        client: ClientId,
+        tx: TxId,
+        error: ProcessError,
+    },
+}
+
+impl fmt::Display for EngineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineEvent::Saturated {
+                client,
+                tx,
+                attempted,
+            } => write!(
+                f,
+                "balance of client {} saturated applying {} from tx {}",
+                client, attempted, tx
+            ),
+            EngineEvent::AccountFrozen { client, tx } => write!(
+                f,
+                "account of client {} frozen: tx {} would overflow a balance",
+                client, tx
+            ),
+            EngineEvent::AccountUnlocked { client } => {
+                write!(f, "account of client {} unlocked after cooldown", client)
+            }
+            EngineEvent::DisputeExpired { client, tx } => {
+                write!(
+                    f,
+                    "dispute on tx {} of client {} expired and was resolved",
+                    tx, client
+                )
+            }
+            EngineEvent::DisputeEscalated { client, tx } => {
+                write!(
+                    f,
+                    "dispute on tx {} of client {} expired and was escalated",
+                    tx, client
+                )
+            }
+            EngineEvent::IdCollision {
+                tx,
+                client,
+                tx_type,
+                first_client,
+                first_type,
+            } => write!(
+                f,
+                "tx id {} reused by {} of client {} (first used by {} of client {})",
+                tx, tx_type, client, first_type, first_client
+            ),
+            EngineEvent::OutOfOrder {
+                client,
+                tx,
+                timestamp,
+                latest,
+            } => write!(
+                f,
+                "tx {} of client {} is out of order: timestamp {} precedes {}",
+                tx, client, timestamp, latest
+            ),
+            EngineEvent::ClientsMerged {
+                from,
+                into,
+                deposits,
+            } => write!(
+                f,
+                "client {} merged into client {} ({} deposits moved)",
+                from, into, deposits
+            ),
+            EngineEvent::ScheduledRejected { client, tx, error } => write!(
+                f,
+                "post-dated tx {} of client {} rejected at its value date: {}",
+                tx, client, error
+            ),
+        }
+    }
+}
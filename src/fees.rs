@@ -0,0 +1,101 @@
+//! Withdrawal fees and interest on balances, configured with
+//! [`EngineConfig::with_fees`](crate::EngineConfig::with_fees) and
+//! [`EngineConfig::with_interest`](crate::EngineConfig::with_interest).
+//!
+//! A [`FeePolicy`] charges every withdrawal a flat amount plus a share of what is withdrawn,
+//! on top of the amount itself, and credits it to a designated fee account. The withdrawal
+//! and its fee are applied together or not at all: a client who can't cover both is
+//! rejected with `insufficient_funds`, and a fee that would overflow the fee account is
+//! handled by the arithmetic mode like any other overflow.
+//!
+//! An [`InterestPolicy`] is applied by [`Engine::accrue_interest`](crate::Engine::accrue_interest),
+//! fed from timestamped `accrue` rows in CSV input. Each accrual credits every open account
+//! with a positive available balance with interest for the time since the previous one,
+//! pro rata at `rate` per `period`. Amounts are truncated to the engine's scale.
+
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+use crate::types::{ClientId, Money};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeePolicy {
+    /// Charged on every withdrawal, in minor units.
+    pub flat: Money,
+    /// Fraction of the amount withdrawn charged on top of `flat` (e.g. `0.01` for 1%).
+    pub rate: Decimal,
+    /// The client credited with the fees. Its own withdrawals are free.
+    pub account: ClientId,
+}
+
+impl FeePolicy {
+    /// The fee on a withdrawal of `amount`, at `scale` decimal places.
+    pub fn fee(&self, amount: Money, scale: u32) -> Money {
+        let share = amount
+            .to_decimal(scale)
+            .checked_mul(self.rate)
+            .map_or(Money::MAX, |share| Money::from_decimal_lossy(share, scale));
+        self.flat.saturating_add(share)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterestPolicy {
+    /// Fraction of the available balance earned per `period` (e.g. `0.001` for 0.1%).
+    pub rate: Decimal,
+    pub period: Duration,
+}
+
+impl InterestPolicy {
+    /// The interest earned by `balance` over `elapsed` seconds, at `scale` decimal places.
+    /// Zero for a balance that isn't positive.
+    pub fn interest(&self, balance: Money, elapsed: u64, scale: u32) -> Money {
+        let period = self.period.as_secs();
+        if !balance.is_positive() || period == 0 {
+            return Money::ZERO;
+        }
+        balance
+            .to_decimal(scale)
+            .checked_mul(self.rate)
+            .and_then(|interest| interest.checked_mul(Decimal::from(elapsed)))
+            .and_then(|interest| interest.checked_div(Decimal::from(period)))
+            .map_or(Money::MAX, |interest| {
+                Money::from_decimal_lossy(interest, scale)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_fee_and_interest() {
+        let fees = FeePolicy {
+            flat: Money::from_units(5_000),
+            rate: dec!(0.01),
+            account: 0,
+        };
+        // 0.5 flat plus 1% of 12.3456, truncated to 4 places.
+        assert_eq!(
+            fees.fee(Money::from_units(123_456), 4),
+            Money::from_units(6_234)
+        );
+
+        let interest = InterestPolicy {
+            rate: dec!(0.1),
+            period: Duration::from_secs(100),
+        };
+        assert_eq!(
+            interest.interest(Money::from_units(10_000), 50, 4),
+            Money::from_units(500)
+        );
+        assert_eq!(
+            interest.interest(Money::from_units(-10_000), 50, 4),
+            Money::ZERO
+        );
+        assert_eq!(interest.interest(Money::from_units(3), 1, 4), Money::ZERO);
+    }
+}
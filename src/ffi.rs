@@ -0,0 +1,195 @@
+//! C interface for embedding the engine in C and C++ programs (feature `ffi`).
+//!
+//! Building with the feature writes the matching declarations to `include/tx_engine.h`.
+//! An engine is an opaque `TxEngine` pointer from [`tx_engine_new`], released with
+//! [`tx_engine_free`]. Transactions go in as JSON objects with the input's column names,
+//! one per [`tx_engine_process`] call, and [`tx_engine_output_json`] returns the accounts
+//! as a JSON array. None of the functions may be called on the same engine from two
+//! threads at once.
+
+use std::ffi::{CStr, CString, c_char};
+use std::ptr;
+
+use crate::engine::Engine;
+use crate::types::Transaction;
+
+/// The transaction was applied.
+pub const TX_ENGINE_APPLIED: i32 = 0;
+/// The transaction was rejected; [`tx_engine_last_error`] gives the reason code.
+pub const TX_ENGINE_REJECTED: i32 = 1;
+/// The argument was null, not UTF-8 or not a transaction; [`tx_engine_last_error`] says why.
+pub const TX_ENGINE_INVALID: i32 = -1;
+
+/// An engine and the message about its latest failed call.
+pub struct TxEngine {
+    engine: Engine,
+    last_error: Option<CString>,
+}
+
+/// Creates an engine with the default configuration. Release it with [`tx_engine_free`].
+#[unsafe(no_mangle)]
+pub extern "C" fn tx_engine_new() -> *mut TxEngine {
+    Box::into_raw(Box::new(TxEngine {
+        engine: Engine::new(),
+        last_error: None,
+    }))
+}
+
+/// Applies the transaction in the NUL-terminated JSON string `transaction`, e.g.
+/// `{"type":"deposit","client":1,"tx":1,"amount":"2.5"}`. Returns `TX_ENGINE_APPLIED`,
+/// `TX_ENGINE_REJECTED` or `TX_ENGINE_INVALID`.
+///
+/// # Safety
+///
+/// `engine` must come from [`tx_engine_new`] and not have been freed, and `transaction`
+/// must be null or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tx_engine_process(
+    engine: *mut TxEngine,
+    transaction: *const c_char,
+) -> i32 {
+    let Some(engine) = (unsafe { engine.as_mut() }) else {
+        return TX_ENGINE_INVALID;
+    };
+    let result = if transaction.is_null() {
+        Err("transaction is null".to_string())
+    } else {
+        let input = unsafe { CStr::from_ptr(transaction) };
+        process_json(&mut engine.engine, input)
+    };
+    match result {
+        Ok(None) => {
+            engine.last_error = None;
+            TX_ENGINE_APPLIED
+        }
+        Ok(Some(code)) => {
+            engine.set_error(code);
+            TX_ENGINE_REJECTED
+        }
+        Err(message) => {
+            engine.set_error(&message);
+            TX_ENGINE_INVALID
+        }
+    }
+}
+
+/// The reason the latest [`tx_engine_process`] call on `engine` did not apply its
+/// transaction: a rejection code such as `insufficient_funds`, or a description of the
+/// invalid input. Null if it was applied. The string stays valid until the next call on
+/// `engine`.
+///
+/// # Safety
+///
+/// `engine` must come from [`tx_engine_new`] and not have been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tx_engine_last_error(engine: *const TxEngine) -> *const c_char {
+    match unsafe { engine.as_ref() }.and_then(|engine| engine.last_error.as_ref()) {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Every account, ordered by client, as a JSON array in the format of the HTTP server's
+/// `GET /accounts`. Release the string with [`tx_engine_string_free`]. Null if `engine`
+/// is null.
+///
+/// # Safety
+///
+/// `engine` must come from [`tx_engine_new`] and not have been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tx_engine_output_json(engine: *const TxEngine) -> *mut c_char {
+    let Some(engine) = (unsafe { engine.as_ref() }) else {
+        return ptr::null_mut();
+    };
+    let json =
+        serde_json::to_string(&engine.engine.output_sorted()).expect("accounts serialize to JSON");
+    CString::new(json)
+        .expect("JSON has no NUL bytes")
+        .into_raw()
+}
+
+/// Releases a string returned by [`tx_engine_output_json`]. Does nothing if it is null.
+///
+/// # Safety
+///
+/// `string` must be null or come from [`tx_engine_output_json`], and not have been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tx_engine_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Releases an engine. Does nothing if it is null.
+///
+/// # Safety
+///
+/// `engine` must be null or come from [`tx_engine_new`], and not have been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tx_engine_free(engine: *mut TxEngine) {
+    if !engine.is_null() {
+        drop(unsafe { Box::from_raw(engine) });
+    }
+}
+
+impl TxEngine {
+    fn set_error(&mut self, message: &str) {
+        // Messages come from serde_json and the rejection codes, neither of which has NULs.
+        self.last_error = CString::new(message).ok();
+    }
+}
+
+/// Applies the transaction in `input`, returning the rejection code if it was rejected.
+fn process_json(engine: &mut Engine, input: &CStr) -> Result<Option<&'static str>, String> {
+    let input = input
+        .to_str()
+        .map_err(|_| "transaction is not UTF-8".to_string())?;
+    let tx: Transaction = serde_json::from_str(input).map_err(|error| error.to_string())?;
+    Ok(engine.process(tx).err().map(|error| error.code()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffi_round_trip() {
+        unsafe {
+            let engine = tx_engine_new();
+            let deposit = c"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.5\"}";
+            assert_eq!(
+                tx_engine_process(engine, deposit.as_ptr()),
+                TX_ENGINE_APPLIED
+            );
+            assert!(tx_engine_last_error(engine).is_null());
+
+            let withdrawal = c"{\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"9\"}";
+            assert_eq!(
+                tx_engine_process(engine, withdrawal.as_ptr()),
+                TX_ENGINE_REJECTED
+            );
+            let reason = CStr::from_ptr(tx_engine_last_error(engine));
+            assert_eq!(reason.to_str().unwrap(), "insufficient_funds");
+
+            assert_eq!(
+                tx_engine_process(engine, c"{\"type\":".as_ptr()),
+                TX_ENGINE_INVALID
+            );
+            assert!(!tx_engine_last_error(engine).is_null());
+            assert_eq!(tx_engine_process(engine, ptr::null()), TX_ENGINE_INVALID);
+            assert_eq!(
+                tx_engine_process(ptr::null_mut(), deposit.as_ptr()),
+                TX_ENGINE_INVALID
+            );
+
+            let output = tx_engine_output_json(engine);
+            assert_eq!(
+                CStr::from_ptr(output).to_str().unwrap(),
+                "[{\"client\":1,\"available\":\"2.5000\",\"held\":\"0.0000\",\
+                 \"total\":\"2.5000\",\"locked\":false}]"
+            );
+            tx_engine_string_free(output);
+            tx_engine_free(engine);
+        }
+    }
+}
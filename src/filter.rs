@@ -0,0 +1,123 @@
+//! Selection of a few clients out of a large input, such as `1,2,5-10`.
+//!
+//! Investigating one customer shouldn't mean processing every other customer's
+//! transactions. A [`ClientFilter`] given to
+//! [`CsvReader::with_clients`](crate::io::CsvReader::with_clients) drops the rows of other
+//! clients as they are read, and given to
+//! [`ReportOptions::clients`](crate::schema::ReportOptions::clients) keeps their accounts
+//! out of the report.
+//!
+//! A transfer is kept when either of its clients is selected. Transfers a selected client
+//! receives from one that isn't are still rejected when their sender's deposits were
+//! dropped, so the balances of such a client differ from a full run.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::types::{ClientId, Transaction};
+
+/// A set of client ids, held as sorted, non-overlapping ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFilter {
+    ranges: Vec<RangeInclusive<ClientId>>,
+}
+
+impl ClientFilter {
+    /// The clients in any of `ranges`.
+    pub fn new(ranges: impl IntoIterator<Item = RangeInclusive<ClientId>>) -> Self {
+        let mut sorted: Vec<_> = ranges
+            .into_iter()
+            .filter(|range| !range.is_empty())
+            .collect();
+        sorted.sort_unstable_by_key(|range| *range.start());
+        let mut ranges: Vec<RangeInclusive<ClientId>> = Vec::with_capacity(sorted.len());
+        for range in sorted {
+            match ranges.last_mut() {
+                Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                    *last = *last.start()..=*last.end().max(range.end());
+                }
+                _ => ranges.push(range),
+            }
+        }
+        Self { ranges }
+    }
+
+    pub fn contains(&self, client: ClientId) -> bool {
+        let index = self.ranges.partition_point(|range| *range.end() < client);
+        self.ranges
+            .get(index)
+            .is_some_and(|range| range.contains(&client))
+    }
+
+    /// Whether `tx` involves a selected client, as its client or the recipient of a transfer.
+    pub fn selects(&self, tx: &Transaction) -> bool {
+        self.contains(tx.client) || tx.to_client.is_some_and(|to| self.contains(to))
+    }
+}
+
+/// Comma-separated client ids and inclusive ranges, e.g. `1,2,5-10`.
+impl FromStr for ClientFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let client = |value: &str| {
+            value
+                .trim()
+                .parse::<ClientId>()
+                .map_err(|_| format!("invalid client id '{}'", value.trim()))
+        };
+        let mut ranges = Vec::new();
+        for part in s.split(',') {
+            let range = match part.split_once('-') {
+                Some((start, end)) => client(start)?..=client(end)?,
+                None => client(part).map(|client| client..=client)?,
+            };
+            if range.is_empty() {
+                return Err(format!("empty client range '{}'", part.trim()));
+            }
+            ranges.push(range);
+        }
+        Ok(Self::new(ranges))
+    }
+}
+
+/// The ranges in the form [`ClientFilter::from_str`] reads.
+impl fmt::Display for ClientFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, range) in self.ranges.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            match (range.start(), range.end()) {
+                (start, end) if start == end => write!(f, "{}", start)?,
+                (start, end) => write!(f, "{}-{}", start, end)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_contains() {
+        let filter: ClientFilter = "7, 1,5-10,2,11".parse().unwrap();
+        assert_eq!(filter.to_string(), "1-2,5-11");
+        for client in [1, 2, 5, 8, 11] {
+            assert!(filter.contains(client));
+        }
+        for client in [0, 3, 4, 12, ClientId::MAX] {
+            assert!(!filter.contains(client));
+        }
+
+        assert!("".parse::<ClientFilter>().is_err());
+        assert!("1,x".parse::<ClientFilter>().is_err());
+        assert_eq!(
+            "10-5".parse::<ClientFilter>(),
+            Err("empty client range '10-5'".to_string())
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Fixed-width record input for mainframe-originated transaction files.
+//!
+//! Field positions come from a layout file with one field per line:
+//!
+//! ```text
+//! # field  start  width
+//! type     1      10
+//! client   11     5
+//! tx       16     10
+//! amount   26     20
+//! ```
+//!
+//! `start` is 1-based, matching the column numbers in copybook-style record descriptions.
+//! Blank lines and `#` comments are ignored. Values are trimmed before parsing, and a blank
+//! or truncated amount field is read as no amount.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+
+use crate::types::{Transaction, TransactionType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    /// 0-based byte offset into the record.
+    pub offset: usize,
+    pub width: usize,
+}
+
+impl Field {
+    fn slice<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let end = (self.offset + self.width).min(line.len());
+        if self.offset >= end {
+            return Some("");
+        }
+        line.get(self.offset..end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub tx_type: Field,
+    pub client: Field,
+    pub tx: Field,
+    pub amount: Field,
+}
+
+#[derive(Debug)]
+pub enum FixedWidthError {
+    Io(io::Error),
+    /// The layout file is malformed. `line` is 1-based.
+    Layout {
+        line: usize,
+        message: String,
+    },
+    /// The layout file does not define one of the required fields.
+    MissingField(&'static str),
+    /// A record could not be converted. `line` is 1-based.
+    Record {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for FixedWidthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixedWidthError::Io(e) => write!(f, "{}", e),
+            FixedWidthError::Layout { line, message } => {
+                write!(f, "layout line {}: {}", line, message)
+            }
+            FixedWidthError::MissingField(name) => write!(f, "layout is missing field '{}'", name),
+            FixedWidthError::Record { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for FixedWidthError {}
+
+impl From<io::Error> for FixedWidthError {
+    fn from(e: io::Error) -> Self {
+        FixedWidthError::Io(e)
+    }
+}
+
+impl Layout {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FixedWidthError> {
+        fs::read_to_string(path)?.parse()
+    }
+}
+
+impl FromStr for Layout {
+    type Err = FixedWidthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields: [Option<Field>; 4] = [None; 4];
+
+        for (index, raw) in s.lines().enumerate() {
+            let line = index + 1;
+            let err = |message: String| FixedWidthError::Layout { line, message };
+            let content = raw.split('#').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            let parts: Vec<&str> = content.split_whitespace().collect();
+            let [name, start, width] = parts[..] else {
+                return Err(err("expected '<field> <start> <width>'".to_string()));
+            };
+            let start: usize = start
+                .parse()
+                .ok()
+                .filter(|&s| s >= 1)
+                .ok_or_else(|| err(format!("invalid start '{}'", start)))?;
+            let width: usize = width
+                .parse()
+                .ok()
+                .filter(|&w| w >= 1)
+                .ok_or_else(|| err(format!("invalid width '{}'", width)))?;
+            let slot = match name.to_ascii_lowercase().as_str() {
+                "type" => 0,
+                "client" => 1,
+                "tx" => 2,
+                "amount" => 3,
+                _ => return Err(err(format!("unknown field '{}'", name))),
+            };
+            fields[slot] = Some(Field {
+                offset: start - 1,
+                width,
+            });
+        }
+
+        let missing = FixedWidthError::MissingField;
+        Ok(Layout {
+            tx_type: fields[0].ok_or_else(|| missing("type"))?,
+            client: fields[1].ok_or_else(|| missing("client"))?,
+            tx: fields[2].ok_or_else(|| missing("tx"))?,
+            amount: fields[3].ok_or_else(|| missing("amount"))?,
+        })
+    }
+}
+
+/// Streams transactions from fixed-width records, one per line.
+pub struct FixedWidthReader<R> {
+    lines: io::Lines<R>,
+    layout: Layout,
+    line: usize,
+}
+
+impl<R: BufRead> FixedWidthReader<R> {
+    pub fn new(reader: R, layout: Layout) -> Self {
+        Self {
+            lines: reader.lines(),
+            layout,
+            line: 0,
+        }
+    }
+
+    fn parse_record(&self, record: &str) -> Result<Transaction, FixedWidthError> {
+        let line = self.line;
+        let err = |message: String| FixedWidthError::Record { line, message };
+        let field = |field: &Field, name: &str| {
+            field
+                .slice(record)
+                .map(str::trim)
+                .ok_or_else(|| err(format!("{} field is not on a character boundary", name)))
+        };
+
+        let tx_type = TransactionType::from_str(field(&self.layout.tx_type, "type")?)
+            .map_err(|e| err(e.to_string()))?;
+        let client = field(&self.layout.client, "client")?;
+        let client = client
+            .parse()
+            .map_err(|_| err(format!("invalid client id '{}'", client)))?;
+        let tx = field(&self.layout.tx, "tx")?;
+        let tx = tx
+            .parse()
+            .map_err(|_| err(format!("invalid transaction id '{}'", tx)))?;
+        let amount = match field(&self.layout.amount, "amount")? {
+            "" => None,
+            text => Some(
+                Decimal::from_str(text)
+                    .map_err(|e| err(format!("invalid amount '{}': {}", text, e)))?,
+            ),
+        };
+
+        Ok(Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for FixedWidthReader<R> {
+    type Item = Result<Transaction, FixedWidthError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.lines.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+            if record.trim().is_empty() {
+                continue;
+            }
+            return Some(self.parse_record(&record));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const LAYOUT: &str = "\
+# field start width
+type    1  10
+client  11 5
+tx      16 10
+amount  26 20
+";
+
+    #[test]
+    fn test_parse_layout() {
+        let layout: Layout = LAYOUT.parse().unwrap();
+        assert_eq!(
+            layout.client,
+            Field {
+                offset: 10,
+                width: 5
+            }
+        );
+        assert_eq!(
+            layout.amount,
+            Field {
+                offset: 25,
+                width: 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_layout_missing_field() {
+        let err = "type 1 10\nclient 11 5\ntx 16 10\n"
+            .parse::<Layout>()
+            .unwrap_err();
+        assert!(err.to_string().contains("amount"));
+    }
+
+    #[test]
+    fn test_read_records() {
+        let input = "\
+deposit   000010000000001703.2999
+dispute   000010000000001
+
+withdrawal000020000000002      1.5
+";
+        let layout: Layout = LAYOUT.parse().unwrap();
+        let txs: Vec<_> = FixedWidthReader::new(input.as_bytes(), layout)
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(txs.len(), 3);
+        assert_eq!(txs[0].client, 1);
+        assert_eq!(txs[0].tx, 1);
+        assert_eq!(txs[0].amount, Some(dec!(703.2999)));
+        assert!(matches!(txs[1].tx_type, TransactionType::Dispute));
+        assert_eq!(txs[1].amount, None);
+        assert_eq!(txs[2].client, 2);
+        assert_eq!(txs[2].amount, Some(dec!(1.5)));
+    }
+
+    #[test]
+    fn test_bad_record_reports_line() {
+        let input = "deposit   000010000000001 1.0\ndeposit   0000x0000000002 1.0\n";
+        let layout: Layout = LAYOUT.parse().unwrap();
+        let results: Vec<_> = FixedWidthReader::new(input.as_bytes(), layout).collect();
+        assert!(matches!(
+            results[1],
+            Err(FixedWidthError::Record { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+//! Helpers for fuzzing and property-testing code built on the engine (feature `arbitrary`).
+//!
+//! [`Transaction`] implements both `arbitrary::Arbitrary`, for cargo-fuzz style byte-driven
+//! fuzzers, and `proptest::arbitrary::Arbitrary`, with [`transaction`] as its strategy.
+//! Clients and transaction ids come from small ranges, so generated disputes, resolves,
+//! chargebacks and releases mostly refer to transactions made earlier in the same sequence.
+//! [`Engine::check_invariants`](crate::Engine::check_invariants) verifies the engine's books
+//! after each step.
+
+use std::ops::RangeInclusive;
+
+use arbitrary::Unstructured;
+use proptest::prelude::*;
+
+pub use crate::invariants::InvariantViolation;
+use crate::types::{ClientId, Transaction, TransactionType, TxId, from_minor_units};
+
+/// Clients generated transactions belong to.
+pub const CLIENTS: RangeInclusive<ClientId> = 1..=8;
+/// Ids generated transactions use.
+pub const TX_IDS: RangeInclusive<TxId> = 1..=64;
+/// Amounts of generated deposits, withdrawals, transfers, holds and releases, in minor units
+/// (up to 100.0000).
+pub const AMOUNTS: RangeInclusive<i64> = 0..=1_000_000;
+
+const TYPES: [TransactionType; 9] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Transfer,
+    TransactionType::ChargebackReversal,
+    TransactionType::Hold,
+    TransactionType::Release,
+];
+
+/// Builds a transaction from generated parts; the amount and recipient are only set for the
+/// types that use them.
+fn build(
+    tx_type: TransactionType,
+    client: ClientId,
+    tx: TxId,
+    units: i64,
+    to: ClientId,
+) -> Transaction {
+    let moves_funds = matches!(
+        tx_type,
+        TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Hold
+            | TransactionType::Release
+    );
+    Transaction {
+        tx_type,
+        client,
+        tx,
+        amount: moves_funds.then(|| from_minor_units(units)),
+        timestamp: None,
+        to_client: (tx_type == TransactionType::Transfer).then_some(to),
+        metadata: Default::default(),
+    }
+}
+
+impl<'a> arbitrary::Arbitrary<'a> for TransactionType {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.choose(&TYPES).copied()
+    }
+}
+
+impl<'a> arbitrary::Arbitrary<'a> for Transaction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let tx_type = TransactionType::arbitrary(u)?;
+        Ok(build(
+            tx_type,
+            u.int_in_range(CLIENTS)?,
+            u.int_in_range(TX_IDS)?,
+            u.int_in_range(AMOUNTS)?,
+            u.int_in_range(CLIENTS)?,
+        ))
+    }
+}
+
+/// Any transaction type, with amounts and clients as described in the module docs.
+pub fn transaction() -> impl Strategy<Value = Transaction> {
+    (0..TYPES.len(), CLIENTS, TX_IDS, AMOUNTS, CLIENTS)
+        .prop_map(|(kind, client, tx, units, to)| build(TYPES[kind], client, tx, units, to))
+}
+
+impl proptest::arbitrary::Arbitrary for Transaction {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Transaction>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        transaction().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DisputeShortfallPolicy, EngineConfig, ReservePolicy};
+    use crate::engine::Engine;
+    use arbitrary::Arbitrary;
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+
+    proptest! {
+        #[test]
+        fn test_invariants_hold(txs in prop::collection::vec(any::<Transaction>(), 0..300)) {
+            let mut engine = Engine::new();
+            for tx in txs {
+                let _ = engine.process(tx);
+                prop_assert_eq!(engine.check_invariants(), Ok(()));
+            }
+        }
+
+        #[test]
+        fn test_invariants_hold_with_reserve(txs in prop::collection::vec(transaction(), 0..300)) {
+            let reserve = ReservePolicy {
+                rate: Decimal::new(1, 1),
+                period: Duration::from_secs(60),
+            };
+            let mut engine = Engine::with_config(EngineConfig::default().with_reserve(reserve));
+            for tx in txs {
+                let _ = engine.process(tx);
+                prop_assert_eq!(engine.check_invariants(), Ok(()));
+            }
+        }
+
+        #[test]
+        fn test_invariants_hold_with_partial_holds(txs in prop::collection::vec(transaction(), 0..300)) {
+            let config = EngineConfig::default().with_dispute_shortfall(DisputeShortfallPolicy::HoldPartial);
+            let mut engine = Engine::with_config(config);
+            for tx in txs {
+                let _ = engine.process(tx);
+                prop_assert_eq!(engine.check_invariants(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_transactions() {
+        let bytes: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let mut u = Unstructured::new(&bytes);
+        let mut engine = Engine::new();
+        while !u.is_empty() {
+            let tx = Transaction::arbitrary(&mut u).unwrap();
+            assert!(CLIENTS.contains(&tx.client) && TX_IDS.contains(&tx.tx));
+            assert_eq!(
+                tx.to_client.is_some(),
+                tx.tx_type == TransactionType::Transfer
+            );
+            let _ = engine.process(tx);
+            assert_eq!(engine.check_invariants(), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! A gRPC API over a shared [`Engine`] (feature `grpc`), as run by `tx-engine serve --grpc`.
+//!
+//! The service is defined in `proto/tx_engine.proto`:
+//!
+//! - `SubmitTransaction` processes one transaction and answers whether it was accepted,
+//!   with the reason's [code](crate::ProcessError::code) if not.
+//! - `GetAccount` returns one account, or `NOT_FOUND`.
+//! - `StreamAccounts` streams every account in memory, ordered by client.
+//! - `GetStats` returns the engine's processing statistics.
+//!
+//! Amounts are decimal strings, as in the CSV input and the report. Like the
+//! [HTTP server](crate::server), the engine sits behind a mutex, so calls are applied one at
+//! a time, in the order they get hold of it.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec;
+
+use rust_decimal::Decimal;
+use tonic::{Request, Response, Status};
+
+use crate::engine::Engine;
+use crate::stats::{self, EngineStats};
+use crate::types::{AccountOutput, ClientId, Money, Transaction, TransactionType};
+
+use proto::tx_engine_server::{TxEngine, TxEngineServer};
+
+/// Messages and service stubs generated from `proto/tx_engine.proto`.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("txengine.v1");
+}
+
+/// The gRPC service, answering calls with a shared engine.
+#[derive(Clone)]
+pub struct GrpcService {
+    engine: Arc<Mutex<Engine>>,
+}
+
+impl GrpcService {
+    pub fn new(engine: Engine) -> Self {
+        Self::shared(Arc::new(Mutex::new(engine)))
+    }
+
+    /// A service over an engine used elsewhere too, such as [`Server::engine`] of an HTTP
+    /// server running alongside.
+    ///
+    /// [`Server::engine`]: crate::server::Server::engine
+    pub fn shared(engine: Arc<Mutex<Engine>>) -> Self {
+        Self { engine }
+    }
+
+    /// The engine calls are applied to, for use alongside the service.
+    pub fn engine(&self) -> Arc<Mutex<Engine>> {
+        Arc::clone(&self.engine)
+    }
+
+    /// The service as a tonic server, to add to a router of one's own.
+    pub fn into_server(self) -> TxEngineServer<Self> {
+        TxEngineServer::new(self)
+    }
+
+    /// Serves the API on `address` until the transport fails.
+    pub async fn serve(self, address: SocketAddr) -> Result<(), tonic::transport::Error> {
+        tonic::transport::Server::builder()
+            .add_service(self.into_server())
+            .serve(address)
+            .await
+    }
+
+    /// Serves the API on `address` from a runtime of its own, blocking the calling thread.
+    pub fn run(self, address: SocketAddr) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(self.serve(address))?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Engine>, Status> {
+        self.engine
+            .lock()
+            .map_err(|_| Status::internal("engine unavailable"))
+    }
+}
+
+#[tonic::async_trait]
+impl TxEngine for GrpcService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::SubmitResponse>, Status> {
+        let tx = transaction(request.into_inner())?;
+        let result = self.lock()?.process(tx);
+        Ok(Response::new(proto::SubmitResponse {
+            accepted: result.is_ok(),
+            error: result
+                .err()
+                .map_or_else(String::new, |error| error.code().to_string()),
+        }))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let request = request.into_inner();
+        let client = client_id(request.client, request.client_high);
+        match self.lock()?.account_output(client) {
+            Some(output) => Ok(Response::new(account(&output))),
+            None => Err(Status::not_found("unknown client")),
+        }
+    }
+
+    type StreamAccountsStream = tokio_stream::Iter<vec::IntoIter<Result<proto::Account, Status>>>;
+
+    async fn stream_accounts(
+        &self,
+        _request: Request<proto::StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        let accounts: Vec<_> = self
+            .lock()?
+            .output_sorted()
+            .iter()
+            .map(|output| Ok(account(output)))
+            .collect();
+        Ok(Response::new(tokio_stream::iter(accounts)))
+    }
+
+    async fn get_stats(
+        &self,
+        _request: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::Stats>, Status> {
+        Ok(Response::new(stats_message(self.lock()?.stats())))
+    }
+}
+
+/// The engine's transaction for a request, or `INVALID_ARGUMENT` if it doesn't describe one.
+fn transaction(request: proto::Transaction) -> Result<Transaction, Status> {
+    let invalid = |field: &str| Status::invalid_argument(format!("invalid {}", field));
+    let tx_type = TransactionType::from_str(&request.r#type).map_err(|_| invalid("type"))?;
+    let client = client_id(request.client, request.client_high);
+    let amount = match request.amount.as_str() {
+        "" => None,
+        amount => Some(Decimal::from_str(amount).map_err(|_| invalid("amount"))?),
+    };
+    let to_client = request
+        .to_client
+        .map(|to_client| client_id(to_client, request.to_client_high));
+    Ok(Transaction {
+        tx_type,
+        client,
+        tx: request.tx,
+        amount,
+        timestamp: request.timestamp,
+        to_client,
+        metadata: request.metadata,
+    })
+}
+
+/// The client id with the low and high 64 bits given.
+fn client_id(low: u64, high: u64) -> ClientId {
+    (ClientId::from(high) << 64) | ClientId::from(low)
+}
+
+fn account(output: &AccountOutput) -> proto::Account {
+    let amount = |value: Money| value.format(output.scale);
+    proto::Account {
+        client: output.client as u64,
+        client_high: (output.client >> 64) as u64,
+        available: amount(output.available),
+        held: amount(output.held),
+        total: amount(output.total),
+        locked: output.locked,
+    }
+}
+
+fn stats_message(stats: &EngineStats) -> proto::Stats {
+    let rejected: HashMap<String, u64> = stats
+        .rejected
+        .iter()
+        .map(|(reason, count)| (reason.code().to_string(), count))
+        .collect();
+    proto::Stats {
+        processed: stats.processed,
+        types: stats::TYPES
+            .iter()
+            .map(|&tx_type| proto::TypeStats {
+                r#type: tx_type.to_string(),
+                accepted: stats.counts(tx_type).accepted,
+                rejected: stats.counts(tx_type).rejected,
+            })
+            .collect(),
+        rejected,
+        accounts_locked: stats.accounts_locked,
+        accounts_frozen: stats.accounts_frozen,
+        accounts_unlocked: stats.accounts_unlocked,
+        disputes_expired: stats.disputes_expired,
+        scheduled: stats.scheduled,
+        batches_rolled_back: stats.batches_rolled_back,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn submit(tx_type: &str, client: u64, tx: u64, amount: &str) -> Request<proto::Transaction> {
+        Request::new(proto::Transaction {
+            r#type: tx_type.to_string(),
+            client,
+            tx,
+            amount: amount.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let service = GrpcService::new(Engine::new());
+        let accepted = service
+            .submit_transaction(submit("deposit", 2, 1, "3.5"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(accepted.accepted);
+        service
+            .submit_transaction(submit("deposit", 1, 2, "1.0"))
+            .await
+            .unwrap();
+        let mut wide = submit("deposit", 2, u64::MAX, "0.5");
+        wide.get_mut().client_high = 1;
+        service.submit_transaction(wide).await.unwrap();
+        let rejected = service
+            .submit_transaction(submit("withdrawal", 1, 3, "5.0"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            (rejected.accepted, rejected.error.as_str()),
+            (false, "insufficient_funds")
+        );
+        let invalid = service
+            .submit_transaction(submit("refund", 1, 4, "1.0"))
+            .await
+            .unwrap_err();
+        assert_eq!(invalid.code(), tonic::Code::InvalidArgument);
+
+        let request = Request::new(proto::GetAccountRequest {
+            client: 2,
+            client_high: 0,
+        });
+        let account = service.get_account(request).await.unwrap().into_inner();
+        assert_eq!(
+            (account.available.as_str(), account.locked),
+            ("3.5000", false)
+        );
+        let request = Request::new(proto::GetAccountRequest {
+            client: 2,
+            client_high: 1,
+        });
+        let account = service.get_account(request).await.unwrap().into_inner();
+        assert_eq!(account.available, "0.5000");
+        let request = Request::new(proto::GetAccountRequest {
+            client: 9,
+            client_high: 0,
+        });
+        let missing = service.get_account(request).await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        let request = Request::new(proto::StreamAccountsRequest {});
+        let stream = service.stream_accounts(request).await.unwrap().into_inner();
+        let clients: Vec<_> = stream
+            .map(|account| account.unwrap())
+            .map(|account| (account.client_high, account.client))
+            .collect()
+            .await;
+        assert_eq!(clients, [(0, 1), (0, 2), (1, 2)]);
+
+        let request = Request::new(proto::GetStatsRequest {});
+        let stats = service.get_stats(request).await.unwrap().into_inner();
+        assert_eq!(stats.processed, 4);
+        assert_eq!(stats.rejected["insufficient_funds"], 1);
+        assert_eq!(stats.types[0].accepted, 3);
+    }
+}
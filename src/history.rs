@@ -0,0 +1,209 @@
+//! Per-client transaction history, kept with `EngineConfig::with_history`, and the
+//! statement CSV built from it.
+
+#[cfg(feature = "io")]
+use std::io::Write;
+
+use crate::types::{Account, ClientId, Money, TransactionType, TxId};
+
+/// One accepted transaction as it affected one client's account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Position among all transactions the engine processed, accepted or not.
+    pub seq: u64,
+    pub tx: TxId,
+    pub tx_type: TransactionType,
+    /// The other client of a transfer, or of the transfer a dispute, resolve or
+    /// chargeback refers to.
+    pub counterparty: Option<ClientId>,
+    /// The transaction's amount, or for a dispute, resolve or chargeback the amount of the
+    /// transaction it refers to. A release has the amount of its hold.
+    pub amount: Money,
+    pub timestamp: Option<u64>,
+    /// The account right after the transaction.
+    pub balance: Account,
+}
+
+#[cfg(feature = "io")]
+const STATEMENT_COLUMNS: [&str; 9] = [
+    "tx",
+    "type",
+    "counterparty",
+    "amount",
+    "available",
+    "held",
+    "total",
+    "locked",
+    "timestamp",
+];
+
+#[cfg(feature = "io")]
+/// Writes `entries` as a statement CSV, one row per transaction with the balance after it,
+/// with amounts of `scale` decimal places. Returns the number of rows; the header is written
+/// even when there are none.
+pub fn write_statement<W: Write>(
+    entries: &[HistoryEntry],
+    scale: u32,
+    writer: W,
+) -> csv::Result<usize> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(STATEMENT_COLUMNS)?;
+    for entry in entries {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let amount = |value: Money| value.format(scale);
+        writer.write_record([
+            entry.tx.to_string(),
+            entry.tx_type.to_string(),
+            optional(entry.counterparty.map(|client| client.to_string())),
+            amount(entry.amount),
+            amount(entry.balance.available),
+            // Funds on hold are shown with held funds, as in the account report.
+            amount(entry.balance.held.saturating_add(entry.balance.on_hold)),
+            amount(entry.balance.total()),
+            entry.balance.locked.to_string(),
+            optional(entry.timestamp.map(|timestamp| timestamp.to_string())),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::engine::Engine;
+    #[cfg(feature = "io")]
+    use crate::types::DEFAULT_SCALE;
+    use crate::types::Transaction;
+    use rust_decimal_macros::dec;
+
+    fn tx(tx_type: TransactionType, client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_history_and_statement() {
+        let mut engine = Engine::with_config(EngineConfig::default().with_history());
+        let deposit = Transaction {
+            amount: Some(dec!(10.0)),
+            timestamp: Some(100),
+            ..tx(TransactionType::Deposit, 1, 1)
+        };
+        let transfer = Transaction {
+            amount: Some(dec!(4.0)),
+            to_client: Some(2),
+            ..tx(TransactionType::Transfer, 1, 2)
+        };
+        let overdraft = Transaction {
+            amount: Some(dec!(50.0)),
+            ..tx(TransactionType::Withdrawal, 1, 3)
+        };
+        engine.process(deposit).unwrap();
+        engine.process(transfer).unwrap();
+        assert!(engine.process(overdraft).is_err());
+        engine.process(tx(TransactionType::Dispute, 1, 2)).unwrap();
+
+        // Rolled-back transactions leave no history.
+        engine.begin_batch();
+        engine.process(tx(TransactionType::Resolve, 1, 2)).unwrap();
+        engine.rollback_batch();
+
+        let sender: Vec<_> = engine
+            .history(1)
+            .iter()
+            .map(|e| (e.seq, e.tx_type))
+            .collect();
+        assert_eq!(
+            sender,
+            [
+                (1, TransactionType::Deposit),
+                (2, TransactionType::Transfer),
+                (4, TransactionType::Dispute)
+            ]
+        );
+        let recipient = engine.history(2);
+        assert_eq!(recipient.len(), 2);
+        assert_eq!(recipient[1].counterparty, Some(1));
+        assert!(engine.history(3).is_empty());
+
+        let mut out = Vec::new();
+        assert_eq!(
+            write_statement(recipient, DEFAULT_SCALE, &mut out).unwrap(),
+            2
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,type,counterparty,amount,available,held,total,locked,timestamp\n\
+             2,transfer,1,4.0000,4.0000,0.0000,4.0000,false,\n\
+             2,dispute,1,4.0000,0.0000,4.0000,4.0000,false,\n"
+        );
+        let mut out = Vec::new();
+        write_statement(engine.history(1), DEFAULT_SCALE, &mut out).unwrap();
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .nth(1)
+                .unwrap()
+                .ends_with(",100")
+        );
+    }
+
+    #[test]
+    fn test_balance_at() {
+        let mut engine = Engine::with_config(EngineConfig::default().with_history());
+        let at = |tx_type, id, amount, timestamp| Transaction {
+            amount,
+            timestamp,
+            ..tx(tx_type, 1, id)
+        };
+        engine
+            .process(at(TransactionType::Deposit, 1, Some(dec!(10.0)), Some(100)))
+            .unwrap();
+        engine
+            .process(at(TransactionType::Deposit, 2, Some(dec!(5.0)), None))
+            .unwrap();
+        engine
+            .process(at(
+                TransactionType::Withdrawal,
+                3,
+                Some(dec!(3.0)),
+                Some(200),
+            ))
+            .unwrap();
+        engine
+            .process(at(TransactionType::Dispute, 1, None, Some(300)))
+            .unwrap();
+
+        let available = |timestamp| engine.balance_at(1, timestamp).map(|a| a.available);
+        assert_eq!(available(99), None);
+        // The untimestamped deposit counts from where it was processed.
+        assert_eq!(available(100), Some(Money::from_units(150_000)));
+        assert_eq!(available(250), Some(Money::from_units(120_000)));
+        let disputed = engine.balance_at(1, u64::MAX).unwrap();
+        assert_eq!(disputed, *engine.account(1).unwrap());
+        assert_eq!(disputed.held, Money::from_units(100_000));
+        assert_eq!(engine.balance_at(2, u64::MAX), None);
+    }
+
+    #[test]
+    fn test_history_is_off_by_default() {
+        let mut engine = Engine::new();
+        let deposit = Transaction {
+            amount: Some(dec!(1.0)),
+            ..tx(TransactionType::Deposit, 1, 1)
+        };
+        engine.process(deposit).unwrap();
+        assert!(engine.history(1).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Visibility into transactions the engine rejected.
+//!
+//! Many valid-looking rows are no-ops: withdrawals without the funds, resolves of
+//! transactions that aren't disputed, disputes of unknown transactions. [`IgnoredCounts`]
+//! tallies them per reason and [`IgnoredLog`] writes one CSV row per rejection, so data
+//! quality problems upstream show up instead of hiding in unchanged balances.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[cfg(feature = "io")]
+use rust_decimal::Decimal;
+#[cfg(feature = "io")]
+use serde::Serialize;
+
+use crate::outcome::ProcessError;
+#[cfg(feature = "io")]
+use crate::types::{ClientId, Transaction, TxId};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IgnoredCounts {
+    counts: BTreeMap<ProcessError, u64>,
+}
+
+impl IgnoredCounts {
+    pub fn record(&mut self, reason: ProcessError) {
+        *self.counts.entry(reason).or_default() += 1;
+    }
+
+    pub fn get(&self, reason: ProcessError) -> u64 {
+        self.counts.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Adds every count in `other` to these.
+    pub fn merge(&mut self, other: &IgnoredCounts) {
+        for (reason, count) in other.iter() {
+            *self.counts.entry(reason).or_default() += count;
+        }
+    }
+
+    /// Non-zero counts, in a fixed order.
+    pub fn iter(&self) -> impl Iterator<Item = (ProcessError, u64)> + '_ {
+        self.counts.iter().map(|(&reason, &count)| (reason, count))
+    }
+
+    /// Writes one `reason: count` line per non-zero category.
+    pub fn write_summary<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (reason, count) in self.iter() {
+            writeln!(writer, "{}: {}", reason.code(), count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "io")]
+#[derive(Serialize)]
+struct Row<'a> {
+    row: u64,
+    #[serde(rename = "type")]
+    tx_type: String,
+    client: ClientId,
+    tx: TxId,
+    amount: Option<&'a Decimal>,
+    reason: &'static str,
+}
+
+#[cfg(feature = "io")]
+/// Detailed CSV report with columns `row`, `type`, `client`, `tx`, `amount`, `reason`.
+/// `row` is the 1-based position of the transaction in the input.
+pub struct IgnoredLog<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+#[cfg(feature = "io")]
+impl<W: Write> IgnoredLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+
+    pub fn record(&mut self, row: u64, tx: &Transaction, reason: ProcessError) -> io::Result<()> {
+        self.writer.serialize(Row {
+            row,
+            tx_type: tx.tx_type.to_string(),
+            client: tx.client,
+            tx: tx.tx,
+            amount: tx.amount.as_ref(),
+            reason: reason.code(),
+        })?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_and_summary() {
+        let mut counts = IgnoredCounts::default();
+        counts.record(ProcessError::NotDisputed);
+        counts.record(ProcessError::InsufficientFunds);
+        counts.record(ProcessError::NotDisputed);
+
+        assert_eq!(counts.get(ProcessError::NotDisputed), 2);
+        assert_eq!(counts.get(ProcessError::AccountLocked), 0);
+        assert_eq!(counts.total(), 3);
+
+        let mut out = Vec::new();
+        counts.write_summary(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "insufficient_funds: 1\nnot_disputed: 2\n"
+        );
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_detailed_log() {
+        use crate::types::TransactionType;
+        use rust_decimal_macros::dec;
+
+        let mut out = Vec::new();
+        let mut log = IgnoredLog::new(&mut out);
+        let tx = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client: 4,
+            tx: 9,
+            amount: Some(dec!(2.5)),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        };
+        log.record(3, &tx, ProcessError::InsufficientFunds).unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "row,type,client,tx,amount,reason\n3,withdrawal,4,9,2.5,insufficient_funds\n"
+        );
+    }
+}
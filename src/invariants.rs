@@ -0,0 +1,542 @@
+//! Checks of the engine's books, on demand with [`Engine::check_invariants`] or after every
+//! accepted transaction with [`EngineConfig::with_invariant_checks`] or the
+//! `debug-invariants` feature.
+//!
+//! After each accepted transaction the accounts it touched are checked: the total still
+//! equals `available + held + reserved + on_hold` (the sum fits in an `i64`), held,
+//! reserved and on-hold funds are not negative, a locked account's balances were only moved
+//! by a dispute, resolve, chargeback, chargeback reversal, hold or release, or by a deposit
+//! or incoming transfer under [`LockedPolicy::AllowCreditsOnly`], and an account was only
+//! locked by a chargeback and unlocked by a reversal. A transaction that breaks one is
+//! undone and rejected with [`ProcessError::InvariantViolation`], and the details are
+//! logged and kept for [`Engine::invariant_violations`]. None of this should ever happen;
+//! the checks are there to catch engine bugs in tests and staging before they corrupt
+//! balances.
+//!
+//! [`EngineConfig::with_invariant_checks`]: crate::EngineConfig::with_invariant_checks
+//! [`LockedPolicy::AllowCreditsOnly`]: crate::LockedPolicy::AllowCreditsOnly
+//! [`ProcessError::InvariantViolation`]: crate::ProcessError::InvariantViolation
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::config::LockedPolicy;
+use crate::engine::Engine;
+use crate::types::{Account, ClientId, DisputeState, Money, Transaction, TransactionType};
+
+/// A broken engine invariant, found by [`Engine::check_invariants`] or by the checks after
+/// a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// `available + held + reserved + on_hold` doesn't fit in an `i64`.
+    TotalOverflow {
+        client: ClientId,
+    },
+    NegativeHeld {
+        client: ClientId,
+        held: Money,
+    },
+    NegativeReserved {
+        client: ClientId,
+        reserved: Money,
+    },
+    NegativeOnHold {
+        client: ClientId,
+        on_hold: Money,
+    },
+    /// The held funds differ from the sum of the client's transactions under dispute.
+    HeldMismatch {
+        client: ClientId,
+        held: Money,
+        disputed: Money,
+    },
+    /// The funds on hold differ from what is left on the client's holds.
+    OnHoldMismatch {
+        client: ClientId,
+        on_hold: Money,
+        holds: Money,
+    },
+    /// A `tx_type` moved the balances of a locked account.
+    LockedAccountChanged {
+        client: ClientId,
+        tx_type: TransactionType,
+    },
+    /// A `tx_type` locked or unlocked an account.
+    LockChanged {
+        client: ClientId,
+        tx_type: TransactionType,
+        locked: bool,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::TotalOverflow { client } => {
+                write!(f, "client {}: total balance overflows", client)
+            }
+            InvariantViolation::NegativeHeld { client, held } => {
+                write!(f, "client {}: held is negative ({})", client, held)
+            }
+            InvariantViolation::NegativeReserved { client, reserved } => {
+                write!(f, "client {}: reserved is negative ({})", client, reserved)
+            }
+            InvariantViolation::NegativeOnHold { client, on_hold } => {
+                write!(f, "client {}: on hold is negative ({})", client, on_hold)
+            }
+            InvariantViolation::HeldMismatch {
+                client,
+                held,
+                disputed,
+            } => write!(
+                f,
+                "client {}: held is {} but disputed transactions add up to {}",
+                client, held, disputed
+            ),
+            InvariantViolation::OnHoldMismatch {
+                client,
+                on_hold,
+                holds,
+            } => write!(
+                f,
+                "client {}: on hold is {} but its holds add up to {}",
+                client, on_hold, holds
+            ),
+            InvariantViolation::LockedAccountChanged { client, tx_type } => write!(
+                f,
+                "client {}: locked account changed by a {}",
+                client, tx_type
+            ),
+            InvariantViolation::LockChanged {
+                client,
+                tx_type,
+                locked,
+            } => write!(
+                f,
+                "client {}: account {} by a {}",
+                client,
+                if *locked { "locked" } else { "unlocked" },
+                tx_type
+            ),
+        }
+    }
+}
+
+impl Error for InvariantViolation {}
+
+/// A transaction rejected because it broke an invariant, with the account it broke it on
+/// as it was before and would have been after.
+#[derive(Debug, Clone)]
+pub struct TransactionViolation {
+    pub tx: Transaction,
+    pub violation: InvariantViolation,
+    pub before: Account,
+    pub after: Account,
+}
+
+impl fmt::Display for TransactionViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let balances = |account: &Account| {
+            format!(
+                "available {}, held {}, reserved {}, on hold {}{}",
+                account.available,
+                account.held,
+                account.reserved,
+                account.on_hold,
+                if account.locked { ", locked" } else { "" }
+            )
+        };
+        write!(
+            f,
+            "{} {} of client {} rejected, {} (before: {}; after: {})",
+            self.tx.tx_type,
+            self.tx.tx,
+            self.tx.client,
+            self.violation,
+            balances(&self.before),
+            balances(&self.after)
+        )
+    }
+}
+
+impl Error for TransactionViolation {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.violation)
+    }
+}
+
+/// Checks one account's books. Unless `clamped`, held, reserved and on-hold funds must not
+/// be negative; the total must fit either way.
+pub(crate) fn check_account(
+    client: ClientId,
+    account: &Account,
+    clamped: bool,
+) -> Result<(), InvariantViolation> {
+    let total = account
+        .available
+        .checked_add(account.held)
+        .and_then(|sum| sum.checked_add(account.reserved))
+        .and_then(|sum| sum.checked_add(account.on_hold));
+    if total.is_none() {
+        return Err(InvariantViolation::TotalOverflow { client });
+    }
+    if clamped {
+        return Ok(());
+    }
+    if account.held.is_negative() {
+        return Err(InvariantViolation::NegativeHeld {
+            client,
+            held: account.held,
+        });
+    }
+    if account.reserved.is_negative() {
+        return Err(InvariantViolation::NegativeReserved {
+            client,
+            reserved: account.reserved,
+        });
+    }
+    if account.on_hold.is_negative() {
+        return Err(InvariantViolation::NegativeOnHold {
+            client,
+            on_hold: account.on_hold,
+        });
+    }
+    Ok(())
+}
+
+/// Checks what accepted transaction `tx` did to `client`'s account, from `before` to
+/// `after`, under the engine's `locked` policy.
+pub(crate) fn check_change(
+    tx: &Transaction,
+    client: ClientId,
+    before: &Account,
+    after: &Account,
+    clamped: bool,
+    locked: LockedPolicy,
+) -> Result<(), InvariantViolation> {
+    check_account(client, after, clamped)?;
+    let tx_type = tx.tx_type;
+    // A withdrawal fee is credited to the fee account even while it is locked.
+    let debited = matches!(
+        tx_type,
+        TransactionType::Withdrawal | TransactionType::Transfer
+    ) && client == tx.client;
+    let credited = match tx_type {
+        TransactionType::Deposit => client == tx.client,
+        TransactionType::Transfer => tx.to_client == Some(client),
+        _ => false,
+    };
+    let moves_own_funds = debited || (credited && !locked.accepts_credits());
+    let balances = |account: &Account| {
+        (
+            account.available,
+            account.held,
+            account.reserved,
+            account.on_hold,
+        )
+    };
+    if before.locked && moves_own_funds && balances(before) != balances(after) {
+        return Err(InvariantViolation::LockedAccountChanged { client, tx_type });
+    }
+    let allowed = if after.locked {
+        TransactionType::Chargeback
+    } else {
+        TransactionType::ChargebackReversal
+    };
+    if before.locked != after.locked && tx_type != allowed {
+        return Err(InvariantViolation::LockChanged {
+            client,
+            tx_type,
+            locked: after.locked,
+        });
+    }
+    Ok(())
+}
+
+impl Engine {
+    /// Checks the books of every account in memory: the total is representable, and unless
+    /// an update was ever clamped ([`Engine::saturation_count`]), held, reserved and on-hold
+    /// funds aren't negative, held funds equal the amounts under dispute on that account and
+    /// funds on hold equal what is left on its holds. Archived accounts are not checked.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let clamped = self.saturation_count() > 0;
+        let mut disputed: HashMap<ClientId, Money> = HashMap::new();
+        let mut holds: HashMap<ClientId, Money> = HashMap::new();
+        if !clamped {
+            for (tx, stored) in self.transactions() {
+                if stored.dispute_state == DisputeState::Disputed {
+                    let sum = disputed.entry(stored.credited()).or_default();
+                    *sum = sum.saturating_add(self.held_by(tx, &stored));
+                }
+            }
+            for (_, hold) in self.holds() {
+                let sum = holds.entry(hold.client).or_default();
+                *sum = sum.saturating_add(hold.remaining);
+            }
+        }
+        for (client, account) in self.accounts() {
+            check_account(client, account, clamped)?;
+            if clamped {
+                continue;
+            }
+            let expected = disputed.get(&client).copied().unwrap_or_default();
+            if account.held != expected {
+                return Err(InvariantViolation::HeldMismatch {
+                    client,
+                    held: account.held,
+                    disputed: expected,
+                });
+            }
+            let expected = holds.get(&client).copied().unwrap_or_default();
+            if account.on_hold != expected {
+                return Err(InvariantViolation::OnHoldMismatch {
+                    client,
+                    on_hold: account.on_hold,
+                    holds: expected,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EngineConfig;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_check_change() {
+        let tx = |tx_type, client| Transaction {
+            tx_type,
+            client,
+            tx: 9,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        };
+        let open = Account {
+            available: Money::from_units(10),
+            ..Account::default()
+        };
+        let locked = Account {
+            locked: true,
+            ..open
+        };
+        let moved = Account {
+            available: Money::from_units(4),
+            ..locked
+        };
+        let policy = LockedPolicy::default();
+        let deposit = tx(TransactionType::Deposit, 1);
+        assert_eq!(
+            check_change(&deposit, 1, &locked, &moved, false, policy),
+            Err(InvariantViolation::LockedAccountChanged {
+                client: 1,
+                tx_type: TransactionType::Deposit
+            })
+        );
+        // The fee account of a withdrawal is credited even when locked.
+        let withdrawal = tx(TransactionType::Withdrawal, 2);
+        assert_eq!(
+            check_change(&withdrawal, 1, &locked, &moved, false, policy),
+            Ok(())
+        );
+        let dispute = tx(TransactionType::Dispute, 1);
+        assert_eq!(
+            check_change(&dispute, 1, &locked, &moved, false, policy),
+            Ok(())
+        );
+        assert_eq!(
+            check_change(&dispute, 1, &open, &locked, false, policy),
+            Err(InvariantViolation::LockChanged {
+                client: 1,
+                tx_type: TransactionType::Dispute,
+                locked: true
+            })
+        );
+        let chargeback = tx(TransactionType::Chargeback, 1);
+        assert_eq!(
+            check_change(&chargeback, 1, &open, &locked, false, policy),
+            Ok(())
+        );
+        let negative = Account {
+            held: Money::from_units(-1),
+            ..open
+        };
+        assert_eq!(
+            check_change(&dispute, 1, &open, &negative, false, policy),
+            Err(InvariantViolation::NegativeHeld {
+                client: 1,
+                held: Money::from_units(-1)
+            })
+        );
+        assert_eq!(
+            check_change(&dispute, 1, &open, &negative, true, policy),
+            Ok(())
+        );
+        let overflowing = Account {
+            available: Money::MAX,
+            held: Money::from_units(1),
+            ..open
+        };
+        assert_eq!(
+            check_change(&dispute, 1, &open, &overflowing, true, policy),
+            Err(InvariantViolation::TotalOverflow { client: 1 })
+        );
+    }
+
+    #[test]
+    fn test_check_change_allow_credits() {
+        let policy = LockedPolicy::AllowCreditsOnly;
+        let tx = |tx_type, client, to_client| Transaction {
+            tx_type,
+            client,
+            tx: 9,
+            amount: Some(dec!(1)),
+            timestamp: None,
+            to_client,
+            metadata: Default::default(),
+        };
+        let locked = Account {
+            available: Money::from_units(10),
+            locked: true,
+            ..Account::default()
+        };
+        let credited = Account {
+            available: Money::from_units(20),
+            ..locked
+        };
+        let debited = Account {
+            available: Money::from_units(4),
+            ..locked
+        };
+        let deposit = tx(TransactionType::Deposit, 1, None);
+        assert_eq!(
+            check_change(&deposit, 1, &locked, &credited, false, policy),
+            Ok(())
+        );
+        let incoming = tx(TransactionType::Transfer, 2, Some(1));
+        assert_eq!(
+            check_change(&incoming, 1, &locked, &credited, false, policy),
+            Ok(())
+        );
+        // Debits are still flagged.
+        let withdrawal = tx(TransactionType::Withdrawal, 1, None);
+        assert_eq!(
+            check_change(&withdrawal, 1, &locked, &debited, false, policy),
+            Err(InvariantViolation::LockedAccountChanged {
+                client: 1,
+                tx_type: TransactionType::Withdrawal
+            })
+        );
+        let outgoing = tx(TransactionType::Transfer, 1, Some(2));
+        assert_eq!(
+            check_change(&outgoing, 1, &locked, &debited, false, policy),
+            Err(InvariantViolation::LockedAccountChanged {
+                client: 1,
+                tx_type: TransactionType::Transfer
+            })
+        );
+
+        // A checked engine accepts the credits the policy allows.
+        let mut engine = Engine::with_config(
+            EngineConfig::default()
+                .with_locked_policy(policy)
+                .with_invariant_checks(),
+        );
+        let op = |tx_type, client, tx, amount, to_client| Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp: None,
+            to_client,
+            metadata: Default::default(),
+        };
+        engine
+            .process(op(TransactionType::Deposit, 1, 1, Some(dec!(5)), None))
+            .unwrap();
+        engine
+            .process(op(TransactionType::Deposit, 2, 2, Some(dec!(5)), None))
+            .unwrap();
+        engine
+            .process(op(TransactionType::Dispute, 1, 1, None, None))
+            .unwrap();
+        engine
+            .process(op(TransactionType::Chargeback, 1, 1, None, None))
+            .unwrap();
+        engine
+            .process(op(TransactionType::Deposit, 1, 3, Some(dec!(2)), None))
+            .unwrap();
+        engine
+            .process(op(TransactionType::Transfer, 2, 4, Some(dec!(1)), Some(1)))
+            .unwrap();
+        assert_eq!(
+            engine.process(op(TransactionType::Withdrawal, 1, 5, Some(dec!(1)), None)),
+            Err(crate::ProcessError::AccountLocked)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.locked),
+            (Money::from_units(30_000), true)
+        );
+        assert!(engine.invariant_violations().is_empty());
+    }
+
+    #[test]
+    fn test_checked_engine_rejects_violations() {
+        let mut engine = Engine::with_config(EngineConfig::default().with_invariant_checks());
+        let tx = |tx_type, tx, amount| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        };
+        engine.begin_batch();
+        engine
+            .process(tx(TransactionType::Deposit, 1, Some(dec!(1))))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Dispute, 1, None))
+            .unwrap();
+        engine.commit_batch();
+
+        // Saturating arithmetic lets the deposit through, but the total would overflow.
+        let huge = tx(TransactionType::Deposit, 2, Some(dec!(922337203685477)));
+        assert_eq!(
+            engine.process(huge),
+            Err(crate::ProcessError::InvariantViolation)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Money::ZERO, Money::from_units(10_000))
+        );
+        assert!(engine.transaction(2).is_none());
+        let [violation] = engine.invariant_violations() else {
+            panic!("expected one violation");
+        };
+        assert_eq!(violation.tx.tx, 2);
+        assert_eq!(
+            violation.violation,
+            InvariantViolation::TotalOverflow { client: 1 }
+        );
+        assert_eq!(
+            violation.after.available,
+            Money::from_units(9_223_372_036_854_770_000)
+        );
+
+        engine
+            .process(tx(TransactionType::Chargeback, 1, None))
+            .unwrap();
+        assert!(engine.account(1).unwrap().locked);
+        assert_eq!(engine.check_invariants(), Ok(()));
+    }
+}
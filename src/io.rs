@@ -0,0 +1,1668 @@
+//! Streaming CSV input and output over any [`Read`] or [`Write`].
+//!
+//! [`CsvReader`] turns rows with the columns `type,client,tx,amount[,timestamp][,to_client]`
+//! into [`Input`] items one at a time, recognising the `begin`/`commit` batch markers, so
+//! files of any size can be processed from a file, a socket or a byte slice.
+//! [`Engine::process_csv`] runs a whole stream through the engine and
+//! [`Engine::write_accounts_csv`] writes the account report; the command-line tool is built
+//! on the same pieces. [`ErrorPolicy`] and [`ErrorLog`] are how it deals with malformed
+//! rows, and [`InputLimits`] bounds what untrusted input may make it hold.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::str::{self, FromStr};
+use std::sync::mpsc;
+use std::thread;
+use std::{iter, mem};
+
+use csv::{ByteRecord, ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::admin::AdminOp;
+use crate::cases;
+use crate::engine::Engine;
+use crate::filter::ClientFilter;
+use crate::ignored::IgnoredCounts;
+use crate::outcome::ProcessError;
+use crate::schema::{self, ReportOptions};
+use crate::types::{
+    ClientId, DEFAULT_SCALE, Metadata, MinorUnitsTransaction, Money, Transaction, TransactionType,
+};
+
+/// Columns the reader interprets; any others are metadata.
+const COLUMNS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "to_client",
+    "reason",
+    "value_date",
+];
+
+/// Rows per chunk handed from the parser thread to the engine in
+/// [`CsvReader::process_parallel`].
+const PIPELINE_CHUNK: usize = 1024;
+/// Chunks parsed ahead of the engine before the parser thread waits.
+const PIPELINE_CHUNKS: usize = 16;
+/// Rows between two calls of the callback of [`CsvReader::process_with_progress`].
+const PROGRESS_ROWS: u64 = 100_000;
+
+/// One item of input: a transaction, or a batch boundary marker.
+#[derive(Debug, Clone)]
+pub enum Input {
+    Transaction(Transaction),
+    /// A row of type `begin`: the transactions up to the next `commit` are one batch.
+    BeginBatch,
+    /// A row of type `commit`, closing the open batch.
+    CommitBatch,
+    /// A row of type `lock`, `unlock`, `adjust`, `close` or `overdraft`, read only when admin
+    /// rows are enabled.
+    Admin(AdminOp),
+    /// A row of type `accrue`: interest accrues up to its `timestamp`.
+    Accrue {
+        timestamp: u64,
+    },
+    /// A transaction row with a `value_date`, applied once the engine clock reaches it; see
+    /// [`Engine::schedule`].
+    Scheduled {
+        tx: Transaction,
+        value_date: u64,
+    },
+    /// A row of type `settle_until`: the engine clock advances to its `timestamp`, settling
+    /// the post-dated transactions due by then.
+    SettleUntil {
+        timestamp: u64,
+    },
+}
+
+impl From<Transaction> for Input {
+    fn from(tx: Transaction) -> Self {
+        Input::Transaction(tx)
+    }
+}
+
+impl Input {
+    /// Whether `clients` keeps this input: transactions and admin operations involving one
+    /// of them, and every batch marker, interest accrual and clock advance.
+    pub fn selected_by(&self, clients: &ClientFilter) -> bool {
+        match self {
+            Input::Transaction(tx) | Input::Scheduled { tx, .. } => clients.selects(tx),
+            Input::Admin(op) => clients.contains(op.client()),
+            Input::BeginBatch
+            | Input::CommitBatch
+            | Input::Accrue { .. }
+            | Input::SettleUntil { .. } => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CsvError {
+    /// The input could not be read or is not valid CSV.
+    Csv(csv::Error),
+    /// The row at `line` does not describe a transaction.
+    Malformed {
+        line: u64,
+        fields: Vec<String>,
+        error: csv::Error,
+    },
+    /// A `begin` inside an open batch, a `commit` outside one, or input ending inside a
+    /// batch.
+    Batch { line: u64, message: &'static str },
+    /// The row at `line` goes over one of the reader's [`InputLimits`]. Reading can carry on
+    /// without it, except after [`InputLimit::RowBytes`], which ends the input.
+    LimitExceeded { line: u64, limit: InputLimit },
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsvError::Csv(error) => write!(f, "{}", error),
+            CsvError::Malformed {
+                line,
+                fields,
+                error,
+            } => write!(
+                f,
+                "line {}: malformed record [{}]: {}",
+                line,
+                fields.join(", "),
+                error
+            ),
+            CsvError::Batch { line, message } => write!(f, "line {}: {}", line, message),
+            CsvError::LimitExceeded { line, limit } => {
+                write!(f, "line {}: limit exceeded: {}", line, limit)
+            }
+        }
+    }
+}
+
+impl Error for CsvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CsvError::Csv(error) | CsvError::Malformed { error, .. } => Some(error),
+            CsvError::Batch { .. } | CsvError::LimitExceeded { .. } => None,
+        }
+    }
+}
+
+impl From<csv::Error> for CsvError {
+    fn from(error: csv::Error) -> Self {
+        CsvError::Csv(error)
+    }
+}
+
+/// Reads transactions and batch markers from CSV. Fields are trimmed and rows may omit
+/// trailing optional columns.
+pub struct CsvReader<R> {
+    headers: StringRecord,
+    reader: csv::Reader<LineGuard<R>>,
+    type_column: Option<usize>,
+    columns: Columns,
+    /// Parse rows by hand when possible; see [`CsvReader::with_fast`].
+    fast: bool,
+    /// Reused by the fast path, so reading a row allocates nothing.
+    bytes: ByteRecord,
+    minor_units: bool,
+    /// Decimal places of minor units and adjustment amounts.
+    scale: u32,
+    admin: bool,
+    /// Columns kept as transaction metadata; see [`CsvReader::with_metadata`].
+    metadata: Vec<usize>,
+    /// Rows of other clients are skipped; see [`CsvReader::with_clients`].
+    clients: Option<ClientFilter>,
+    limits: InputLimits,
+    /// Clients seen so far, kept while `max_clients` is set.
+    seen: HashSet<ClientId>,
+    /// Deposit, withdrawal and transfer rows read so far.
+    transactions: u64,
+    /// Set once a row over `max_row_bytes` is reported; nothing more is read.
+    ended: bool,
+    line: u64,
+}
+
+/// Columns of a `lock`, `unlock` or `close` row.
+#[derive(Deserialize)]
+struct AccountRow {
+    client: ClientId,
+    reason: Option<String>,
+}
+
+/// Columns of an `accrue` or `settle_until` row.
+#[derive(Deserialize)]
+struct AccrueRow {
+    timestamp: u64,
+}
+
+/// The value date of a transaction row, if it has one.
+#[derive(Deserialize)]
+struct ValueDateRow {
+    #[serde(default)]
+    value_date: Option<u64>,
+}
+
+/// Columns of an `adjust` or `overdraft` row, with the amount as a decimal or in minor units.
+#[derive(Deserialize)]
+struct AdjustRow<A> {
+    client: ClientId,
+    amount: A,
+    reason: Option<String>,
+}
+
+impl<R: Read> CsvReader<R> {
+    /// Reads the header row; fails if the input can't be read.
+    pub fn new(reader: R) -> Result<Self, CsvError> {
+        Self::with_columns(reader, &ColumnMap::default())
+    }
+
+    /// Like [`CsvReader::new`], but reads the columns under the names `columns` gives them,
+    /// and takes them from `columns` instead of a header row if the input has none.
+    pub fn with_columns(reader: R, columns: &ColumnMap) -> Result<Self, CsvError> {
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .has_headers(columns.positions.is_none())
+            .from_reader(LineGuard::new(reader, columns.positions.is_none()));
+        let headers: StringRecord = match &columns.positions {
+            Some(names) => names.iter().map(|name| columns.column(name)).collect(),
+            None => reader
+                .headers()?
+                .iter()
+                .map(|h| columns.column(h))
+                .collect(),
+        };
+        reader.get_mut().one_line = false;
+        let line = if columns.positions.is_some() { 0 } else { 1 };
+        let column = |name| headers.iter().position(|header| header == name);
+        Ok(Self {
+            type_column: column("type"),
+            columns: Columns {
+                client: column("client"),
+                tx: column("tx"),
+                amount: column("amount"),
+                timestamp: column("timestamp"),
+                to_client: column("to_client"),
+                reason: column("reason"),
+                value_date: column("value_date"),
+            },
+            headers,
+            reader,
+            fast: false,
+            bytes: ByteRecord::new(),
+            minor_units: false,
+            scale: DEFAULT_SCALE,
+            admin: false,
+            metadata: Vec::new(),
+            clients: None,
+            limits: InputLimits::default(),
+            seen: HashSet::new(),
+            transactions: 0,
+            ended: false,
+            line,
+        })
+    }
+
+    /// Read amounts as integers in minor units (`123456` is `12.3456`).
+    pub fn with_minor_units(mut self, minor_units: bool) -> Self {
+        self.minor_units = minor_units;
+        self
+    }
+
+    /// Read minor units, and convert adjustment amounts, with `scale` decimal places
+    /// instead of 4, to match an engine configured with `EngineConfig::with_scale`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Read rows of type `lock`, `unlock`, `adjust`, `close` and `overdraft` (columns
+    /// `client`, `amount` for adjustments and overdraft limits, and an optional `reason`) as
+    /// [`Input::Admin`]. Otherwise they are malformed.
+    pub fn with_admin(mut self, admin: bool) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    /// Keep the non-empty values of columns the reader doesn't interpret, such as a payment
+    /// `reference`, as each transaction's [`metadata`](Transaction::metadata).
+    pub fn with_metadata(mut self, metadata: bool) -> Self {
+        self.metadata = if metadata {
+            (0..self.headers.len())
+                .filter(|&column| !COLUMNS.contains(&&self.headers[column]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self
+    }
+
+    /// Parse transaction rows straight from their bytes, without serde and without going
+    /// through strings. Rows the fast path doesn't recognise (batch markers, admin rows,
+    /// unusual number formats, malformed rows) fall back to the normal parser, so the
+    /// transactions and errors are the same either way, except that invalid UTF-8 is
+    /// reported as an I/O error instead of a CSV one.
+    pub fn with_fast(mut self, fast: bool) -> Self {
+        self.fast = fast;
+        self
+    }
+
+    /// Skip transaction and admin rows that involve none of `clients`; see
+    /// [`crate::filter`]. Batch markers and `accrue` rows are kept.
+    pub fn with_clients(mut self, clients: ClientFilter) -> Self {
+        self.clients = Some(clients);
+        self
+    }
+
+    /// Fail rows that go over `limits` with [`CsvError::LimitExceeded`]; see
+    /// [`InputLimits`]. Set before reading any row.
+    pub fn with_limits(mut self, limits: InputLimits) -> Self {
+        self.reader.get_mut().max = limits.max_row_bytes;
+        self.limits = limits;
+        self
+    }
+
+    /// Line of the most recently read row.
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    /// Bytes of input read up to the end of the most recently read row.
+    pub fn bytes(&self) -> u64 {
+        self.reader.position().byte()
+    }
+
+    /// Feeds every row to `engine`, handling batch markers the way the command-line tool
+    /// does: a rejection inside a batch rolls the batch back and the rest of it is skipped.
+    /// Stops at the first malformed row.
+    pub fn process(self, engine: &mut Engine) -> Result<ProcessSummary, CsvError> {
+        self.process_with_progress(engine, |_| {})
+    }
+
+    /// Like [`CsvReader::process`], calling `progress` every 100,000 rows and once more when
+    /// the input ends, so a long run can report how far it got.
+    pub fn process_with_progress(
+        mut self,
+        engine: &mut Engine,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<ProcessSummary, CsvError> {
+        let inputs = iter::from_fn(|| {
+            let input = self.next()?;
+            Some((self.line(), self.bytes(), input))
+        });
+        apply(inputs, engine, &mut progress)
+    }
+
+    /// Like [`CsvReader::process`], but parses on a thread of its own while the calling
+    /// thread applies the transactions, so the two overlap. Parsed rows are handed over in
+    /// chunks through a bounded channel; when the engine falls behind, the parser waits.
+    /// The result is the same as from [`CsvReader::process`].
+    pub fn process_parallel(self, engine: &mut Engine) -> Result<ProcessSummary, CsvError>
+    where
+        R: Send,
+    {
+        let (sender, receiver) =
+            mpsc::sync_channel::<Vec<(u64, u64, Result<Input, CsvError>)>>(PIPELINE_CHUNKS);
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                let mut reader = self;
+                let mut chunk = Vec::with_capacity(PIPELINE_CHUNK);
+                while let Some(input) = reader.next() {
+                    let failed = input.is_err();
+                    chunk.push((reader.line(), reader.bytes(), input));
+                    if failed || chunk.len() == PIPELINE_CHUNK {
+                        let full = mem::replace(&mut chunk, Vec::with_capacity(PIPELINE_CHUNK));
+                        // The engine side stops listening at the first error.
+                        if sender.send(full).is_err() || failed {
+                            return;
+                        }
+                    }
+                }
+                let _ = sender.send(chunk);
+            });
+            apply(receiver.into_iter().flatten(), engine, &mut |_| {})
+        })
+    }
+}
+
+/// Applies `inputs`, each with the line it was read from and the bytes read up to its end;
+/// the body of [`CsvReader::process_with_progress`].
+fn apply(
+    inputs: impl Iterator<Item = (u64, u64, Result<Input, CsvError>)>,
+    engine: &mut Engine,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<ProcessSummary, CsvError> {
+    let mut summary = ProcessSummary::default();
+    let mut batch = None;
+    // The header row, until a row is read.
+    let mut last_line = 1;
+    let mut done = Progress::default();
+    for (line, bytes, input) in inputs {
+        last_line = line;
+        if done.rows > 0 && done.rows % PROGRESS_ROWS == 0 {
+            progress(done);
+        }
+        done.rows += 1;
+        done.bytes = bytes;
+        match (input?, &mut batch) {
+            (Input::BeginBatch, Some(_)) => {
+                let message = "batch begun inside an open batch";
+                return Err(CsvError::Batch { line, message });
+            }
+            (Input::BeginBatch, None) => {
+                engine.begin_batch();
+                batch = Some(Batch::Open(0));
+            }
+            (Input::CommitBatch, None) => {
+                let message = "batch commit without a begin";
+                return Err(CsvError::Batch { line, message });
+            }
+            (Input::CommitBatch, Some(state)) => {
+                if let Batch::Open(applied) = *state {
+                    engine.commit_batch();
+                    summary.applied += applied;
+                }
+                batch = None;
+            }
+            (
+                Input::Transaction(_)
+                | Input::Admin(_)
+                | Input::Accrue { .. }
+                | Input::Scheduled { .. }
+                | Input::SettleUntil { .. },
+                Some(Batch::RolledBack),
+            ) => summary.skipped += 1,
+            (Input::Transaction(tx), state) => {
+                let result = engine.process(tx).map(|_| ());
+                tally(engine, result, state, &mut summary);
+            }
+            (Input::Scheduled { tx, value_date }, state) => {
+                let result = engine.schedule(tx, value_date);
+                tally(engine, result, state, &mut summary);
+            }
+            (Input::SettleUntil { timestamp }, _) => engine.advance_time(timestamp),
+            (Input::Admin(op), state) => {
+                let result = engine.apply_admin(op);
+                tally(engine, result, state, &mut summary);
+            }
+            (Input::Accrue { timestamp }, _) => {
+                engine.accrue_interest(timestamp);
+            }
+        }
+        done.rejected = summary.ignored.total();
+    }
+    progress(done);
+    if batch.is_some() {
+        engine.rollback_batch();
+        let message = "input ended inside a batch";
+        return Err(CsvError::Batch {
+            line: last_line,
+            message,
+        });
+    }
+    Ok(summary)
+}
+
+/// Counts one processed row, rolling back the open batch if it was rejected.
+fn tally(
+    engine: &mut Engine,
+    result: Result<(), ProcessError>,
+    batch: &mut Option<Batch>,
+    summary: &mut ProcessSummary,
+) {
+    match (result, batch) {
+        (Ok(()), Some(Batch::Open(applied))) => *applied += 1,
+        (Ok(()), _) => summary.applied += 1,
+        (Err(error), batch) => {
+            summary.ignored.record(error);
+            if let Some(batch) = batch {
+                engine.rollback_batch();
+                *batch = Batch::RolledBack;
+                summary.rolled_back += 1;
+            }
+        }
+    }
+}
+
+/// State of the open batch while processing.
+enum Batch {
+    /// Number of transactions applied so far, kept if the batch commits.
+    Open(u64),
+    /// A member was rejected; the rest is skipped.
+    RolledBack,
+}
+
+impl<R: Read> Iterator for CsvReader<R> {
+    type Item = Result<Input, CsvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let input = self.next_row()?;
+            match (&self.clients, &input) {
+                (Some(clients), Ok(input)) if !input.selected_by(clients) => continue,
+                _ => return Some(input.and_then(|input| self.check_limits(input))),
+            }
+        }
+    }
+}
+
+impl<R: Read> CsvReader<R> {
+    fn next_row(&mut self) -> Option<Result<Input, CsvError>> {
+        if self.ended {
+            return None;
+        }
+        if self.fast {
+            return self.next_fast();
+        }
+        let mut record = StringRecord::new();
+        match self.reader.read_record(&mut record) {
+            Ok(true) => Some(self.parse(record)),
+            Ok(false) => None,
+            Err(error) => Some(Err(self.read_error(error))),
+        }
+    }
+
+    /// The error for a failed read: the input's own, or the overlong row that stopped it.
+    fn read_error(&mut self, error: csv::Error) -> CsvError {
+        let guard = self.reader.get_ref();
+        match guard.max {
+            Some(max) if guard.exceeded => {
+                self.ended = true;
+                CsvError::LimitExceeded {
+                    line: guard.lines + 1,
+                    limit: InputLimit::RowBytes(max),
+                }
+            }
+            _ => error.into(),
+        }
+    }
+
+    /// Counts `input` against the reader's limits, failing it if it goes over one.
+    fn check_limits(&mut self, input: Input) -> Result<Input, CsvError> {
+        let (clients, tx) = match &input {
+            Input::Transaction(tx) | Input::Scheduled { tx, .. } => {
+                ([Some(tx.client), tx.to_client], Some(tx))
+            }
+            Input::Admin(op) => ([Some(op.client()), None], None),
+            _ => return Ok(input),
+        };
+        let limits = self.limits;
+        let exceeded = |limit| CsvError::LimitExceeded {
+            line: self.line,
+            limit,
+        };
+        if let (Some(max), Some(amount)) = (limits.max_amount, tx.and_then(|tx| tx.amount))
+            && amount.abs() > max
+        {
+            return Err(exceeded(InputLimit::Amount(max)));
+        }
+        let stored = tx.is_some_and(|tx| {
+            matches!(
+                tx.tx_type,
+                TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+            )
+        });
+        if let Some(max) = limits.max_transactions
+            && stored
+            && self.transactions >= max
+        {
+            return Err(exceeded(InputLimit::Transactions(max)));
+        }
+        let mut new: Vec<ClientId> = Vec::new();
+        if let Some(max) = limits.max_clients {
+            new = clients
+                .into_iter()
+                .flatten()
+                .filter(|client| !self.seen.contains(client))
+                .collect();
+            new.dedup();
+            if self.seen.len() + new.len() > max {
+                return Err(exceeded(InputLimit::Clients(max)));
+            }
+        }
+        self.seen.extend(new);
+        self.transactions += u64::from(stored);
+        Ok(input)
+    }
+
+    /// Reads the next row into the reused byte buffer, parsing it by hand if it is a plain
+    /// transaction row and with [`CsvReader::parse`] otherwise.
+    fn next_fast(&mut self) -> Option<Result<Input, CsvError>> {
+        match self.reader.read_byte_record(&mut self.bytes) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(error) => return Some(Err(self.read_error(error))),
+        }
+        self.line = self.bytes.position().map_or(self.line + 1, |p| p.line());
+        if let Some(tx) = self.parse_bytes() {
+            return Some(Ok(Input::Transaction(tx)));
+        }
+        match StringRecord::from_byte_record(self.bytes.clone()) {
+            Ok(mut record) => {
+                // Byte records are trimmed of ASCII whitespace only.
+                record.trim();
+                Some(self.parse(record))
+            }
+            Err(error) => {
+                let error =
+                    io::Error::new(io::ErrorKind::InvalidData, error.utf8_error().to_string());
+                Some(Err(CsvError::Csv(error.into())))
+            }
+        }
+    }
+
+    /// The transaction in the current byte record, if it is one in the plain format: a
+    /// known type, integer ids and timestamp, and an amount [`parse_decimal`] takes (integer
+    /// minor units with [`CsvReader::with_minor_units`]).
+    fn parse_bytes(&self) -> Option<Transaction> {
+        let record = &self.bytes;
+        if record.len() > self.headers.len() {
+            return None;
+        }
+        let field = |column: Option<usize>| record.get(column?);
+        // Missing and empty optional fields are both `None`.
+        let optional = |column| field(column).filter(|bytes| !bytes.is_empty());
+        if optional(self.columns.value_date).is_some() {
+            return None;
+        }
+        let tx_type = parse_type(field(self.type_column)?)?;
+        let client = parse_uint(field(self.columns.client)?)?;
+        let tx = parse_uint(field(self.columns.tx)?)?;
+        let amount = match optional(self.columns.amount) {
+            None => None,
+            Some(bytes) if self.minor_units => {
+                let units = parse_signed(bytes)?;
+                Some(Decimal::try_from_i128_with_scale(units.into(), self.scale).ok()?)
+            }
+            Some(bytes) => Some(parse_decimal(bytes)?),
+        };
+        let timestamp = match optional(self.columns.timestamp) {
+            None => None,
+            Some(bytes) => Some(parse_uint(bytes)?),
+        };
+        let to_client = match optional(self.columns.to_client) {
+            None => None,
+            Some(bytes) => Some(parse_uint(bytes)?),
+        };
+        let mut metadata = Metadata::new();
+        for &column in &self.metadata {
+            if let Some(bytes) = optional(Some(column)) {
+                let value = str::from_utf8(bytes).ok()?;
+                metadata.insert(self.headers[column].to_string(), value.to_string());
+            }
+        }
+        if tx_type == TransactionType::Dispute
+            && let Some(bytes) = optional(self.columns.reason)
+        {
+            let reason = str::from_utf8(bytes).ok()?;
+            metadata.insert(cases::REASON_KEY.to_string(), reason.to_string());
+        }
+        Some(Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp,
+            to_client,
+            metadata,
+        })
+    }
+
+    /// Turns a row into an item of input with serde.
+    fn parse(&mut self, record: StringRecord) -> Result<Input, CsvError> {
+        self.line = record.position().map_or(self.line + 1, |p| p.line());
+        let marker = match self.type_column.and_then(|column| record.get(column)) {
+            Some("begin") => Some(Input::BeginBatch),
+            Some("commit") => Some(Input::CommitBatch),
+            _ => None,
+        };
+        if let Some(marker) = marker {
+            return Ok(marker);
+        }
+        let kind = self.type_column.and_then(|column| record.get(column));
+        if let (true, Some(kind @ ("lock" | "unlock" | "adjust" | "close" | "overdraft"))) =
+            (self.admin, kind)
+        {
+            return self.admin_op(kind, &record);
+        }
+        if let Some(kind @ ("accrue" | "settle_until")) = kind {
+            return record
+                .deserialize::<AccrueRow>(Some(&self.headers))
+                .map(|row| match kind {
+                    "accrue" => Input::Accrue {
+                        timestamp: row.timestamp,
+                    },
+                    _ => Input::SettleUntil {
+                        timestamp: row.timestamp,
+                    },
+                })
+                .map_err(|error| CsvError::Malformed {
+                    line: self.line,
+                    fields: record.iter().map(str::to_string).collect(),
+                    error,
+                });
+        }
+        let parsed = if self.minor_units {
+            record
+                .deserialize::<MinorUnitsTransaction>(Some(&self.headers))
+                .map(|row| row.into_transaction(self.scale))
+        } else {
+            record.deserialize(Some(&self.headers))
+        };
+        let value_date = match self.columns.value_date {
+            Some(_) => record
+                .deserialize::<ValueDateRow>(Some(&self.headers))
+                .map(|row| row.value_date),
+            None => Ok(None),
+        };
+        parsed
+            .and_then(|tx| Ok((tx, value_date?)))
+            .map(|(tx, value_date)| {
+                let tx = Transaction {
+                    metadata: self.row_metadata(tx.tx_type, &record),
+                    ..tx
+                };
+                match value_date {
+                    Some(value_date) => Input::Scheduled { tx, value_date },
+                    None => Input::Transaction(tx),
+                }
+            })
+            .map_err(|error| CsvError::Malformed {
+                line: self.line,
+                fields: record.iter().map(str::to_string).collect(),
+                error,
+            })
+    }
+}
+
+/// Positions of the transaction columns other than `type`.
+struct Columns {
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    to_client: Option<usize>,
+    /// Reason code of a dispute, kept as its metadata; see [`crate::cases`].
+    reason: Option<usize>,
+    value_date: Option<usize>,
+}
+
+/// A transaction type written exactly as serde expects it.
+fn parse_type(bytes: &[u8]) -> Option<TransactionType> {
+    Some(match bytes {
+        b"deposit" => TransactionType::Deposit,
+        b"withdrawal" => TransactionType::Withdrawal,
+        b"dispute" => TransactionType::Dispute,
+        b"resolve" => TransactionType::Resolve,
+        b"chargeback" => TransactionType::Chargeback,
+        b"transfer" => TransactionType::Transfer,
+        b"chargeback_reversal" => TransactionType::ChargebackReversal,
+        b"hold" => TransactionType::Hold,
+        b"release" => TransactionType::Release,
+        _ => return None,
+    })
+}
+
+/// An unsigned integer of plain digits that fits in `T`.
+fn parse_uint<T: TryFrom<u64>>(bytes: &[u8]) -> Option<T> {
+    if bytes.is_empty() || bytes.len() > 19 {
+        return None;
+    }
+    let mut value = 0u64;
+    for &byte in bytes {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        value = value * 10 + u64::from(byte - b'0');
+    }
+    T::try_from(value).ok()
+}
+
+/// An `i64` of plain digits with an optional minus sign.
+fn parse_signed(bytes: &[u8]) -> Option<i64> {
+    match bytes.split_first()? {
+        (b'-', digits) => parse_uint::<i64>(digits).map(|value| -value),
+        _ => parse_uint(bytes),
+    }
+}
+
+/// A decimal of the form `[-]digits[.digits]`, as the CSV deserializer would read it. That
+/// reads integers as `i64` and anything with a point as `f64`, so only amounts that survive
+/// the round trip through `f64` unchanged (at most 15 significant digits) are taken here,
+/// and normalized as the round trip would.
+fn parse_decimal(bytes: &[u8]) -> Option<Decimal> {
+    let (negative, unsigned) = match bytes.split_first()? {
+        (b'-', rest) => (true, rest),
+        _ => (false, bytes),
+    };
+    let (whole, fraction) = match unsigned.iter().position(|&byte| byte == b'.') {
+        Some(point) => (&unsigned[..point], &unsigned[point + 1..]),
+        None => (unsigned, &[][..]),
+    };
+    let has_point = whole.len() < unsigned.len();
+    if whole.is_empty() || (has_point && fraction.is_empty()) || whole.len() + fraction.len() > 28 {
+        return None;
+    }
+    let mut mantissa = 0i128;
+    for &byte in whole.iter().chain(fraction) {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        mantissa = mantissa * 10 + i128::from(byte - b'0');
+    }
+    // `-0` keeps its sign in `Decimal`; leave it to the normal parser.
+    if negative && mantissa == 0 {
+        return None;
+    }
+    let mantissa = if negative { -mantissa } else { mantissa };
+    let amount = Decimal::try_from_i128_with_scale(mantissa, fraction.len() as u32)
+        .ok()?
+        .normalize();
+    if amount.mantissa().abs() >= 1_000_000_000_000_000 {
+        return None;
+    }
+    Some(amount)
+}
+
+/// Bounds on what a [`CsvReader`] takes from untrusted input, such as files from partners;
+/// see [`CsvReader::with_limits`]. A limit left `None` is not checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InputLimits {
+    /// Longest line, in bytes. Lines are measured as they are read, so an overlong one is
+    /// never held in memory whole; the reader stops there.
+    pub max_row_bytes: Option<usize>,
+    /// Distinct clients that transaction and admin rows may involve.
+    pub max_clients: Option<usize>,
+    /// Deposit, withdrawal and transfer rows, which the engine keeps to be disputed later.
+    pub max_transactions: Option<u64>,
+    /// Largest amount of a transaction, of either sign.
+    pub max_amount: Option<Decimal>,
+}
+
+impl InputLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == InputLimits::default()
+    }
+}
+
+/// The limit of [`InputLimits`] a row went over, with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputLimit {
+    RowBytes(usize),
+    Clients(usize),
+    Transactions(u64),
+    Amount(Decimal),
+}
+
+impl fmt::Display for InputLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputLimit::RowBytes(max) => write!(f, "row longer than {} bytes", max),
+            InputLimit::Clients(max) => write!(f, "more than {} distinct clients", max),
+            InputLimit::Transactions(max) => {
+                write!(f, "more than {} deposits, withdrawals and transfers", max)
+            }
+            InputLimit::Amount(max) => write!(f, "amount beyond {}", max),
+        }
+    }
+}
+
+/// Passes input through, failing once a line runs longer than `max` bytes, so that the
+/// CSV reader never buffers it whole.
+struct LineGuard<R> {
+    inner: BufReader<R>,
+    max: Option<usize>,
+    /// Hand over one line at a time, so nothing past the header row is read before the
+    /// limit is set.
+    one_line: bool,
+    /// Complete lines handed over.
+    lines: u64,
+    /// Bytes of the current line handed over.
+    length: usize,
+    exceeded: bool,
+}
+
+impl<R: Read> LineGuard<R> {
+    fn new(inner: R, one_line: bool) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            max: None,
+            one_line,
+            lines: 0,
+            length: 0,
+            exceeded: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LineGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let too_long = || io::Error::new(io::ErrorKind::InvalidData, "line too long");
+        if self.exceeded {
+            return Err(too_long());
+        }
+        if self.max.is_none() && !self.one_line {
+            return self.inner.read(buf);
+        }
+        let max = self.max.unwrap_or(usize::MAX);
+        let available = self.inner.fill_buf()?;
+        let mut handed = 0;
+        for &byte in available.iter().take(buf.len()) {
+            if byte == b'\n' {
+                self.lines += 1;
+                self.length = 0;
+                handed += 1;
+                if self.one_line {
+                    break;
+                }
+                continue;
+            }
+            self.length += 1;
+            if self.length > max {
+                self.exceeded = true;
+                break;
+            }
+            handed += 1;
+        }
+        buf[..handed].copy_from_slice(&available[..handed]);
+        self.inner.consume(handed);
+        // The lines before an overlong one are handed over first; the next read fails.
+        if self.exceeded && handed == 0 {
+            return Err(too_long());
+        }
+        Ok(handed)
+    }
+}
+
+/// What to do with a row that does not describe a transaction, or goes over an
+/// [`InputLimits`] bound the input can be read past.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Stop processing with an error.
+    #[default]
+    Abort,
+    /// Leave the row out and carry on.
+    Skip,
+    /// Leave the row out, record it in an [`ErrorLog`] and carry on.
+    Report,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(ErrorPolicy::Abort),
+            "skip" => Ok(ErrorPolicy::Skip),
+            "report" => Ok(ErrorPolicy::Report),
+            _ => Err(format!("unknown error policy '{}'", s)),
+        }
+    }
+}
+
+/// How the columns of a CSV input map to the ones [`CsvReader`] reads, for exports with
+/// their own header names or no header row at all. Parsed from `column=header` pairs such
+/// as `type=txn_type,client=customer,tx=txn_id,amount=value`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnMap {
+    /// Header names read as one of the reader's columns, keyed by header.
+    renames: Vec<(String, String)>,
+    /// The names of the columns of a headerless input, in order.
+    positions: Option<Vec<String>>,
+}
+
+impl ColumnMap {
+    /// Read the input as having no header row, its columns named `names` in order. Names
+    /// are renamed like headers would be; unknown ones are metadata columns.
+    pub fn with_positions<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.positions = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Whether the input is read as is.
+    pub fn is_identity(&self) -> bool {
+        self.renames.is_empty() && self.positions.is_none()
+    }
+
+    /// The column a header is read as.
+    fn column<'a>(&'a self, header: &'a str) -> &'a str {
+        self.renames
+            .iter()
+            .find(|(from, _)| from == header)
+            .map_or(header, |(_, column)| column)
+    }
+}
+
+impl FromStr for ColumnMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut renames: Vec<(String, String)> = Vec::new();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (column, header) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected column=header, got '{}'", pair))?;
+            let (column, header) = (column.trim(), header.trim());
+            if !COLUMNS.contains(&column) {
+                return Err(format!("unknown column '{}'", column));
+            }
+            if renames.iter().any(|(_, mapped)| mapped == column) {
+                return Err(format!("column '{}' is mapped twice", column));
+            }
+            renames.push((header.to_string(), column.to_string()));
+        }
+        Ok(ColumnMap {
+            renames,
+            positions: None,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorRow<'a> {
+    line: u64,
+    record: String,
+    error: &'a str,
+}
+
+/// Report of malformed rows as CSV with columns `line`, `record`, `error`. `record` is the
+/// row's fields joined with commas.
+pub struct ErrorLog<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> ErrorLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+
+    pub fn record(&mut self, line: u64, fields: &[String], error: &csv::Error) -> io::Result<()> {
+        // The deserialize error alone; its position repeats `line`.
+        let error = match error.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+            _ => error.to_string(),
+        };
+        self.write(line, fields.join(","), &error)
+    }
+
+    /// Records a row left out for going over `limit`, with an empty `record`.
+    pub fn record_limit(&mut self, line: u64, limit: InputLimit) -> io::Result<()> {
+        self.write(line, String::new(), &format!("limit exceeded: {}", limit))
+    }
+
+    fn write(&mut self, line: u64, record: String, error: &str) -> io::Result<()> {
+        self.writer.serialize(ErrorRow {
+            line,
+            record,
+            error,
+        })?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<R> CsvReader<R> {
+    fn admin_op(&self, kind: &str, record: &StringRecord) -> Result<Input, CsvError> {
+        let headers = Some(&self.headers);
+        let with_amount = |client, amount, reason: Option<String>| {
+            let reason = reason.unwrap_or_default();
+            match kind {
+                "adjust" => AdminOp::Adjust {
+                    client,
+                    amount,
+                    reason,
+                },
+                _ => AdminOp::Overdraft {
+                    client,
+                    limit: amount,
+                    reason,
+                },
+            }
+        };
+        let parsed = match (kind, self.minor_units) {
+            ("adjust" | "overdraft", false) => record
+                .deserialize::<AdjustRow<Decimal>>(headers)
+                .map(|row| {
+                    let amount = Money::from_decimal_lossy(row.amount, self.scale);
+                    with_amount(row.client, amount, row.reason)
+                }),
+            ("adjust" | "overdraft", true) => record
+                .deserialize::<AdjustRow<i64>>(headers)
+                .map(|row| with_amount(row.client, Money::from_units(row.amount), row.reason)),
+            (kind, _) => record.deserialize::<AccountRow>(headers).map(|row| {
+                let (client, reason) = (row.client, row.reason.unwrap_or_default());
+                match kind {
+                    "lock" => AdminOp::Lock { client, reason },
+                    "close" => AdminOp::Close { client, reason },
+                    _ => AdminOp::Unlock { client, reason },
+                }
+            }),
+        };
+        parsed
+            .map(Input::Admin)
+            .map_err(|error| CsvError::Malformed {
+                line: self.line,
+                fields: record.iter().map(str::to_string).collect(),
+                error,
+            })
+    }
+
+    /// The non-empty metadata columns of `record`, and its reason if it is a dispute.
+    fn row_metadata(&self, tx_type: TransactionType, record: &StringRecord) -> Metadata {
+        let reason = self
+            .columns
+            .reason
+            .filter(|_| tx_type == TransactionType::Dispute);
+        self.metadata
+            .iter()
+            .chain(reason.as_ref())
+            .filter_map(|&column| {
+                let value = record.get(column).filter(|value| !value.is_empty())?;
+                Some((self.headers[column].to_string(), value.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// How far [`CsvReader::process_with_progress`] got.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Rows read, header excluded.
+    pub rows: u64,
+    /// Bytes of input read, which compare to the size of an uncompressed file.
+    pub bytes: u64,
+    /// Transactions rejected so far, including those of rolled-back batches.
+    pub rejected: u64,
+}
+
+/// What [`Engine::process_csv`] did with its input.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProcessSummary {
+    /// Transactions (and admin operations) applied and kept; members of rolled-back
+    /// batches don't count.
+    pub applied: u64,
+    /// Rejected transactions by reason.
+    pub ignored: IgnoredCounts,
+    /// Transactions not attempted because an earlier member of their batch was rejected.
+    pub skipped: u64,
+    /// Batches rolled back.
+    pub rolled_back: u64,
+}
+
+impl Engine {
+    /// Processes every row of a CSV stream; see [`CsvReader::process`].
+    pub fn process_csv<R: Read>(&mut self, reader: R) -> Result<ProcessSummary, CsvError> {
+        CsvReader::new(reader)?.process(self)
+    }
+
+    /// Processes every row of a CSV stream, reporting its progress; see
+    /// [`CsvReader::process_with_progress`].
+    pub fn process_csv_with_progress<R: Read>(
+        &mut self,
+        reader: R,
+        progress: impl FnMut(Progress),
+    ) -> Result<ProcessSummary, CsvError> {
+        CsvReader::new(reader)?.process_with_progress(self, progress)
+    }
+
+    /// Processes every row of a CSV stream, parsing on a separate thread; see
+    /// [`CsvReader::process_parallel`].
+    pub fn process_csv_parallel<R: Read + Send>(
+        &mut self,
+        reader: R,
+    ) -> Result<ProcessSummary, CsvError> {
+        CsvReader::new(reader)?.process_parallel(self)
+    }
+
+    /// Writes the account report (`client,available,held,total,locked`) as CSV. Use
+    /// [`schema::write_report`] for the other layouts.
+    pub fn write_accounts_csv<W: Write>(&self, writer: W) -> csv::Result<()> {
+        schema::write_report(self, &ReportOptions::default(), writer).map(|_| ())
+    }
+
+    /// Writes the same report straight from [`Engine::iter_output`], without collecting or
+    /// sorting the rows first, so in no particular order. Returns the number of rows.
+    pub fn write_output<W: Write>(&self, writer: W) -> csv::Result<usize> {
+        let mut writer = csv::Writer::from_writer(writer);
+        let mut rows = 0;
+        for account in self.iter_output() {
+            writer.serialize(account)?;
+            rows += 1;
+        }
+        writer.flush()?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outcome::ProcessError;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_process_csv_and_write_accounts() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 10.0\n\
+                     withdrawal, 1, 2, 20.0\n\
+                     begin,,,\n\
+                     deposit, 2, 3, 5.0\n\
+                     withdrawal, 2, 4, 9.0\n\
+                     deposit, 2, 5, 1.0\n\
+                     commit,,,\n\
+                     dispute, 1, 1,\n";
+        let mut engine = Engine::new();
+        let summary = engine.process_csv(input.as_bytes()).unwrap();
+        assert_eq!(
+            (summary.applied, summary.skipped, summary.rolled_back),
+            (2, 1, 1)
+        );
+        assert_eq!(summary.ignored.get(ProcessError::InsufficientFunds), 2);
+
+        let mut out = Vec::new();
+        engine.write_accounts_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,0.0000,10.0000,10.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_write_output_streams_every_row() {
+        let mut engine = Engine::new();
+        engine
+            .process_csv(
+                "type,client,tx,amount
+deposit,2,1,1.5
+deposit,1,2,3.0
+"
+                .as_bytes(),
+            )
+            .unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(engine.write_output(&mut out).unwrap(), 2);
+        let text = String::from_utf8(out).unwrap();
+        let mut lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.remove(0), "client,available,held,total,locked");
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            [
+                "1,3.0000,0.0000,3.0000,false",
+                "2,1.5000,0.0000,1.5000,false"
+            ]
+        );
+        assert_eq!(engine.iter_output().count(), 2);
+    }
+
+    #[test]
+    fn test_malformed_row_and_open_batch_are_errors() {
+        let mut engine = Engine::new();
+        let error = engine
+            .process_csv("type,client,tx,amount\ndeposit,x,1,1.0\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2: malformed record [deposit, x, 1, 1.0]: CSV deserialize error: record 1 \
+             (line: 2, byte: 22): field 1: invalid digit found in string"
+        );
+
+        let error = engine
+            .process_csv("type,client,tx,amount\nbegin,,,\ndeposit,1,1,1.0\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "line 3: input ended inside a batch");
+        assert_eq!(engine.account(1), None);
+    }
+
+    #[test]
+    fn test_process_parallel_matches_process() {
+        let input = std::fs::read("examples/3k_records.csv").unwrap();
+        let mut sequential = Engine::new();
+        let expected = sequential.process_csv(input.as_slice()).unwrap();
+        let mut parallel = Engine::new();
+        let summary = parallel.process_csv_parallel(input.as_slice()).unwrap();
+        assert_eq!(summary, expected);
+        assert_eq!(
+            format!("{:?}", parallel.output_sorted()),
+            format!("{:?}", sequential.output_sorted())
+        );
+
+        // Errors stop the pipeline at the same row, with the rows before it applied.
+        let mut engine = Engine::new();
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\ndeposit,1,3,1.0\n";
+        let error = engine.process_csv_parallel(input.as_bytes()).unwrap_err();
+        assert!(error.to_string().starts_with("line 3: malformed record"));
+        assert_eq!(
+            engine.account(1).unwrap().available,
+            Money::from_units(10_000)
+        );
+        let error = engine
+            .process_csv_parallel("type,client,tx,amount\nbegin,,,\ndeposit,1,4,1.0\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "line 3: input ended inside a batch");
+    }
+
+    #[test]
+    fn test_fast_path_matches_serde() {
+        let input = "type,client,tx,amount,timestamp,to_client\n\
+                     deposit,1,1,10.0\n\
+                     deposit, 2 ,2,007.50,1700000000,\n\
+                     withdrawal,1,3,1.2345678901234567890123456789\n\
+                     withdrawal,1,4,12345678901234567890123456789\n\
+                     deposit,1,5,-0.0\n\
+                     deposit,1,6,.5\n\
+                     deposit,1,7,1e3\n\
+                     deposit,1,8,+4\n\
+                     Deposit,1,9,1\n\
+                     transfer,1,10,1,,2\n\
+                     dispute,1,1,,\n\
+                     begin,,,\n\
+                     chargeback,1,1\n\
+                     commit,,,\n\
+                     deposit,70000,11,1\n\
+                     deposit,1,12,abc\n\
+                     deposit,1,13,1,2,3,4\n\
+                     deposit,1,14,1,-5\n\
+                     deposit,1,15,123456789012345.6\n\
+                     deposit,1,16,12345678901234.5678\n\
+                     withdrawal,1,17,-2.50\n\
+                     deposit,1,18,0.00000000000000000001\n\
+                     deposit,1,19,123456789012345678\n";
+        for minor_units in [false, true] {
+            let read = |fast| {
+                let reader = CsvReader::new(input.as_bytes())
+                    .unwrap()
+                    .with_minor_units(minor_units)
+                    .with_fast(fast);
+                let mut items = Vec::new();
+                let mut reader = reader;
+                while let Some(item) = reader.next() {
+                    items.push(format!("{} {:?}", reader.line(), item));
+                }
+                items
+            };
+            assert_eq!(read(true), read(false));
+        }
+
+        let file = std::fs::read("examples/3k_records.csv").unwrap();
+        let mut fast = Engine::new();
+        let summary = CsvReader::new(file.as_slice())
+            .unwrap()
+            .with_fast(true)
+            .process(&mut fast)
+            .unwrap();
+        let mut normal = Engine::new();
+        assert_eq!(summary, normal.process_csv(file.as_slice()).unwrap());
+        assert_eq!(
+            format!("{:?}", fast.output_sorted()),
+            format!("{:?}", normal.output_sorted())
+        );
+    }
+
+    #[test]
+    fn test_error_log_records_malformed_rows() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\nwithdrawal,1,3,abc\n";
+        let mut log = ErrorLog::new(Vec::new());
+        let mut transactions = 0;
+        for input in CsvReader::new(input.as_bytes()).unwrap() {
+            match input {
+                Ok(_) => transactions += 1,
+                Err(CsvError::Malformed {
+                    line,
+                    fields,
+                    error,
+                }) => log.record(line, &fields, &error).unwrap(),
+                Err(error) => panic!("{}", error),
+            }
+        }
+        log.flush().unwrap();
+        assert_eq!(transactions, 1);
+        assert_eq!(
+            String::from_utf8(log.writer.into_inner().unwrap()).unwrap(),
+            "line,record,error\n\
+             3,\"deposit,x,2,1.0\",field 1: invalid digit found in string\n\
+             4,\"withdrawal,1,3,abc\",\"invalid value: string \"\"abc\"\", expected a Decimal \
+             type representing a fixed-point number\"\n"
+        );
+        assert_eq!("skip".parse(), Ok(ErrorPolicy::Skip));
+    }
+
+    #[test]
+    fn test_transfer_rows() {
+        let input = "type,client,tx,amount,timestamp,to_client\n\
+                     deposit,1,1,10.0\n\
+                     transfer,1,2,4.0,,2\n";
+        let mut engine = Engine::new();
+        let summary = engine.process_csv(input.as_bytes()).unwrap();
+        assert_eq!(summary.applied, 2);
+        assert_eq!(
+            engine.account(2).map(|account| account.available),
+            Some(Money::from_units(40_000))
+        );
+    }
+
+    #[test]
+    fn test_metadata_columns() {
+        let input = "type,client,tx,amount,reference,merchant_id\n\
+                     deposit,1,1,10.0,INV-1,m-7\n\
+                     deposit,1,2,1.5e0,INV-2,\n\
+                     dispute,1,1,,case-9,\n";
+        for fast in [false, true] {
+            let mut engine = Engine::new();
+            let audited = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&audited);
+            engine.on_audit(move |event| sink.lock().unwrap().push(event.clone()));
+            CsvReader::new(input.as_bytes())
+                .unwrap()
+                .with_metadata(true)
+                .with_fast(fast)
+                .process(&mut engine)
+                .unwrap();
+            let reference = |tx| {
+                engine
+                    .metadata(tx)
+                    .map(|metadata| metadata["reference"].clone())
+            };
+            assert_eq!(reference(1).as_deref(), Some("INV-1"));
+            assert_eq!(engine.metadata(1).unwrap()["merchant_id"], "m-7");
+            assert_eq!(engine.metadata(2).unwrap().len(), 1);
+            // The dispute's record carries the payment's references, not its own.
+            let audited = audited.lock().unwrap();
+            assert_eq!(audited[2].metadata["reference"], "INV-1");
+        }
+
+        let mut engine = Engine::new();
+        engine.process_csv(input.as_bytes()).unwrap();
+        assert_eq!(engine.metadata(1), None);
+    }
+
+    #[test]
+    fn test_column_map() {
+        let columns: ColumnMap = "type=txn_type,client=customer,tx=txn_id,amount=value"
+            .parse()
+            .unwrap();
+        let input = "txn_type,customer,txn_id,value,branch\n\
+                     deposit,1,1,10.0,north\n\
+                     withdrawal,1,2,2.5,north\n";
+        for fast in [false, true] {
+            let mut engine = Engine::new();
+            CsvReader::with_columns(input.as_bytes(), &columns)
+                .unwrap()
+                .with_metadata(true)
+                .with_fast(fast)
+                .process(&mut engine)
+                .unwrap();
+            assert_eq!(engine.output()[0].available, Money::from_units(75_000));
+            assert_eq!(engine.metadata(1).unwrap()["branch"], "north");
+        }
+
+        let headerless = ColumnMap::default().with_positions(["client", "type", "tx", "amount"]);
+        let mut engine = Engine::new();
+        CsvReader::with_columns("1,deposit,1,3.0\n1,deposit,2,1.0\n".as_bytes(), &headerless)
+            .unwrap()
+            .process(&mut engine)
+            .unwrap();
+        assert_eq!(engine.output()[0].available, Money::from_units(40_000));
+
+        assert!("kind=txn_type".parse::<ColumnMap>().is_err());
+        assert!("type".parse::<ColumnMap>().is_err());
+        assert!("tx=id,tx=ref".parse::<ColumnMap>().is_err());
+    }
+
+    #[test]
+    fn test_input_limits() {
+        let input = "type,client,tx,amount,timestamp,to_client\n\
+                     deposit,1,1,10.0,,\n\
+                     deposit,2,2,-500.0,,\n\
+                     transfer,1,3,1.0,,2\n\
+                     deposit,3,4,1.0,,\n\
+                     dispute,1,1,,,\n\
+                     withdrawal,2,5,1.0,,\n\
+                     deposit,1,6,1.0,,\n";
+        let limits = InputLimits {
+            max_clients: Some(2),
+            max_transactions: Some(3),
+            max_amount: Some(Decimal::from(100)),
+            ..InputLimits::default()
+        };
+        for fast in [false, true] {
+            let reader = CsvReader::new(input.as_bytes())
+                .unwrap()
+                .with_fast(fast)
+                .with_limits(limits);
+            let exceeded: Vec<_> = reader
+                .filter_map(|input| match input {
+                    Ok(_) => None,
+                    Err(CsvError::LimitExceeded { line, limit }) => Some((line, limit)),
+                    Err(error) => panic!("{}", error),
+                })
+                .collect();
+            assert_eq!(
+                exceeded,
+                [
+                    (3, InputLimit::Amount(Decimal::from(100))),
+                    (5, InputLimit::Clients(2)),
+                    (8, InputLimit::Transactions(3)),
+                ]
+            );
+        }
+
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2,1.00000000000000000001\n\
+                     deposit,1,3,1.0\n";
+        let limits = InputLimits {
+            max_row_bytes: Some(20),
+            ..InputLimits::default()
+        };
+        let mut reader = CsvReader::new(input.as_bytes())
+            .unwrap()
+            .with_limits(limits);
+        assert!(matches!(reader.next(), Some(Ok(Input::Transaction(_)))));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(CsvError::LimitExceeded {
+                line: 3,
+                limit: InputLimit::RowBytes(20)
+            }))
+        ));
+        assert!(reader.next().is_none());
+
+        // Without the limit the long row is read as usual.
+        let mut engine = Engine::new();
+        let summary = engine.process_csv(input.as_bytes()).unwrap();
+        assert_eq!(summary.applied, 3);
+    }
+
+    #[test]
+    fn test_value_dates() {
+        let input = "type,client,tx,amount,timestamp,value_date\n\
+                     deposit,1,1,10.0,,500\n\
+                     deposit,1,2,1.0,100,\n\
+                     dispute,1,2,,,600\n\
+                     settle_until,,,,500,\n\
+                     withdrawal,1,3,12.0,,\n";
+        for fast in [false, true] {
+            let mut engine = Engine::new();
+            let summary = CsvReader::new(input.as_bytes())
+                .unwrap()
+                .with_fast(fast)
+                .process(&mut engine)
+                .unwrap();
+            assert_eq!(summary.ignored.get(ProcessError::NotSchedulable), 1);
+            assert_eq!(engine.transaction(1).unwrap().timestamp, Some(500));
+            assert!(engine.transaction(3).is_none());
+            assert_eq!(engine.output()[0].available, Money::from_units(110_000));
+        }
+        assert!(
+            CsvReader::new("type,client,tx,amount,value_date\ndeposit,1,1,1.0,soon\n".as_bytes())
+                .unwrap()
+                .next()
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_chargeback_reversal_rows() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     dispute,1,1,\n\
+                     chargeback,1,1,\n\
+                     chargeback_reversal,1,1,\n";
+        let mut engine = Engine::new();
+        let summary = engine.process_csv(input.as_bytes()).unwrap();
+        assert_eq!(summary.applied, 4);
+        assert_eq!(
+            engine.account(1).map(|account| account.available),
+            Some(Money::from_units(100_000))
+        );
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=PROGRESS_ROWS + 1 {
+            input.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        input.push_str("withdrawal,1,0,1000000\n");
+        let mut reported = Vec::new();
+        Engine::new()
+            .process_csv_with_progress(input.as_bytes(), |progress| reported.push(progress))
+            .unwrap();
+        let first = reported[0];
+        assert_eq!((first.rows, first.rejected), (PROGRESS_ROWS, 0));
+        assert_eq!(
+            reported[1],
+            Progress {
+                rows: PROGRESS_ROWS + 2,
+                bytes: input.len() as u64,
+                rejected: 1
+            }
+        );
+        assert_eq!(reported.len(), 2);
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let input = "type,client,tx,amount,timestamp,to_client,reason\n\
+                     deposit,1,1,10.0,,,\n\
+                     deposit,2,2,5.0,,,\n\
+                     transfer,2,3,1.0,,3,\n\
+                     transfer,4,4,1.0,,5,\n\
+                     lock,4,,,,,\n\
+                     deposit,3,5,2.0,,,\n";
+        for fast in [false, true] {
+            let mut engine = Engine::new();
+            let summary = CsvReader::new(input.as_bytes())
+                .unwrap()
+                .with_admin(true)
+                .with_fast(fast)
+                .with_clients("1,3-3".parse().unwrap())
+                .process(&mut engine)
+                .unwrap();
+            // The transfer to client 3 is kept, but its sender's deposit wasn't.
+            assert_eq!(summary.applied, 2);
+            assert_eq!(summary.ignored.get(ProcessError::InsufficientFunds), 1);
+            let clients: Vec<_> = engine.accounts().map(|(client, _)| client).collect();
+            assert!(!clients.contains(&4));
+        }
+    }
+
+    #[test]
+    fn test_admin_rows() {
+        let input = "type,client,tx,amount,reason\n\
+                     deposit,1,1,10.0,\n\
+                     adjust,1,,-2.5,duplicate payout\n\
+                     overdraft,1,,5,\n\
+                     lock,1,,,\n";
+        let mut engine = Engine::new();
+        let summary = CsvReader::new(input.as_bytes())
+            .unwrap()
+            .with_admin(true)
+            .process(&mut engine)
+            .unwrap();
+        assert_eq!(summary.applied, 4);
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.locked),
+            (Money::from_units(75_000), true)
+        );
+
+        // Without admin rows enabled they are malformed.
+        assert!(matches!(
+            Engine::new().process_csv(input.as_bytes()),
+            Err(CsvError::Malformed { line: 3, .. })
+        ));
+    }
+}
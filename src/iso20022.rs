@@ -0,0 +1,304 @@
+//! ISO 20022 XML message input.
+//!
+//! Supported messages and how they map onto engine transactions:
+//!
+//! - `camt.052`/`camt.053`/`camt.054` entries (`Ntry`): credits become deposits, debits
+//!   become withdrawals. Entries flagged as reversals (`RvslInd`) or carrying return
+//!   information (`RtrInf`) become a dispute followed by a chargeback of the original
+//!   transaction.
+//! - `pain.001` credit transfer instructions (`CdtTrfTxInf`) become withdrawals from the
+//!   debtor account.
+//!
+//! The client is taken from the account identification (IBAN or other id), looked up in
+//! [`Iso20022Options::accounts`] or, failing that, parsed as a numeric client id. The
+//! transaction id is the first numeric reference found, in order of preference:
+//! `EndToEndId`, `TxId`, `NtryRef`, `AcctSvcrRef`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::BufRead;
+use std::str::FromStr;
+
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use rust_decimal::Decimal;
+
+use crate::types::{ClientId, Transaction, TransactionType, TxId};
+
+const REFERENCE_PRIORITY: [&str; 4] = ["EndToEndId", "TxId", "NtryRef", "AcctSvcrRef"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Iso20022Options {
+    /// Account identifier (IBAN or other id) to client id.
+    pub accounts: HashMap<String, ClientId>,
+}
+
+#[derive(Debug)]
+pub enum Iso20022Error {
+    Xml(quick_xml::Error),
+    /// An entry could not be mapped. `entry` is the 1-based index of the entry in the message.
+    InvalidEntry {
+        entry: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Iso20022Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Iso20022Error::Xml(e) => write!(f, "invalid XML: {}", e),
+            Iso20022Error::InvalidEntry { entry, message } => {
+                write!(f, "entry {}: {}", entry, message)
+            }
+        }
+    }
+}
+
+impl Error for Iso20022Error {}
+
+impl From<quick_xml::Error> for Iso20022Error {
+    fn from(e: quick_xml::Error) -> Self {
+        Iso20022Error::Xml(e)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    account: Option<String>,
+    amount: Option<String>,
+    credit: Option<bool>,
+    returned: bool,
+    references: HashMap<&'static str, String>,
+}
+
+/// Reads all transactions from an ISO 20022 message.
+pub fn read_transactions<R: BufRead>(
+    reader: R,
+    options: &Iso20022Options,
+) -> Result<Vec<Transaction>, Iso20022Error> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut account: Option<String> = None;
+    let mut entry: Option<Entry> = None;
+    let mut entries = 0;
+    let mut transactions = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_string();
+                match name.as_str() {
+                    "Ntry" => entry = Some(Entry::default()),
+                    "CdtTrfTxInf" => {
+                        entry = Some(Entry {
+                            credit: Some(false),
+                            ..Entry::default()
+                        })
+                    }
+                    "RtrInf" => {
+                        if let Some(entry) = entry.as_mut() {
+                            entry.returned = true;
+                        }
+                    }
+                    _ => {}
+                }
+                path.push(name);
+                text.clear();
+            }
+            Event::Text(t) => text.push_str(&t.xml10_content()),
+            Event::End(_) => {
+                let name = path.pop().unwrap_or_default();
+                let parent = path.last().map(String::as_str).unwrap_or("");
+                let value = std::mem::take(&mut text);
+
+                // Account ids nested inside an entry belong to counterparties, not the client.
+                if entry.is_none() && is_account_id(&path, &name) {
+                    account = Some(value);
+                } else if let Some(current) = entry.as_mut() {
+                    match (parent, name.as_str()) {
+                        ("Ntry", "Amt") | ("Amt", "InstdAmt") => current.amount = Some(value),
+                        ("Ntry", "CdtDbtInd") => current.credit = Some(value == "CRDT"),
+                        ("Ntry", "RvslInd") => current.returned |= value == "true",
+                        ("Ntry" | "Refs" | "PmtId", reference) => {
+                            if let Some(key) = REFERENCE_PRIORITY.iter().find(|r| **r == reference)
+                            {
+                                current.references.insert(key, value);
+                            }
+                        }
+                        (_, "Ntry" | "CdtTrfTxInf") => {
+                            entries += 1;
+                            let mut finished = entry.take().unwrap_or_default();
+                            finished.account = account.clone();
+                            map_entry(finished, options, &mut transactions).map_err(|message| {
+                                Iso20022Error::InvalidEntry {
+                                    entry: entries,
+                                    message,
+                                }
+                            })?;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(transactions)
+}
+
+/// Matches `Acct/Id/IBAN`, `Acct/Id/Othr/Id` and the `DbtrAcct` equivalents.
+fn is_account_id(path: &[String], name: &str) -> bool {
+    let holder = match (name, path) {
+        ("IBAN", [.., holder, id]) if id == "Id" => holder,
+        ("Id", [.., holder, id, othr]) if id == "Id" && othr == "Othr" => holder,
+        _ => return false,
+    };
+    holder == "Acct" || holder == "DbtrAcct"
+}
+
+fn map_entry(
+    entry: Entry,
+    options: &Iso20022Options,
+    out: &mut Vec<Transaction>,
+) -> Result<(), String> {
+    let account = entry.account.ok_or("no account identification")?;
+    let client = match options.accounts.get(&account) {
+        Some(&client) => client,
+        None => account
+            .parse()
+            .map_err(|_| format!("no client mapping for account '{}'", account))?,
+    };
+    let tx = REFERENCE_PRIORITY
+        .iter()
+        .filter_map(|key| entry.references.get(key))
+        .find_map(|reference| reference.parse::<TxId>().ok())
+        .ok_or("no numeric transaction reference")?;
+
+    if entry.returned {
+        for tx_type in [TransactionType::Dispute, TransactionType::Chargeback] {
+            out.push(Transaction {
+                tx_type,
+                client,
+                tx,
+                amount: None,
+                timestamp: None,
+                to_client: None,
+                metadata: Default::default(),
+            });
+        }
+        return Ok(());
+    }
+
+    let amount = entry.amount.ok_or("missing amount")?;
+    let amount =
+        Decimal::from_str(&amount).map_err(|e| format!("invalid amount '{}': {}", amount, e))?;
+    let tx_type = match entry.credit.ok_or("missing credit/debit indicator")? {
+        true => TransactionType::Deposit,
+        false => TransactionType::Withdrawal,
+    };
+    out.push(Transaction {
+        tx_type,
+        client,
+        tx,
+        amount: Some(amount),
+        timestamp: None,
+        to_client: None,
+        metadata: Default::default(),
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const CAMT054: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.054.001.08">
+  <BkToCstmrDbtCdtNtfctn>
+    <Ntfctn>
+      <Acct><Id><IBAN>DE89370400440532013000</IBAN></Id></Acct>
+      <Ntry>
+        <Amt Ccy="EUR">100.50</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <NtryDtls><TxDtls><Refs><EndToEndId>1</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">20</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <NtryRef>2</NtryRef>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">100.50</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <NtryDtls><TxDtls>
+          <Refs><EndToEndId>1</EndToEndId></Refs>
+          <RtrInf><Rsn><Cd>AC04</Cd></Rsn></RtrInf>
+        </TxDtls></NtryDtls>
+      </Ntry>
+    </Ntfctn>
+  </BkToCstmrDbtCdtNtfctn>
+</Document>"#;
+
+    const PAIN001: &str = r#"<Document xmlns="urn:iso:std:iso:20022:tech:xsd:pain.001.001.09">
+  <CstmrCdtTrfInitn>
+    <PmtInf>
+      <DbtrAcct><Id><Othr><Id>7</Id></Othr></Id></DbtrAcct>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>42</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">12.3456</InstdAmt></Amt>
+        <CdtrAcct><Id><IBAN>FR7630006000011234567890189</IBAN></Id></CdtrAcct>
+      </CdtTrfTxInf>
+    </PmtInf>
+  </CstmrCdtTrfInitn>
+</Document>"#;
+
+    #[test]
+    fn test_camt054_mapping() {
+        let mut options = Iso20022Options::default();
+        options
+            .accounts
+            .insert("DE89370400440532013000".to_string(), 5);
+
+        let txs = read_transactions(CAMT054.as_bytes(), &options).unwrap();
+        let kinds: Vec<_> = txs
+            .iter()
+            .map(|t| (format!("{:?}", t.tx_type), t.tx))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Deposit".to_string(), 1),
+                ("Withdrawal".to_string(), 2),
+                ("Dispute".to_string(), 1),
+                ("Chargeback".to_string(), 1),
+            ]
+        );
+        assert!(txs.iter().all(|t| t.client == 5));
+        assert_eq!(txs[0].amount, Some(dec!(100.50)));
+    }
+
+    #[test]
+    fn test_pain001_uses_debtor_account() {
+        let txs = read_transactions(PAIN001.as_bytes(), &Iso20022Options::default()).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert!(matches!(txs[0].tx_type, TransactionType::Withdrawal));
+        assert_eq!(txs[0].client, 7);
+        assert_eq!(txs[0].tx, 42);
+        assert_eq!(txs[0].amount, Some(dec!(12.3456)));
+    }
+
+    #[test]
+    fn test_unmapped_account() {
+        let err = read_transactions(CAMT054.as_bytes(), &Iso20022Options::default()).unwrap_err();
+        assert!(matches!(err, Iso20022Error::InvalidEntry { entry: 1, .. }));
+    }
+}
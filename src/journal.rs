@@ -0,0 +1,523 @@
+//! Append-only binary journal for archiving transactions.
+//!
+//! Layout (all integers little-endian):
+//!
+//! ```text
+//! header:  magic "TXJ\0" (4 bytes) | version u16 | reserved u16
+//! record:  payload length u32 | payload | CRC-32 of payload u32
+//! payload: type u8 | client u128 | tx u64 | flags u8
+//!          | amount (16 bytes, rust_decimal serialized) if flags & 1
+//!          | timestamp u64 if flags & 2
+//!          | to_client u128 if flags & 4
+//! ```
+//!
+//! Records are self-delimiting and individually checksummed, so a journal can be appended to
+//! across runs and a torn final record is detected rather than silently misread. Readers
+//! reject versions newer than they understand. Version 1 journals, whose clients are u16
+//! and transaction ids u32, are still read; writers only write the current version.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use rust_decimal::Decimal;
+
+use crate::types::{Transaction, TransactionType, TxId};
+
+pub const MAGIC: [u8; 4] = *b"TXJ\0";
+pub const VERSION: u16 = 2;
+/// The oldest version readers accept.
+pub const MIN_VERSION: u16 = 1;
+
+const FLAG_AMOUNT: u8 = 1;
+const FLAG_TIMESTAMP: u8 = 2;
+const FLAG_TO_CLIENT: u8 = 4;
+/// Larger than any valid payload; guards against allocating on a corrupt length prefix.
+const MAX_PAYLOAD: u32 = 80;
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// Record `record` (0-based) is damaged: bad checksum, bad length or unknown contents.
+    Corrupt {
+        record: u64,
+        message: String,
+    },
+    /// The journal ends partway through record `record`.
+    Truncated {
+        record: u64,
+    },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "{}", e),
+            JournalError::BadMagic => write!(f, "not a transaction journal"),
+            JournalError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "unsupported journal version {} (expected {} to {})",
+                    v, MIN_VERSION, VERSION
+                )
+            }
+            JournalError::Corrupt { record, message } => {
+                write!(f, "journal record {}: {}", record, message)
+            }
+            JournalError::Truncated { record } => {
+                write!(f, "journal truncated in record {}", record)
+            }
+        }
+    }
+}
+
+impl Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+pub struct JournalWriter<W> {
+    writer: W,
+    payload: Vec<u8>,
+}
+
+impl<W: Write> JournalWriter<W> {
+    /// Starts a new journal, writing the header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        Ok(Self::append_to(writer))
+    }
+
+    /// Appends to an existing journal whose header has already been written.
+    pub fn append_to(writer: W) -> Self {
+        Self {
+            writer,
+            payload: Vec::with_capacity(MAX_PAYLOAD as usize),
+        }
+    }
+
+    pub fn append(&mut self, tx: &Transaction) -> io::Result<()> {
+        let payload = &mut self.payload;
+        payload.clear();
+        payload.push(encode_type(&tx.tx_type));
+        payload.extend_from_slice(&tx.client.to_le_bytes());
+        payload.extend_from_slice(&tx.tx.to_le_bytes());
+        let mut flags = 0;
+        if tx.amount.is_some() {
+            flags |= FLAG_AMOUNT;
+        }
+        if tx.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        if tx.to_client.is_some() {
+            flags |= FLAG_TO_CLIENT;
+        }
+        payload.push(flags);
+        if let Some(amount) = tx.amount {
+            payload.extend_from_slice(&amount.serialize());
+        }
+        if let Some(timestamp) = tx.timestamp {
+            payload.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        if let Some(to_client) = tx.to_client {
+            payload.extend_from_slice(&to_client.to_le_bytes());
+        }
+
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(payload)?;
+        self.writer.write_all(&crc32(payload).to_le_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct JournalReader<R> {
+    reader: R,
+    version: u16,
+    record: u64,
+    /// Byte offset just past the last record read successfully.
+    position: u64,
+    done: bool,
+}
+
+impl<R: Read> JournalReader<R> {
+    /// Opens a journal, validating its header.
+    pub fn new(mut reader: R) -> Result<Self, JournalError> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => JournalError::BadMagic,
+            _ => JournalError::Io(e),
+        })?;
+        if header[..4] != MAGIC {
+            return Err(JournalError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(JournalError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            reader,
+            version,
+            record: 0,
+            position: header.len() as u64,
+            done: false,
+        })
+    }
+
+    /// The version the journal was written in.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Length of the valid prefix read so far: the header and every record returned.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn read_record(&mut self) -> Result<Option<Transaction>, JournalError> {
+        let record = self.record;
+        let mut len = [0u8; 4];
+        match read_full(&mut self.reader, &mut len)? {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(JournalError::Truncated { record }),
+        }
+        let len = u32::from_le_bytes(len);
+        if len > MAX_PAYLOAD {
+            return Err(JournalError::Corrupt {
+                record,
+                message: format!("invalid record length {}", len),
+            });
+        }
+
+        let mut payload = vec![0u8; len as usize + 4];
+        if read_full(&mut self.reader, &mut payload)? != payload.len() {
+            return Err(JournalError::Truncated { record });
+        }
+        let (payload, checksum) = payload.split_at(len as usize);
+        if crc32(payload).to_le_bytes() != checksum {
+            return Err(JournalError::Corrupt {
+                record,
+                message: "checksum mismatch".to_string(),
+            });
+        }
+
+        let transaction =
+            decode(payload, self.version).map_err(|message| JournalError::Corrupt {
+                record,
+                message: message.to_string(),
+            })?;
+        self.record += 1;
+        self.position += 4 + u64::from(len) + 4;
+        Ok(Some(transaction))
+    }
+}
+
+impl<R: Read> Iterator for JournalReader<R> {
+    type Item = Result<Transaction, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_record().transpose();
+        // Stop after the end or the first error; later offsets cannot be trusted.
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Like `read_exact`, but returns how many bytes were read before EOF.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+pub(crate) fn encode_type(tx_type: &TransactionType) -> u8 {
+    match tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Transfer => 5,
+        TransactionType::ChargebackReversal => 6,
+        TransactionType::Hold => 7,
+        TransactionType::Release => 8,
+    }
+}
+
+pub(crate) fn decode_type(byte: u8) -> Option<TransactionType> {
+    Some(match byte {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Transfer,
+        6 => TransactionType::ChargebackReversal,
+        7 => TransactionType::Hold,
+        8 => TransactionType::Release,
+        _ => return None,
+    })
+}
+
+fn decode(payload: &[u8], version: u16) -> Result<Transaction, &'static str> {
+    let mut rest = payload;
+    let mut take = |n: usize| -> Result<&[u8], &'static str> {
+        if rest.len() < n {
+            return Err("record too short");
+        }
+        let (head, tail) = rest.split_at(n);
+        rest = tail;
+        Ok(head)
+    };
+
+    let (client_len, tx_len) = if version == 1 { (2, 4) } else { (16, 8) };
+
+    let tx_type = decode_type(take(1)?[0]).ok_or("unknown transaction type")?;
+    let client = le_uint(take(client_len)?);
+    // At most 8 bytes wide, so the id fits.
+    let tx = le_uint(take(tx_len)?) as TxId;
+    let flags = take(1)?[0];
+    let amount = if flags & FLAG_AMOUNT != 0 {
+        let bytes: [u8; 16] = take(16)?.try_into().unwrap_or_default();
+        Some(Decimal::deserialize(bytes))
+    } else {
+        None
+    };
+    let timestamp = if flags & FLAG_TIMESTAMP != 0 {
+        Some(u64::from_le_bytes(take(8)?.try_into().unwrap_or_default()))
+    } else {
+        None
+    };
+    let to_client = if flags & FLAG_TO_CLIENT != 0 {
+        Some(le_uint(take(client_len)?))
+    } else {
+        None
+    };
+    if !rest.is_empty() {
+        return Err("trailing bytes in record");
+    }
+
+    Ok(Transaction {
+        tx_type,
+        client,
+        tx,
+        amount,
+        timestamp,
+        to_client,
+        metadata: Default::default(),
+    })
+}
+
+/// The little-endian unsigned integer in `bytes`, at most 16 of them.
+fn le_uint(bytes: &[u8]) -> u128 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u128::from(byte))
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE 802.3), as used by zip and gzip.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn sample() -> Vec<Transaction> {
+        vec![
+            Transaction {
+                tx_type: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(dec!(703.29991)),
+                timestamp: Some(1_700_000_000),
+                to_client: None,
+                metadata: Default::default(),
+            },
+            Transaction {
+                tx_type: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                timestamp: None,
+                to_client: None,
+                metadata: Default::default(),
+            },
+        ]
+    }
+
+    fn write(txs: &[Transaction]) -> Vec<u8> {
+        let mut journal = JournalWriter::new(Vec::new()).unwrap();
+        for tx in txs {
+            journal.append(tx).unwrap();
+        }
+        journal.into_inner()
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut txs = sample();
+        txs.push(Transaction {
+            tx_type: TransactionType::Transfer,
+            client: 1,
+            tx: 2,
+            amount: Some(dec!(1.5)),
+            timestamp: None,
+            to_client: Some(9),
+            metadata: Default::default(),
+        });
+        let bytes = write(&txs);
+        let read: Vec<_> = JournalReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].amount, Some(dec!(703.29991)));
+        assert_eq!(read[0].timestamp, Some(1_700_000_000));
+        assert!(matches!(read[1].tx_type, TransactionType::Dispute));
+        assert_eq!(read[1].amount, None);
+        assert_eq!(read[1].to_client, None);
+        assert!(matches!(read[2].tx_type, TransactionType::Transfer));
+        assert_eq!(read[2].to_client, Some(9));
+    }
+
+    #[test]
+    fn test_reads_version_1() {
+        // A transfer of 1.5 from client 7 to client 9 as tx 300, with u16 clients and a
+        // u32 transaction id.
+        let mut payload = vec![5, 7, 0, 44, 1, 0, 0, FLAG_AMOUNT | FLAG_TO_CLIENT];
+        payload.extend_from_slice(&dec!(1.5).serialize());
+        payload.extend_from_slice(&[9, 0]);
+        let mut bytes = b"TXJ\0\x01\0\0\0".to_vec();
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+
+        let mut reader = JournalReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.version(), 1);
+        let tx = reader.next().unwrap().unwrap();
+        assert_eq!((tx.client, tx.tx, tx.to_client), (7, 300, Some(9)));
+        assert_eq!(tx.amount, Some(dec!(1.5)));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_wide_ids() {
+        let tx = Transaction {
+            tx_type: TransactionType::Transfer,
+            client: u128::MAX - 1,
+            tx: u64::MAX,
+            amount: Some(dec!(2)),
+            timestamp: None,
+            to_client: Some(1 << 100),
+            metadata: Default::default(),
+        };
+        let bytes = write(&[tx]);
+        let read = JournalReader::new(bytes.as_slice())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (read.client, read.tx, read.to_client),
+            (u128::MAX - 1, u64::MAX, Some(1 << 100))
+        );
+    }
+
+    #[test]
+    fn test_detects_corruption_and_truncation() {
+        let mut bytes = write(&sample());
+        let last = bytes.len() - 1;
+
+        let truncated = &bytes[..last];
+        let results: Vec<_> = JournalReader::new(truncated).unwrap().collect();
+        assert!(matches!(
+            results[1],
+            Err(JournalError::Truncated { record: 1 })
+        ));
+
+        bytes[14] ^= 0xFF;
+        let results: Vec<_> = JournalReader::new(bytes.as_slice()).unwrap().collect();
+        assert_eq!(results.len(), 1);
+        assert!(matches!(
+            results[0],
+            Err(JournalError::Corrupt { record: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert!(matches!(
+            JournalReader::new(&b"type,client"[..]),
+            Err(JournalError::BadMagic)
+        ));
+        let mut bytes = write(&[]);
+        bytes[4] = 9;
+        assert!(matches!(
+            JournalReader::new(bytes.as_slice()),
+            Err(JournalError::UnsupportedVersion(9))
+        ));
+    }
+}
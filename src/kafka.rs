@@ -0,0 +1,449 @@
+//! Kafka consumer (feature `kafka`).
+//!
+//! [`consume`] reads every partition of a topic, one transaction per message, and applies
+//! the transactions to an [`Engine`]. A message is either a JSON object with the CSV column
+//! names (`{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`) or a single CSV line
+//! without a header, in the order `type,client,tx,amount,timestamp,to_client`; trailing
+//! columns may be left out.
+//!
+//! Progress is saved in rounds. Every [`KafkaConfig::with_commit_every`] messages the
+//! consumer writes the next offset of each partition to the checkpoint directory, takes
+//! an engine snapshot next to it ([`Checkpointer`]) and then commits the same offsets to
+//! the consumer group. On start it restores the latest snapshot and carries on from the
+//! offsets saved with it. If it stopped after saving the offsets but before the snapshot,
+//! the saved offsets are ahead of the snapshot and the group's committed offsets, which
+//! belong to the previous snapshot, are used instead. Without a snapshot it starts at the
+//! beginning of each partition. Either way the engine state and the offsets it resumes
+//! from match, so a crash neither loses messages nor applies them twice. Only the group's
+//! committed offsets, which monitoring tools read, can lag behind by a round.
+//!
+//! Since the engine needs all of a client's transactions, one consumer reads every
+//! partition; the group is only used to record offsets.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::{Message, Offset, TopicPartitionList};
+
+use crate::checkpoint::{CheckpointError, Checkpointer};
+use crate::engine::Engine;
+use crate::ignored::IgnoredCounts;
+use crate::types::Transaction;
+
+/// Messages between checkpoints unless configured otherwise.
+pub const COMMIT_EVERY: u64 = 10_000;
+
+/// CSV columns in message order.
+const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "timestamp", "to_client"];
+/// Name of the offsets file in the checkpoint directory.
+const OFFSETS: &str = "offsets";
+/// How long to wait for the broker when fetching metadata and committing.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a poll waits for a message.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Encoding of the transaction in each message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug)]
+pub enum ConsumeError {
+    Kafka(rdkafka::error::KafkaError),
+    Checkpoint(CheckpointError),
+    /// The topic has no partitions, usually because it doesn't exist.
+    UnknownTopic(String),
+}
+
+impl fmt::Display for ConsumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsumeError::Kafka(error) => write!(f, "kafka: {}", error),
+            ConsumeError::Checkpoint(error) => write!(f, "checkpoint: {}", error),
+            ConsumeError::UnknownTopic(topic) => write!(f, "topic '{}' has no partitions", topic),
+        }
+    }
+}
+
+impl Error for ConsumeError {}
+
+impl From<rdkafka::error::KafkaError> for ConsumeError {
+    fn from(error: rdkafka::error::KafkaError) -> Self {
+        ConsumeError::Kafka(error)
+    }
+}
+
+impl From<CheckpointError> for ConsumeError {
+    fn from(error: CheckpointError) -> Self {
+        ConsumeError::Checkpoint(error)
+    }
+}
+
+impl From<io::Error> for ConsumeError {
+    fn from(error: io::Error) -> Self {
+        ConsumeError::Checkpoint(CheckpointError::Io(error))
+    }
+}
+
+/// Where to consume from and how often to save progress.
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    brokers: String,
+    topic: String,
+    group: String,
+    checkpoint_dir: PathBuf,
+    format: MessageFormat,
+    commit_every: u64,
+    idle_timeout: Option<Duration>,
+    properties: Vec<(String, String)>,
+}
+
+impl KafkaConfig {
+    /// Consumes `topic` from `brokers` (a comma-separated `host:port` list), committing
+    /// offsets for consumer group `group` and keeping snapshots in `checkpoint_dir`.
+    pub fn new(
+        brokers: impl Into<String>,
+        topic: impl Into<String>,
+        group: impl Into<String>,
+        checkpoint_dir: impl AsRef<Path>,
+    ) -> Self {
+        Self {
+            brokers: brokers.into(),
+            topic: topic.into(),
+            group: group.into(),
+            checkpoint_dir: checkpoint_dir.as_ref().to_path_buf(),
+            format: MessageFormat::default(),
+            commit_every: COMMIT_EVERY,
+            idle_timeout: None,
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn with_format(mut self, format: MessageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Saves progress every `messages` messages, malformed ones included.
+    pub fn with_commit_every(mut self, messages: u64) -> Self {
+        self.commit_every = messages.max(1);
+        self
+    }
+
+    /// Returns once no message has arrived for `timeout`, instead of running until an
+    /// error.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Passes a librdkafka setting through, such as `security.protocol`.
+    pub fn with_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// What [`consume`] did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConsumeSummary {
+    /// Transactions applied.
+    pub applied: u64,
+    /// Rejected transactions by reason.
+    pub ignored: IgnoredCounts,
+    /// Messages that weren't a transaction; they are logged and skipped.
+    pub malformed: u64,
+    /// Rounds of progress saved.
+    pub checkpoints: u64,
+}
+
+/// Applies the transactions of `config`'s topic to `engine`, which must be new, resuming
+/// from the latest checkpoint; see the [module docs](self). Runs until the idle timeout,
+/// if one is set, or an error. Progress is saved before returning normally.
+pub fn consume(config: &KafkaConfig, engine: &mut Engine) -> Result<ConsumeSummary, ConsumeError> {
+    let mut client = ClientConfig::new();
+    client
+        .set("bootstrap.servers", &config.brokers)
+        .set("group.id", &config.group)
+        .set("enable.auto.commit", "false");
+    for (key, value) in &config.properties {
+        client.set(key, value);
+    }
+    let consumer: BaseConsumer = client.create()?;
+
+    let mut checkpointer = Checkpointer::new(&config.checkpoint_dir, config.commit_every)?;
+    let mut position = checkpointer.resume(engine)?;
+    let mut offsets = start_offsets(&consumer, config, position)?;
+    consumer.assign(&partition_list(&config.topic, &offsets)?)?;
+
+    let mut summary = ConsumeSummary::default();
+    let mut saved = position;
+    let mut last_message = Instant::now();
+    loop {
+        match consumer.poll(POLL) {
+            Some(message) => {
+                let message = message?;
+                match decode(config.format, message.payload().unwrap_or_default()) {
+                    Ok(tx) => match engine.process(tx) {
+                        Ok(_) => summary.applied += 1,
+                        Err(error) => summary.ignored.record(error),
+                    },
+                    Err(error) => {
+                        log::warn!(
+                            "skipping malformed message at partition {} offset {}: {}",
+                            message.partition(),
+                            message.offset(),
+                            error
+                        );
+                        summary.malformed += 1;
+                    }
+                }
+                offsets.insert(message.partition(), Some(message.offset() + 1));
+                position += 1;
+                last_message = Instant::now();
+            }
+            None if config
+                .idle_timeout
+                .is_some_and(|timeout| last_message.elapsed() >= timeout) =>
+            {
+                break;
+            }
+            None => {}
+        }
+        if position - saved >= config.commit_every {
+            save(
+                &consumer,
+                config,
+                &mut checkpointer,
+                engine,
+                position,
+                &offsets,
+            )?;
+            saved = position;
+            summary.checkpoints += 1;
+        }
+    }
+    if position > saved {
+        save(
+            &consumer,
+            config,
+            &mut checkpointer,
+            engine,
+            position,
+            &offsets,
+        )?;
+        summary.checkpoints += 1;
+    }
+    Ok(summary)
+}
+
+/// Next offset to read of each partition of the topic; `None` for the beginning.
+type Offsets = BTreeMap<i32, Option<i64>>;
+
+/// The offsets that go with the engine state at `position`.
+fn start_offsets(
+    consumer: &BaseConsumer,
+    config: &KafkaConfig,
+    position: u64,
+) -> Result<Offsets, ConsumeError> {
+    let metadata = consumer.fetch_metadata(Some(&config.topic), TIMEOUT)?;
+    let mut offsets: Offsets = metadata
+        .topics()
+        .iter()
+        .filter(|topic| topic.name() == config.topic)
+        .flat_map(|topic| topic.partitions())
+        .map(|partition| (partition.id(), None))
+        .collect();
+    if offsets.is_empty() {
+        return Err(ConsumeError::UnknownTopic(config.topic.clone()));
+    }
+    if position == 0 {
+        return Ok(offsets);
+    }
+    match read_offsets(&config.checkpoint_dir)? {
+        Some((at, saved)) if at == position => offsets.extend(saved),
+        _ => {
+            let committed =
+                consumer.committed_offsets(partition_list(&config.topic, &offsets)?, TIMEOUT)?;
+            for element in committed.elements() {
+                if let Offset::Offset(offset) = element.offset() {
+                    offsets.insert(element.partition(), Some(offset));
+                }
+            }
+        }
+    }
+    Ok(offsets)
+}
+
+fn partition_list(topic: &str, offsets: &Offsets) -> Result<TopicPartitionList, ConsumeError> {
+    let mut list = TopicPartitionList::new();
+    for (&partition, offset) in offsets {
+        let offset = offset.map_or(Offset::Beginning, Offset::Offset);
+        list.add_partition_offset(topic, partition, offset)?;
+    }
+    Ok(list)
+}
+
+/// Saves a round: the offsets file, then the snapshot, then the group commit.
+fn save(
+    consumer: &BaseConsumer,
+    config: &KafkaConfig,
+    checkpointer: &mut Checkpointer,
+    engine: &Engine,
+    position: u64,
+    offsets: &Offsets,
+) -> Result<(), ConsumeError> {
+    write_offsets(&config.checkpoint_dir, position, offsets)?;
+    checkpointer.checkpoint(engine, position)?;
+    let read: Offsets = offsets
+        .iter()
+        .filter(|(_, offset)| offset.is_some())
+        .map(|(&partition, &offset)| (partition, offset))
+        .collect();
+    if !read.is_empty() {
+        consumer.commit(&partition_list(&config.topic, &read)?, CommitMode::Sync)?;
+    }
+    Ok(())
+}
+
+/// The transaction in a message's payload.
+fn decode(format: MessageFormat, payload: &[u8]) -> Result<Transaction, Box<dyn Error>> {
+    match format {
+        MessageFormat::Json => Ok(serde_json::from_slice(payload)?),
+        MessageFormat::Csv => {
+            let mut reader = ReaderBuilder::new()
+                .has_headers(false)
+                .trim(Trim::All)
+                .from_reader(payload);
+            let record = reader.records().next().ok_or("empty message")??;
+            if record.len() > COLUMNS.len() {
+                return Err(format!(
+                    "{} fields, expected at most {}",
+                    record.len(),
+                    COLUMNS.len()
+                )
+                .into());
+            }
+            let headers = StringRecord::from(&COLUMNS[..record.len()]);
+            Ok(record.deserialize(Some(&headers))?)
+        }
+    }
+}
+
+/// Writes the offsets going with the snapshot at `position`: the position on the first
+/// line, then `<partition>,<next offset>` for each partition read from. The file is
+/// replaced atomically like a checkpoint.
+fn write_offsets(dir: &Path, position: u64, offsets: &Offsets) -> io::Result<()> {
+    let path = dir.join(OFFSETS);
+    let temporary = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    writeln!(writer, "{}", position)?;
+    for (partition, offset) in offsets {
+        if let Some(offset) = offset {
+            writeln!(writer, "{},{}", partition, offset)?;
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(temporary, path)
+}
+
+/// The position and offsets saved by [`write_offsets`], if any.
+fn read_offsets(dir: &Path) -> Result<Option<(u64, Offsets)>, CheckpointError> {
+    let file = match File::open(dir.join(OFFSETS)) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let corrupt = || CheckpointError::Corrupt("bad offsets file".to_string());
+    let mut lines = BufReader::new(file).lines();
+    let position = lines.next().ok_or_else(corrupt)??;
+    let position = position.parse().map_err(|_| corrupt())?;
+    let mut offsets = Offsets::new();
+    for line in lines {
+        let line = line?;
+        let (partition, offset) = line.split_once(',').ok_or_else(corrupt)?;
+        let partition = partition.parse().map_err(|_| corrupt())?;
+        let offset = offset.parse().map_err(|_| corrupt())?;
+        offsets.insert(partition, Some(offset));
+    }
+    Ok(Some((position, offsets)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_decode() {
+        let json = br#"{"type":"deposit","client":1,"tx":7,"amount":"2.5"}"#;
+        let tx = decode(MessageFormat::Json, json).unwrap();
+        assert_eq!(
+            (tx.tx_type, tx.client, tx.tx, tx.amount),
+            (TransactionType::Deposit, 1, 7, Some(dec!(2.5)))
+        );
+        let tx = decode(
+            MessageFormat::Json,
+            br#"{"type":"dispute","client":1,"tx":7}"#,
+        )
+        .unwrap();
+        assert_eq!((tx.tx_type, tx.amount), (TransactionType::Dispute, None));
+
+        let tx = decode(MessageFormat::Csv, b"transfer, 1, 8, 1.0, 60, 2").unwrap();
+        assert_eq!(
+            (tx.tx_type, tx.amount, tx.timestamp, tx.to_client),
+            (
+                TransactionType::Transfer,
+                Some(dec!(1.0)),
+                Some(60),
+                Some(2)
+            )
+        );
+        let tx = decode(MessageFormat::Csv, b"resolve,1,7").unwrap();
+        assert_eq!(
+            (tx.tx_type, tx.tx, tx.amount),
+            (TransactionType::Resolve, 7, None)
+        );
+
+        assert!(decode(MessageFormat::Json, b"deposit,1,7,2.5").is_err());
+        assert!(decode(MessageFormat::Csv, b"").is_err());
+        assert!(decode(MessageFormat::Csv, b"deposit,1,7,2.5,60,2,extra").is_err());
+    }
+
+    #[test]
+    fn test_offsets_file() {
+        let dir = env::temp_dir().join(format!("tx-engine-kafka-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert!(read_offsets(&dir).unwrap().is_none());
+
+        let offsets = Offsets::from([(0, Some(12)), (1, None), (2, Some(3))]);
+        write_offsets(&dir, 15, &offsets).unwrap();
+        let (position, read) = read_offsets(&dir).unwrap().unwrap();
+        assert_eq!(position, 15);
+        assert_eq!(read, Offsets::from([(0, Some(12)), (2, Some(3))]));
+
+        fs::write(dir.join(OFFSETS), "15\n0;12\n").unwrap();
+        assert!(matches!(
+            read_offsets(&dir),
+            Err(CheckpointError::Corrupt(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! Plain-text accounting export (Beancount or Ledger-CLI).
+//!
+//! Each applied transaction becomes one balanced entry. Postings are derived from how the
+//! client's balances changed, so every engine rule (reserves, disputes, chargebacks) is
+//! reflected without the exporter re-implementing it. Client balances are liabilities of
+//! the operator: money owed to a client appears as a negative balance on its accounts,
+//! offset by the external (bank) account.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::types::{Account, ClientId, DEFAULT_SCALE, Money, Transaction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerFormat {
+    Beancount,
+    Ledger,
+}
+
+impl FromStr for LedgerFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "beancount" => Ok(LedgerFormat::Beancount),
+            "ledger" => Ok(LedgerFormat::Ledger),
+            _ => Err(format!("unknown ledger format '{}'", s)),
+        }
+    }
+}
+
+/// Account names used in postings. `{client}` is replaced with the client id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountMapping {
+    pub available: String,
+    pub held: String,
+    pub reserved: String,
+    pub on_hold: String,
+    pub external: String,
+    pub commodity: String,
+}
+
+impl Default for AccountMapping {
+    fn default() -> Self {
+        Self {
+            available: "Liabilities:Clients:{client}:Available".to_string(),
+            held: "Liabilities:Clients:{client}:Held".to_string(),
+            reserved: "Liabilities:Clients:{client}:Reserved".to_string(),
+            on_hold: "Liabilities:Clients:{client}:OnHold".to_string(),
+            external: "Assets:Bank".to_string(),
+            commodity: "USD".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MappingError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "account mapping line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for MappingError {}
+
+impl AccountMapping {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Ok(fs::read_to_string(path)?.parse()?)
+    }
+
+    fn account(template: &str, client: ClientId) -> String {
+        template.replace("{client}", &client.to_string())
+    }
+}
+
+/// Parses `key = value` lines overriding the defaults. Keys are `available`, `held`,
+/// `reserved`, `on_hold`, `external` and `commodity`; blank lines and `#` comments are ignored.
+impl FromStr for AccountMapping {
+    type Err = MappingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mapping = AccountMapping::default();
+        for (index, raw) in s.lines().enumerate() {
+            let err = |message: String| MappingError {
+                line: index + 1,
+                message,
+            };
+            let content = raw.split('#').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+            let (key, value) = content
+                .split_once('=')
+                .ok_or_else(|| err("expected 'key = value'".to_string()))?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "available" => mapping.available = value,
+                "held" => mapping.held = value,
+                "reserved" => mapping.reserved = value,
+                "on_hold" => mapping.on_hold = value,
+                "external" => mapping.external = value,
+                "commodity" => mapping.commodity = value,
+                other => return Err(err(format!("unknown key '{}'", other))),
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+pub struct LedgerExporter<W> {
+    writer: W,
+    format: LedgerFormat,
+    mapping: AccountMapping,
+    /// Date used for transactions without a timestamp, as `YYYY-MM-DD`.
+    default_date: String,
+    opened: HashSet<String>,
+    scale: u32,
+}
+
+impl<W: Write> LedgerExporter<W> {
+    pub fn new(writer: W, format: LedgerFormat, mapping: AccountMapping) -> Self {
+        Self {
+            writer,
+            format,
+            mapping,
+            default_date: "1970-01-01".to_string(),
+            opened: HashSet::new(),
+            scale: DEFAULT_SCALE,
+        }
+    }
+
+    /// Amounts have `scale` decimal places, as in an engine configured with
+    /// `EngineConfig::with_scale`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_default_date(mut self, date: &str) -> Self {
+        self.default_date = date.to_string();
+        self
+    }
+
+    /// Writes an entry for `tx` given the client's account before and after processing.
+    /// Nothing is written when the balances did not change (the transaction was ignored).
+    pub fn record(
+        &mut self,
+        tx: &Transaction,
+        before: &Account,
+        after: &Account,
+    ) -> io::Result<()> {
+        self.record_changes(tx, &[(tx.client, *before, *after)])
+    }
+
+    /// Writes one entry for `tx` covering every account it changed, given as
+    /// `(client, before, after)`, such as both sides of a transfer. Money moving between
+    /// clients needs no external posting.
+    pub fn record_changes(
+        &mut self,
+        tx: &Transaction,
+        changes: &[(ClientId, Account, Account)],
+    ) -> io::Result<()> {
+        let mut postings: Vec<(String, Money)> = Vec::new();
+        for (client, before, after) in changes {
+            let buckets = [
+                (
+                    &self.mapping.available,
+                    after.available.saturating_sub(before.available),
+                ),
+                (&self.mapping.held, after.held.saturating_sub(before.held)),
+                (
+                    &self.mapping.reserved,
+                    after.reserved.saturating_sub(before.reserved),
+                ),
+                (
+                    &self.mapping.on_hold,
+                    after.on_hold.saturating_sub(before.on_hold),
+                ),
+            ];
+            postings.extend(buckets.iter().filter(|(_, delta)| !delta.is_zero()).map(
+                |(template, delta)| {
+                    (
+                        AccountMapping::account(template, *client),
+                        delta.saturating_neg(),
+                    )
+                },
+            ));
+        }
+        if postings.is_empty() {
+            return Ok(());
+        }
+        let external = postings
+            .iter()
+            .fold(Money::ZERO, |sum, (_, amount)| sum.saturating_sub(*amount));
+        if !external.is_zero() {
+            postings.insert(0, (self.mapping.external.clone(), external));
+        }
+
+        let date = match tx.timestamp {
+            Some(ts) => format_date(ts),
+            None => self.default_date.clone(),
+        };
+        let narration =
+            format!("{:?} client {} tx {}", tx.tx_type, tx.client, tx.tx).to_lowercase();
+
+        match self.format {
+            LedgerFormat::Beancount => {
+                for (account, _) in &postings {
+                    if self.opened.insert(account.clone()) {
+                        writeln!(self.writer, "{} open {}", date, account)?;
+                    }
+                }
+                writeln!(self.writer, "{} * \"{}\"", date, narration)?;
+            }
+            LedgerFormat::Ledger => {
+                writeln!(self.writer, "{} {}", date.replace('-', "/"), narration)?;
+            }
+        }
+        for (account, amount) in &postings {
+            writeln!(
+                self.writer,
+                "  {}  {} {}",
+                account,
+                amount.format(self.scale),
+                self.mapping.commodity
+            )?;
+        }
+        writeln!(self.writer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Formats a Unix timestamp as a UTC `YYYY-MM-DD` date.
+fn format_date(timestamp: u64) -> String {
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{SCALE, TransactionType};
+
+    fn account(available: i64, held: i64) -> Account {
+        Account {
+            available: Money::from_units(available * SCALE),
+            held: Money::from_units(held * SCALE),
+            ..Account::default()
+        }
+    }
+
+    fn tx(tx_type: TransactionType, timestamp: Option<u64>) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 7,
+            tx: 3,
+            amount: None,
+            timestamp,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+        assert_eq!(format_date(1_704_067_199), "2023-12-31");
+    }
+
+    #[test]
+    fn test_beancount_deposit_and_dispute() {
+        let mut out = Vec::new();
+        let mut exporter =
+            LedgerExporter::new(&mut out, LedgerFormat::Beancount, AccountMapping::default());
+        exporter
+            .record(
+                &tx(TransactionType::Deposit, Some(86_400)),
+                &account(0, 0),
+                &account(10, 0),
+            )
+            .unwrap();
+        exporter
+            .record(
+                &tx(TransactionType::Dispute, Some(86_400)),
+                &account(10, 0),
+                &account(0, 10),
+            )
+            .unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(
+            text,
+            "\
+1970-01-02 open Assets:Bank
+1970-01-02 open Liabilities:Clients:7:Available
+1970-01-02 * \"deposit client 7 tx 3\"
+  Assets:Bank  10.0000 USD
+  Liabilities:Clients:7:Available  -10.0000 USD
+
+1970-01-02 open Liabilities:Clients:7:Held
+1970-01-02 * \"dispute client 7 tx 3\"
+  Liabilities:Clients:7:Available  10.0000 USD
+  Liabilities:Clients:7:Held  -10.0000 USD
+
+"
+        );
+    }
+
+    #[test]
+    fn test_transfer_moves_funds_between_clients() {
+        let mut out = Vec::new();
+        let mut exporter =
+            LedgerExporter::new(&mut out, LedgerFormat::Ledger, AccountMapping::default());
+        let transfer = Transaction {
+            to_client: Some(8),
+            ..tx(TransactionType::Transfer, None)
+        };
+        exporter
+            .record_changes(
+                &transfer,
+                &[
+                    (7, account(10, 0), account(6, 0)),
+                    (8, account(0, 0), account(4, 0)),
+                ],
+            )
+            .unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(
+            text,
+            "\
+1970/01/01 transfer client 7 tx 3
+  Liabilities:Clients:7:Available  4.0000 USD
+  Liabilities:Clients:8:Available  -4.0000 USD
+
+"
+        );
+    }
+
+    #[test]
+    fn test_ledger_skips_unchanged_and_uses_mapping() {
+        let mapping: AccountMapping = "external = Assets:Checking\ncommodity = EUR\n"
+            .parse()
+            .unwrap();
+        let mut out = Vec::new();
+        let mut exporter = LedgerExporter::new(&mut out, LedgerFormat::Ledger, mapping);
+        exporter
+            .record(
+                &tx(TransactionType::Withdrawal, None),
+                &account(5, 0),
+                &account(5, 0),
+            )
+            .unwrap();
+        exporter
+            .record(
+                &tx(TransactionType::Chargeback, None),
+                &account(0, 4),
+                &account(0, 0),
+            )
+            .unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(
+            text,
+            "\
+1970/01/01 chargeback client 7 tx 3
+  Assets:Checking  -4.0000 EUR
+  Liabilities:Clients:7:Held  4.0000 EUR
+
+"
+        );
+    }
+}
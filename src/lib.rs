@@ -1,5 +1,107 @@
+mod accounts;
+pub mod admin;
+pub mod alerts;
+pub mod archive;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "tokio")]
+pub mod async_engine;
+pub mod audit;
+pub mod cases;
+pub mod checkpoint;
+pub mod clock;
+pub mod compaction;
+pub mod compression;
+pub mod concurrent;
+pub mod config;
+#[cfg(feature = "io")]
+pub mod config_file;
+#[cfg(feature = "tui")]
+pub mod dashboard;
+#[cfg(feature = "io")]
+pub mod dedup;
 mod engine;
+pub mod event_log;
+pub mod events;
+pub mod fees;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod fixed_width;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod history;
+pub mod ignored;
+pub mod invariants;
+#[cfg(feature = "io")]
+pub mod io;
+#[cfg(feature = "iso20022")]
+pub mod iso20022;
+pub mod journal;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod ledger;
+pub mod limits;
+pub mod logging;
+pub mod merge;
+pub mod metrics;
+pub mod mock;
+pub mod netting;
+pub mod observer;
+mod outcome;
+#[cfg(feature = "rayon")]
+pub mod parallel;
+pub mod partition;
+pub mod processor;
+#[cfg(feature = "pyo3")]
+mod python;
+#[cfg(feature = "io")]
+pub mod reconcile;
+#[cfg(feature = "io")]
+pub mod schema;
+#[cfg(feature = "io")]
+pub mod server;
+#[cfg(feature = "io")]
+pub mod shadow;
+pub mod sharded;
+pub mod snapshot;
+#[cfg(feature = "io")]
+pub mod sort;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod store;
+mod timers;
+#[cfg(feature = "io")]
+pub mod transcript;
 mod types;
+#[cfg(feature = "io")]
+pub mod wal;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "xlsx")]
+pub mod xlsx;
 
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use config::{
+    AccountStorage, ArithmeticMode, BalanceLimits, ClientTiers, CompactionPolicy, DisputeExpiry,
+    DisputeShortfallPolicy, DormancyPolicy, EngineConfig, ExpiryAction, LockedPolicy,
+    OrderingPolicy, ReservePolicy,
+};
 pub use engine::Engine;
-pub use types::{Account, AccountOutput, Transaction, TransactionType, SCALE};
+pub use event_log::DomainEvent;
+pub use events::EngineEvent;
+pub use limits::RiskLimits;
+pub use observer::EngineObserver;
+pub use outcome::{
+    AmountError, BatchError, BatchRejected, MergeError, ProcessError, ProcessOutcome,
+};
+pub use processor::TransactionProcessor;
+pub use types::{
+    Account, AccountActivity, AccountOutput, ClientId, DEFAULT_SCALE, DisputeState, Hold,
+    MAX_SCALE, MinorUnitsTransaction, Money, ParseTransactionTypeError, Precision, Rounding, SCALE,
+    StoredKind, StoredTransaction, Transaction, TransactionType, TxId, format_fixed,
+    format_fixed_at, from_minor_units, to_fixed, to_fixed_at,
+};
@@ -0,0 +1,373 @@
+//! Velocity and risk limits on deposits, withdrawals and transfers.
+//!
+//! [`RiskLimits`] caps single amounts, the total a client takes out per day and the number
+//! of transactions a client makes within a sliding window. A transaction over a limit is
+//! rejected with [`ProcessError::AmountLimit`], [`ProcessError::DailyLimit`] or
+//! [`ProcessError::VelocityLimit`]. Times are transaction times: the row's timestamp, or
+//! the engine clock's; without either every transaction happens at time 0, so the daily
+//! total never resets and the window never slides.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+use crate::outcome::ProcessError;
+use crate::types::{DEFAULT_SCALE, Money, TransactionType};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// At most `count` transactions per client within any `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Velocity {
+    pub count: u32,
+    pub window: Duration,
+}
+
+/// Limits in fixed-point units. A limit left `None` is not checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RiskLimits {
+    /// Largest single deposit.
+    pub max_deposit: Option<Money>,
+    /// Largest single withdrawal or transfer.
+    pub max_withdrawal: Option<Money>,
+    /// Most a client may withdraw and transfer away per UTC day.
+    pub max_daily_withdrawal: Option<Money>,
+    /// Deposits, withdrawals and transfers a client may make per window.
+    pub velocity: Option<Velocity>,
+}
+
+impl RiskLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == RiskLimits::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::load_with_scale(path, DEFAULT_SCALE)
+    }
+
+    /// Like [`load`](Self::load), with amounts in `scale` decimal places.
+    pub fn load_with_scale<P: AsRef<Path>>(path: P, scale: u32) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::parse_with_scale(&fs::read_to_string(path)?, scale)?)
+    }
+
+    /// Checks a deposit, withdrawal or transfer of `amount` made at `now` by a client with
+    /// `state`. Other transaction types are never limited.
+    pub(crate) fn check(
+        &self,
+        tx_type: TransactionType,
+        amount: Money,
+        now: u64,
+        state: Option<&ClientVelocity>,
+    ) -> Result<(), ProcessError> {
+        let max_amount = match tx_type {
+            TransactionType::Deposit => self.max_deposit,
+            TransactionType::Withdrawal | TransactionType::Transfer => self.max_withdrawal,
+            _ => return Ok(()),
+        };
+        if max_amount.is_some_and(|max| amount > max) {
+            return Err(ProcessError::AmountLimit);
+        }
+        if tx_type != TransactionType::Deposit {
+            let withdrawn = state
+                .filter(|state| state.day == now / SECONDS_PER_DAY)
+                .map_or(Money::ZERO, |state| state.withdrawn);
+            if self
+                .max_daily_withdrawal
+                .is_some_and(|max| withdrawn.saturating_add(amount) > max)
+            {
+                return Err(ProcessError::DailyLimit);
+            }
+        }
+        if let Some(velocity) = self.velocity {
+            let window = velocity.window.as_secs();
+            let recent = state.map_or(0, |state| {
+                let in_window = |time: &&u64| time.saturating_add(window) > now;
+                state.recent.iter().filter(in_window).count()
+            });
+            if recent >= velocity.count as usize {
+                return Err(ProcessError::VelocityLimit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts an accepted transaction that [`RiskLimits::check`] allowed.
+    pub(crate) fn record(
+        &self,
+        tx_type: TransactionType,
+        amount: Money,
+        now: u64,
+        state: &mut ClientVelocity,
+    ) {
+        let limited = matches!(
+            tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        );
+        if !limited {
+            return;
+        }
+        if tx_type != TransactionType::Deposit && self.max_daily_withdrawal.is_some() {
+            let day = now / SECONDS_PER_DAY;
+            if state.day != day {
+                state.day = day;
+                state.withdrawn = Money::ZERO;
+            }
+            state.withdrawn = state.withdrawn.saturating_add(amount);
+        }
+        if let Some(velocity) = self.velocity {
+            let window = velocity.window.as_secs();
+            while state
+                .recent
+                .front()
+                .is_some_and(|&time| time.saturating_add(window) <= now)
+            {
+                state.recent.pop_front();
+            }
+            state.recent.push_back(now);
+        }
+    }
+}
+
+/// What the limits need to remember about one client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ClientVelocity {
+    /// Day (days since the epoch) `withdrawn` was counted on.
+    pub(crate) day: u64,
+    pub(crate) withdrawn: Money,
+    /// Times of the client's latest limited transactions, oldest first.
+    pub(crate) recent: VecDeque<u64>,
+}
+
+impl ClientVelocity {
+    /// Adds a merged client's counts to these.
+    pub(crate) fn absorb(&mut self, other: ClientVelocity) {
+        if other.day > self.day {
+            self.day = other.day;
+            self.withdrawn = other.withdrawn;
+        } else if other.day == self.day {
+            self.withdrawn = self.withdrawn.saturating_add(other.withdrawn);
+        }
+        self.recent.extend(other.recent);
+        self.recent.make_contiguous().sort_unstable();
+    }
+}
+
+#[derive(Debug)]
+pub struct LimitsError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LimitsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "limits file line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for LimitsError {}
+
+/// Parses a limits file of top-level TOML settings, one per line:
+///
+/// ```toml
+/// max_deposit = 10000
+/// max_withdrawal = "2500.50"
+/// max_daily_withdrawal = 5000
+/// max_transactions = 20
+/// velocity_window_secs = 3600
+/// ```
+///
+/// Amounts may be bare or quoted. `max_transactions` and `velocity_window_secs` go
+/// together. Blank lines and `#` comments are ignored.
+impl FromStr for RiskLimits {
+    type Err = LimitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_scale(s, DEFAULT_SCALE)
+    }
+}
+
+impl RiskLimits {
+    /// Parses a limits file like `from_str`, converting amounts to `scale` decimal places.
+    pub fn parse_with_scale(s: &str, scale: u32) -> Result<Self, LimitsError> {
+        let mut limits = RiskLimits::default();
+        let (mut count, mut window) = (None, None);
+        for (index, raw) in s.lines().enumerate() {
+            let err = |message: String| LimitsError {
+                line: index + 1,
+                message,
+            };
+            let content = raw.split('#').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+            let (key, value) = content
+                .split_once('=')
+                .ok_or_else(|| err(format!("expected key = value, got '{}'", content)))?;
+            let value = value.trim().trim_matches('"');
+            let amount = || {
+                Decimal::from_str(value)
+                    .ok()
+                    .filter(|amount| *amount > Decimal::ZERO)
+                    .map(|amount| Money::from_decimal_lossy(amount, scale))
+                    .ok_or_else(|| err(format!("invalid amount '{}'", value)))
+            };
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| err(format!("invalid number '{}'", value)))
+            };
+            match key.trim() {
+                "max_deposit" => limits.max_deposit = Some(amount()?),
+                "max_withdrawal" => limits.max_withdrawal = Some(amount()?),
+                "max_daily_withdrawal" => limits.max_daily_withdrawal = Some(amount()?),
+                "max_transactions" => {
+                    let n = number()?;
+                    count = Some(u32::try_from(n).map_err(|_| err(format!("too large: {}", n)))?);
+                }
+                "velocity_window_secs" => window = Some(Duration::from_secs(number()?)),
+                key => return Err(err(format!("unknown limit '{}'", key))),
+            }
+        }
+        limits.velocity = match (count, window) {
+            (Some(count), Some(window)) => Some(Velocity { count, window }),
+            (None, None) => None,
+            _ => {
+                return Err(LimitsError {
+                    line: 0,
+                    message: "max_transactions and velocity_window_secs go together".to_string(),
+                });
+            }
+        };
+        Ok(limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::engine::Engine;
+    use crate::outcome::ProcessOutcome;
+    use crate::types::{ClientId, SCALE, Transaction, TxId};
+
+    fn tx(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: TxId,
+        amount: i64,
+        time: u64,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: Some(Decimal::from(amount)),
+            timestamp: Some(time),
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_limits_file() {
+        let limits: RiskLimits = "# limits\nmax_deposit = 100\nmax_withdrawal = \"2.5\"\n\
+                                  max_transactions = 3 # per hour\nvelocity_window_secs = 3600\n"
+            .parse()
+            .unwrap();
+        assert_eq!(limits.max_deposit, Some(Money::from_units(100 * SCALE)));
+        assert_eq!(limits.max_withdrawal, Some(Money::from_units(25_000)));
+        assert_eq!(limits.max_daily_withdrawal, None);
+        assert_eq!(
+            limits.velocity,
+            Some(Velocity {
+                count: 3,
+                window: Duration::from_secs(3600)
+            })
+        );
+
+        let error = "max_deposit = 0".parse::<RiskLimits>().unwrap_err();
+        assert_eq!(error.to_string(), "limits file line 1: invalid amount '0'");
+        assert!("max_transactions = 3".parse::<RiskLimits>().is_err());
+        assert!("max_credit = 3".parse::<RiskLimits>().is_err());
+    }
+
+    #[test]
+    fn test_limits_reject_transactions() {
+        let limits = RiskLimits {
+            max_deposit: Some(Money::from_units(1000 * SCALE)),
+            max_withdrawal: Some(Money::from_units(300 * SCALE)),
+            max_daily_withdrawal: Some(Money::from_units(500 * SCALE)),
+            velocity: None,
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().with_limits(limits));
+        let day = SECONDS_PER_DAY;
+        use TransactionType::{Deposit, Withdrawal};
+
+        assert_eq!(
+            engine.process(tx(Deposit, 1, 1, 1001, 0)),
+            Err(ProcessError::AmountLimit)
+        );
+        engine.process(tx(Deposit, 1, 2, 1000, 0)).unwrap();
+        assert_eq!(
+            engine.process(tx(Withdrawal, 1, 3, 301, 10)),
+            Err(ProcessError::AmountLimit)
+        );
+        engine.process(tx(Withdrawal, 1, 4, 300, 20)).unwrap();
+        assert_eq!(
+            engine.process(tx(Withdrawal, 1, 5, 201, 30)),
+            Err(ProcessError::DailyLimit)
+        );
+        engine.process(tx(Withdrawal, 1, 6, 200, 40)).unwrap();
+        // A rolled-back withdrawal doesn't use up the next day's allowance.
+        engine.begin_batch();
+        engine.process(tx(Withdrawal, 1, 7, 300, day)).unwrap();
+        engine.rollback_batch();
+        engine.process(tx(Withdrawal, 1, 8, 300, day + 1)).unwrap();
+        engine.process(tx(Withdrawal, 1, 9, 200, day + 2)).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, Money::ZERO);
+    }
+
+    #[test]
+    fn test_velocity_window() {
+        let limits = RiskLimits {
+            velocity: Some(Velocity {
+                count: 2,
+                window: Duration::from_secs(60),
+            }),
+            ..RiskLimits::default()
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().with_limits(limits));
+        use TransactionType::Deposit;
+
+        engine.process(tx(Deposit, 1, 1, 1, 0)).unwrap();
+        engine.process(tx(Deposit, 1, 2, 1, 30)).unwrap();
+        assert_eq!(
+            engine.process(tx(Deposit, 1, 3, 1, 59)),
+            Err(ProcessError::VelocityLimit)
+        );
+        engine.process(tx(Deposit, 2, 4, 1, 59)).unwrap();
+        engine.process(tx(Deposit, 1, 5, 1, 60)).unwrap();
+        // Disputes are never limited, nor counted.
+        let dispute = Transaction {
+            amount: None,
+            ..tx(TransactionType::Dispute, 1, 5, 0, 61)
+        };
+        engine.process(dispute).unwrap();
+        assert_eq!(
+            engine.process(tx(Deposit, 1, 6, 1, 91)),
+            Ok(ProcessOutcome::Deposited)
+        );
+        assert_eq!(
+            engine.process(tx(Deposit, 1, 7, 1, 92)),
+            Err(ProcessError::VelocityLimit)
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Logger behind the `log` facade for the command-line tool.
+//!
+//! Diagnostics go to stderr either as `Level: message` lines for people or as one JSON
+//! object per line for log collectors. The library itself only uses the `log` macros, so
+//! embedding applications can install whatever logger they already use.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One object per line with `time`, `level`, `target` and `message` fields.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'", s)),
+        }
+    }
+}
+
+pub struct Logger<W> {
+    level: LevelFilter,
+    format: LogFormat,
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> Logger<W> {
+    pub fn new(writer: W, level: LevelFilter, format: LogFormat) -> Self {
+        Self {
+            level,
+            format,
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl Logger<io::Stderr> {
+    pub fn stderr(level: LevelFilter, format: LogFormat) -> Self {
+        Self::new(io::stderr(), level, format)
+    }
+}
+
+impl<W: Write + Send + 'static> Logger<W> {
+    /// Installs this logger as the global `log` logger. Fails if one is already set.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> Log for Logger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format_record(self.format, record, now);
+        if let Ok(mut writer) = self.writer.lock() {
+            // Nowhere left to report a failure to write a log line.
+            let _ = writeln!(writer, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+fn format_record(format: LogFormat, record: &Record, now: Duration) -> String {
+    match format {
+        LogFormat::Text => {
+            let label = match record.level() {
+                Level::Error => "Error",
+                Level::Warn => "Warning",
+                Level::Info => "Info",
+                Level::Debug => "Debug",
+                Level::Trace => "Trace",
+            };
+            format!("{}: {}", label, record.args())
+        }
+        LogFormat::Json => {
+            let mut line = format!(
+                "{{\"time\":{}.{:03},\"level\":\"{}\",\"target\":",
+                now.as_secs(),
+                now.subsec_millis(),
+                record.level().as_str().to_ascii_lowercase()
+            );
+            push_json_string(&mut line, record.target());
+            line.push_str(",\"message\":");
+            push_json_string(&mut line, &record.args().to_string());
+            line.push('}');
+            line
+        }
+    }
+}
+
+pub(crate) fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_and_json_lines() {
+        let now = Duration::from_millis(1_700_000_000_042);
+        let line = |format| {
+            format_record(
+                format,
+                &Record::builder()
+                    .level(Level::Warn)
+                    .target("tx_engine")
+                    .args(format_args!("tx {} rejected:\n\"locked\"", 7))
+                    .build(),
+                now,
+            )
+        };
+
+        assert_eq!(line(LogFormat::Text), "Warning: tx 7 rejected:\n\"locked\"");
+        assert_eq!(
+            line(LogFormat::Json),
+            r#"{"time":1700000000.042,"level":"warn","target":"tx_engine","message":"tx 7 rejected:\n\"locked\""}"#
+        );
+    }
+}
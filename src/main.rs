@@ -1,45 +1,2580 @@
 use std::env;
 use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::File;
-use std::io;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use csv::{ReaderBuilder, Trim, Writer};
+use clap::error::ErrorKind;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use csv::{ReaderBuilder, Trim};
+use log::{LevelFilter, debug, error, info, warn};
+use rust_decimal::Decimal;
 
-use tx_engine::{Engine, Transaction};
+use tx_engine::admin::AdminOp;
+use tx_engine::alerts::{AlertMonitor, AlertRule, LoggerSink, NotificationBus, WebhookSink};
+use tx_engine::archive::FileArchive;
+use tx_engine::audit::{AuditEvent, AuditLog};
+use tx_engine::cases;
+use tx_engine::checkpoint::Checkpointer;
+use tx_engine::compression;
+use tx_engine::config_file::ConfigFile;
+use tx_engine::dedup;
+use tx_engine::filter::ClientFilter;
+use tx_engine::fixed_width::{FixedWidthReader, Layout};
+use tx_engine::history;
+use tx_engine::ignored::{IgnoredCounts, IgnoredLog};
+use tx_engine::io::{
+    ColumnMap, CsvError, CsvReader, ErrorLog, ErrorPolicy, Input, InputLimit, InputLimits, Progress,
+};
+use tx_engine::journal::{JournalReader, JournalWriter};
+use tx_engine::ledger::{AccountMapping, LedgerExporter, LedgerFormat};
+use tx_engine::logging::{LogFormat, Logger};
+use tx_engine::merge;
+use tx_engine::netting;
+use tx_engine::reconcile;
+use tx_engine::schema::{self, Manifest, OutputFormat, OutputSchema, ReportOptions};
+use tx_engine::server::Server;
+use tx_engine::shadow::ShadowRunner;
+use tx_engine::sharded::ShardedEngine;
+use tx_engine::sort::{self, SortKey};
+#[cfg(feature = "sqlite")]
+use tx_engine::sqlite::SqliteStore;
+use tx_engine::stats::EngineStats;
+use tx_engine::store::FileStore;
+#[cfg(feature = "sqlite")]
+use tx_engine::store::StateStore;
+use tx_engine::transcript::{self, TranscriptWriter};
+use tx_engine::{
+    Account, AccountOutput, AccountStorage, ArithmeticMode, ClientId, DEFAULT_SCALE,
+    DisputeShortfallPolicy, Engine, EngineConfig, EngineEvent, ExpiryAction, LockedPolicy,
+    MAX_SCALE, MinorUnitsTransaction, Money, OrderingPolicy, ProcessError, Rounding, StoredKind,
+    StoredTransaction, SystemClock, Transaction, TransactionType, TxId,
+};
 
-fn run(input_path: &str) -> Result<(), Box<dyn Error>> {
-    let file = File::open(input_path)?;
-    let mut reader = ReaderBuilder::new()
-        .trim(Trim::All)
-        .flexible(true)
-        .from_reader(file);
+/// Format of the input file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InputFormat {
+    Csv,
+    Journal,
+    /// Needs `--layout`.
+    FixedWidth,
+    Xlsx,
+    Iso20022,
+    Parquet,
+}
+
+/// Where the time of rows without a timestamp, and of timers, comes from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ClockSource {
+    /// The latest timestamp seen in the input.
+    #[default]
+    Input,
+    /// The wall clock.
+    System,
+}
+
+/// How amounts in CSV input are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AmountFormat {
+    /// Decimal numbers, e.g. `12.3456`.
+    #[default]
+    Decimal,
+    /// Integers in minor units, e.g. `123456` for `12.3456`.
+    MinorUnits,
+}
+
+/// Processes payment transactions and prints the resulting client accounts.
+#[derive(Debug, Parser)]
+#[command(name = "tx-engine", version, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Input files, read one after another into the same engine; `-` is stdin.
+    #[arg(value_name = "INPUT", required = true)]
+    inputs: Vec<String>,
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+enum Command {
+    /// Process the input and print the account report, as without a command.
+    Process {
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Parse the input without processing it, listing malformed rows.
+    Validate {
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Process the input and print the engine's statistics instead of the report.
+    Stats {
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Convert the input into a binary journal.
+    ExportJournal {
+        /// The CSV or fixed-width input; `-` is stdin.
+        input: String,
+        /// The journal to write.
+        output: String,
+    },
+    /// Process binary journals and print the account report.
+    ImportJournal {
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Sort the CSV input into `output` without loading it all into memory.
+    Sort {
+        /// The CSV file to sort; `-` is stdin.
+        input: String,
+        /// Where the sorted rows go.
+        output: String,
+        /// Sort by `tx`, or by client then transaction with `client-tx`.
+        #[arg(long, value_name = "tx|client-tx", default_value = "tx")]
+        by: SortKey,
+        /// Records held in memory per sorted run.
+        #[arg(long, value_name = "N", default_value_t = SORT_ROWS, value_parser = positive::<usize>)]
+        max_rows: usize,
+    },
+    /// Process several CSV files, each already sorted, as one merged stream.
+    Merge {
+        #[arg(value_name = "SORTED", required = true, num_args = 2..)]
+        inputs: Vec<String>,
+    },
+    /// Process the input, then merge client `from` into client `into` before reporting.
+    MergeClients {
+        /// The client merged away.
+        #[arg(value_parser = parse_client)]
+        from: ClientId,
+        /// The client receiving `from`'s account.
+        #[arg(value_parser = parse_client)]
+        into: ClientId,
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Process the input and print the statement of one client.
+    Statement {
+        #[arg(value_parser = parse_client)]
+        client: ClientId,
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Process the input and print one client's balances as they were at a given time.
+    BalanceAt {
+        #[arg(value_parser = parse_client)]
+        client: ClientId,
+        /// Seconds since the Unix epoch.
+        timestamp: u64,
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Process the input and save the engine's state to a SQLite database.
+    ExportDb {
+        /// The SQLite database to write.
+        path: String,
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Process the input and save the engine's state as a binary snapshot.
+    Snapshot {
+        /// The transactions to process; `-` is stdin.
+        input: String,
+        /// The snapshot to write.
+        output: String,
+    },
+    /// Compare two account reports and list the clients whose rows differ.
+    Diff {
+        /// The reference report.
+        expected: String,
+        /// The report checked against it.
+        actual: String,
+    },
+    /// Replay a transcript, checking that it reproduces the recorded results and accounts,
+    /// and print the account report.
+    Replay { transcript: String },
+    /// Run the input through the `--config-a` and `--config-b` engines side by side and list
+    /// where their results differ.
+    Shadow {
+        /// The engine configuration the candidate is compared against.
+        #[arg(long, value_name = "FILE")]
+        config_a: String,
+        /// The engine configuration compared against `--config-a`.
+        #[arg(long, value_name = "FILE")]
+        config_b: String,
+        #[arg(value_name = "INPUT", required = true)]
+        inputs: Vec<String>,
+    },
+    /// Run the HTTP API on `address`.
+    Serve {
+        /// The address to listen on, e.g. `127.0.0.1:8080`.
+        address: String,
+        /// Speak gRPC instead of HTTP.
+        #[arg(long)]
+        grpc: bool,
+    },
+}
+
+impl Command {
+    /// Whether the command reads the engine's transaction history.
+    fn needs_history(&self) -> bool {
+        matches!(self, Command::Statement { .. } | Command::BalanceAt { .. })
+    }
+
+    /// The files read into the engine. `merge` reads its inputs itself, and `diff` and
+    /// `serve` read none.
+    fn inputs(&self) -> &[String] {
+        match self {
+            Command::Process { inputs }
+            | Command::Validate { inputs }
+            | Command::Stats { inputs }
+            | Command::ImportJournal { inputs }
+            | Command::MergeClients { inputs, .. }
+            | Command::Statement { inputs, .. }
+            | Command::BalanceAt { inputs, .. }
+            | Command::ExportDb { inputs, .. }
+            | Command::Shadow { inputs, .. } => inputs,
+            Command::ExportJournal { input, .. }
+            | Command::Sort { input, .. }
+            | Command::Snapshot { input, .. } => slice::from_ref(input),
+            Command::Replay { transcript } => slice::from_ref(transcript),
+            Command::Merge { .. } | Command::Diff { .. } | Command::Serve { .. } => &[],
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct Options {
+    #[arg(skip = Command::Process { inputs: Vec::new() })]
+    command: Command,
+    /// Input format, overriding the one picked from the file extension and `--layout`.
+    #[arg(long, global = true)]
+    input_format: Option<InputFormat>,
+    /// Layout file for fixed-width input; when set the input is not treated as CSV.
+    #[arg(long, global = true, value_name = "FILE")]
+    layout: Option<String>,
+    /// Where the report goes instead of stdout.
+    #[arg(short, long, global = true, value_name = "FILE")]
+    output: Option<String>,
+    /// Engine settings, which the other options override.
+    #[arg(long, global = true, value_name = "FILE", value_parser = load_config)]
+    config: Option<ConfigFile>,
+    /// The configuration `shadow` compares against `--config-a`, its scale resolved.
+    #[arg(skip)]
+    config_b: Option<ConfigFile>,
+    /// Alert when an account's available balance falls below this.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    alert_below: Option<Decimal>,
+    /// Alert when an account's available balance drops by more than this from its peak.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    alert_drop: Option<Decimal>,
+    /// Also post alerts to this URL; may be repeated.
+    #[arg(long = "alert-webhook", global = true, value_name = "URL")]
+    alert_webhooks: Vec<String>,
+    /// Fraction of each deposit held in reserve.
+    #[arg(long, global = true, value_name = "FRACTION", value_parser = parse_amount)]
+    reserve_rate: Option<Decimal>,
+    /// Days a deposit's reserve is held for.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_days)]
+    reserve_days: Option<u64>,
+    /// Flat fee charged on each withdrawal.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    withdrawal_fee: Option<Decimal>,
+    /// Fee charged on each withdrawal, as a fraction of its amount.
+    #[arg(long, global = true, value_name = "FRACTION", value_parser = parse_amount)]
+    withdrawal_fee_rate: Option<Decimal>,
+    /// The client receiving withdrawal fees.
+    #[arg(long, global = true, value_name = "CLIENT", value_parser = parse_client)]
+    fee_account: Option<ClientId>,
+    /// Interest paid on available balances per `--interest-days`.
+    #[arg(long, global = true, value_name = "FRACTION", value_parser = parse_amount)]
+    interest_rate: Option<Decimal>,
+    /// The interest rate's period; a year by default.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_days)]
+    interest_days: Option<u64>,
+    /// Unlock accounts this many days after their chargeback.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_days)]
+    unlock_after_days: Option<u64>,
+    /// Unlock an account when its chargeback is reversed.
+    #[arg(long, global = true)]
+    unlock_on_reversal: bool,
+    /// What a locked account still accepts.
+    #[arg(
+        long,
+        global = true,
+        value_name = "reject-all|allow-disputes|allow-credits"
+    )]
+    locked_policy: Option<LockedPolicy>,
+    /// Days after which an open dispute expires.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_days)]
+    dispute_expiry_days: Option<u64>,
+    /// Days after a transaction within which it may be disputed.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_days)]
+    dispute_window_days: Option<u64>,
+    /// What a dispute holding more than is available does.
+    #[arg(long, global = true, value_name = "allow-negative|reject|hold-partial")]
+    dispute_shortfall: Option<DisputeShortfallPolicy>,
+    /// What happens to an expired dispute.
+    #[arg(long, global = true, value_name = "resolve|escalate")]
+    dispute_expiry_action: Option<ExpiryAction>,
+    /// Warn about deposit, withdrawal and transfer ids reused later, accepted or not.
+    #[arg(long, global = true)]
+    detect_id_collisions: bool,
+    /// What to do with a transaction timestamped before the client's latest one.
+    #[arg(long, global = true, value_name = "ignore|flag|reject")]
+    ordering: Option<OrderingPolicy>,
+    /// Add each account's last activity timestamp to the report.
+    #[arg(long, global = true)]
+    last_activity: bool,
+    /// Add each account's `closed` and `overdrawn` flags and activity columns to the report.
+    #[arg(long, global = true, visible_alias = "extended")]
+    extended_output: bool,
+    /// Log how many transactions were ignored, by reason.
+    #[arg(long, global = true)]
+    ignored_summary: bool,
+    /// Where to write the ignored transactions; implies `--ignored-summary`.
+    #[arg(long, global = true, value_name = "FILE")]
+    ignored_report: Option<String>,
+    /// Where to write the open dispute cases after the report.
+    #[arg(long, global = true, value_name = "FILE", conflicts_with_all = ["checkpoint_dir", "load_snapshot"])]
+    disputes_report: Option<String>,
+    /// Where to write the instructions settling the net positions of the transfers after the
+    /// report.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        conflicts_with = "compact_every"
+    )]
+    settlement_out: Option<String>,
+    /// Process the input without writing the report or any other file, printing the
+    /// transactions that were rejected instead.
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = [
+            "ignored_report",
+            "disputes_report",
+            "settlement_out",
+            "error_report",
+            "dedup_report",
+            "ledger",
+            "audit_log",
+            "transcript",
+            "checkpoint_dir",
+            "transaction_store",
+            "archive_dormant",
+            "manifest",
+            "alert_webhooks",
+            "tui",
+        ]
+    )]
+    dry_run: bool,
+    /// Print the engine's processing statistics when done.
+    #[arg(long, global = true)]
+    stats: bool,
+    /// Stop at the first rejected or malformed transaction.
+    #[arg(long, global = true, visible_alias = "strict")]
+    fail_fast: bool,
+    /// Read `lock`, `unlock`, `adjust` and `close` rows from CSV input.
+    #[arg(long, global = true)]
+    allow_admin: bool,
+    /// What to do with malformed CSV rows.
+    #[arg(
+        long,
+        global = true,
+        value_name = "abort|skip|report",
+        default_value = "abort"
+    )]
+    on_error: ErrorPolicy,
+    /// Where `--on-error report` writes malformed rows; implies it.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        required_if_eq("on_error", "report")
+    )]
+    error_report: Option<String>,
+    /// Stop with an error once the engine's tables outgrow this.
+    #[arg(long, global = true, value_name = "BYTES[K|M|G]", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// Longest CSV line read.
+    #[arg(long, global = true, value_name = "BYTES[K|M|G]", value_parser = parse_size)]
+    max_row_bytes: Option<usize>,
+    /// Distinct clients CSV input may involve.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_count::<usize>)]
+    max_clients: Option<usize>,
+    /// Deposit, withdrawal and transfer rows CSV input may hold.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_count::<u64>)]
+    max_transactions: Option<u64>,
+    /// Largest amount of a CSV row, of either sign.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    max_input_amount: Option<Decimal>,
+    /// File holding stored transactions instead of memory.
+    #[arg(long, global = true, value_name = "FILE")]
+    transaction_store: Option<String>,
+    /// How amounts in CSV input are written.
+    #[arg(long, global = true, default_value = "decimal")]
+    amount_format: AmountFormat,
+    /// Parse plain CSV rows by hand instead of with serde.
+    #[arg(long, global = true)]
+    fast: bool,
+    /// Header names of CSV input.
+    #[arg(long, global = true, value_name = "COLUMN=HEADER,...")]
+    columns: Option<ColumnMap>,
+    /// The columns of CSV input without a header row.
+    #[arg(long, global = true, value_name = "COLUMN,...", value_delimiter = ',', value_parser = parse_name)]
+    no_header: Option<Vec<String>>,
+    /// Where the time of rows without a timestamp, and of timers, comes from.
+    #[arg(long, global = true, default_value = "input")]
+    clock: ClockSource,
+    /// Columns of the report.
+    #[arg(long, global = true, value_name = "v1|v2", default_value = "v1")]
+    output_schema: OutputSchema,
+    /// Format of the report.
+    #[arg(
+        long,
+        global = true,
+        value_name = "csv|tsv|json|ndjson|parquet",
+        default_value = "csv"
+    )]
+    output_format: OutputFormat,
+    /// Fills the `v2` currency column.
+    #[arg(long, global = true, value_name = "CODE")]
+    currency: Option<String>,
+    /// Leave dormant accounts out of the report.
+    #[arg(long, global = true)]
+    exclude_dormant: bool,
+    /// Only process and report these clients, e.g. `1,2,5-10`.
+    #[arg(long, global = true, value_name = "IDS")]
+    clients: Option<ClientFilter>,
+    /// Days without activity after which an account is dormant.
+    #[arg(long, global = true, value_name = "N", value_parser = parse_days)]
+    dormant_after_days: Option<u64>,
+    /// Transactions of other clients after which an inactive account is dormant.
+    #[arg(long, global = true, value_name = "N", value_parser = positive::<u64>)]
+    dormant_after_txs: Option<u64>,
+    /// Side file receiving dormant accounts, which are then dropped from memory.
+    #[arg(long, global = true, value_name = "FILE")]
+    archive_dormant: Option<String>,
+    /// Compact stored transactions every so many rows.
+    #[arg(long, global = true, value_name = "N", value_parser = positive::<u64>)]
+    compact_every: Option<u64>,
+    /// Compact charged-back transactions too.
+    #[arg(long, global = true)]
+    compact_charged_back: bool,
+    /// Directory receiving periodic checkpoints of the engine.
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        conflicts_with = "archive_dormant"
+    )]
+    checkpoint_dir: Option<String>,
+    /// Transactions between checkpoints.
+    #[arg(long, global = true, value_name = "N", default_value_t = CHECKPOINT_EVERY, value_parser = positive::<u64>)]
+    checkpoint_every: u64,
+    /// Resume from the latest checkpoint in `--checkpoint-dir`.
+    #[arg(
+        long,
+        global = true,
+        requires = "checkpoint_dir",
+        conflicts_with = "load_snapshot"
+    )]
+    resume: bool,
+    /// Binary snapshot to load into the engine before processing.
+    #[arg(long, global = true, value_name = "FILE")]
+    load_snapshot: Option<String>,
+    /// Where to write the JSON manifest describing the report.
+    #[arg(long, global = true, value_name = "FILE")]
+    manifest: Option<String>,
+    /// What arithmetic overflow does.
+    #[arg(long, global = true, value_name = "saturating|checked|freeze")]
+    arithmetic: Option<ArithmeticMode>,
+    /// Check the engine's invariants after every transaction.
+    #[arg(long, global = true)]
+    check_invariants: bool,
+    /// How accounts are stored.
+    #[arg(long, global = true, value_name = "map|dense")]
+    account_storage: Option<AccountStorage>,
+    /// Decimal places of balances and output amounts; the configuration file's, else 4, by
+    /// default.
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(..=i64::from(MAX_SCALE)))]
+    scale: Option<u32>,
+    /// What to do with amounts finer than the scale.
+    #[arg(long, global = true, value_name = "truncate|half-even|reject")]
+    rounding: Option<Rounding>,
+    /// Largest amount of a transaction.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    max_amount: Option<Decimal>,
+    /// Lowest balance an account may reach.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    balance_floor: Option<Decimal>,
+    /// Highest balance an account may reach.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    balance_ceiling: Option<Decimal>,
+    /// How far below zero a withdrawal may take an account.
+    #[arg(long, global = true, value_name = "AMOUNT", value_parser = parse_amount)]
+    overdraft: Option<Decimal>,
+    /// File of client tiers and their limits.
+    #[arg(long, global = true, value_name = "FILE")]
+    tiers: Option<String>,
+    /// File of per-client amount, daily and frequency limits.
+    #[arg(long, global = true, value_name = "FILE")]
+    limits: Option<String>,
+    /// Where to write the applied transactions as accounting entries.
+    #[arg(long, global = true, value_name = "FILE")]
+    ledger: Option<String>,
+    /// Format of the `--ledger` entries.
+    #[arg(long, global = true, value_name = "beancount|ledger")]
+    ledger_format: Option<LedgerFormat>,
+    /// Account names of the `--ledger` entries.
+    #[arg(long, global = true, value_name = "FILE")]
+    ledger_accounts: Option<String>,
+    /// Where to write the audit trail of account changes.
+    #[arg(long, global = true, value_name = "FILE")]
+    audit_log: Option<String>,
+    /// Where to write the transcript of the run.
+    #[arg(long, global = true, value_name = "FILE")]
+    transcript: Option<String>,
+    /// Remove repeated deposit/withdrawal ids from CSV input before processing.
+    #[arg(long, global = true, conflicts_with_all = ["columns", "no_header"])]
+    dedup: bool,
+    /// Where to write the removed duplicates; implies `--dedup`.
+    #[arg(long, global = true, value_name = "FILE", conflicts_with_all = ["columns", "no_header"])]
+    dedup_report: Option<String>,
+    /// Only log errors.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Log debug messages; twice, trace messages too.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Format of the log on stderr.
+    #[arg(long, global = true, value_name = "text|json", default_value = "text")]
+    log_format: LogFormat,
+    /// Show a live dashboard on stderr while processing.
+    #[arg(long, global = true)]
+    tui: bool,
+    /// Show a progress bar on stderr while processing.
+    #[arg(long, global = true, conflicts_with = "tui")]
+    progress: bool,
+    /// Worker threads; more than one processes the input with a `ShardedEngine`.
+    #[arg(long, global = true, value_name = "N", default_value_t = 1, value_parser = positive::<usize>)]
+    threads: usize,
+}
+
+impl Options {
+    /// Input files, read one after another into the same engine; `-` is stdin.
+    fn inputs(&self) -> &[String] {
+        self.command.inputs()
+    }
+
+    /// The `--input-format`, or else the one implied by the command, `--layout` and the
+    /// extension of `path`, less any `.gz` or `.zst`.
+    fn input_format(&self, path: &str) -> InputFormat {
+        let path = compression::strip_extension(path);
+        if let Some(format) = self.input_format {
+            format
+        } else if matches!(self.command, Command::ImportJournal { .. })
+            || has_extension(path, "txj")
+        {
+            InputFormat::Journal
+        } else if self.layout.is_some() {
+            InputFormat::FixedWidth
+        } else if has_extension(path, "xlsx") {
+            InputFormat::Xlsx
+        } else if has_extension(path, "xml") {
+            InputFormat::Iso20022
+        } else if has_extension(path, "parquet") {
+            InputFormat::Parquet
+        } else {
+            InputFormat::Csv
+        }
+    }
+
+    /// How CSV columns are read, from `--columns` and `--no-header`.
+    fn column_map(&self) -> ColumnMap {
+        let columns = self.columns.clone().unwrap_or_default();
+        match &self.no_header {
+            Some(names) => columns.with_positions(names.clone()),
+            None => columns,
+        }
+    }
+
+    /// Engine settings read from `--config`, if given.
+    fn config_file(&self) -> ConfigFile {
+        self.config.clone().unwrap_or_default()
+    }
+
+    /// Decimal places of balances and output amounts: `--scale`, else the configuration
+    /// file's, else 4.
+    fn scale(&self) -> u32 {
+        self.scale
+            .or(self.config.as_ref().and_then(|file| file.scale))
+            .unwrap_or(DEFAULT_SCALE)
+    }
+
+    /// Bounds on what CSV input may contain.
+    fn input_limits(&self) -> InputLimits {
+        InputLimits {
+            max_row_bytes: self.max_row_bytes,
+            max_clients: self.max_clients,
+            max_transactions: self.max_transactions,
+            max_amount: self.max_input_amount,
+        }
+    }
+
+    /// CSV amounts are integers in minor units (`123456` is `12.3456`).
+    fn minor_units(&self) -> bool {
+        self.amount_format == AmountFormat::MinorUnits
+    }
+
+    /// Use the wall clock for rows without a timestamp and to fire timers.
+    fn system_clock(&self) -> bool {
+        self.clock == ClockSource::System
+    }
+
+    fn report(&self) -> ReportOptions {
+        ReportOptions {
+            schema: self.output_schema,
+            format: self.output_format,
+            currency: self.currency.clone().unwrap_or_default(),
+            exclude_dormant: self.exclude_dormant,
+            clients: self.clients.clone(),
+        }
+    }
+
+    fn log_level(&self) -> LevelFilter {
+        // Anything else printed while the dashboard is drawn would tear it.
+        match (self.quiet || self.tui, self.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Info,
+            (false, 1) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Dormant accounts are moved to the archive every this many transactions.
+const ARCHIVE_EVERY: u64 = 100_000;
+
+/// Default number of transactions between checkpoints.
+const CHECKPOINT_EVERY: u64 = 1_000_000;
+
+/// Default number of records `sort` holds in memory per run.
+const SORT_ROWS: usize = 1_000_000;
+
+fn parse_args<I, T>(args: I) -> Result<Options, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let invalid = |message: String| Cli::command().error(ErrorKind::ValueValidation, message);
+    let conflict = |message: String| Cli::command().error(ErrorKind::ArgumentConflict, message);
+    let cli = Cli::try_parse_from(args)?;
+    let mut options = cli.options;
+    options.command = cli
+        .command
+        .unwrap_or(Command::Process { inputs: cli.inputs });
+    if let Command::Shadow {
+        config_a, config_b, ..
+    } = &options.command
+    {
+        if options.config.is_some() {
+            return Err(conflict(
+                "--config cannot be used with shadow, which takes --config-a".to_string(),
+            ));
+        }
+        options.config = Some(load_config(config_a).map_err(invalid)?);
+        options.config_b = Some(load_config(config_b).map_err(invalid)?);
+    }
+    options.ignored_summary |= options.ignored_report.is_some();
+    options.dedup |= options.dedup_report.is_some();
+    if options.error_report.is_some() {
+        options.on_error = ErrorPolicy::Report;
+    }
+
+    let scale = options.scale;
+    let resolve_scale = |file_scale: Option<u32>| match scale.or(file_scale) {
+        Some(scale) if scale > MAX_SCALE => Err(invalid(format!(
+            "invalid scale {}, expected 0 to {}",
+            scale, MAX_SCALE
+        ))),
+        scale => Ok(scale.unwrap_or(DEFAULT_SCALE)),
+    };
+    resolve_scale(options.config.as_ref().and_then(|file| file.scale))?;
+    if let Some(file) = &mut options.config_b {
+        file.scale = Some(resolve_scale(file.scale)?);
+    }
+    if options.checkpoint_dir.is_some() && options.command.needs_history() {
+        return Err(conflict(
+            "--checkpoint-dir cannot be used with statement or balance-at".to_string(),
+        ));
+    }
+    if options.dry_run && !matches!(options.command, Command::Process { .. }) {
+        return Err(conflict(
+            "--dry-run cannot be used with a command".to_string(),
+        ));
+    }
+    if options.dry_run && options.threads > 1 {
+        return Err(conflict(
+            "--dry-run cannot be combined with --threads".to_string(),
+        ));
+    }
+    if let Some(option) = transcript_conflict(&options).filter(|_| options.transcript.is_some()) {
+        return Err(conflict(format!(
+            "--transcript cannot be combined with {}",
+            option
+        )));
+    }
+    #[cfg(feature = "arrow")]
+    if options.output_format == OutputFormat::Parquet && options.output_schema != OutputSchema::V1 {
+        return Err(conflict(
+            "--output-format parquet only supports --output-schema v1".to_string(),
+        ));
+    }
+    Ok(options)
+}
+
+fn engine_config(options: &Options) -> Result<EngineConfig, Box<dyn Error>> {
+    if options.overdraft.is_some_and(|limit| limit < Decimal::ZERO) {
+        return Err("--overdraft must not be negative".into());
+    }
+    let out_of_range = |rate: &Decimal| *rate < Decimal::ZERO || *rate > Decimal::ONE;
+    if let Some(rate) = options.reserve_rate.filter(out_of_range) {
+        return Err(format!("--reserve-rate must be between 0 and 1, got {}", rate).into());
+    }
+    if let Some(rate) = options.interest_rate.filter(|rate| *rate < Decimal::ZERO) {
+        return Err(format!("--interest-rate must not be negative, got {}", rate).into());
+    }
+
+    engine_config_from(options, options.config_file(), options.scale())
+}
+
+/// The engine configuration of `file` at `scale`, overridden by the engine options given on
+/// the command line.
+fn engine_config_from(
+    options: &Options,
+    mut file: ConfigFile,
+    scale: u32,
+) -> Result<EngineConfig, Box<dyn Error>> {
+    file.scale = Some(scale);
+    file.rounding = options.rounding.or(file.rounding);
+    file.arithmetic = options.arithmetic.or(file.arithmetic);
+    file.ordering = options.ordering.or(file.ordering);
+    file.account_storage = options.account_storage.or(file.account_storage);
+    file.max_amount = options.max_amount.or(file.max_amount);
+    file.memory_limit = options.max_memory.or(file.memory_limit);
+    file.detect_id_collisions |= options.detect_id_collisions;
+    file.check_invariants |= options.check_invariants;
+    file.last_activity |= options.last_activity;
+    file.extended_output |= options.extended_output;
+    if let Some(path) = &options.tiers {
+        file.tiers = Some(path.into());
+    }
+    if let Some(path) = &options.limits {
+        file.limits = Some(path.into());
+    }
+    let dispute = &mut file.dispute;
+    dispute.shortfall = options.dispute_shortfall.or(dispute.shortfall);
+    dispute.window_days = options.dispute_window_days.or(dispute.window_days);
+    dispute.expiry_days = options.dispute_expiry_days.or(dispute.expiry_days);
+    dispute.expiry_action = options.dispute_expiry_action.or(dispute.expiry_action);
+    let locked = &mut file.locked;
+    locked.unlock_after_days = options.unlock_after_days.or(locked.unlock_after_days);
+    locked.unlock_on_reversal |= options.unlock_on_reversal;
+    locked.policy = options.locked_policy.or(locked.policy);
+    let balance = &mut file.balance;
+    balance.floor = options.balance_floor.or(balance.floor);
+    balance.ceiling = options.balance_ceiling.or(balance.ceiling);
+    balance.overdraft = options.overdraft.or(balance.overdraft);
+    file.reserve.rate = options.reserve_rate.or(file.reserve.rate);
+    file.reserve.days = options.reserve_days.or(file.reserve.days);
+    let fees = &mut file.fees;
+    fees.flat = options.withdrawal_fee.or(fees.flat);
+    fees.rate = options.withdrawal_fee_rate.or(fees.rate);
+    fees.account = options.fee_account.or(fees.account);
+    if (fees.flat.is_some() || fees.rate.is_some()) && fees.account.is_none() {
+        return Err(
+            "--withdrawal-fee and --withdrawal-fee-rate need --fee-account <client>".into(),
+        );
+    }
+    file.interest.rate = options.interest_rate.or(file.interest.rate);
+    file.interest.days = options.interest_days.or(file.interest.days);
+    let dormancy = &mut file.dormancy;
+    dormancy.after_days = options.dormant_after_days.or(dormancy.after_days);
+    dormancy.after_txs = options.dormant_after_txs.or(dormancy.after_txs);
+    let compaction = &mut file.compaction;
+    compaction.every = options.compact_every.or(compaction.every);
+    compaction.charged_back |= options.compact_charged_back;
+    if compaction.charged_back && compaction.every.is_none() {
+        return Err("--compact-charged-back needs --compact-every".into());
+    }
+
+    let mut config = file.into_config()?;
+    if options.command.needs_history() {
+        config = config.with_history();
+    }
+    if options.disputes_report.is_some() {
+        config = config.with_dispute_cases();
+    }
+    if config.dormancy.is_none() && (options.archive_dormant.is_some() || options.exclude_dormant) {
+        return Err("--archive-dormant and --exclude-dormant need a dormancy threshold".into());
+    }
+    Ok(config)
+}
+
+fn parse_days(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number of days '{}'", value))
+}
+
+/// Parses a byte count with an optional binary `K`, `M` or `G` suffix, e.g. `512M`.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid size '{}'", value);
+    let (digits, unit) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    let count: usize = digits.parse().map_err(|_| invalid())?;
+    count.checked_mul(unit).ok_or_else(invalid)
+}
+
+fn parse_count<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid count '{}'", value))
+}
+
+/// Parses a count of at least 1.
+fn positive<T: FromStr + PartialOrd + From<u8>>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|count| *count >= T::from(1))
+        .ok_or_else(|| format!("invalid count '{}', expected at least 1", value))
+}
+
+/// A column name of `--no-header`, less surrounding whitespace.
+fn parse_name(value: &str) -> Result<String, String> {
+    Ok(value.trim().to_string())
+}
+
+fn load_config(path: &str) -> Result<ConfigFile, String> {
+    ConfigFile::load(path).map_err(|e| format!("config file {}: {}", path, e))
+}
+
+fn format_size(bytes: usize) -> String {
+    let units = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+    match units.iter().find(|&&(_, size)| bytes >= size) {
+        Some((unit, size)) => format!("{:.1} {}", bytes as f64 / *size as f64, unit),
+        None => format!("{} B", bytes),
+    }
+}
+
+fn parse_client(value: &str) -> Result<ClientId, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid client id '{}'", value))
+}
 
-    let mut engine = Engine::new();
+fn parse_amount(value: &str) -> Result<Decimal, String> {
+    Decimal::from_str(value).map_err(|e| format!("invalid amount '{}': {}", value, e))
+}
+
+/// Engine plus the optional per-transaction hooks enabled on the command line.
+struct Session {
+    engine: Engine,
+    alerts: Option<AlertMonitor>,
+    ledger: Option<LedgerExporter<BufWriter<File>>>,
+    ignored: Option<IgnoredCounts>,
+    ignored_log: Option<IgnoredLog<Box<dyn Write>>>,
+    audit: Option<AuditTrail>,
+    /// Number of transactions applied so far.
+    rows: u64,
+    stats: bool,
+    fail_fast: bool,
+    /// The open batch, between a `begin` and a `commit` marker.
+    batch: Option<Batch>,
+    dashboard: Option<Dashboard>,
+    progress: Option<ProgressBar>,
+    checkpointer: Option<Checkpointer>,
+    /// Transactions still to skip, already covered by the checkpoint resumed from.
+    skip: u64,
+    transcript: Option<TranscriptWriter<BufWriter<File>>>,
+    /// Why the input being applied was rejected, for the transcript.
+    rejection: Option<ProcessError>,
+}
+
+/// Audit records delivered by the engine, written out after each input item.
+struct AuditTrail {
+    pending: Arc<Mutex<Vec<AuditEvent>>>,
+    log: AuditLog<BufWriter<File>>,
+}
+
+impl AuditTrail {
+    fn write_pending(&mut self) -> io::Result<()> {
+        let pending = mem::take(&mut *self.pending.lock().expect("audit sink never panics"));
+        for event in &pending {
+            self.log.record(event)?;
+        }
+        Ok(())
+    }
+}
+
+/// Effects of a batch's accepted transactions, reported to the hooks only once it commits.
+#[derive(Default)]
+struct Batch {
+    effects: Vec<Effect>,
+    /// A member was rejected and the batch rolled back; the rest of it is skipped.
+    failed: bool,
+}
+
+/// How one transaction changed the accounts it touched: its client's and, for transfers
+/// and their disputes, the other party's.
+struct Effect {
+    /// `(client, before, after)` for each touched account that exists.
+    changes: Vec<(ClientId, Account, Account)>,
+    /// Kept only when a hook needs it.
+    tx: Option<Transaction>,
+}
+
+/// A transaction rejected in `--fail-fast` mode, with the state needed to debug it.
+#[derive(Debug)]
+struct Rejection {
+    /// 1-based position of the transaction in the input.
+    row: u64,
+    tx: Transaction,
+    error: ProcessError,
+    account: Option<Account>,
+    /// The deposit a dispute, resolve or chargeback refers to.
+    referenced: Option<StoredTransaction>,
+    /// Decimal places of the amounts.
+    scale: u32,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tx = &self.tx;
+        let amount = |value: Money| value.format(self.scale);
+        writeln!(f, "transaction {} rejected: {}", self.row, self.error)?;
+        write!(
+            f,
+            "  parsed: type={} client={} tx={} amount={} timestamp={}",
+            tx.tx_type,
+            tx.client,
+            tx.tx,
+            tx.amount.map_or("-".to_string(), |a| a.to_string()),
+            tx.timestamp.map_or("-".to_string(), |t| t.to_string())
+        )?;
+        match &self.account {
+            Some(account) => write!(
+                f,
+                "\n  client {}: available={} held={} reserved={} on_hold={} total={} locked={}",
+                tx.client,
+                amount(account.available),
+                amount(account.held),
+                amount(account.reserved),
+                amount(account.on_hold),
+                amount(account.total()),
+                account.locked
+            )?,
+            None => write!(f, "\n  client {}: no account", tx.client)?,
+        }
+        if let Some(stored) = &self.referenced {
+            write!(
+                f,
+                "\n  tx {}: client={} amount={} dispute state={:?}",
+                tx.tx,
+                stored.client,
+                amount(stored.amount),
+                stored.dispute_state
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Rejection {}
+
+impl Session {
+    fn new(options: &Options) -> Result<Self, Box<dyn Error>> {
+        let mut engine = build_engine(options)?;
+        let audit = match &options.audit_log {
+            Some(path) => {
+                let pending = Arc::new(Mutex::new(Vec::new()));
+                let sink = Arc::clone(&pending);
+                engine.on_audit(move |event| {
+                    sink.lock()
+                        .expect("audit sink never panics")
+                        .push(event.clone())
+                });
+                let log =
+                    AuditLog::new(BufWriter::new(File::create(path)?)).with_scale(options.scale());
+                Some(AuditTrail { pending, log })
+            }
+            None => None,
+        };
+        let mut checkpointer = match &options.checkpoint_dir {
+            Some(dir) => Some(Checkpointer::new(dir, options.checkpoint_every)?),
+            None => None,
+        };
+        let resumed = match &mut checkpointer {
+            Some(checkpointer) if options.resume => checkpointer.resume(&mut engine)?,
+            _ => 0,
+        };
+        if resumed > 0 {
+            info!("resuming after transaction {} from checkpoint", resumed);
+        }
+        Ok(Self {
+            engine,
+            alerts: alert_monitor(options)?,
+            ledger: ledger_exporter(options)?,
+            ignored: options.ignored_summary.then(IgnoredCounts::default),
+            ignored_log: match &options.ignored_report {
+                _ if options.dry_run => Some(IgnoredLog::new(output(options)?)),
+                Some(path) => Some(IgnoredLog::new(Box::new(BufWriter::new(File::create(
+                    path,
+                )?)))),
+                None => None,
+            },
+            audit,
+            rows: resumed,
+            stats: options.stats,
+            fail_fast: options.fail_fast,
+            batch: None,
+            dashboard: dashboard(options)?,
+            progress: match options.progress {
+                true => Some(ProgressBar::new(options)?),
+                false => None,
+            },
+            checkpointer,
+            skip: resumed,
+            transcript: match &options.transcript {
+                Some(path) => Some(
+                    TranscriptWriter::new(BufWriter::new(File::create(path)?))
+                        .with_scale(options.scale()),
+                ),
+                None => None,
+            },
+            rejection: None,
+        })
+    }
+
+    fn apply(&mut self, input: Input) -> Result<(), Box<dyn Error>> {
+        if let Some(progress) = &mut self.progress {
+            progress.row(&self.engine);
+        }
+        if self.skip > 0 {
+            if let Input::Transaction(_) | Input::Scheduled { .. } = input {
+                self.skip -= 1;
+            }
+            return Ok(());
+        }
+        // Rows skipped for their rolled-back batch aren't processed, so aren't recorded.
+        let skipped = self.batch.as_ref().is_some_and(|batch| batch.failed)
+            && !matches!(input, Input::CommitBatch);
+        let recorded = (self.transcript.is_some() && !skipped).then(|| input.clone());
+        self.apply_input(input)?;
+        if let (Some(transcript), Some(input)) = (&mut self.transcript, recorded) {
+            transcript.record(&input, self.rejection.take().map_or(Ok(()), Err))?;
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.write_pending()?;
+        }
+        // A failed batch has already been rolled back in the engine, but the rest of it
+        // still has to be skipped, so no checkpoint until it ends.
+        let checkpointed = match &mut self.checkpointer {
+            Some(checkpointer) if self.batch.is_none() => {
+                checkpointer.maybe_checkpoint(&self.engine, self.rows)?
+            }
+            _ => false,
+        };
+        if checkpointed {
+            debug!("checkpoint after transaction {}", self.rows);
+        }
+        Ok(())
+    }
+
+    fn apply_input(&mut self, input: Input) -> Result<(), Box<dyn Error>> {
+        match input {
+            Input::Transaction(tx) => {
+                self.apply_transaction(tx)?;
+                if let Some(dashboard) = &mut self.dashboard {
+                    dashboard.refresh(&self.engine)?;
+                }
+                Ok(())
+            }
+            Input::BeginBatch => {
+                if self.batch.is_some() {
+                    return Err("batch begun inside an open batch".into());
+                }
+                self.engine.begin_batch();
+                self.batch = Some(Batch::default());
+                Ok(())
+            }
+            Input::Admin(op) => self.apply_admin(op),
+            Input::Scheduled { tx, value_date } => self.schedule(tx, value_date),
+            Input::SettleUntil { timestamp } => {
+                if self.batch.as_ref().is_some_and(|batch| batch.failed) {
+                    debug!("settlement skipped: its batch was rolled back");
+                    return Ok(());
+                }
+                self.engine.advance_time(timestamp);
+                debug!("settled post-dated transactions up to {}", timestamp);
+                Ok(())
+            }
+            Input::Accrue { timestamp } => {
+                if self.batch.as_ref().is_some_and(|batch| batch.failed) {
+                    debug!("accrual skipped: its batch was rolled back");
+                    return Ok(());
+                }
+                let credited = self.engine.accrue_interest(timestamp);
+                info!(
+                    "interest accrued up to {}: {} credited",
+                    timestamp, credited
+                );
+                Ok(())
+            }
+            Input::CommitBatch => {
+                let Some(batch) = self.batch.take() else {
+                    return Err("batch commit without a begin".into());
+                };
+                if batch.failed {
+                    return Ok(());
+                }
+                self.engine.commit_batch();
+                for effect in batch.effects {
+                    self.observe(effect)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn apply_transaction(&mut self, tx: Transaction) -> Result<(), Box<dyn Error>> {
+        self.rows += 1;
+        if let Some(dashboard) = &mut self.dashboard {
+            dashboard.row();
+        }
+        if self.rows.is_multiple_of(ARCHIVE_EVERY) {
+            self.archive_dormant()?;
+        }
+        if self.batch.as_ref().is_some_and(|batch| batch.failed) {
+            debug!("tx {} skipped: its batch was rolled back", tx.tx);
+            return Ok(());
+        }
+
+        let client = tx.client;
+        let counterparty = match tx.tx_type {
+            TransactionType::Transfer => tx.to_client,
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::ChargebackReversal => {
+                self.engine
+                    .transaction(tx.tx)
+                    .and_then(|stored| match stored.kind {
+                        StoredKind::Transfer { to_client } => Some(to_client),
+                        StoredKind::Deposit | StoredKind::Withdrawal => None,
+                    })
+            }
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Hold
+            | TransactionType::Release => None,
+        };
+        let touched: Vec<(ClientId, Account)> =
+            [Some(client), counterparty.filter(|&c| c != client)]
+                .into_iter()
+                .flatten()
+                .map(|c| (c, self.engine.account(c).copied().unwrap_or_default()))
+                .collect();
+        let keep = self.ledger.is_some() || self.ignored_log.is_some() || self.fail_fast;
+        let original = keep.then(|| tx.clone());
+        let id = tx.tx;
+
+        let result = self.engine.process(tx);
+        if result == Err(ProcessError::MemoryLimit) {
+            return Err(format!(
+                "engine memory limit reached after {} transactions (peak {}); raise \
+                 --max-memory or split the input by client",
+                self.rows - 1,
+                format_size(self.engine.peak_memory())
+            )
+            .into());
+        }
+        if let Err(error) = result {
+            self.rejected(client, id, original.as_ref(), error)?;
+            if let Some(batch) = &mut self.batch {
+                self.engine.rollback_batch();
+                batch.failed = true;
+                batch.effects.clear();
+                warn!("batch rolled back: tx {} rejected: {}", id, error);
+                return Ok(());
+            }
+        }
+
+        let changes: Vec<(ClientId, Account, Account)> = touched
+            .into_iter()
+            .filter_map(|(c, before)| Some((c, before, *self.engine.account(c)?)))
+            .collect();
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let effect = Effect {
+            changes,
+            tx: original,
+        };
+        match &mut self.batch {
+            Some(batch) => batch.effects.push(effect),
+            None => self.observe(effect)?,
+        }
+        Ok(())
+    }
+
+    fn schedule(&mut self, tx: Transaction, value_date: u64) -> Result<(), Box<dyn Error>> {
+        self.rows += 1;
+        if self.batch.as_ref().is_some_and(|batch| batch.failed) {
+            debug!("tx {} skipped: its batch was rolled back", tx.tx);
+            return Ok(());
+        }
+        let (client, id) = (tx.client, tx.tx);
+        let original = (self.ignored_log.is_some() || self.fail_fast).then(|| tx.clone());
+        let Err(error) = self.engine.schedule(tx, value_date) else {
+            debug!("tx {} scheduled for {}", id, value_date);
+            return Ok(());
+        };
+        self.rejected(client, id, original.as_ref(), error)?;
+        if let Some(batch) = &mut self.batch {
+            self.engine.rollback_batch();
+            batch.failed = true;
+            batch.effects.clear();
+            warn!("batch rolled back: tx {} rejected: {}", id, error);
+        }
+        Ok(())
+    }
+
+    fn apply_admin(&mut self, op: AdminOp) -> Result<(), Box<dyn Error>> {
+        if self.batch.as_ref().is_some_and(|batch| batch.failed) {
+            debug!("admin {} skipped: its batch was rolled back", op);
+            return Ok(());
+        }
+        let (client, description) = (op.client(), op.to_string());
+        let Err(error) = self.engine.apply_admin(op) else {
+            info!("admin {} applied to client {}", description, client);
+            return Ok(());
+        };
+        self.rejection = Some(error);
+        let message = format!(
+            "admin {} on client {} failed: {}",
+            description, client, error
+        );
+        if self.fail_fast {
+            return Err(message.into());
+        }
+        warn!("{}", message);
+        if let Some(batch) = &mut self.batch {
+            self.engine.rollback_batch();
+            batch.failed = true;
+            batch.effects.clear();
+            warn!("batch rolled back");
+        }
+        Ok(())
+    }
+
+    fn archive_dormant(&mut self) -> Result<(), Box<dyn Error>> {
+        let archived = self.engine.archive_dormant()?;
+        if archived > 0 {
+            debug!("archived {} dormant accounts", archived);
+        }
+        Ok(())
+    }
+
+    fn rejected(
+        &mut self,
+        client: ClientId,
+        id: TxId,
+        tx: Option<&Transaction>,
+        error: ProcessError,
+    ) -> Result<(), Box<dyn Error>> {
+        self.rejection = Some(error);
+        if matches!(
+            error,
+            ProcessError::Overflow
+                | ProcessError::BelowFloor
+                | ProcessError::AboveCeiling
+                | ProcessError::AmountLimit
+                | ProcessError::DailyLimit
+                | ProcessError::VelocityLimit
+                | ProcessError::Amount(_)
+        ) {
+            warn!("tx {} rejected: {}", id, error);
+        } else {
+            debug!("tx {} rejected: {}", id, error);
+        }
+        if let Some(counts) = &mut self.ignored {
+            counts.record(error);
+        }
+        if let Some(dashboard) = &mut self.dashboard {
+            dashboard.reject(self.rows, client, id, error);
+        }
+        if let (Some(log), Some(tx)) = (&mut self.ignored_log, tx) {
+            log.record(self.rows, tx, error)?;
+        }
+        if let Some(tx) = tx.filter(|_| self.fail_fast) {
+            return Err(Box::new(Rejection {
+                row: self.rows,
+                account: self.engine.account(client).copied(),
+                referenced: match tx.tx_type {
+                    TransactionType::Deposit
+                    | TransactionType::Withdrawal
+                    | TransactionType::Transfer => None,
+                    _ => self.engine.transaction(id),
+                },
+                tx: tx.clone(),
+                error,
+                scale: self.engine.config().precision.scale,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Feeds an applied transaction's effect to the alert monitor and ledger exporter.
+    fn observe(&mut self, effect: Effect) -> Result<(), Box<dyn Error>> {
+        if let Some(monitor) = &mut self.alerts {
+            for (client, _, after) in &effect.changes {
+                for error in monitor.observe(*client, after).1 {
+                    warn!("alert delivery failed: {}", error);
+                }
+            }
+        }
+        if let (Some(ledger), Some(tx)) = (&mut self.ledger, &effect.tx) {
+            ledger.record_changes(tx, &effect.changes)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the audit records still pending, including those of a final merge.
+    fn finish_audit(&mut self) -> io::Result<()> {
+        match &mut self.audit {
+            Some(audit) => {
+                audit.write_pending()?;
+                audit.log.flush()
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(progress) = &mut self.progress {
+            progress.finish(&self.engine);
+        }
+        if self.batch.take().is_some() {
+            self.engine.rollback_batch();
+            return Err("input ended inside a batch".into());
+        }
+        self.archive_dormant()?;
+        if let Some(transcript) = &mut self.transcript {
+            transcript.finish(&self.engine)?;
+            info!(
+                "transcript written, state digest {:032x}",
+                self.engine.state_digest()
+            );
+        }
+        if self.engine.archived_count() > 0 {
+            info!(
+                "{} dormant accounts archived and left out of the report",
+                self.engine.archived_count()
+            );
+        }
+        debug!(
+            "peak engine memory: {}",
+            format_size(self.engine.peak_memory())
+        );
+        let saturations = self.engine.saturation_count();
+        if saturations > 0 {
+            warn!(
+                "{} balance updates were clamped at the representable range",
+                saturations
+            );
+        }
+        if let Some(counts) = &self.ignored {
+            log_ignored(counts);
+        }
+        if self.stats {
+            log_stats(self.engine.stats());
+        }
+        if let Some(log) = &mut self.ignored_log {
+            log.flush()?;
+        }
+        if let Some(ledger) = &mut self.ledger {
+            ledger.flush()?;
+        }
+        if let Some(dashboard) = &mut self.dashboard {
+            dashboard.finish(&self.engine)?;
+        }
+        debug!("processed {} transactions", self.rows);
+        Ok(())
+    }
+}
+
+fn log_event(event: &EngineEvent) {
+    match event {
+        EngineEvent::Saturated { .. }
+        | EngineEvent::AccountFrozen { .. }
+        | EngineEvent::IdCollision { .. }
+        | EngineEvent::OutOfOrder { .. } => warn!("{}", event),
+        _ => info!("{}", event),
+    }
+}
+
+fn log_ignored(counts: &IgnoredCounts) {
+    info!("ignored transactions: {}", counts.total());
+    for (reason, count) in counts.iter() {
+        info!("ignored {}: {}", reason.code(), count);
+    }
+}
+
+fn log_stats(stats: &EngineStats) {
+    for line in stats.to_string().lines() {
+        info!("{}", line);
+    }
+}
+
+/// Bytes read so far from the input files, before decompression, for `--progress`.
+static INPUT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Time between two redraws of the progress bar.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Width of the progress bar, in characters.
+const PROGRESS_WIDTH: usize = 30;
+
+/// Adds the bytes read through it to `INPUT_BYTES`.
+struct CountingReader<R>(R);
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        INPUT_BYTES.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// The `--progress` bar: how much of the input files was read, with the rows read and the
+/// transactions rejected so far, redrawn in place on stderr.
+struct ProgressBar {
+    /// Combined size of the input files; unknown when one of them is stdin.
+    total: Option<u64>,
+    rows: u64,
+    drawn: Instant,
+}
+
+impl ProgressBar {
+    fn new(options: &Options) -> io::Result<Self> {
+        let paths = match &options.command {
+            Command::Merge { inputs } => inputs,
+            _ => options.inputs(),
+        };
+        let total = match paths.iter().any(|path| path == "-") {
+            true => None,
+            false => Some(
+                paths
+                    .iter()
+                    .map(|path| Ok(File::open(path)?.metadata()?.len()))
+                    .sum::<io::Result<u64>>()?,
+            ),
+        };
+        Ok(Self {
+            total,
+            rows: 0,
+            drawn: Instant::now(),
+        })
+    }
+
+    fn row(&mut self, engine: &Engine) {
+        self.rows += 1;
+        if self.drawn.elapsed() >= PROGRESS_INTERVAL {
+            self.draw(engine);
+        }
+    }
 
-    for result in reader.deserialize() {
-        let tx: Transaction = result?;
-        engine.process(tx);
+    fn finish(&mut self, engine: &Engine) {
+        self.draw(engine);
+        eprintln!();
     }
 
-    let mut writer = Writer::from_writer(io::stdout());
-    for account in engine.output() {
-        writer.serialize(account)?;
+    fn draw(&mut self, engine: &Engine) {
+        let progress = Progress {
+            rows: self.rows,
+            bytes: INPUT_BYTES.load(Ordering::Relaxed),
+            rejected: engine.stats().rejected.total(),
+        };
+        let size = |bytes: u64| format_size(usize::try_from(bytes).unwrap_or(usize::MAX));
+        let read = match self.total {
+            Some(total) => {
+                let done = progress.bytes.min(total) as f64 / total.max(1) as f64;
+                let filled = (done * PROGRESS_WIDTH as f64) as usize;
+                format!(
+                    "[{}{}] {:5.1}% {} of {}",
+                    "#".repeat(filled),
+                    " ".repeat(PROGRESS_WIDTH - filled),
+                    done * 100.0,
+                    size(progress.bytes),
+                    size(total)
+                )
+            }
+            None => size(progress.bytes),
+        };
+        eprint!(
+            "\r{}, {} rows, {} rejected ",
+            read, progress.rows, progress.rejected
+        );
+        self.drawn = Instant::now();
     }
-    writer.flush()?;
+}
+
+#[cfg(feature = "tui")]
+type Dashboard = tx_engine::dashboard::Dashboard<ratatui::backend::CrosstermBackend<io::Stderr>>;
+
+#[cfg(feature = "tui")]
+fn dashboard(options: &Options) -> Result<Option<Dashboard>, Box<dyn Error>> {
+    use std::io::IsTerminal;
+
+    if !options.tui {
+        return Ok(None);
+    }
+    if !io::stderr().is_terminal() {
+        return Err("--tui needs stderr to be a terminal".into());
+    }
+    Ok(Some(Dashboard::stderr(count_rows(options)?)?))
+}
+
+/// Stand-in for the dashboard in builds without the `tui` feature; it cannot be created.
+#[cfg(not(feature = "tui"))]
+enum Dashboard {}
 
+#[cfg(not(feature = "tui"))]
+impl Dashboard {
+    fn row(&mut self) {
+        match *self {}
+    }
+
+    fn reject(&mut self, _row: u64, _client: ClientId, _tx: TxId, _error: ProcessError) {
+        match *self {}
+    }
+
+    fn refresh(&mut self, _engine: &Engine) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn finish(&mut self, _engine: &Engine) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn dashboard(options: &Options) -> Result<Option<Dashboard>, Box<dyn Error>> {
+    if options.tui {
+        return Err("--tui requires building with the `tui` feature".into());
+    }
+    Ok(None)
+}
+
+/// Number of data rows in the CSV input, counted up front for the dashboard's progress.
+/// `None` for the other input formats.
+#[cfg(feature = "tui")]
+fn count_rows(options: &Options) -> io::Result<Option<u64>> {
+    let paths = match &options.command {
+        Command::Merge { inputs } => inputs.clone(),
+        Command::Process { .. }
+        | Command::Stats { .. }
+        | Command::MergeClients { .. }
+        | Command::ExportDb { .. }
+            if options
+                .inputs()
+                .iter()
+                .all(|path| path != "-" && options.input_format(path) == InputFormat::Csv) =>
+        {
+            options.inputs().to_vec()
+        }
+        _ => return Ok(None),
+    };
+    let mut rows = 0;
+    for path in paths {
+        let mut file = open_input(&path)?;
+        let mut buffer = vec![0; 64 * 1024];
+        let mut lines = 0u64;
+        let mut last = b'\n';
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            let chunk = &buffer[..read];
+            lines += chunk.iter().filter(|&&byte| byte == b'\n').count() as u64;
+            last = chunk[read - 1];
+        }
+        if last != b'\n' {
+            lines += 1;
+        }
+        // Less the header line.
+        rows += lines.saturating_sub(1);
+    }
+    Ok(Some(rows))
+}
+
+fn ledger_exporter(
+    options: &Options,
+) -> Result<Option<LedgerExporter<BufWriter<File>>>, Box<dyn Error>> {
+    let Some(path) = &options.ledger else {
+        return Ok(None);
+    };
+    let mapping = match &options.ledger_accounts {
+        Some(mapping_path) => AccountMapping::load(mapping_path)?,
+        None => AccountMapping::default(),
+    };
+    let format = options.ledger_format.unwrap_or(LedgerFormat::Beancount);
+    let writer = BufWriter::new(File::create(path)?);
+    Ok(Some(
+        LedgerExporter::new(writer, format, mapping).with_scale(options.scale()),
+    ))
+}
+
+fn alert_monitor(options: &Options) -> Result<Option<AlertMonitor>, Box<dyn Error>> {
+    let mut rules = Vec::new();
+    if let Some(threshold) = options.alert_below {
+        rules.push(AlertRule::BalanceBelow {
+            threshold: Money::from_decimal_lossy(threshold, options.scale()),
+        });
+    }
+    if let Some(amount) = options.alert_drop {
+        rules.push(AlertRule::DropExceeding {
+            amount: Money::from_decimal_lossy(amount, options.scale()),
+        });
+    }
+    if rules.is_empty() {
+        return Ok(None);
+    }
+
+    let mut bus = NotificationBus::new();
+    bus.add_sink(LoggerSink);
+    for url in &options.alert_webhooks {
+        bus.add_sink(WebhookSink::new(url)?);
+    }
+    Ok(Some(
+        AlertMonitor::new(rules, bus).with_scale(options.scale()),
+    ))
+}
+
+type Visit<'a> = dyn FnMut(Input) -> Result<(), Box<dyn Error>> + 'a;
+
+/// `visit`, passed only the inputs `--clients` keeps.
+fn select_clients<'a>(
+    options: &'a Options,
+    visit: &'a mut Visit,
+) -> impl FnMut(Input) -> Result<(), Box<dyn Error>> + 'a {
+    move |input| match &options.clients {
+        Some(clients) if !input.selected_by(clients) => Ok(()),
+        _ => visit(input),
+    }
+}
+
+/// Malformed CSV rows written with `--error-report`, shared by all inputs.
+type ErrorReport = Option<ErrorLog<BufWriter<File>>>;
+
+/// Reads every transaction from the inputs in turn. With several inputs, errors name the
+/// input they occurred in.
+fn read_input(options: &Options, visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    let visit = &mut select_clients(options, visit);
+    if options.dedup && options.inputs().len() > 1 {
+        return Err("--dedup takes a single input file".into());
+    }
+    let mut errors = match &options.error_report {
+        Some(path) => Some(ErrorLog::new(BufWriter::new(File::create(path)?))),
+        None => None,
+    };
+    for path in options.inputs() {
+        match read_path(options, path, &mut errors, visit) {
+            Err(error) if options.inputs().len() > 1 => {
+                return Err(format!("{}: {}", path, error).into());
+            }
+            result => result?,
+        }
+    }
+    if let Some(log) = &mut errors {
+        log.flush()?;
+    }
+    Ok(())
+}
+
+/// Reads one input, choosing the reader by its format.
+fn read_path(
+    options: &Options,
+    path: &str,
+    errors: &mut ErrorReport,
+    visit: &mut Visit,
+) -> Result<(), Box<dyn Error>> {
+    match options.input_format(path) {
+        InputFormat::Csv => read_csv(options, path, errors, visit),
+        InputFormat::Journal => read_journal(open_input(path)?, visit),
+        InputFormat::FixedWidth => {
+            let Some(layout_path) = &options.layout else {
+                return Err("fixed-width input needs --layout".into());
+            };
+            read_fixed_width(open_input(path)?, layout_path, visit)
+        }
+        InputFormat::Xlsx if path == "-" => Err("xlsx input cannot be read from stdin".into()),
+        InputFormat::Xlsx => read_xlsx(path, visit),
+        InputFormat::Iso20022 => read_iso20022(open_input(path)?, visit),
+        InputFormat::Parquet if path == "-" => {
+            Err("parquet input cannot be read from stdin".into())
+        }
+        InputFormat::Parquet => read_parquet(path, visit),
+    }
+}
+
+/// Opens an input file, or stdin for `-`, decompressing gzip or zstd content.
+fn open_input(path: &str) -> io::Result<Box<dyn Read>> {
+    match path {
+        "-" => compression::decompress(CountingReader(io::stdin().lock())),
+        path => compression::decompress(CountingReader(File::open(path)?)),
+    }
+}
+
+/// Where the report goes: the `--output` file, or stdout.
+fn output(options: &Options) -> io::Result<Box<dyn Write>> {
+    Ok(match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    })
+}
+
+fn write_stats(options: &Options, stats: &EngineStats) -> Result<(), Box<dyn Error>> {
+    let mut output = output(options)?;
+    writeln!(output, "{}", stats)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Parses the inputs without processing them. Malformed CSV rows are written to the output
+/// in the `--error-report` format, all of them unless `--strict`; the other formats stop at
+/// their first error. Fails if anything was malformed.
+fn validate(options: &Options) -> Result<(), Box<dyn Error>> {
+    let mut errors = ErrorLog::new(output(options)?);
+    let mut malformed = 0u64;
+    'inputs: for path in options.inputs() {
+        let mut rows = 0u64;
+        if options.input_format(path) != InputFormat::Csv {
+            read_path(options, path, &mut None, &mut |_| {
+                rows += 1;
+                Ok(())
+            })
+            .map_err(|error| format!("{}: {}", path, error))?;
+            info!("{}: {} rows valid", path, rows);
+            continue;
+        }
+        let reader = CsvReader::with_columns(open_input(path)?, &options.column_map())?
+            .with_minor_units(options.minor_units())
+            .with_scale(options.scale())
+            .with_admin(options.allow_admin)
+            .with_fast(options.fast)
+            .with_limits(options.input_limits());
+        let mut skipped = 0u64;
+        for input in reader {
+            match input {
+                Ok(_) => rows += 1,
+                Err(CsvError::Malformed {
+                    line,
+                    fields,
+                    error,
+                }) => {
+                    skipped += 1;
+                    malformed += 1;
+                    errors.record(line, &fields, &error)?;
+                    if options.fail_fast {
+                        break 'inputs;
+                    }
+                }
+                Err(error) => return Err(format!("{}: {}", path, error).into()),
+            }
+        }
+        info!("{}: {} rows valid, {} malformed", path, rows, skipped);
+    }
+    errors.flush()?;
+    match malformed {
+        0 => Ok(()),
+        count => Err(format!("{} malformed rows", count).into()),
+    }
+}
+
+/// Replays the transcript given as input into an engine configured by the options and
+/// prints the account report. Fails if the transcript doesn't reproduce the recorded
+/// results and accounts.
+fn replay(options: &Options) -> Result<(), Box<dyn Error>> {
+    let path = &options.inputs()[0];
+    let mut engine = build_engine(options)?;
+    let digest = transcript::replay(BufReader::new(open_input(path)?), &mut engine)
+        .map_err(|error| format!("{}: {}", path, error))?;
+    info!(
+        "{}: replayed to the recorded state digest {:032x}",
+        path, digest
+    );
+    schema::write_report(&engine, &options.report(), output(options)?)?;
+    Ok(())
+}
+
+/// Runs the input through an engine configured by `--config-a` and one configured by
+/// `--config-b`, each with the other options, and writes the fields of the accounts that
+/// differ to the output. Fails if any input or account came out differently.
+fn shadow(options: &Options) -> Result<(), Box<dyn Error>> {
+    if options.threads > 1 {
+        return Err("shadow cannot be combined with --threads".into());
+    }
+    let file = options.config_b.clone().expect("shadow has --config-b");
+    let scale = file.scale.unwrap_or(options.scale());
+    let mut runner = ShadowRunner::with_configs(
+        engine_config(options)?,
+        engine_config_from(options, file, scale)?,
+    );
+    read_input(options, &mut |input| {
+        runner.apply(input)?;
+        Ok(())
+    })?;
+    for divergence in runner.divergences() {
+        let subject = match (divergence.tx, divergence.client) {
+            (Some(tx), _) => format!("tx {}", tx),
+            (None, Some(client)) => format!("admin operation on client {}", client),
+            (None, None) => "batch marker".to_string(),
+        };
+        warn!(
+            "input {}: {} {} under A, {} under B",
+            divergence.position, subject, divergence.a, divergence.b
+        );
+    }
+    let diffs = runner.account_diffs();
+    reconcile::write_diff(&diffs, output(options)?)?;
+    info!(
+        "{} inputs compared, {} answered differently, {} clients differ",
+        runner.inputs(),
+        runner.divergences().len(),
+        diffs.len()
+    );
+    match (runner.divergences().len(), diffs.len()) {
+        (0, 0) => Ok(()),
+        (inputs, clients) => Err(format!(
+            "{} inputs answered differently, {} clients differ",
+            inputs, clients
+        )
+        .into()),
+    }
+}
+
+/// Compares the account reports at `expected` and `actual`, writing the differing fields
+/// to the output. Fails if any client differs.
+fn diff_reports(options: &Options, expected: &str, actual: &str) -> Result<(), Box<dyn Error>> {
+    let read = |path: &str| -> Result<reconcile::Report, String> {
+        let input = open_input(path).map_err(|error| format!("{}: {}", path, error))?;
+        reconcile::read_report(input).map_err(|error| format!("{}: {}", path, error))
+    };
+    let (expected, actual) = (read(expected)?, read(actual)?);
+    let diffs = reconcile::reconcile(&expected, &actual);
+    reconcile::write_diff(&diffs, output(options)?)?;
+    let clients = expected.keys().chain(
+        actual
+            .keys()
+            .filter(|client| !expected.contains_key(client)),
+    );
+    info!(
+        "{} clients compared, {} differ",
+        clients.count(),
+        diffs.len()
+    );
+    match diffs.len() {
+        0 => Ok(()),
+        count => Err(format!("{} clients differ", count).into()),
+    }
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    match &options.command {
+        Command::ExportJournal { output, .. } => return export_journal(options, output),
+        Command::Sort {
+            output,
+            by,
+            max_rows,
+            ..
+        } => return sort_file(options, output, *by, *max_rows),
+        Command::Serve { address, grpc } => return serve(options, address, *grpc),
+        Command::Validate { .. } => return validate(options),
+        Command::Diff { expected, actual } => return diff_reports(options, expected, actual),
+        Command::Replay { .. } => return replay(options),
+        Command::Shadow { .. } => return shadow(options),
+        Command::Process { .. }
+        | Command::Stats { .. }
+        | Command::ImportJournal { .. }
+        | Command::Merge { .. }
+        | Command::MergeClients { .. }
+        | Command::Statement { .. }
+        | Command::BalanceAt { .. }
+        | Command::ExportDb { .. }
+        | Command::Snapshot { .. } => {}
+    }
+    if options.threads > 1 {
+        return run_sharded(options);
+    }
+
+    let mut session = Session::new(options)?;
+    match &options.command {
+        Command::Merge { inputs } => read_merged(inputs, options, &mut |tx| session.apply(tx))?,
+        _ => read_input(options, &mut |tx| session.apply(tx))?,
+    }
+    session.finish()?;
+    if let Command::MergeClients { from, into, .. } = options.command {
+        session.engine.merge_clients(from, into)?;
+    }
+    session.finish_audit()?;
+    if options.dry_run {
+        return Ok(());
+    }
+
+    let engine = &session.engine;
+    match options.command {
+        Command::Stats { .. } => return write_stats(options, engine.stats()),
+        Command::Statement { client, .. } => {
+            history::write_statement(engine.history(client), options.scale(), output(options)?)?;
+            return Ok(());
+        }
+        Command::BalanceAt {
+            client, timestamp, ..
+        } => {
+            let account = engine
+                .balance_at(client, timestamp)
+                .ok_or_else(|| format!("client {} had no transactions by {}", client, timestamp))?;
+            let row = historic_output(client, &account, options.scale());
+            schema::write_accounts(&[row], options.output_format, output(options)?)?;
+            return Ok(());
+        }
+        Command::ExportDb { ref path, .. } => return export_db(engine, path),
+        Command::Snapshot { ref output, .. } => {
+            engine.save_snapshot(session.rows, output)?;
+            info!(
+                "saved a snapshot of {} transactions to {}",
+                session.rows, output
+            );
+            return Ok(());
+        }
+        _ => {}
+    }
+    let rows = schema::write_report(engine, &options.report(), output(options)?)?;
+    if let Some(path) = &options.disputes_report {
+        let cases = cases::write_disputes(engine, BufWriter::new(File::create(path)?))?;
+        debug!("wrote {} open dispute cases to {}", cases, path);
+    }
+    if let Some(path) = &options.settlement_out {
+        let instructions = netting::settle(&netting::net_positions(engine.obligations()));
+        let file = BufWriter::new(File::create(path)?);
+        netting::write_instructions_csv(file, &instructions, engine.config().precision.scale)?;
+        debug!(
+            "wrote {} settlement instructions to {}",
+            instructions.len(),
+            path
+        );
+    }
+    write_manifest(options, engine.config(), rows)
+}
+
+/// The report row of `client`'s `account` as reconstructed from its history, with only the
+/// default columns.
+fn historic_output(client: ClientId, account: &Account, scale: u32) -> AccountOutput {
+    AccountOutput {
+        client,
+        available: account.available,
+        held: account.held.saturating_add(account.on_hold),
+        total: account.total(),
+        locked: account.locked,
+        reserved: None,
+        last_activity: None,
+        closed: None,
+        overdrawn: None,
+        activity: None,
+        scale,
+    }
+}
+
+/// The engine with the configuration, clock, archive and store the options ask for,
+/// logging its events, and the state of `--load-snapshot` if given.
+fn build_engine(options: &Options) -> Result<Engine, Box<dyn Error>> {
+    let mut engine = Engine::with_config(engine_config(options)?);
+    if options.system_clock() {
+        engine.set_clock(SystemClock);
+    }
+    if let Some(path) = &options.archive_dormant {
+        engine.set_archive(FileArchive::create(path)?);
+    }
+    if let Some(path) = &options.transaction_store {
+        engine.set_store(FileStore::create(path)?)?;
+    }
+    engine.on_event(log_event);
+    if let Some(path) = &options.load_snapshot {
+        let position = engine.load_snapshot(path)?;
+        info!(
+            "loaded a snapshot of {} transactions from {}",
+            position, path
+        );
+    }
+    Ok(engine)
+}
+
+/// Runs the HTTP API on `address` until the process is stopped.
+fn serve(options: &Options, address: &str, grpc: bool) -> Result<(), Box<dyn Error>> {
+    if options.threads > 1 || options.tui {
+        return Err("serve cannot be combined with --threads or --tui".into());
+    }
+    if grpc {
+        return serve_grpc(build_engine(options)?, address);
+    }
+    let server = Server::bind(address, build_engine(options)?)?;
+    info!("listening on http://{}", server.local_addr()?);
+    server.run()?;
     Ok(())
 }
 
+#[cfg(feature = "grpc")]
+fn serve_grpc(engine: Engine, address: &str) -> Result<(), Box<dyn Error>> {
+    let address = std::net::ToSocketAddrs::to_socket_addrs(address)?
+        .next()
+        .ok_or_else(|| format!("no address to listen on for '{}'", address))?;
+    info!("listening for gRPC on {}", address);
+    tx_engine::grpc::GrpcService::new(engine)
+        .run(address)
+        .map_err(|error| error.to_string())?;
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_engine: Engine, _address: &str) -> Result<(), Box<dyn Error>> {
+    Err("serve --grpc requires building with the `grpc` feature".into())
+}
+
+/// Processes the input on `--threads` worker threads, each owning a share of the clients.
+fn run_sharded(options: &Options) -> Result<(), Box<dyn Error>> {
+    if let Some(option) = unsharded_option(options) {
+        return Err(format!("{} cannot be combined with --threads", option).into());
+    }
+    let config = engine_config(options)?;
+    let mut engine = ShardedEngine::from_fn(options.threads, |_| {
+        let mut engine = Engine::with_config(config.clone());
+        engine.on_event(log_event);
+        engine
+    });
+    let mut visit = |input| match input {
+        Input::Transaction(tx) => Ok(engine.submit(tx)?),
+        Input::BeginBatch | Input::CommitBatch => {
+            Err("batches cannot be processed with --threads".into())
+        }
+        Input::Admin(_) => Err("admin rows cannot be processed with --threads".into()),
+        Input::Accrue { .. } => Err("accrue rows cannot be processed with --threads".into()),
+        Input::Scheduled { .. } | Input::SettleUntil { .. } => {
+            Err("post-dated transactions cannot be processed with --threads".into())
+        }
+    };
+    match &options.command {
+        Command::Merge { inputs } => read_merged(inputs, options, &mut visit)?,
+        _ => read_input(options, &mut visit)?,
+    }
+
+    let mut report = engine.finish()?;
+    if options.ignored_summary {
+        log_ignored(&report.ignored);
+    }
+    if options.stats {
+        log_stats(&report.stats);
+    }
+    debug!(
+        "applied {} transactions on {} threads",
+        report.applied, options.threads
+    );
+    if matches!(options.command, Command::Stats { .. }) {
+        return write_stats(options, &report.stats);
+    }
+    if let Some(clients) = &options.clients {
+        report
+            .accounts
+            .retain(|account| clients.contains(account.client));
+    }
+    let rows = schema::write_accounts(&report.accounts, options.output_format, output(options)?)?;
+    write_manifest(options, &config, rows)
+}
+
+/// The first option given that needs to see every client in one engine, if any.
+fn unsharded_option(options: &Options) -> Option<&'static str> {
+    let conflicts = [
+        (
+            matches!(options.command, Command::MergeClients { .. }),
+            "merge-clients",
+        ),
+        (
+            matches!(options.command, Command::Statement { .. }),
+            "statement",
+        ),
+        (
+            matches!(options.command, Command::BalanceAt { .. }),
+            "balance-at",
+        ),
+        (
+            matches!(options.command, Command::ExportDb { .. }),
+            "export-db",
+        ),
+        (
+            matches!(options.command, Command::Snapshot { .. }),
+            "snapshot",
+        ),
+        (options.load_snapshot.is_some(), "--load-snapshot"),
+        (options.fail_fast, "--fail-fast"),
+        (options.ignored_report.is_some(), "--ignored-report"),
+        (options.disputes_report.is_some(), "--disputes-report"),
+        (options.settlement_out.is_some(), "--settlement-out"),
+        (options.tui, "--tui"),
+        (options.progress, "--progress"),
+        (
+            options.alert_below.is_some() || options.alert_drop.is_some(),
+            "--alert-below/--alert-drop",
+        ),
+        (options.ledger.is_some(), "--ledger"),
+        (options.audit_log.is_some(), "--audit-log"),
+        (options.checkpoint_dir.is_some(), "--checkpoint-dir"),
+        (options.transaction_store.is_some(), "--transaction-store"),
+        (
+            options.withdrawal_fee.is_some() || options.withdrawal_fee_rate.is_some(),
+            "--withdrawal-fee/--withdrawal-fee-rate",
+        ),
+        (options.detect_id_collisions, "--detect-id-collisions"),
+        (options.system_clock(), "--clock system"),
+        (
+            options.dormant_after_days.is_some() || options.dormant_after_txs.is_some(),
+            "--dormant-after-days/--dormant-after-txs",
+        ),
+        (
+            options.output_schema == OutputSchema::V2,
+            "--output-schema v2",
+        ),
+    ];
+    conflicts
+        .into_iter()
+        .find_map(|(given, option)| given.then_some(option))
+}
+
+/// The option given with `--transcript` that would keep the transcript from reproducing the
+/// run, if any.
+fn transcript_conflict(options: &Options) -> Option<&'static str> {
+    let conflicts = [
+        (options.threads > 1, "--threads"),
+        (options.system_clock(), "--clock system"),
+        (options.resume, "--resume"),
+        (options.archive_dormant.is_some(), "--archive-dormant"),
+        (
+            matches!(options.command, Command::MergeClients { .. }),
+            "merge-clients",
+        ),
+    ];
+    conflicts
+        .into_iter()
+        .find_map(|(given, option)| given.then_some(option))
+}
+
+fn write_manifest(
+    options: &Options,
+    config: &EngineConfig,
+    rows: usize,
+) -> Result<(), Box<dyn Error>> {
+    let Some(path) = &options.manifest else {
+        return Ok(());
+    };
+    let schema = options.output_schema;
+    let manifest = Manifest {
+        schema,
+        format: options.output_format,
+        columns: schema.columns(
+            config.reserve.is_some(),
+            config.report_last_activity,
+            config.extended_output,
+        ),
+        rows,
+    };
+    manifest.write_json(BufWriter::new(File::create(path)?))?;
+    Ok(())
+}
+
+fn export_journal(options: &Options, output: &str) -> Result<(), Box<dyn Error>> {
+    let mut journal = JournalWriter::new(BufWriter::new(File::create(output)?))?;
+    read_input(options, &mut |input| match input {
+        Input::Transaction(tx) => Ok(journal.append(&tx)?),
+        Input::BeginBatch | Input::CommitBatch => {
+            Err("batch markers cannot be stored in a journal".into())
+        }
+        Input::Admin(_) => Err("admin rows cannot be stored in a journal".into()),
+        Input::Accrue { .. } => Err("accrue rows cannot be stored in a journal".into()),
+        Input::Scheduled { .. } | Input::SettleUntil { .. } => {
+            Err("post-dated transactions cannot be stored in a journal".into())
+        }
+    })?;
+    journal.flush()?;
+    Ok(())
+}
+
+fn sort_file(
+    options: &Options,
+    output: &str,
+    by: SortKey,
+    max_rows: usize,
+) -> Result<(), Box<dyn Error>> {
+    let input = BufReader::new(open_input(&options.inputs()[0])?);
+    let writer = BufWriter::new(File::create(output)?);
+    let stats = sort::external_sort(input, writer, by, max_rows, None)?;
+    info!("sorted {} rows in {} runs", stats.rows, stats.runs);
+    Ok(())
+}
+
+/// Reads sorted CSV files as one stream, interleaving them by timestamp and transaction id.
+fn read_merged(
+    inputs: &[String],
+    options: &Options,
+    visit: &mut Visit,
+) -> Result<(), Box<dyn Error>> {
+    let visit = &mut select_clients(options, visit);
+    type Source = Box<dyn Iterator<Item = Result<Transaction, csv::Error>>>;
+    let sources = inputs
+        .iter()
+        .map(|path| {
+            let reader = ReaderBuilder::new()
+                .trim(Trim::All)
+                .flexible(true)
+                .from_reader(BufReader::new(open_input(path)?));
+            let source: Source = if options.minor_units() {
+                let scale = options.scale();
+                let rows = reader.into_deserialize::<MinorUnitsTransaction>();
+                Box::new(rows.map(move |row| row.map(|row| row.into_transaction(scale))))
+            } else {
+                Box::new(reader.into_deserialize::<Transaction>())
+            };
+            Ok(source)
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+
+    for result in merge::merge_sorted(sources) {
+        visit(result?.into())?;
+    }
+    Ok(())
+}
+
+fn read_journal(input: impl Read, visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    for result in JournalReader::new(BufReader::new(input))? {
+        visit(result?.into())?;
+    }
+    Ok(())
+}
+
+fn read_csv(
+    options: &Options,
+    path: &str,
+    errors: &mut ErrorReport,
+    visit: &mut Visit,
+) -> Result<(), Box<dyn Error>> {
+    if options.dedup {
+        let cleaned = deduplicate(options, path)?;
+        return read_csv_from(cleaned.as_slice(), options, errors, visit);
+    }
+    read_csv_from(open_input(path)?, options, errors, visit)
+}
+
+/// Reads CSV input, dealing with malformed rows and rows over the input limits as
+/// `--on-error` says. A row over `--max-row-bytes` always stops it.
+fn read_csv_from<R: Read>(
+    input: R,
+    options: &Options,
+    errors: &mut ErrorReport,
+    visit: &mut Visit,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = CsvReader::with_columns(input, &options.column_map())?
+        .with_minor_units(options.minor_units())
+        .with_scale(options.scale())
+        .with_admin(options.allow_admin)
+        .with_fast(options.fast)
+        .with_limits(options.input_limits());
+    let (mut malformed, mut over_limit) = (0, 0);
+    while let Some(input) = reader.next() {
+        let line = reader.line();
+        let input = match input {
+            Ok(input) => input,
+            Err(error) if options.on_error == ErrorPolicy::Abort => return Err(error.into()),
+            Err(CsvError::Malformed {
+                line,
+                fields,
+                error,
+            }) => {
+                malformed += 1;
+                match errors {
+                    Some(log) => log.record(line, &fields, &error)?,
+                    None => debug!("skipped malformed line {}: {}", line, error),
+                }
+                continue;
+            }
+            Err(CsvError::LimitExceeded { line, limit })
+                if !matches!(limit, InputLimit::RowBytes(_)) =>
+            {
+                over_limit += 1;
+                match errors {
+                    Some(log) => log.record_limit(line, limit)?,
+                    None => debug!("skipped line {}: limit exceeded: {}", line, limit),
+                }
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        visit(input).map_err(|e| format!("line {}: {}", line, e))?;
+    }
+    if malformed > 0 {
+        warn!("{} malformed rows skipped", malformed);
+    }
+    if over_limit > 0 {
+        warn!("{} rows over input limits skipped", over_limit);
+    }
+    Ok(())
+}
+
+/// Runs the parallel duplicate scan over the whole input, reports the findings and returns
+/// the input with flagged rows removed.
+fn deduplicate(options: &Options, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    open_input(path)?.read_to_end(&mut data)?;
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let report = dedup::scan(&data, threads);
+
+    info!(
+        "dedup: {} duplicate and {} conflicting rows removed",
+        report.duplicates(),
+        report.conflicts()
+    );
+    if let Some(path) = &options.dedup_report {
+        report.write_csv(BufWriter::new(File::create(path)?))?;
+    }
+
+    let mut cleaned = Vec::with_capacity(data.len());
+    report.write_cleaned(&data, &mut cleaned)?;
+    Ok(cleaned)
+}
+
+fn read_fixed_width(
+    input: impl Read,
+    layout_path: &str,
+    visit: &mut Visit,
+) -> Result<(), Box<dyn Error>> {
+    let layout = Layout::load(layout_path)?;
+
+    for result in FixedWidthReader::new(BufReader::new(input), layout) {
+        visit(result?.into())?;
+    }
+    Ok(())
+}
+
+/// Saves the engine's state to the SQLite database at `path`, creating it if needed.
+#[cfg(feature = "sqlite")]
+fn export_db(engine: &Engine, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut store = SqliteStore::open(path)?;
+    store.save(engine)?;
+    info!("saved {} accounts to {}", engine.accounts().count(), path);
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn export_db(_engine: &Engine, _path: &str) -> Result<(), Box<dyn Error>> {
+    Err("export-db requires building with the `sqlite` feature".into())
+}
+
+#[cfg(feature = "xlsx")]
+fn read_xlsx(input_path: &str, visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    let transactions = tx_engine::xlsx::read_transactions(input_path, &Default::default())?;
+    for tx in transactions {
+        visit(tx.into())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "xlsx"))]
+fn read_xlsx(_input_path: &str, _visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    Err("xlsx input requires building with the `xlsx` feature".into())
+}
+
+#[cfg(feature = "arrow")]
+fn read_parquet(input_path: &str, visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    for tx in tx_engine::arrow::ParquetReader::open(input_path)? {
+        visit(tx?.into())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "arrow"))]
+fn read_parquet(_input_path: &str, _visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    Err("parquet input requires building with the `arrow` feature".into())
+}
+
+#[cfg(feature = "iso20022")]
+fn read_iso20022(input: impl Read, visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    let transactions =
+        tx_engine::iso20022::read_transactions(BufReader::new(input), &Default::default())?;
+    for tx in transactions {
+        visit(tx.into())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "iso20022"))]
+fn read_iso20022(_input: impl Read, _visit: &mut Visit) -> Result<(), Box<dyn Error>> {
+    Err("ISO 20022 input requires building with the `iso20022` feature".into())
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let options = parse_args(env::args_os()).unwrap_or_else(|e| e.exit());
+
+    Logger::stderr(options.log_level(), options.log_format)
+        .init()
+        .expect("logger is installed once");
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <transactions.csv>", args[0]);
+    if let Err(e) = run(&options) {
+        error!("{}", e);
+        log::logger().flush();
         std::process::exit(1);
     }
+}
 
-    if let Err(e) = run(&args[1]) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_options_after_the_command() {
+        let options = parse_args([
+            "tx-engine",
+            "sort",
+            "in.csv",
+            "out.csv",
+            "--by",
+            "client-tx",
+            "-q",
+        ])
+        .unwrap();
+        assert_eq!(
+            options.command,
+            Command::Sort {
+                input: "in.csv".to_string(),
+                output: "out.csv".to_string(),
+                by: SortKey::ClientTx,
+                max_rows: SORT_ROWS,
+            }
+        );
+        assert_eq!(options.inputs(), ["in.csv"]);
+        assert!(options.quiet);
+
+        let options = parse_args([
+            "tx-engine",
+            "--error-report",
+            "bad.csv",
+            "process",
+            "a.csv",
+            "-",
+        ])
+        .unwrap();
+        assert!(matches!(options.command, Command::Process { .. }));
+        assert_eq!(options.inputs(), ["a.csv", "-"]);
+        assert_eq!(options.on_error, ErrorPolicy::Report);
+    }
+
+    #[test]
+    fn test_conflicting_options() {
+        let error = |args: &[&str]| parse_args(args).unwrap_err().kind();
+        assert_eq!(
+            error(&[
+                "tx-engine",
+                "in.csv",
+                "--dry-run",
+                "--ledger",
+                "out.beancount"
+            ]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            error(&["tx-engine", "in.csv", "--dry-run", "--threads", "2"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            error(&["tx-engine", "in.csv", "--resume"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            error(&["tx-engine", "merge", "sorted.csv"]),
+            ErrorKind::TooFewValues
+        );
+    }
+
+    #[test]
+    fn test_shadow_takes_config_a() {
+        let path = env::temp_dir().join(format!("tx-engine-shadow-{}.toml", std::process::id()));
+        fs::write(&path, "scale = 2\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let options = parse_args([
+            "tx-engine",
+            "shadow",
+            "--config-a",
+            path,
+            "--config-b",
+            path,
+            "in.csv",
+        ])
+        .unwrap();
+        assert_eq!(options.scale(), 2);
+        assert_eq!(options.inputs(), ["in.csv"]);
+
+        let error = parse_args([
+            "tx-engine",
+            "--config",
+            path,
+            "shadow",
+            "--config-a",
+            path,
+            "--config-b",
+            path,
+            "in.csv",
+        ])
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,221 @@
+//! K-way merge of transaction streams that are each already in order.
+//!
+//! Transactions are ordered by timestamp and then transaction id; sources without
+//! timestamps are therefore merged by transaction id, the order `sort --by tx` produces.
+//! Ties keep source order, so a deposit and its dispute from the same file stay in sequence.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt;
+
+use crate::types::{Transaction, TxId};
+
+#[derive(Debug)]
+pub enum MergeError<E> {
+    /// Source `source` (0-based) failed to produce a transaction.
+    Source { source: usize, error: E },
+    /// Source `source` yielded transaction `tx` after a later one; the merged order would
+    /// no longer be chronological.
+    OutOfOrder { source: usize, tx: TxId },
+}
+
+impl<E: fmt::Display> fmt::Display for MergeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::Source { source, error } => write!(f, "input {}: {}", source + 1, error),
+            MergeError::OutOfOrder { source, tx } => {
+                write!(f, "input {} is not sorted at tx {}", source + 1, tx)
+            }
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for MergeError<E> {}
+
+fn order_key(tx: &Transaction) -> (u64, TxId) {
+    (tx.timestamp.unwrap_or(0), tx.tx)
+}
+
+struct Head {
+    key: (u64, TxId),
+    source: usize,
+    tx: Transaction,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        (self.key, self.source) == (other.key, other.source)
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.key, self.source).cmp(&(other.key, other.source))
+    }
+}
+
+/// Iterator produced by [`merge_sorted`]. Only one pending transaction per source is held
+/// in memory.
+pub struct MergeSorted<I> {
+    sources: Vec<I>,
+    heap: BinaryHeap<Reverse<Head>>,
+    /// Whether the heap has been seeded with the first transaction of every source.
+    primed: bool,
+    done: bool,
+}
+
+/// Merges sources that are each sorted by timestamp then transaction id into a single
+/// stream in that order. Stops after the first error.
+pub fn merge_sorted<I, E>(sources: Vec<I>) -> MergeSorted<I>
+where
+    I: Iterator<Item = Result<Transaction, E>>,
+{
+    MergeSorted {
+        heap: BinaryHeap::with_capacity(sources.len()),
+        sources,
+        primed: false,
+        done: false,
+    }
+}
+
+impl<I, E> MergeSorted<I>
+where
+    I: Iterator<Item = Result<Transaction, E>>,
+{
+    /// Queues the next transaction from `source`, which must not sort before `after`.
+    fn pull(&mut self, source: usize, after: (u64, TxId)) -> Result<(), MergeError<E>> {
+        match self.sources[source].next() {
+            None => Ok(()),
+            Some(Err(error)) => Err(MergeError::Source { source, error }),
+            Some(Ok(tx)) if order_key(&tx) < after => {
+                Err(MergeError::OutOfOrder { source, tx: tx.tx })
+            }
+            Some(Ok(tx)) => {
+                self.heap.push(Reverse(Head {
+                    key: order_key(&tx),
+                    source,
+                    tx,
+                }));
+                Ok(())
+            }
+        }
+    }
+
+    fn advance(&mut self) -> Result<Option<Transaction>, MergeError<E>> {
+        if !self.primed {
+            self.primed = true;
+            for source in 0..self.sources.len() {
+                self.pull(source, (0, 0))?;
+            }
+        }
+        let Some(Reverse(head)) = self.heap.pop() else {
+            return Ok(None);
+        };
+        self.pull(head.source, head.key)?;
+        Ok(Some(head.tx))
+    }
+}
+
+impl<I, E> Iterator for MergeSorted<I>
+where
+    I: Iterator<Item = Result<Transaction, E>>,
+{
+    type Item = Result<Transaction, MergeError<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.advance().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+
+    fn tx(
+        tx_type: TransactionType,
+        tx: TxId,
+        timestamp: Option<u64>,
+    ) -> Result<Transaction, String> {
+        Ok(Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: None,
+            timestamp,
+            to_client: None,
+            metadata: Default::default(),
+        })
+    }
+
+    fn ids(sources: Vec<Vec<Result<Transaction, String>>>) -> Vec<TxId> {
+        let sources = sources.into_iter().map(Vec::into_iter).collect();
+        merge_sorted(sources).map(|r| r.unwrap().tx).collect()
+    }
+
+    #[test]
+    fn test_merges_by_tx_keeping_source_order_on_ties() {
+        use TransactionType::*;
+        let a = vec![
+            tx(Deposit, 1, None),
+            tx(Dispute, 1, None),
+            tx(Deposit, 4, None),
+        ];
+        let b = vec![tx(Deposit, 2, None), tx(Deposit, 3, None)];
+        let merged: Vec<_> = merge_sorted(vec![a.into_iter(), b.into_iter()])
+            .map(|r| r.unwrap())
+            .collect();
+        let order: Vec<(TxId, bool)> = merged
+            .iter()
+            .map(|t| (t.tx, matches!(t.tx_type, Dispute)))
+            .collect();
+        assert_eq!(
+            order,
+            [(1, false), (1, true), (2, false), (3, false), (4, false)]
+        );
+    }
+
+    #[test]
+    fn test_merges_by_timestamp_first() {
+        use TransactionType::Deposit;
+        let a = vec![tx(Deposit, 9, Some(10)), tx(Deposit, 1, Some(30))];
+        let b = vec![tx(Deposit, 5, Some(20))];
+        assert_eq!(ids(vec![a, b]), [9, 5, 1]);
+    }
+
+    #[test]
+    fn test_reports_unsorted_source_and_source_errors() {
+        use TransactionType::Deposit;
+        let a = vec![tx(Deposit, 3, None), tx(Deposit, 2, None)];
+        let results: Vec<_> = merge_sorted(vec![a.into_iter()]).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "input 1 is not sorted at tx 2"
+        );
+
+        let b = vec![tx(Deposit, 1, None), Err("bad row".to_string())];
+        let c = vec![tx(Deposit, 2, None)];
+        let results: Vec<_> = merge_sorted(vec![b.into_iter(), c.into_iter()]).collect();
+        assert!(matches!(
+            results.last(),
+            Some(Err(MergeError::Source { source: 0, .. }))
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+//! Prometheus metrics, served by the HTTP server as `GET /metrics`.
+//!
+//! [`render`] writes the text exposition format: gauges of the accounts in memory and the
+//! funds they hold, counters of transactions by type and result and of rejections by
+//! reason from [`Engine::stats`], and a histogram of how long `POST /transactions`
+//! requests took to process, which the server records in [`Metrics`].
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::engine::Engine;
+use crate::stats::TYPES;
+use crate::types::Money;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Latency of processing requests, updated without locking.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Observations per bucket, not cumulative; the last counts those above every bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one request that took `elapsed` to process.
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+/// The metrics of `engine` and `metrics` in the Prometheus text format.
+pub fn render(engine: &Engine, metrics: &Metrics) -> String {
+    let (mut accounts, mut locked, mut held) = (0u64, 0u64, Money::ZERO);
+    for (_, account) in engine.accounts() {
+        accounts += 1;
+        locked += u64::from(account.locked);
+        held = held.saturating_add(account.held);
+    }
+    let scale = engine.config().precision.scale;
+    let stats = engine.stats();
+
+    let mut out = String::new();
+    header(
+        &mut out,
+        "tx_engine_accounts",
+        "gauge",
+        "Accounts in memory.",
+    );
+    line(&mut out, "tx_engine_accounts", accounts);
+    header(
+        &mut out,
+        "tx_engine_accounts_locked",
+        "gauge",
+        "Locked accounts in memory.",
+    );
+    line(&mut out, "tx_engine_accounts_locked", locked);
+    header(
+        &mut out,
+        "tx_engine_held_funds",
+        "gauge",
+        "Funds held by open disputes.",
+    );
+    line(&mut out, "tx_engine_held_funds", held.format(scale));
+
+    let name = "tx_engine_transactions_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Transactions processed, by type and result.",
+    );
+    for tx_type in TYPES {
+        let counts = stats.counts(tx_type);
+        for (result, count) in [("accepted", counts.accepted), ("rejected", counts.rejected)] {
+            let labels = format!("type=\"{}\",result=\"{}\"", tx_type, result);
+            line(&mut out, &format!("{}{{{}}}", name, labels), count);
+        }
+    }
+    let name = "tx_engine_rejections_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Rejected transactions, by reason.",
+    );
+    for (reason, count) in stats.rejected.iter() {
+        line(
+            &mut out,
+            &format!("{}{{reason=\"{}\"}}", name, reason.code()),
+            count,
+        );
+    }
+
+    let name = "tx_engine_request_duration_seconds";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Time taken to process POST /transactions.",
+    );
+    let mut cumulative = 0;
+    for (bound, count) in BUCKETS.iter().zip(&metrics.buckets) {
+        cumulative += count.load(Ordering::Relaxed);
+        line(
+            &mut out,
+            &format!("{}_bucket{{le=\"{}\"}}", name, bound),
+            cumulative,
+        );
+    }
+    cumulative += metrics.buckets[BUCKETS.len()].load(Ordering::Relaxed);
+    line(
+        &mut out,
+        &format!("{}_bucket{{le=\"+Inf\"}}", name),
+        cumulative,
+    );
+    let sum = metrics.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    line(&mut out, &format!("{}_sum", name), sum);
+    line(&mut out, &format!("{}_count", name), cumulative);
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn line(out: &mut String, series: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "{} {}", series, value);
+}
+
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut engine = Engine::new();
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10.0\n\
+                   deposit,2,2,2.5\n\
+                   dispute,2,2,\n\
+                   chargeback,2,2,\n\
+                   deposit,1,3,1.5\n\
+                   dispute,1,3,\n\
+                   withdrawal,1,4,50.0\n";
+        engine.process_csv(csv.as_bytes()).unwrap();
+        let metrics = Metrics::new();
+        metrics.observe(Duration::from_micros(700));
+        metrics.observe(Duration::from_secs(10));
+
+        let text = render(&engine, &metrics);
+        for expected in [
+            "# TYPE tx_engine_accounts gauge\ntx_engine_accounts 2\n",
+            "tx_engine_accounts_locked 1\n",
+            "tx_engine_held_funds 1.5000\n",
+            "tx_engine_transactions_total{type=\"deposit\",result=\"accepted\"} 3\n",
+            "tx_engine_transactions_total{type=\"withdrawal\",result=\"rejected\"} 1\n",
+            "tx_engine_rejections_total{reason=\"insufficient_funds\"} 1\n",
+            "tx_engine_request_duration_seconds_bucket{le=\"0.0005\"} 0\n",
+            "tx_engine_request_duration_seconds_bucket{le=\"0.001\"} 1\n",
+            "tx_engine_request_duration_seconds_bucket{le=\"5\"} 1\n",
+            "tx_engine_request_duration_seconds_bucket{le=\"+Inf\"} 2\n",
+            "tx_engine_request_duration_seconds_sum 10.0007\n",
+            "tx_engine_request_duration_seconds_count 2\n",
+        ] {
+            assert!(
+                text.contains(expected),
+                "missing {:?} in\n{}",
+                expected,
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Recording test double for code that drives a [`TransactionProcessor`].
+//!
+//! `MockEngine` applies no balance rules. It records every transaction it receives, answers
+//! with scripted outcomes, and reports whatever accounts the test has set up, so
+//! orchestration code can be tested without building real balance scenarios.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::outcome::{ProcessError, ProcessOutcome};
+use crate::processor::TransactionProcessor;
+use crate::types::{Account, AccountOutput, ClientId, DEFAULT_SCALE, Transaction};
+
+#[derive(Debug, Default)]
+pub struct MockEngine {
+    received: Vec<Transaction>,
+    script: VecDeque<Result<ProcessOutcome, ProcessError>>,
+    fallback: Option<Result<ProcessOutcome, ProcessError>>,
+    accounts: BTreeMap<ClientId, Account>,
+}
+
+impl MockEngine {
+    /// A mock that accepts every transaction until outcomes are scripted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the result for the next unscripted call to `process`. Queued results are
+    /// returned in order.
+    pub fn push_outcome(&mut self, outcome: Result<ProcessOutcome, ProcessError>) {
+        self.script.push_back(outcome);
+    }
+
+    /// Result returned once the queued outcomes run out. Without one, every transaction
+    /// is accepted with the outcome matching its type.
+    pub fn with_fallback(mut self, outcome: Result<ProcessOutcome, ProcessError>) -> Self {
+        self.fallback = Some(outcome);
+        self
+    }
+
+    /// Sets the balances reported for `client` by `query` and `output`.
+    pub fn set_account(&mut self, client: ClientId, account: Account) {
+        self.accounts.insert(client, account);
+    }
+
+    /// Every transaction passed to `process`, in order, whatever its scripted outcome.
+    pub fn received(&self) -> &[Transaction] {
+        &self.received
+    }
+
+    /// Clears the recorded transactions, keeping the script and accounts.
+    pub fn clear_received(&mut self) {
+        self.received.clear();
+    }
+}
+
+impl TransactionProcessor for MockEngine {
+    fn process(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        let outcome = self
+            .script
+            .pop_front()
+            .or(self.fallback)
+            .unwrap_or_else(|| Ok(ProcessOutcome::for_type(&tx.tx_type)));
+        self.received.push(tx);
+        outcome
+    }
+
+    fn output(&self) -> Vec<AccountOutput> {
+        self.accounts
+            .iter()
+            .map(|(&client, account)| AccountOutput {
+                client,
+                available: account.available,
+                held: account.held,
+                total: account.total(),
+                locked: account.locked,
+                reserved: None,
+                last_activity: None,
+                closed: None,
+                overdrawn: None,
+                activity: None,
+                scale: DEFAULT_SCALE,
+            })
+            .collect()
+    }
+
+    fn query(&self, client: ClientId) -> Option<Account> {
+        self.accounts.get(&client).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Money, TransactionType, TxId};
+
+    fn tx(tx_type: TransactionType, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_records_and_follows_script() {
+        let mut mock = MockEngine::new().with_fallback(Err(ProcessError::AccountLocked));
+        mock.push_outcome(Err(ProcessError::InsufficientFunds));
+        mock.push_outcome(Ok(ProcessOutcome::Withdrawn));
+
+        let results: Vec<_> = (1..=3)
+            .map(|id| mock.process(tx(TransactionType::Withdrawal, id)))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                Err(ProcessError::InsufficientFunds),
+                Ok(ProcessOutcome::Withdrawn),
+                Err(ProcessError::AccountLocked)
+            ]
+        );
+        let ids: Vec<TxId> = mock.received().iter().map(|t| t.tx).collect();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_defaults_and_scripted_accounts() {
+        let mut mock = MockEngine::new();
+        assert_eq!(
+            mock.process(tx(TransactionType::Chargeback, 1)),
+            Ok(ProcessOutcome::ChargedBack)
+        );
+        assert_eq!(mock.query(1), None);
+
+        let account = Account {
+            available: Money::from_units(5),
+            locked: true,
+            ..Account::default()
+        };
+        mock.set_account(1, account);
+        assert_eq!(mock.query(1), Some(account));
+        assert_eq!(mock.output()[0].total, Money::from_units(5));
+
+        mock.clear_received();
+        assert!(mock.received().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! Multilateral netting of obligations between clients.
+//!
+//! Gross obligations (party A owes party B some amount) are reduced to one net position per
+//! party, and those positions are settled by greedily matching the largest debtor with the
+//! largest creditor. That takes at most one fewer movement than the number of parties with a
+//! non-zero position, but not always the fewest possible: finding those means splitting the
+//! parties into as many groups that net to zero as possible, which is NP-hard.
+//!
+//! [`Engine::obligations`](crate::engine::Engine::obligations) gives the stored transfers as
+//! obligations, which `tx-engine --settlement-out <file>` nets and writes as CSV.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "io")]
+use std::io::{self, Write};
+
+#[cfg(feature = "io")]
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::types::{ClientId, Money};
+
+/// `from` owes `to` the fixed-point `amount`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Obligation {
+    pub from: ClientId,
+    pub to: ClientId,
+    pub amount: Money,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SettlementInstruction {
+    pub from: ClientId,
+    pub to: ClientId,
+    pub amount: Money,
+}
+
+/// Net position per party: positive means the party is owed money, negative that it owes.
+/// Parties whose obligations cancel out are omitted.
+pub fn net_positions<I>(obligations: I) -> BTreeMap<ClientId, Money>
+where
+    I: IntoIterator<Item = Obligation>,
+{
+    let mut positions = BTreeMap::new();
+    for obligation in obligations {
+        if obligation.from == obligation.to {
+            continue;
+        }
+        let from: &mut Money = positions.entry(obligation.from).or_default();
+        *from = from.saturating_sub(obligation.amount);
+        let to: &mut Money = positions.entry(obligation.to).or_default();
+        *to = to.saturating_add(obligation.amount);
+    }
+    positions.retain(|_, position| !position.is_zero());
+    positions
+}
+
+/// Settlement movements that bring every net position to zero. Ties are broken by party
+/// id, so the result is deterministic.
+pub fn settle(positions: &BTreeMap<ClientId, Money>) -> Vec<SettlementInstruction> {
+    let mut debtors: Vec<(ClientId, Money)> = positions
+        .iter()
+        .filter(|(_, p)| p.is_negative())
+        .map(|(&party, &p)| (party, p.saturating_neg()))
+        .collect();
+    let mut creditors: Vec<(ClientId, Money)> = positions
+        .iter()
+        .filter(|(_, p)| p.is_positive())
+        .map(|(&party, &p)| (party, p))
+        .collect();
+
+    let mut instructions = Vec::new();
+    while let (Some(d), Some(c)) = (largest(&debtors), largest(&creditors)) {
+        let amount = debtors[d].1.min(creditors[c].1);
+        instructions.push(SettlementInstruction {
+            from: debtors[d].0,
+            to: creditors[c].0,
+            amount,
+        });
+        debtors[d].1 -= amount;
+        creditors[c].1 -= amount;
+    }
+    instructions
+}
+
+fn largest(parties: &[(ClientId, Money)]) -> Option<usize> {
+    parties
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, amount))| amount.is_positive())
+        .max_by(|(_, a), (_, b)| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(index, _)| index)
+}
+
+#[cfg(feature = "io")]
+#[derive(Serialize)]
+struct InstructionRow {
+    from: ClientId,
+    to: ClientId,
+    amount: Decimal,
+}
+
+#[cfg(feature = "io")]
+/// Writes settlement instructions as CSV with columns `from`, `to`, `amount`, amounts being
+/// fixed-point with `scale` decimal places.
+pub fn write_instructions_csv<W: Write>(
+    writer: W,
+    instructions: &[SettlementInstruction],
+    scale: u32,
+) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for instruction in instructions {
+        writer.serialize(InstructionRow {
+            from: instruction.from,
+            to: instruction.to,
+            amount: instruction.amount.to_decimal(scale),
+        })?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owes(from: ClientId, to: ClientId, amount: i64) -> Obligation {
+        Obligation {
+            from,
+            to,
+            amount: Money::from_units(amount),
+        }
+    }
+
+    #[test]
+    fn test_cycle_nets_to_nothing() {
+        let positions = net_positions([owes(1, 2, 10), owes(2, 3, 10), owes(3, 1, 10)]);
+        assert!(positions.is_empty());
+        assert!(settle(&positions).is_empty());
+    }
+
+    #[test]
+    fn test_netted_intermediary_moves_nothing() {
+        // 1 owes 2, 2 owes 3, 1 owes 3: net is 1 -> -30, 2 -> 0, 3 -> +30.
+        let positions = net_positions([owes(1, 2, 10), owes(2, 3, 10), owes(1, 3, 20)]);
+        assert_eq!(
+            settle(&positions),
+            vec![SettlementInstruction {
+                from: 1,
+                to: 3,
+                amount: Money::from_units(30)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_settlement_balances_every_position() {
+        let obligations = [
+            owes(1, 2, 70),
+            owes(3, 2, 20),
+            owes(4, 1, 15),
+            owes(2, 5, 5),
+        ];
+        let positions = net_positions(obligations);
+        let instructions = settle(&positions);
+        assert!(instructions.len() < positions.len());
+
+        let settled = net_positions(
+            instructions
+                .iter()
+                .map(|i| owes(i.to, i.from, i.amount.units()))
+                .chain(obligations),
+        );
+        assert!(settled.is_empty());
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_csv_output() {
+        let instruction = SettlementInstruction {
+            from: 1,
+            to: 3,
+            amount: Money::from_units(300_000),
+        };
+        let mut out = Vec::new();
+        write_instructions_csv(&mut out, &[instruction], 4).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "from,to,amount\n1,3,30.0000\n"
+        );
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_settles_engine_transfers() {
+        use crate::engine::Engine;
+        use crate::types::{Transaction, TransactionType};
+        use rust_decimal_macros::dec;
+
+        let mut engine = Engine::new();
+        let mut process = |tx_type, client, tx, to_client, amount| {
+            engine
+                .process(Transaction {
+                    tx_type,
+                    client,
+                    tx,
+                    amount,
+                    timestamp: None,
+                    to_client,
+                    metadata: Default::default(),
+                })
+                .unwrap();
+        };
+        process(TransactionType::Deposit, 1, 1, None, Some(dec!(100)));
+        process(TransactionType::Deposit, 2, 2, None, Some(dec!(100)));
+        process(TransactionType::Transfer, 1, 3, Some(2), Some(dec!(30)));
+        process(TransactionType::Transfer, 2, 4, Some(3), Some(dec!(10)));
+        process(TransactionType::Transfer, 2, 5, Some(1), Some(dec!(5)));
+        // Charged back, so no longer owed.
+        process(TransactionType::Transfer, 1, 6, Some(3), Some(dec!(40)));
+        process(TransactionType::Dispute, 1, 6, None, None);
+        process(TransactionType::Chargeback, 1, 6, None, None);
+
+        let instructions = settle(&net_positions(engine.obligations()));
+        let mut out = Vec::new();
+        write_instructions_csv(&mut out, &instructions, engine.config().precision.scale).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "from,to,amount\n1,2,15.0000\n1,3,10.0000\n"
+        );
+    }
+}
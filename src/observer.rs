@@ -0,0 +1,44 @@
+//! Callbacks for the moments in an account's life a fraud service reacts to, registered
+//! with [`Engine::subscribe`](crate::Engine::subscribe).
+//!
+//! Each callback receives the [`AuditEvent`] of the change, with the account before and
+//! after. Like audit records, the notifications of a batch are only delivered once it
+//! commits, and a rolled-back batch sends none. All methods do nothing by default, so an
+//! observer implements only those it needs.
+
+use crate::audit::{AuditAction, AuditEvent};
+use crate::types::{Money, TransactionType};
+
+pub trait EngineObserver: Send {
+    /// The account was locked, by a chargeback, an overflow in freeze mode or an operator.
+    fn on_account_locked(&mut self, _event: &AuditEvent) {}
+
+    /// The account was unlocked, automatically or by an operator.
+    fn on_account_unlocked(&mut self, _event: &AuditEvent) {}
+
+    /// A chargeback took the disputed funds from the account; `event.tx` is the transaction
+    /// charged back. Called for the account that held the funds, which for a transfer is
+    /// the recipient's.
+    fn on_chargeback(&mut self, _event: &AuditEvent) {}
+
+    /// The account's available balance dropped below zero.
+    fn on_balance_negative(&mut self, _event: &AuditEvent) {}
+}
+
+/// Calls the callbacks of `observer` that `event` calls for. `charged_back` says whether a
+/// chargeback took its funds from this account.
+pub(crate) fn notify(observer: &mut dyn EngineObserver, event: &AuditEvent, charged_back: bool) {
+    let (before, after) = (&event.before, &event.after);
+    if charged_back && event.action == AuditAction::Applied(TransactionType::Chargeback) {
+        observer.on_chargeback(event);
+    }
+    if event.locked() {
+        observer.on_account_locked(event);
+    }
+    if before.locked && !after.locked {
+        observer.on_account_unlocked(event);
+    }
+    if before.available >= Money::ZERO && after.available < Money::ZERO {
+        observer.on_balance_negative(event);
+    }
+}
@@ -0,0 +1,330 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::types::{ClientId, TransactionType, TxId};
+
+/// What a successfully applied transaction did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessOutcome {
+    Deposited,
+    Withdrawn,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Transferred,
+    ChargebackReversed,
+    Held,
+    Released,
+}
+
+impl ProcessOutcome {
+    /// The outcome of applying a transaction of type `tx_type`.
+    pub fn for_type(tx_type: &TransactionType) -> Self {
+        match tx_type {
+            TransactionType::Deposit => ProcessOutcome::Deposited,
+            TransactionType::Withdrawal => ProcessOutcome::Withdrawn,
+            TransactionType::Dispute => ProcessOutcome::Disputed,
+            TransactionType::Resolve => ProcessOutcome::Resolved,
+            TransactionType::Chargeback => ProcessOutcome::ChargedBack,
+            TransactionType::Transfer => ProcessOutcome::Transferred,
+            TransactionType::ChargebackReversal => ProcessOutcome::ChargebackReversed,
+            TransactionType::Hold => ProcessOutcome::Held,
+            TransactionType::Release => ProcessOutcome::Released,
+        }
+    }
+}
+
+/// Why a transaction was rejected. A rejected transaction leaves balances unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProcessError {
+    /// Deposit, withdrawal, transfer or hold without an amount, or any transaction with a
+    /// zero or negative one.
+    InvalidAmount,
+    /// Transfer without a recipient, or to the sending client itself.
+    InvalidRecipient,
+    /// A deposit, withdrawal, transfer or hold reusing the id of one already applied.
+    DuplicateTransaction,
+    AccountLocked,
+    InsufficientFunds,
+    /// The referenced transaction does not exist (or is not a deposit, withdrawal or
+    /// transfer).
+    UnknownTransaction,
+    /// The referenced transaction was made by another client.
+    ClientMismatch,
+    AlreadyDisputed,
+    NotDisputed,
+    /// The referenced transaction was charged back and can no longer be disputed.
+    AlreadyChargedBack,
+    /// A chargeback reversal referenced a transaction that is not charged back.
+    NotChargedBack,
+    /// The dispute was raised after the configured window following the transaction.
+    DisputeWindowClosed,
+    /// The disputed amount exceeds the available funds, and the shortfall policy rejects
+    /// such disputes.
+    DisputeShortfall,
+    /// A balance would leave the representable range (checked arithmetic only).
+    Overflow,
+    /// The withdrawal would take the account total below its configured floor.
+    BelowFloor,
+    /// The deposit would take the account total above its configured ceiling.
+    AboveCeiling,
+    /// The amount can't be represented in, or is out of bounds for, the engine's minor units.
+    Amount(AmountError),
+    /// The amount is larger than the configured single-transaction limit.
+    AmountLimit,
+    /// The withdrawal or transfer would exceed the client's daily limit.
+    DailyLimit,
+    /// The client already made the most transactions allowed in the velocity window.
+    VelocityLimit,
+    /// The transaction is timestamped earlier than its client's latest one, and the
+    /// ordering policy rejects those.
+    OutOfOrder,
+    /// The engine's tables have outgrown the configured memory limit.
+    MemoryLimit,
+    /// The client's account is archived and could not be read back.
+    ArchiveUnavailable,
+    /// The transaction could not be written to the write-ahead log, so it was not applied.
+    WalUnavailable,
+    /// The transaction store could not be read or written.
+    StoreUnavailable,
+    /// The account, or a transfer's other account, was closed.
+    AccountClosed,
+    /// An account can't be closed while it has a balance.
+    AccountNotEmpty,
+    /// An account can't be closed while one of its transactions is disputed.
+    OpenDispute,
+    /// A chargeback reversal referenced a chargeback that was compacted; see
+    /// [`crate::compaction`].
+    TransactionCompacted,
+    /// Applying the transaction would break an engine invariant, so it was undone; see
+    /// [`crate::invariants`].
+    InvariantViolation,
+    /// A release referenced a hold that was already released in full.
+    HoldReleased,
+    /// A release asked for more than is left on the hold.
+    ReleaseExceedsHold,
+    /// A value date was given for a transaction other than a deposit, withdrawal or
+    /// transfer.
+    NotSchedulable,
+}
+
+impl ProcessError {
+    /// Stable snake_case identifier, for reports and metrics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProcessError::InvalidAmount => "invalid_amount",
+            ProcessError::InvalidRecipient => "invalid_recipient",
+            ProcessError::DuplicateTransaction => "duplicate_transaction",
+            ProcessError::AccountLocked => "account_locked",
+            ProcessError::InsufficientFunds => "insufficient_funds",
+            ProcessError::UnknownTransaction => "unknown_transaction",
+            ProcessError::ClientMismatch => "client_mismatch",
+            ProcessError::AlreadyDisputed => "already_disputed",
+            ProcessError::NotDisputed => "not_disputed",
+            ProcessError::AlreadyChargedBack => "already_charged_back",
+            ProcessError::NotChargedBack => "not_charged_back",
+            ProcessError::DisputeWindowClosed => "dispute_window_closed",
+            ProcessError::DisputeShortfall => "dispute_shortfall",
+            ProcessError::Overflow => "overflow",
+            ProcessError::BelowFloor => "below_floor",
+            ProcessError::AboveCeiling => "above_ceiling",
+            ProcessError::Amount(error) => error.code(),
+            ProcessError::AmountLimit => "amount_limit",
+            ProcessError::DailyLimit => "daily_limit",
+            ProcessError::VelocityLimit => "velocity_limit",
+            ProcessError::OutOfOrder => "out_of_order",
+            ProcessError::MemoryLimit => "memory_limit",
+            ProcessError::ArchiveUnavailable => "archive_unavailable",
+            ProcessError::WalUnavailable => "wal_unavailable",
+            ProcessError::StoreUnavailable => "store_unavailable",
+            ProcessError::AccountClosed => "account_closed",
+            ProcessError::AccountNotEmpty => "account_not_empty",
+            ProcessError::OpenDispute => "open_dispute",
+            ProcessError::TransactionCompacted => "transaction_compacted",
+            ProcessError::InvariantViolation => "invariant_violation",
+            ProcessError::HoldReleased => "hold_released",
+            ProcessError::ReleaseExceedsHold => "release_exceeds_hold",
+            ProcessError::NotSchedulable => "not_schedulable",
+        }
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ProcessError::InvalidAmount => "missing or non-positive amount",
+            ProcessError::InvalidRecipient => "transfer needs a recipient other than the sender",
+            ProcessError::DuplicateTransaction => "transaction id already used",
+            ProcessError::AccountLocked => "account is locked",
+            ProcessError::InsufficientFunds => "insufficient available funds",
+            ProcessError::UnknownTransaction => "referenced transaction not found",
+            ProcessError::ClientMismatch => "referenced transaction belongs to another client",
+            ProcessError::AlreadyDisputed => "transaction is already disputed",
+            ProcessError::NotDisputed => "transaction is not disputed",
+            ProcessError::AlreadyChargedBack => "transaction was charged back",
+            ProcessError::NotChargedBack => "transaction is not charged back",
+            ProcessError::DisputeWindowClosed => "dispute window has closed",
+            ProcessError::DisputeShortfall => "available funds don't cover the disputed amount",
+            ProcessError::Overflow => "balance would overflow",
+            ProcessError::BelowFloor => "balance would fall below the floor",
+            ProcessError::AboveCeiling => "balance would exceed the ceiling",
+            ProcessError::Amount(error) => return fmt::Display::fmt(error, f),
+            ProcessError::AmountLimit => "amount exceeds the transaction limit",
+            ProcessError::DailyLimit => "daily withdrawal limit reached",
+            ProcessError::VelocityLimit => "too many transactions in the velocity window",
+            ProcessError::OutOfOrder => "timestamp precedes the client's latest transaction",
+            ProcessError::MemoryLimit => "engine memory limit reached",
+            ProcessError::ArchiveUnavailable => "archived account could not be restored",
+            ProcessError::WalUnavailable => "write-ahead log could not be written",
+            ProcessError::StoreUnavailable => "transaction store could not be accessed",
+            ProcessError::AccountClosed => "account is closed",
+            ProcessError::AccountNotEmpty => "account still has a balance",
+            ProcessError::OpenDispute => "account has an open dispute",
+            ProcessError::TransactionCompacted => "referenced transaction was compacted",
+            ProcessError::InvariantViolation => "transaction would break an engine invariant",
+            ProcessError::HoldReleased => "hold was already released",
+            ProcessError::ReleaseExceedsHold => "release exceeds the amount on hold",
+            ProcessError::NotSchedulable => {
+                "only deposits, withdrawals and transfers can be post-dated"
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl Error for ProcessError {}
+
+impl From<AmountError> for ProcessError {
+    fn from(error: AmountError) -> Self {
+        ProcessError::Amount(error)
+    }
+}
+
+/// Why an input amount was not converted to minor units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AmountError {
+    /// Too large in magnitude for `i64` minor units at the engine's scale.
+    Overflow,
+    /// Not zero, but less than one minor unit once rounded, so it would change nothing.
+    BelowMinorUnit,
+    /// More decimal places than the engine's scale, with a rounding mode that rejects those.
+    ExcessPrecision,
+    /// Larger than the engine's configured maximum amount.
+    TooLarge,
+}
+
+impl AmountError {
+    /// Stable snake_case identifier, for reports and metrics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AmountError::Overflow => "amount_overflow",
+            AmountError::BelowMinorUnit => "amount_below_minor_unit",
+            AmountError::ExcessPrecision => "excess_precision",
+            AmountError::TooLarge => "amount_too_large",
+        }
+    }
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AmountError::Overflow => "amount is out of the representable range",
+            AmountError::BelowMinorUnit => "amount is smaller than one minor unit",
+            AmountError::ExcessPrecision => "amount has more decimal places than the scale",
+            AmountError::TooLarge => "amount exceeds the maximum",
+        })
+    }
+}
+
+impl Error for AmountError {}
+
+/// A batch member was rejected, so the whole batch was rolled back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchRejected {
+    /// Position of the rejected transaction within the batch.
+    pub index: usize,
+    pub error: ProcessError,
+}
+
+impl fmt::Display for BatchRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "batch member {} rejected ({}); batch rolled back",
+            self.index, self.error
+        )
+    }
+}
+
+impl Error for BatchRejected {}
+
+/// Why [`Engine::process_batch`](crate::Engine::process_batch) applied none of its
+/// transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchError {
+    /// A batch was already open. Batches don't nest, so the transactions weren't processed
+    /// and the open batch was left as it was.
+    BatchOpen,
+    /// A member was rejected, so the batch was rolled back.
+    Rejected(BatchRejected),
+}
+
+impl From<BatchRejected> for BatchError {
+    fn from(rejected: BatchRejected) -> Self {
+        BatchError::Rejected(rejected)
+    }
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::BatchOpen => f.write_str("a batch is already open"),
+            BatchError::Rejected(rejected) => rejected.fmt(f),
+        }
+    }
+}
+
+impl Error for BatchError {}
+
+/// Why [`Engine::merge_clients`](crate::Engine::merge_clients) refused to merge two
+/// clients, or [`Engine::merge`](crate::Engine::merge) two engines. Nothing is changed when
+/// it does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeError {
+    /// Both ids are the same client, possibly through an earlier merge.
+    SameClient,
+    /// The client being merged away has no account.
+    UnknownClient(ClientId),
+    /// A combined balance would leave the representable range.
+    Overflow,
+    /// A batch is open.
+    InBatch,
+    /// The engine already holds state, which partitions processed separately can't see.
+    NotEmpty,
+    /// Both engines store a transaction with this id.
+    DuplicateTransaction(TxId),
+    /// One of the accounts is archived and could not be read back.
+    ArchiveUnavailable,
+    /// The transaction store failed while the merged client's transactions were moved.
+    /// Unlike the other errors, some of them may already have been moved.
+    StoreUnavailable,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::SameClient => f.write_str("cannot merge a client into itself"),
+            MergeError::UnknownClient(client) => write!(f, "client {} has no account", client),
+            MergeError::Overflow => f.write_str("combined balance out of range"),
+            MergeError::InBatch => f.write_str("cannot merge clients inside a batch"),
+            MergeError::NotEmpty => f.write_str("partitioned processing needs a new engine"),
+            MergeError::DuplicateTransaction(tx) => {
+                write!(f, "transaction {} is stored by both engines", tx)
+            }
+            MergeError::ArchiveUnavailable => f.write_str("archived account could not be read"),
+            MergeError::StoreUnavailable => f.write_str("transaction store could not be accessed"),
+        }
+    }
+}
+
+impl Error for MergeError {}
@@ -0,0 +1,304 @@
+//! Batch processing spread over threads with rayon (feature `rayon`), through
+//! [`Engine::process_partitioned`].
+//!
+//! Transactions are grouped so that no group can affect another: clients linked by a
+//! transfer, or by using the same transaction id, end up in the same group, so transfers,
+//! disputes naming another client's transaction and reused ids are treated exactly as by a
+//! single engine. The groups are spread over as many partitions as rayon has threads, each
+//! partition is processed in input order by an engine of its own, and the partitions are
+//! merged into the engine at the end.
+//!
+//! Timers see the same time as in a single engine, as with a
+//! [`ShardedEngine`](crate::sharded::ShardedEngine): each transaction's partition first
+//! catches up to the latest timestamp before it, and every partition is advanced to the
+//! final time. Given that, the accounts, stored transactions and results come out as
+//! [`Engine::process`] would leave them. What works per partition is dormancy by
+//! transaction count and the memory limit, and histories and domain events are ordered by
+//! partition rather than across all of them. The engine's clock isn't consulted, and its
+//! event sinks, audit sinks, observers and write-ahead log don't see these transactions.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::engine::Engine;
+use crate::outcome::{MergeError, ProcessError, ProcessOutcome};
+use crate::types::{ClientId, Transaction, TransactionType, TxId};
+
+impl Engine {
+    /// Processes `transactions` as [`Engine::process`] would one after the other, with
+    /// independent groups of clients processed in parallel; see [`crate::parallel`]. Returns
+    /// each transaction's result, in input order.
+    ///
+    /// The engine must be new, since the partitions can't see its state: fails with
+    /// `NotEmpty` otherwise, and `InBatch` inside a batch. If merging a partition fails,
+    /// with `StoreUnavailable` or `ArchiveUnavailable`, the engine holds the partitions
+    /// merged before it.
+    pub fn process_partitioned(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Result<Vec<Result<ProcessOutcome, ProcessError>>, MergeError> {
+        if self.in_batch() {
+            return Err(MergeError::InBatch);
+        }
+        if !self.is_new() {
+            return Err(MergeError::NotEmpty);
+        }
+        let count = transactions.len();
+        let partitions = partitions(&transactions, rayon::current_num_threads());
+
+        // The latest timestamp before each transaction, which its partition catches up to.
+        let mut now = self.now();
+        let mut slots: Vec<Option<(u64, Transaction)>> = transactions
+            .into_iter()
+            .map(|tx| {
+                let before = now;
+                now = now.max(tx.timestamp.unwrap_or(0));
+                Some((before, tx))
+            })
+            .collect();
+        let partitions: Vec<Vec<(usize, u64, Transaction)>> = partitions
+            .into_iter()
+            .map(|indices| {
+                indices
+                    .into_iter()
+                    .filter_map(|index| slots[index].take().map(|(at, tx)| (index, at, tx)))
+                    .collect()
+            })
+            .collect();
+
+        let config = self.config();
+        let processed: Vec<_> = partitions
+            .into_par_iter()
+            .map(|partition| {
+                let mut engine = Engine::with_config(config.clone());
+                let results: Vec<_> = partition
+                    .into_iter()
+                    .map(|(index, at, tx)| {
+                        engine.advance_time(at);
+                        (index, engine.process(tx))
+                    })
+                    .collect();
+                engine.advance_time(now);
+                (engine, results)
+            })
+            .collect();
+
+        let mut outcomes: Vec<Option<Result<ProcessOutcome, ProcessError>>> =
+            (0..count).map(|_| None).collect();
+        for (engine, results) in processed {
+            self.merge(engine)?;
+            for (index, result) in results {
+                outcomes[index] = Some(result);
+            }
+        }
+        self.advance_time(now);
+        Ok(outcomes.into_iter().flatten().collect())
+    }
+}
+
+/// The indices of `transactions` in at most `count` partitions, in input order within
+/// each, such that no transaction can affect one in another partition. Groups of linked
+/// clients are assigned largest first to the partition with the fewest transactions.
+fn partitions(transactions: &[Transaction], count: usize) -> Vec<Vec<usize>> {
+    let mut groups = ClientGroups::default();
+    let mut owners: HashMap<TxId, ClientId> = HashMap::new();
+    for tx in transactions {
+        let owner = *owners.entry(tx.tx).or_insert(tx.client);
+        groups.join(tx.client, owner);
+        if let (TransactionType::Transfer, Some(to)) = (tx.tx_type, tx.to_client) {
+            groups.join(tx.client, to);
+        }
+    }
+
+    let mut members: HashMap<ClientId, Vec<usize>> = HashMap::new();
+    for (index, tx) in transactions.iter().enumerate() {
+        members
+            .entry(groups.root(tx.client))
+            .or_default()
+            .push(index);
+    }
+    let mut members: Vec<Vec<usize>> = members.into_values().collect();
+    members.sort_by_key(|group| (Reverse(group.len()), group[0]));
+
+    let mut partitions: Vec<Vec<usize>> = vec![Vec::new(); count.max(1)];
+    for group in members {
+        if let Some(smallest) = partitions
+            .iter_mut()
+            .min_by_key(|partition| partition.len())
+        {
+            smallest.extend(group);
+        }
+    }
+    partitions.retain(|partition| !partition.is_empty());
+    for partition in &mut partitions {
+        partition.sort_unstable();
+    }
+    partitions
+}
+
+/// Disjoint sets of clients, joined with union by size.
+#[derive(Default)]
+struct ClientGroups {
+    parent: HashMap<ClientId, ClientId>,
+    size: HashMap<ClientId, usize>,
+}
+
+impl ClientGroups {
+    /// The client representing `client`'s group.
+    fn root(&mut self, client: ClientId) -> ClientId {
+        let mut root = client;
+        while let Some(&parent) = self.parent.get(&root) {
+            root = parent;
+        }
+        // Point the path straight at the root for later lookups.
+        let mut current = client;
+        while current != root {
+            let next = self.parent[&current];
+            self.parent.insert(current, root);
+            current = next;
+        }
+        root
+    }
+
+    fn join(&mut self, a: ClientId, b: ClientId) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a == b {
+            return;
+        }
+        let size = |groups: &Self, client| groups.size.get(&client).copied().unwrap_or(1);
+        let (small, large) = if size(self, a) < size(self, b) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let joined = size(self, small) + size(self, large);
+        self.parent.insert(small, large);
+        self.size.insert(large, joined);
+        self.size.remove(&small);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EngineConfig, ReservePolicy};
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+
+    /// A deterministic mix of every transaction type, with transfers, reused ids and
+    /// disputes that mostly name the client of a recent transaction. Clients are only
+    /// linked within blocks of ten, so there are twenty groups to spread.
+    fn transactions() -> Vec<Transaction> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut next = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        let mut clients: Vec<ClientId> = Vec::new();
+        let mut transactions = Vec::new();
+        for id in 1..=5_000u64 {
+            let mut client = next(200) as ClientId;
+            let earlier = id - next(id.min(40)) as TxId;
+            let (tx_type, tx, amount, to_client) = match next(20) {
+                0..=8 => (TransactionType::Deposit, id, Some(next(100) + 1), None),
+                9..=11 => (TransactionType::Withdrawal, id, Some(next(50) + 1), None),
+                12..=13 => (
+                    TransactionType::Transfer,
+                    id,
+                    Some(next(20) + 1),
+                    Some(client / 10 * 10 + next(10) as ClientId),
+                ),
+                14 => (TransactionType::Deposit, earlier, Some(1), None),
+                15..=16 => (TransactionType::Dispute, earlier, None, None),
+                17 => (TransactionType::Resolve, earlier, None, None),
+                _ => (TransactionType::Chargeback, earlier, None, None),
+            };
+            if tx != id {
+                let owner = clients[tx as usize - 1];
+                client = if next(10) > 0 {
+                    owner
+                } else {
+                    owner / 10 * 10 + next(10) as ClientId
+                };
+            }
+            clients.push(client);
+            transactions.push(Transaction {
+                tx_type,
+                client,
+                tx,
+                amount: amount.map(Decimal::from),
+                timestamp: Some(id * 60),
+                to_client,
+                metadata: Default::default(),
+            });
+        }
+        transactions
+    }
+
+    #[test]
+    fn test_partitioned_matches_sequential() {
+        let config = EngineConfig::default().with_reserve(ReservePolicy {
+            rate: Decimal::new(1, 1),
+            period: Duration::from_secs(3_600),
+        });
+        let mut sequential = Engine::with_config(config.clone());
+        let expected: Vec<_> = transactions()
+            .into_iter()
+            .map(|tx| sequential.process(tx))
+            .collect();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        assert_eq!(partitions(&transactions(), 4).len(), 4);
+        let mut partitioned = Engine::with_config(config);
+        let results = pool
+            .install(|| partitioned.process_partitioned(transactions()))
+            .unwrap();
+        assert_eq!(results, expected);
+        let rows = |engine: &Engine| -> Vec<_> {
+            engine
+                .output_sorted()
+                .iter()
+                .map(|a| (a.client, a.available, a.held, a.locked, a.reserved))
+                .collect()
+        };
+        assert_eq!(rows(&partitioned), rows(&sequential));
+        assert_eq!(partitioned.stats(), sequential.stats());
+
+        assert_eq!(
+            partitioned.process_partitioned(transactions()),
+            Err(MergeError::NotEmpty)
+        );
+    }
+
+    #[test]
+    fn test_linked_clients_share_a_partition() {
+        let tx = |tx_type, client, tx, to_client| Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            to_client,
+            metadata: Default::default(),
+        };
+        let transactions = [
+            tx(TransactionType::Deposit, 1, 1, None),
+            tx(TransactionType::Deposit, 2, 2, None),
+            tx(TransactionType::Transfer, 2, 3, Some(3)),
+            tx(TransactionType::Deposit, 4, 1, None),
+            tx(TransactionType::Deposit, 5, 4, None),
+        ];
+        let partitions = partitions(&transactions, 8);
+        assert_eq!(partitions.len(), 3);
+        assert!(partitions.contains(&vec![0, 3]));
+        assert!(partitions.contains(&vec![1, 2]));
+        assert_eq!(self::partitions(&transactions, 1), [vec![0, 1, 2, 3, 4]]);
+    }
+}
@@ -0,0 +1,124 @@
+//! Stable assignment of clients to shards with rendezvous (highest-random-weight) hashing.
+//!
+//! Every (client, shard) pair gets a pseudo-random weight and a client belongs to the shard
+//! with the highest weight. Adding a shard only moves the clients that now weigh highest on
+//! it (about `1 / n` of them), and removing one only moves the clients it owned, so state
+//! partitioned by an earlier topology stays mostly where it is.
+//!
+//! Weights come from a fixed mixing function rather than `std`'s hasher, so assignments are
+//! identical across processes, platforms and compiler versions.
+
+use crate::types::ClientId;
+
+/// Identifies a shard. Ids are stable names; their position in the shard list is irrelevant.
+pub type ShardId = u32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partitioner {
+    shards: Vec<ShardId>,
+}
+
+impl Partitioner {
+    /// A partitioner over shards `0..count`.
+    pub fn new(count: u32) -> Self {
+        Self::with_shards((0..count).collect())
+    }
+
+    pub fn with_shards(mut shards: Vec<ShardId>) -> Self {
+        shards.sort_unstable();
+        shards.dedup();
+        Self { shards }
+    }
+
+    pub fn shards(&self) -> &[ShardId] {
+        &self.shards
+    }
+
+    pub fn add_shard(&mut self, shard: ShardId) {
+        if let Err(index) = self.shards.binary_search(&shard) {
+            self.shards.insert(index, shard);
+        }
+    }
+
+    pub fn remove_shard(&mut self, shard: ShardId) {
+        self.shards.retain(|&s| s != shard);
+    }
+
+    /// The shard owning `client`, or `None` when there are no shards.
+    pub fn shard_for(&self, client: ClientId) -> Option<ShardId> {
+        self.shards
+            .iter()
+            .copied()
+            .max_by_key(|&shard| (weight(client, shard), shard))
+    }
+}
+
+fn weight(client: ClientId, shard: ShardId) -> u64 {
+    // The bits above the low 16 are hashed separately and are zero for smaller clients, so
+    // those keep the shards they had when client ids were 16 bits wide.
+    let high = client >> 16;
+    let high = if high == 0 {
+        0
+    } else {
+        mix(high as u64 ^ mix((high >> 64) as u64))
+    };
+    mix(((u64::from(shard) << 16) | (client & 0xFFFF) as u64) ^ high)
+}
+
+/// SplitMix64 finalizer.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assignment_is_balanced() {
+        let partitioner = Partitioner::new(4);
+        let mut counts = [0u32; 4];
+        for client in 0..=ClientId::from(u16::MAX) {
+            counts[partitioner.shard_for(client).unwrap() as usize] += 1;
+        }
+        // 16384 expected per shard.
+        assert!(
+            counts.iter().all(|&c| (15_500..17_300).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_adding_a_shard_only_moves_clients_to_it() {
+        let before = Partitioner::new(4);
+        let mut after = before.clone();
+        after.add_shard(4);
+
+        let mut moved = 0;
+        for client in 0..=ClientId::from(u16::MAX) {
+            let (old, new) = (before.shard_for(client), after.shard_for(client));
+            if old != new {
+                assert_eq!(new, Some(4));
+                moved += 1;
+            }
+        }
+        // Roughly a fifth of the clients, never a wholesale reshuffle.
+        assert!((11_500..14_700).contains(&moved), "{}", moved);
+
+        after.remove_shard(4);
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_order_of_shards_does_not_matter() {
+        let a = Partitioner::with_shards(vec![7, 3, 11]);
+        let b = Partitioner::with_shards(vec![11, 7, 3, 7]);
+        assert_eq!(a.shards(), [3, 7, 11]);
+        assert!((0..1000).all(|c| a.shard_for(c) == b.shard_for(c)));
+        assert_eq!(Partitioner::new(0).shard_for(1), None);
+    }
+}
@@ -0,0 +1,96 @@
+//! Common interface over transaction-processing engines.
+//!
+//! Code that only feeds transactions and reads balances can be written against
+//! [`TransactionProcessor`] and run unchanged on the in-memory [`Engine`] or any other
+//! implementation, such as a sharded engine, a persistent backend or a test double.
+
+use crate::engine::Engine;
+use crate::outcome::{ProcessError, ProcessOutcome};
+use crate::types::{Account, AccountOutput, ClientId, Transaction};
+
+pub trait TransactionProcessor {
+    /// Applies one transaction, reporting why it was rejected if it was. A rejected
+    /// transaction leaves balances unchanged.
+    fn process(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError>;
+
+    /// The final account report, one row per client, in no particular order.
+    fn output(&self) -> Vec<AccountOutput>;
+
+    /// Current balances of `client`, or `None` if the client has never been seen. Returned
+    /// by value so implementations that don't keep accounts in local memory can answer it.
+    fn query(&self, client: ClientId) -> Option<Account>;
+}
+
+impl TransactionProcessor for Engine {
+    fn process(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        Engine::process(self, tx)
+    }
+
+    fn output(&self) -> Vec<AccountOutput> {
+        Engine::output(self)
+    }
+
+    fn query(&self, client: ClientId) -> Option<Account> {
+        self.account(client).copied()
+    }
+}
+
+impl<P: TransactionProcessor + ?Sized> TransactionProcessor for Box<P> {
+    fn process(&mut self, tx: Transaction) -> Result<ProcessOutcome, ProcessError> {
+        (**self).process(tx)
+    }
+
+    fn output(&self) -> Vec<AccountOutput> {
+        (**self).output()
+    }
+
+    fn query(&self, client: ClientId) -> Option<Account> {
+        (**self).query(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Money, SCALE, TransactionType};
+    use rust_decimal_macros::dec;
+
+    fn run<P: TransactionProcessor>(
+        processor: &mut P,
+    ) -> Vec<Result<ProcessOutcome, ProcessError>> {
+        let deposit = processor.process(Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 3,
+            tx: 1,
+            amount: Some(dec!(2.5)),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        });
+        let dispute = processor.process(Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 3,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        });
+        vec![deposit, dispute]
+    }
+
+    #[test]
+    fn test_engine_through_trait_object() {
+        let mut processor: Box<dyn TransactionProcessor> = Box::new(Engine::new());
+        assert_eq!(
+            run(&mut processor),
+            [Ok(ProcessOutcome::Deposited), Ok(ProcessOutcome::Disputed)]
+        );
+
+        let account = processor.query(3).unwrap();
+        assert_eq!(account.held, Money::from_units(25_000));
+        assert_eq!(account.total(), Money::from_units(2 * SCALE + SCALE / 2));
+        assert_eq!(processor.query(4), None);
+        assert_eq!(processor.output().len(), 1);
+    }
+}
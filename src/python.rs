@@ -0,0 +1,182 @@
+//! Python bindings, built as the `tx_engine` extension module from the `pyo3` feature
+//! (e.g. with `maturin develop --features pyo3`).
+//!
+//! The module has three classes: `Engine`, which applies transactions and reports the
+//! accounts; `Transaction`, one input row; and `AccountOutput`, one row of the report.
+//! Amounts cross the boundary as `decimal.Decimal`, so nothing is lost to floats.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rust_decimal::Decimal;
+
+use crate::engine::Engine;
+use crate::types::{AccountOutput, ClientId, Transaction, TransactionType, TxId};
+
+/// One transaction, as in a row of the input.
+#[pyclass(name = "Transaction", module = "tx_engine")]
+#[derive(Clone)]
+pub struct PyTransaction {
+    inner: Transaction,
+}
+
+#[pymethods]
+impl PyTransaction {
+    #[new]
+    #[pyo3(signature = (r#type, client, tx, amount=None, timestamp=None, to_client=None))]
+    fn new(
+        r#type: &str,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<Decimal>,
+        timestamp: Option<u64>,
+        to_client: Option<ClientId>,
+    ) -> PyResult<Self> {
+        let tx_type: TransactionType = r#type
+            .parse()
+            .map_err(|error| PyValueError::new_err(format!("{}", error)))?;
+        Ok(Self {
+            inner: Transaction {
+                tx_type,
+                client,
+                tx,
+                amount,
+                timestamp,
+                to_client,
+                metadata: Default::default(),
+            },
+        })
+    }
+
+    #[getter]
+    fn r#type(&self) -> String {
+        self.inner.tx_type.to_string()
+    }
+
+    #[getter]
+    fn client(&self) -> ClientId {
+        self.inner.client
+    }
+
+    #[getter]
+    fn tx(&self) -> TxId {
+        self.inner.tx
+    }
+
+    #[getter]
+    fn amount(&self) -> Option<Decimal> {
+        self.inner.amount
+    }
+
+    #[getter]
+    fn timestamp(&self) -> Option<u64> {
+        self.inner.timestamp
+    }
+
+    #[getter]
+    fn to_client(&self) -> Option<ClientId> {
+        self.inner.to_client
+    }
+
+    fn __repr__(&self) -> String {
+        let amount = self
+            .inner
+            .amount
+            .map(|amount| format!("Decimal('{}')", amount))
+            .unwrap_or_else(|| "None".to_string());
+        format!(
+            "Transaction(type='{}', client={}, tx={}, amount={})",
+            self.inner.tx_type, self.inner.client, self.inner.tx, amount
+        )
+    }
+}
+
+/// One account in the report.
+#[pyclass(name = "AccountOutput", module = "tx_engine", get_all, frozen)]
+pub struct PyAccountOutput {
+    client: ClientId,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    reserved: Option<Decimal>,
+    last_activity: Option<u64>,
+}
+
+impl From<AccountOutput> for PyAccountOutput {
+    fn from(output: AccountOutput) -> Self {
+        let scale = output.scale;
+        Self {
+            client: output.client,
+            available: output.available.to_decimal(scale),
+            held: output.held.to_decimal(scale),
+            total: output.total.to_decimal(scale),
+            locked: output.locked,
+            reserved: output.reserved.map(|reserved| reserved.to_decimal(scale)),
+            last_activity: output.last_activity,
+        }
+    }
+}
+
+#[pymethods]
+impl PyAccountOutput {
+    fn __repr__(&self) -> String {
+        format!(
+            "AccountOutput(client={}, available=Decimal('{}'), held=Decimal('{}'), \
+             total=Decimal('{}'), locked={})",
+            self.client,
+            self.available,
+            self.held,
+            self.total,
+            if self.locked { "True" } else { "False" }
+        )
+    }
+}
+
+/// An engine with the default configuration. It is tied to the thread that created it.
+#[pyclass(name = "Engine", module = "tx_engine", unsendable)]
+pub struct PyEngine {
+    engine: Engine,
+}
+
+#[pymethods]
+impl PyEngine {
+    #[new]
+    fn new() -> Self {
+        Self {
+            engine: Engine::new(),
+        }
+    }
+
+    /// Applies one transaction. Returns `None` if it was applied, or the code of the
+    /// reason it was rejected, e.g. `"insufficient_funds"`.
+    fn process(&mut self, tx: &PyTransaction) -> Option<&'static str> {
+        self.engine
+            .process(tx.inner.clone())
+            .err()
+            .map(|error| error.code())
+    }
+
+    /// Applies transactions in order, returning the number applied.
+    fn process_all(&mut self, txs: Vec<PyRef<'_, PyTransaction>>) -> usize {
+        txs.iter()
+            .filter(|tx| self.engine.process(tx.inner.clone()).is_ok())
+            .count()
+    }
+
+    /// Every account, ordered by client.
+    fn output(&self) -> Vec<PyAccountOutput> {
+        self.engine
+            .output_sorted()
+            .into_iter()
+            .map(PyAccountOutput::from)
+            .collect()
+    }
+}
+
+#[pymodule]
+fn tx_engine(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyEngine>()?;
+    module.add_class::<PyTransaction>()?;
+    module.add_class::<PyAccountOutput>()?;
+    Ok(())
+}
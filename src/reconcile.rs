@@ -0,0 +1,293 @@
+//! Reconciliation of two account reports, for checking an engine change against the
+//! output of the version in production.
+//!
+//! [`read_report`] parses an account report CSV, in either schema; only the `client`,
+//! `available`, `held`, `total` and `locked` columns are compared, so extra columns and
+//! their order don't matter. Amounts are compared as numbers, so `1.5` matches `1.5000`.
+//! [`reconcile`] lists the clients whose rows differ and [`write_diff`] writes them as CSV,
+//! one row per differing field:
+//!
+//! ```text
+//! client,field,expected,actual
+//! 2,available,10.0000,9.5000
+//! 2,total,10.0000,9.5000
+//! 7,locked,false,true
+//! ```
+//!
+//! A client in only one report gets a row for each field, with the other side empty.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+
+use crate::types::ClientId;
+
+/// The compared columns of one report row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportRow {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl ReportRow {
+    /// The compared fields' values, in [`FIELDS`] order.
+    fn values(&self) -> [String; 4] {
+        [
+            self.available.to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked.to_string(),
+        ]
+    }
+}
+
+/// Names of the compared fields besides `client`.
+const FIELDS: [&str; 4] = ["available", "held", "total", "locked"];
+
+/// A report's rows by client.
+pub type Report = BTreeMap<ClientId, ReportRow>;
+
+/// A client whose rows differ between the expected and the actual report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientDiff {
+    /// Only in the expected report.
+    Missing {
+        client: ClientId,
+        expected: ReportRow,
+    },
+    /// Only in the actual report.
+    Unexpected { client: ClientId, actual: ReportRow },
+    /// In both, with at least one field different.
+    Changed {
+        client: ClientId,
+        expected: ReportRow,
+        actual: ReportRow,
+    },
+}
+
+impl ClientDiff {
+    pub fn client(&self) -> ClientId {
+        match *self {
+            ClientDiff::Missing { client, .. }
+            | ClientDiff::Unexpected { client, .. }
+            | ClientDiff::Changed { client, .. } => client,
+        }
+    }
+
+    /// The differing fields as name, expected and actual value; a side without the client
+    /// is empty.
+    pub fn fields(&self) -> Vec<(&'static str, String, String)> {
+        let (expected, actual) = match *self {
+            ClientDiff::Missing { expected, .. } => (Some(expected), None),
+            ClientDiff::Unexpected { actual, .. } => (None, Some(actual)),
+            ClientDiff::Changed {
+                expected, actual, ..
+            } => (Some(expected), Some(actual)),
+        };
+        let differs = match (expected, actual) {
+            (Some(e), Some(a)) => [
+                e.available != a.available,
+                e.held != a.held,
+                e.total != a.total,
+                e.locked != a.locked,
+            ],
+            _ => [true; 4],
+        };
+        let values = |row: Option<ReportRow>| row.map(|row| row.values()).unwrap_or_default();
+        FIELDS
+            .into_iter()
+            .zip(values(expected))
+            .zip(values(actual))
+            .zip(differs)
+            .filter(|&(_, differs)| differs)
+            .map(|(((field, expected), actual), _)| (field, expected, actual))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum ReconcileError {
+    Csv(csv::Error),
+    MissingColumn(&'static str),
+    /// Row at 1-based line `line` can't be read.
+    InvalidRow {
+        line: u64,
+        message: String,
+    },
+}
+
+impl fmt::Display for ReconcileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReconcileError::Csv(error) => write!(f, "{}", error),
+            ReconcileError::MissingColumn(column) => write!(f, "missing column '{}'", column),
+            ReconcileError::InvalidRow { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for ReconcileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReconcileError::Csv(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for ReconcileError {
+    fn from(error: csv::Error) -> Self {
+        ReconcileError::Csv(error)
+    }
+}
+
+/// Reads an account report CSV. Fails on a row that can't be parsed or repeats a client.
+pub fn read_report<R: Read>(reader: R) -> Result<Report, ReconcileError> {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |name: &'static str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or(ReconcileError::MissingColumn(name))
+    };
+    let columns = [
+        column("client")?,
+        column("available")?,
+        column("held")?,
+        column("total")?,
+        column("locked")?,
+    ];
+
+    let mut report = Report::new();
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let invalid = |message: String| ReconcileError::InvalidRow { line, message };
+        let [client, available, held, total, locked] =
+            columns.map(|column| record.get(column).unwrap_or_default());
+        let client: ClientId = client
+            .parse()
+            .map_err(|_| invalid(format!("invalid client '{}'", client)))?;
+        let amount = |field: &str| {
+            field
+                .parse::<Decimal>()
+                .map_err(|_| invalid(format!("invalid amount '{}'", field)))
+        };
+        let row = ReportRow {
+            available: amount(available)?,
+            held: amount(held)?,
+            total: amount(total)?,
+            locked: locked
+                .parse()
+                .map_err(|_| invalid(format!("invalid locked flag '{}'", locked)))?,
+        };
+        if report.insert(client, row).is_some() {
+            return Err(invalid(format!("client {} appears twice", client)));
+        }
+    }
+    Ok(report)
+}
+
+/// The clients whose rows differ between `expected` and `actual`, in client order.
+pub fn reconcile(expected: &Report, actual: &Report) -> Vec<ClientDiff> {
+    let mut clients: Vec<ClientId> = expected.keys().chain(actual.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+    clients
+        .into_iter()
+        .filter_map(
+            |client| match (expected.get(&client), actual.get(&client)) {
+                (Some(&expected), Some(&actual)) => {
+                    (expected != actual).then_some(ClientDiff::Changed {
+                        client,
+                        expected,
+                        actual,
+                    })
+                }
+                (Some(&expected), None) => Some(ClientDiff::Missing { client, expected }),
+                (None, Some(&actual)) => Some(ClientDiff::Unexpected { client, actual }),
+                (None, None) => None,
+            },
+        )
+        .collect()
+}
+
+/// Writes `diffs` as CSV, one row per differing field; see the [module docs](self).
+/// Returns the number of rows.
+pub fn write_diff<W: Write>(diffs: &[ClientDiff], writer: W) -> csv::Result<usize> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["client", "field", "expected", "actual"])?;
+    let mut rows = 0;
+    for diff in diffs {
+        for (field, expected, actual) in diff.fields() {
+            writer.write_record([
+                diff.client().to_string(),
+                field.to_string(),
+                expected,
+                actual,
+            ])?;
+            rows += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_reconcile_reports() {
+        let expected = "client,available,held,total,locked\n\
+                        1,1.5000,0.0000,1.5000,false\n\
+                        2,10.0000,0.0000,10.0000,false\n\
+                        3,0.0000,0.0000,0.0000,true\n";
+        let actual = "locked,client,total,held,available,status\n\
+                      false,1,1.5,0,1.5,active\n\
+                      false,2,9.5,0,9.5,active\n\
+                      false,4,1,0,1,active\n";
+        let expected = read_report(expected.as_bytes()).unwrap();
+        let actual = read_report(actual.as_bytes()).unwrap();
+        assert_eq!(actual[&1].available, dec!(1.5));
+
+        let diffs = reconcile(&expected, &actual);
+        assert_eq!(
+            diffs.iter().map(ClientDiff::client).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert!(matches!(diffs[1], ClientDiff::Missing { client: 3, .. }));
+        let mut out = Vec::new();
+        assert_eq!(write_diff(&diffs, &mut out).unwrap(), 10);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(
+            "client,field,expected,actual\n\
+             2,available,10.0000,9.5\n\
+             2,total,10.0000,9.5\n\
+             3,available,0.0000,\n"
+        ));
+        assert!(out.ends_with("4,locked,,false\n"));
+        assert!(reconcile(&expected, &expected).is_empty());
+
+        let duplicate = "client,available,held,total,locked\n1,1,0,1,false\n1,1,0,1,false\n";
+        assert_eq!(
+            read_report(duplicate.as_bytes()).unwrap_err().to_string(),
+            "line 3: client 1 appears twice"
+        );
+        assert!(matches!(
+            read_report("client,available\n".as_bytes()),
+            Err(ReconcileError::MissingColumn("held"))
+        ));
+    }
+}
@@ -0,0 +1,515 @@
+//! Versioned layouts of the account report.
+//!
+//! `v1` is the original `client,available,held,total,locked` report (plus `reserved` when a
+//! rolling reserve is configured, `last_activity` when the engine reports it, and `closed`,
+//! `overdrawn`, `transactions`, `open_disputes`, `deposited`, `withdrawn`, `last_tx` and
+//! `last_tx_timestamp` with extended output). `v2` keeps those columns in the same order,
+//! except `open_disputes`, and appends `currency`, `status`, `open_disputes` and
+//! `chargebacks`, so consumers reading columns by name can switch versions without other
+//! changes. A [`Manifest`] describes what a run
+//! produced, letting consumers check the version before parsing.
+//!
+//! Either layout can be written as CSV, TSV, a JSON array or newline-delimited JSON
+//! ([`OutputFormat`]). JSON objects carry the same fields as the CSV columns, amounts
+//! included as strings so no precision is lost. With the `arrow` feature, `v1` can also be
+//! written as Parquet, through [`crate::arrow`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::engine::Engine;
+use crate::filter::ClientFilter;
+use crate::types::{AccountOutput, ClientId, DisputeState, Money, TxId};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSchema {
+    #[default]
+    V1,
+    V2,
+}
+
+impl OutputSchema {
+    /// Column names of a report in this schema.
+    pub fn columns(
+        &self,
+        reserved: bool,
+        last_activity: bool,
+        extended: bool,
+    ) -> Vec<&'static str> {
+        let mut columns = vec!["client", "available", "held", "total", "locked"];
+        if reserved {
+            columns.push("reserved");
+        }
+        if last_activity {
+            columns.push("last_activity");
+        }
+        if extended {
+            columns.extend(["closed", "overdrawn", "transactions"]);
+            if *self == OutputSchema::V1 {
+                columns.push("open_disputes");
+            }
+            columns.extend(["deposited", "withdrawn", "last_tx", "last_tx_timestamp"]);
+        }
+        if *self == OutputSchema::V2 {
+            columns.extend(["currency", "status", "open_disputes", "chargebacks"]);
+        }
+        columns
+    }
+}
+
+impl FromStr for OutputSchema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(OutputSchema::V1),
+            "v2" | "2" => Ok(OutputSchema::V2),
+            _ => Err(format!("unknown output schema '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputSchema::V1 => "v1",
+            OutputSchema::V2 => "v2",
+        })
+    }
+}
+
+/// How report rows are encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Tsv,
+    /// One JSON array of objects, a row per line.
+    Json,
+    /// One JSON object per line.
+    Ndjson,
+    /// A Parquet file; `v1` only.
+    #[cfg(feature = "arrow")]
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" | "jsonl" => Ok(OutputFormat::Ndjson),
+            #[cfg(feature = "arrow")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            #[cfg(not(feature = "arrow"))]
+            "parquet" => Err("parquet output requires building with the `arrow` feature".into()),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Parquet => "parquet",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    Active,
+    /// At least one of its transactions is under dispute.
+    Disputed,
+    /// Frozen by a chargeback; takes precedence over the other states.
+    Locked,
+    /// Inactive for longer than the engine's dormancy policy allows.
+    Dormant,
+    /// Closed by an operator; takes precedence over all the other states.
+    Closed,
+}
+
+/// One row of a `v2` report, with amounts formatted at the engine's scale.
+#[derive(Debug, Serialize)]
+pub struct AccountOutputV2<'a> {
+    pub client: ClientId,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overdrawn: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transactions: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deposited: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub withdrawn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_tx: Option<Option<TxId>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_tx_timestamp: Option<Option<u64>>,
+    /// Empty when no currency was configured.
+    pub currency: &'a str,
+    pub status: AccountStatus,
+    pub open_disputes: u32,
+    pub chargebacks: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReportOptions {
+    pub schema: OutputSchema,
+    pub format: OutputFormat,
+    /// Fills the `v2` currency column.
+    pub currency: String,
+    /// Leave out accounts the engine considers dormant.
+    pub exclude_dormant: bool,
+    /// Only report these clients' accounts.
+    pub clients: Option<ClientFilter>,
+}
+
+/// Writes the engine's account report in client id order, returning the number of rows.
+pub fn write_report<W: Write>(
+    engine: &Engine,
+    options: &ReportOptions,
+    writer: W,
+) -> csv::Result<usize> {
+    let mut accounts = engine.output_sorted();
+    if options.exclude_dormant {
+        accounts.retain(|account| !engine.is_dormant(account.client));
+    }
+    if let Some(clients) = &options.clients {
+        accounts.retain(|account| clients.contains(account.client));
+    }
+    #[cfg(feature = "arrow")]
+    if options.format == OutputFormat::Parquet {
+        if options.schema != OutputSchema::V1 {
+            return Err(io::Error::other("parquet output only supports the v1 schema").into());
+        }
+        return write_parquet(&accounts, writer);
+    }
+    let mut writer = RowWriter::new(options.format, writer);
+    let currency = options.currency.as_str();
+    match options.schema {
+        OutputSchema::V1 => {
+            for account in &accounts {
+                writer.write(account)?;
+            }
+        }
+        OutputSchema::V2 => {
+            let risk = risk_counts(engine);
+            for account in &accounts {
+                let (open_disputes, chargebacks) =
+                    risk.get(&account.client).copied().unwrap_or_default();
+                let dormant = engine.is_dormant(account.client);
+                let row = v2_row(account, currency, open_disputes, chargebacks, dormant);
+                writer.write(&row)?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(accounts.len())
+}
+
+/// Writes `v1` rows that were already collected, such as a [`crate::sharded::ShardedReport`]'s,
+/// in `format`. Returns the number of rows.
+pub fn write_accounts<W: Write>(
+    accounts: &[AccountOutput],
+    format: OutputFormat,
+    writer: W,
+) -> csv::Result<usize> {
+    #[cfg(feature = "arrow")]
+    if format == OutputFormat::Parquet {
+        return write_parquet(accounts, writer);
+    }
+    let mut writer = RowWriter::new(format, writer);
+    for account in accounts {
+        writer.write(account)?;
+    }
+    writer.finish()?;
+    Ok(accounts.len())
+}
+
+/// Writes `v1` rows as a Parquet file, returning the number of rows.
+#[cfg(feature = "arrow")]
+fn write_parquet<W: Write>(accounts: &[AccountOutput], mut writer: W) -> csv::Result<usize> {
+    // The Parquet writer needs a `Send` writer, and the report is small enough to buffer.
+    let mut buffer = Vec::new();
+    let batch = crate::arrow::accounts_batch(accounts).map_err(io::Error::other)?;
+    crate::arrow::write_parquet(&batch, &mut buffer).map_err(io::Error::other)?;
+    writer.write_all(&buffer)?;
+    writer.flush()?;
+    Ok(accounts.len())
+}
+
+/// Encodes rows in a textual [`OutputFormat`].
+enum RowWriter<W: Write> {
+    Delimited(Box<csv::Writer<W>>),
+    Json { writer: W, array: bool, rows: usize },
+}
+
+impl<W: Write> RowWriter<W> {
+    fn new(format: OutputFormat, writer: W) -> Self {
+        match format {
+            OutputFormat::Csv => RowWriter::Delimited(Box::new(csv::Writer::from_writer(writer))),
+            OutputFormat::Tsv => {
+                let writer = csv::WriterBuilder::new()
+                    .delimiter(b'\t')
+                    .from_writer(writer);
+                RowWriter::Delimited(Box::new(writer))
+            }
+            OutputFormat::Json | OutputFormat::Ndjson => RowWriter::Json {
+                writer,
+                array: format == OutputFormat::Json,
+                rows: 0,
+            },
+            #[cfg(feature = "arrow")]
+            OutputFormat::Parquet => unreachable!("parquet reports are written by write_parquet"),
+        }
+    }
+
+    fn write(&mut self, row: &impl Serialize) -> csv::Result<()> {
+        match self {
+            RowWriter::Delimited(writer) => writer.serialize(row),
+            RowWriter::Json {
+                writer,
+                array,
+                rows,
+            } => {
+                if *array {
+                    writer.write_all(if *rows == 0 { b"[\n" } else { b",\n" })?;
+                }
+                serde_json::to_writer(&mut *writer, row).map_err(io::Error::from)?;
+                if !*array {
+                    writer.write_all(b"\n")?;
+                }
+                *rows += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> csv::Result<()> {
+        match self {
+            RowWriter::Delimited(mut writer) => writer.flush()?,
+            RowWriter::Json {
+                mut writer,
+                array,
+                rows,
+            } => {
+                if array {
+                    writer.write_all(if rows == 0 { b"[]\n" } else { b"\n]\n" })?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn v2_row<'a>(
+    account: &AccountOutput,
+    currency: &'a str,
+    open_disputes: u32,
+    chargebacks: u32,
+    dormant: bool,
+) -> AccountOutputV2<'a> {
+    let status = if account.closed == Some(true) {
+        AccountStatus::Closed
+    } else if account.locked {
+        AccountStatus::Locked
+    } else if open_disputes > 0 {
+        AccountStatus::Disputed
+    } else if dormant {
+        AccountStatus::Dormant
+    } else {
+        AccountStatus::Active
+    };
+    let amount = |value: Money| value.format(account.scale);
+    AccountOutputV2 {
+        client: account.client,
+        available: amount(account.available),
+        held: amount(account.held),
+        total: amount(account.total),
+        locked: account.locked,
+        reserved: account.reserved.map(amount),
+        last_activity: account.last_activity,
+        closed: account.closed,
+        overdrawn: account.overdrawn,
+        transactions: account.activity.map(|activity| activity.transactions),
+        deposited: account.activity.map(|activity| amount(activity.deposited)),
+        withdrawn: account.activity.map(|activity| amount(activity.withdrawn)),
+        last_tx: account.activity.map(|activity| activity.last_tx),
+        last_tx_timestamp: account.activity.map(|activity| activity.last_timestamp),
+        currency,
+        status,
+        open_disputes,
+        chargebacks,
+    }
+}
+
+/// Open disputes and chargebacks per client, counted against the account holding (or
+/// having lost) the funds.
+fn risk_counts(engine: &Engine) -> HashMap<ClientId, (u32, u32)> {
+    let mut counts: HashMap<ClientId, (u32, u32)> = HashMap::new();
+    for (_, stored) in engine.transactions() {
+        let entry = counts.entry(stored.credited()).or_default();
+        match stored.dispute_state {
+            DisputeState::Disputed => entry.0 += 1,
+            DisputeState::ChargedBack => entry.1 += 1,
+            DisputeState::None => {}
+        }
+    }
+    counts
+}
+
+/// Description of a report, written next to it as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub schema: OutputSchema,
+    pub format: OutputFormat,
+    pub columns: Vec<&'static str>,
+    pub rows: usize,
+}
+
+impl Manifest {
+    pub fn write_json<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let columns: Vec<String> = self.columns.iter().map(|c| format!("\"{}\"", c)).collect();
+        writeln!(
+            writer,
+            "{{\"schema\":\"{}\",\"format\":\"{}\",\"columns\":[{}],\"rows\":{},\"generator\":\"{} {}\"}}",
+            self.schema,
+            self.format,
+            columns.join(","),
+            self.rows,
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Transaction, TransactionType, TxId};
+    use rust_decimal_macros::dec;
+
+    fn tx(tx_type: TransactionType, client: ClientId, tx: TxId) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: Some(dec!(1.0)),
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_v1_and_v2_reports() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1)).unwrap();
+        engine.process(tx(TransactionType::Deposit, 1, 2)).unwrap();
+        engine.process(tx(TransactionType::Dispute, 1, 1)).unwrap();
+
+        let mut options = ReportOptions {
+            currency: "EUR".to_string(),
+            ..ReportOptions::default()
+        };
+        let mut v1 = Vec::new();
+        assert_eq!(write_report(&engine, &options, &mut v1).unwrap(), 1);
+        assert_eq!(
+            String::from_utf8(v1).unwrap(),
+            "client,available,held,total,locked\n1,1.0000,1.0000,2.0000,false\n"
+        );
+
+        engine.process(tx(TransactionType::Dispute, 1, 2)).unwrap();
+        engine
+            .process(tx(TransactionType::Chargeback, 1, 2))
+            .unwrap();
+        options.schema = OutputSchema::V2;
+        let mut v2 = Vec::new();
+        write_report(&engine, &options, &mut v2).unwrap();
+        assert_eq!(
+            String::from_utf8(v2).unwrap(),
+            "client,available,held,total,locked,currency,status,open_disputes,chargebacks\n\
+             1,0.0000,1.0000,1.0000,true,EUR,locked,1,1\n"
+        );
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 2, 1)).unwrap();
+        engine.process(tx(TransactionType::Deposit, 1, 2)).unwrap();
+        engine.process(tx(TransactionType::Dispute, 1, 2)).unwrap();
+        let report = |format| {
+            let options = ReportOptions {
+                format,
+                ..ReportOptions::default()
+            };
+            let mut out = Vec::new();
+            write_report(&engine, &options, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            report(OutputFormat::Tsv),
+            "client\tavailable\theld\ttotal\tlocked\n\
+             1\t0.0000\t1.0000\t1.0000\tfalse\n\
+             2\t1.0000\t0.0000\t1.0000\tfalse\n"
+        );
+        let one =
+            r#"{"client":1,"available":"0.0000","held":"1.0000","total":"1.0000","locked":false}"#;
+        let two =
+            r#"{"client":2,"available":"1.0000","held":"0.0000","total":"1.0000","locked":false}"#;
+        assert_eq!(report(OutputFormat::Ndjson), format!("{}\n{}\n", one, two));
+        assert_eq!(
+            report(OutputFormat::Json),
+            format!("[\n{},\n{}\n]\n", one, two)
+        );
+
+        let mut empty = Vec::new();
+        write_accounts(&[], OutputFormat::Json, &mut empty).unwrap();
+        assert_eq!(empty, b"[]\n");
+        assert_eq!("jsonl".parse(), Ok(OutputFormat::Ndjson));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest {
+            schema: OutputSchema::V2,
+            format: OutputFormat::Ndjson,
+            columns: OutputSchema::V2.columns(false, false, false),
+            rows: 3,
+        };
+        let mut out = Vec::new();
+        manifest.write_json(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.starts_with(
+            "{\"schema\":\"v2\",\"format\":\"ndjson\",\"columns\":[\"client\",\"available\",\"held\",\"total\",\
+             \"locked\",\"currency\",\"status\",\"open_disputes\",\"chargebacks\"],\"rows\":3,"
+        ));
+    }
+}
@@ -0,0 +1,357 @@
+//! A small HTTP API over a shared [`Engine`], as run by `tx-engine serve`.
+//!
+//! - `POST /transactions` takes CSV in the input format, header row included and batch
+//!   markers allowed, and processes it like [`Engine::process_csv`]. The response gives the
+//!   number applied and the rejections by reason.
+//! - `GET /accounts` lists every account in memory, ordered by client.
+//! - `GET /accounts/{client}` returns one account.
+//! - `GET /metrics` returns Prometheus metrics; see [`crate::metrics`].
+//!
+//! Other responses are JSON, with amounts as strings in the report's format. Each connection is
+//! served on a thread of its own and carries one request. The engine sits behind a mutex,
+//! so requests are applied one at a time, in the order they get hold of it.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::engine::Engine;
+use crate::io::ProcessSummary;
+use crate::logging::push_json_string;
+use crate::metrics::{self, Metrics};
+use crate::types::{AccountOutput, Money};
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 16 << 20;
+
+/// How long a connection may stay silent before it is dropped.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const JSON: &str = "application/json";
+/// Content type of the Prometheus text format.
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// Serves the API on a bound socket.
+pub struct Server {
+    listener: TcpListener,
+    engine: Arc<Mutex<Engine>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Server {
+    pub fn bind(address: impl ToSocketAddrs, engine: Engine) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+            engine: Arc::new(Mutex::new(engine)),
+            metrics: Arc::new(Metrics::new()),
+        })
+    }
+
+    /// The address the server listens on, e.g. to find the port after binding port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The engine requests are applied to, for use alongside the server.
+    pub fn engine(&self) -> Arc<Mutex<Engine>> {
+        Arc::clone(&self.engine)
+    }
+
+    /// Accepts connections until the listener fails. Errors on a single connection are
+    /// logged and don't stop the server.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            let engine = Arc::clone(&self.engine);
+            let metrics = Arc::clone(&self.metrics);
+            thread::spawn(move || {
+                if let Err(error) = serve_connection(stream, &engine, &metrics) {
+                    log::debug!("connection from {} failed: {}", peer, error);
+                }
+            });
+        }
+    }
+}
+
+/// Status, content type and body of a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: JSON,
+            body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        let mut body = String::from("{\"error\":");
+        push_json_string(&mut body, message);
+        body.push('}');
+        Self {
+            status,
+            content_type: JSON,
+            body,
+        }
+    }
+}
+
+/// Answers one request: the routing of [`Server`] without the networking. The time taken
+/// by `POST /transactions` is recorded in `metrics`.
+pub fn respond(
+    engine: &Mutex<Engine>,
+    metrics: &Metrics,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Response {
+    let path = path.split('?').next().unwrap_or_default();
+    let Ok(mut engine) = engine.lock() else {
+        return Response::error(500, "engine unavailable");
+    };
+    match (method, path) {
+        ("POST", "/transactions") => {
+            let started = Instant::now();
+            let result = engine.process_csv(body);
+            metrics.observe(started.elapsed());
+            match result {
+                Ok(summary) => Response::ok(summary_json(&summary)),
+                Err(error) => Response::error(400, &error.to_string()),
+            }
+        }
+        ("GET", "/accounts") => {
+            let accounts = engine.output_sorted();
+            let rows: Vec<String> = accounts.iter().map(account_json).collect();
+            Response::ok(format!("[{}]", rows.join(",")))
+        }
+        ("GET", path) if path.starts_with("/accounts/") => {
+            let Ok(client) = path["/accounts/".len()..].parse() else {
+                return Response::error(400, "invalid client id");
+            };
+            match engine.account_output(client) {
+                Some(account) => Response::ok(account_json(&account)),
+                None => Response::error(404, "unknown client"),
+            }
+        }
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: PROMETHEUS,
+            body: metrics::render(&engine, metrics),
+        },
+        (_, "/transactions" | "/accounts" | "/metrics") => {
+            Response::error(405, "method not allowed")
+        }
+        (_, path) if path.starts_with("/accounts/") => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn summary_json(summary: &ProcessSummary) -> String {
+    let ignored: Vec<String> = summary
+        .ignored
+        .iter()
+        .map(|(reason, count)| format!("\"{}\":{}", reason.code(), count))
+        .collect();
+    format!(
+        "{{\"applied\":{},\"ignored\":{{{}}},\"skipped\":{},\"rolled_back\":{}}}",
+        summary.applied,
+        ignored.join(","),
+        summary.skipped,
+        summary.rolled_back
+    )
+}
+
+fn account_json(account: &AccountOutput) -> String {
+    let amount = |value: Money| value.format(account.scale);
+    let reserved = account
+        .reserved
+        .map(|reserved| format!(",\"reserved\":\"{}\"", amount(reserved)))
+        .unwrap_or_default();
+    let last_activity = account
+        .last_activity
+        .map(|timestamp| format!(",\"last_activity\":{}", timestamp))
+        .unwrap_or_default();
+    let closed = account
+        .closed
+        .map(|closed| format!(",\"closed\":{}", closed))
+        .unwrap_or_default();
+    let overdrawn = account
+        .overdrawn
+        .map(|overdrawn| format!(",\"overdrawn\":{}", overdrawn))
+        .unwrap_or_default();
+    let activity = account
+        .activity
+        .map(|activity| {
+            let optional = |value: Option<u64>| value.map_or("null".to_string(), |v| v.to_string());
+            format!(
+                ",\"transactions\":{},\"open_disputes\":{},\"deposited\":\"{}\",\"withdrawn\":\"{}\",\"last_tx\":{},\"last_tx_timestamp\":{}",
+                activity.transactions,
+                activity.open_disputes,
+                amount(activity.deposited),
+                amount(activity.withdrawn),
+                optional(activity.last_tx),
+                optional(activity.last_timestamp)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "{{\"client\":{},\"available\":\"{}\",\"held\":\"{}\",\"total\":\"{}\",\"locked\":{}{}{}{}{}{}}}",
+        account.client,
+        amount(account.available),
+        amount(account.held),
+        amount(account.total),
+        account.locked,
+        reserved,
+        last_activity,
+        closed,
+        overdrawn,
+        activity
+    )
+}
+
+/// Reads one request from `stream` and writes the response.
+fn serve_connection(
+    stream: TcpStream,
+    engine: &Mutex<Engine>,
+    metrics: &Metrics,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return write_response(&stream, &Response::error(400, "malformed request"));
+    };
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        match header.split_once(':') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("content-length") => {
+                length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+            _ => {}
+        }
+    }
+    if length > MAX_BODY {
+        return write_response(&stream, &Response::error(413, "request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let response = respond(engine, metrics, method, path, &body);
+    write_response(&stream, &response)
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let engine = Mutex::new(Engine::new());
+        let metrics = Metrics::new();
+        let csv =
+            b"type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,50.0\ndeposit,2,3,1.5\n";
+        assert_eq!(
+            respond(&engine, &metrics, "POST", "/transactions", csv),
+            Response::ok(
+                r#"{"applied":2,"ignored":{"insufficient_funds":1},"skipped":0,"rolled_back":0}"#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            respond(&engine, &metrics, "GET", "/accounts/2", b""),
+            Response::ok(
+                r#"{"client":2,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#
+                    .to_string()
+            )
+        );
+        let accounts = respond(&engine, &metrics, "GET", "/accounts?format=json", b"");
+        assert!(accounts.body.starts_with(r#"[{"client":1,"#));
+        assert_eq!(
+            respond(&engine, &metrics, "GET", "/accounts/9", b"").status,
+            404
+        );
+        assert_eq!(
+            respond(&engine, &metrics, "GET", "/accounts/x", b"").status,
+            400
+        );
+        assert_eq!(
+            respond(&engine, &metrics, "DELETE", "/accounts", b"").status,
+            405
+        );
+
+        let malformed = respond(
+            &engine,
+            &metrics,
+            "POST",
+            "/transactions",
+            b"type,client,tx,amount\ndeposit,x,4,1.0\n",
+        );
+        assert_eq!(malformed.status, 400);
+        assert!(
+            malformed
+                .body
+                .starts_with(r#"{"error":"line 2: malformed record"#)
+        );
+    }
+
+    #[test]
+    fn test_serves_over_tcp() {
+        let server = Server::bind("127.0.0.1:0", Engine::new()).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let request = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let body = "type,client,tx,amount\ndeposit,7,1,2.0\n";
+        let response = request(&format!(
+            "POST /transactions HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = request("GET /accounts/7 HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.ends_with(r#""total":"2.0000","locked":false}"#));
+    }
+}
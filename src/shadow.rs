@@ -0,0 +1,302 @@
+//! Shadow runs: two engine configurations side by side over the same input, for checking a
+//! policy change against the configuration in production before rolling it out, as run by
+//! `tx-engine shadow`.
+//!
+//! A [`ShadowRunner`] feeds every [`Input`] to both engines, each keeping batches of its
+//! own, so a batch one side rolls back may still commit on the other. Inputs the engines
+//! answer differently are kept as [`Divergence`]s, and [`ShadowRunner::account_diffs`]
+//! compares the resulting accounts as [`reconcile`](crate::reconcile) compares two reports,
+//! engine A taking the place of the expected one.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::config::EngineConfig;
+use crate::engine::Engine;
+use crate::io::Input;
+use crate::outcome::ProcessError;
+use crate::reconcile::{self, ClientDiff, Report, ReportRow};
+use crate::types::{ClientId, Money, TxId};
+
+/// What one engine made of an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Applied, or for a batch marker, interest accrual or clock advance, carried out.
+    Applied,
+    Rejected(ProcessError),
+    /// Not applied, because the engine rolled back the batch it belongs to.
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Applied => write!(f, "applied"),
+            Outcome::Rejected(error) => write!(f, "rejected ({})", error.code()),
+            Outcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// An input the two engines answered differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    /// 1-based position of the input among those fed to the runner.
+    pub position: u64,
+    /// The client of a transaction or admin operation.
+    pub client: Option<ClientId>,
+    /// The id of a transaction.
+    pub tx: Option<TxId>,
+    pub a: Outcome,
+    pub b: Outcome,
+}
+
+/// The input isn't a valid sequence of batches, so neither engine could apply it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowError {
+    /// A batch begun inside an open batch.
+    NestedBatch { position: u64 },
+    /// A batch commit without a begin.
+    UnopenedCommit { position: u64 },
+}
+
+impl fmt::Display for ShadowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShadowError::NestedBatch { position } => {
+                write!(f, "input {}: batch begun inside an open batch", position)
+            }
+            ShadowError::UnopenedCommit { position } => {
+                write!(f, "input {}: batch commit without a begin", position)
+            }
+        }
+    }
+}
+
+impl Error for ShadowError {}
+
+/// One of the two engines, with the state of its open batch.
+struct Side {
+    engine: Engine,
+    /// Whether the open batch was rolled back, if one is open.
+    batch: Option<bool>,
+}
+
+impl Side {
+    fn new(engine: Engine) -> Self {
+        Self {
+            engine,
+            batch: None,
+        }
+    }
+
+    fn apply(&mut self, input: Input) -> Outcome {
+        if self.batch == Some(true) && !matches!(input, Input::CommitBatch) {
+            return Outcome::Skipped;
+        }
+        let result = match input {
+            Input::Transaction(tx) => self.engine.process(tx).map(drop),
+            Input::Scheduled { tx, value_date } => self.engine.schedule(tx, value_date),
+            Input::Admin(op) => self.engine.apply_admin(op),
+            Input::BeginBatch => {
+                self.engine.begin_batch();
+                self.batch = Some(false);
+                Ok(())
+            }
+            Input::CommitBatch => {
+                if self.batch.take() == Some(false) {
+                    self.engine.commit_batch();
+                }
+                Ok(())
+            }
+            Input::Accrue { timestamp } => {
+                self.engine.accrue_interest(timestamp);
+                Ok(())
+            }
+            Input::SettleUntil { timestamp } => {
+                self.engine.advance_time(timestamp);
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => Outcome::Applied,
+            Err(error) => {
+                if self.batch.is_some() {
+                    self.engine.rollback_batch();
+                    self.batch = Some(true);
+                }
+                Outcome::Rejected(error)
+            }
+        }
+    }
+}
+
+/// Two engines fed the same input, recording where they part ways.
+pub struct ShadowRunner {
+    a: Side,
+    b: Side,
+    inputs: u64,
+    divergences: Vec<Divergence>,
+}
+
+impl ShadowRunner {
+    pub fn new(a: Engine, b: Engine) -> Self {
+        Self {
+            a: Side::new(a),
+            b: Side::new(b),
+            inputs: 0,
+            divergences: Vec::new(),
+        }
+    }
+
+    /// A runner over two new engines with the given configurations.
+    pub fn with_configs(a: EngineConfig, b: EngineConfig) -> Self {
+        Self::new(Engine::with_config(a), Engine::with_config(b))
+    }
+
+    /// Applies `input` to both engines and returns their outcomes, recording a
+    /// [`Divergence`] if they differ.
+    pub fn apply(&mut self, input: Input) -> Result<(Outcome, Outcome), ShadowError> {
+        self.inputs += 1;
+        let position = self.inputs;
+        // Both sides open and close batches on the same inputs, so checking one will do.
+        match input {
+            Input::BeginBatch if self.a.batch.is_some() => {
+                return Err(ShadowError::NestedBatch { position });
+            }
+            Input::CommitBatch if self.a.batch.is_none() => {
+                return Err(ShadowError::UnopenedCommit { position });
+            }
+            _ => {}
+        }
+        let (client, tx) = match &input {
+            Input::Transaction(tx) | Input::Scheduled { tx, .. } => (Some(tx.client), Some(tx.tx)),
+            Input::Admin(op) => (Some(op.client()), None),
+            _ => (None, None),
+        };
+        let a = self.a.apply(input.clone());
+        let b = self.b.apply(input);
+        if a != b {
+            self.divergences.push(Divergence {
+                position,
+                client,
+                tx,
+                a,
+                b,
+            });
+        }
+        Ok((a, b))
+    }
+
+    /// The inputs the engines answered differently, in input order.
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+
+    /// How many inputs were applied.
+    pub fn inputs(&self) -> u64 {
+        self.inputs
+    }
+
+    /// The clients whose accounts differ between the engines, ordered by client, with
+    /// engine A as the expected side.
+    pub fn account_diffs(&self) -> Vec<ClientDiff> {
+        reconcile::reconcile(&report(&self.a.engine), &report(&self.b.engine))
+    }
+
+    pub fn engines(&self) -> (&Engine, &Engine) {
+        (&self.a.engine, &self.b.engine)
+    }
+
+    pub fn into_engines(self) -> (Engine, Engine) {
+        (self.a.engine, self.b.engine)
+    }
+}
+
+/// The compared columns of `engine`'s account report.
+fn report(engine: &Engine) -> Report {
+    engine
+        .output_sorted()
+        .into_iter()
+        .map(|output| {
+            let amount = |value: Money| value.to_decimal(output.scale);
+            let row = ReportRow {
+                available: amount(output.available),
+                held: amount(output.held),
+                total: amount(output.total),
+                locked: output.locked,
+            };
+            (output.client, row)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DisputeShortfallPolicy;
+    use crate::types::{Transaction, TransactionType};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_shadow_run() {
+        let mut runner = ShadowRunner::with_configs(
+            EngineConfig::default(),
+            EngineConfig::default().with_dispute_shortfall(DisputeShortfallPolicy::RejectDispute),
+        );
+        let tx = |tx_type, client, tx, amount| {
+            Input::Transaction(Transaction {
+                tx_type,
+                client,
+                tx,
+                amount,
+                timestamp: None,
+                to_client: None,
+                metadata: Default::default(),
+            })
+        };
+        let inputs = [
+            tx(TransactionType::Deposit, 1, 1, Some(dec!(5))),
+            tx(TransactionType::Withdrawal, 1, 2, Some(dec!(3))),
+            Input::BeginBatch,
+            tx(TransactionType::Dispute, 1, 1, None),
+            tx(TransactionType::Deposit, 1, 3, Some(dec!(1))),
+            Input::CommitBatch,
+            tx(TransactionType::Deposit, 2, 4, Some(dec!(2))),
+        ];
+        for input in inputs {
+            runner.apply(input).unwrap();
+        }
+
+        let divergences: Vec<_> = runner
+            .divergences()
+            .iter()
+            .map(|divergence| (divergence.position, divergence.a, divergence.b))
+            .collect();
+        assert_eq!(
+            divergences,
+            [
+                (
+                    4,
+                    Outcome::Applied,
+                    Outcome::Rejected(ProcessError::DisputeShortfall)
+                ),
+                (5, Outcome::Applied, Outcome::Skipped),
+            ]
+        );
+        let diffs = runner.account_diffs();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].client(), 1);
+        let fields: Vec<_> = diffs[0]
+            .fields()
+            .into_iter()
+            .map(|(field, ..)| field)
+            .collect();
+        assert_eq!(fields, ["available", "held", "total"]);
+
+        assert_eq!(
+            runner.apply(Input::CommitBatch),
+            Err(ShadowError::UnopenedCommit { position: 8 })
+        );
+    }
+}
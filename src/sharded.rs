@@ -0,0 +1,324 @@
+//! Multi-threaded processing with clients partitioned across engines.
+//!
+//! A dispute, resolve or chargeback only ever refers to a transaction made by the same
+//! client, so apart from transfers, clients are independent of each other and can be
+//! processed in parallel. A [`ShardedEngine`] gives each worker thread its own [`Engine`]
+//! and routes every transaction to the worker owning its client, chosen by a
+//! [`Partitioner`], so a client's transactions are applied in input order by one engine. At
+//! the end the workers' accounts are merged into one report ordered by client.
+//!
+//! Timers see the same time as in a single engine: each transaction carries the latest
+//! timestamp read so far and its shard catches up to it first, and every shard is advanced
+//! to the final time when processing finishes. Given unique transaction ids, the accounts
+//! come out exactly as a single engine would leave them. What doesn't carry over is anything
+//! spanning clients: a transfer is only accepted between clients of the same shard, a
+//! dispute of another client's deposit may be rejected as `UnknownTransaction` rather than
+//! `ClientMismatch`, batches aren't available, id collision detection and dormancy by
+//! transaction count work per shard, and a memory limit applies to each shard separately.
+
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "io")]
+use std::io::Write;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::config::EngineConfig;
+use crate::engine::Engine;
+use crate::ignored::IgnoredCounts;
+use crate::partition::{Partitioner, ShardId};
+use crate::stats::EngineStats;
+use crate::types::{AccountOutput, ClientId, Transaction, TransactionType, TxId};
+
+/// Transactions handed to a worker at a time.
+const CHUNK: usize = 4096;
+
+/// Chunks queued per worker before [`ShardedEngine::submit`] waits for it to catch up.
+const QUEUED_CHUNKS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardError {
+    /// A worker thread panicked, so its accounts are lost.
+    Failed { shard: ShardId },
+    /// Transfer `tx` is between clients of different shards, which can't be applied
+    /// atomically.
+    CrossShardTransfer { tx: TxId },
+}
+
+impl fmt::Display for ShardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardError::Failed { shard } => write!(f, "worker for shard {} panicked", shard),
+            ShardError::CrossShardTransfer { tx } => {
+                write!(f, "transfer {} is between clients of different shards", tx)
+            }
+        }
+    }
+}
+
+impl Error for ShardError {}
+
+enum Message {
+    /// Transactions, each with the latest timestamp seen before it.
+    Chunk(Vec<(u64, Transaction)>),
+    /// No more input; advance to the final time.
+    Finish { now: u64 },
+}
+
+/// What one worker did.
+struct ShardResult {
+    accounts: Vec<AccountOutput>,
+    applied: u64,
+    ignored: IgnoredCounts,
+    stats: EngineStats,
+}
+
+struct Worker {
+    sender: SyncSender<Message>,
+    pending: Vec<(u64, Transaction)>,
+    handle: JoinHandle<ShardResult>,
+}
+
+/// Engines on worker threads, one per shard of the client space.
+pub struct ShardedEngine {
+    workers: Vec<Worker>,
+    partitioner: Partitioner,
+    /// Index into `workers` for every client id below 2^16, the others asking `partitioner`.
+    routes: Vec<u16>,
+    now: u64,
+}
+
+impl ShardedEngine {
+    /// `threads` workers (at least one), each running an engine with `config`.
+    pub fn new(threads: usize, config: EngineConfig) -> Self {
+        Self::from_fn(threads, |_| Engine::with_config(config.clone()))
+    }
+
+    /// `threads` workers (at least one), running the engines `engine` builds for each shard,
+    /// e.g. to install event sinks.
+    pub fn from_fn(threads: usize, mut engine: impl FnMut(ShardId) -> Engine) -> Self {
+        let threads = threads.clamp(1, usize::from(u16::MAX));
+        let partitioner = Partitioner::new(threads as u32);
+        let routes = (0..=ClientId::from(u16::MAX))
+            .map(|client| partitioner.shard_for(client).unwrap_or(0) as u16)
+            .collect();
+        let workers = (0..threads as ShardId)
+            .map(|shard| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUED_CHUNKS);
+                let engine = engine(shard);
+                Worker {
+                    sender,
+                    pending: Vec::with_capacity(CHUNK),
+                    handle: thread::spawn(move || run_shard(engine, receiver)),
+                }
+            })
+            .collect();
+        Self {
+            workers,
+            partitioner,
+            routes,
+            now: 0,
+        }
+    }
+
+    /// Number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// The shard processing `client`'s transactions.
+    pub fn shard_for(&self, client: ClientId) -> ShardId {
+        let route = usize::try_from(client)
+            .ok()
+            .and_then(|index| self.routes.get(index));
+        match route {
+            Some(&shard) => ShardId::from(shard),
+            None => self.partitioner.shard_for(client).unwrap_or(0),
+        }
+    }
+
+    /// Queues `tx` for its client's worker. Transactions are handed over in chunks, so
+    /// this only blocks when the worker falls behind. Fails if the worker has died, or for a
+    /// transfer to a client of another shard, which is not queued.
+    pub fn submit(&mut self, tx: Transaction) -> Result<(), ShardError> {
+        let shard = self.shard_for(tx.client);
+        let recipient = match tx.tx_type {
+            TransactionType::Transfer => tx.to_client.map(|to| self.shard_for(to)),
+            _ => None,
+        };
+        if recipient.is_some_and(|recipient| recipient != shard) {
+            return Err(ShardError::CrossShardTransfer { tx: tx.tx });
+        }
+        let now = self.now;
+        self.now = self.now.max(tx.timestamp.unwrap_or(0));
+        let worker = &mut self.workers[shard as usize];
+        worker.pending.push((now, tx));
+        if worker.pending.len() < CHUNK {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut worker.pending, Vec::with_capacity(CHUNK));
+        worker
+            .sender
+            .send(Message::Chunk(chunk))
+            .map_err(|_| ShardError::Failed { shard })
+    }
+
+    /// Processes everything still queued, stops the workers and merges their results.
+    pub fn finish(self) -> Result<ShardedReport, ShardError> {
+        let now = self.now;
+        // Hand everything over before waiting on any worker, so they finish in parallel.
+        let mut handles = Vec::with_capacity(self.workers.len());
+        for (shard, worker) in self.workers.into_iter().enumerate() {
+            // A send only fails if the worker is gone, which joining it reports.
+            let _ = worker.sender.send(Message::Chunk(worker.pending));
+            let _ = worker.sender.send(Message::Finish { now });
+            handles.push((shard as ShardId, worker.handle));
+        }
+        let mut report = ShardedReport::default();
+        for (shard, handle) in handles {
+            let result = handle.join().map_err(|_| ShardError::Failed { shard })?;
+            report.accounts.extend(result.accounts);
+            report.applied += result.applied;
+            report.ignored.merge(&result.ignored);
+            report.stats.merge(&result.stats);
+        }
+        report.accounts.sort_by_key(|account| account.client);
+        Ok(report)
+    }
+}
+
+fn run_shard(mut engine: Engine, receiver: Receiver<Message>) -> ShardResult {
+    let mut applied = 0;
+    let mut ignored = IgnoredCounts::default();
+    for message in receiver {
+        match message {
+            Message::Chunk(transactions) => {
+                for (now, tx) in transactions {
+                    engine.advance_time(now);
+                    match engine.process(tx) {
+                        Ok(_) => applied += 1,
+                        Err(error) => ignored.record(error),
+                    }
+                }
+            }
+            Message::Finish { now } => {
+                engine.advance_time(now);
+                break;
+            }
+        }
+    }
+    ShardResult {
+        accounts: engine.output(),
+        applied,
+        ignored,
+        stats: engine.stats().clone(),
+    }
+}
+
+/// The merged outcome of a [`ShardedEngine`] run.
+#[derive(Debug, Default)]
+pub struct ShardedReport {
+    /// Every account, ordered by client.
+    pub accounts: Vec<AccountOutput>,
+    pub applied: u64,
+    /// Rejected transactions by reason.
+    pub ignored: IgnoredCounts,
+    /// Every worker's [`Engine::stats`], added up.
+    pub stats: EngineStats,
+}
+
+impl ShardedReport {
+    #[cfg(feature = "io")]
+    /// Writes the accounts as a `v1` CSV report, returning the number of rows.
+    pub fn write_csv<W: Write>(&self, writer: W) -> csv::Result<usize> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for account in &self.accounts {
+            writer.serialize(account)?;
+        }
+        writer.flush()?;
+        Ok(self.accounts.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReservePolicy;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    /// A deterministic mix of every transaction type over a few hundred clients.
+    fn transactions() -> Vec<Transaction> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        (1..=20_000u64)
+            .map(|id| {
+                let (tx_type, tx, amount) = match next(10) {
+                    0..=4 => (
+                        TransactionType::Deposit,
+                        id,
+                        Some(Decimal::from(next(100) + 1)),
+                    ),
+                    5 | 6 => (
+                        TransactionType::Withdrawal,
+                        id,
+                        Some(Decimal::from(next(50) + 1)),
+                    ),
+                    7 => (TransactionType::Dispute, next(id) as TxId + 1, None),
+                    8 => (TransactionType::Resolve, next(id) as TxId + 1, None),
+                    _ => (TransactionType::Chargeback, next(id) as TxId + 1, None),
+                };
+                Transaction {
+                    tx_type,
+                    client: next(300) as ClientId,
+                    tx,
+                    amount,
+                    timestamp: (id % 3 != 0).then_some(id * 60),
+                    to_client: None,
+                    metadata: Default::default(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_a_single_engine() {
+        let config = EngineConfig::default().with_reserve(ReservePolicy {
+            rate: dec!(0.1),
+            period: Duration::from_secs(3600),
+        });
+        let mut single = Engine::with_config(config.clone());
+        let mut expected_ignored = IgnoredCounts::default();
+        for tx in transactions() {
+            if let Err(error) = single.process(tx) {
+                expected_ignored.record(error);
+            }
+        }
+        let mut expected = single.output();
+        expected.sort_by_key(|account| account.client);
+        let rows = |accounts: &[AccountOutput]| -> Vec<_> {
+            accounts
+                .iter()
+                .map(|a| (a.client, a.available, a.held, a.locked, a.reserved))
+                .collect()
+        };
+
+        let mut sharded = ShardedEngine::new(4, config);
+        assert_eq!(sharded.threads(), 4);
+        for tx in transactions() {
+            sharded.submit(tx).unwrap();
+        }
+        let report = sharded.finish().unwrap();
+        assert_eq!(rows(&report.accounts), rows(&expected));
+        // Disputes of other clients' deposits may be counted under a different reason.
+        assert_eq!(report.ignored.total(), expected_ignored.total());
+        assert_eq!(report.applied + report.ignored.total(), 20_000);
+    }
+}
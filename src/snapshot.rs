@@ -0,0 +1,817 @@
+//! Binary engine snapshots, for warm starts without replaying the input.
+//!
+//! [`Engine::save_snapshot`] writes the same state as a [checkpoint](crate::checkpoint),
+//! in a compact binary form that loads far faster than the text one, and
+//! [`Engine::load_snapshot`] reads it back into a new engine configured as the one that
+//! wrote it.
+//!
+//! Layout (all integers little-endian):
+//!
+//! ```text
+//! header:  magic "TXS\0" (4 bytes) | version u16 | reserved u16
+//! section: entries u32 | payload length u32 | payload | CRC-32 of payload u32
+//! entry:   tag u8 | fields
+//! ```
+//!
+//! A section with no entries ends the snapshot. Entry tags are the letters of the
+//! checkpoint lines:
+//!
+//! ```text
+//! S  position u64 | seq u64 | now u64 | saturations u64      first, exactly once
+//! A  client u128 | available i64 | held i64 | reserved i64 | flags u8 (1 locked, 2 closed)
+//! T  tx u64 | amount i64 | state u8 | kind u8 | to_client u128 | has_timestamp u8 | timestamp u64
+//! R  due u64 | client u128 | tx u64 | amount i64
+//! U  due u64 | client u128 | locked_at u64
+//! X  due u64 | client u128 | tx u64 | opened_at u64
+//! Q  due u64 | client u128 | tx u64 | type u8 | amount i64 | has_to_client u8 | to_client u128
+//! C  client u128 | time u64
+//! O  tx u64 | time u64
+//! P  tx u64 | amount i64
+//! M  from u128 | into u128
+//! Y  client u128 | time u64
+//! D  client u128 | time u64 | seq u64
+//! I  tx u64 | client u128 | type u8
+//! H  tx u64 | client u128 | amount i64 | remaining i64
+//! V  client u128 | day u64 | withdrawn i64 | count u32 | time u64 × count
+//! L  time u64
+//! W  client u128 | limit i64
+//! K  first u64 | last u64 | state u8
+//! ```
+//!
+//! `T` entries are the stored transactions of the latest `A` account. Amounts are in the
+//! engine's fixed-point units, states are 0 none, 1 disputed and 2 charged back, kinds 0
+//! deposit, 1 withdrawal and 2 transfer, and types as in the [journal](crate::journal).
+//! Sections are kept small, so a damaged one is found before much is read, and readers
+//! reject versions newer than they understand. Version 1 snapshots, with clients as u16 and
+//! transaction ids as u32 but otherwise the same, are still read.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::archive::ArchivedAccount;
+use crate::checkpoint::{CheckpointError, Snapshot, sorted};
+use crate::engine::Engine;
+use crate::journal;
+use crate::limits::ClientVelocity;
+use crate::timers::Timer;
+use crate::types::{
+    Account, ClientId, DisputeState, Hold, Money, StoredKind, StoredTransaction, TxId,
+};
+
+pub const MAGIC: [u8; 4] = *b"TXS\0";
+pub const VERSION: u16 = 2;
+/// The oldest version readers accept.
+pub const MIN_VERSION: u16 = 1;
+
+/// Payload size after which the writer starts a new section.
+const SECTION_BYTES: usize = 64 * 1024;
+/// Larger than any section the writer produces; guards against allocating on a corrupt
+/// length.
+const MAX_SECTION: u32 = 16 * 1024 * 1024;
+
+impl Engine {
+    /// Saves the engine's state to `path` as a binary snapshot covering the first `position`
+    /// transactions of the input. The file is written next to `path` and synced before it
+    /// replaces it, so a crash leaves any previous snapshot intact. Fails as
+    /// [`Engine::write_snapshot`] does.
+    pub fn save_snapshot(
+        &self,
+        position: u64,
+        path: impl AsRef<Path>,
+    ) -> Result<(), CheckpointError> {
+        let path = path.as_ref();
+        let snapshot = self.snapshot(position)?;
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        write(&snapshot, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Loads the binary snapshot at `path` into this engine, which must be new and
+    /// configured as the one that saved it, and returns its position. The installed clock,
+    /// store, archive and sinks are kept.
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> Result<u64, CheckpointError> {
+        let snapshot = read(BufReader::new(File::open(path)?))?;
+        let position = snapshot.position;
+        self.restore(snapshot)?;
+        Ok(position)
+    }
+}
+
+pub(crate) fn write<W: Write>(snapshot: &Snapshot, writer: &mut W) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    let mut sections = SectionWriter::new(writer);
+
+    let entry = sections.entry(b'S')?;
+    put_u64(entry, snapshot.position);
+    put_u64(entry, snapshot.seq);
+    put_u64(entry, snapshot.now);
+    put_u64(entry, snapshot.saturations);
+    for archived in &snapshot.accounts {
+        let account = &archived.account;
+        let entry = sections.entry(b'A')?;
+        put_client(entry, archived.client);
+        put_i64(entry, account.available.units());
+        put_i64(entry, account.held.units());
+        put_i64(entry, account.reserved.units());
+        entry.push(u8::from(account.locked) | u8::from(account.closed) << 1);
+        for (tx, stored) in &archived.transactions {
+            let entry = sections.entry(b'T')?;
+            put_tx(entry, *tx);
+            put_i64(entry, stored.amount.units());
+            entry.push(match stored.dispute_state {
+                DisputeState::None => 0,
+                DisputeState::Disputed => 1,
+                DisputeState::ChargedBack => 2,
+            });
+            let (kind, to_client) = match stored.kind {
+                StoredKind::Deposit => (0, 0),
+                StoredKind::Withdrawal => (1, 0),
+                StoredKind::Transfer { to_client } => (2, to_client),
+            };
+            entry.push(kind);
+            put_client(entry, to_client);
+            entry.push(u8::from(stored.timestamp.is_some()));
+            put_u64(entry, stored.timestamp.unwrap_or(0));
+        }
+    }
+    for (due, timer) in &snapshot.timers {
+        match *timer {
+            Timer::ReleaseReserve { client, tx, amount } => {
+                let entry = sections.entry(b'R')?;
+                put_u64(entry, *due);
+                put_client(entry, client);
+                put_tx(entry, tx);
+                put_i64(entry, amount.units());
+            }
+            Timer::Unlock { client, locked_at } => {
+                let entry = sections.entry(b'U')?;
+                put_u64(entry, *due);
+                put_client(entry, client);
+                put_u64(entry, locked_at);
+            }
+            Timer::ExpireDispute {
+                client,
+                tx,
+                opened_at,
+            } => {
+                let entry = sections.entry(b'X')?;
+                put_u64(entry, *due);
+                put_client(entry, client);
+                put_tx(entry, tx);
+                put_u64(entry, opened_at);
+            }
+            Timer::Settle {
+                client,
+                tx,
+                tx_type,
+                amount,
+                to_client,
+            } => {
+                let entry = sections.entry(b'Q')?;
+                put_u64(entry, *due);
+                put_client(entry, client);
+                put_tx(entry, tx);
+                entry.push(journal::encode_type(&tx_type));
+                put_i64(entry, amount.units());
+                entry.push(u8::from(to_client.is_some()));
+                put_client(entry, to_client.unwrap_or(0));
+            }
+        }
+    }
+    for (client, &time) in sorted(&snapshot.last_chargeback) {
+        let entry = sections.entry(b'C')?;
+        put_client(entry, client);
+        put_u64(entry, time);
+    }
+    for (tx, &time) in sorted(&snapshot.dispute_opened) {
+        let entry = sections.entry(b'O')?;
+        put_tx(entry, tx);
+        put_u64(entry, time);
+    }
+    for (tx, amount) in sorted(&snapshot.partial_holds) {
+        let entry = sections.entry(b'P')?;
+        put_tx(entry, tx);
+        put_i64(entry, amount.units());
+    }
+    for (from, &into) in sorted(&snapshot.merged) {
+        let entry = sections.entry(b'M')?;
+        put_client(entry, from);
+        put_client(entry, into);
+    }
+    for (client, &time) in sorted(&snapshot.last_activity) {
+        let entry = sections.entry(b'Y')?;
+        put_client(entry, client);
+        put_u64(entry, time);
+    }
+    for (client, &(time, seq)) in sorted(&snapshot.last_active) {
+        let entry = sections.entry(b'D')?;
+        put_client(entry, client);
+        put_u64(entry, time);
+        put_u64(entry, seq);
+    }
+    for (tx, (client, tx_type)) in sorted(&snapshot.id_owners) {
+        let entry = sections.entry(b'I')?;
+        put_tx(entry, tx);
+        put_client(entry, *client);
+        entry.push(journal::encode_type(tx_type));
+    }
+    for (tx, hold) in sorted(&snapshot.holds) {
+        let entry = sections.entry(b'H')?;
+        put_tx(entry, tx);
+        put_client(entry, hold.client);
+        put_i64(entry, hold.amount.units());
+        put_i64(entry, hold.remaining.units());
+    }
+    for (client, counts) in sorted(&snapshot.velocity) {
+        let entry = sections.entry(b'V')?;
+        put_client(entry, client);
+        put_u64(entry, counts.day);
+        put_i64(entry, counts.withdrawn.units());
+        put_u32(entry, counts.recent.len() as u32);
+        for &time in &counts.recent {
+            put_u64(entry, time);
+        }
+    }
+    if let Some(time) = snapshot.last_accrual {
+        put_u64(sections.entry(b'L')?, time);
+    }
+    for (client, limit) in sorted(&snapshot.overdrafts) {
+        let entry = sections.entry(b'W')?;
+        put_client(entry, client);
+        put_i64(entry, limit.units());
+    }
+    for (first, last, state) in snapshot.compacted.runs() {
+        let entry = sections.entry(b'K')?;
+        put_tx(entry, first);
+        put_tx(entry, last);
+        entry.push(u8::from(state == DisputeState::ChargedBack) * 2);
+    }
+    sections.finish()
+}
+
+pub(crate) fn read<R: Read>(mut reader: R) -> Result<Snapshot, CheckpointError> {
+    let mut header = [0u8; 8];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| corrupt("not a binary snapshot"))?;
+    if header[..4] != MAGIC {
+        return Err(corrupt("not a binary snapshot"));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(corrupt(&format!("unknown version {}", version)));
+    }
+
+    let mut sections = SectionReader::new(reader, version);
+    if sections.next_entry()? != Some(b'S') {
+        return Err(corrupt("missing header"));
+    }
+    let mut snapshot = Snapshot {
+        position: sections.u64()?,
+        seq: sections.u64()?,
+        now: sections.u64()?,
+        saturations: sections.u64()?,
+        ..Snapshot::default()
+    };
+    while let Some(tag) = sections.next_entry()? {
+        match tag {
+            b'A' => {
+                let client = sections.client()?;
+                let available = sections.money()?;
+                let held = sections.money()?;
+                let reserved = sections.money()?;
+                let flags = sections.u8()?;
+                let account = Account {
+                    available,
+                    held,
+                    reserved,
+                    on_hold: Money::ZERO,
+                    locked: flags & 1 != 0,
+                    closed: flags & 2 != 0,
+                };
+                snapshot.accounts.push(ArchivedAccount {
+                    client,
+                    account,
+                    transactions: Vec::new(),
+                });
+            }
+            b'T' => {
+                let tx = sections.tx()?;
+                let amount = sections.money()?;
+                let dispute_state = match sections.u8()? {
+                    0 => DisputeState::None,
+                    1 => DisputeState::Disputed,
+                    2 => DisputeState::ChargedBack,
+                    _ => return Err(corrupt("bad dispute state")),
+                };
+                let kind = sections.u8()?;
+                let to_client = sections.client()?;
+                let kind = match kind {
+                    0 => StoredKind::Deposit,
+                    1 => StoredKind::Withdrawal,
+                    2 => StoredKind::Transfer { to_client },
+                    _ => return Err(corrupt("bad transaction kind")),
+                };
+                let has_timestamp = sections.u8()? != 0;
+                let timestamp = Some(sections.u64()?).filter(|_| has_timestamp);
+                let archived = snapshot
+                    .accounts
+                    .last_mut()
+                    .ok_or_else(|| corrupt("transaction before any account"))?;
+                let stored = StoredTransaction {
+                    client: archived.client,
+                    amount,
+                    dispute_state,
+                    kind,
+                    timestamp,
+                };
+                archived.transactions.push((tx, stored));
+            }
+            b'R' => {
+                let due = sections.u64()?;
+                let timer = Timer::ReleaseReserve {
+                    client: sections.client()?,
+                    tx: sections.tx()?,
+                    amount: sections.money()?,
+                };
+                snapshot.timers.push((due, timer));
+            }
+            b'U' => {
+                let due = sections.u64()?;
+                let timer = Timer::Unlock {
+                    client: sections.client()?,
+                    locked_at: sections.u64()?,
+                };
+                snapshot.timers.push((due, timer));
+            }
+            b'X' => {
+                let due = sections.u64()?;
+                let timer = Timer::ExpireDispute {
+                    client: sections.client()?,
+                    tx: sections.tx()?,
+                    opened_at: sections.u64()?,
+                };
+                snapshot.timers.push((due, timer));
+            }
+            b'Q' => {
+                let due = sections.u64()?;
+                let client = sections.client()?;
+                let tx = sections.tx()?;
+                let tx_type = journal::decode_type(sections.u8()?)
+                    .ok_or_else(|| corrupt("bad transaction type"))?;
+                let amount = sections.money()?;
+                let has_to_client = sections.u8()? != 0;
+                let to_client = Some(sections.client()?).filter(|_| has_to_client);
+                let timer = Timer::Settle {
+                    client,
+                    tx,
+                    tx_type,
+                    amount,
+                    to_client,
+                };
+                snapshot.timers.push((due, timer));
+            }
+            b'C' => {
+                let client = sections.client()?;
+                snapshot.last_chargeback.insert(client, sections.u64()?);
+            }
+            b'O' => {
+                let tx = sections.tx()?;
+                snapshot.dispute_opened.insert(tx, sections.u64()?);
+            }
+            b'P' => {
+                let tx = sections.tx()?;
+                snapshot.partial_holds.insert(tx, sections.money()?);
+            }
+            b'M' => {
+                let from = sections.client()?;
+                snapshot.merged.insert(from, sections.client()?);
+            }
+            b'Y' => {
+                let client = sections.client()?;
+                snapshot.last_activity.insert(client, sections.u64()?);
+            }
+            b'D' => {
+                let client = sections.client()?;
+                let active = (sections.u64()?, sections.u64()?);
+                snapshot.last_active.insert(client, active);
+            }
+            b'I' => {
+                let tx = sections.tx()?;
+                let client = sections.client()?;
+                let tx_type = journal::decode_type(sections.u8()?)
+                    .ok_or_else(|| corrupt("bad transaction type"))?;
+                snapshot.id_owners.insert(tx, (client, tx_type));
+            }
+            b'H' => {
+                let tx = sections.tx()?;
+                let hold = Hold {
+                    client: sections.client()?,
+                    amount: sections.money()?,
+                    remaining: sections.money()?,
+                };
+                snapshot.holds.insert(tx, hold);
+            }
+            b'V' => {
+                let client = sections.client()?;
+                let day = sections.u64()?;
+                let withdrawn = sections.money()?;
+                let count = sections.u32()?;
+                let recent = (0..count)
+                    .map(|_| sections.u64())
+                    .collect::<Result<_, _>>()?;
+                let counts = ClientVelocity {
+                    day,
+                    withdrawn,
+                    recent,
+                };
+                snapshot.velocity.insert(client, counts);
+            }
+            b'L' => snapshot.last_accrual = Some(sections.u64()?),
+            b'W' => {
+                let client = sections.client()?;
+                snapshot.overdrafts.insert(client, sections.money()?);
+            }
+            b'K' => {
+                let first = sections.tx()?;
+                let last = sections.tx()?;
+                let state = match sections.u8()? {
+                    0 => DisputeState::None,
+                    2 => DisputeState::ChargedBack,
+                    _ => return Err(corrupt("bad dispute state")),
+                };
+                snapshot.compacted.insert_run(first, last, state);
+            }
+            tag => return Err(corrupt(&format!("unknown entry tag {}", tag))),
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Groups entries into checksummed sections as they are written.
+struct SectionWriter<'a, W> {
+    writer: &'a mut W,
+    entries: u32,
+    payload: Vec<u8>,
+}
+
+impl<'a, W: Write> SectionWriter<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            entries: 0,
+            payload: Vec::with_capacity(SECTION_BYTES),
+        }
+    }
+
+    /// Starts an entry tagged `tag`, to be appended to the returned buffer.
+    fn entry(&mut self, tag: u8) -> io::Result<&mut Vec<u8>> {
+        if self.payload.len() >= SECTION_BYTES {
+            self.flush()?;
+        }
+        self.payload.push(tag);
+        self.entries += 1;
+        Ok(&mut self.payload)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.entries.to_le_bytes())?;
+        self.writer
+            .write_all(&(self.payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&self.payload)?;
+        self.writer
+            .write_all(&journal::crc32(&self.payload).to_le_bytes())?;
+        self.entries = 0;
+        self.payload.clear();
+        Ok(())
+    }
+
+    /// Writes the last section and the empty one ending the snapshot.
+    fn finish(mut self) -> io::Result<()> {
+        if self.entries > 0 {
+            self.flush()?;
+        }
+        self.flush()
+    }
+}
+
+/// Reads entries back from the sections of a [`SectionWriter`], checking each section's
+/// length and checksum before any of it is decoded.
+struct SectionReader<R> {
+    reader: R,
+    /// The snapshot's version, which decides the width of ids.
+    version: u16,
+    /// Entries of the current section not started yet.
+    remaining: u32,
+    payload: Vec<u8>,
+    offset: usize,
+    section: u64,
+}
+
+impl<R: Read> SectionReader<R> {
+    fn new(reader: R, version: u16) -> Self {
+        Self {
+            reader,
+            version,
+            remaining: 0,
+            payload: Vec::new(),
+            offset: 0,
+            section: 0,
+        }
+    }
+
+    /// Starts the next entry and returns its tag, or `None` at the end of the snapshot.
+    fn next_entry(&mut self) -> Result<Option<u8>, CheckpointError> {
+        if self.remaining == 0 {
+            if self.offset != self.payload.len() {
+                return Err(self.corrupt("entries don't fill it"));
+            }
+            self.read_section()?;
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+        }
+        self.remaining -= 1;
+        self.u8().map(Some)
+    }
+
+    fn read_section(&mut self) -> Result<(), CheckpointError> {
+        self.section += 1;
+        let mut header = [0u8; 8];
+        self.reader
+            .read_exact(&mut header)
+            .map_err(|_| corrupt("truncated"))?;
+        let entries = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if length > MAX_SECTION || (entries == 0) != (length == 0) {
+            return Err(self.corrupt("bad length"));
+        }
+        self.remaining = entries;
+        self.payload.resize(length as usize, 0);
+        let mut checksum = [0u8; 4];
+        self.reader
+            .read_exact(&mut self.payload)
+            .and_then(|_| self.reader.read_exact(&mut checksum))
+            .map_err(|_| corrupt("truncated"))?;
+        if journal::crc32(&self.payload).to_le_bytes() != checksum {
+            return Err(self.corrupt("bad checksum"));
+        }
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        let bytes = self
+            .payload
+            .get(self.offset..self.offset + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| self.corrupt("entries overrun it"))?;
+        self.offset += N;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, CheckpointError> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, CheckpointError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, CheckpointError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn client(&mut self) -> Result<ClientId, CheckpointError> {
+        if self.version == 1 {
+            return self.u16().map(ClientId::from);
+        }
+        self.take().map(ClientId::from_le_bytes)
+    }
+
+    fn tx(&mut self) -> Result<TxId, CheckpointError> {
+        if self.version == 1 {
+            return self.u32().map(TxId::from);
+        }
+        self.u64()
+    }
+
+    fn money(&mut self) -> Result<Money, CheckpointError> {
+        self.take().map(i64::from_le_bytes).map(Money::from_units)
+    }
+
+    /// A [`CheckpointError::Corrupt`] naming the current section.
+    fn corrupt(&self, problem: &str) -> CheckpointError {
+        corrupt(&format!("section {}: {}", self.section, problem))
+    }
+}
+
+fn put_client(buf: &mut Vec<u8>, value: ClientId) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_tx(buf: &mut Vec<u8>, value: TxId) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_i64(buf: &mut Vec<u8>, value: i64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn corrupt(message: &str) -> CheckpointError {
+    CheckpointError::Corrupt(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DisputeExpiry, EngineConfig, ExpiryAction, ReservePolicy};
+    use crate::types::{Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+
+    fn config() -> EngineConfig {
+        EngineConfig::default()
+            .with_dispute_expiry(DisputeExpiry {
+                after: Duration::from_secs(500),
+                action: ExpiryAction::Resolve,
+            })
+            .with_reserve(ReservePolicy {
+                rate: Decimal::new(1, 1),
+                period: Duration::from_secs(1_000),
+            })
+            .with_id_collision_detection()
+    }
+
+    fn input() -> Vec<Transaction> {
+        (1..=6_000u64)
+            .map(|id| {
+                let earlier = id.saturating_sub(7).max(1);
+                let (tx_type, tx, to_client) = match id % 10 {
+                    0..=4 => (TransactionType::Deposit, id, None),
+                    5 => (TransactionType::Withdrawal, id, None),
+                    6 => (TransactionType::Transfer, id, Some(ClientId::from(id % 13))),
+                    7 => (TransactionType::Dispute, earlier, None),
+                    8 => (TransactionType::Chargeback, earlier, None),
+                    _ => (TransactionType::Resolve, earlier, None),
+                };
+                Transaction {
+                    tx_type,
+                    client: ClientId::from(id % 13),
+                    tx,
+                    amount: matches!(
+                        tx_type,
+                        TransactionType::Deposit
+                            | TransactionType::Withdrawal
+                            | TransactionType::Transfer
+                    )
+                    .then(|| Decimal::new((id % 50 + 1) as i64, 1)),
+                    timestamp: Some(id * 10),
+                    to_client,
+                    metadata: Default::default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Queues a deposit and a transfer settling after the snapshot's position.
+    fn post_date(engine: &mut Engine) {
+        for (tx_type, tx, to_client) in [
+            (TransactionType::Deposit, 10_001, None),
+            (TransactionType::Transfer, 10_002, Some(7)),
+        ] {
+            let tx = Transaction {
+                tx_type,
+                client: 6,
+                tx,
+                amount: Some(Decimal::new(25, 1)),
+                timestamp: None,
+                to_client,
+                metadata: Default::default(),
+            };
+            engine.schedule(tx, 50_005).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trip_and_resume() {
+        let mut whole = Engine::with_config(config());
+        post_date(&mut whole);
+        for tx in input() {
+            let _ = whole.process(tx);
+        }
+        let mut first = Engine::with_config(config());
+        post_date(&mut first);
+        for tx in input().into_iter().take(4_000) {
+            let _ = first.process(tx);
+        }
+        let path = std::env::temp_dir().join(format!("tx-snapshot-{}.bin", std::process::id()));
+        first.save_snapshot(4_000, &path).unwrap();
+
+        let mut resumed = Engine::with_config(config());
+        let position = resumed.load_snapshot(&path).unwrap();
+        assert_eq!(position, 4_000);
+        for tx in input().into_iter().skip(position as usize) {
+            let _ = resumed.process(tx);
+        }
+        let accounts = |engine: &Engine| {
+            let mut accounts: Vec<_> = engine.accounts().map(|(c, a)| (c, *a)).collect();
+            accounts.sort_by_key(|&(client, _)| client);
+            accounts
+        };
+        assert_eq!(accounts(&resumed), accounts(&whole));
+
+        // Reading and writing again gives the same bytes, as does the text format.
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut again = Vec::new();
+        write(&read(bytes.as_slice()).unwrap(), &mut again).unwrap();
+        assert_eq!(again, bytes);
+        let mut text = Vec::new();
+        first.write_snapshot(4_000, &mut text).unwrap();
+        let mut from_binary = Vec::new();
+        crate::checkpoint::write(&read(bytes.as_slice()).unwrap(), &mut from_binary).unwrap();
+        assert_eq!(from_binary, text);
+    }
+
+    #[test]
+    fn test_reads_version_1() {
+        let mut payload = vec![b'S'];
+        for value in [3u64, 3, 0, 0] {
+            put_u64(&mut payload, value);
+        }
+        // Client 7 with 1.5 available, and tx 300 owned by it, with a u16 client and a u32
+        // transaction id.
+        payload.extend_from_slice(&[b'A', 7, 0]);
+        for units in [15_000i64, 0, 0] {
+            put_i64(&mut payload, units);
+        }
+        payload.push(0);
+        payload.extend_from_slice(&[b'I', 44, 1, 0, 0, 7, 0, 0]);
+        let mut bytes = b"TXS\0\x01\0\0\0".to_vec();
+        for section in [payload, Vec::new()] {
+            let entries: u32 = if section.is_empty() { 0 } else { 3 };
+            bytes.extend_from_slice(&entries.to_le_bytes());
+            bytes.extend_from_slice(&(section.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&section);
+            bytes.extend_from_slice(&journal::crc32(&section).to_le_bytes());
+        }
+
+        let snapshot = read(bytes.as_slice()).unwrap();
+        assert_eq!(snapshot.accounts.len(), 1);
+        assert_eq!(snapshot.accounts[0].client, 7);
+        assert_eq!(
+            snapshot.accounts[0].account.available,
+            Money::from_units(15_000)
+        );
+        let owner = snapshot.id_owners.get(&300).map(|&(client, _)| client);
+        assert_eq!(owner, Some(7));
+    }
+
+    #[test]
+    fn test_detects_damage() {
+        let mut engine = Engine::with_config(config());
+        for tx in input() {
+            let _ = engine.process(tx);
+        }
+        let mut bytes = Vec::new();
+        write(&engine.snapshot(6_000).unwrap(), &mut bytes).unwrap();
+        assert!(read(bytes.as_slice()).is_ok());
+
+        let damaged = |at: usize| {
+            let mut bytes = bytes.clone();
+            bytes[at] ^= 0x40;
+            read(bytes.as_slice())
+        };
+        let message = |result: Result<Snapshot, CheckpointError>| match result {
+            Err(CheckpointError::Corrupt(message)) => message,
+            other => panic!("expected a corrupt snapshot, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(message(damaged(0)), "not a binary snapshot");
+        assert_eq!(message(damaged(4)), "unknown version 66");
+        assert_eq!(message(damaged(30)), "section 1: bad checksum");
+        assert_eq!(message(damaged(bytes.len() - 12)), "section 6: bad length");
+        assert_eq!(message(read(&bytes[..bytes.len() - 1])), "truncated");
+    }
+}
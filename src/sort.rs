@@ -0,0 +1,328 @@
+//! External merge sort for transaction CSV files larger than memory.
+//!
+//! The input is read in runs of at most `max_rows` records. Each run is sorted in memory and
+//! spilled to a temporary file, then all runs are merged with a k-way heap merge. Both the
+//! in-memory sort and the merge are stable, so rows with equal keys (a deposit and its
+//! dispute, say) keep their original relative order.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use csv::{ReaderBuilder, StringRecord, Trim, WriterBuilder};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    /// By transaction id.
+    #[default]
+    Tx,
+    /// By client id, then transaction id.
+    ClientTx,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tx" => Ok(SortKey::Tx),
+            "client-tx" | "client,tx" => Ok(SortKey::ClientTx),
+            _ => Err(format!(
+                "unknown sort key '{}' (expected tx or client-tx)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SortStats {
+    pub rows: u64,
+    pub runs: usize,
+}
+
+#[derive(Debug)]
+pub struct SortError {
+    /// 1-based line number of the offending row.
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for SortError {}
+
+/// Composite key; `seq` is the row's position in the input and makes ordering total and stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    primary: u64,
+    seq: u64,
+}
+
+/// Sorts CSV transactions from `input` into `output`, holding at most `max_rows` records in
+/// memory at a time. Spill files are created under `temp_dir` (the system temp directory
+/// when `None`) and removed afterwards.
+pub fn external_sort<R: Read, W: Write>(
+    input: R,
+    output: W,
+    key: SortKey,
+    max_rows: usize,
+    temp_dir: Option<PathBuf>,
+) -> Result<SortStats, Box<dyn Error>> {
+    let max_rows = max_rows.max(1);
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    let client_col = column(&headers, "client")?;
+    let tx_col = column(&headers, "tx")?;
+
+    let spill = SpillDir::create(temp_dir)?;
+    let mut stats = SortStats::default();
+    let mut run: Vec<(Key, StringRecord)> = Vec::new();
+    let mut runs = Vec::new();
+
+    for result in reader.records() {
+        let record = result?;
+        let line = record.position().map_or(0, |p| p.line());
+        let field = |col: usize, name: &str| -> Result<u64, SortError> {
+            record
+                .get(col)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| SortError {
+                    line,
+                    message: format!("invalid {} id", name),
+                })
+        };
+        let tx = field(tx_col, "tx")?;
+        let primary = match key {
+            SortKey::Tx => tx,
+            SortKey::ClientTx => (field(client_col, "client")? << 32) | tx,
+        };
+        run.push((
+            Key {
+                primary,
+                seq: stats.rows,
+            },
+            record,
+        ));
+        stats.rows += 1;
+
+        if run.len() >= max_rows {
+            runs.push(spill.write_run(runs.len(), &mut run)?);
+        }
+    }
+    if !run.is_empty() {
+        runs.push(spill.write_run(runs.len(), &mut run)?);
+    }
+    stats.runs = runs.len();
+
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(output);
+    writer.write_record(&headers)?;
+    merge_runs(&runs, &mut writer)?;
+    writer.flush()?;
+    Ok(stats)
+}
+
+fn column(headers: &StringRecord, name: &str) -> Result<usize, Box<dyn Error>> {
+    headers
+        .iter()
+        .position(|h| h == name)
+        .ok_or_else(|| format!("missing column '{}'", name).into())
+}
+
+/// Heap entry for the k-way merge: the smallest key across all runs is popped first.
+struct Head {
+    key: Key,
+    record: StringRecord,
+    run: usize,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+fn merge_runs<W: Write>(
+    runs: &[PathBuf],
+    writer: &mut csv::Writer<W>,
+) -> Result<(), Box<dyn Error>> {
+    let mut readers = runs
+        .iter()
+        .map(|path| {
+            Ok(ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(BufReader::new(File::open(path)?)))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(head) = next_head(reader, run)? {
+            heap.push(Reverse(head));
+        }
+    }
+    while let Some(Reverse(head)) = heap.pop() {
+        // Spilled records carry the two key fields in front of the original columns.
+        writer.write_record(head.record.iter().skip(2))?;
+        if let Some(next) = next_head(&mut readers[head.run], head.run)? {
+            heap.push(Reverse(next));
+        }
+    }
+    Ok(())
+}
+
+fn next_head<R: Read>(
+    reader: &mut csv::Reader<R>,
+    run: usize,
+) -> Result<Option<Head>, Box<dyn Error>> {
+    let mut record = StringRecord::new();
+    if !reader.read_record(&mut record)? {
+        return Ok(None);
+    }
+    let parse = |i: usize| {
+        record
+            .get(i)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    };
+    let key = Key {
+        primary: parse(0),
+        seq: parse(1),
+    };
+    Ok(Some(Head { key, record, run }))
+}
+
+/// Temporary directory for sorted runs, removed on drop.
+struct SpillDir {
+    path: PathBuf,
+}
+
+impl SpillDir {
+    fn create(parent: Option<PathBuf>) -> std::io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let path = parent.unwrap_or_else(env::temp_dir).join(format!(
+            "tx-engine-sort-{}-{}",
+            process::id(),
+            nanos
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    fn write_run(
+        &self,
+        index: usize,
+        run: &mut Vec<(Key, StringRecord)>,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        run.sort_unstable_by_key(|(key, _)| *key);
+        let path = self.path.join(format!("run-{}.csv", index));
+        let mut writer = WriterBuilder::new()
+            .flexible(true)
+            .from_writer(BufWriter::new(File::create(&path)?));
+        for (key, record) in run.drain(..) {
+            let primary = key.primary.to_string();
+            let seq = key.seq.to_string();
+            writer.write_record(
+                [primary.as_str(), seq.as_str()]
+                    .into_iter()
+                    .chain(record.iter()),
+            )?;
+        }
+        writer.flush()?;
+        Ok(path)
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,2,5,1.0
+deposit,1,3,2.0
+dispute,2,5,
+withdrawal,1,1,0.5
+deposit,3,2,4.0
+resolve,2,5,
+";
+
+    fn sort(key: SortKey, max_rows: usize) -> (String, SortStats) {
+        let mut out = Vec::new();
+        let stats = external_sort(INPUT.as_bytes(), &mut out, key, max_rows, None).unwrap();
+        (String::from_utf8(out).unwrap(), stats)
+    }
+
+    #[test]
+    fn test_sort_by_tx_is_stable_across_runs() {
+        let expected = "type,client,tx,amount
+withdrawal,1,1,0.5
+deposit,3,2,4.0
+deposit,1,3,2.0
+deposit,2,5,1.0
+dispute,2,5,
+resolve,2,5,
+";
+        for max_rows in [1, 2, 4, 100] {
+            let (out, stats) = sort(SortKey::Tx, max_rows);
+            assert_eq!(out, expected, "max_rows = {}", max_rows);
+            assert_eq!(stats.rows, 6);
+            assert_eq!(stats.runs, 6usize.div_ceil(max_rows));
+        }
+    }
+
+    #[test]
+    fn test_sort_by_client_then_tx() {
+        let (out, _) = sort(SortKey::ClientTx, 2);
+        let clients: Vec<&str> = out
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').nth(1).unwrap())
+            .collect();
+        assert_eq!(clients, ["1", "1", "2", "2", "2", "3"]);
+    }
+
+    #[test]
+    fn test_invalid_id_reports_line() {
+        let input = "type,client,tx,amount\ndeposit,1,x,1.0\n";
+        let err = external_sort(input.as_bytes(), Vec::new(), SortKey::Tx, 10, None).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid tx id");
+    }
+}
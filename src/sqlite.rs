@@ -0,0 +1,469 @@
+//! Engine state in a SQLite database (feature `sqlite`), so it survives restarts and can be
+//! queried with SQL.
+//!
+//! [`SqliteStore`] is a [`StateStore`]. Saving replaces the database's contents with the
+//! engine's accounts and stored transactions, in one SQL transaction:
+//!
+//! ```text
+//! accounts(client, available, held, reserved, locked, closed, on_hold)
+//! transactions(tx, client, kind, amount, dispute_state, timestamp, to_client)
+//! engine_state(key, value)
+//! ```
+//!
+//! Ids are stored as SQLite's signed 64-bit integers, so saving fails for a client or
+//! transaction id above `i64::MAX`. Amounts are integers in minor units at the engine's
+//! scale, which `engine_state` holds
+//! under `scale`; `kind` is `deposit`, `withdrawal` or `transfer`, and `dispute_state` is
+//! `none`, `disputed` or `chargedback`. The rest of the engine's bookkeeping (pending
+//! timers, open disputes, merged clients and so on) is kept under `bookkeeping` in the
+//! [checkpoint](crate::checkpoint) format. Saving has the same limits as a checkpoint.
+//!
+//! Opening a database brings its schema up to date, recording the version in
+//! `PRAGMA user_version`. A database from a newer version of the engine is refused.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::archive::ArchivedAccount;
+use crate::checkpoint::{self, CheckpointError};
+use crate::engine::Engine;
+use crate::store::StateStore;
+use crate::types::{Account, ClientId, DisputeState, Money, StoredKind, StoredTransaction, TxId};
+
+/// The schema changes, in order; the database's `user_version` counts those applied.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        available INTEGER NOT NULL,
+        held INTEGER NOT NULL,
+        reserved INTEGER NOT NULL,
+        locked INTEGER NOT NULL
+    );
+    CREATE TABLE transactions (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        amount INTEGER NOT NULL,
+        dispute_state TEXT NOT NULL,
+        timestamp INTEGER,
+        to_client INTEGER
+    );
+    CREATE INDEX transactions_client ON transactions (client);
+    CREATE TABLE engine_state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+",
+    "
+    ALTER TABLE accounts ADD COLUMN closed INTEGER NOT NULL DEFAULT 0;
+",
+    "
+    ALTER TABLE accounts ADD COLUMN on_hold INTEGER NOT NULL DEFAULT 0;
+",
+];
+
+#[derive(Debug)]
+pub enum SqliteError {
+    Sqlite(rusqlite::Error),
+    /// The engine can't be saved, or the saved bookkeeping doesn't load.
+    Checkpoint(CheckpointError),
+    /// The database's schema is newer than this version supports.
+    NewerSchema(u32),
+    /// The database was saved at a different scale than the engine's.
+    ScaleMismatch {
+        saved: u32,
+        engine: u32,
+    },
+    /// A client or transaction id is too large for an SQLite integer.
+    IdTooLarge {
+        what: &'static str,
+        id: u128,
+    },
+    /// A row holds a value the engine can't have written.
+    Corrupt(String),
+}
+
+impl fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SqliteError::Sqlite(error) => write!(f, "{}", error),
+            SqliteError::Checkpoint(error) => write!(f, "{}", error),
+            SqliteError::NewerSchema(version) => write!(
+                f,
+                "database schema version {} is newer than the supported {}",
+                version,
+                MIGRATIONS.len()
+            ),
+            SqliteError::ScaleMismatch { saved, engine } => write!(
+                f,
+                "database was saved with scale {}, but the engine uses {}",
+                saved, engine
+            ),
+            SqliteError::IdTooLarge { what, id } => {
+                write!(f, "{} {} is too large for an SQLite integer", what, id)
+            }
+            SqliteError::Corrupt(message) => write!(f, "corrupt database: {}", message),
+        }
+    }
+}
+
+impl Error for SqliteError {}
+
+impl From<rusqlite::Error> for SqliteError {
+    fn from(error: rusqlite::Error) -> Self {
+        SqliteError::Sqlite(error)
+    }
+}
+
+impl From<CheckpointError> for SqliteError {
+    fn from(error: CheckpointError) -> Self {
+        SqliteError::Checkpoint(error)
+    }
+}
+
+/// Engine state in a SQLite database.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path` and migrates its schema.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SqliteError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// A database held in memory, gone when the store is dropped.
+    pub fn open_in_memory() -> Result<Self, SqliteError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut conn: Connection) -> Result<Self, SqliteError> {
+        migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// The underlying connection, for queries.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+/// Applies the migrations the database hasn't had yet.
+fn migrate(conn: &mut Connection) -> Result<(), SqliteError> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(SqliteError::NewerSchema(version));
+    }
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let sql = conn.transaction()?;
+        sql.execute_batch(migration)?;
+        sql.pragma_update(None, "user_version", applied + 1)?;
+        sql.commit()?;
+    }
+    Ok(())
+}
+
+impl StateStore for SqliteStore {
+    type Error = SqliteError;
+
+    fn save(&mut self, engine: &Engine) -> Result<(), SqliteError> {
+        let mut snapshot = engine.snapshot(0)?;
+        let accounts = std::mem::take(&mut snapshot.accounts);
+        let mut bookkeeping = Vec::new();
+        checkpoint::write(&snapshot, &mut bookkeeping).map_err(CheckpointError::Io)?;
+
+        let sql = self.conn.transaction()?;
+        sql.execute_batch(
+            "DELETE FROM accounts; DELETE FROM transactions; DELETE FROM engine_state;",
+        )?;
+        {
+            let mut insert_account = sql.prepare(
+                "INSERT INTO accounts \
+                 (client, available, held, reserved, locked, closed, on_hold) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            let mut insert_transaction = sql.prepare(
+                "INSERT INTO transactions \
+                 (tx, client, kind, amount, dispute_state, timestamp, to_client) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for archived in &accounts {
+                let account = &archived.account;
+                insert_account.execute(params![
+                    integer("client", archived.client)?,
+                    account.available.units(),
+                    account.held.units(),
+                    account.reserved.units(),
+                    account.locked,
+                    account.closed,
+                    account.on_hold.units(),
+                ])?;
+                for (tx, stored) in &archived.transactions {
+                    let (kind, to_client) = match stored.kind {
+                        StoredKind::Deposit => ("deposit", None),
+                        StoredKind::Withdrawal => ("withdrawal", None),
+                        StoredKind::Transfer { to_client } => {
+                            ("transfer", Some(integer("client", to_client)?))
+                        }
+                    };
+                    let dispute_state = match stored.dispute_state {
+                        DisputeState::None => "none",
+                        DisputeState::Disputed => "disputed",
+                        DisputeState::ChargedBack => "chargedback",
+                    };
+                    insert_transaction.execute(params![
+                        integer("transaction", *tx)?,
+                        integer("client", stored.client)?,
+                        kind,
+                        stored.amount.units(),
+                        dispute_state,
+                        // SQLite integers are signed; the bits round-trip unchanged.
+                        stored.timestamp.map(|timestamp| timestamp as i64),
+                        to_client,
+                    ])?;
+                }
+            }
+            let mut insert_state =
+                sql.prepare("INSERT INTO engine_state (key, value) VALUES (?1, ?2)")?;
+            insert_state.execute(params![
+                "scale",
+                engine.config().precision.scale.to_string()
+            ])?;
+            insert_state.execute(params![
+                "bookkeeping",
+                String::from_utf8(bookkeeping).expect("snapshots are ASCII")
+            ])?;
+        }
+        sql.commit()?;
+        Ok(())
+    }
+
+    fn load(&self, engine: &mut Engine) -> Result<bool, SqliteError> {
+        let Some(scale) = self.state("scale")? else {
+            return Ok(false);
+        };
+        let saved = scale
+            .parse()
+            .map_err(|_| SqliteError::Corrupt(format!("bad scale '{}'", scale)))?;
+        let scale = engine.config().precision.scale;
+        if saved != scale {
+            return Err(SqliteError::ScaleMismatch {
+                saved,
+                engine: scale,
+            });
+        }
+        let bookkeeping = self
+            .state("bookkeeping")?
+            .ok_or_else(|| SqliteError::Corrupt("missing bookkeeping".to_string()))?;
+        let mut snapshot = checkpoint::read(bookkeeping.as_bytes())?;
+
+        let mut owned: HashMap<ClientId, Vec<(TxId, StoredTransaction)>> = HashMap::new();
+        let mut select = self.conn.prepare(
+            "SELECT tx, client, kind, amount, dispute_state, timestamp, to_client \
+             FROM transactions ORDER BY tx",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let client = id(row.get(1)?)?;
+            let kind = match (row.get::<_, String>(2)?.as_str(), row.get(6)?) {
+                ("deposit", None) => StoredKind::Deposit,
+                ("withdrawal", None) => StoredKind::Withdrawal,
+                ("transfer", Some(to_client)) => StoredKind::Transfer {
+                    to_client: id(to_client)?,
+                },
+                (kind, _) => return Err(SqliteError::Corrupt(format!("bad kind '{}'", kind))),
+            };
+            let dispute_state = match row.get::<_, String>(4)?.as_str() {
+                "none" => DisputeState::None,
+                "disputed" => DisputeState::Disputed,
+                "chargedback" => DisputeState::ChargedBack,
+                state => {
+                    return Err(SqliteError::Corrupt(format!(
+                        "bad dispute state '{}'",
+                        state
+                    )));
+                }
+            };
+            let stored = StoredTransaction {
+                client,
+                amount: Money::from_units(row.get(3)?),
+                dispute_state,
+                kind,
+                timestamp: row
+                    .get::<_, Option<i64>>(5)?
+                    .map(|timestamp| timestamp as u64),
+            };
+            owned
+                .entry(client)
+                .or_default()
+                .push((id(row.get(0)?)?, stored));
+        }
+
+        let mut select = self.conn.prepare(
+            "SELECT client, available, held, reserved, locked, closed, on_hold \
+             FROM accounts ORDER BY client",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let client = id(row.get(0)?)?;
+            snapshot.accounts.push(ArchivedAccount {
+                client,
+                account: Account {
+                    available: Money::from_units(row.get(1)?),
+                    held: Money::from_units(row.get(2)?),
+                    reserved: Money::from_units(row.get(3)?),
+                    on_hold: Money::from_units(row.get(6)?),
+                    locked: row.get(4)?,
+                    closed: row.get(5)?,
+                },
+                transactions: owned.remove(&client).unwrap_or_default(),
+            });
+        }
+        if let Some(client) = owned.keys().next() {
+            return Err(SqliteError::Corrupt(format!(
+                "transactions of client {} without an account",
+                client
+            )));
+        }
+        engine.restore(snapshot)?;
+        Ok(true)
+    }
+}
+
+/// The id `id` as an SQLite integer.
+fn integer(what: &'static str, id: impl Into<u128>) -> Result<i64, SqliteError> {
+    let id = id.into();
+    i64::try_from(id).map_err(|_| SqliteError::IdTooLarge { what, id })
+}
+
+/// The id saved as `value`.
+fn id<T: TryFrom<i64>>(value: i64) -> Result<T, SqliteError> {
+    T::try_from(value).map_err(|_| SqliteError::Corrupt(format!("bad id {}", value)))
+}
+
+impl SqliteStore {
+    /// The `engine_state` value under `key`.
+    fn state(&self, key: &str) -> Result<Option<String>, SqliteError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT value FROM engine_state WHERE key = ?1",
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::types::{Transaction, TransactionType};
+
+    fn tx(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: amount.map(|amount| amount.parse().unwrap()),
+            timestamp: Some(tx),
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let mut engine = Engine::new();
+        engine
+            .process(tx(TransactionType::Deposit, 1, 1, Some("10")))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Deposit, 2, 2, Some("5.25")))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Withdrawal, 1, 3, Some("2.5")))
+            .unwrap();
+        engine
+            .process(tx(TransactionType::Dispute, 2, 2, None))
+            .unwrap();
+
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let mut empty = Engine::new();
+        assert!(!store.load(&mut empty).unwrap());
+        store.save(&engine).unwrap();
+        store.save(&engine).unwrap();
+
+        let held: i64 = store
+            .connection()
+            .query_row("SELECT held FROM accounts WHERE client = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(held, 52_500);
+
+        let mut restored = Engine::new();
+        assert!(store.load(&mut restored).unwrap());
+        let accounts = |engine: &Engine| {
+            let mut accounts: Vec<_> = engine.accounts().map(|(c, a)| (c, *a)).collect();
+            accounts.sort_by_key(|&(client, _)| client);
+            accounts
+        };
+        assert_eq!(accounts(&restored), accounts(&engine));
+        restored
+            .process(tx(TransactionType::Resolve, 2, 2, None))
+            .unwrap();
+        assert_eq!(
+            restored.account(2).unwrap().available,
+            Money::from_units(52_500)
+        );
+
+        let mut rescaled = Engine::with_config(EngineConfig::default().with_scale(2));
+        assert!(matches!(
+            store.load(&mut rescaled),
+            Err(SqliteError::ScaleMismatch {
+                saved: 4,
+                engine: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_ids_beyond_sqlite_integers_are_refused() {
+        let mut engine = Engine::new();
+        engine
+            .process(tx(TransactionType::Deposit, 1 << 63, 1, Some("1")))
+            .unwrap();
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        assert!(matches!(
+            store.save(&engine),
+            Err(SqliteError::IdTooLarge {
+                what: "client",
+                id
+            }) if id == 1 << 63
+        ));
+        assert!(!store.load(&mut Engine::new()).unwrap());
+    }
+
+    #[test]
+    fn test_newer_schema_is_refused() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(
+            SqliteStore::new(conn),
+            Err(SqliteError::NewerSchema(_))
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! Running totals of what an engine has done, from [`Engine::stats`](crate::Engine::stats).
+
+use std::fmt;
+
+use crate::ignored::IgnoredCounts;
+use crate::outcome::{ProcessError, ProcessOutcome};
+use crate::types::TransactionType;
+
+/// Transactions of one type, by result.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TypeCounts {
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+impl TypeCounts {
+    fn merge(&mut self, other: &TypeCounts) {
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+    }
+}
+
+/// Totals since the engine was created. A rolled-back batch is taken out of them again,
+/// like everything else it did, and only counted in `batches_rolled_back`. So are undone
+/// transactions, and anything counted after them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Transactions passed to `process`, accepted or not.
+    pub processed: u64,
+    pub deposits: TypeCounts,
+    pub withdrawals: TypeCounts,
+    pub transfers: TypeCounts,
+    /// Accepted disputes are the disputes opened.
+    pub disputes: TypeCounts,
+    pub resolves: TypeCounts,
+    pub chargebacks: TypeCounts,
+    pub chargeback_reversals: TypeCounts,
+    /// Accepted holds are the holds placed, released in full or not.
+    pub holds: TypeCounts,
+    pub releases: TypeCounts,
+    /// Rejected transactions by reason.
+    pub rejected: IgnoredCounts,
+    /// Times an unlocked account was locked by a chargeback.
+    pub accounts_locked: u64,
+    /// Times an unlocked account was frozen after an overflow.
+    pub accounts_frozen: u64,
+    /// Accounts unlocked automatically, after their cooldown or a chargeback reversal.
+    pub accounts_unlocked: u64,
+    /// Disputes resolved automatically because they expired.
+    pub disputes_expired: u64,
+    /// Post-dated transactions queued until their value date. Each is counted again by
+    /// type when it settles.
+    pub scheduled: u64,
+    pub batches_rolled_back: u64,
+}
+
+impl EngineStats {
+    pub fn counts(&self, tx_type: TransactionType) -> &TypeCounts {
+        match tx_type {
+            TransactionType::Deposit => &self.deposits,
+            TransactionType::Withdrawal => &self.withdrawals,
+            TransactionType::Transfer => &self.transfers,
+            TransactionType::Dispute => &self.disputes,
+            TransactionType::Resolve => &self.resolves,
+            TransactionType::Chargeback => &self.chargebacks,
+            TransactionType::ChargebackReversal => &self.chargeback_reversals,
+            TransactionType::Hold => &self.holds,
+            TransactionType::Release => &self.releases,
+        }
+    }
+
+    fn counts_mut(&mut self, tx_type: TransactionType) -> &mut TypeCounts {
+        match tx_type {
+            TransactionType::Deposit => &mut self.deposits,
+            TransactionType::Withdrawal => &mut self.withdrawals,
+            TransactionType::Transfer => &mut self.transfers,
+            TransactionType::Dispute => &mut self.disputes,
+            TransactionType::Resolve => &mut self.resolves,
+            TransactionType::Chargeback => &mut self.chargebacks,
+            TransactionType::ChargebackReversal => &mut self.chargeback_reversals,
+            TransactionType::Hold => &mut self.holds,
+            TransactionType::Release => &mut self.releases,
+        }
+    }
+
+    /// Counts one processed transaction of type `tx_type`.
+    pub fn record(
+        &mut self,
+        tx_type: TransactionType,
+        result: &Result<ProcessOutcome, ProcessError>,
+    ) {
+        self.processed += 1;
+        let counts = self.counts_mut(tx_type);
+        match result {
+            Ok(_) => counts.accepted += 1,
+            Err(reason) => {
+                counts.rejected += 1;
+                self.rejected.record(*reason);
+            }
+        }
+    }
+
+    /// Adds every total in `other` to these.
+    pub fn merge(&mut self, other: &EngineStats) {
+        self.processed += other.processed;
+        for tx_type in TYPES {
+            self.counts_mut(tx_type).merge(other.counts(tx_type));
+        }
+        self.rejected.merge(&other.rejected);
+        self.accounts_locked += other.accounts_locked;
+        self.accounts_frozen += other.accounts_frozen;
+        self.accounts_unlocked += other.accounts_unlocked;
+        self.disputes_expired += other.disputes_expired;
+        self.scheduled += other.scheduled;
+        self.batches_rolled_back += other.batches_rolled_back;
+    }
+}
+
+pub(crate) const TYPES: [TransactionType; 9] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Transfer,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::ChargebackReversal,
+    TransactionType::Hold,
+    TransactionType::Release,
+];
+
+/// One total per line, then the rejections by reason.
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "processed: {}", self.processed)?;
+        for tx_type in TYPES {
+            let counts = self.counts(tx_type);
+            writeln!(
+                f,
+                "{}: {} accepted, {} rejected",
+                tx_type, counts.accepted, counts.rejected
+            )?;
+        }
+        writeln!(f, "accounts locked: {}", self.accounts_locked)?;
+        writeln!(f, "accounts frozen: {}", self.accounts_frozen)?;
+        writeln!(f, "accounts unlocked: {}", self.accounts_unlocked)?;
+        writeln!(f, "disputes expired: {}", self.disputes_expired)?;
+        writeln!(f, "transactions scheduled: {}", self.scheduled)?;
+        write!(f, "batches rolled back: {}", self.batches_rolled_back)?;
+        for (reason, count) in self.rejected.iter() {
+            write!(f, "\nrejected {}: {}", reason.code(), count)?;
+        }
+        Ok(())
+    }
+}
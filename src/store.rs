@@ -0,0 +1,402 @@
+//! Where the engine keeps stored transactions.
+//!
+//! Every accepted deposit, withdrawal and transfer is kept for later disputes, so the
+//! transaction table grows with the input. [`MemoryStore`], the default, keeps it in a hash
+//! map. [`FileStore`] keeps it in a file instead, so the engine's memory stays bounded
+//! however many transactions it has seen; install it with
+//! [`Engine::set_store`](crate::Engine::set_store).
+//!
+//! A [`StateStore`] is different: it saves the whole state of an engine, accounts and
+//! stored transactions together, so it can be loaded into a new engine after a restart.
+//! `SqliteStore`, from the `sqlite` feature, is one.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+use crate::engine::Engine;
+use crate::types::{ClientId, DisputeState, Money, StoredKind, StoredTransaction, TxId};
+
+pub trait TxStore: Send {
+    fn get(&self, tx: TxId) -> io::Result<Option<StoredTransaction>>;
+
+    /// Stores `stored` under `tx`, replacing what was there.
+    fn insert(&mut self, tx: TxId, stored: StoredTransaction) -> io::Result<()>;
+
+    fn remove(&mut self, tx: TxId) -> io::Result<Option<StoredTransaction>>;
+
+    /// Every stored transaction, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<(TxId, StoredTransaction)>> + '_>;
+
+    /// Approximate bytes of memory the store holds.
+    fn memory_usage(&self) -> usize;
+}
+
+/// Durable storage for an engine's whole state.
+pub trait StateStore {
+    type Error;
+
+    /// Replaces the saved state with the state of `engine`.
+    fn save(&mut self, engine: &Engine) -> Result<(), Self::Error>;
+
+    /// Loads the saved state into `engine`, which must be new and configured as the one
+    /// that saved it. Returns `false`, leaving `engine` alone, if nothing was saved yet.
+    fn load(&self, engine: &mut Engine) -> Result<bool, Self::Error>;
+}
+
+/// Stored transactions in a hash map.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    transactions: HashMap<TxId, StoredTransaction>,
+}
+
+impl TxStore for MemoryStore {
+    fn get(&self, tx: TxId) -> io::Result<Option<StoredTransaction>> {
+        Ok(self.transactions.get(&tx).copied())
+    }
+
+    fn insert(&mut self, tx: TxId, stored: StoredTransaction) -> io::Result<()> {
+        self.transactions.insert(tx, stored);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: TxId) -> io::Result<Option<StoredTransaction>> {
+        Ok(self.transactions.remove(&tx))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<(TxId, StoredTransaction)>> + '_> {
+        Box::new(
+            self.transactions
+                .iter()
+                .map(|(&tx, &stored)| Ok((tx, stored))),
+        )
+    }
+
+    fn memory_usage(&self) -> usize {
+        // One `(K, V)` slot plus one control byte per bucket.
+        self.transactions.capacity() * (mem::size_of::<(TxId, StoredTransaction)>() + 1)
+    }
+}
+
+/// Bytes per record in a [`FileStore`].
+const RECORD: usize = 56;
+
+/// Ids below this have their record at a fixed offset in a [`FileStore`]; larger ones in
+/// the overflow region after them.
+const DIRECT_IDS: TxId = 1 << 32;
+
+/// Offset of a [`FileStore`]'s overflow region.
+const OVERFLOW_START: u64 = DIRECT_IDS * RECORD as u64;
+
+/// Records read at a time when iterating.
+const RECORDS_PER_READ: usize = 4096;
+
+/// Stored transactions in a file, one fixed-size record at offset `tx * 56` for ids below
+/// 2^32. Nothing else is held in memory; every access reads or writes the file and the
+/// operating system's cache does the caching. Ids leave holes in the file where nothing is
+/// stored, which most file systems keep sparse. Larger ids get the next free record of an
+/// overflow region after those, found through an index in memory. Iterating reads the
+/// whole file, up to the highest id stored, and then the overflow region.
+pub struct FileStore {
+    file: File,
+    /// End of the records of ids below [`DIRECT_IDS`].
+    len: u64,
+    /// Overflow record of each larger id ever stored.
+    overflow: HashMap<TxId, u64>,
+    /// The id of each overflow record, in record order.
+    overflow_ids: Vec<TxId>,
+}
+
+impl FileStore {
+    /// Creates (or truncates) the store file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            len: 0,
+            overflow: HashMap::new(),
+            overflow_ids: Vec::new(),
+        })
+    }
+
+    /// Offset of the record of `tx`, if it has one.
+    fn offset(&self, tx: TxId) -> Option<u64> {
+        if tx < DIRECT_IDS {
+            let offset = tx * RECORD as u64;
+            return (offset < self.len).then_some(offset);
+        }
+        let slot = *self.overflow.get(&tx)?;
+        Some(OVERFLOW_START + slot * RECORD as u64)
+    }
+
+    /// Offset of the record of `tx`, giving it one in the overflow region if it needs it.
+    fn offset_for_insert(&mut self, tx: TxId) -> u64 {
+        if tx < DIRECT_IDS {
+            let offset = tx * RECORD as u64;
+            self.len = self.len.max(offset + RECORD as u64);
+            return offset;
+        }
+        let slots = self.overflow_ids.len() as u64;
+        let slot = *self.overflow.entry(tx).or_insert(slots);
+        if slot == slots {
+            self.overflow_ids.push(tx);
+        }
+        OVERFLOW_START + slot * RECORD as u64
+    }
+
+    fn write_at(&mut self, offset: u64, record: &[u8; RECORD]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(record)
+    }
+}
+
+impl TxStore for FileStore {
+    fn get(&self, tx: TxId) -> io::Result<Option<StoredTransaction>> {
+        let Some(offset) = self.offset(tx) else {
+            return Ok(None);
+        };
+        let mut file = &self.file;
+        let mut record = [0; RECORD];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut record)?;
+        decode(&record)
+    }
+
+    fn insert(&mut self, tx: TxId, stored: StoredTransaction) -> io::Result<()> {
+        let offset = self.offset_for_insert(tx);
+        self.write_at(offset, &encode(&stored))
+    }
+
+    fn remove(&mut self, tx: TxId) -> io::Result<Option<StoredTransaction>> {
+        let removed = self.get(tx)?;
+        if let (Some(_), Some(offset)) = (removed, self.offset(tx)) {
+            self.write_at(offset, &[0; RECORD])?;
+        }
+        Ok(removed)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<(TxId, StoredTransaction)>> + '_> {
+        Box::new(FileStoreIter {
+            file: &self.file,
+            overflow: &self.overflow_ids,
+            end: self.len,
+            offset: 0,
+            buffer: Vec::new(),
+            start: 0,
+            position: 0,
+            failed: false,
+        })
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.overflow.capacity() * (mem::size_of::<(TxId, u64)>() + 1)
+            + self.overflow_ids.capacity() * mem::size_of::<TxId>()
+    }
+}
+
+/// Reads a [`FileStore`] in chunks, seeking before each one so lookups in between don't
+/// disturb it.
+struct FileStoreIter<'a> {
+    file: &'a File,
+    /// The ids of the overflow records, read once the others are done.
+    overflow: &'a [TxId],
+    /// End of the region being read.
+    end: u64,
+    /// Offset of the next chunk.
+    offset: u64,
+    /// Records read from `start`.
+    buffer: Vec<u8>,
+    start: u64,
+    /// Next record within `buffer`.
+    position: usize,
+    failed: bool,
+}
+
+impl Iterator for FileStoreIter<'_> {
+    type Item = io::Result<(TxId, StoredTransaction)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            if self.position == self.buffer.len() {
+                if self.offset >= self.end {
+                    if self.offset >= OVERFLOW_START || self.overflow.is_empty() {
+                        return None;
+                    }
+                    self.offset = OVERFLOW_START;
+                    self.end = OVERFLOW_START + (self.overflow.len() * RECORD) as u64;
+                }
+                let size = (self.end - self.offset).min((RECORD * RECORDS_PER_READ) as u64);
+                self.buffer.resize(size as usize, 0);
+                self.start = self.offset;
+                self.position = 0;
+                let read = self
+                    .file
+                    .seek(SeekFrom::Start(self.offset))
+                    .and_then(|_| self.file.read_exact(&mut self.buffer));
+                if let Err(error) = read {
+                    self.failed = true;
+                    return Some(Err(error));
+                }
+                self.offset += size;
+            }
+            let at = self.position;
+            self.position += RECORD;
+            let record: &[u8; RECORD] = self.buffer[at..self.position]
+                .try_into()
+                .expect("chunks hold whole records");
+            let offset = self.start + at as u64;
+            let tx = match offset.checked_sub(OVERFLOW_START) {
+                Some(overflow) => self.overflow[(overflow / RECORD as u64) as usize],
+                None => offset / RECORD as u64,
+            };
+            match decode(record) {
+                Ok(Some(stored)) => return Some(Ok((tx, stored))),
+                Ok(None) => {}
+                Err(error) => {
+                    self.failed = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Layout: present flag, kind, dispute state, timestamp flag, 4 unused bytes, client (u128),
+/// transfer recipient (u128), amount (i64), timestamp (u64), all little-endian.
+fn encode(stored: &StoredTransaction) -> [u8; RECORD] {
+    let mut record = [0; RECORD];
+    let (kind, to_client) = match stored.kind {
+        StoredKind::Deposit => (0, 0),
+        StoredKind::Withdrawal => (1, 0),
+        StoredKind::Transfer { to_client } => (2, to_client),
+    };
+    record[0] = 1;
+    record[1] = kind;
+    record[2] = match stored.dispute_state {
+        DisputeState::None => 0,
+        DisputeState::Disputed => 1,
+        DisputeState::ChargedBack => 2,
+    };
+    record[3] = u8::from(stored.timestamp.is_some());
+    record[8..24].copy_from_slice(&stored.client.to_le_bytes());
+    record[24..40].copy_from_slice(&to_client.to_le_bytes());
+    record[40..48].copy_from_slice(&stored.amount.units().to_le_bytes());
+    record[48..56].copy_from_slice(&stored.timestamp.unwrap_or_default().to_le_bytes());
+    record
+}
+
+fn decode(record: &[u8; RECORD]) -> io::Result<Option<StoredTransaction>> {
+    if record[0] == 0 {
+        return Ok(None);
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt transaction record");
+    let client_at =
+        |at: usize| ClientId::from_le_bytes(record[at..at + 16].try_into().expect("16 bytes"));
+    let kind = match record[1] {
+        0 => StoredKind::Deposit,
+        1 => StoredKind::Withdrawal,
+        2 => StoredKind::Transfer {
+            to_client: client_at(24),
+        },
+        _ => return Err(invalid()),
+    };
+    let dispute_state = match record[2] {
+        0 => DisputeState::None,
+        1 => DisputeState::Disputed,
+        2 => DisputeState::ChargedBack,
+        _ => return Err(invalid()),
+    };
+    let amount = Money::from_units(i64::from_le_bytes(
+        record[40..48].try_into().expect("8 bytes"),
+    ));
+    let timestamp = match record[3] {
+        0 => None,
+        1 => Some(u64::from_le_bytes(
+            record[48..56].try_into().expect("8 bytes"),
+        )),
+        _ => return Err(invalid()),
+    };
+    Ok(Some(StoredTransaction {
+        client: client_at(8),
+        amount,
+        dispute_state,
+        kind,
+        timestamp,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn stored(client: ClientId, amount: i64, kind: StoredKind) -> StoredTransaction {
+        StoredTransaction {
+            client,
+            amount: Money::from_units(amount),
+            dispute_state: DisputeState::None,
+            kind,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_file_store_round_trips_and_iterates() {
+        let path = env::temp_dir().join(format!("tx-engine-store-{}", process::id()));
+        let mut store = FileStore::create(&path).unwrap();
+        let mut transfer = stored(2, -5, StoredKind::Transfer { to_client: 9 });
+        transfer.timestamp = Some(1_700_000_000);
+        store
+            .insert(7, stored(1, 100, StoredKind::Deposit))
+            .unwrap();
+        store.insert(5_000, transfer).unwrap();
+        store
+            .insert(3, stored(1, 1, StoredKind::Withdrawal))
+            .unwrap();
+        let mut disputed = stored(1, 100, StoredKind::Deposit);
+        disputed.dispute_state = DisputeState::Disputed;
+        store.insert(7, disputed).unwrap();
+        let wide = stored(u128::MAX, 3, StoredKind::Transfer { to_client: 1 << 70 });
+        store.insert(u64::MAX, wide).unwrap();
+        store
+            .insert(1 << 40, stored(4, 4, StoredKind::Deposit))
+            .unwrap();
+
+        assert_eq!(store.get(7).unwrap(), Some(disputed));
+        assert_eq!(store.get(5_000).unwrap(), Some(transfer));
+        assert_eq!(store.get(8).unwrap(), None);
+        assert_eq!(store.get(1_000_000).unwrap(), None);
+        assert_eq!(
+            store.remove(3).unwrap(),
+            Some(stored(1, 1, StoredKind::Withdrawal))
+        );
+        assert_eq!(store.get(3).unwrap(), None);
+        assert_eq!(store.get(u64::MAX).unwrap(), Some(wide));
+        assert_eq!(
+            store.remove(1 << 40).unwrap(),
+            Some(stored(4, 4, StoredKind::Deposit))
+        );
+        assert_eq!(store.get(1 << 40).unwrap(), None);
+        assert_eq!(store.get(1 << 41).unwrap(), None);
+
+        // A lookup in the middle of iterating doesn't disturb it.
+        let mut iter = store.iter();
+        assert_eq!(iter.next().unwrap().unwrap(), (7, disputed));
+        assert_eq!(store.get(7).unwrap(), Some(disputed));
+        assert_eq!(iter.next().unwrap().unwrap(), (5_000, transfer));
+        assert_eq!(iter.next().unwrap().unwrap(), (u64::MAX, wide));
+        assert!(iter.next().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
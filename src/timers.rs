@@ -0,0 +1,172 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::types::{ClientId, Money, TransactionType, TxId};
+
+/// Deferred engine actions, keyed by the timestamp at which they become due.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Timer {
+    /// Moves `amount` withheld from deposit `tx` out of reserve.
+    ReleaseReserve {
+        client: ClientId,
+        tx: TxId,
+        amount: Money,
+    },
+    /// Unlocks `client` if its most recent chargeback is still the one at `locked_at`.
+    Unlock { client: ClientId, locked_at: u64 },
+    /// Expires the dispute on deposit `tx` if it is still the one opened at `opened_at`.
+    ExpireDispute {
+        client: ClientId,
+        tx: TxId,
+        opened_at: u64,
+    },
+    /// Applies post-dated transaction `tx`, of fixed-point `amount`, at its value date.
+    Settle {
+        client: ClientId,
+        tx: TxId,
+        tx_type: TransactionType,
+        amount: Money,
+        to_client: Option<ClientId>,
+    },
+}
+
+impl Timer {
+    /// The client the timer acts for.
+    pub fn client(&self) -> ClientId {
+        match *self {
+            Timer::ReleaseReserve { client, .. }
+            | Timer::Unlock { client, .. }
+            | Timer::ExpireDispute { client, .. }
+            | Timer::Settle { client, .. } => client,
+        }
+    }
+
+    /// The deposit and amount a reserve release moves out of reserve.
+    fn release(&self) -> Option<(TxId, Money)> {
+        match *self {
+            Timer::ReleaseReserve { tx, amount, .. } => Some((tx, amount)),
+            _ => None,
+        }
+    }
+}
+
+/// Min-heap of timers. Timers due at the same instant fire in scheduling order.
+#[derive(Debug, Default)]
+pub struct TimerQueue {
+    heap: BinaryHeap<Reverse<(u64, u64, Timer)>>,
+    next_seq: u64,
+    /// Amounts of the pending reserve releases, by deposit.
+    reserves: HashMap<TxId, Money>,
+}
+
+impl TimerQueue {
+    pub fn schedule(&mut self, due: u64, timer: Timer) {
+        if let Some((tx, amount)) = timer.release() {
+            let reserve = self.reserves.entry(tx).or_insert(Money::ZERO);
+            *reserve = reserve.saturating_add(amount);
+        }
+        self.heap.push(Reverse((due, self.next_seq, timer)));
+        self.next_seq += 1;
+    }
+
+    /// Position in scheduling order; timers scheduled from now on are at or after it.
+    pub fn mark(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Drops every pending timer scheduled at or after `mark`.
+    pub fn discard_from(&mut self, mark: u64) {
+        let reserves = &mut self.reserves;
+        self.heap.retain(|Reverse((_, seq, timer))| {
+            if *seq < mark {
+                return true;
+            }
+            forget_release(reserves, timer);
+            false
+        });
+    }
+
+    /// Points every pending timer of client `from` at client `into`.
+    pub fn rehome(&mut self, from: ClientId, into: ClientId) {
+        let heap = std::mem::take(&mut self.heap);
+        self.heap = heap
+            .into_iter()
+            .map(|Reverse((due, seq, mut timer))| {
+                let (Timer::ReleaseReserve { client, .. }
+                | Timer::Unlock { client, .. }
+                | Timer::ExpireDispute { client, .. }
+                | Timer::Settle { client, .. }) = &mut timer;
+                if *client == from {
+                    *client = into;
+                }
+                if let Timer::Settle {
+                    to_client: Some(to_client),
+                    ..
+                } = &mut timer
+                    && *to_client == from
+                {
+                    *to_client = into;
+                }
+                Reverse((due, seq, timer))
+            })
+            .collect();
+    }
+
+    /// Moves every pending timer of `other` into this queue, after the timers already here
+    /// that are due at the same instant.
+    pub fn append(&mut self, other: TimerQueue) {
+        for Reverse((due, _, timer)) in other.heap.into_sorted_vec().into_iter().rev() {
+            self.schedule(due, timer);
+        }
+    }
+
+    /// Every pending timer with its due time, in the order they would fire.
+    pub fn pending(&self) -> Vec<(u64, Timer)> {
+        let mut pending: Vec<_> = self
+            .heap
+            .iter()
+            .map(|Reverse(entry)| entry.clone())
+            .collect();
+        pending.sort();
+        pending
+            .into_iter()
+            .map(|(due, _, timer)| (due, timer))
+            .collect()
+    }
+
+    /// The amount withheld from deposit `tx` that is still waiting to be released.
+    pub fn reserve_of(&self, tx: TxId) -> Money {
+        self.reserves.get(&tx).copied().unwrap_or(Money::ZERO)
+    }
+
+    /// Approximate bytes allocated for pending timers.
+    pub fn memory_usage(&self) -> usize {
+        self.heap.capacity() * std::mem::size_of::<Reverse<(u64, u64, Timer)>>()
+            + self.reserves.capacity() * std::mem::size_of::<(TxId, Money)>()
+    }
+
+    /// Removes and returns the earliest timer due at or before `now`.
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, Timer)> {
+        match self.heap.peek() {
+            Some(Reverse((due, _, _))) if *due <= now => {
+                let Reverse((due, _, timer)) = self.heap.pop()?;
+                forget_release(&mut self.reserves, &timer);
+                Some((due, timer))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Takes a reserve release that left the queue off the pending reserves.
+fn forget_release(reserves: &mut HashMap<TxId, Money>, timer: &Timer) {
+    let Some((tx, amount)) = timer.release() else {
+        return;
+    };
+    if let Some(reserve) = reserves.get_mut(&tx) {
+        *reserve = reserve.saturating_sub(amount);
+        if !reserve.is_positive() {
+            reserves.remove(&tx);
+        }
+    }
+}
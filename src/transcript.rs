@@ -0,0 +1,458 @@
+//! Canonical transcripts of a run, for reproducing it bit for bit (feature `io`).
+//!
+//! A [`TranscriptWriter`] records every input item the engine processed, in order and with
+//! its result, and closes the transcript with the engine's
+//! [`state_digest`](crate::Engine::state_digest). Rejected rows are recorded too, since
+//! they still move the engine clock; rows skipped because their batch was rolled back are
+//! not. The transcript is CSV:
+//!
+//! ```text
+//! type,client,tx,amount,timestamp,to_client,value_date,reason,result
+//! deposit,1,1,10.5,,,,,ok
+//! withdrawal,1,2,20,,,,,insufficient_funds
+//! lock,1,,,,,,fraud review,ok
+//! digest,,,,,,,,9ae2c3e0e1f4c3d6b0c1b8fa5a0e7d21
+//! ```
+//!
+//! `result` is `ok` or the rejection's [code](crate::ProcessError::code), and is empty for
+//! batch markers, accruals and clock advances. [`replay`] feeds a transcript to an engine
+//! configured as the original was and checks every result and then the digest, so a
+//! partner's bug report can be reproduced and verified exactly. Transaction metadata is not
+//! recorded, as it doesn't affect balances.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::admin::AdminOp;
+use crate::engine::Engine;
+use crate::io::Input;
+use crate::outcome::ProcessError;
+use crate::types::{ClientId, DEFAULT_SCALE, Money, Transaction, TransactionType, TxId};
+
+const HEADER: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "to_client",
+    "value_date",
+    "reason",
+    "result",
+];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Row {
+    #[serde(rename = "type")]
+    kind: String,
+    client: Option<ClientId>,
+    tx: Option<TxId>,
+    amount: Option<Decimal>,
+    timestamp: Option<u64>,
+    to_client: Option<ClientId>,
+    value_date: Option<u64>,
+    reason: Option<String>,
+    result: Option<String>,
+}
+
+/// Writes a transcript; see the [module documentation](self).
+pub struct TranscriptWriter<W: Write> {
+    writer: csv::Writer<W>,
+    scale: u32,
+}
+
+impl<W: Write> TranscriptWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+            scale: DEFAULT_SCALE,
+        }
+    }
+
+    /// Adjustment amounts and overdraft limits have `scale` decimal places, as in an engine
+    /// configured with `EngineConfig::with_scale`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Records `input`, which the engine processed with `result`.
+    pub fn record(&mut self, input: &Input, result: Result<(), ProcessError>) -> io::Result<()> {
+        let result = Some(match result {
+            Ok(()) => "ok".to_string(),
+            Err(error) => error.code().to_string(),
+        });
+        let row = match input {
+            Input::Transaction(tx) => Row {
+                result,
+                ..transaction_row(tx)
+            },
+            Input::Scheduled { tx, value_date } => Row {
+                value_date: Some(*value_date),
+                result,
+                ..transaction_row(tx)
+            },
+            Input::Admin(op) => {
+                let (kind, amount) = match op {
+                    AdminOp::Lock { .. } => ("lock", None),
+                    AdminOp::Unlock { .. } => ("unlock", None),
+                    AdminOp::Adjust { amount, .. } => ("adjust", Some(*amount)),
+                    AdminOp::Close { .. } => ("close", None),
+                    AdminOp::Overdraft { limit, .. } => ("overdraft", Some(*limit)),
+                };
+                Row {
+                    kind: kind.to_string(),
+                    client: Some(op.client()),
+                    amount: amount.map(|amount| amount.to_decimal(self.scale)),
+                    reason: Some(op.reason().to_string()),
+                    result,
+                    ..Row::default()
+                }
+            }
+            Input::BeginBatch => marker("begin", None),
+            Input::CommitBatch => marker("commit", None),
+            Input::Accrue { timestamp } => marker("accrue", Some(*timestamp)),
+            Input::SettleUntil { timestamp } => marker("settle_until", Some(*timestamp)),
+        };
+        self.writer.serialize(row)?;
+        Ok(())
+    }
+
+    /// Closes the transcript with `engine`'s state digest and flushes it.
+    pub fn finish(&mut self, engine: &Engine) -> io::Result<()> {
+        self.writer.serialize(Row {
+            result: Some(format!("{:032x}", engine.state_digest())),
+            ..marker("digest", None)
+        })?;
+        self.writer.flush()
+    }
+}
+
+fn transaction_row(tx: &Transaction) -> Row {
+    Row {
+        kind: tx.tx_type.to_string(),
+        client: Some(tx.client),
+        tx: Some(tx.tx),
+        amount: tx.amount,
+        timestamp: tx.timestamp,
+        to_client: tx.to_client,
+        ..Row::default()
+    }
+}
+
+fn marker(kind: &str, timestamp: Option<u64>) -> Row {
+    Row {
+        kind: kind.to_string(),
+        timestamp,
+        ..Row::default()
+    }
+}
+
+#[derive(Debug)]
+pub enum TranscriptError {
+    Csv(csv::Error),
+    /// The input doesn't start with the transcript header.
+    NotATranscript,
+    /// The row at `line` can't be replayed.
+    Malformed {
+        line: u64,
+        message: String,
+    },
+    /// The row at `line` had a different result when replayed.
+    Diverged {
+        line: u64,
+        recorded: String,
+        replayed: String,
+    },
+    /// The replayed accounts have a different digest from the recorded one.
+    DigestMismatch {
+        recorded: u128,
+        replayed: u128,
+    },
+    /// The transcript ends without its digest, as when the run writing it failed.
+    MissingDigest,
+}
+
+impl fmt::Display for TranscriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranscriptError::Csv(error) => write!(f, "{}", error),
+            TranscriptError::NotATranscript => write!(f, "not a transcript"),
+            TranscriptError::Malformed { line, message } => {
+                write!(f, "line {}: {}", line, message)
+            }
+            TranscriptError::Diverged {
+                line,
+                recorded,
+                replayed,
+            } => write!(
+                f,
+                "line {}: recorded as {} but replayed as {}",
+                line, recorded, replayed
+            ),
+            TranscriptError::DigestMismatch { recorded, replayed } => write!(
+                f,
+                "state digest {:032x} differs from the recorded {:032x}",
+                replayed, recorded
+            ),
+            TranscriptError::MissingDigest => write!(f, "transcript ends without a digest"),
+        }
+    }
+}
+
+impl Error for TranscriptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TranscriptError::Csv(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for TranscriptError {
+    fn from(error: csv::Error) -> Self {
+        TranscriptError::Csv(error)
+    }
+}
+
+/// Replays the transcript in `reader` into `engine`, which should be new, or loaded from
+/// the same snapshot, and configured as the engine that wrote it. Fails at the first row
+/// whose result differs, or if the final accounts don't match the recorded digest, which
+/// it returns otherwise.
+pub fn replay<R: Read>(reader: R, engine: &mut Engine) -> Result<u128, TranscriptError> {
+    let mut reader = csv::Reader::from_reader(reader);
+    if !reader.headers()?.iter().eq(HEADER) {
+        return Err(TranscriptError::NotATranscript);
+    }
+    let scale = engine.config().precision.scale;
+    // Whether the open batch was rolled back.
+    let mut batch = None;
+    let mut digest = None;
+    for (index, row) in reader.deserialize::<Row>().enumerate() {
+        let line = index as u64 + 2;
+        let row = row?;
+        let malformed = |message: &str| TranscriptError::Malformed {
+            line,
+            message: message.to_string(),
+        };
+        if digest.is_some() {
+            return Err(malformed("row after the digest"));
+        }
+        let client = || row.client.ok_or_else(|| malformed("missing client"));
+        let timestamp = || row.timestamp.ok_or_else(|| malformed("missing timestamp"));
+        let amount = || {
+            row.amount
+                .map(|amount| Money::from_decimal_lossy(amount, scale))
+                .ok_or_else(|| malformed("missing amount"))
+        };
+        let reason = || row.reason.clone().unwrap_or_default();
+        let result = match row.kind.as_str() {
+            "digest" => {
+                if batch.is_some() {
+                    return Err(malformed("digest inside a batch"));
+                }
+                let recorded = row
+                    .result
+                    .as_deref()
+                    .and_then(|hex| u128::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| malformed("invalid digest"))?;
+                digest = Some(recorded);
+                continue;
+            }
+            "begin" if batch.is_none() => {
+                engine.begin_batch();
+                batch = Some(false);
+                continue;
+            }
+            "commit" => {
+                match batch.take() {
+                    Some(false) => engine.commit_batch(),
+                    Some(true) => {}
+                    None => return Err(malformed("batch commit without a begin")),
+                }
+                continue;
+            }
+            "accrue" => {
+                engine.accrue_interest(timestamp()?);
+                continue;
+            }
+            "settle_until" => {
+                engine.advance_time(timestamp()?);
+                continue;
+            }
+            "lock" => engine.apply_admin(AdminOp::Lock {
+                client: client()?,
+                reason: reason(),
+            }),
+            "unlock" => engine.apply_admin(AdminOp::Unlock {
+                client: client()?,
+                reason: reason(),
+            }),
+            "close" => engine.apply_admin(AdminOp::Close {
+                client: client()?,
+                reason: reason(),
+            }),
+            "adjust" => engine.apply_admin(AdminOp::Adjust {
+                client: client()?,
+                amount: amount()?,
+                reason: reason(),
+            }),
+            "overdraft" => engine.apply_admin(AdminOp::Overdraft {
+                client: client()?,
+                limit: amount()?,
+                reason: reason(),
+            }),
+            kind => {
+                let tx = Transaction {
+                    tx_type: TransactionType::from_str(kind)
+                        .map_err(|_| malformed(&format!("unknown type '{}'", kind)))?,
+                    client: client()?,
+                    tx: row.tx.ok_or_else(|| malformed("missing tx"))?,
+                    amount: row.amount,
+                    timestamp: row.timestamp,
+                    to_client: row.to_client,
+                    metadata: Default::default(),
+                };
+                match row.value_date {
+                    Some(value_date) => engine.schedule(tx, value_date),
+                    None => engine.process(tx).map(|_| ()),
+                }
+            }
+        };
+        let replayed = result.map_or_else(|error| error.code(), |()| "ok");
+        let recorded = row.result.as_deref().unwrap_or_default();
+        if replayed != recorded {
+            if batch.is_some() {
+                engine.rollback_batch();
+            }
+            return Err(TranscriptError::Diverged {
+                line,
+                recorded: recorded.to_string(),
+                replayed: replayed.to_string(),
+            });
+        }
+        if result.is_err() && batch == Some(false) {
+            engine.rollback_batch();
+            batch = Some(true);
+        }
+    }
+    let recorded = digest.ok_or(TranscriptError::MissingDigest)?;
+    let replayed = engine.state_digest();
+    if replayed != recorded {
+        return Err(TranscriptError::DigestMismatch { recorded, replayed });
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn transcript() -> (Vec<u8>, u128) {
+        let tx = |tx_type, client, tx, amount| Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        };
+        let inputs = [
+            Input::Transaction(tx(TransactionType::Deposit, 1, 1, Some(dec!(10.5)))),
+            Input::Transaction(tx(TransactionType::Withdrawal, 1, 2, Some(dec!(20)))),
+            Input::BeginBatch,
+            Input::Transaction(tx(TransactionType::Deposit, 2, 3, Some(dec!(1)))),
+            Input::Transaction(tx(TransactionType::Dispute, 2, 9, None)),
+            Input::CommitBatch,
+            Input::Admin(AdminOp::Adjust {
+                client: 1,
+                amount: Money::from_units(-5_000),
+                reason: "fee, refunded".to_string(),
+            }),
+            Input::Scheduled {
+                tx: tx(TransactionType::Deposit, 1, 4, Some(dec!(2))),
+                value_date: 100,
+            },
+            Input::SettleUntil { timestamp: 100 },
+        ];
+        let mut engine = Engine::new();
+        let mut writer = TranscriptWriter::new(Vec::new());
+        let mut in_batch = false;
+        for input in inputs {
+            let result = match input.clone() {
+                Input::Transaction(tx) => engine.process(tx).map(|_| ()),
+                Input::Scheduled { tx, value_date } => engine.schedule(tx, value_date),
+                Input::Admin(op) => engine.apply_admin(op),
+                Input::BeginBatch => {
+                    engine.begin_batch();
+                    in_batch = true;
+                    Ok(())
+                }
+                Input::CommitBatch => {
+                    engine.commit_batch();
+                    in_batch = false;
+                    Ok(())
+                }
+                Input::SettleUntil { timestamp } => {
+                    engine.advance_time(timestamp);
+                    Ok(())
+                }
+                Input::Accrue { .. } => unreachable!(),
+            };
+            if result.is_err() && in_batch {
+                engine.rollback_batch();
+            }
+            writer.record(&input, result).unwrap();
+        }
+        writer.finish(&engine).unwrap();
+        (writer.writer.into_inner().unwrap(), engine.state_digest())
+    }
+
+    #[test]
+    fn test_replay_reproduces_the_run() {
+        let (transcript, digest) = transcript();
+        let text = String::from_utf8(transcript.clone()).unwrap();
+        assert!(text.starts_with(
+            "type,client,tx,amount,timestamp,to_client,value_date,reason,result\n\
+             deposit,1,1,10.5,,,,,ok\n\
+             withdrawal,1,2,20,,,,,insufficient_funds\n\
+             begin,,,,,,,,\n"
+        ));
+        assert!(text.contains("adjust,1,,-0.5000,,,,\"fee, refunded\",ok\n"));
+
+        let mut engine = Engine::new();
+        assert_eq!(replay(transcript.as_slice(), &mut engine).unwrap(), digest);
+        assert_eq!(
+            engine.account(1).unwrap().available,
+            Money::from_units(120_000)
+        );
+        assert!(engine.account(2).is_none());
+
+        let diverged = text.replace("20,,,,,insufficient_funds", "20,,,,,ok");
+        assert!(matches!(
+            replay(diverged.as_bytes(), &mut Engine::new()),
+            Err(TranscriptError::Diverged { line: 3, .. })
+        ));
+        let tampered = text.replace("10.5,", "10.6,");
+        assert!(matches!(
+            replay(tampered.as_bytes(), &mut Engine::new()),
+            Err(TranscriptError::DigestMismatch { .. })
+        ));
+        let truncated = &text[..text.find("digest").unwrap()];
+        assert!(matches!(
+            replay(truncated.as_bytes(), &mut Engine::new()),
+            Err(TranscriptError::MissingDigest)
+        ));
+        assert!(matches!(
+            replay("type,client,tx,amount\n".as_bytes(), &mut Engine::new()),
+            Err(TranscriptError::NotATranscript)
+        ));
+    }
+}
@@ -1,37 +1,302 @@
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::outcome::AmountError;
+
+/// Decimal places of fixed-point amounts unless the engine is configured otherwise.
+pub const DEFAULT_SCALE: u32 = 4;
+
+/// Most decimal places an engine can be configured with; at this scale balances still
+/// reach ±92 billion.
+pub const MAX_SCALE: u32 = 8;
 
 /// Scale factor for fixed-point arithmetic (4 decimal places)
 pub const SCALE: i64 = 10_000;
 
 /// Convert Decimal to fixed-point i64
 pub fn to_fixed(d: Decimal) -> i64 {
-    use rust_decimal::prelude::ToPrimitive;
-    (d * Decimal::from(SCALE)).trunc().to_i64().unwrap_or(0)
+    to_fixed_at(d, DEFAULT_SCALE)
+}
+
+/// Convert Decimal to fixed-point i64 with `scale` decimal places, truncating the rest and
+/// saturating at the ends of the range
+pub fn to_fixed_at(d: Decimal, scale: u32) -> i64 {
+    Money::from_decimal_lossy(d, scale).units()
+}
+
+/// Decimal amount of `units` minor units, where one minor unit is `1 / SCALE`
+pub fn from_minor_units(units: i64) -> Decimal {
+    Decimal::new(units, DEFAULT_SCALE)
 }
 
 /// Format fixed-point i64 as decimal string
-fn format_fixed(value: i64) -> String {
+pub fn format_fixed(value: i64) -> String {
+    format_fixed_at(value, DEFAULT_SCALE)
+}
+
+/// Format fixed-point i64 with `scale` decimal places as decimal string
+pub fn format_fixed_at(value: i64, scale: u32) -> String {
     let is_negative = value < 0;
     // Use wrapping_abs to avoid panic on i64::MIN
     let abs_value = value.wrapping_abs() as u64;
-    let whole = abs_value / SCALE as u64;
-    let frac = abs_value % SCALE as u64;
-    if is_negative {
-        format!("-{}.{:04}", whole, frac)
+    let factor = 10u64.pow(scale);
+    let whole = abs_value / factor;
+    let frac = abs_value % factor;
+    let sign = if is_negative { "-" } else { "" };
+    if scale == 0 {
+        format!("{}{}", sign, whole)
     } else {
-        format!("{}.{:04}", whole, frac)
+        format!("{}{}.{:0width$}", sign, whole, frac, width = scale as usize)
     }
 }
 
-fn serialize_fixed<S>(value: &i64, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    serializer.serialize_str(&format_fixed(*value))
+/// What happens to the digits of an input amount beyond the engine's scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    /// Drop them, rounding towards zero.
+    #[default]
+    Truncate,
+    /// Round to the nearest minor unit, ties to even.
+    HalfEven,
+    /// Reject the transaction with `AmountError::ExcessPrecision`.
+    Reject,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Rounding::Truncate),
+            "half-even" => Ok(Rounding::HalfEven),
+            "reject" => Ok(Rounding::Reject),
+            _ => Err(format!("unknown rounding mode '{}'", s)),
+        }
+    }
+}
+
+/// Decimal places of an engine's fixed-point amounts and how input amounts are fitted to
+/// them. The default is 4 places, truncating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    pub scale: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self::new(DEFAULT_SCALE)
+    }
+}
+
+impl Precision {
+    /// `scale` decimal places, truncating.
+    ///
+    /// # Panics
+    ///
+    /// If `scale` is above [`MAX_SCALE`].
+    pub fn new(scale: u32) -> Self {
+        assert!(scale <= MAX_SCALE, "scale {} is above {}", scale, MAX_SCALE);
+        Self {
+            scale,
+            rounding: Rounding::Truncate,
+        }
+    }
+
+    /// Minor units in one whole unit.
+    pub fn factor(&self) -> i64 {
+        10i64.pow(self.scale)
+    }
+
+    /// `d` in minor units. Fails if it is out of range, rounds to zero without being zero,
+    /// or has more decimal places than the scale and the rounding mode rejects those.
+    pub fn to_fixed(&self, d: Decimal) -> Result<Money, AmountError> {
+        let fitted = match self.rounding {
+            Rounding::Truncate => d.round_dp_with_strategy(self.scale, RoundingStrategy::ToZero),
+            Rounding::HalfEven => {
+                d.round_dp_with_strategy(self.scale, RoundingStrategy::MidpointNearestEven)
+            }
+            Rounding::Reject if d.normalize().scale() > self.scale => {
+                return Err(AmountError::ExcessPrecision);
+            }
+            Rounding::Reject => d,
+        };
+        let units = fitted
+            .checked_mul(Decimal::from(self.factor()))
+            .and_then(|units| units.trunc().to_i64())
+            .ok_or(AmountError::Overflow)?;
+        if units == 0 && !d.is_zero() {
+            return Err(AmountError::BelowMinorUnit);
+        }
+        Ok(Money(units))
+    }
+
+    /// Formats `money` as a decimal string.
+    pub fn format(&self, money: Money) -> String {
+        money.format(self.scale)
+    }
+}
+
+/// An amount of money: a whole number of minor units, such as ten-thousandths. The scale
+/// belongs to the engine ([`Precision`]) rather than to each amount, so `Money` stays as
+/// small as an `i64`; conversions from and to decimals take it, while `Display` and serde
+/// use the default of 4 places.
+///
+/// The operators panic on overflow in debug builds like the integer ones; the engine's
+/// balance updates use the checked and saturating methods.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+    pub const MIN: Money = Money(i64::MIN);
+    pub const MAX: Money = Money(i64::MAX);
+
+    pub const fn from_units(units: i64) -> Self {
+        Money(units)
+    }
+
+    /// The amount in minor units.
+    pub const fn units(self) -> i64 {
+        self.0
+    }
+
+    /// `amount` fitted to `precision`; see [`Precision::to_fixed`].
+    pub fn from_decimal(amount: Decimal, precision: Precision) -> Result<Self, AmountError> {
+        precision.to_fixed(amount)
+    }
+
+    /// `amount` with `scale` decimal places, truncating the rest and saturating at the ends
+    /// of the range. For amounts of settings rather than of transactions.
+    pub fn from_decimal_lossy(amount: Decimal, scale: u32) -> Self {
+        match Precision::new(scale).to_fixed(amount) {
+            Ok(money) => money,
+            Err(AmountError::Overflow) if amount.is_sign_negative() => Money::MIN,
+            Err(AmountError::Overflow) => Money::MAX,
+            Err(_) => Money::ZERO,
+        }
+    }
+
+    /// The amount as a decimal, reading it with `scale` decimal places.
+    pub fn to_decimal(self, scale: u32) -> Decimal {
+        Decimal::new(self.0, scale)
+    }
+
+    /// The amount as a decimal string with exactly `scale` decimal places.
+    pub fn format(self, scale: u32) -> String {
+        format_fixed_at(self.0, scale)
+    }
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+
+    pub fn checked_neg(self) -> Option<Money> {
+        self.0.checked_neg().map(Money)
+    }
+
+    pub fn saturating_add(self, other: Money) -> Money {
+        Money(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Money) -> Money {
+        Money(self.0.saturating_sub(other.0))
+    }
+
+    pub fn saturating_neg(self) -> Money {
+        Money(self.0.saturating_neg())
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+/// With the default 4 decimal places; use [`Money::format`] for other scales.
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format(DEFAULT_SCALE))
+    }
+}
+
+/// As a decimal string with 4 decimal places, like the reports.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// From a decimal string or number, truncated to 4 decimal places.
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let amount = <Decimal as Deserialize>::deserialize(deserializer)?;
+        Money::from_decimal(amount, Precision::default()).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -39,15 +304,146 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Moves funds from `client` to `to_client`.
+    Transfer,
+    /// Reverses the chargeback of the referenced transaction, as after a successful
+    /// representment.
+    #[serde(rename = "chargeback_reversal")]
+    ChargebackReversal,
+    /// Sets `amount` of the client's available funds aside on hold, under its own `tx` id.
+    Hold,
+    /// Releases the hold `tx` back to available funds: `amount` of it, or all that is left
+    /// without one.
+    Release,
+}
+
+/// Writes the lowercase name used in CSV input.
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Transfer => "transfer",
+            TransactionType::ChargebackReversal => "chargeback_reversal",
+            TransactionType::Hold => "hold",
+            TransactionType::Release => "release",
+        })
+    }
+}
+
+/// Error returned when a transaction type name is not recognised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTransactionTypeError(pub String);
+
+impl fmt::Display for ParseTransactionTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown transaction type '{}'", self.0)
+    }
 }
 
-#[derive(Debug, Deserialize)]
+impl std::error::Error for ParseTransactionTypeError {}
+
+/// Parses the same lowercase names accepted by the CSV input, ignoring case and surrounding whitespace.
+impl FromStr for TransactionType {
+    type Err = ParseTransactionTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "transfer" => Ok(TransactionType::Transfer),
+            "chargeback_reversal" => Ok(TransactionType::ChargebackReversal),
+            "hold" => Ok(TransactionType::Hold),
+            "release" => Ok(TransactionType::Release),
+            _ => Err(ParseTransactionTypeError(s.to_string())),
+        }
+    }
+}
+
+/// Identifies a client, read from the `client` column.
+///
+/// The engine and its APIs are written against this alias and [`TxId`] rather than the
+/// integer types, so the ids read as what they are. A client id is 128 bits wide, enough
+/// for a UUID read as an integer, and a transaction id 64. The binary journal, the
+/// write-ahead log and binary snapshots lay them out as 16 and 8 bytes and still read
+/// version 1 files, where they were 2 and 4. The dense account table has slots for
+/// clients below 65,536 only, and the transaction file store fixed records for ids below
+/// 2^32 only, keeping larger ones beside them.
+pub type ClientId = u128;
+
+/// Identifies a transaction, read from the `tx` column.
+pub type TxId = u64;
+
+/// Values of a transaction row's columns other than the ones the engine reads, such as a
+/// payment `reference`, by column name.
+pub type Metadata = HashMap<String, String>;
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
-    pub client: u16,
-    pub tx: u32,
+    pub client: ClientId,
+    pub tx: TxId,
     pub amount: Option<Decimal>,
+    /// Seconds since the Unix epoch. Optional; only time-based policies use it.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Recipient of a transfer; unused by the other types.
+    #[serde(default)]
+    pub to_client: Option<ClientId>,
+    /// Extra columns, kept with an accepted deposit, withdrawal or transfer and available
+    /// through [`Engine::metadata`](crate::Engine::metadata). Filled by
+    /// [`CsvReader::with_metadata`](crate::io::CsvReader::with_metadata).
+    #[serde(skip)]
+    pub metadata: Metadata,
+}
+
+impl From<&Transaction> for Transaction {
+    fn from(tx: &Transaction) -> Self {
+        tx.clone()
+    }
+}
+
+/// A transaction row whose amount is an integer number of minor units (`123456` is
+/// `12.3456`). Parsing it involves no decimal parsing, and converting it is exact.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MinorUnitsTransaction {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Option<i64>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    #[serde(default)]
+    pub to_client: Option<ClientId>,
+}
+
+impl MinorUnitsTransaction {
+    /// The transaction, reading the amount as minor units of `scale` decimal places.
+    pub fn into_transaction(self, scale: u32) -> Transaction {
+        Transaction {
+            tx_type: self.tx_type,
+            client: self.client,
+            tx: self.tx,
+            amount: self.amount.map(|units| Decimal::new(units, scale)),
+            timestamp: self.timestamp,
+            to_client: self.to_client,
+            metadata: Metadata::new(),
+        }
+    }
+}
+
+impl From<MinorUnitsTransaction> for Transaction {
+    fn from(row: MinorUnitsTransaction) -> Self {
+        row.into_transaction(DEFAULT_SCALE)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -58,34 +454,175 @@ pub enum DisputeState {
     ChargedBack,
 }
 
-#[derive(Debug, Clone)]
+/// Which transaction a [`StoredTransaction`] records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoredKind {
+    Deposit,
+    Withdrawal,
+    Transfer { to_client: ClientId },
+}
+
+/// A deposit, withdrawal or transfer kept so it can be disputed. `client` is the one who may
+/// dispute it: the client who made it, which for a transfer is the sender.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct StoredTransaction {
-    pub client: u16,
-    pub amount: i64,
+    pub client: ClientId,
+    pub amount: Money,
     pub dispute_state: DisputeState,
+    pub kind: StoredKind,
+    /// When the transaction happened: its `timestamp`, or the installed clock's time.
+    /// `None` when it had neither.
+    pub timestamp: Option<u64>,
+}
+
+impl StoredTransaction {
+    /// The client whose account a dispute holds funds on: the recipient of a transfer, or
+    /// `client` otherwise.
+    pub fn credited(&self) -> ClientId {
+        match self.kind {
+            StoredKind::Transfer { to_client } => to_client,
+            StoredKind::Deposit | StoredKind::Withdrawal => self.client,
+        }
+    }
 }
 
-#[derive(Debug, Default)]
+/// Funds set aside by a hold transaction, kept under its `tx` id until released. A hold
+/// released in full stays with nothing `remaining`, so its id can't be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hold {
+    pub client: ClientId,
+    /// The amount originally held.
+    pub amount: Money,
+    /// What is still on hold, after partial releases.
+    pub remaining: Money,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Account {
-    pub available: i64,
-    pub held: i64,
+    pub available: Money,
+    pub held: Money,
+    /// Funds withheld from deposits by the rolling reserve, awaiting release.
+    pub reserved: Money,
+    /// Funds set aside by hold transactions, kept apart from `held`, which only holds
+    /// disputed funds.
+    pub on_hold: Money,
     pub locked: bool,
+    /// Closed by an operator; rejects all further money movement.
+    pub closed: bool,
 }
 
 impl Account {
-    pub fn total(&self) -> i64 {
-        self.available + self.held
+    pub fn total(&self) -> Money {
+        self.available + self.held + self.reserved + self.on_hold
     }
 }
 
-#[derive(Debug, Serialize)]
+/// A client's activity since the engine started, reported with extended output. Only
+/// accepted transactions count, under the client that made them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountActivity {
+    /// Accepted transactions of every type.
+    pub transactions: u64,
+    /// Disputes open on funds the account holds, as in the `v2` report.
+    pub open_disputes: u64,
+    /// Sum of the accepted deposits.
+    pub deposited: Money,
+    /// Sum of the accepted withdrawals, without fees.
+    pub withdrawn: Money,
+    /// Id of the latest accepted transaction.
+    pub last_tx: Option<TxId>,
+    /// Timestamp of the latest accepted transaction, if it had one.
+    pub last_timestamp: Option<u64>,
+}
+
+impl AccountActivity {
+    /// Adds `other`'s counts and totals, for a merged client. The latest transaction is
+    /// the one with the later timestamp, or this one's on a tie.
+    pub fn absorb(&mut self, other: AccountActivity) {
+        self.transactions += other.transactions;
+        self.open_disputes += other.open_disputes;
+        self.deposited = self.deposited.saturating_add(other.deposited);
+        self.withdrawn = self.withdrawn.saturating_add(other.withdrawn);
+        if self.last_tx.is_none() || other.last_timestamp > self.last_timestamp {
+            self.last_tx = other.last_tx;
+            self.last_timestamp = other.last_timestamp;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(into = "AccountRow")]
 pub struct AccountOutput {
-    pub client: u16,
-    #[serde(serialize_with = "serialize_fixed")]
-    pub available: i64,
-    #[serde(serialize_with = "serialize_fixed")]
-    pub held: i64,
-    #[serde(serialize_with = "serialize_fixed")]
-    pub total: i64,
+    pub client: ClientId,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
     pub locked: bool,
+    /// Only present when a rolling reserve is configured, so the default columns are unchanged.
+    pub reserved: Option<Money>,
+    /// Timestamp of the client's latest accepted transaction, 0 if none had a time. Only
+    /// present when the engine is configured to report it.
+    pub last_activity: Option<u64>,
+    /// Whether the account is closed. Only present with extended output.
+    pub closed: Option<bool>,
+    /// Whether the available balance is below zero. Only present with extended output.
+    pub overdrawn: Option<bool>,
+    /// Transaction counts and totals. Only present with extended output.
+    pub activity: Option<AccountActivity>,
+    /// Decimal places of the amounts, which are serialized with exactly that many.
+    pub scale: u32,
+}
+
+/// How an [`AccountOutput`] is serialized.
+#[derive(Serialize)]
+struct AccountRow {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserved: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_activity: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overdrawn: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transactions: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open_disputes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deposited: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    withdrawn: Option<String>,
+    /// Present but empty for an account without accepted transactions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_tx: Option<Option<TxId>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_tx_timestamp: Option<Option<u64>>,
+}
+
+impl From<AccountOutput> for AccountRow {
+    fn from(output: AccountOutput) -> Self {
+        let amount = |money: Money| money.format(output.scale);
+        AccountRow {
+            client: output.client,
+            available: amount(output.available),
+            held: amount(output.held),
+            total: amount(output.total),
+            locked: output.locked,
+            reserved: output.reserved.map(amount),
+            last_activity: output.last_activity,
+            closed: output.closed,
+            overdrawn: output.overdrawn,
+            transactions: output.activity.map(|activity| activity.transactions),
+            open_disputes: output.activity.map(|activity| activity.open_disputes),
+            deposited: output.activity.map(|activity| amount(activity.deposited)),
+            withdrawn: output.activity.map(|activity| amount(activity.withdrawn)),
+            last_tx: output.activity.map(|activity| activity.last_tx),
+            last_tx_timestamp: output.activity.map(|activity| activity.last_timestamp),
+        }
+    }
 }
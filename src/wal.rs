@@ -0,0 +1,380 @@
+//! Write-ahead log for crash recovery.
+//!
+//! With a [`Wal`] installed through [`Engine::set_wal`](crate::Engine::set_wal), the engine
+//! appends each transaction to the log before applying it and removes it again if it is
+//! rejected, so the log holds exactly the accepted transactions in order. After a crash,
+//! [`Engine::recover_from_wal`](crate::Engine::recover_from_wal) replays them into a fresh
+//! engine with the same configuration, rebuilding its state.
+//!
+//! The log is a directory of segments named `wal-00000001.txj`, `wal-00000002.txj`, ...
+//! Each segment is a [journal](crate::journal), so every entry carries a CRC-32 and a torn
+//! final entry is recognised. Once a segment reaches its size limit the next entry starts a
+//! new one. Segments are never deleted: without snapshots, recovery needs all of them.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::journal::{self, JournalError, JournalReader, JournalWriter};
+use crate::types::Transaction;
+
+/// Default size at which a segment is closed and a new one started.
+pub const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum WalError {
+    Io(io::Error),
+    /// A segment is damaged somewhere other than its torn final entry.
+    Journal {
+        segment: PathBuf,
+        error: JournalError,
+    },
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalError::Io(error) => write!(f, "{}", error),
+            WalError::Journal { segment, error } => {
+                write!(f, "{}: {}", segment.display(), error)
+            }
+        }
+    }
+}
+
+impl Error for WalError {}
+
+impl From<io::Error> for WalError {
+    fn from(error: io::Error) -> Self {
+        WalError::Io(error)
+    }
+}
+
+/// Position in the log: a segment number and a length within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WalMark {
+    segment: u64,
+    len: u64,
+}
+
+/// The appending end of a write-ahead log.
+pub struct Wal {
+    dir: PathBuf,
+    segment_bytes: u64,
+    sync: bool,
+    segment: u64,
+    len: u64,
+    writer: SegmentWriter,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, creating the directory and a first segment if needed, and
+    /// continues the latest segment. A torn final entry left by a crash is cut off. A latest
+    /// segment written in an older journal version is left as it is and a new one started.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, WalError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut segment = segments(&dir)?.last().map_or(1, |&(number, _)| number);
+        let (writer, len) = match open_segment(&dir, segment)? {
+            Some(opened) => opened,
+            None => {
+                segment += 1;
+                new_segment(&dir, segment)?
+            }
+        };
+        Ok(Self {
+            dir,
+            segment_bytes: SEGMENT_BYTES,
+            sync: false,
+            segment,
+            len,
+            writer,
+        })
+    }
+
+    /// Starts a new segment once the current one reaches `bytes`.
+    pub fn with_segment_bytes(mut self, bytes: u64) -> Self {
+        self.segment_bytes = bytes;
+        self
+    }
+
+    /// Forces every entry to stable storage before the transaction is applied. Without it
+    /// entries reach the operating system, which survives a process crash but not a power
+    /// failure.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Number of the segment being written.
+    pub fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    pub(crate) fn mark(&self) -> WalMark {
+        WalMark {
+            segment: self.segment,
+            len: self.len,
+        }
+    }
+
+    /// Appends `tx`, returning the position before it.
+    pub(crate) fn append(&mut self, tx: &Transaction) -> io::Result<WalMark> {
+        if self.len >= self.segment_bytes {
+            self.writer.flush()?;
+            let (writer, len) = new_segment(&self.dir, self.segment + 1)?;
+            self.segment += 1;
+            self.writer = writer;
+            self.len = len;
+        }
+        let mark = self.mark();
+        self.writer.append(tx)?;
+        self.writer.flush()?;
+        let file = self.writer.get_ref().get_ref();
+        if self.sync {
+            file.sync_data()?;
+        }
+        self.len = file.metadata()?.len();
+        Ok(mark)
+    }
+
+    /// Drops everything appended after `mark`, including whole segments started since.
+    pub(crate) fn truncate(&mut self, mark: WalMark) -> io::Result<()> {
+        for (number, path) in segments(&self.dir)? {
+            if number > mark.segment {
+                fs::remove_file(path)?;
+            }
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .open(segment_path(&self.dir, mark.segment))?;
+        file.set_len(mark.len)?;
+        let mut writer = BufWriter::new(file);
+        writer.seek(SeekFrom::End(0))?;
+        self.writer = JournalWriter::append_to(writer);
+        self.segment = mark.segment;
+        self.len = mark.len;
+        Ok(())
+    }
+}
+
+/// Reads back every entry of the log in `dir`, in order. A torn final entry of the last
+/// segment is ignored; any other damage is an error.
+pub(crate) fn read_all(dir: &Path) -> Result<Vec<Transaction>, WalError> {
+    let segments = segments(dir)?;
+    let mut transactions = Vec::new();
+    for (index, (_, path)) in segments.iter().enumerate() {
+        let last = index + 1 == segments.len();
+        let journal = |error| WalError::Journal {
+            segment: path.clone(),
+            error,
+        };
+        let reader = match JournalReader::new(io::BufReader::new(File::open(path)?)) {
+            Ok(reader) => reader,
+            // Crashed while writing the header of a new segment.
+            Err(JournalError::BadMagic) if last && fs::metadata(path)?.len() < 8 => break,
+            Err(error) => return Err(journal(error)),
+        };
+        for result in reader {
+            match result {
+                Ok(tx) => transactions.push(tx),
+                Err(JournalError::Truncated { .. }) if last => break,
+                Err(error) => return Err(journal(error)),
+            }
+        }
+    }
+    Ok(transactions)
+}
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("wal-{:08}.txj", number))
+}
+
+/// Segment files in `dir`, by number.
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("wal-")?.strip_suffix(".txj"))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            segments.push((number, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+type SegmentWriter = JournalWriter<BufWriter<File>>;
+
+/// Opens segment `number` for appending, cutting off a torn final entry, or starts it if
+/// it doesn't exist yet. Returns the writer and the segment's length, or `None` if the
+/// segment is in an older journal version, which can't be appended to.
+fn open_segment(dir: &Path, number: u64) -> Result<Option<(SegmentWriter, u64)>, WalError> {
+    let path = segment_path(dir, number);
+    let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(file) if file.metadata()?.len() >= 8 => file,
+        // Missing, or crashed while writing its header.
+        _ => return Ok(Some(new_segment(dir, number)?)),
+    };
+    let journal = |error| WalError::Journal {
+        segment: path.clone(),
+        error,
+    };
+    let mut reader = JournalReader::new(io::BufReader::new(&mut file)).map_err(journal)?;
+    for result in reader.by_ref() {
+        match result {
+            Ok(_) | Err(JournalError::Truncated { .. }) => {}
+            Err(error) => return Err(journal(error)),
+        }
+    }
+    let len = reader.position();
+    let current = reader.version() == journal::VERSION;
+    file.set_len(len)?;
+    if !current {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(len))?;
+    Ok(Some((JournalWriter::append_to(BufWriter::new(file)), len)))
+}
+
+/// Creates segment `number`, replacing any file of that name, and writes its header.
+fn new_segment(dir: &Path, number: u64) -> io::Result<(SegmentWriter, u64)> {
+    let file = File::create(segment_path(dir, number))?;
+    let mut writer = JournalWriter::new(BufWriter::new(file))?;
+    writer.flush()?;
+    let len = writer.get_ref().get_ref().metadata()?.len();
+    Ok((writer, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::outcome::ProcessError;
+    use crate::types::{ClientId, Money, TransactionType, TxId};
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::process;
+
+    fn tx(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<rust_decimal::Decimal>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_log_rotates_and_recovers_after_torn_write() {
+        let dir = env::temp_dir().join(format!("tx-engine-wal-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut engine = Engine::new();
+        engine.set_wal(Wal::open(&dir).unwrap().with_segment_bytes(64));
+        let deposit = |client, id| tx(TransactionType::Deposit, client, id, Some(dec!(5.0)));
+        for id in 1..=4 {
+            engine.process(deposit(1, id)).unwrap();
+        }
+        let overdraw = tx(TransactionType::Withdrawal, 1, 5, Some(dec!(100.0)));
+        assert_eq!(
+            engine.process(overdraw),
+            Err(ProcessError::InsufficientFunds)
+        );
+        let rejected_batch = engine.process_batch([
+            deposit(2, 6),
+            deposit(2, 7),
+            tx(TransactionType::Withdrawal, 2, 8, Some(dec!(100.0))),
+        ]);
+        assert!(rejected_batch.is_err());
+        engine
+            .process(tx(TransactionType::Dispute, 1, 2, None))
+            .unwrap();
+        assert!(segments(&dir).unwrap().len() > 1);
+
+        // A crash in the middle of the next entry.
+        let (_, last) = segments(&dir).unwrap().pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&last).unwrap();
+        io::Write::write_all(&mut file, &[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut recovered = Engine::new();
+        assert_eq!(recovered.recover_from_wal(&dir).unwrap(), 5);
+        for client in [1, 2] {
+            assert_eq!(recovered.account(client), engine.account(client));
+        }
+
+        // Reopening cuts off the torn entry and continues where the log ended.
+        recovered.set_wal(Wal::open(&dir).unwrap().with_segment_bytes(64));
+        recovered.process(deposit(3, 9)).unwrap();
+        let mut again = Engine::new();
+        assert_eq!(again.recover_from_wal(&dir).unwrap(), 6);
+        assert_eq!(
+            again.account(3).map(|account| account.available),
+            Some(Money::from_units(50_000))
+        );
+
+        // Damage anywhere else is reported.
+        let (_, first) = segments(&dir).unwrap().remove(0);
+        let mut bytes = fs::read(&first).unwrap();
+        bytes[14] ^= 0xFF;
+        fs::write(&first, bytes).unwrap();
+        let error = Engine::new().recover_from_wal(&dir).unwrap_err();
+        assert!(matches!(
+            error,
+            WalError::Journal {
+                error: JournalError::Corrupt { .. },
+                ..
+            }
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_continues_a_version_1_segment_in_a_new_one() {
+        let dir = env::temp_dir().join(format!("tx-engine-wal-v1-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // A deposit of 5 by client 1 as tx 1, with a u16 client and a u32 transaction id.
+        let mut payload = vec![0, 1, 0, 1, 0, 0, 0, 1];
+        payload.extend_from_slice(&dec!(5).serialize());
+        let mut bytes = b"TXJ\0\x01\0\0\0".to_vec();
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&journal::crc32(&payload).to_le_bytes());
+        fs::write(segment_path(&dir, 1), &bytes).unwrap();
+
+        let mut engine = Engine::new();
+        let wal = Wal::open(&dir).unwrap();
+        assert_eq!(wal.segment(), 2);
+        engine.set_wal(wal);
+        engine
+            .process(tx(TransactionType::Deposit, 1, 2, Some(dec!(2))))
+            .unwrap();
+        assert_eq!(fs::read(segment_path(&dir, 1)).unwrap(), bytes);
+
+        let mut recovered = Engine::new();
+        assert_eq!(recovered.recover_from_wal(&dir).unwrap(), 2);
+        assert_eq!(
+            recovered.account(1).map(|account| account.available),
+            Some(Money::from_units(70_000))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
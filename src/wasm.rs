@@ -0,0 +1,82 @@
+//! JavaScript bindings for running the engine in a browser or Node.js, built with
+//! `wasm-pack` from the `wasm` feature.
+//!
+//! [`WasmEngine`] wraps an [`Engine`] with the default configuration. Transactions go in one
+//! at a time as JSON objects with the fields of the input format (`type`, `client`, `tx`,
+//! `amount`, and optionally `timestamp` and `to_client`), and the accounts come out as a JSON
+//! array shaped like the server's `GET /accounts` response.
+
+use wasm_bindgen::prelude::*;
+
+use crate::engine::Engine;
+use crate::types::Transaction;
+
+#[wasm_bindgen]
+pub struct WasmEngine {
+    engine: Engine,
+}
+
+#[wasm_bindgen]
+impl WasmEngine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            engine: Engine::new(),
+        }
+    }
+
+    /// Applies one transaction. Returns `undefined` if it was applied, or the code of the
+    /// reason it was rejected (e.g. `"insufficient_funds"`); throws if `transaction` is not
+    /// a valid transaction object.
+    pub fn process(&mut self, transaction: &str) -> Result<Option<String>, JsError> {
+        process_json(&mut self.engine, transaction)
+            .map(|rejected| rejected.map(String::from))
+            .map_err(|error| JsError::new(&error.to_string()))
+    }
+
+    /// Every account, ordered by client, as a JSON array.
+    pub fn accounts(&self) -> String {
+        accounts_json(&self.engine)
+    }
+}
+
+impl Default for WasmEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Applies the transaction in `input`, returning the rejection code if it was rejected.
+fn process_json(engine: &mut Engine, input: &str) -> serde_json::Result<Option<&'static str>> {
+    let tx: Transaction = serde_json::from_str(input)?;
+    Ok(engine.process(tx).err().map(|error| error.code()))
+}
+
+fn accounts_json(engine: &Engine) -> String {
+    serde_json::to_string(&engine.output_sorted()).expect("accounts serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_json() {
+        let mut engine = Engine::new();
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+        assert_eq!(process_json(&mut engine, deposit).unwrap(), None);
+        let withdrawal = r#"{"type":"withdrawal","client":1,"tx":2,"amount":"3"}"#;
+        assert_eq!(
+            process_json(&mut engine, withdrawal).unwrap(),
+            Some("insufficient_funds")
+        );
+        let dispute = r#"{"type":"dispute","client":1,"tx":1,"amount":null}"#;
+        assert_eq!(process_json(&mut engine, dispute).unwrap(), None);
+        assert!(process_json(&mut engine, r#"{"type":"deposit"}"#).is_err());
+
+        assert_eq!(
+            accounts_json(&engine),
+            r#"[{"client":1,"available":"0.0000","held":"2.5000","total":"2.5000","locked":false}]"#
+        );
+    }
+}
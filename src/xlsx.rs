@@ -0,0 +1,240 @@
+//! Transaction input from Excel `.xlsx` workbooks.
+//!
+//! Only the first worksheet is read. Its first row is treated as a header and columns are
+//! located by name through a [`ColumnMapping`], so partner files with extra or reordered
+//! columns can be ingested as-is.
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use calamine::{Data, Range, Reader, Xlsx, open_workbook};
+use rust_decimal::Decimal;
+
+use crate::types::{ClientId, Transaction, TransactionType, TxId};
+
+/// Header names used to locate each transaction field in the sheet. Matching ignores case
+/// and surrounding whitespace.
+#[derive(Debug, Clone)]
+pub struct ColumnMapping {
+    pub tx_type: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            tx_type: "type".to_string(),
+            client: "client".to_string(),
+            tx: "tx".to_string(),
+            amount: "amount".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum XlsxError {
+    Workbook(calamine::XlsxError),
+    NoSheets,
+    MissingColumn(String),
+    /// A data row could not be converted. `row` is 1-based, as shown in Excel.
+    InvalidRow {
+        row: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for XlsxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XlsxError::Workbook(e) => write!(f, "failed to read workbook: {}", e),
+            XlsxError::NoSheets => write!(f, "workbook contains no worksheets"),
+            XlsxError::MissingColumn(name) => write!(f, "missing column '{}'", name),
+            XlsxError::InvalidRow { row, message } => write!(f, "row {}: {}", row, message),
+        }
+    }
+}
+
+impl Error for XlsxError {}
+
+impl From<calamine::XlsxError> for XlsxError {
+    fn from(e: calamine::XlsxError) -> Self {
+        XlsxError::Workbook(e)
+    }
+}
+
+/// Reads all transactions from the first sheet of the workbook at `path`.
+pub fn read_transactions<P: AsRef<Path>>(
+    path: P,
+    mapping: &ColumnMapping,
+) -> Result<Vec<Transaction>, XlsxError> {
+    let mut workbook: Xlsx<_> = open_workbook(path)?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or(XlsxError::NoSheets)??;
+    transactions_from_range(&range, mapping)
+}
+
+fn transactions_from_range(
+    range: &Range<Data>,
+    mapping: &ColumnMapping,
+) -> Result<Vec<Transaction>, XlsxError> {
+    let mut rows = range.rows();
+    let Some(header) = rows.next() else {
+        return Ok(Vec::new());
+    };
+
+    let find = |name: &str| {
+        header
+            .iter()
+            .position(|cell| cell_text(cell).is_some_and(|h| h.trim().eq_ignore_ascii_case(name)))
+            .ok_or_else(|| XlsxError::MissingColumn(name.to_string()))
+    };
+    let type_col = find(&mapping.tx_type)?;
+    let client_col = find(&mapping.client)?;
+    let tx_col = find(&mapping.tx)?;
+    let amount_col = find(&mapping.amount)?;
+
+    let mut transactions = Vec::new();
+    // Row 1 is the header, so data starts at row 2.
+    for (index, row) in rows.enumerate() {
+        if row.iter().all(|cell| matches!(cell, Data::Empty)) {
+            continue;
+        }
+        let row_number = index + 2;
+        let invalid = |message: String| XlsxError::InvalidRow {
+            row: row_number,
+            message,
+        };
+        let cell = |col: usize| row.get(col).unwrap_or(&Data::Empty);
+
+        let tx_type = cell_text(cell(type_col))
+            .ok_or_else(|| invalid("missing transaction type".to_string()))?;
+        let tx_type = TransactionType::from_str(&tx_type).map_err(|e| invalid(e.to_string()))?;
+        let client = cell_integer(cell(client_col))
+            .and_then(|v| ClientId::try_from(v).ok())
+            .ok_or_else(|| invalid("invalid client id".to_string()))?;
+        let tx = cell_integer(cell(tx_col))
+            .and_then(|v| TxId::try_from(v).ok())
+            .ok_or_else(|| invalid("invalid transaction id".to_string()))?;
+        let amount = match cell_text(cell(amount_col)) {
+            None => None,
+            Some(text) if text.trim().is_empty() => None,
+            Some(text) => Some(
+                Decimal::from_str(text.trim())
+                    .map_err(|e| invalid(format!("invalid amount '{}': {}", text, e)))?,
+            ),
+        };
+
+        transactions.push(Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp: None,
+            to_client: None,
+            metadata: Default::default(),
+        });
+    }
+
+    Ok(transactions)
+}
+
+/// Renders a cell as text. Floats use the shortest representation that round-trips, so a
+/// cell displayed as `703.2999` is read as exactly that rather than its binary expansion.
+fn cell_text(cell: &Data) -> Option<String> {
+    match cell {
+        Data::String(s) => Some(s.clone()),
+        Data::Float(f) => Some(f.to_string()),
+        Data::Int(i) => Some(i.to_string()),
+        Data::Empty => None,
+        other => Some(other.to_string()),
+    }
+}
+
+fn cell_integer(cell: &Data) -> Option<i64> {
+    match cell {
+        Data::Int(i) => Some(*i),
+        Data::Float(f) if f.fract() == 0.0 => Some(*f as i64),
+        Data::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn sheet(rows: Vec<Vec<Data>>) -> Range<Data> {
+        let height = rows.len() as u32;
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0) as u32;
+        let mut range = Range::new((0, 0), (height - 1, width - 1));
+        for (r, row) in rows.into_iter().enumerate() {
+            for (c, value) in row.into_iter().enumerate() {
+                range.set_value((r as u32, c as u32), value);
+            }
+        }
+        range
+    }
+
+    fn text(s: &str) -> Data {
+        Data::String(s.to_string())
+    }
+
+    #[test]
+    fn test_maps_columns_by_header_name() {
+        let range = sheet(vec![
+            vec![
+                text("Amount"),
+                text("TX"),
+                text("note"),
+                text("Client"),
+                text("Type"),
+            ],
+            vec![
+                Data::Float(703.2999),
+                Data::Float(1.0),
+                text("x"),
+                Data::Int(78),
+                text("deposit"),
+            ],
+            vec![
+                Data::Empty,
+                Data::Int(1),
+                Data::Empty,
+                Data::Int(78),
+                text("Dispute"),
+            ],
+        ]);
+
+        let txs = transactions_from_range(&range, &ColumnMapping::default()).unwrap();
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].client, 78);
+        assert_eq!(txs[0].tx, 1);
+        assert_eq!(txs[0].amount, Some(dec!(703.2999)));
+        assert!(matches!(txs[1].tx_type, TransactionType::Dispute));
+        assert_eq!(txs[1].amount, None);
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let range = sheet(vec![vec![text("type"), text("client"), text("tx")]]);
+        let err = transactions_from_range(&range, &ColumnMapping::default()).unwrap_err();
+        assert!(matches!(err, XlsxError::MissingColumn(ref c) if c == "amount"));
+    }
+
+    #[test]
+    fn test_invalid_row_reports_excel_row_number() {
+        let range = sheet(vec![
+            vec![text("type"), text("client"), text("tx"), text("amount")],
+            vec![text("deposit"), Data::Int(1), Data::Int(1), text("1.0")],
+            vec![text("deposit"), Data::Int(-1), Data::Int(2), text("1.0")],
+        ]);
+        let err = transactions_from_range(&range, &ColumnMapping::default()).unwrap_err();
+        assert!(matches!(err, XlsxError::InvalidRow { row: 3, .. }));
+    }
+}